@@ -31,11 +31,27 @@ impl Clone for WamrEngine {
     }
 }
 
+/// Version of the wamr-rust-sdk, the tag of its dependency in the lockfile, when it has one
+pub(crate) const WAMR_SDK_VERSION: Option<&str> = option_env!("WAMR_SDK_VERSION");
+
 impl Engine for WamrEngine {
     fn name() -> &'static str {
         "wamr"
     }
 
+    fn version() -> Option<&'static str> {
+        WAMR_SDK_VERSION
+    }
+
+    fn features() -> &'static [&'static str] {
+        &[
+            "bulk-memory",
+            "multi-value",
+            "reference-types",
+            "wasi-preview1",
+        ]
+    }
+
     fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32> {
         let args = ctx.args();
         let envs = ctx.envs();
@@ -117,3 +117,17 @@ fn test_custom_entrypoint() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_engine_info() {
+    use containerd_shim_wasm::sandbox::Instance as _;
+
+    use crate::instance::WAMR_SDK_VERSION;
+
+    let info = WasiInstance::engine_info();
+    assert_eq!(info.name, "wamr");
+    assert_eq!(info.version.as_deref(), WAMR_SDK_VERSION);
+    assert!(info.features.iter().any(|f| f == "wasi-preview1"));
+    assert!(!info.precompile);
+    assert!(info.media_types.iter().any(|t| t == "application/wasm"));
+}
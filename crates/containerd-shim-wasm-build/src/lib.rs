@@ -0,0 +1,101 @@
+//! Helpers for the build scripts of the shims, e.g., for the version of their engine.
+
+use std::path::PathBuf;
+
+/// The version of `package` in the `Cargo.lock` of the workspace building the crate,
+/// above the manifest of the crate, or above its target directory for a dependency.
+///
+/// It's None, with a warning of the build, without a lockfile, or when the lockfile has
+/// none or several versions of `package`.
+pub fn locked_version(package: &str) -> Option<String> {
+    let dirs = ["CARGO_MANIFEST_DIR", "OUT_DIR"]
+        .into_iter()
+        .filter_map(|key| std::env::var_os(key).map(PathBuf::from))
+        .collect::<Vec<_>>();
+    let Some(lockfile) = dirs
+        .iter()
+        .flat_map(|dir| dir.ancestors())
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.is_file())
+    else {
+        println!("cargo:warning=no Cargo.lock, the version of {package} is unknown");
+        return None;
+    };
+    println!("cargo:rerun-if-changed={}", lockfile.display());
+
+    let lock = match std::fs::read_to_string(&lockfile) {
+        Ok(lock) => lock,
+        Err(err) => {
+            println!("cargo:warning=failed to read {}: {err}", lockfile.display());
+            return None;
+        }
+    };
+    let version = version_in(&lock, package);
+    if version.is_none() {
+        println!(
+            "cargo:warning=not one version of {package} in {}, its version is unknown",
+            lockfile.display()
+        );
+    }
+    version.map(str::to_string)
+}
+
+/// The version of `package` in the lockfile `lock`, if it has only one
+fn version_in<'a>(lock: &'a str, package: &str) -> Option<&'a str> {
+    let mut versions = lock
+        .split("[[package]]")
+        .filter(|entry| field(entry, "name") == Some(package))
+        .filter_map(|entry| git_tag(entry).or_else(|| field(entry, "version")));
+    let version = versions.next()?;
+    versions.next().is_none().then_some(version)
+}
+
+/// The tag of a git dependency, e.g., `1.1.0` for `?tag=v1.1.0`, which its manifest may not follow
+fn git_tag(entry: &str) -> Option<&str> {
+    let (_, tag) = field(entry, "source")?.split_once("?tag=")?;
+    let tag = tag.split('#').next()?;
+    Some(tag.strip_prefix('v').unwrap_or(tag))
+}
+
+/// The value of the string `key` of an entry of the lockfile
+fn field<'a>(entry: &'a str, key: &str) -> Option<&'a str> {
+    entry.lines().find_map(|line| {
+        let (k, v) = line.split_once('=')?;
+        (k.trim() == key).then(|| v.trim().trim_matches('"'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCK: &str = r#"
+[[package]]
+name = "wasmtime"
+version = "30.0.2"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "wamr-rust-sdk"
+version = "1.0.0"
+source = "git+https://github.com/bytecodealliance/wamr-rust-sdk?tag=v1.1.0#1d2a3b4c"
+
+[[package]]
+name = "wasmer"
+version = "5.0.4"
+
+[[package]]
+name = "wasmer"
+version = "6.0.0"
+"#;
+
+    #[test]
+    fn test_version_in() {
+        assert_eq!(version_in(LOCK, "wasmtime"), Some("30.0.2"));
+        // the tag of a git dependency
+        assert_eq!(version_in(LOCK, "wamr-rust-sdk"), Some("1.1.0"));
+        // not one version
+        assert_eq!(version_in(LOCK, "wasmer"), None);
+        assert_eq!(version_in(LOCK, "wasmedge-sdk"), None);
+    }
+}
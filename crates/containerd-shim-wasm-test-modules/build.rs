@@ -63,8 +63,17 @@ fn compile_rust(src: impl AsRef<Path>) -> Result<PathBuf> {
     let src = src.as_ref();
     let dst = output_for(src)?;
 
+    // the sources build modules, unless their first line asks for another target,
+    // e.g., `// target: wasm32-wasip2` for a component
+    let source = std::fs::read_to_string(src)?;
+    let target = source
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("// target: "))
+        .unwrap_or("wasm32-wasip1");
+
     Command::new(rustc)
-        .arg("--target=wasm32-wasip1")
+        .arg(format!("--target={}", target.trim()))
         .arg("-Copt-level=z")
         .arg("-Cstrip=symbols")
         .arg("-o")
@@ -0,0 +1,8 @@
+use std::io::Read;
+
+// like the `count-stdin` command of wasi-demo-app
+fn main() {
+    let mut input = Vec::new();
+    std::io::stdin().read_to_end(&mut input).unwrap();
+    println!("{}", input.len());
+}
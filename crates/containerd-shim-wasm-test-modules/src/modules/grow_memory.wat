@@ -0,0 +1,52 @@
+(module
+    ;; Grow the memory by 1MiB at a time, up to 128MiB, filling each new MiB,
+    ;; and print 'grew 128MiB\n', or 'out of memory\n' when a growth fails.
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 8) "grew 128MiB\n")
+    (data (i32.const 32) "out of memory\n")
+
+    (func $print (param $offset i32) (param $len i32)
+        (i32.store (i32.const 0) (local.get $offset))
+        (i32.store (i32.const 4) (local.get $len))
+        (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 48))
+        drop
+    )
+
+    (func $main (export "_start")
+        (local $previous i32)
+        (local $count i32)
+
+        (block $done
+            (loop $grow
+                (br_if $done (i32.eq (local.get $count) (i32.const 128)))
+
+                ;; 16 pages of 64KiB, the previous size in pages, or -1 on failure
+                (local.set $previous (memory.grow (i32.const 16)))
+                (if (i32.eq (local.get $previous) (i32.const -1))
+                    (then
+                        (call $print (i32.const 32) (i32.const 14))
+                        (call $proc_exit (i32.const 1))
+                        unreachable
+                    )
+                )
+
+                ;; fill the new memory, so that the process really uses it
+                (memory.fill
+                    (i32.mul (local.get $previous) (i32.const 65536))
+                    (i32.const 1)
+                    (i32.const 1048576)
+                )
+
+                (local.set $count (i32.add (local.get $count) (i32.const 1)))
+                (br $grow)
+            )
+        )
+
+        (call $print (i32.const 8) (i32.const 12))
+    )
+)
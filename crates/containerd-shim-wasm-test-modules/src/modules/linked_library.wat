@@ -0,0 +1,5 @@
+(module
+    (func (export "exit_code") (result i32)
+        (i32.const 42)
+    )
+)
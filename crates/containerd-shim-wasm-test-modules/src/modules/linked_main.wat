@@ -0,0 +1,12 @@
+(module
+    ;; Import the exit code from the `linked_library` module, which has to be
+    ;; provided as a layer of the image after this one.
+    (import "linked_library" "exit_code" (func $exit_code (result i32)))
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+    (memory 1)
+    (export "memory" (memory 0))
+    (func $main (export "_start")
+        (call $proc_exit (call $exit_code))
+        unreachable
+    )
+)
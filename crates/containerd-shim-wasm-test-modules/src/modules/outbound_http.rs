@@ -0,0 +1,22 @@
+// target: wasm32-wasip2
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+// makes an HTTP request to `HTTP_ADDR` with `wasi:sockets`, and prints the status line of
+// the response, or the kind of the error
+fn main() {
+    let addr = std::env::var("HTTP_ADDR").unwrap();
+    let response = TcpStream::connect(&addr).and_then(|mut stream| {
+        write!(stream, "GET / HTTP/1.0\r\nHost: {addr}\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    });
+    match response {
+        Ok(response) => println!("{}", response.lines().next().unwrap_or_default()),
+        Err(err) => {
+            println!("{:?}", err.kind());
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,5 @@
+fn main() {
+    for (key, value) in std::env::vars() {
+        println!("{key}={value}");
+    }
+}
@@ -0,0 +1,12 @@
+use std::fs::{read_to_string, write};
+
+fn main() {
+    // the directory is a mount of the container
+    let content = read_to_string("/mnt/data/hello.txt").expect("failed to read the mount");
+    print!("{content}");
+
+    match write("/mnt/data/new.txt", "new") {
+        Ok(_) => println!("writable"),
+        Err(_) => println!("read-only"),
+    }
+}
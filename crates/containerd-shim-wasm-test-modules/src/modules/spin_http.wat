@@ -0,0 +1,24 @@
+;; a wasi:http/proxy component that spins on each request, without ever responding
+(component
+  (import "wasi:http/types@0.2.1" (instance $types
+    (export "incoming-request" (type (sub resource)))
+    (export "response-outparam" (type (sub resource)))
+  ))
+  (alias export $types "incoming-request" (type $incoming-request))
+  (alias export $types "response-outparam" (type $response-outparam))
+
+  (core module $m
+    (func (export "handle") (param i32 i32)
+      (loop $spin
+        (br $spin)))
+  )
+  (core instance $i (instantiate $m))
+
+  (func $handle (param "request" (own $incoming-request)) (param "response-out" (own $response-outparam))
+    (canon lift (core func $i "handle"))
+  )
+  (instance $incoming-handler
+    (export "handle" (func $handle))
+  )
+  (export "wasi:http/incoming-handler@0.2.1" (instance $incoming-handler))
+)
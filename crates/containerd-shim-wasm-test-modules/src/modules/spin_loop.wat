@@ -0,0 +1,5 @@
+;; spins until it runs out of fuel, or is killed
+(func $main (export "_start")
+    (loop $spin
+        (br $spin))
+)
@@ -0,0 +1,11 @@
+use std::fs::write;
+
+fn main() {
+    // as the `write` command of wasi-demo-app, without panicking when the write fails
+    for path in ["/new.txt", "/mnt/data/new.txt"] {
+        match write(path, "new") {
+            Ok(_) => println!("{path}: writable"),
+            Err(_) => println!("{path}: read-only"),
+        }
+    }
+}
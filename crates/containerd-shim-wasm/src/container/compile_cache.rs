@@ -0,0 +1,245 @@
+//! A cache of the modules compiled by an engine, in a directory of the node.
+//!
+//! The precompiled layers of the content store of containerd only exist for the images pulled
+//! by containerd. The modules of the bundle, e.g., a `.wasm` file bind mounted with `ctr`, are
+//! compiled by every container instead. An engine keeps the code it compiled for them in the
+//! `CompileCacheDir` of the shim options, `/var/lib/runwasi/<engine>-cache` by default, and the
+//! next containers of the same module load it from there.
+//!
+//! Each artifact is a file named by its key, which the engine derives from the bytes of the
+//! module and its configuration. An artifact is written to a temporary file, then renamed in
+//! place, so that the containers starting at once never read a partial artifact. Once the
+//! artifacts are larger than `CompileCacheMaxSize`, the least recently used ones are removed,
+//! along with the temporary files that a crashed container left behind.
+
+use std::fs::{self, File, FileTimes};
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+/// Directory of the compile caches of the engines, `<engine>-cache` each
+pub const DEFAULT_COMPILE_CACHE_ROOT: &str = "/var/lib/runwasi";
+
+/// Size of the artifacts of a cache before the least recently used ones are removed, 1GiB
+pub const DEFAULT_COMPILE_CACHE_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Overrides the `compile_cache_dir` of the configuration file of the shim
+pub const COMPILE_CACHE_DIR_ENV: &str = "RUNWASI_COMPILE_CACHE_DIR";
+
+/// Age of a temporary file of the cache after which its write is taken to have crashed,
+/// far longer than writing the largest artifact
+const STALE_TMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The directory where an engine keeps the modules it compiled
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CompileCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl CompileCache {
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_size,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// The file of the artifact of `key`, if it's cached. The artifact is marked as used,
+    /// whether the filesystem updates the access times or not, e.g., with `noatime`.
+    pub fn get(&self, key: &str) -> Option<PathBuf> {
+        let path = self.path(key).ok()?;
+        let file = File::open(&path).ok()?;
+        let _ = file.set_times(FileTimes::new().set_accessed(SystemTime::now()));
+        Some(path)
+    }
+
+    /// Store `artifact` as the artifact of `key`, and remove the least recently used artifacts
+    /// past the maximum size of the cache. An artifact larger than the cache isn't stored.
+    pub fn put(&self, key: &str, artifact: &[u8]) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+        if artifact.len() as u64 > self.max_size {
+            log::debug!(
+                "not caching {key}, its {} bytes are more than the {} bytes of the cache",
+                artifact.len(),
+                self.max_size
+            );
+            return Ok(());
+        }
+
+        // a container starting at the same time renames its own file, with the same content.
+        // The temporary files start with a '.', which the keys can't, and are removed on errors.
+        let res = NamedTempFile::new_in(&self.dir).and_then(|mut tmp| {
+            tmp.write_all(artifact)?;
+            tmp.persist(&path)?;
+            Ok(())
+        });
+        res.with_context(|| format!("failed to write {}", path.display()))?;
+
+        self.evict()
+    }
+
+    /// Remove the artifact of `key`, e.g., one that the engine failed to load
+    pub fn remove(&self, key: &str) {
+        if let Ok(path) = self.path(key) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Remove the least recently used artifacts of the cache, until their size is at most the
+    /// maximum size of the cache. The temporary files of the writes in progress are left alone,
+    /// and the stale ones, left by a crashed write, are removed.
+    fn evict(&self) -> anyhow::Result<()> {
+        let mut files = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if entry.file_name().as_encoded_bytes().starts_with(b".") {
+                let age = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok())
+                    .unwrap_or_default();
+                if metadata.is_file() && age > STALE_TMP_AGE {
+                    log::debug!(
+                        "removing the stale {} of the compile cache",
+                        entry.path().display()
+                    );
+                    let _ = fs::remove_file(entry.path());
+                }
+                continue;
+            }
+            if metadata.is_file() {
+                let accessed = metadata.accessed().unwrap_or(SystemTime::UNIX_EPOCH);
+                files.push((accessed, metadata.len(), entry.path()));
+            }
+        }
+
+        let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+        files.sort();
+        for (_, len, path) in files {
+            if size <= self.max_size {
+                break;
+            }
+            log::debug!("evicting {} from the compile cache", path.display());
+            match fs::remove_file(&path) {
+                Ok(()) => size -= len,
+                // another container evicted it first
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => size -= len,
+                Err(err) => log::warn!("failed to evict {}: {err}", path.display()),
+            }
+        }
+        Ok(())
+    }
+
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        if key.is_empty() || key.starts_with('.') || key.contains('/') {
+            bail!("invalid compile cache key {key:?}");
+        }
+        Ok(self.dir.join(key))
+    }
+
+    /// Create the directory of the cache, and open it for the container process, which can only
+    /// reach it through the opened directory after the pivot root.
+    #[cfg(unix)]
+    pub(crate) fn open(&self) -> anyhow::Result<(Self, File)> {
+        use std::os::fd::AsRawFd as _;
+
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+        let dir = File::open(&self.dir)
+            .with_context(|| format!("failed to open {}", self.dir.display()))?;
+        let cache = Self::new(format!("/proc/self/fd/{}", dir.as_raw_fd()), self.max_size);
+        Ok((cache, dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_cache() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = CompileCache::new(dir.path(), 1024);
+
+        assert_eq!(cache.get("module.cwasm"), None);
+        cache.put("module.cwasm", b"compiled")?;
+        let path = cache.get("module.cwasm").context("missing artifact")?;
+        assert_eq!(fs::read(path)?, b"compiled");
+
+        // the temporary file was renamed
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        cache.remove("module.cwasm");
+        assert_eq!(cache.get("module.cwasm"), None);
+
+        assert!(cache.put("../module.cwasm", b"compiled").is_err());
+        assert!(cache.put(".module.cwasm", b"compiled").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_cache_temporary_files() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = CompileCache::new(dir.path(), 1024);
+
+        // the write of another container, and the one of a container that crashed
+        fs::write(dir.path().join(".tmp-writing"), [0; 2048])?;
+        fs::write(dir.path().join(".tmp-crashed"), [0; 2048])?;
+        let past = SystemTime::now() - STALE_TMP_AGE - Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(dir.path().join(".tmp-crashed"))?
+            .set_times(FileTimes::new().set_modified(past))?;
+
+        // the temporary files don't count towards the size of the cache
+        cache.put("a", &[0; 400])?;
+        assert!(cache.get("a").is_some());
+        assert!(dir.path().join(".tmp-writing").exists());
+        assert!(!dir.path().join(".tmp-crashed").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_cache_eviction() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = CompileCache::new(dir.path(), 1024);
+        let artifact = [0; 400];
+
+        cache.put("a", &artifact)?;
+        cache.put("b", &artifact)?;
+        // the access times have a coarse granularity on some filesystems
+        let past = SystemTime::now() - Duration::from_secs(60);
+        File::open(dir.path().join("b"))?.set_times(FileTimes::new().set_accessed(past))?;
+        File::open(dir.path().join("a"))?
+            .set_times(FileTimes::new().set_accessed(past - Duration::from_secs(60)))?;
+        // `a` is used again, and `b` is now the least recently used artifact
+        assert!(cache.get("a").is_some());
+
+        cache.put("c", &artifact)?;
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.get("b"), None);
+        assert!(cache.get("c").is_some());
+
+        // an artifact larger than the cache isn't stored
+        cache.put("d", &[0; 2048])?;
+        assert_eq!(cache.get("d"), None);
+        assert!(cache.get("a").is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+//! Per-container configuration of the engine, from the annotations of the OCI spec.
+//!
+//! The annotations `runwasi.io/engine.<key>: <value>` set the `<key>` of the configuration,
+//! e.g., with Kubernetes, as annotations of the pod when containerd passes them to the runtime.
+//! The annotations `io.runwasi.<engine>.<key>: <value>` set it for the engine named `<engine>`
+//! only, e.g., `io.runwasi.wasmtime.fuel`, over the key of `runwasi.io/engine.<key>`.
+//! Each engine documents the keys it supports.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::str::FromStr;
+
+use anyhow::{Result, bail};
+
+/// Prefix of the annotations with the engine configuration, followed by the key
+pub const ENGINE_CONFIG_ANNOTATION_PREFIX: &str = "runwasi.io/engine.";
+
+/// Prefix of the annotations with the configuration of a single engine, followed by
+/// the name of the engine, a dot, and the key
+pub const ENGINE_SCOPED_ANNOTATION_PREFIX: &str = "io.runwasi.";
+
+/// With `runwasi.io/engine-strict: "true"`, the container fails to be created
+/// when the engine doesn't support one of the keys, instead of ignoring it
+pub const ENGINE_CONFIG_STRICT_ANNOTATION: &str = "runwasi.io/engine-strict";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EngineConfig {
+    values: BTreeMap<String, String>,
+    /// The annotations of the keys set for the engine only
+    scoped: BTreeMap<String, String>,
+    strict: bool,
+}
+
+impl EngineConfig {
+    pub fn from_annotations(annotations: Option<&HashMap<String, String>>) -> Self {
+        let Some(annotations) = annotations else {
+            return Self::default();
+        };
+        let values = annotations
+            .iter()
+            .filter_map(|(name, value)| {
+                let key = name.strip_prefix(ENGINE_CONFIG_ANNOTATION_PREFIX)?;
+                Some((key.to_string(), value.clone()))
+            })
+            .collect();
+        let strict = annotations
+            .get(ENGINE_CONFIG_STRICT_ANNOTATION)
+            .is_some_and(|v| v == "true");
+        Self {
+            values,
+            scoped: BTreeMap::new(),
+            strict,
+        }
+    }
+
+    /// The configuration of the engine named `engine`, with the `io.runwasi.<engine>.<key>`
+    /// annotations over the `runwasi.io/engine.<key>` ones
+    pub fn for_engine(engine: &str, annotations: Option<&HashMap<String, String>>) -> Self {
+        let mut config = Self::from_annotations(annotations);
+        let prefix = format!("{ENGINE_SCOPED_ANNOTATION_PREFIX}{engine}.");
+        for (name, value) in annotations.into_iter().flatten() {
+            let Some(key) = name.strip_prefix(&prefix) else {
+                continue;
+            };
+            let previous = config.values.insert(key.to_string(), value.clone());
+            if previous.is_some_and(|previous| &previous != value) {
+                log::warn!(
+                    "the annotation {name} overrides {ENGINE_CONFIG_ANNOTATION_PREFIX}{key}"
+                );
+            }
+            config.scoped.insert(key.to_string(), name.clone());
+        }
+        config
+    }
+
+    /// Add a key, e.g., for an engine configured in code
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.values.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// The annotation that set `key`
+    fn annotation(&self, key: &str) -> String {
+        match self.scoped.get(key) {
+            Some(name) => name.clone(),
+            None => format!("{ENGINE_CONFIG_ANNOTATION_PREFIX}{key}"),
+        }
+    }
+
+    /// Parse the value of `key`, with an error naming the annotation when it is invalid
+    pub fn parse<T>(&self, key: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => bail!(
+                "invalid value {value:?} for annotation {}: {err}",
+                self.annotation(key)
+            ),
+        }
+    }
+
+    /// Check that all the keys are in `supported`.
+    /// Unsupported keys are an error in strict mode, and are ignored with a warning otherwise.
+    pub fn check_keys(&self, supported: &[&str]) -> Result<()> {
+        let unsupported = self
+            .values
+            .keys()
+            .filter(|key| !supported.contains(&key.as_str()))
+            .map(|key| self.annotation(key))
+            .collect::<Vec<_>>();
+        if unsupported.is_empty() {
+            return Ok(());
+        }
+        if self.strict {
+            bail!(
+                "unsupported engine configuration {}, the engine supports {supported:?}",
+                unsupported.join(", ")
+            );
+        }
+        log::warn!(
+            "ignoring unsupported engine configuration {}",
+            unsupported.join(", ")
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annotations(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_annotations() {
+        let annotations = annotations(&[
+            ("runwasi.io/engine.fuel", "1000"),
+            ("runwasi.io/entrypoint", "app.wasm"),
+            ("io.kubernetes.cri.sandbox-id", "pod"),
+        ]);
+        let config = EngineConfig::from_annotations(Some(&annotations));
+
+        assert_eq!(config.iter().collect::<Vec<_>>(), [("fuel", "1000")]);
+        assert!(!config.is_strict());
+        assert!(EngineConfig::from_annotations(None).is_empty());
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let config = EngineConfig::default()
+            .with("fuel", "1000")
+            .with("stack", "big");
+
+        assert_eq!(config.parse::<u64>("fuel")?, Some(1000));
+        assert_eq!(config.parse::<u64>("missing")?, None);
+        let err = config.parse::<u64>("stack").unwrap_err().to_string();
+        assert!(err.contains("runwasi.io/engine.stack"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_keys() {
+        let annotations = annotations(&[
+            ("runwasi.io/engine.fuel", "1000"),
+            ("runwasi.io/engine.unknown", "1"),
+        ]);
+        let config = EngineConfig::from_annotations(Some(&annotations));
+        assert!(config.check_keys(&["fuel"]).is_ok());
+
+        let config = config.with_strict(true);
+        assert!(config.check_keys(&["fuel", "unknown"]).is_ok());
+        let err = config.check_keys(&["fuel"]).unwrap_err().to_string();
+        assert!(err.contains("runwasi.io/engine.unknown"), "{err}");
+    }
+
+    #[test]
+    fn test_for_engine() -> Result<()> {
+        let annotations = annotations(&[
+            ("runwasi.io/engine.fuel", "1000"),
+            ("runwasi.io/engine.max-wasm-stack", "65536"),
+            ("io.runwasi.wasmtime.fuel", "2000"),
+            ("io.runwasi.wasmtime.stack", "big"),
+            ("io.runwasi.wasmedge.fuel", "3000"),
+            ("io.runwasi.env-policy", "{}"),
+        ]);
+        let config = EngineConfig::for_engine("wasmtime", Some(&annotations));
+
+        assert_eq!(
+            config.iter().collect::<Vec<_>>(),
+            [
+                ("fuel", "2000"),
+                ("max-wasm-stack", "65536"),
+                ("stack", "big")
+            ]
+        );
+        assert_eq!(config.parse::<u64>("fuel")?, Some(2000));
+        let err = config.parse::<u64>("stack").unwrap_err().to_string();
+        assert!(err.contains("io.runwasi.wasmtime.stack"), "{err}");
+
+        let config = config.with_strict(true);
+        let err = config.check_keys(&["fuel"]).unwrap_err().to_string();
+        assert!(err.contains("io.runwasi.wasmtime.stack"), "{err}");
+        assert!(err.contains("runwasi.io/engine.max-wasm-stack"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_annotation() {
+        let strict = annotations(&[(ENGINE_CONFIG_STRICT_ANNOTATION, "true")]);
+        assert!(EngineConfig::from_annotations(Some(&strict)).is_strict());
+
+        let not_strict = annotations(&[(ENGINE_CONFIG_STRICT_ANNOTATION, "false")]);
+        assert!(!EngineConfig::from_annotations(Some(&not_strict)).is_strict());
+    }
+}
@@ -1,12 +1,22 @@
-use std::borrow::Cow;
+use std::net::{SocketAddr, TcpListener};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, bail};
-use oci_spec::image::Platform;
-use oci_spec::runtime::Spec;
+use oci_spec::image::{Config as ImageConfig, Platform};
+use oci_spec::runtime::{LinuxResources, Mount, PosixRlimitType, Spec};
 
 use crate::container::path::PathResolve;
-use crate::sandbox::oci::WasmLayer;
+use crate::container::wasm::function_exports;
+use crate::container::{CompileCache, EngineConfig, ExecutionMode};
+use crate::sandbox::SocketPolicy;
+use crate::sandbox::listen::mode_from_annotations;
+use crate::sandbox::oci::{ImageInfo, ModuleBytes, WasmLayer};
+
+/// Annotation with the `path#func` entrypoint of a container without arguments,
+/// e.g., for a Wasm OCI artifact, as its config has no entrypoint.
+pub const ENTRYPOINT_ANNOTATION: &str = "runwasi.io/entrypoint";
 
 /// The `RuntimeContext` trait provides access to the runtime context that includes
 /// the arguments, environment variables, and entrypoint for the container.
@@ -31,12 +41,37 @@ pub trait RuntimeContext {
     //   "/app/app.wasm#entry" -> { source: File("/app/app.wasm"), func: "entry", name: "Some(app)", arg0: "/app/app.wasm#entry" }
     //   "my_module.wat" -> { source: File("my_module.wat"), func: "_start", name: "Some(my_module)", arg0: "my_module.wat" }
     //   "#init" -> { source: File(""), func: "init", name: None, arg0: "#init" }
+    //
+    // With wasm layers in the image, `path` can be the title of a layer, with or without its extension,
+    // to call the export of that layer, and a single word on an image with a single layer is the export, e.g.:
+    //   "library#init" -> { source: Oci([library.wasm]), func: "init", name: "Some(library)", arg0: "library#init" }
+    //   "init" -> { source: Oci([app.wasm]), func: "init", name: "Some(app)", arg0: "init" }
+    // The following arguments are the arguments of the guest. Creating the container fails when the
+    // module doesn't export the function.
+    //
+    // Without arguments, e.g., for a Wasm OCI artifact, the entrypoint is read from the
+    // `runwasi.io/entrypoint` annotation, and the name defaults to the title of the first layer.
     fn entrypoint(&self) -> Entrypoint;
 
     // the platform for the container using the struct defined on the OCI spec definition
     // https://github.com/opencontainers/image-spec/blob/v1.1.0-rc5/image-index.md
+    // It is the platform of the manifest selected in the index of a multi-platform image, e.g., `wasip2`/`wasm`,
+    // or the default platform without an image, e.g., for a module file of the bundle.
     fn platform(&self) -> &Platform;
 
+    // ctx.image_config() returns the `Entrypoint`, `Cmd` and `Env` of the image, as in its config blob,
+    // or `None` without an image, e.g., for a module file of the bundle.
+    // The args and the env of the OCI spec already include them, unless the container overrides them.
+    fn image_config(&self) -> Option<&ImageConfig> {
+        None
+    }
+
+    // ctx.manifest_digest() returns the digest of the manifest of the image, e.g., `sha256:...`,
+    // or `None` without an image.
+    fn manifest_digest(&self) -> Option<&str> {
+        None
+    }
+
     // the container id for the running container
     fn container_id(&self) -> &str;
 
@@ -46,6 +81,86 @@ pub trait RuntimeContext {
     fn pod_id(&self) -> Option<&str> {
         None
     }
+
+    // ctx.engine_config() returns the configuration of the engine for this container, from the
+    // `runwasi.io/engine.<key>` annotations of the OCI spec, and the `io.runwasi.<engine>.<key>` ones.
+    // Engines apply the keys they support, after checking them in `Engine::validate_engine_config`.
+    fn engine_config(&self) -> EngineConfig {
+        EngineConfig::default()
+    }
+
+    // ctx.preopens() returns the directories bind mounted in the container, e.g., the volumes of a pod,
+    // that engines preopen for the guest in addition to `/`, with read-only capabilities for read-only mounts.
+    fn preopens(&self) -> Vec<Preopen> {
+        vec![]
+    }
+
+    // ctx.readonly_rootfs() returns `root.readonly` in the OCI spec, e.g., for the `readOnlyRootFilesystem`
+    // of a pod, in which case engines preopen `/` with read capabilities only.
+    // The preopens of the read-write mounts keep their write capabilities.
+    fn readonly_rootfs(&self) -> bool {
+        false
+    }
+
+    // ctx.memory_limit() returns the memory limit of the container in bytes, from `linux.resources.memory.limit`
+    // in the OCI spec, or `None` when the container has no memory limit.
+    // The cgroup of the container enforces it on the whole process, engines can also enforce it on the
+    // memories of the guest, so that the guest traps instead of the process being killed.
+    fn memory_limit(&self) -> Option<u64> {
+        None
+    }
+
+    // ctx.cpu_quota() returns the CPU quota of the container, from `linux.resources.cpu.quota` and `period`
+    // in the OCI spec, or `None` when the container has no CPU quota.
+    fn cpu_quota(&self) -> Option<CpuQuota> {
+        None
+    }
+
+    // ctx.cpu_time_limit() returns the CPU time the container can use, from the soft `RLIMIT_CPU` of
+    // `process.rlimits` in the OCI spec, or `None` when the container has no such limit.
+    // The kernel signals the process past it, engines can also stop the guest before, with a clearer error.
+    fn cpu_time_limit(&self) -> Option<Duration> {
+        None
+    }
+
+    // ctx.pids_limit() returns the maximum number of processes of the container, from `linux.resources.pids.limit`
+    // in the OCI spec, or `None` when the container has no pids limit.
+    fn pids_limit(&self) -> Option<u64> {
+        None
+    }
+
+    // ctx.listeners() returns the sockets that the shim bound for the container, from the
+    // `io.runwasi.listen` annotation, e.g., `tcp://0.0.0.0:8080`.
+    // Engines serve them, or hand them to the guest, instead of binding their own sockets.
+    fn listeners(&self) -> &[Listener] {
+        &[]
+    }
+
+    // ctx.execution_mode() returns the mode of the `io.runwasi.mode` annotation, `ExecutionMode::Serve`
+    // for `http`, or `None` without the annotation, in which case the engine picks the mode.
+    fn execution_mode(&self) -> Option<ExecutionMode> {
+        None
+    }
+
+    // ctx.socket_policy() returns the sockets that the guest can use with `wasi:sockets`: the ones of the
+    // `io.runwasi.socket-policy` annotation that the policy of the node allows, or none without the annotation.
+    // Engines check the addresses that the guest binds and connects to against it.
+    fn socket_policy(&self) -> SocketPolicy {
+        SocketPolicy::default()
+    }
+
+    // ctx.memory_reserve() returns the memory to keep out of the memory limit of the guest, in bytes, for the
+    // allocations of the engine, from the `MemoryReserve` shim option, or `None` for the default of the engine.
+    fn memory_reserve(&self) -> Option<u64> {
+        None
+    }
+
+    // ctx.compile_cache() returns the directory where the engine keeps the modules it compiles, from the
+    // `CompileCacheDir` shim option, or `None` when the cache is disabled or the engine doesn't cache.
+    // Engines look up the modules of the container there before compiling them, and store the ones they compiled.
+    fn compile_cache(&self) -> Option<&CompileCache> {
+        None
+    }
 }
 
 /// The source for a WASI module / components.
@@ -64,21 +179,123 @@ pub enum Source<'a> {
 }
 
 impl<'a> Source<'a> {
-    pub fn as_bytes(&self) -> anyhow::Result<Cow<'a, [u8]>> {
+    /// The bytes of the single module / component of the source.
+    /// Use `modules` for engines that can compose more than one.
+    pub fn as_bytes(&self) -> anyhow::Result<ModuleBytes<'a>> {
         match self {
             Source::File(path) => {
                 let path = path
                     .resolve_in_path_or_cwd()
                     .next()
                     .context("module not found")?;
-                Ok(Cow::Owned(std::fs::read(path)?))
+                Ok(ModuleBytes::map(path)?)
             }
-            Source::Oci([module]) => Ok(Cow::Borrowed(&module.layer)),
-            Source::Oci(_modules) => {
-                bail!("only a single module is supported when using images with OCI layers")
+            Source::Oci([module]) => Ok(module.bytes()?),
+            Source::Oci(modules) => {
+                bail!(
+                    "only a single module is supported when using images with OCI layers, found {}",
+                    modules.len()
+                )
             }
         }
     }
+
+    /// The file of the single OCI layer of the source, when the layer is large enough
+    /// to be kept in a file instead of in memory.
+    /// Engines that can load a module from a file should prefer it to `as_bytes`.
+    pub fn layer_path(&self) -> Option<&'a Path> {
+        match self {
+            Source::Oci([module]) => module.path(),
+            _ => None,
+        }
+    }
+
+    /// All the modules / components of the source, in the order of the layers of the image.
+    /// It is up to the engine to compose them, or to reject more than one.
+    pub fn modules(&self) -> anyhow::Result<Vec<WasmModule<'a>>> {
+        match self {
+            Source::File(path) => Ok(vec![WasmModule {
+                name: path
+                    .file_stem()
+                    .map(|name| name.to_string_lossy().to_string()),
+                media_type: None,
+                path: None,
+                bytes: self.as_bytes()?,
+            }]),
+            Source::Oci(layers) => Ok(layers
+                .iter()
+                .map(|layer| WasmModule {
+                    name: layer
+                        .name()
+                        .and_then(|name| Path::new(name).file_stem())
+                        .map(|name| name.to_string_lossy().to_string()),
+                    media_type: Some(layer.config.media_type().to_string()),
+                    path: layer.path(),
+                    bytes: ModuleBytes::Borrowed(&layer.layer),
+                })
+                .collect()),
+        }
+    }
+}
+
+/// A directory preopened for the guest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Preopen {
+    // The directory in the container process. The engine runs in the rootfs of the container,
+    // so this is the destination of the mount, where its source is bind mounted.
+    pub host_path: PathBuf,
+    // The path of the directory for the guest.
+    pub guest_path: String,
+    // Whether the guest can only read the directory, for a read-only mount.
+    pub read_only: bool,
+}
+
+/// A listening socket bound by the shim, which keeps it open across restarts of the container.
+#[derive(Clone, Debug)]
+pub struct Listener {
+    // The address the socket is bound to.
+    pub addr: SocketAddr,
+    // The listening socket, use `try_clone` to own it, e.g., with `tokio::net::TcpListener::from_std`.
+    pub socket: Arc<TcpListener>,
+}
+
+/// The CPU time a container can use in each period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuQuota {
+    pub quota: Duration,
+    pub period: Duration,
+}
+
+impl CpuQuota {
+    /// The number of CPUs the quota amounts to, e.g., `0.5` for half a CPU
+    pub fn cpus(&self) -> f64 {
+        self.quota.as_secs_f64() / self.period.as_secs_f64()
+    }
+}
+
+/// Period of the CPU quota when the spec has none, as for the CFS scheduler
+const DEFAULT_CPU_PERIOD: Duration = Duration::from_millis(100);
+
+/// A WASI module / component of a `Source`.
+#[derive(Debug)]
+pub struct WasmModule<'a> {
+    // The file name of the module, or the title of its layer, without the extension.
+    pub name: Option<String>,
+    // The media type of the layer, for a module provided as a layer in the OCI spec.
+    pub media_type: Option<String>,
+    // The file of a large layer, which engines can load without reading it in memory.
+    pub path: Option<&'a Path>,
+    bytes: ModuleBytes<'a>,
+}
+
+impl WasmModule<'_> {
+    /// The bytes of the module, mapped from its file for a large layer
+    pub fn bytes(&self) -> anyhow::Result<ModuleBytes<'_>> {
+        match self.path {
+            Some(path) => Ok(ModuleBytes::map(path)?),
+            None => Ok(ModuleBytes::Borrowed(&self.bytes)),
+        }
+    }
 }
 
 /// The entrypoint for a WASI module / component.
@@ -93,7 +310,13 @@ pub struct Entrypoint<'a> {
 pub(crate) struct WasiContext<'a> {
     pub spec: &'a Spec,
     pub wasm_layers: &'a [WasmLayer],
-    pub platform: &'a Platform,
+    pub image: &'a ImageInfo,
+    pub listeners: &'a [Listener],
+    pub socket_policy: &'a SocketPolicy,
+    pub memory_reserve: Option<u64>,
+    pub compile_cache: Option<&'a CompileCache>,
+    /// The name of the engine, for its `io.runwasi.<engine>.<key>` annotations
+    pub engine: &'static str,
     pub id: String,
 }
 
@@ -119,37 +342,82 @@ impl RuntimeContext for WasiContext<'_> {
     fn entrypoint(&self) -> Entrypoint {
         let arg0 = self.args().first();
 
-        let entry_point = arg0.map(String::as_str).unwrap_or("");
-        let (path, func) = entry_point
-            .split_once('#')
-            .unwrap_or((entry_point, "_start"));
-
-        let source = if self.wasm_layers.is_empty() {
-            Source::File(PathBuf::from(path))
-        } else {
-            Source::Oci(self.wasm_layers)
+        let entry_point = match arg0 {
+            Some(arg0) => arg0.as_str(),
+            None => self
+                .spec
+                .annotations()
+                .as_ref()
+                .and_then(|a| a.get(ENTRYPOINT_ANNOTATION))
+                .map(String::as_str)
+                .unwrap_or(""),
         };
+        let arg0 = arg0.map(Path::new);
+
+        if self.wasm_layers.is_empty() {
+            let (path, func) = entry_point
+                .split_once('#')
+                .unwrap_or((entry_point, "_start"));
+            return Entrypoint {
+                func: func.to_string(),
+                arg0,
+                source: Source::File(PathBuf::from(path)),
+                name: file_stem(path),
+            };
+        }
 
-        let module_name = PathBuf::from(path)
-            .file_stem()
-            .map(|name| name.to_string_lossy().to_string());
-
+        let (layers, name, func) = oci_entrypoint(self.wasm_layers, entry_point);
         Entrypoint {
-            func: func.to_string(),
-            arg0: arg0.map(Path::new),
-            source,
-            name: module_name,
+            func,
+            arg0,
+            source: Source::Oci(layers),
+            name,
         }
     }
 
     fn platform(&self) -> &Platform {
-        self.platform
+        &self.image.platform
+    }
+
+    fn image_config(&self) -> Option<&ImageConfig> {
+        self.image.config.as_ref()
+    }
+
+    fn manifest_digest(&self) -> Option<&str> {
+        self.image.manifest_digest.as_deref()
     }
 
     fn container_id(&self) -> &str {
         &self.id
     }
 
+    fn listeners(&self) -> &[Listener] {
+        self.listeners
+    }
+
+    fn execution_mode(&self) -> Option<ExecutionMode> {
+        // checked when the container is created
+        mode_from_annotations(self.spec.annotations().as_ref())
+            .ok()
+            .flatten()
+    }
+
+    fn socket_policy(&self) -> SocketPolicy {
+        // checked when the container is created
+        let container = SocketPolicy::from_annotations(self.spec.annotations().as_ref())
+            .ok()
+            .flatten();
+        self.socket_policy.for_container(container.as_ref())
+    }
+
+    fn memory_reserve(&self) -> Option<u64> {
+        self.memory_reserve
+    }
+
+    fn compile_cache(&self) -> Option<&CompileCache> {
+        self.compile_cache
+    }
+
     fn pod_id(&self) -> Option<&str> {
         self.spec
             .annotations()
@@ -157,16 +425,205 @@ impl RuntimeContext for WasiContext<'_> {
             .and_then(|a| a.get("io.kubernetes.cri.sandbox-id"))
             .map(|s| s.as_str())
     }
+
+    fn engine_config(&self) -> EngineConfig {
+        EngineConfig::for_engine(self.engine, self.spec.annotations().as_ref())
+    }
+
+    fn memory_limit(&self) -> Option<u64> {
+        let limit = self.resources()?.memory().as_ref()?.limit()?;
+        // a negative limit is no limit
+        u64::try_from(limit).ok().filter(|l| *l > 0)
+    }
+
+    fn cpu_quota(&self) -> Option<CpuQuota> {
+        let cpu = self.resources()?.cpu().as_ref()?;
+        let quota = u64::try_from(cpu.quota()?).ok().filter(|q| *q > 0)?;
+        let period = cpu
+            .period()
+            .filter(|p| *p > 0)
+            .map_or(DEFAULT_CPU_PERIOD, Duration::from_micros);
+        Some(CpuQuota {
+            quota: Duration::from_micros(quota),
+            period,
+        })
+    }
+
+    fn cpu_time_limit(&self) -> Option<Duration> {
+        let rlimits = self.spec.process().as_ref()?.rlimits().as_ref()?;
+        rlimits
+            .iter()
+            .find(|rlimit| rlimit.typ() == PosixRlimitType::RlimitCpu)
+            // RLIM_INFINITY is no limit
+            .map(|rlimit| rlimit.soft())
+            .filter(|&secs| secs != u64::MAX)
+            .map(Duration::from_secs)
+    }
+
+    fn pids_limit(&self) -> Option<u64> {
+        let limit = self.resources()?.pids().as_ref()?.limit();
+        u64::try_from(limit).ok().filter(|l| *l > 0)
+    }
+
+    fn preopens(&self) -> Vec<Preopen> {
+        self.spec
+            .mounts()
+            .iter()
+            .flatten()
+            .filter_map(preopen_of_mount)
+            // bind mounted files, e.g., `/etc/hosts`, are visible in the preopen of `/`
+            .filter(|preopen| preopen.host_path.is_dir())
+            .collect()
+    }
+
+    fn readonly_rootfs(&self) -> bool {
+        self.spec
+            .root()
+            .as_ref()
+            .and_then(|root| root.readonly())
+            .unwrap_or_default()
+    }
+}
+
+impl Entrypoint<'_> {
+    /// Check that the module of the wasm layers exports the function of the entrypoint.
+    /// Components, precompiled modules and files are left to the engine, when it runs them.
+    pub(crate) fn check_export(&self) -> anyhow::Result<()> {
+        let Source::Oci([layer, ..]) = self.source else {
+            return Ok(());
+        };
+        let Some(exports) = function_exports(&layer.bytes()?) else {
+            return Ok(());
+        };
+        if !exports.contains(&self.func) {
+            bail!(
+                "the module {} doesn't export the function {:?}, its exports are: {}",
+                self.name.as_deref().unwrap_or_default(),
+                self.func,
+                exports.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+impl WasiContext<'_> {
+    fn resources(&self) -> Option<&LinuxResources> {
+        self.spec.linux().as_ref()?.resources().as_ref()
+    }
+}
+
+/// The layers, the module name, and the function of the entrypoint of an image with wasm layers,
+/// in this order:
+///   - `<name>#<export>`, where `<name>` is the title of a layer, with or without its extension,
+///     calls the export of that layer, which runs alone unless it is the first layer
+///   - `<export>`, without a path, calls that export of the module of a single layer image
+///   - anything else calls the `#<export>` of the first layer, `_start` by default
+fn oci_entrypoint<'a>(
+    layers: &'a [WasmLayer],
+    entrypoint: &str,
+) -> (&'a [WasmLayer], Option<String>, String) {
+    let first_layer_name = || file_stem(layers.first()?.name()?);
+
+    if let Some((name, func)) = entrypoint.split_once('#') {
+        let name = Path::new(name)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let layer = layers.iter().position(|layer| {
+            layer
+                .name()
+                .is_some_and(|title| title == name || file_stem(title).as_ref() == Some(&name))
+        });
+        return match layer {
+            Some(0) => (layers, first_layer_name(), func.to_string()),
+            Some(i) => (
+                std::slice::from_ref(&layers[i]),
+                file_stem(layers[i].name().unwrap_or_default()),
+                func.to_string(),
+            ),
+            None => (
+                layers,
+                file_stem(&name).or_else(first_layer_name),
+                func.to_string(),
+            ),
+        };
+    }
+
+    let path = Path::new(entrypoint);
+    let is_export = layers.len() == 1
+        && !entrypoint.is_empty()
+        && path.components().count() == 1
+        && path
+            .extension()
+            .is_none_or(|ext| ext != "wasm" && ext != "wat");
+    if is_export {
+        return (layers, first_layer_name(), entrypoint.to_string());
+    }
+
+    (
+        layers,
+        file_stem(entrypoint).or_else(first_layer_name),
+        "_start".to_string(),
+    )
+}
+
+/// The file name of a path, without its extension
+fn file_stem(path: &str) -> Option<String> {
+    Path::new(path)
+        .file_stem()
+        .map(|name| name.to_string_lossy().to_string())
+}
+
+/// Mounts of the container runtime, that are not for the guest
+const SYSTEM_MOUNTS: &[&str] = &["/proc", "/sys", "/dev"];
+
+fn preopen_of_mount(mount: &Mount) -> Option<Preopen> {
+    let options = mount.options().as_deref().unwrap_or_default();
+    let is_bind = mount.typ().as_deref() == Some("bind")
+        || options.iter().any(|o| o == "bind" || o == "rbind");
+    let destination = mount.destination();
+    if !is_bind
+        || destination == Path::new("/")
+        || SYSTEM_MOUNTS.iter().any(|dir| destination.starts_with(dir))
+    {
+        return None;
+    }
+    Some(Preopen {
+        host_path: destination.clone(),
+        guest_path: destination.to_string_lossy().to_string(),
+        read_only: options.iter().any(|o| o == "ro"),
+    })
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::LazyLock;
+
     use anyhow::Result;
     use oci_spec::image::{Descriptor, Digest};
     use oci_spec::runtime::{ProcessBuilder, RootBuilder, SpecBuilder};
 
     use super::*;
 
+    /// The context of a container of `spec`, without layers, listeners or limits of the shim
+    fn ctx(spec: &Spec) -> WasiContext<'_> {
+        static IMAGE: LazyLock<ImageInfo> = LazyLock::new(ImageInfo::default);
+        static SOCKET_POLICY: LazyLock<SocketPolicy> = LazyLock::new(SocketPolicy::default);
+        WasiContext {
+            spec,
+            wasm_layers: &[],
+            image: &IMAGE,
+            id: "test".to_string(),
+            listeners: &[],
+            socket_policy: &SOCKET_POLICY,
+            memory_reserve: None,
+            compile_cache: None,
+            engine: "test",
+        }
+    }
+
     #[test]
     fn test_get_args() -> Result<()> {
         let spec = SpecBuilder::default()
@@ -179,12 +636,7 @@ mod tests {
             )
             .build()?;
 
-        let ctx = WasiContext {
-            spec: &spec,
-            wasm_layers: &[],
-            platform: &Platform::default(),
-            id: "test".to_string(),
-        };
+        let ctx = ctx(&spec);
 
         let args = ctx.args();
         assert_eq!(args.len(), 1);
@@ -200,12 +652,7 @@ mod tests {
             .process(ProcessBuilder::default().cwd("/").args(vec![]).build()?)
             .build()?;
 
-        let ctx = WasiContext {
-            spec: &spec,
-            wasm_layers: &[],
-            platform: &Platform::default(),
-            id: "test".to_string(),
-        };
+        let ctx = ctx(&spec);
 
         let args = ctx.args();
         assert_eq!(args.len(), 0);
@@ -229,12 +676,7 @@ mod tests {
             )
             .build()?;
 
-        let ctx = WasiContext {
-            spec: &spec,
-            wasm_layers: &[],
-            platform: &Platform::default(),
-            id: "test".to_string(),
-        };
+        let ctx = ctx(&spec);
 
         let args = ctx.args();
         assert_eq!(args.len(), 3);
@@ -252,12 +694,7 @@ mod tests {
             .process(ProcessBuilder::default().cwd("/").args(vec![]).build()?)
             .build()?;
 
-        let ctx = WasiContext {
-            spec: &spec,
-            wasm_layers: &[],
-            platform: &Platform::default(),
-            id: "test".to_string(),
-        };
+        let ctx = ctx(&spec);
 
         let path = ctx.entrypoint().source;
         assert!(matches!(
@@ -284,12 +721,7 @@ mod tests {
             )
             .build()?;
 
-        let ctx = WasiContext {
-            spec: &spec,
-            wasm_layers: &[],
-            platform: &Platform::default(),
-            id: "test".to_string(),
-        };
+        let ctx = ctx(&spec);
 
         let expected_path = PathBuf::from("hello.wat");
         let Entrypoint {
@@ -325,12 +757,7 @@ mod tests {
             )
             .build()?;
 
-        let ctx = WasiContext {
-            spec: &spec,
-            wasm_layers: &[],
-            platform: &Platform::default(),
-            id: "test".to_string(),
-        };
+        let ctx = ctx(&spec);
 
         let expected_path = PathBuf::from("/root/hello.wat");
         let Entrypoint {
@@ -366,12 +793,7 @@ mod tests {
             )
             .build()?;
 
-        let ctx = WasiContext {
-            spec: &spec,
-            wasm_layers: &[],
-            platform: &Platform::default(),
-            id: "test".to_string(),
-        };
+        let ctx = ctx(&spec);
 
         let expected_path = PathBuf::from("/root/hello.wat");
         assert!(matches!(
@@ -399,17 +821,16 @@ mod tests {
             .build()?;
 
         let ctx = WasiContext {
-            spec: &spec,
             wasm_layers: &[WasmLayer {
                 layer: vec![],
+                path: None,
                 config: Descriptor::new(
                     oci_spec::image::MediaType::Other("".to_string()),
                     10,
                     Digest::try_from(format!("sha256:{:064?}", 0))?,
                 ),
             }],
-            platform: &Platform::default(),
-            id: "test".to_string(),
+            ..ctx(&spec)
         };
 
         assert!(matches!(ctx.entrypoint().source, Source::Oci(_)));
@@ -417,6 +838,227 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_modules_keep_layers_order() -> Result<()> {
+        use std::collections::HashMap;
+
+        let layer = |title: &str, bytes: &[u8]| -> Result<WasmLayer> {
+            let mut config = Descriptor::new(
+                oci_spec::image::MediaType::Other("application/wasm".to_string()),
+                bytes.len() as u64,
+                Digest::try_from(format!("sha256:{:064?}", bytes[0]))?,
+            );
+            config.set_annotations(Some(HashMap::from([(
+                oci_spec::image::ANNOTATION_TITLE.to_string(),
+                title.to_string(),
+            )])));
+            Ok(WasmLayer {
+                layer: bytes.to_vec(),
+                path: None,
+                config,
+            })
+        };
+        let layers = [layer("main.wasm", &[1])?, layer("library.wasm", &[2])?];
+
+        let modules = Source::Oci(&layers).modules()?;
+        assert_eq!(modules.len(), 2);
+        assert_eq!(modules[0].name.as_deref(), Some("main"));
+        assert_eq!(modules[0].bytes()?.as_ref(), &[1]);
+        assert_eq!(modules[1].name.as_deref(), Some("library"));
+        assert_eq!(modules[1].media_type.as_deref(), Some("application/wasm"));
+        assert!(Source::Oci(&layers).as_bytes().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_layer_is_read_from_its_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("layer");
+        std::fs::write(&path, b"module")?;
+
+        let layers = [WasmLayer {
+            layer: vec![],
+            path: Some(path.clone()),
+            config: Descriptor::new(
+                oci_spec::image::MediaType::Other("application/wasm".to_string()),
+                6,
+                Digest::try_from(format!("sha256:{:064?}", 0))?,
+            ),
+        }];
+
+        let source = Source::Oci(&layers);
+        assert_eq!(source.layer_path(), Some(path.as_path()));
+        assert_eq!(source.as_bytes()?.as_ref(), b"module");
+        assert_eq!(source.modules()?[0].bytes()?.as_ref(), b"module");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preopens_of_bind_mounts() -> Result<()> {
+        use oci_spec::runtime::MountBuilder;
+
+        let data = tempfile::tempdir()?;
+        let config = tempfile::tempdir()?;
+        let hosts = tempfile::NamedTempFile::new()?;
+        let bind = |destination: &Path, options: &[&str]| {
+            MountBuilder::default()
+                .destination(destination)
+                .typ("bind")
+                .source("/host")
+                .options(options.iter().map(|o| o.to_string()).collect::<Vec<_>>())
+                .build()
+        };
+
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(ProcessBuilder::default().cwd("/").build()?)
+            .mounts(vec![
+                MountBuilder::default()
+                    .destination("/proc")
+                    .typ("proc")
+                    .source("proc")
+                    .build()?,
+                bind(Path::new("/dev/shm"), &["rbind"])?,
+                bind(data.path(), &["rbind", "rw"])?,
+                bind(config.path(), &["rbind", "ro"])?,
+                bind(hosts.path(), &["rbind", "rw"])?,
+            ])
+            .build()?;
+
+        let ctx = ctx(&spec);
+
+        let preopens = ctx.preopens();
+        assert_eq!(
+            preopens,
+            [
+                Preopen {
+                    host_path: data.path().to_path_buf(),
+                    guest_path: data.path().to_string_lossy().to_string(),
+                    read_only: false,
+                },
+                Preopen {
+                    host_path: config.path().to_path_buf(),
+                    guest_path: config.path().to_string_lossy().to_string(),
+                    read_only: true,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_readonly_rootfs() -> Result<()> {
+        let readonly = |readonly: Option<bool>| -> Result<bool> {
+            let mut root = RootBuilder::default().path("rootfs");
+            if let Some(readonly) = readonly {
+                root = root.readonly(readonly);
+            }
+            let spec = SpecBuilder::default()
+                .root(root.build()?)
+                .process(ProcessBuilder::default().cwd("/").build()?)
+                .build()?;
+            let ctx = ctx(&spec);
+            Ok(ctx.readonly_rootfs())
+        };
+
+        assert!(readonly(Some(true))?);
+        assert!(!readonly(Some(false))?);
+        assert!(!readonly(None)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_limits() -> Result<()> {
+        use oci_spec::runtime::{
+            LinuxBuilder, LinuxCpuBuilder, LinuxMemoryBuilder, LinuxPidsBuilder,
+            LinuxResourcesBuilder, PosixRlimitBuilder,
+        };
+
+        let spec = |resources: LinuxResources| -> Result<Spec> {
+            Ok(SpecBuilder::default()
+                .root(RootBuilder::default().path("rootfs").build()?)
+                .process(ProcessBuilder::default().cwd("/").build()?)
+                .linux(LinuxBuilder::default().resources(resources).build()?)
+                .build()?)
+        };
+        let limited = spec(
+            LinuxResourcesBuilder::default()
+                .memory(
+                    LinuxMemoryBuilder::default()
+                        .limit(64 * 1024 * 1024)
+                        .build()?,
+                )
+                .cpu(LinuxCpuBuilder::default().quota(50_000).build()?)
+                .pids(LinuxPidsBuilder::default().limit(32).build()?)
+                .build()?,
+        )?;
+        let ctx_limited = ctx(&limited);
+        assert_eq!(ctx_limited.memory_limit(), Some(64 * 1024 * 1024));
+        assert_eq!(
+            ctx_limited.cpu_quota(),
+            Some(CpuQuota {
+                quota: Duration::from_millis(50),
+                period: Duration::from_millis(100),
+            })
+        );
+        assert_eq!(ctx_limited.cpu_quota().map(|q| q.cpus()), Some(0.5));
+        assert_eq!(ctx_limited.pids_limit(), Some(32));
+
+        // -1 is no limit
+        let unlimited = spec(
+            LinuxResourcesBuilder::default()
+                .memory(LinuxMemoryBuilder::default().limit(-1).build()?)
+                .cpu(
+                    LinuxCpuBuilder::default()
+                        .quota(-1)
+                        .period(100_000u64)
+                        .build()?,
+                )
+                .pids(LinuxPidsBuilder::default().limit(-1).build()?)
+                .build()?,
+        )?;
+        let ctx_unlimited = ctx(&unlimited);
+        assert_eq!(ctx_unlimited.memory_limit(), None);
+        assert_eq!(ctx_unlimited.cpu_quota(), None);
+        assert_eq!(ctx_unlimited.pids_limit(), None);
+
+        let none = spec(LinuxResources::default())?;
+        assert_eq!(ctx(&none).memory_limit(), None);
+        assert_eq!(ctx(&none).cpu_quota(), None);
+        assert_eq!(ctx(&none).cpu_time_limit(), None);
+
+        let rlimit = |soft: u64| {
+            PosixRlimitBuilder::default()
+                .typ(PosixRlimitType::RlimitCpu)
+                .soft(soft)
+                .hard(u64::MAX)
+                .build()
+        };
+        let spec_with_rlimit = |soft: u64| -> Result<Spec> {
+            Ok(SpecBuilder::default()
+                .root(RootBuilder::default().path("rootfs").build()?)
+                .process(
+                    ProcessBuilder::default()
+                        .cwd("/")
+                        .rlimits(vec![rlimit(soft)?])
+                        .build()?,
+                )
+                .build()?)
+        };
+        let cpu_limited = spec_with_rlimit(30)?;
+        assert_eq!(
+            ctx(&cpu_limited).cpu_time_limit(),
+            Some(Duration::from_secs(30))
+        );
+        let cpu_unlimited = spec_with_rlimit(u64::MAX)?;
+        assert_eq!(ctx(&cpu_unlimited).cpu_time_limit(), None);
+
+        Ok(())
+    }
+
     #[test]
     fn test_get_envs() -> Result<()> {
         let spec = SpecBuilder::default()
@@ -429,12 +1071,7 @@ mod tests {
             )
             .build()?;
 
-        let ctx = WasiContext {
-            spec: &spec,
-            wasm_layers: &[],
-            platform: &Platform::default(),
-            id: "test".to_string(),
-        };
+        let ctx = ctx(&spec);
 
         let envs = ctx.envs();
         assert_eq!(envs.len(), 2);
@@ -451,12 +1088,7 @@ mod tests {
             .process(ProcessBuilder::default().cwd("/").env(vec![]).build()?)
             .build()?;
 
-        let ctx = WasiContext {
-            spec: &spec,
-            wasm_layers: &[],
-            platform: &Platform::default(),
-            id: "test".to_string(),
-        };
+        let ctx = ctx(&spec);
 
         let envs = ctx.envs();
         assert_eq!(envs.len(), 0);
@@ -471,12 +1103,7 @@ mod tests {
             .process(ProcessBuilder::default().cwd("/").build()?)
             .build()?;
 
-        let ctx = WasiContext {
-            spec: &spec,
-            wasm_layers: &[],
-            platform: &Platform::default(),
-            id: "test".to_string(),
-        };
+        let ctx = ctx(&spec);
 
         let envs = ctx.envs();
         assert_eq!(envs.len(), 2);
@@ -501,10 +1128,8 @@ mod tests {
             .build()?;
 
         let ctx = WasiContext {
-            spec: &spec,
-            wasm_layers: &[],
-            platform: &Platform::default(),
             id: "test-container".to_string(),
+            ..ctx(&spec)
         };
 
         assert_eq!(ctx.pod_id(), Some("test-pod-id"));
@@ -520,14 +1145,240 @@ mod tests {
             .build()?;
 
         let ctx = WasiContext {
-            spec: &spec,
-            wasm_layers: &[],
-            platform: &Platform::default(),
             id: "test-container".to_string(),
+            ..ctx(&spec)
         };
 
         assert_eq!(ctx.pod_id(), None);
 
         Ok(())
     }
+
+    #[test]
+    fn test_get_entrypoint_of_artifact() -> Result<()> {
+        use std::collections::HashMap;
+
+        let annotations = HashMap::from([(ENTRYPOINT_ANNOTATION.to_string(), "#init".to_string())]);
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(ProcessBuilder::default().cwd("/").args(vec![]).build()?)
+            .annotations(annotations)
+            .build()?;
+
+        let mut config = Descriptor::new(
+            oci_spec::image::MediaType::Other(
+                "application/vnd.wasm.content.layer.v1+wasm".to_string(),
+            ),
+            10,
+            Digest::try_from(format!("sha256:{:064?}", 0))?,
+        );
+        config.set_annotations(Some(HashMap::from([(
+            oci_spec::image::ANNOTATION_TITLE.to_string(),
+            "hello.wasm".to_string(),
+        )])));
+
+        let ctx = WasiContext {
+            wasm_layers: &[WasmLayer {
+                layer: vec![],
+                path: None,
+                config,
+            }],
+            ..ctx(&spec)
+        };
+
+        let entrypoint = ctx.entrypoint();
+        assert_eq!(entrypoint.func, "init");
+        assert_eq!(entrypoint.name, Some("hello".to_string()));
+        assert!(entrypoint.arg0.is_none());
+        assert!(matches!(entrypoint.source, Source::Oci(_)));
+
+        Ok(())
+    }
+
+    /// A module with several exports, for a layer titled `title`
+    fn multi_export_layer(title: &str) -> Result<WasmLayer> {
+        use std::collections::HashMap;
+
+        let bytes = wat::parse_str(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "_start"))
+                (func (export "init"))
+                (func (export "serve"))
+            )"#,
+        )?;
+        let mut config = Descriptor::new(
+            oci_spec::image::MediaType::Other("application/wasm".to_string()),
+            bytes.len() as u64,
+            Digest::try_from(format!("sha256:{:064?}", 0))?,
+        );
+        config.set_annotations(Some(HashMap::from([(
+            oci_spec::image::ANNOTATION_TITLE.to_string(),
+            title.to_string(),
+        )])));
+        Ok(WasmLayer {
+            layer: bytes,
+            path: None,
+            config,
+        })
+    }
+
+    fn oci_entrypoint_of(layers: &[WasmLayer], args: &[&str]) -> Result<(String, String, usize)> {
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(
+                ProcessBuilder::default()
+                    .cwd("/")
+                    .args(args.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+                    .build()?,
+            )
+            .build()?;
+        let ctx = WasiContext {
+            wasm_layers: layers,
+            ..ctx(&spec)
+        };
+        let entrypoint = ctx.entrypoint();
+        entrypoint.check_export()?;
+        let Source::Oci(layers) = entrypoint.source else {
+            bail!("the source isn't the wasm layers");
+        };
+        Ok((
+            entrypoint.name.unwrap_or_default(),
+            entrypoint.func,
+            layers.len(),
+        ))
+    }
+
+    #[test]
+    fn test_entrypoint_of_multi_export_modules() -> Result<()> {
+        let single = [multi_export_layer("app.wasm")?];
+        let layers = [
+            multi_export_layer("app.wasm")?,
+            multi_export_layer("library.wasm")?,
+        ];
+
+        let entrypoint =
+            |layers: &[WasmLayer], arg0: &str| oci_entrypoint_of(layers, &[arg0, "--verbose"]);
+
+        // the export of a layer, by its title with or without the extension
+        assert_eq!(
+            entrypoint(&layers, "library#init")?,
+            ("library".into(), "init".into(), 1)
+        );
+        assert_eq!(
+            entrypoint(&layers, "library.wasm#serve")?,
+            ("library".into(), "serve".into(), 1)
+        );
+        // the first layer runs with the others
+        assert_eq!(
+            entrypoint(&layers, "app#init")?,
+            ("app".into(), "init".into(), 2)
+        );
+        // a path that isn't a layer calls the export of the first layer
+        assert_eq!(
+            entrypoint(&layers, "/app/main.wasm#init")?,
+            ("main".into(), "init".into(), 2)
+        );
+
+        // a single word is the export of a single layer image
+        assert_eq!(
+            entrypoint(&single, "serve")?,
+            ("app".into(), "serve".into(), 1)
+        );
+        assert_eq!(
+            entrypoint(&layers, "library")?,
+            ("library".into(), "_start".into(), 2)
+        );
+
+        // a path calls `_start`
+        assert_eq!(
+            entrypoint(&single, "/app.wasm")?,
+            ("app".into(), "_start".into(), 1)
+        );
+        assert_eq!(
+            entrypoint(&single, "app.wasm")?,
+            ("app".into(), "_start".into(), 1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_entrypoint_without_export() -> Result<()> {
+        let layers = [
+            multi_export_layer("app.wasm")?,
+            multi_export_layer("library.wasm")?,
+        ];
+
+        let err = oci_entrypoint_of(&layers, &["library#main"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"the module library doesn't export the function "main", its exports are: _start, init, serve"#
+        );
+
+        let err = oci_entrypoint_of(&layers[..1], &["main"]).unwrap_err();
+        assert!(err.to_string().contains(r#"the function "main""#), "{err}");
+
+        // a memory isn't a function
+        assert!(oci_entrypoint_of(&layers[..1], &["memory"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_is_not_checked_for_components() -> Result<()> {
+        let mut layer = multi_export_layer("app.wasm")?;
+        layer.layer = wat::parse_str("(component)")?;
+        let (_, func, _) = oci_entrypoint_of(&[layer], &["main"])?;
+        assert_eq!(func, "main");
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_of_the_container() -> Result<()> {
+        use oci_spec::image::{Arch, ConfigBuilder, Os, PlatformBuilder};
+
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .process(ProcessBuilder::default().cwd("/").build()?)
+            .build()?;
+        let image = ImageInfo {
+            platform: PlatformBuilder::default()
+                .os(Os::Other("wasip2".to_string()))
+                .architecture(Arch::Wasm)
+                .build()?,
+            config: Some(
+                ConfigBuilder::default()
+                    .entrypoint(vec!["/app.wasm".to_string()])
+                    .env(vec!["MODE=prod".to_string()])
+                    .build()?,
+            ),
+            manifest_digest: Some("sha256:abc".to_string()),
+        };
+        let ctx = WasiContext {
+            image: &image,
+            ..ctx(&spec)
+        };
+
+        assert_eq!(ctx.platform().os(), &Os::Other("wasip2".to_string()));
+        assert_eq!(ctx.platform().architecture(), &Arch::Wasm);
+        let config = ctx.image_config().unwrap();
+        assert_eq!(
+            config.entrypoint().as_deref(),
+            Some(&["/app.wasm".to_string()][..])
+        );
+        assert_eq!(
+            config.env().as_deref(),
+            Some(&["MODE=prod".to_string()][..])
+        );
+        assert_eq!(ctx.manifest_digest(), Some("sha256:abc"));
+
+        // a module file of the bundle has no image
+        let ctx = WasiContext {
+            image: &ImageInfo::default(),
+            ..ctx
+        };
+        assert!(ctx.image_config().is_none());
+        assert!(ctx.manifest_digest().is_none());
+
+        Ok(())
+    }
 }
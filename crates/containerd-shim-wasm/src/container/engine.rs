@@ -2,9 +2,10 @@ use std::fs::File;
 use std::io::Read;
 
 use anyhow::{Context, Result, bail};
+use oci_spec::runtime::LinuxResources;
 
 use super::Source;
-use crate::container::{PathResolve, RuntimeContext};
+use crate::container::{EngineConfig, EngineMetrics, Listener, PathResolve, RuntimeContext};
 use crate::sandbox::oci::WasmLayer;
 
 /// The `Engine` trait provides a simplified API for running WebAssembly containers.
@@ -14,9 +15,70 @@ pub trait Engine: Clone + Send + Sync + 'static {
     /// The name to use for this engine
     fn name() -> &'static str;
 
+    /// Version of the engine, e.g., of the crate of the runtime it embeds,
+    /// reported by the `info` command of the shim.  The default is None.
+    fn version() -> Option<&'static str> {
+        None
+    }
+
+    /// Features lists the wasm proposals and the WASI APIs that the engine supports,
+    /// e.g., `simd`, `component-model` or `wasi-http`, reported by the `info` command of the shim.
+    /// The default is none.
+    fn features() -> &'static [&'static str] {
+        &[]
+    }
+
     /// Run a WebAssembly container
     fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32>;
 
+    /// Execution_mode decides whether the container runs its module to completion with `run_wasi`,
+    /// or serves requests with `serve`, e.g., a component exporting `wasi:http/incoming-handler`.
+    /// The default follows the `io.runwasi.mode` annotation of the container, and runs the module without it.
+    fn execution_mode(&self, ctx: &impl RuntimeContext) -> Result<ExecutionMode> {
+        Ok(ctx.execution_mode().unwrap_or(ExecutionMode::Run))
+    }
+
+    /// Can_serve lets the shim know if the runtime serves requests with `serve`.
+    /// When it returns false the containers with the `io.runwasi.mode=http` annotation fail to be
+    /// created with an `Unsupported` error.  The default is false.
+    fn can_serve(&self) -> bool {
+        false
+    }
+
+    /// Serve the requests of a container on `listener` until the container is killed.
+    /// The listener is the first socket of the `io.runwasi.listen` annotation, bound by the shim,
+    /// or a socket on `0.0.0.0:8080` without the annotation, so the container is RUNNING as soon as
+    /// it starts, and its State, Kill and Delete are the ones of any container.
+    ///
+    /// The exit code tells how serving ended, e.g., 0 after a graceful shutdown on `SIGTERM`,
+    /// while an error, e.g., a component without a handler, fails the container as for `run_wasi`.
+    /// The default fails, see `can_serve`.
+    fn serve(&self, _ctx: &impl RuntimeContext, _listener: &Listener) -> Result<i32> {
+        bail!("the {} engine does not serve requests", Self::name())
+    }
+
+    /// Can_reload lets the shim know if the runtime swaps the module of a running container on
+    /// `SIGHUP`, with `load` and `interrupt`.
+    /// When it returns false the containers with the `io.runwasi.reload-on-sighup=true` annotation
+    /// fail to be created with an `Unsupported` error.  The default is false.
+    fn can_reload(&self) -> bool {
+        false
+    }
+
+    /// Load the module of the container from `ctx.entrypoint().source` without running it,
+    /// e.g., compile it. On a reload, the shim loads the new module before it stops the running
+    /// instance, which keeps running when the new module fails to load.
+    /// The default fails, see `can_reload`.
+    fn load(&self, _ctx: &impl RuntimeContext) -> Result<()> {
+        bail!("the {} engine does not reload modules", Self::name())
+    }
+
+    /// Stop the running instance for a reload, so that `run_wasi` or `serve` returns, and the shim
+    /// runs the new module with the same stdio and listeners: `serve` once its in-flight requests
+    /// are done, and `run_wasi` right away. The exit code of the stopped instance is ignored.
+    /// As `handle_signal`, it is called from another thread of the container process.
+    fn interrupt(&self) {}
+
     /// Check that the runtime can run the container.
     /// This checks runs after the container creation and before the container starts.
     /// By default it checks that the wasi_entrypoint is either:
@@ -83,4 +145,166 @@ pub trait Engine: Clone + Send + Sync + 'static {
     fn can_precompile(&self) -> Option<String> {
         None
     }
+
+    /// Compiler_cache_key identifies the compiler that produced the precompiled modules,
+    /// e.g., the engine name, its version, and the target features it compiles for.
+    /// It is stored as a label of the precompiled content, and compared when the content is loaded.
+    /// If it doesn't match, e.g., after an upgrade of the engine, the layers are recompiled,
+    /// and the stale precompiled content is left for containerd to garbage collect.
+    ///
+    /// The default is the name of the engine, the `unique_string` of `can_precompile`, and the target architecture.
+    fn compiler_cache_key(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            Self::name(),
+            self.can_precompile().unwrap_or_default(),
+            std::env::consts::ARCH
+        )
+    }
+
+    /// Validate_engine_config checks the configuration of the engine for a container,
+    /// from the `runwasi.io/engine.<key>` annotations of its OCI spec.
+    /// It is called when the container is created, and an error fails the creation.
+    ///
+    /// Runtimes should check the values of the keys they support, and call `config.check_keys`
+    /// with those keys, so that unknown keys fail in strict mode.
+    /// The default doesn't support any key.
+    fn validate_engine_config(&self, config: &EngineConfig) -> Result<()> {
+        config.check_keys(&[])
+    }
+
+    /// Precompile_compatible lets the shim know if the precompiled layers can run with the
+    /// configuration of the engine for a container, e.g., a configuration that changes
+    /// the generated code can't run modules precompiled without it.
+    ///
+    /// When it returns false, the original layers are loaded for the container, and compiled
+    /// when it starts.  The default is true.
+    fn precompile_compatible(&self, _config: &EngineConfig) -> bool {
+        true
+    }
+
+    /// Can_cache_compiled lets the shim know if the runtime keeps the modules that it compiles
+    /// in `ctx.compile_cache()`, e.g., the modules of the bundle, which aren't precompiled in the
+    /// content store.  When it returns false the shim doesn't create the directory of the cache.
+    /// The default is false.
+    fn can_cache_compiled(&self) -> bool {
+        false
+    }
+
+    /// Can_exec lets the shim know if the runtime supports exec processes.
+    /// An exec process calls `run_wasi` again, in a new process of the running container,
+    /// against the module layers that were already loaded for the container.
+    /// The context of that call has the args, entrypoint and envs of the exec process spec,
+    /// and the stdio of the exec process.
+    ///
+    /// When it returns false exec requests fail with an `Unsupported` error.  The default is true.
+    fn can_exec(&self) -> bool {
+        true
+    }
+
+    /// On_resources_updated lets the runtime react to an Update of the container's resource limits,
+    /// e.g., to enforce the new memory limit in the store limits of the exec processes that follow.
+    /// It is called after the limits were applied to the container's cgroup, in the process
+    /// that the exec processes of the container are forked from, so that state kept by the
+    /// runtime (e.g., in a static) is inherited by them.
+    /// Returning an error fails the Update.  The default does nothing.
+    fn on_resources_updated(&self, _resources: &LinuxResources) -> Result<()> {
+        Ok(())
+    }
+
+    /// Can_suspend lets the shim know if the runtime suspends the running module itself on Pause.
+    /// When it returns true, Pause sends [`suspend_signal`] and Resume sends [`resume_signal`] to
+    /// the container process, instead of freezing the container's cgroup.
+    /// `run_wasi` is then expected to handle those signals, e.g., by blocking in an epoch deadline
+    /// callback between the two. They are realtime signals that the shim reserves, which the Kill
+    /// requests can't send, so the module keeps the signals it handles, e.g., `SIGUSR1`.
+    ///
+    /// Note that the signals are sent to all the containers of the engine, including the Linux
+    /// containers it falls back to.  The default is false.
+    fn can_suspend(&self) -> bool {
+        false
+    }
+
+    /// Collect_metrics lets the runtime report metrics of the running module, e.g., the size of its
+    /// linear memory, which are added to the Stats of the container.
+    /// It is called every second from another thread of the container process, while `run_wasi` runs,
+    /// on a clone of the engine that runs it.  The Stats report the latest metrics.
+    ///
+    /// When it returns None the metrics stop being collected, and the Stats only have the metrics
+    /// of the container's cgroup.  This is the default value.
+    fn collect_metrics(&self) -> Option<EngineMetrics> {
+        None
+    }
+
+    /// Wasm_backtrace lets the runtime describe the wasm frames of an error of `run_wasi`,
+    /// e.g., of a trap, for the crash report of the container.
+    /// When it returns None the report only has the error.  This is the default value.
+    fn wasm_backtrace(&self, _err: &anyhow::Error) -> Option<String> {
+        None
+    }
+
+    /// Handled_signals lists the signals that the runtime handles with `handle_signal`,
+    /// instead of their default action, e.g., to terminate the container on `SIGTERM`.
+    ///
+    /// The signals are blocked in the container process before `run_wasi` is called, so the
+    /// runtime can't also wait for them, e.g., with `tokio::signal`.
+    /// `SIGKILL` and `SIGSTOP` can't be handled, `SIGKILL` always stops the container.
+    /// The default is no signal.
+    fn handled_signals(&self) -> &'static [i32] {
+        &[]
+    }
+
+    /// Handle_signal lets the runtime handle a signal of `handled_signals` sent to the container,
+    /// e.g., with a Kill request, which passes its signal as is.
+    /// It is called from another thread of the container process, while `run_wasi` runs,
+    /// on a clone of the engine that runs it.
+    ///
+    /// The runtime can, e.g., set a flag that the guest polls to shut down gracefully or to reload
+    /// its configuration, or interrupt the guest by incrementing the epoch of its engine.
+    /// The default is the default action of the signal, as without `handled_signals`.
+    /// Note that the container process ignores the signals with a default action
+    /// when it is the init process of its PID namespace.
+    fn handle_signal(&self, _signal: i32) -> SignalAction {
+        SignalAction::Default
+    }
+}
+
+/// How a container runs its module, see `Engine::execution_mode`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecutionMode {
+    /// The module runs to completion with `Engine::run_wasi`, e.g., a command
+    Run,
+    /// The module serves requests with `Engine::serve` until the container is killed
+    Serve,
+}
+
+/// The signal that Pause sends to the container process of an engine that suspends
+/// the running module itself, see `Engine::can_suspend`
+#[cfg(unix)]
+pub fn suspend_signal() -> i32 {
+    libc::SIGRTMIN() + 1
+}
+
+/// The signal that Resume sends to the container process of an engine that suspends
+/// the running module itself, see `Engine::can_suspend`
+#[cfg(unix)]
+pub fn resume_signal() -> i32 {
+    libc::SIGRTMIN() + 2
+}
+
+/// Whether `signal` is one of the signals of Pause and Resume, which the Kill requests can't send
+#[cfg(unix)]
+pub(crate) fn is_reserved_signal(signal: i32) -> bool {
+    signal == suspend_signal() || signal == resume_signal()
+}
+
+/// What happens to the container after `Engine::handle_signal`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignalAction {
+    /// The runtime handled the signal, and the container keeps running
+    Handled,
+    /// The container exits right away, with the exit code `128 + signal`
+    Terminate,
+    /// The default action of the signal, as when the runtime doesn't handle it
+    Default,
 }
@@ -10,19 +10,37 @@
 //! * Less customizable
 //! * Currently only works on Linux
 
+mod compile_cache;
+mod config;
 mod context;
 mod engine;
 pub mod log;
 mod path;
 mod wasm;
 
+pub use compile_cache::CompileCache;
+pub(crate) use compile_cache::{
+    COMPILE_CACHE_DIR_ENV, DEFAULT_COMPILE_CACHE_MAX_SIZE, DEFAULT_COMPILE_CACHE_ROOT,
+};
+pub use config::{
+    ENGINE_CONFIG_ANNOTATION_PREFIX, ENGINE_CONFIG_STRICT_ANNOTATION,
+    ENGINE_SCOPED_ANNOTATION_PREFIX, EngineConfig,
+};
 pub(crate) use context::WasiContext;
-pub use context::{Entrypoint, RuntimeContext, Source};
-pub use engine::Engine;
+pub use context::{
+    CpuQuota, ENTRYPOINT_ANNOTATION, Entrypoint, Listener, Preopen, RuntimeContext, Source,
+    WasmModule,
+};
+#[cfg(unix)]
+pub(crate) use engine::is_reserved_signal;
+pub use engine::{Engine, ExecutionMode, SignalAction};
+#[cfg(unix)]
+pub use engine::{resume_signal, suspend_signal};
 pub use instance::Instance;
 pub(crate) use path::PathResolve;
 pub use wasm::WasmBinaryType;
 
+pub use crate::sandbox::{EngineMetrics, ModuleBytes};
 use crate::sys::container::instance;
 
 #[cfg(test)]
@@ -36,3 +36,136 @@ fn test_validation_error() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(unix)]
+mod signals {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use containerd_shim_wasm_test_modules::HELLO_WORLD;
+
+    use super::*;
+    use crate::container::SignalAction;
+    use crate::sandbox::Instance as _;
+    use crate::sandbox::async_utils::AmbientRuntime as _;
+
+    /// An engine that stops gracefully on `SIGTERM`, reloads on `SIGUSR1`, and terminates on `SIGUSR2`.
+    /// With the `stubborn` entrypoint, it ignores `SIGTERM`.
+    #[derive(Clone, Default)]
+    struct GracefulEngine;
+
+    static STOPPING: AtomicBool = AtomicBool::new(false);
+    static RELOADING: AtomicBool = AtomicBool::new(false);
+
+    impl Engine for GracefulEngine {
+        fn name() -> &'static str {
+            "graceful"
+        }
+
+        fn run_wasi(&self, ctx: &impl RuntimeContext) -> anyhow::Result<i32> {
+            let stubborn = ctx.entrypoint().func == "stubborn";
+            println!("ready");
+            loop {
+                if RELOADING.swap(false, Ordering::Relaxed) {
+                    println!("reloaded");
+                }
+                if STOPPING.load(Ordering::Relaxed) && !stubborn {
+                    println!("stopped");
+                    return Ok(0);
+                }
+                sleep(Duration::from_millis(10));
+            }
+        }
+
+        fn handled_signals(&self) -> &'static [i32] {
+            &[libc::SIGTERM, libc::SIGUSR1, libc::SIGUSR2]
+        }
+
+        fn handle_signal(&self, signal: i32) -> SignalAction {
+            match signal {
+                libc::SIGTERM => STOPPING.store(true, Ordering::Relaxed),
+                libc::SIGUSR1 => RELOADING.store(true, Ordering::Relaxed),
+                libc::SIGUSR2 => return SignalAction::Terminate,
+                _ => return SignalAction::Default,
+            }
+            SignalAction::Handled
+        }
+    }
+
+    type GracefulInstance = Instance<GracefulEngine>;
+
+    fn wait_for_stdout(test: &WasiTest<GracefulInstance>, line: &str) -> anyhow::Result<()> {
+        for _ in 0..500 {
+            let stdout = test.read_stdout()?.unwrap_or_default();
+            if stdout.lines().any(|l| l == line) {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(10));
+        }
+        bail!("timeout waiting for {line:?}");
+    }
+
+    #[test]
+    fn test_signals_are_handled_by_the_engine() -> anyhow::Result<()> {
+        let test = WasiTest::<GracefulInstance>::builder()?
+            .with_wasm(HELLO_WORLD)?
+            .build()?;
+        test.start()?;
+        wait_for_stdout(&test, "ready")?;
+
+        test.signal(libc::SIGUSR1)?;
+        wait_for_stdout(&test, "reloaded")?;
+
+        let (exit_code, stdout, _) = test.terminate()?.wait(Duration::from_secs(5))?;
+        assert_eq!(exit_code, 0);
+        assert_eq!(stdout, "ready\nreloaded\nstopped\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_engine_terminates_on_signal() -> anyhow::Result<()> {
+        let test = WasiTest::<GracefulInstance>::builder()?
+            .with_wasm(HELLO_WORLD)?
+            .build()?;
+        test.start()?;
+        wait_for_stdout(&test, "ready")?;
+
+        let (exit_code, stdout, _) = test.signal(libc::SIGUSR2)?.wait(Duration::from_secs(5))?;
+        assert_eq!(exit_code, 128 + libc::SIGUSR2 as u32);
+        assert_eq!(
+            stdout,
+            "ready
+    "
+        );
+
+        Ok(())
+    }
+
+    // On pod deletion, the kubelet sends SIGTERM, and SIGKILL once the grace period is over.
+    #[test]
+    fn test_sigterm_grace_period_then_sigkill() -> anyhow::Result<()> {
+        let test = WasiTest::<GracefulInstance>::builder()?
+            .with_start_fn("stubborn")
+            .with_wasm(HELLO_WORLD)?
+            .build()?;
+        test.start()?;
+        wait_for_stdout(&test, "ready")?;
+
+        // still running after the grace period
+        test.terminate()?;
+        let exited = test
+            .instance()
+            .wait()
+            .with_timeout(Duration::from_secs(1))
+            .block_on();
+        assert!(exited.is_none());
+
+        let (exit_code, stdout, _) = test.kill()?.wait(Duration::from_secs(5))?;
+        assert_eq!(exit_code, 128 + libc::SIGKILL as u32);
+        assert_eq!(stdout, "ready\n");
+
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use wasmparser::Parser;
+use wasmparser::{ExternalKind, Parser, Payload};
 
 /// The type of a wasm binary.
 pub enum WasmBinaryType {
@@ -20,3 +20,23 @@ impl WasmBinaryType {
         }
     }
 }
+
+/// The names of the functions exported by a wasm module,
+/// or `None` for a component or bytes that aren't a valid module.
+pub(crate) fn function_exports(bytes: &[u8]) -> Option<Vec<String>> {
+    if !Parser::is_core_wasm(bytes) {
+        return None;
+    }
+    let mut exports = vec![];
+    for payload in Parser::new(0).parse_all(bytes) {
+        if let Payload::ExportSection(reader) = payload.ok()? {
+            for export in reader {
+                let export = export.ok()?;
+                if export.kind == ExternalKind::Func {
+                    exports.push(export.name.to_string());
+                }
+            }
+        }
+    }
+    Some(exports)
+}
@@ -71,6 +71,23 @@
 //! );
 //! ```
 //!
+//! ## Runtime Information
+//!
+//! The `info` command prints the shim, its version, and the engine it embeds as JSON,
+//! with the version, the features and the supported media types of the engine, from
+//! [`Engine::version`](crate::container::Engine::version) and
+//! [`Engine::features`](crate::container::Engine::features):
+//!
+//! ```console
+//! $ containerd-shim-wasmtime-v1 info
+//! ```
+//!
+//! containerd 2.0 introspects the runtime with the `-info` flag, which prints the same
+//! information as a `RuntimeInfo` protobuf message.
+//!
+//! The log records are plain text, unless `RUNWASI_LOG_FORMAT=json` is set, and their level
+//! can change while the shim runs, see [`crate::sandbox::logging`].
+//!
 //! When the `opentelemetry` feature is enabled, additional runtime config
 //! is available through environment variables:
 //!
@@ -79,10 +96,12 @@
 //! - `OTEL_SDK_DISABLED`: Disable OpenTelemetry SDK
 //!
 
+use std::io::Write as _;
 use std::path::PathBuf;
 
 use containerd_shim::{Config, parse, run};
 
+use crate::sandbox::info::ShimInfo;
 #[cfg(feature = "opentelemetry")]
 use crate::sandbox::shim::{OtlpConfig, otel_traces_enabled};
 use crate::sandbox::{Instance, ShimCli};
@@ -184,21 +203,52 @@ pub fn shim_main<'a, I>(
 {
     // parse the version flag
     let os_args: Vec<_> = std::env::args_os().collect();
-
-    let flags = parse(&os_args[1..]).unwrap();
     let argv0 = PathBuf::from(&os_args[0]);
     let argv0 = argv0.file_stem().unwrap_or_default().to_string_lossy();
+    let revision: Option<&str> = revision.into();
+
+    let info = || ShimInfo {
+        name: argv0.to_string(),
+        runtime: name.to_string(),
+        version: version.to_string(),
+        revision: revision.map(str::to_string),
+        engine: I::engine_info(),
+    };
+
+    // containerd introspects the runtime with the `-info` flag, which containerd-shim doesn't parse
+    if os_args[1..]
+        .iter()
+        .any(|arg| arg == "-info" || arg == "--info")
+    {
+        let mut stdout = std::io::stdout();
+        stdout
+            .write_all(&info().to_runtime_info())
+            .and_then(|_| stdout.flush())
+            .expect("Failed to write the runtime info");
+        std::process::exit(0);
+    }
+
+    let flags = parse(&os_args[1..]).unwrap();
 
     if flags.version {
         println!("{argv0}:");
         println!("  Runtime: {name}");
         println!("  Version: {version}");
-        println!("  Revision: {}", revision.into().unwrap_or("<none>"));
+        println!("  Revision: {}", revision.unwrap_or("<none>"));
         println!();
 
         std::process::exit(0);
     }
 
+    if flags.action == "info" {
+        println!("{}", info().to_json());
+        std::process::exit(0);
+    }
+
+    // the containers, forked from the zygote, don't get the NOTIFY_SOCKET of another service
+    #[cfg(target_os = "linux")]
+    crate::sys::notify::forget_foreign_socket();
+
     // Initialize the zygote and logger for the container process
     #[cfg(unix)]
     {
@@ -207,14 +257,49 @@ pub fn shim_main<'a, I>(
         init_zygote_and_logger(flags.debug, config);
     }
 
+    // The shim serving the task API logs with the vendored logger, which supports the JSON format.
+    // containerd-shim would set up its own logger, with the same FIFO.
+    let mut config = config.unwrap_or_default();
+
+    // The shim serving the sandbox API waits for the signals itself, they must be blocked
+    // before any thread is spawned, e.g., by the logger or the opentelemetry runtime
+    #[cfg(all(unix, feature = "sandbox"))]
+    if !matches!(flags.action.as_str(), "start" | "delete") {
+        crate::vendor::containerd_shim::serve::block_signals(&config)
+            .expect("Failed to block the signals");
+    }
+
+    if !config.no_setup_logger && !matches!(flags.action.as_str(), "start" | "delete") {
+        crate::vendor::containerd_shim::logger::init(
+            flags.debug,
+            &config.default_log_level,
+            &flags.namespace,
+            &flags.id,
+        )
+        .expect("Failed to initialize logger");
+        crate::sandbox::logging::watch_control_file();
+        config.no_setup_logger = true;
+    }
+
+    // the shim serving the task API forks the zygotes of its containers ahead of them
+    #[cfg(unix)]
+    if !matches!(flags.action.as_str(), "start" | "delete") {
+        crate::sys::container::pool::ZygotePool::global().prefork();
+    }
+    let config = Some(config);
+
     #[cfg(feature = "opentelemetry")]
     if otel_traces_enabled() {
         // opentelemetry uses tokio, so we need to initialize a runtime
         async {
-            let otlp_config = OtlpConfig::build_from_env().expect("Failed to build OtelConfig.");
-            let _guard = otlp_config
-                .init()
-                .expect("Failed to initialize OpenTelemetry.");
+            // the shim runs without traces rather than failing, e.g., with an invalid protocol
+            let _guard = match OtlpConfig::build_from_env().and_then(|config| config.init()) {
+                Ok(guard) => Some(guard),
+                Err(err) => {
+                    log::warn!("failed to initialize OpenTelemetry, traces are disabled: {err:#}");
+                    None
+                }
+            };
             tokio::task::block_in_place(move || {
                 shim_main_inner::<I>(name, version, revision, shim_version, config);
             });
@@ -263,5 +348,37 @@ fn shim_main_inner<'a, I>(
     let lower_name = name.to_lowercase();
     let shim_id = format!("io.containerd.{lower_name}.{shim_version}");
 
+    #[cfg(all(unix, feature = "sandbox"))]
+    if crate::vendor::containerd_shim::serve::signals_blocked() {
+        serve_with_sandbox::<I>(&shim_id, config.unwrap_or_default());
+        return;
+    }
+
     run::<ShimCli<I>>(&shim_id, config);
 }
+
+/// Serve the sandbox API alongside the task API, which `containerd_shim::run` can't do
+#[cfg(all(unix, feature = "sandbox"))]
+fn serve_with_sandbox<I>(shim_id: &str, config: Config)
+where
+    I: 'static + Instance + Sync + Send,
+{
+    use std::sync::Arc;
+
+    use containerd_shim::protos::shim::shim_ttrpc::create_task;
+    use containerd_shim_protos::sandbox::sandbox_ttrpc::create_sandbox;
+
+    use crate::vendor::containerd_shim::serve::serve;
+
+    let os_args: Vec<_> = std::env::args_os().collect();
+    let flags = parse(&os_args[1..]).unwrap();
+    let result = serve::<ShimCli<I>>(shim_id, &flags, config, |local| {
+        let local = Arc::new(local);
+        vec![create_task(local.clone()), create_sandbox(local)]
+    });
+    if let Err(err) = result {
+        log::error!("{shim_id}: {err:#}");
+        eprintln!("{shim_id}: {err:#}");
+        std::process::exit(1);
+    }
+}
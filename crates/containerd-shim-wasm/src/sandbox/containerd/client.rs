@@ -1,7 +1,9 @@
 #![cfg(unix)]
 
 use std::collections::HashMap;
-use std::path::Path;
+use std::fs::{File, create_dir_all};
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
 
 use containerd_client;
 use containerd_client::services::v1::containers_client::ContainersClient;
@@ -17,28 +19,40 @@ use containerd_client::tonic::Streaming;
 use containerd_client::tonic::transport::Channel;
 use containerd_client::{tonic, with_namespace};
 use futures::TryStreamExt;
-use oci_spec::image::{Arch, Digest, ImageManifest, MediaType, Platform};
+use oci_spec::image::{
+    Arch, Config as ImageConfig, Descriptor, Digest, ImageIndex, ImageManifest, MediaType, Platform,
+};
+use serde::Deserialize;
 use sha256::digest;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Code, Request};
 
 use super::lease::LeaseGuard;
-use crate::container::Engine;
+use super::lock::{PRECOMPILE_LOCK_TIMEOUT, PrecompileLock};
+use crate::container::{Engine, EngineConfig};
 use crate::sandbox::error::{Error as ShimError, Result};
-use crate::sandbox::oci::{self, WasmLayer};
+use crate::sandbox::oci::{self, ImageInfo, WasmLayer};
+use crate::sandbox::shim::metrics::ShimMetrics;
 use crate::with_lease;
 
 static PRECOMPILE_PREFIX: &str = "runwasi.io/precompiled";
+// Label of the precompiled content with the `Engine::compiler_cache_key` of the compiler that produced it
+static CACHE_KEY_LABEL: &str = "runwasi.io/precompiled/cache-key";
+static GC_REF_PRECOMPILE_PREFIX: &str = "containerd.io/gc.ref.content.precompile.";
 // 16MB is the default maximum gRPC message size for gRPC in containerd:
 // https://github.com/containerd/containerd/blob/main/defaults/defaults.go
 // Conservatively set the max to 15MB to leave room for message overhead
 static MAX_WRITE_CHUNK_SIZE_BYTES: i64 = 1024 * 1024 * 15;
+// Layers larger than this are streamed to a file instead of being read in memory
+static LARGE_LAYER_SIZE: u64 = 1024 * 1024 * 64;
 
 #[derive(Debug)]
 pub struct Client {
     inner: Channel,
     namespace: String,
+    layers_dir: Option<PathBuf>,
+    engine_config: EngineConfig,
 }
 
 #[derive(Debug)]
@@ -70,9 +84,23 @@ impl Client {
         Ok(Client {
             inner,
             namespace: namespace.into(),
+            layers_dir: None,
+            engine_config: EngineConfig::default(),
         })
     }
 
+    /// Keep the large layers in files in `dir`, instead of in memory
+    pub fn with_layers_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.layers_dir = Some(dir.into());
+        self
+    }
+
+    /// Load the layers for a container with the engine configuration `config`
+    pub fn with_engine_config(mut self, config: EngineConfig) -> Self {
+        self.engine_config = config;
+        self
+    }
+
     // wrapper around read that will read the entire content file
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     async fn read_content(&self, digest: impl ToString + std::fmt::Debug) -> Result<Vec<u8>> {
@@ -92,6 +120,35 @@ impl Client {
             .map_err(|err| ShimError::Containerd(err.to_string()))
     }
 
+    // wrapper around read that will stream the entire content to a file
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    async fn read_content_to_file(
+        &self,
+        digest: impl ToString + std::fmt::Debug,
+        path: &Path,
+    ) -> Result<()> {
+        let req = ReadContentRequest {
+            digest: digest.to_string(),
+            ..Default::default()
+        };
+        let req = with_namespace!(req, self.namespace);
+        let mut stream = ContentClient::new(self.inner.clone())
+            .read(req)
+            .await
+            .map_err(|err| ShimError::Containerd(err.to_string()))?
+            .into_inner();
+
+        let mut file = File::create(path)?;
+        while let Some(msg) = stream
+            .message()
+            .await
+            .map_err(|err| ShimError::Containerd(err.to_string()))?
+        {
+            file.write_all(&msg.data)?;
+        }
+        Ok(())
+    }
+
     // used in tests to clean up content
     #[allow(dead_code)]
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
@@ -145,7 +202,12 @@ impl Client {
         labels: HashMap<String, String>,
     ) -> Result<WriteContent> {
         let expected = format!("sha256:{}", digest(data.clone()));
-        let reference = format!("precompile-{}", unique_id);
+        // one lease per content, so that shims can write the precompiled layers of different images at once
+        let reference = format!(
+            "precompile-{}-{}",
+            unique_id,
+            &expected["sha256:".len()..][..12]
+        );
         let lease = self.lease(reference.clone()).await?;
 
         let digest = 'digest: {
@@ -175,6 +237,10 @@ impl Client {
                 Ok(response_stream) => response_stream.into_inner(),
                 Err(e) if e.code() == Code::AlreadyExists => {
                     log::info!("content already exists {}", expected.clone().to_string());
+                    // the labels are only set on commit, update them on the existing content
+                    let mut info = self.get_info(&expected.parse()?).await?;
+                    info.labels.extend(labels);
+                    self.update_info(info).await?;
                     break 'digest expected;
                 }
                 Err(e) => return Err(ShimError::Containerd(e.to_string())),
@@ -365,24 +431,47 @@ impl Client {
         image_name: &str,
     ) -> Result<(ImageManifest, Digest)> {
         let image = self.get_image(image_name).await?;
-        let image_digest = self.extract_image_content_sha(&image)?.try_into()?;
-        let manifest =
-            ImageManifest::from_reader(self.read_content(&image_digest).await?.as_slice())?;
+        let mut image_digest: Digest = self.extract_image_content_sha(&image)?.try_into()?;
+        let mut content = self.read_content(&image_digest).await?;
+
+        // a multi-platform image is an index of the manifests of its platforms
+        let media_type = image.target.as_ref().map(|t| t.media_type.as_str());
+        if media_type.is_some_and(is_image_index) {
+            let index = ImageIndex::from_reader(content.as_slice())?;
+            let manifest = select_manifest(&index).ok_or_else(|| {
+                ShimError::Containerd(format!("the index of image {image_name} has no manifest"))
+            })?;
+            log::info!(
+                "selected manifest {} for the platform {:?} of image {image_name}",
+                manifest.digest(),
+                manifest.platform()
+            );
+            image_digest = manifest.digest().clone();
+            content = self.read_content(&image_digest).await?;
+        }
+
+        let manifest = ImageManifest::from_reader(content.as_slice())?;
         Ok((manifest, image_digest))
     }
 
+    /// The image of a container
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    pub async fn container_image(
+        &self,
+        containerd_id: impl ToString + std::fmt::Debug,
+    ) -> Result<String> {
+        Ok(self.get_container(containerd_id).await?.image)
+    }
+
     // load module will query the containerd store to find an image that has an OS of type 'wasm'
     // If found it continues to parse the manifest and return the layers that contains the WASM modules
     // and possibly other configuration layers.
-    #[cfg_attr(
-        feature = "tracing",
-        tracing::instrument(skip(engine), level = "Debug")
-    )]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(engine), level = "Info"))]
     pub async fn load_modules<T: Engine>(
         &self,
         containerd_id: impl ToString + std::fmt::Debug,
         engine: &T,
-    ) -> Result<(Vec<oci::WasmLayer>, Platform)> {
+    ) -> Result<(Vec<oci::WasmLayer>, ImageInfo)> {
         let container = self.get_container(containerd_id.to_string()).await?;
         let (manifest, image_digest) = self.get_image_manifest_and_digest(&container.image).await?;
 
@@ -390,50 +479,103 @@ impl Client {
         let image_config = self.read_content(image_config_descriptor.digest()).await?;
         let image_config = image_config.as_slice();
 
-        // the only part we care about here is the platform values
+        // the platform values, and the entrypoint and env of the image
         let platform: Platform = serde_json::from_slice(image_config)?;
-        let Arch::Wasm = platform.architecture() else {
-            log::info!("manifest is not in WASM OCI image format");
-            return Ok((vec![], platform));
+        let config = serde_json::from_slice::<ConfigBlob>(image_config)
+            .map(|blob| blob.config)
+            .unwrap_or_default();
+        let is_wasm = matches!(platform.architecture(), Arch::Wasm);
+        let info = ImageInfo {
+            platform,
+            config,
+            manifest_digest: Some(image_digest.to_string()),
         };
+        let is_artifact = is_wasm_artifact(image_config_descriptor.media_type());
+        if is_artifact {
+            log::info!("found manifest with WASM OCI artifact format");
+        } else if is_wasm {
+            log::info!("found manifest with WASM OCI image format");
+        } else {
+            log::info!("manifest is not in WASM OCI image format");
+            return Ok((vec![], info));
+        }
 
-        log::info!("found manifest with WASM OCI image format");
         // This label is unique across runtimes and version of the shim running
         // a precompiled component/module will not work across different runtimes or versions
         let (can_precompile, precompile_id) = match engine.can_precompile() {
+            Some(_) if !engine.precompile_compatible(&self.engine_config) => {
+                log::info!(
+                    "the engine configuration of the container can't use precompiled layers"
+                );
+                (false, "".to_string())
+            }
             Some(precompile_id) => (true, precompile_label(T::name(), &precompile_id)),
             None => (false, "".to_string()),
         };
+        let cache_key = if can_precompile {
+            engine.compiler_cache_key()
+        } else {
+            "".to_string()
+        };
 
-        let image_info = self.get_info(&image_digest).await?;
-        let mut needs_precompile =
-            can_precompile && !image_info.labels.contains_key(&precompile_id);
         let configs = manifest
             .layers()
             .iter()
-            .filter(|x| is_wasm_layer(x.media_type(), T::supported_layers_types()));
+            .filter(|x| {
+                is_wasm_layer(x.media_type(), T::supported_layers_types())
+                    || (is_artifact
+                        && is_wasm_layer(x.media_type(), oci::WASM_ARTIFACT_LAYER_MEDIA_TYPES))
+            })
+            .collect::<Vec<_>>();
+
+        let (mut layers, mut needs_precompile) = self
+            .read_wasm_layers(
+                &configs,
+                &image_digest,
+                can_precompile,
+                &precompile_id,
+                &cache_key,
+            )
+            .await?;
 
-        let mut layers = vec![];
-        for original_config in configs {
-            let layer = self
-                .read_wasm_layer(
-                    original_config,
+        if layers.is_empty() {
+            log::info!("no WASM layers found in OCI image");
+            return Ok((vec![], info));
+        }
+
+        // Only one shim of the node precompiles the layers, the others wait for it,
+        // and load its precompiled content. The lock is held until the content is labeled.
+        let _lock = if needs_precompile {
+            let digests = configs.iter().map(|c| c.digest().to_string());
+            let lock = PrecompileLock::acquire(digests, &cache_key, PRECOMPILE_LOCK_TIMEOUT).await;
+            // the layers may have been precompiled while waiting for the lock
+            (layers, needs_precompile) = self
+                .read_wasm_layers(
+                    &configs,
+                    &image_digest,
                     can_precompile,
                     &precompile_id,
-                    &mut needs_precompile,
+                    &cache_key,
                 )
                 .await?;
-            layers.push(layer);
-        }
+            lock
+        } else {
+            None
+        };
 
-        if layers.is_empty() {
-            log::info!("no WASM layers found in OCI image");
-            return Ok((vec![], platform));
+        if can_precompile {
+            ShimMetrics::global().record_precompile(!needs_precompile);
         }
 
         if needs_precompile {
             log::info!("precompiling layers for image: {}", container.image);
-            let compiled_layers = match engine.precompile(&layers) {
+            let compiled_layers = {
+                // the span isn't held across an await, as the future has to be Send
+                #[cfg(feature = "tracing")]
+                let _span = tracing::info_span!("precompile", layers = layers.len()).entered();
+                engine.precompile(&layers)
+            };
+            let compiled_layers = match compiled_layers {
                 Ok(compiled_layers) => {
                     if compiled_layers.len() != layers.len() {
                         return Err(ShimError::FailedPrecondition(
@@ -444,7 +586,7 @@ impl Client {
                 }
                 Err(e) => {
                     log::error!("precompilation failed: {}", e);
-                    return Ok((layers, platform));
+                    return Ok((layers, info));
                 }
             };
 
@@ -458,10 +600,13 @@ impl Client {
 
                 let compiled_layer = compiled_layer.as_ref().unwrap();
                 let original_config = &layers[i].config;
-                let labels = HashMap::from([(
-                    format!("{precompile_id}/original"),
-                    original_config.digest().to_string(),
-                )]);
+                let labels = HashMap::from([
+                    (
+                        format!("{precompile_id}/original"),
+                        original_config.digest().to_string(),
+                    ),
+                    (CACHE_KEY_LABEL.to_string(), cache_key.clone()),
+                ]);
                 let precompiled_content = self
                     .save_content(compiled_layer.clone(), &precompile_id, labels)
                     .await?;
@@ -474,12 +619,13 @@ impl Client {
                 // We add two labels here:
                 // - one with cache key per engine instance
                 // - one with a gc ref flag so it doesn't get cleaned up as long as the original layer exists
+                //   the gc ref is per engine, so that the content of another engine isn't released
                 let mut original_layer = self.get_info(original_config.digest()).await?;
                 original_layer
                     .labels
                     .insert(precompile_id.clone(), precompiled_content.digest.clone());
                 original_layer.labels.insert(
-                    format!("containerd.io/gc.ref.content.precompile.{}", i),
+                    precompile_gc_ref_label(T::name(), i),
                     precompiled_content.digest.clone(),
                 );
                 self.update_info(original_layer).await?;
@@ -493,7 +639,7 @@ impl Client {
                 );
                 let mut image_content = self.get_info(&image_digest).await?;
                 image_content.labels.insert(
-                    format!("containerd.io/gc.ref.content.precompile.{}", i),
+                    precompile_gc_ref_label(T::name(), i),
                     precompiled_content.digest,
                 );
                 image_content
@@ -501,49 +647,124 @@ impl Client {
                     .insert(precompile_id.clone(), "true".to_string());
                 self.update_info(image_content).await?;
 
-                layers_for_runtime.push(WasmLayer {
-                    config: original_config.clone(),
-                    layer: compiled_layer.clone(),
+                // keep the precompiled content in a file, that the engines map
+                let path = layers[i].path.clone().or_else(|| {
+                    let dir = self.layers_dir.as_ref()?;
+                    Some(dir.join(original_config.digest().digest()))
                 });
+                let layer = match path {
+                    Some(path) => {
+                        if let Some(dir) = path.parent() {
+                            create_dir_all(dir)?;
+                        }
+                        std::fs::write(&path, compiled_layer)?;
+                        WasmLayer {
+                            config: original_config.clone(),
+                            layer: vec![],
+                            path: Some(path),
+                        }
+                    }
+                    None => WasmLayer {
+                        config: original_config.clone(),
+                        layer: compiled_layer.clone(),
+                        path: None,
+                    },
+                };
+                layers_for_runtime.push(layer);
 
-                let _ = precompiled_content.lease.release().await;
+                // the content is referenced by the image now, it doesn't need the lease anymore
+                if let Err(err) = precompiled_content.lease.release().await {
+                    log::warn!("failed to release the lease of the precompiled content: {err}");
+                }
             }
-            return Ok((layers_for_runtime, platform));
+            return Ok((layers_for_runtime, info));
         };
 
         log::info!("using OCI layers");
-        Ok((layers, platform))
+        Ok((layers, info))
+    }
+
+    /// Read the `configs` layers, or their precompiled content,
+    /// and whether they need to be precompiled
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, configs), level = "Info")
+    )]
+    async fn read_wasm_layers(
+        &self,
+        configs: &[&oci_spec::image::Descriptor],
+        image_digest: &Digest,
+        can_precompile: bool,
+        precompile_id: &String,
+        cache_key: &str,
+    ) -> Result<(Vec<WasmLayer>, bool)> {
+        let image_info = self.get_info(image_digest).await?;
+        let mut needs_precompile = can_precompile && !image_info.labels.contains_key(precompile_id);
+
+        let mut layers = vec![];
+        for original_config in configs {
+            let layer = self
+                .read_wasm_layer(
+                    original_config,
+                    image_digest,
+                    can_precompile,
+                    precompile_id,
+                    cache_key,
+                    &mut needs_precompile,
+                )
+                .await?;
+            layers.push(layer);
+        }
+        Ok((layers, needs_precompile))
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     async fn read_wasm_layer(
         &self,
         original_config: &oci_spec::image::Descriptor,
+        image_digest: &Digest,
         can_precompile: bool,
         precompile_id: &String,
+        cache_key: &str,
         needs_precompile: &mut bool,
     ) -> std::prelude::v1::Result<WasmLayer, ShimError> {
         let mut digest_to_load = original_config.digest().clone();
         if can_precompile {
             let info = self.get_info(&digest_to_load).await?;
             if let Some(label) = info.labels.get(precompile_id) {
-                // Safe to unwrap here since we already checked for the label's existence
-                digest_to_load = label.parse()?;
-                log::info!(
-                    "layer {} has pre-compiled content: {} ",
-                    info.digest,
-                    &digest_to_load
-                );
+                let precompiled: Digest = label.parse()?;
+                // content that can't be found is handled as a failure to load it below
+                let stale = match self.get_info(&precompiled).await {
+                    Ok(precompiled_info) => {
+                        precompiled_info
+                            .labels
+                            .get(CACHE_KEY_LABEL)
+                            .map(String::as_str)
+                            != Some(cache_key)
+                    }
+                    Err(_) => false,
+                };
+                if stale {
+                    log::warn!(
+                        "pre-compiled content {} of layer {} was compiled with a different compiler, recompiling",
+                        precompiled,
+                        info.digest
+                    );
+                    self.unlabel_precompiled(info, image_digest, precompile_id, &precompiled)
+                        .await?;
+                    *needs_precompile = true;
+                } else {
+                    digest_to_load = precompiled;
+                    log::info!(
+                        "layer {} has pre-compiled content: {} ",
+                        info.digest,
+                        &digest_to_load
+                    );
+                }
             }
         }
         log::debug!("loading digest: {} ", &digest_to_load);
-        let res = self
-            .read_content(&digest_to_load)
-            .await
-            .map(|module| WasmLayer {
-                config: original_config.clone(),
-                layer: module,
-            });
+        let res = self.read_layer(original_config, &digest_to_load).await;
 
         match res {
             Ok(res) => Ok(res),
@@ -552,21 +773,87 @@ impl Client {
                 log::error!("failed to load precompiled layer: {err}");
                 log::error!("falling back to original layer and marking for recompile");
                 *needs_precompile = can_precompile; // only mark for recompile if engine is capable
-                self.read_content(original_config.digest())
+                self.read_layer(original_config, original_config.digest())
                     .await
-                    .map(|module| WasmLayer {
-                        config: original_config.clone(),
-                        layer: module,
-                    })
             }
         }
     }
+
+    /// Read the content `digest` of the layer `config`, to a file in the layers directory
+    /// for a large layer, so that the shim doesn't keep it in memory, or for precompiled content,
+    /// that the engines map from the file
+    async fn read_layer(
+        &self,
+        config: &oci_spec::image::Descriptor,
+        digest: &Digest,
+    ) -> Result<WasmLayer> {
+        let precompiled = digest != config.digest();
+        let path = self
+            .layers_dir
+            .as_ref()
+            .filter(|_| config.size() > LARGE_LAYER_SIZE || precompiled)
+            .map(|dir| dir.join(config.digest().digest()));
+
+        let Some(path) = path else {
+            return Ok(WasmLayer {
+                config: config.clone(),
+                layer: self.read_content(digest).await?,
+                path: None,
+            });
+        };
+
+        log::debug!("streaming large layer {} to {}", digest, path.display());
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        self.read_content_to_file(digest, &path).await?;
+        Ok(WasmLayer {
+            config: config.clone(),
+            layer: vec![],
+            path: Some(path),
+        })
+    }
+
+    /// Remove the references to stale precompiled content from the original layer and the image,
+    /// and the labels of the content, so that containerd can garbage collect it
+    async fn unlabel_precompiled(
+        &self,
+        mut layer_info: Info,
+        image_digest: &Digest,
+        precompile_id: &str,
+        precompiled: &Digest,
+    ) -> Result<()> {
+        let mut precompiled_info = self.get_info(precompiled).await?;
+        precompiled_info
+            .labels
+            .retain(|k, _| !k.starts_with(PRECOMPILE_PREFIX));
+        self.update_info(precompiled_info).await?;
+
+        let precompiled = precompiled.to_string();
+        let is_stale_ref = |key: &String, value: &String| {
+            key.starts_with(GC_REF_PRECOMPILE_PREFIX) && *value == precompiled
+        };
+
+        layer_info.labels.remove(precompile_id);
+        layer_info.labels.retain(|k, v| !is_stale_ref(k, v));
+        self.update_info(layer_info).await?;
+
+        let mut image_info = self.get_info(image_digest).await?;
+        image_info.labels.remove(precompile_id);
+        image_info.labels.retain(|k, v| !is_stale_ref(k, v));
+        self.update_info(image_info).await?;
+        Ok(())
+    }
 }
 
 fn precompile_label(name: &str, version: &str) -> String {
     format!("{}/{}/{}", PRECOMPILE_PREFIX, name, version)
 }
 
+fn precompile_gc_ref_label(name: &str, index: usize) -> String {
+    format!("{}{}.{}", GC_REF_PRECOMPILE_PREFIX, name, index)
+}
+
 fn is_wasm_layer(media_type: &MediaType, supported_layer_types: &[&str]) -> bool {
     let supported = supported_layer_types.contains(&media_type.to_string().as_str());
     log::debug!(
@@ -577,6 +864,41 @@ fn is_wasm_layer(media_type: &MediaType, supported_layer_types: &[&str]) -> bool
     supported
 }
 
+fn is_wasm_artifact(config_media_type: &MediaType) -> bool {
+    config_media_type.to_string() == oci::WASM_ARTIFACT_CONFIG_MEDIA_TYPE
+}
+
+/// Media types of the index of a multi-platform image
+const IMAGE_INDEX_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+fn is_image_index(media_type: &str) -> bool {
+    IMAGE_INDEX_MEDIA_TYPES.contains(&media_type)
+}
+
+/// The manifest of a multi-platform image that the shim runs: the first wasm manifest of the index,
+/// e.g., `wasip1/wasm` or `wasip2/wasm`, or its first manifest, e.g., for the native fallback
+fn select_manifest(index: &ImageIndex) -> Option<&Descriptor> {
+    let manifests = index.manifests();
+    manifests
+        .iter()
+        .find(|m| {
+            m.platform()
+                .as_ref()
+                .is_some_and(|p| matches!(p.architecture(), Arch::Wasm))
+        })
+        .or_else(|| manifests.first())
+}
+
+/// The part of the config of an image with its entrypoint and env
+#[derive(Deserialize)]
+struct ConfigBlob {
+    #[serde(default)]
+    config: Option<ImageConfig>,
+}
+
 async fn send_message(
     request: WriteContentRequest,
     response_stream: &mut Streaming<WriteContentResponse>,
@@ -698,6 +1020,34 @@ mod tests {
         assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_precompiled_layers_are_kept_in_files() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let layers_dir = tempfile::tempdir().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
+            .await
+            .unwrap()
+            .with_layers_dir(layers_dir.path());
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (_image_name, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomiplerEngine::new(Some(()));
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+
+        // when the layers are precompiled, and when the precompiled content is loaded
+        for _ in 0..2 {
+            let (layers, _) = client.load_modules(&container_name, &engine).await.unwrap();
+            assert_eq!(layers.len(), 1);
+            assert!(layers[0].layer.is_empty());
+            assert!(layers[0].path().unwrap().starts_with(layers_dir.path()));
+            assert_eq!(&*layers[0].bytes().unwrap(), fake_precompiled_bytes.bytes);
+        }
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn test_layers_are_recompiled_if_version_changes() {
         let path = PathBuf::from("/run/containerd/containerd.sock");
@@ -721,6 +1071,110 @@ mod tests {
         assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_precompiled_content_lives_as_long_as_the_image() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
+            .await
+            .unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (_image_name, container_name, cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomiplerEngine::new(Some(()));
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+
+        let (_, _) = client.load_modules(&container_name, &engine).await.unwrap();
+        let precompiled = format!("sha256:{}", digest(fake_precompiled_bytes.bytes.clone()));
+
+        // the lease is released, but the content is referenced by the image
+        oci_helpers::prune_content().unwrap();
+        let data = client.read_content(&precompiled).await.unwrap();
+        assert_eq!(data, fake_precompiled_bytes.bytes);
+
+        // removes the container and the image
+        drop(cleanup);
+        oci_helpers::prune_content().unwrap();
+        oci_helpers::wait_for_content_removal(&precompiled).unwrap();
+        client
+            .read_content(&precompiled)
+            .await
+            .expect_err("precompiled content should be removed with the image");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_concurrent_loads_precompile_once() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
+            .await
+            .unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (_image_name, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomiplerEngine::new(Some(()));
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+
+        let loads = (0..5).map(|_| client.load_modules(&container_name, &engine));
+        let results = futures::future::join_all(loads).await;
+
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+        for result in results {
+            let (layers, _) = result.unwrap();
+            assert_eq!(layers[0].layer, fake_precompiled_bytes.bytes);
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_layers_are_recompiled_once_if_cache_key_changes() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
+            .await
+            .unwrap();
+
+        let fake_bytes = generate_content("original", WASM_LAYER_MEDIA_TYPE);
+        let (image_name, container_name, _cleanup) = generate_test_container(None, &[&fake_bytes]);
+
+        let fake_precompiled_bytes = generate_content("precompiled", WASM_LAYER_MEDIA_TYPE);
+        let mut engine = FakePrecomiplerEngine::new(Some(()));
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_precompiled_bytes);
+
+        let (_, _) = client.load_modules(&container_name, &engine).await.unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 1);
+
+        // same precompile id, but the compiler changed
+        let fake_recompiled_bytes = generate_content("recompiled", WASM_LAYER_MEDIA_TYPE);
+        engine.cache_key = "fake/v2".to_string();
+        engine.add_precompiled_bits(fake_bytes.bytes.clone(), &fake_recompiled_bytes);
+
+        let (layers, _) = client.load_modules(&container_name, &engine).await.unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
+        assert_eq!(layers[0].layer, fake_recompiled_bytes.bytes);
+
+        let (layers, _) = client.load_modules(&container_name, &engine).await.unwrap();
+        assert_eq!(engine.precompile_called.load(Ordering::SeqCst), 2);
+        assert_eq!(layers[0].layer, fake_recompiled_bytes.bytes);
+
+        // the stale content isn't referenced anymore
+        let stale = format!("sha256:{}", digest(fake_precompiled_bytes.bytes.clone()));
+        let (manifest, image_digest) = client
+            .get_image_manifest_and_digest(&image_name)
+            .await
+            .unwrap();
+        let layer_info = client
+            .get_info(manifest.layers()[0].digest())
+            .await
+            .unwrap();
+        let image_info = client.get_info(&image_digest).await.unwrap();
+        assert!(!layer_info.labels.values().any(|v| *v == stale));
+        assert!(!image_info.labels.values().any(|v| *v == stale));
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn test_layers_are_precompiled() {
         let path = PathBuf::from("/run/containerd/containerd.sock");
@@ -920,6 +1374,101 @@ mod tests {
         );
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_layers_keep_image_order() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
+            .await
+            .unwrap();
+
+        let mut main = generate_content("main", WASM_LAYER_MEDIA_TYPE);
+        main.name = Some("main.wasm".to_string());
+        let mut library = generate_content("library", WASM_LAYER_MEDIA_TYPE);
+        library.name = Some("library.wasm".to_string());
+
+        let (_image_name, container_name, _cleanup) =
+            generate_test_container(None, &[&main, &library]);
+
+        let engine = FakePrecomiplerEngine::new(None);
+        let (layers, _) = client.load_modules(container_name, &engine).await.unwrap();
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].name(), Some("main.wasm"));
+        assert_eq!(layers[0].layer, main.bytes);
+        assert_eq!(layers[1].name(), Some("library.wasm"));
+        assert_eq!(layers[1].layer, library.bytes);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_image_info_of_the_container() {
+        let path = PathBuf::from("/run/containerd/containerd.sock");
+        let path = path.to_str().unwrap();
+        let client = Client::connect(path, crate::testing::TEST_NAMESPACE)
+            .await
+            .unwrap();
+
+        let main = generate_content("main", WASM_LAYER_MEDIA_TYPE);
+        let (image_name, container_name, _cleanup) = generate_test_container(None, &[&main]);
+
+        let engine = FakePrecomiplerEngine::new(None);
+        let (_, info) = client.load_modules(container_name, &engine).await.unwrap();
+        assert_eq!(info.platform.os().to_string(), "wasip1");
+        assert_eq!(info.platform.architecture(), &Arch::Wasm);
+        let entrypoint = info.config.unwrap().entrypoint().clone();
+        assert_eq!(entrypoint, Some(vec!["_start".to_string()]));
+
+        let (_, digest) = client
+            .get_image_manifest_and_digest(&image_name)
+            .await
+            .unwrap();
+        assert_eq!(info.manifest_digest, Some(digest.to_string()));
+    }
+
+    #[test]
+    fn test_select_manifest() {
+        use oci_spec::image::{DescriptorBuilder, ImageIndexBuilder, Os, PlatformBuilder};
+
+        let manifest = |digest: &str, os: Os, arch: Arch| {
+            let digest = format!("sha256:{}", digest.repeat(64));
+            DescriptorBuilder::default()
+                .media_type(MediaType::ImageManifest)
+                .digest(Digest::try_from(digest).unwrap())
+                .size(100u64)
+                .platform(
+                    PlatformBuilder::default()
+                        .os(os)
+                        .architecture(arch)
+                        .build()
+                        .unwrap(),
+                )
+                .build()
+                .unwrap()
+        };
+        let index = |manifests: Vec<Descriptor>| {
+            ImageIndexBuilder::default()
+                .schema_version(2u32)
+                .manifests(manifests)
+                .build()
+                .unwrap()
+        };
+
+        let linux = manifest("a", Os::Linux, Arch::Amd64);
+        let wasip2 = manifest("b", Os::Other("wasip2".to_string()), Arch::Wasm);
+        let wasip1 = manifest("c", Os::Other("wasip1".to_string()), Arch::Wasm);
+
+        let multi = index(vec![linux.clone(), wasip2.clone(), wasip1]);
+        assert_eq!(select_manifest(&multi), Some(&wasip2));
+
+        let native = index(vec![linux.clone()]);
+        assert_eq!(select_manifest(&native), Some(&linux));
+
+        assert_eq!(select_manifest(&index(vec![])), None);
+        assert!(is_image_index("application/vnd.oci.image.index.v1+json"));
+        assert!(!is_image_index(
+            "application/vnd.oci.image.manifest.v1+json"
+        ));
+    }
+
     fn generate_test_container(
         name: Option<String>,
         original: &[&oci_helpers::ImageContent],
@@ -948,6 +1497,7 @@ mod tests {
         ImageContent {
             bytes: content,
             media_type: media_type.to_string(),
+            name: None,
         }
     }
 
@@ -959,6 +1509,7 @@ mod tests {
     #[derive(Clone, Debug)]
     struct FakePrecomiplerEngine {
         precompile_id: Option<String>,
+        cache_key: String,
         precompiled_layers: HashMap<String, Vec<u8>>,
         precompile_called: Arc<AtomicI32>,
         layers_compiled_per_call: Arc<AtomicI32>,
@@ -976,6 +1527,7 @@ mod tests {
 
             FakePrecomiplerEngine {
                 precompile_id,
+                cache_key: "fake/v1".to_string(),
                 precompiled_layers: HashMap::new(),
                 precompile_called: Arc::new(AtomicI32::new(0)),
                 layers_compiled_per_call: Arc::new(AtomicI32::new(0)),
@@ -1005,6 +1557,10 @@ mod tests {
             self.precompile_id.clone()
         }
 
+        fn compiler_cache_key(&self) -> String {
+            self.cache_key.clone()
+        }
+
         fn supported_layers_types() -> &'static [&'static str] {
             &[WASM_LAYER_MEDIA_TYPE, "textfile"]
         }
@@ -0,0 +1,144 @@
+//! Lock shared by the shims of a node, so that a layer is precompiled once
+//! when many containers of the same image start at the same time.
+
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::io::{Error, ErrorKind};
+use std::os::fd::AsRawFd as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use sha256::digest;
+
+/// How long to wait for another shim precompiling the same layers,
+/// before precompiling them in this shim
+pub(super) const PRECOMPILE_LOCK_TIMEOUT: Duration = Duration::from_secs(120);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// File locks on layers for a compiler, released when dropped.
+///
+/// The locks are `flock`s, so that they are released by the kernel if the shim holding them crashes.
+pub(super) struct PrecompileLock {
+    _files: Vec<File>,
+}
+
+impl PrecompileLock {
+    /// Lock the layers `digests` for the compiler `cache_key`.
+    /// Returns `None` if the locks can't be taken within `timeout`, e.g., when the shim
+    /// holding them hangs, or if the lock files can't be opened.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(digests), level = "Info")
+    )]
+    pub async fn acquire(
+        digests: impl IntoIterator<Item = String>,
+        cache_key: &str,
+        timeout: Duration,
+    ) -> Option<Self> {
+        Self::acquire_in(&lock_dir(), digests, cache_key, timeout).await
+    }
+
+    async fn acquire_in(
+        dir: &Path,
+        digests: impl IntoIterator<Item = String>,
+        cache_key: &str,
+        timeout: Duration,
+    ) -> Option<Self> {
+        // always lock in the same order, so that shims of images sharing layers don't deadlock
+        let mut keys = digests
+            .into_iter()
+            .map(|d| digest(format!("{d}/{cache_key}")))
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+
+        if let Err(err) = create_dir_all(dir) {
+            log::warn!("failed to create precompile lock directory: {err}");
+            return None;
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut files = Vec::with_capacity(keys.len());
+        for key in keys {
+            let file = match OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(dir.join(&key))
+            {
+                Ok(file) => file,
+                Err(err) => {
+                    log::warn!("failed to open precompile lock {key}: {err}");
+                    return None;
+                }
+            };
+            loop {
+                match try_lock(&file) {
+                    Ok(true) => break,
+                    Ok(false) if Instant::now() < deadline => {
+                        log::debug!("waiting for another shim precompiling {key}");
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                    }
+                    Ok(false) => {
+                        log::warn!("timeout waiting for precompile lock {key}, compiling locally");
+                        return None;
+                    }
+                    Err(err) => {
+                        log::warn!("failed to take precompile lock {key}: {err}");
+                        return None;
+                    }
+                }
+            }
+            files.push(file);
+        }
+
+        Some(Self { _files: files })
+    }
+}
+
+fn lock_dir() -> PathBuf {
+    std::env::temp_dir().join("runwasi-precompile")
+}
+
+fn try_lock(file: &File) -> std::io::Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    match Error::last_os_error() {
+        err if err.kind() == ErrorKind::WouldBlock => Ok(false),
+        err if err.kind() == ErrorKind::Interrupted => Ok(false),
+        err => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn lock_waits_for_holder() {
+        let dir = tempfile::tempdir().unwrap();
+        let digests = || vec!["sha256:aa".to_string(), "sha256:bb".to_string()];
+
+        let lock = PrecompileLock::acquire_in(dir.path(), digests(), "key", Duration::ZERO).await;
+        assert!(lock.is_some());
+
+        // held, for the same layers and compiler
+        let other = PrecompileLock::acquire_in(
+            dir.path(),
+            vec!["sha256:bb".to_string()],
+            "key",
+            Duration::from_millis(300),
+        )
+        .await;
+        assert!(other.is_none());
+
+        // not held for another compiler
+        let other =
+            PrecompileLock::acquire_in(dir.path(), digests(), "other", Duration::ZERO).await;
+        assert!(other.is_some());
+
+        drop(lock);
+        let other = PrecompileLock::acquire_in(dir.path(), digests(), "key", Duration::ZERO).await;
+        assert!(other.is_some());
+    }
+}
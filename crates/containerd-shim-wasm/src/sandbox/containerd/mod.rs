@@ -2,5 +2,6 @@
 
 mod client;
 mod lease;
+mod lock;
 
 pub(crate) use client::Client;
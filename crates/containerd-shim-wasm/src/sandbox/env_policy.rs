@@ -0,0 +1,171 @@
+//! Policy on the environment variables passed to the modules.
+//!
+//! The policy of the node is set in the shim options, e.g., in the containerd config:
+//!
+//! ```toml
+//! [plugins."io.containerd.grpc.v1.cri".containerd.runtimes.wasm.options.EnvPolicy]
+//!   Deny = ["*_SERVICE_HOST", "*_SERVICE_PORT*", "AWS_*"]
+//!   Inject = { PLATFORM = "wasm" }
+//! ```
+//!
+//! A container can further restrict its variables with the `io.runwasi.env-policy`
+//! annotation, e.g., `{"allow": ["APP_*"]}`.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// Annotation with the env policy of a container, as JSON
+pub const ENV_POLICY_ANNOTATION: &str = "io.runwasi.env-policy";
+
+/// Patterns of variable names, where `*` matches any characters.
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct EnvPolicy {
+    /// Only the variables matching one of the patterns are passed, or all of them when empty.
+    #[serde(alias = "Allow")]
+    pub allow: Vec<String>,
+    /// The variables matching one of the patterns are never passed, even when allowed.
+    #[serde(alias = "Deny")]
+    pub deny: Vec<String>,
+    /// Variables added to the environment, replacing the variables with the same name.
+    /// Only for the policy of the node.
+    #[serde(alias = "Inject")]
+    pub inject: BTreeMap<String, String>,
+}
+
+impl EnvPolicy {
+    /// The policy of a container, from its `io.runwasi.env-policy` annotation
+    pub fn from_annotations(
+        annotations: Option<&HashMap<String, String>>,
+    ) -> anyhow::Result<Option<Self>> {
+        let Some(policy) = annotations.and_then(|a| a.get(ENV_POLICY_ANNOTATION)) else {
+            return Ok(None);
+        };
+        let mut policy: Self = serde_json::from_str(policy)
+            .with_context(|| format!("invalid {ENV_POLICY_ANNOTATION} annotation"))?;
+        if !policy.inject.is_empty() {
+            log::warn!("ignoring the variables injected by the {ENV_POLICY_ANNOTATION} annotation");
+            policy.inject.clear();
+        }
+        Ok(Some(policy))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty() && self.inject.is_empty()
+    }
+
+    pub fn allows(&self, name: &str) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|p| matches(p, name));
+        allowed && !self.deny.iter().any(|p| matches(p, name))
+    }
+
+    /// Filter the `KEY=VALUE` variables `env` with the policy of the node and the one of
+    /// the `container`, and add the injected variables
+    pub fn apply(&self, container: Option<&EnvPolicy>, env: &[String]) -> Vec<String> {
+        let mut env = env
+            .iter()
+            .filter(|var| {
+                let name = var.split_once('=').map_or(var.as_str(), |(name, _)| name);
+                if self.allows(name) && container.is_none_or(|c| c.allows(name)) {
+                    return true;
+                }
+                log::debug!("removing the variable {name} from the environment");
+                false
+            })
+            .filter(|var| {
+                let name = var.split_once('=').map_or(var.as_str(), |(name, _)| name);
+                !self.inject.contains_key(name)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        env.extend(self.inject.iter().map(|(k, v)| format!("{k}={v}")));
+        env
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any characters
+fn matches(pattern: &str, name: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return pattern == name;
+    };
+    let Some(name) = name.strip_prefix(prefix) else {
+        return false;
+    };
+    rest.is_empty()
+        || (0..=name.len())
+            .filter(|i| name.is_char_boundary(*i))
+            .any(|i| matches(rest, &name[i..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[&str]) -> Vec<String> {
+        vars.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("HOME", "HOME"));
+        assert!(!matches("HOME", "HOMES"));
+        assert!(matches("AWS_*", "AWS_SECRET_ACCESS_KEY"));
+        assert!(matches("*_SERVICE_HOST", "KUBERNETES_SERVICE_HOST"));
+        assert!(matches("*_SERVICE_PORT*", "REDIS_SERVICE_PORT_TCP"));
+        assert!(!matches("*_SERVICE_HOST", "KUBERNETES_SERVICE_PORT"));
+        assert!(matches("*", ""));
+    }
+
+    #[test]
+    fn test_apply_node_policy() {
+        let policy = EnvPolicy {
+            deny: vec!["*_SERVICE_HOST".to_string(), "SECRET".to_string()],
+            inject: BTreeMap::from([("PLATFORM".to_string(), "wasm".to_string())]),
+            ..Default::default()
+        };
+        let env = policy.apply(
+            None,
+            &env(&[
+                "PATH=/",
+                "DB_SERVICE_HOST=10.0.0.1",
+                "SECRET=s",
+                "PLATFORM=linux",
+            ]),
+        );
+        assert_eq!(env, ["PATH=/", "PLATFORM=wasm"]);
+    }
+
+    #[test]
+    fn test_apply_container_policy() -> anyhow::Result<()> {
+        let annotations = HashMap::from([(
+            ENV_POLICY_ANNOTATION.to_string(),
+            r#"{"allow": ["APP_*"], "inject": {"SNEAKY": "1"}}"#.to_string(),
+        )]);
+        let container = EnvPolicy::from_annotations(Some(&annotations))?;
+        assert!(container.as_ref().is_some_and(|c| c.inject.is_empty()));
+
+        let node = EnvPolicy {
+            deny: vec!["APP_TOKEN".to_string()],
+            ..Default::default()
+        };
+        let env = node.apply(
+            container.as_ref(),
+            &env(&["PATH=/", "APP_NAME=demo", "APP_TOKEN=t"]),
+        );
+        assert_eq!(env, ["APP_NAME=demo"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_annotation() {
+        let annotations = HashMap::from([(
+            ENV_POLICY_ANNOTATION.to_string(),
+            "deny everything".to_string(),
+        )]);
+        assert!(EnvPolicy::from_annotations(Some(&annotations)).is_err());
+        assert!(matches!(EnvPolicy::from_annotations(None), Ok(None)));
+    }
+}
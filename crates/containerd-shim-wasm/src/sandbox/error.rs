@@ -29,6 +29,15 @@ pub enum Error {
     /// Supplied arguments/options/config is invalid
     #[error("invalid argument: {0}")]
     InvalidArgument(String),
+    /// The runtime spec of the container is invalid, or can't be read
+    #[error("invalid spec: {0}")]
+    InvalidSpec(String),
+    /// The engine failed, e.g., to build the container or to load its module
+    #[error("engine failure: {0:#}")]
+    EngineFailure(#[source] AnyError),
+    /// A failure of the shim itself
+    #[error("internal error: {0}")]
+    Internal(String),
     /// Any other error
     #[error("{0}")]
     Any(#[from] AnyError),
@@ -38,6 +47,12 @@ pub enum Error {
     /// Error while parsing JSON
     #[error("{0}")]
     Json(#[from] serde_json::Error),
+    /// The operation is not supported by the instance
+    #[error("unsupported: {0}")]
+    Unsupported(String),
+    /// The operation did not finish before its deadline
+    #[error("deadline exceeded: {0}")]
+    DeadlineExceeded(String),
     /// Error from the system
     #[cfg(unix)]
     #[error("{0}")]
@@ -52,41 +67,58 @@ pub enum Error {
 
 pub type Result<T, E = Error> = ::std::result::Result<T, E>;
 
-impl From<Error> for ttrpc::Error {
-    fn from(e: Error) -> Self {
-        match e {
-            Error::Shim(ref s) => match s {
-                ShimError::InvalidArgument(s) => {
-                    ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::INVALID_ARGUMENT, s))
-                }
-                ShimError::NotFoundError(s) => {
-                    ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::NOT_FOUND, s))
-                }
-                _ => ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::UNKNOWN, s)),
-            },
-            Error::NotFound(ref s) => {
-                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::NOT_FOUND, s))
-            }
-            Error::AlreadyExists(ref s) => {
-                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::ALREADY_EXISTS, s))
-            }
-            Error::InvalidArgument(ref s) => {
-                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::INVALID_ARGUMENT, s))
+impl Error {
+    /// The ttrpc code of the error, that containerd maps to the kinds of its errors,
+    /// e.g., for kubelet to tell a missing container from an invalid one
+    pub fn code(&self) -> ttrpc::Code {
+        match self {
+            Error::Shim(ShimError::InvalidArgument(_)) => ttrpc::Code::INVALID_ARGUMENT,
+            Error::Shim(ShimError::NotFoundError(_)) => ttrpc::Code::NOT_FOUND,
+            Error::Shim(ShimError::Unimplemented(_)) => ttrpc::Code::UNIMPLEMENTED,
+            Error::NotFound(_) => ttrpc::Code::NOT_FOUND,
+            Error::AlreadyExists(_) => ttrpc::Code::ALREADY_EXISTS,
+            Error::InvalidArgument(_) | Error::InvalidSpec(_) | Error::Oci(_) => {
+                ttrpc::Code::INVALID_ARGUMENT
             }
-            Error::FailedPrecondition(ref s) => {
-                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::FAILED_PRECONDITION, s))
-            }
-            Error::Oci(ref _s) => {
-                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::UNKNOWN, e.to_string()))
-            }
-            Error::Any(ref s) => {
-                ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::UNKNOWN, s))
-            }
-            _ => ttrpc::Error::Others(e.to_string()),
+            Error::FailedPrecondition(_) => ttrpc::Code::FAILED_PRECONDITION,
+            Error::Unsupported(_) => ttrpc::Code::UNIMPLEMENTED,
+            Error::DeadlineExceeded(_) => ttrpc::Code::DEADLINE_EXCEEDED,
+            Error::EngineFailure(_) | Error::Internal(_) | Error::Stdio(_) => ttrpc::Code::INTERNAL,
+            #[cfg(unix)]
+            Error::Errno(_) | Error::Libcontainer(_) => ttrpc::Code::INTERNAL,
+            Error::Shim(_)
+            | Error::Others(_)
+            | Error::Any(_)
+            | Error::Json(_)
+            | Error::Containerd(_) => ttrpc::Code::UNKNOWN,
+        }
+    }
+
+    /// The message of the status of the error, without the prefix of its kind,
+    /// which the code carries, and with the chain of its sources
+    fn message(&self) -> String {
+        match self {
+            Error::Shim(ShimError::InvalidArgument(s) | ShimError::NotFoundError(s))
+            | Error::NotFound(s)
+            | Error::AlreadyExists(s)
+            | Error::InvalidArgument(s)
+            | Error::InvalidSpec(s)
+            | Error::FailedPrecondition(s)
+            | Error::Unsupported(s)
+            | Error::DeadlineExceeded(s)
+            | Error::Internal(s) => s.clone(),
+            Error::Any(err) | Error::EngineFailure(err) => format!("{err:#}"),
+            _ => self.to_string(),
         }
     }
 }
 
+impl From<Error> for ttrpc::Error {
+    fn from(e: Error) -> Self {
+        ttrpc::Error::RpcStatus(ttrpc::get_status(e.code(), e.message()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use thiserror::Error;
@@ -141,6 +173,26 @@ mod tests {
             _ => panic!("unexpected error"),
         }
 
+        let e = Error::Unsupported("exec".to_string());
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::UNIMPLEMENTED);
+                assert_eq!(s.message, "exec");
+            }
+            _ => panic!("unexpected error"),
+        }
+
+        let e = Error::DeadlineExceeded("create".to_string());
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::DEADLINE_EXCEEDED);
+                assert_eq!(s.message, "create");
+            }
+            _ => panic!("unexpected error"),
+        }
+
         let e = Error::Shim(ShimError::InvalidArgument("invalid argument".to_string()));
         let t: ttrpc::Error = e.into();
         match t {
@@ -160,5 +212,61 @@ mod tests {
             }
             _ => panic!("unexpected error"),
         }
+
+        let e = Error::InvalidSpec("rootfs is not set".to_string());
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::INVALID_ARGUMENT);
+                assert_eq!(s.message, "rootfs is not set");
+            }
+            _ => panic!("unexpected error"),
+        }
+
+        let e = Error::Internal("internal".to_string());
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::INTERNAL);
+                assert_eq!(s.message, "internal");
+            }
+            _ => panic!("unexpected error"),
+        }
+
+        let e = Error::Stdio(std::io::Error::other("broken stdio"));
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::INTERNAL);
+                assert_eq!(s.message, "broken stdio");
+            }
+            _ => panic!("unexpected error"),
+        }
+    }
+
+    #[test]
+    fn test_engine_failure_keeps_its_sources() {
+        use std::error::Error as _;
+
+        let err = AnyError::new(TestError::AnError("out of memory".to_string()))
+            .context("failed to load the module");
+        let e = Error::EngineFailure(err);
+        assert_eq!(
+            e.to_string(),
+            "engine failure: failed to load the module: out of memory"
+        );
+        assert_eq!(
+            e.source().map(ToString::to_string).as_deref(),
+            Some("failed to load the module")
+        );
+
+        let t: ttrpc::Error = e.into();
+        match t {
+            ttrpc::Error::RpcStatus(s) => {
+                assert_eq!(s.code(), ttrpc::Code::INTERNAL);
+                assert_eq!(s.message, "failed to load the module: out of memory");
+            }
+            _ => panic!("unexpected error"),
+        }
     }
 }
@@ -0,0 +1,149 @@
+//! Directories of the node granted to every container of the shim.
+//!
+//! The directories are set by the operator in the shim options, e.g., in the containerd config:
+//!
+//! ```toml
+//! [[plugins."io.containerd.grpc.v1.cri".containerd.runtimes.wasm.options.HostDirs]]
+//!   host = "/var/lib/models"
+//!   guest = "/models"
+//!   readonly = true
+//! ```
+//!
+//! They are bind mounted in the container after the mounts of its spec, so the engines preopen
+//! them as the other mounts. A container can't add directories to the list, and a mount of its spec
+//! on the same guest path replaces the directory of the node.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, ensure};
+use oci_spec::runtime::{MountBuilder, Spec};
+use serde::{Deserialize, Serialize};
+
+/// A directory of the node, visible to the guest at `guest`
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct HostDir {
+    /// The directory on the node
+    #[serde(alias = "Host")]
+    pub host: PathBuf,
+    /// The path of the directory for the guest
+    #[serde(alias = "Guest")]
+    pub guest: PathBuf,
+    /// Whether the guest can only read the directory
+    #[serde(alias = "Readonly", alias = "ReadOnly")]
+    pub readonly: bool,
+}
+
+/// Append the bind mounts of `dirs` to the mounts of `spec`, except for the guest paths that the
+/// spec already mounts. Returns whether the spec changed.
+pub fn mount_host_dirs(spec: &mut Spec, dirs: &[HostDir]) -> Result<bool> {
+    let mut mounts = spec.mounts().clone().unwrap_or_default();
+    let mut changed = false;
+    for dir in dirs {
+        ensure!(
+            dir.host.is_absolute() && dir.guest.is_absolute() && dir.guest != Path::new("/"),
+            "invalid host directory {:?} at {:?}, the paths must be absolute",
+            dir.host,
+            dir.guest
+        );
+        ensure!(
+            dir.host.is_dir(),
+            "the host directory {:?} is not a directory",
+            dir.host
+        );
+        if mounts.iter().any(|m| m.destination() == &dir.guest) {
+            log::debug!(
+                "the spec mounts {:?}, skipping the host directory",
+                dir.guest
+            );
+            continue;
+        }
+        let mode = if dir.readonly { "ro" } else { "rw" };
+        mounts.push(
+            MountBuilder::default()
+                .source(&dir.host)
+                .destination(&dir.guest)
+                .typ("bind")
+                .options(vec!["rbind".to_string(), mode.to_string()])
+                .build()?,
+        );
+        changed = true;
+    }
+    if changed {
+        spec.set_mounts(Some(mounts));
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::runtime::{Mount, SpecBuilder};
+
+    use super::*;
+
+    fn bind(source: &Path, destination: &str, mode: &str) -> Mount {
+        MountBuilder::default()
+            .source(source)
+            .destination(destination)
+            .typ("bind")
+            .options(vec!["rbind".to_string(), mode.to_string()])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_mount_host_dirs() -> Result<()> {
+        let models = tempfile::tempdir()?;
+        let fonts = tempfile::tempdir()?;
+        let volume = tempfile::tempdir()?;
+        let dirs = [
+            HostDir {
+                host: models.path().to_path_buf(),
+                guest: "/models".into(),
+                readonly: true,
+            },
+            HostDir {
+                host: fonts.path().to_path_buf(),
+                guest: "/fonts".into(),
+                readonly: false,
+            },
+        ];
+
+        // the mount of the spec is kept over the host directory
+        let mut spec = SpecBuilder::default()
+            .mounts(vec![bind(volume.path(), "/fonts", "rw")])
+            .build()?;
+        assert!(mount_host_dirs(&mut spec, &dirs)?);
+        assert_eq!(
+            spec.mounts().as_deref(),
+            Some(
+                [
+                    bind(volume.path(), "/fonts", "rw"),
+                    bind(models.path(), "/models", "ro"),
+                ]
+                .as_slice()
+            )
+        );
+
+        // the spec mounts the directory already
+        assert!(!mount_host_dirs(&mut spec, &dirs[..1])?);
+
+        let mut spec = SpecBuilder::default().mounts(vec![]).build()?;
+        assert!(!mount_host_dirs(&mut spec, &[])?);
+
+        let relative = HostDir {
+            host: "models".into(),
+            guest: "/models".into(),
+            readonly: true,
+        };
+        assert!(mount_host_dirs(&mut spec, &[relative]).is_err());
+        let missing = HostDir {
+            host: models.path().join("missing"),
+            guest: "/models".into(),
+            readonly: true,
+        };
+        assert!(mount_host_dirs(&mut spec, &[missing]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+//! Description of the shim, printed as JSON by its `info` command, and as the `RuntimeInfo`
+//! protobuf message of containerd by its `-info` flag, which containerd 2.0 calls to
+//! introspect the runtime, e.g., for `crictl info`.
+
+use std::collections::HashMap;
+
+use prost::Message as _;
+use serde::Serialize;
+
+use crate::sandbox::EngineInfo;
+
+/// Type URL of the OCI runtime features, in the `features` of the runtime info
+const FEATURES_TYPE_URL: &str =
+    "types.containerd.io/opencontainers/runtime-spec/1/features/Features";
+
+/// The shim binary and the engine it embeds
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct ShimInfo {
+    /// Name of the shim binary, e.g., `containerd-shim-wasmtime-v1`
+    pub name: String,
+    /// Name of the runtime, e.g., `wasmtime`
+    pub runtime: String,
+    /// Version of the shim crate
+    pub version: String,
+    /// Git revision of the shim, if known
+    pub revision: Option<String>,
+    pub engine: EngineInfo,
+}
+
+impl ShimInfo {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("the shim info serializes to JSON")
+    }
+
+    /// The info as the encoded `containerd.types.RuntimeInfo`.
+    /// The engine is described in the annotations of the runtime info, and of its OCI features.
+    pub fn to_runtime_info(&self) -> Vec<u8> {
+        let annotations = self.annotations();
+        let features = serde_json::json!({
+            "ociVersionMin": "1.0.0",
+            "ociVersionMax": "1.2.0",
+            "annotations": annotations,
+        });
+        RuntimeInfo {
+            name: self.name.clone(),
+            version: Some(RuntimeVersion {
+                version: self.version.clone(),
+                revision: self.revision.clone().unwrap_or_default(),
+            }),
+            options: None,
+            features: Some(ProtoAny {
+                type_url: FEATURES_TYPE_URL.to_string(),
+                value: features.to_string().into_bytes(),
+            }),
+            annotations,
+        }
+        .encode_to_vec()
+    }
+
+    fn annotations(&self) -> HashMap<String, String> {
+        let engine = &self.engine;
+        let mut annotations = HashMap::from([
+            ("runwasi.io/engine-name".to_string(), engine.name.clone()),
+            (
+                "runwasi.io/engine-features".to_string(),
+                engine.features.join(","),
+            ),
+            (
+                "runwasi.io/precompile".to_string(),
+                engine.precompile.to_string(),
+            ),
+            (
+                "runwasi.io/media-types".to_string(),
+                engine.media_types.join(","),
+            ),
+        ]);
+        if let Some(version) = &engine.version {
+            annotations.insert("runwasi.io/engine-version".to_string(), version.clone());
+        }
+        annotations
+    }
+}
+
+/// `containerd.types.RuntimeInfo`, of `api/types/introspection.proto`
+#[derive(Clone, PartialEq, prost::Message)]
+struct RuntimeInfo {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(message, optional, tag = "2")]
+    version: Option<RuntimeVersion>,
+    #[prost(message, optional, tag = "3")]
+    options: Option<ProtoAny>,
+    #[prost(message, optional, tag = "4")]
+    features: Option<ProtoAny>,
+    #[prost(map = "string, string", tag = "5")]
+    annotations: HashMap<String, String>,
+}
+
+/// `containerd.types.RuntimeVersion`
+#[derive(Clone, PartialEq, prost::Message)]
+struct RuntimeVersion {
+    #[prost(string, tag = "1")]
+    version: String,
+    #[prost(string, tag = "2")]
+    revision: String,
+}
+
+/// `google.protobuf.Any`
+#[derive(Clone, PartialEq, prost::Message)]
+struct ProtoAny {
+    #[prost(string, tag = "1")]
+    type_url: String,
+    #[prost(bytes = "vec", tag = "2")]
+    value: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> ShimInfo {
+        ShimInfo {
+            name: "containerd-shim-wasmtime-v1".to_string(),
+            runtime: "wasmtime".to_string(),
+            version: "0.10.0".to_string(),
+            revision: None,
+            engine: EngineInfo {
+                name: "wasmtime".to_string(),
+                version: Some("27.0.0".to_string()),
+                features: vec!["simd".to_string(), "component-model".to_string()],
+                precompile: true,
+                media_types: vec!["application/wasm".to_string()],
+            },
+        }
+    }
+
+    #[test]
+    fn test_to_json() -> anyhow::Result<()> {
+        let json: serde_json::Value = serde_json::from_str(&info().to_json())?;
+        assert_eq!(json["name"], "containerd-shim-wasmtime-v1");
+        assert_eq!(json["revision"], serde_json::Value::Null);
+        assert_eq!(json["engine"]["version"], "27.0.0");
+        assert_eq!(json["engine"]["precompile"], true);
+        assert_eq!(json["engine"]["media_types"][0], "application/wasm");
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_runtime_info() -> anyhow::Result<()> {
+        let runtime_info = RuntimeInfo::decode(info().to_runtime_info().as_slice())?;
+        assert_eq!(runtime_info.name, "containerd-shim-wasmtime-v1");
+        assert_eq!(
+            runtime_info.version,
+            Some(RuntimeVersion {
+                version: "0.10.0".to_string(),
+                revision: String::new(),
+            })
+        );
+        assert_eq!(
+            runtime_info.annotations["runwasi.io/engine-features"],
+            "simd,component-model"
+        );
+        assert_eq!(runtime_info.annotations["runwasi.io/precompile"], "true");
+
+        let features = runtime_info.features.expect("the OCI features");
+        assert_eq!(features.type_url, FEATURES_TYPE_URL);
+        let features: serde_json::Value = serde_json::from_slice(&features.value)?;
+        assert_eq!(
+            features["annotations"]["runwasi.io/engine-version"],
+            "27.0.0"
+        );
+        Ok(())
+    }
+}
@@ -1,8 +1,11 @@
 //! Abstractions for running/managing a wasm/wasi instance.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use chrono::{DateTime, Utc};
+use futures::FutureExt as _;
+use oci_spec::runtime::{LinuxResources, Process};
+use prost::Message as _;
 use serde::{Deserialize, Serialize};
 
 use super::error::Error;
@@ -20,6 +23,9 @@ pub struct InstanceConfig {
     pub stderr: PathBuf,
     /// Path to the OCI bundle directory.
     pub bundle: PathBuf,
+    /// Whether the process runs in a terminal, whose input and output are
+    /// `stdin` and `stdout`.
+    pub terminal: bool,
     /// Namespace for containerd
     pub namespace: String,
     /// GRPC address back to main containerd
@@ -28,6 +34,109 @@ pub struct InstanceConfig {
     pub config: Config,
 }
 
+/// Options for starting an additional process in a running instance.
+/// This is passed to the `Instance::exec` method.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct ExecConfig {
+    /// Optional stdin named pipe path.
+    pub stdin: PathBuf,
+    /// Optional stdout named pipe path.
+    pub stdout: PathBuf,
+    /// Optional stderr named pipe path.
+    pub stderr: PathBuf,
+    /// OCI process spec of the exec process, with the args to run.
+    pub process: Process,
+}
+
+/// Metrics of the WebAssembly engine running an instance, on top of the metrics of its cgroup.
+/// The metrics that the engine doesn't have are `None`.
+///
+/// They are appended to the payload of the Stats response as the field `EngineMetrics::STATS_FIELD`,
+/// which the consumers that only know the cgroup metrics skip.
+#[derive(Clone, PartialEq, Serialize, Deserialize, prost::Message)]
+pub struct EngineMetrics {
+    /// Size of the linear memories, in bytes
+    #[prost(uint64, optional, tag = "1")]
+    pub linear_memory_bytes: Option<u64>,
+    /// Number of elements of the tables
+    #[prost(uint64, optional, tag = "2")]
+    pub table_elements: Option<u64>,
+    /// Fuel consumed by the module, when the engine meters it
+    #[prost(uint64, optional, tag = "3")]
+    pub fuel_consumed: Option<u64>,
+    /// Time spent compiling the module, in nanoseconds
+    #[prost(uint64, optional, tag = "4")]
+    pub compilation_time_ns: Option<u64>,
+}
+
+/// The engine metrics in the payload of a Stats response
+#[derive(Clone, PartialEq, prost::Message)]
+struct StatsPayload {
+    #[prost(message, optional, tag = "1000")]
+    engine: Option<EngineMetrics>,
+    #[prost(uint32, optional, tag = "1001")]
+    restarts: Option<u32>,
+}
+
+/// Field number of the number of restarts of an instance with a restart policy,
+/// in the payload of a Stats response
+pub const RESTARTS_STATS_FIELD: u32 = 1001;
+
+/// Append the number of restarts of an instance to the encoded cgroup metrics of a Stats response
+pub fn append_restarts_to(restarts: u32, payload: &mut Vec<u8>) {
+    let restarts = StatsPayload {
+        engine: None,
+        restarts: Some(restarts),
+    };
+    restarts
+        .encode(payload)
+        .expect("a Vec<u8> has enough capacity");
+}
+
+/// Read the number of restarts of the instance from the payload of a Stats response,
+/// if it has a restart policy
+pub fn restarts_from_stats(payload: &[u8]) -> Option<u32> {
+    StatsPayload::decode(payload).ok()?.restarts
+}
+
+impl EngineMetrics {
+    /// Field number of the engine metrics in the payload of a Stats response
+    pub const STATS_FIELD: u32 = 1000;
+
+    /// Append the metrics to the encoded cgroup metrics of a Stats response
+    pub fn append_to(&self, payload: &mut Vec<u8>) {
+        // concatenated protobuf messages are merged when decoded
+        let engine = StatsPayload {
+            engine: Some(self.clone()),
+            restarts: None,
+        };
+        engine
+            .encode(payload)
+            .expect("a Vec<u8> has enough capacity");
+    }
+
+    /// Read the engine metrics from the payload of a Stats response, if any
+    pub fn from_stats(payload: &[u8]) -> Option<Self> {
+        StatsPayload::decode(payload).ok()?.engine
+    }
+}
+
+/// Description of the WebAssembly engine running the instances, reported by the `info` command
+/// of the shim and in the runtime info that containerd queries.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug, Default)]
+pub struct EngineInfo {
+    /// Name of the engine, e.g., `wasmtime`
+    pub name: String,
+    /// Version of the engine, when it is known
+    pub version: Option<String>,
+    /// Wasm proposals and WASI APIs that the engine supports, e.g., `component-model`
+    pub features: Vec<String>,
+    /// Whether the engine precompiles the wasm layers
+    pub precompile: bool,
+    /// OCI media types of the layers that the engine runs
+    pub media_types: Vec<String>,
+}
+
 /// Represents a WASI module(s).
 /// Instance is a trait that gets implemented by consumers of this library.
 /// This trait requires that any type implementing it is `'static`, similar to `std::any::Any`.
@@ -54,4 +163,119 @@ pub trait Instance: 'static {
     /// Waits for the instance to finish and returns its exit code
     /// This is an async call.
     async fn wait(&self) -> (u32, DateTime<Utc>);
+
+    /// Returns the exit code of the instance if it already finished, without waiting.
+    /// The default polls `wait` once, which is enough when `wait` is backed by a value
+    /// set once on exit, e.g., a `WaitableCell`.
+    fn try_wait(&self) -> Option<(u32, DateTime<Utc>)> {
+        self.wait().now_or_never()
+    }
+
+    /// Close the input of the instance, which then reads an EOF.
+    /// Instances that can't close their input return `Error::Unsupported`, which is the default.
+    async fn close_stdin(&self) -> Result<(), Error> {
+        async {
+            Err(Error::Unsupported(
+                "closing stdin is not supported".to_string(),
+            ))
+        }
+    }
+
+    /// Attach the stdio FIFOs of a new client to the running instance, e.g., after containerd
+    /// restarted, without the guest noticing. An empty path keeps its stream as it is.
+    /// Instances that can't attach new FIFOs return `Error::Unsupported`, which is the default.
+    async fn attach_stdio(
+        &self,
+        _stdin: &Path,
+        _stdout: &Path,
+        _stderr: &Path,
+    ) -> Result<(), Error> {
+        async {
+            Err(Error::Unsupported(
+                "attaching stdio is not supported".to_string(),
+            ))
+        }
+    }
+
+    /// Resize the terminal of the instance, in characters.
+    /// This can be called before `start`, in which case the size is applied when the instance starts.
+    /// Instances that don't support terminals return `Error::Unsupported`, which is the default.
+    async fn resize_pty(&self, _width: u32, _height: u32) -> Result<(), Error> {
+        async { Err(Error::Unsupported("terminal is not supported".to_string())) }
+    }
+
+    /// Apply new resource limits to the instance.
+    /// Instances that can't change their limits return `Error::Unsupported`, which is the default.
+    async fn update(&self, _resources: &LinuxResources) -> Result<(), Error> {
+        async { Err(Error::Unsupported("update is not supported".to_string())) }
+    }
+
+    /// Suspend the running instance until `resume` is called.
+    /// Instances that can't be suspended return `Error::Unsupported`, which is the default.
+    async fn pause(&self) -> Result<(), Error> {
+        async { Err(Error::Unsupported("pause is not supported".to_string())) }
+    }
+
+    /// Resume the instance after a call to `pause`
+    async fn resume(&self) -> Result<(), Error> {
+        async { Err(Error::Unsupported("resume is not supported".to_string())) }
+    }
+
+    /// Start an additional process `exec_id` in the running instance, with the args of
+    /// `cfg.process` and its own stdio.
+    /// The returned value should be a unique ID (such as a PID) for the process.
+    /// Instances that can't run additional processes return `Error::Unsupported`, which is the default.
+    // `trait_variant` doesn't wrap the default bodies in a future, they return one themselves
+    async fn exec(&self, exec_id: &str, _cfg: &ExecConfig) -> Result<u32, Error> {
+        async move {
+            Err(Error::Unsupported(format!(
+                "exec {exec_id} is not supported"
+            )))
+        }
+    }
+
+    /// Send a signal to the exec process `exec_id`
+    async fn kill_exec(&self, exec_id: &str, _signal: u32) -> Result<(), Error> {
+        async move {
+            Err(Error::Unsupported(format!(
+                "exec {exec_id} is not supported"
+            )))
+        }
+    }
+
+    /// Waits for the exec process `exec_id` to finish and returns its exit code.
+    /// This is called once for each started exec process, after which the instance can forget it.
+    async fn wait_exec(&self, exec_id: &str) -> Result<(u32, DateTime<Utc>), Error> {
+        async move {
+            Err(Error::Unsupported(format!(
+                "exec {exec_id} is not supported"
+            )))
+        }
+    }
+
+    /// Waits for the next OOM kill of a process of the running instance, which is reported with a `TaskOOM` event.
+    /// An OOM kill since the last call returns right away.
+    /// Instances that can't detect OOM kills return `Error::Unsupported`, which is the default.
+    async fn wait_oom(&self) -> Result<(), Error> {
+        async {
+            Err(Error::Unsupported(
+                "OOM detection is not supported".to_string(),
+            ))
+        }
+    }
+
+    /// Latest metrics of the engine running the instance, added to the Stats of the instance.
+    /// Instances without engine metrics return `None`, which is the default.
+    async fn engine_metrics(&self) -> Option<EngineMetrics> {
+        async { None }
+    }
+
+    /// Description of the engine running the instances, for the `info` command of the shim.
+    /// The default describes nothing.
+    fn engine_info() -> EngineInfo
+    where
+        Self: Sized,
+    {
+        EngineInfo::default()
+    }
 }
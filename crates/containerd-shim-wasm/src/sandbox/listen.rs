@@ -0,0 +1,114 @@
+//! Sockets that the shim binds for a container, instead of the guest binding them.
+//!
+//! A container lists them in the `io.runwasi.listen` annotation, separated by commas,
+//! e.g., `tcp://0.0.0.0:8080,tcp://[::]:8443`.
+//! The engine gets them from [`crate::container::RuntimeContext::listeners`].
+//!
+//! A container with the `io.runwasi.mode=http` annotation serves requests on the first of them,
+//! with [`crate::container::Engine::serve`], or on `0.0.0.0:8080` without `io.runwasi.listen`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use anyhow::{Context, bail};
+
+use crate::container::ExecutionMode;
+
+/// Annotation with the addresses of the sockets of a container
+pub const LISTEN_ANNOTATION: &str = "io.runwasi.listen";
+
+/// Annotation with the execution mode of a container, `run` or `http`
+pub const MODE_ANNOTATION: &str = "io.runwasi.mode";
+
+/// The address served by the containers in the `http` mode without `io.runwasi.listen`
+pub const DEFAULT_SERVE_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8080);
+
+/// The execution mode of a container, from its `io.runwasi.mode` annotation, if it has one
+pub fn mode_from_annotations(
+    annotations: Option<&HashMap<String, String>>,
+) -> anyhow::Result<Option<ExecutionMode>> {
+    let Some(mode) = annotations.and_then(|a| a.get(MODE_ANNOTATION)) else {
+        return Ok(None);
+    };
+    match mode.trim() {
+        "run" => Ok(Some(ExecutionMode::Run)),
+        "http" => Ok(Some(ExecutionMode::Serve)),
+        mode => {
+            bail!("invalid {MODE_ANNOTATION} annotation: {mode:?}, expected \"run\" or \"http\"")
+        }
+    }
+}
+
+/// The addresses to listen on for a container, from its `io.runwasi.listen` annotation,
+/// or the default address of the `http` mode
+pub fn listen_addrs(
+    annotations: Option<&HashMap<String, String>>,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let Some(addrs) = annotations.and_then(|a| a.get(LISTEN_ANNOTATION)) else {
+        return match mode_from_annotations(annotations)? {
+            Some(ExecutionMode::Serve) => Ok(vec![DEFAULT_SERVE_ADDR]),
+            _ => Ok(vec![]),
+        };
+    };
+    addrs
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(|addr| {
+            let Some(socket_addr) = addr.strip_prefix("tcp://") else {
+                bail!("invalid {LISTEN_ANNOTATION} annotation: {addr:?} is not a tcp:// address");
+            };
+            socket_addr
+                .parse()
+                .with_context(|| format!("invalid {LISTEN_ANNOTATION} annotation: {addr:?}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annotations(addrs: &str) -> HashMap<String, String> {
+        HashMap::from([(LISTEN_ANNOTATION.to_string(), addrs.to_string())])
+    }
+
+    #[test]
+    fn test_listen_addrs() -> anyhow::Result<()> {
+        assert!(listen_addrs(None)?.is_empty());
+
+        let addrs = listen_addrs(Some(&annotations("tcp://0.0.0.0:8080, tcp://[::1]:8443")))?;
+        assert_eq!(
+            addrs,
+            ["0.0.0.0:8080".parse::<SocketAddr>()?, "[::1]:8443".parse()?]
+        );
+
+        assert!(listen_addrs(Some(&annotations("udp://0.0.0.0:53"))).is_err());
+        assert!(listen_addrs(Some(&annotations("tcp://localhost"))).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_mode() -> anyhow::Result<()> {
+        let http = HashMap::from([(MODE_ANNOTATION.to_string(), "http".to_string())]);
+        assert_eq!(
+            mode_from_annotations(Some(&http))?,
+            Some(ExecutionMode::Serve)
+        );
+        assert_eq!(listen_addrs(Some(&http))?, [DEFAULT_SERVE_ADDR]);
+
+        // the listen annotation replaces the default address
+        let mut listen = annotations("tcp://127.0.0.1:3000");
+        listen.extend(http);
+        assert_eq!(listen_addrs(Some(&listen))?, ["127.0.0.1:3000".parse()?]);
+
+        let run = HashMap::from([(MODE_ANNOTATION.to_string(), "run".to_string())]);
+        assert_eq!(mode_from_annotations(Some(&run))?, Some(ExecutionMode::Run));
+        assert!(listen_addrs(Some(&run))?.is_empty());
+        assert_eq!(mode_from_annotations(None)?, None);
+
+        let grpc = HashMap::from([(MODE_ANNOTATION.to_string(), "grpc".to_string())]);
+        assert!(mode_from_annotations(Some(&grpc)).is_err());
+        Ok(())
+    }
+}
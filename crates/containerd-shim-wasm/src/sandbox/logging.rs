@@ -0,0 +1,442 @@
+//! Format of the records written to the log of the shim.
+//!
+//! The records are plain text by default. With `RUNWASI_LOG_FORMAT=json` in the environment
+//! of the shim, or with the shim option
+//!
+//! ```toml
+//! [plugins."io.containerd.grpc.v1.cri".containerd.runtimes.wasm.options]
+//!   LogFormat = "json"
+//! ```
+//!
+//! each record is a JSON object on a single line, e.g.:
+//!
+//! ```json
+//! {"timestamp":"2024-05-01T10:00:00Z","level":"info","target":"containerd_shim_wasm::sandbox::shim::local","message":"starting instance","container_id":"app","task_id":"app"}
+//! ```
+//!
+//! Either way, the records are written to the log FIFO of containerd.
+//!
+//! The level of the records can change while the shim runs, from the first of:
+//! 1. the `log-level` control file, in the working directory of the shim, i.e., the bundle
+//!    of the container it was started for, e.g., `echo debug > log-level`,
+//! 2. the `io.runwasi.log-level` annotation of the last container with it,
+//! 3. `LogLevel` in the shim options,
+//! 4. `RUST_LOG`, the default level of the shim, and the debug flag of containerd.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use log::LevelFilter;
+use log::kv::{self, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Selects the format of the log records, `text` or `json`
+pub const LOG_FORMAT_ENV: &str = "RUNWASI_LOG_FORMAT";
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `time="..." level=info msg="..."`, as the other shims of containerd
+    #[default]
+    Text,
+    /// A JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => bail!("unknown log format {s:?}, expected \"text\" or \"json\""),
+        }
+    }
+}
+
+static FORMAT: AtomicU8 = AtomicU8::new(LogFormat::Text as u8);
+
+/// The format of the records logged by this process
+pub fn format() -> LogFormat {
+    match FORMAT.load(Ordering::Relaxed) {
+        f if f == LogFormat::Json as u8 => LogFormat::Json,
+        _ => LogFormat::Text,
+    }
+}
+
+/// Set the format of the records logged by this process, and by the processes forked from it
+pub fn set_format(format: LogFormat) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+/// Set the format from the `RUNWASI_LOG_FORMAT` environment variable, if set
+pub(crate) fn set_format_from_env() {
+    let Ok(format) = std::env::var(LOG_FORMAT_ENV) else {
+        return;
+    };
+    match format.parse() {
+        Ok(format) => set_format(format),
+        // the logger isn't set yet
+        Err(err) => eprintln!("invalid {LOG_FORMAT_ENV}: {err}"),
+    }
+}
+
+/// Annotation with the log level of the shim, e.g., `debug`
+pub const LOG_LEVEL_ANNOTATION: &str = "io.runwasi.log-level";
+
+/// File in the working directory of the shim with its log level, overriding the others
+pub const LOG_LEVEL_CONTROL_FILE: &str = "log-level";
+
+/// How often the control file is read
+const CONTROL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The log levels from each source, where the first one set applies
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct LogLevels {
+    control: Option<LevelFilter>,
+    annotation: Option<LevelFilter>,
+    options: Option<LevelFilter>,
+    /// The level when the shim started
+    default: Option<LevelFilter>,
+}
+
+impl LogLevels {
+    fn effective(&self) -> Option<LevelFilter> {
+        self.control
+            .or(self.annotation)
+            .or(self.options)
+            .or(self.default)
+    }
+}
+
+static LEVELS: Mutex<LogLevels> = Mutex::new(LogLevels {
+    control: None,
+    annotation: None,
+    options: None,
+    default: None,
+});
+
+fn update_levels(f: impl FnOnce(&mut LogLevels)) {
+    let mut levels = LEVELS.lock().unwrap_or_else(|e| e.into_inner());
+    levels.default.get_or_insert_with(log::max_level);
+    f(&mut levels);
+    let Some(level) = levels.effective() else {
+        return;
+    };
+    if level != log::max_level() {
+        // logged at the previous level, so that raising the level doesn't go unnoticed
+        log::info!("setting the log level to {level}");
+        log::set_max_level(level);
+    }
+}
+
+/// Parse a log level, e.g., `debug`
+pub fn parse_level(level: &str) -> anyhow::Result<LevelFilter> {
+    level
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid log level {:?}", level.trim()))
+}
+
+/// The log level of the `io.runwasi.log-level` annotation
+pub fn level_from_annotations(
+    annotations: Option<&HashMap<String, String>>,
+) -> anyhow::Result<Option<LevelFilter>> {
+    let Some(level) = annotations.and_then(|a| a.get(LOG_LEVEL_ANNOTATION)) else {
+        return Ok(None);
+    };
+    let level =
+        parse_level(level).with_context(|| format!("invalid {LOG_LEVEL_ANNOTATION} annotation"))?;
+    Ok(Some(level))
+}
+
+/// Set the log level from the shim options
+pub(crate) fn set_options_level(level: LevelFilter) {
+    update_levels(|levels| levels.options = Some(level));
+}
+
+/// Set the log level from the annotations of a container, if it has the annotation.
+/// An invalid level is ignored, rather than failing the container.
+pub(crate) fn set_annotation_level(annotations: Option<&HashMap<String, String>>) {
+    match level_from_annotations(annotations) {
+        Ok(Some(level)) => update_levels(|levels| levels.annotation = Some(level)),
+        Ok(None) => {}
+        Err(err) => log::warn!("ignoring the log level of the container: {err:#}"),
+    }
+}
+
+/// Follow the log level written in the `log-level` control file of the working directory.
+/// Removing the file restores the configured level.
+pub(crate) fn watch_control_file() {
+    let path = PathBuf::from(LOG_LEVEL_CONTROL_FILE);
+    std::thread::spawn(move || {
+        let mut last = None;
+        loop {
+            let content = std::fs::read_to_string(&path).ok();
+            if content != last {
+                let control = match content.as_deref().map(parse_level) {
+                    Some(Ok(level)) => Some(level),
+                    Some(Err(err)) => {
+                        log::warn!("ignoring the {LOG_LEVEL_CONTROL_FILE} control file: {err}");
+                        None
+                    }
+                    None => None,
+                };
+                update_levels(|levels| levels.control = control);
+                last = content;
+            }
+            std::thread::sleep(CONTROL_INTERVAL);
+        }
+    });
+}
+
+/// Number of records kept for the crash reports
+const RECENT_RECORDS: usize = 100;
+
+/// The last records logged by this process, as they were written
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Keep `line` in the last records of this process
+pub(crate) fn keep_recent(line: &str) {
+    let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    if recent.len() == RECENT_RECORDS {
+        recent.pop_front();
+    }
+    recent.push_back(line.to_string());
+}
+
+/// The last records logged by this process, the oldest first.
+/// Empty if a record is being logged, e.g., when panicking in the logger.
+pub(crate) fn recent_records() -> Vec<String> {
+    match RECENT.try_lock() {
+        Ok(recent) => recent.iter().cloned().collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// The container and the task the records are about
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct LogContext {
+    pub container_id: String,
+    /// The exec id, or the container id for the init process
+    pub task_id: String,
+}
+
+tokio::task_local! {
+    static TASK_CONTEXT: LogContext;
+}
+
+/// The context of the records logged outside of a request, e.g., in the container process
+static PROCESS_CONTEXT: RwLock<Option<LogContext>> = RwLock::new(None);
+
+impl LogContext {
+    pub fn new(container_id: &str, exec_id: &str) -> Self {
+        let task_id = if exec_id.is_empty() {
+            container_id
+        } else {
+            exec_id
+        };
+        Self {
+            container_id: container_id.to_string(),
+            task_id: task_id.to_string(),
+        }
+    }
+
+    /// Run `f` with this context for the records it logs
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        TASK_CONTEXT.scope(self, f).await
+    }
+
+    /// Use this context for the records logged outside of a scope
+    pub fn set_for_process(self) {
+        *PROCESS_CONTEXT.write().unwrap_or_else(|e| e.into_inner()) = Some(self);
+    }
+
+    fn current() -> Option<Self> {
+        TASK_CONTEXT.try_with(Clone::clone).ok().or_else(|| {
+            PROCESS_CONTEXT
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone()
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: String,
+    target: &'a str,
+    message: String,
+    container_id: Option<String>,
+    task_id: Option<String>,
+    #[serde(flatten)]
+    fields: Map<String, Value>,
+}
+
+struct JsonVisitor(Map<String, Value>);
+
+impl<'kvs> Visitor<'kvs> for JsonVisitor {
+    fn visit_pair(&mut self, k: kv::Key<'kvs>, v: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(k.to_string(), Value::String(v.to_string()));
+        Ok(())
+    }
+}
+
+/// `record` as a JSON object on a single line, with the key-values of the record as fields
+pub(crate) fn json_record(record: &log::Record, timestamp: String) -> String {
+    // collect key_values but don't fail if error parsing
+    let mut fields = JsonVisitor(Map::new());
+    let _ = record.key_values().visit(&mut fields);
+    let mut fields = fields.0;
+
+    let context = LogContext::current();
+    // the records logged with the macros of `container::log` name their container
+    let instance = match fields.remove("instance") {
+        Some(Value::String(instance)) => Some(instance),
+        _ => None,
+    };
+    let (container_id, task_id) = match context {
+        Some(LogContext {
+            container_id,
+            task_id,
+        }) => (Some(container_id), Some(task_id)),
+        None => (instance.clone(), instance),
+    };
+
+    let record = JsonRecord {
+        timestamp,
+        level: record.level().as_str().to_lowercase(),
+        target: record.target(),
+        message: record.args().to_string(),
+        container_id,
+        task_id,
+        fields,
+    };
+    // serde_json escapes the new lines in the strings
+    serde_json::to_string(&record).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::async_utils::AmbientRuntime as _;
+
+    const TIMESTAMP: &str = "2024-05-01T10:00:00Z";
+
+    fn parse(line: String) -> Value {
+        assert!(!line.contains('\n'), "{line}");
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn test_level_from_annotations() {
+        let annotations = HashMap::from([(LOG_LEVEL_ANNOTATION.to_string(), "Debug".to_string())]);
+        assert_eq!(
+            level_from_annotations(Some(&annotations)).unwrap(),
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(level_from_annotations(None).unwrap(), None);
+        assert_eq!(level_from_annotations(Some(&HashMap::new())).unwrap(), None);
+
+        let annotations = HashMap::from([(LOG_LEVEL_ANNOTATION.to_string(), "loud".to_string())]);
+        let err = level_from_annotations(Some(&annotations)).unwrap_err();
+        assert!(format!("{err:#}").contains(LOG_LEVEL_ANNOTATION), "{err:#}");
+    }
+
+    #[test]
+    fn test_log_level_precedence() {
+        let mut levels = LogLevels {
+            default: Some(LevelFilter::Info),
+            ..Default::default()
+        };
+        assert_eq!(levels.effective(), Some(LevelFilter::Info));
+
+        levels.options = Some(LevelFilter::Warn);
+        assert_eq!(levels.effective(), Some(LevelFilter::Warn));
+
+        levels.annotation = Some(LevelFilter::Debug);
+        assert_eq!(levels.effective(), Some(LevelFilter::Debug));
+
+        levels.control = Some(LevelFilter::Trace);
+        assert_eq!(levels.effective(), Some(LevelFilter::Trace));
+
+        // removing the control file restores the level of the annotation
+        levels.control = None;
+        assert_eq!(levels.effective(), Some(LevelFilter::Debug));
+    }
+
+    #[test]
+    fn test_recent_records() {
+        for i in 0..RECENT_RECORDS + 10 {
+            keep_recent(&format!("record {i}"));
+        }
+        let recent = recent_records();
+        assert_eq!(recent.len(), RECENT_RECORDS);
+        assert_eq!(
+            recent.last().unwrap(),
+            &format!("record {}", RECENT_RECORDS + 9)
+        );
+    }
+
+    #[test]
+    fn test_log_format_from_str() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("Text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_json_record() {
+        let kvs = [("instance", "app"), ("pod", "pod-1")];
+        let line = json_record(
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target("runwasi")
+                .args(format_args!("multi\nline \"message\""))
+                .key_values(&kvs)
+                .build(),
+            TIMESTAMP.to_string(),
+        );
+        let value = parse(line);
+
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "timestamp": "2024-05-01T10:00:00Z",
+                "level": "warn",
+                "target": "runwasi",
+                "message": "multi\nline \"message\"",
+                "container_id": "app",
+                "task_id": "app",
+                "pod": "pod-1",
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_record_in_context() {
+        let line = async {
+            json_record(
+                &log::Record::builder()
+                    .level(log::Level::Info)
+                    .args(format_args!("exec started"))
+                    .build(),
+                TIMESTAMP.to_string(),
+            )
+        };
+        let value = parse(LogContext::new("app", "exec-1").scope(line).block_on());
+
+        assert_eq!(value["container_id"], "app");
+        assert_eq!(value["task_id"], "exec-1");
+        assert_eq!(value["level"], "info");
+    }
+}
@@ -71,18 +71,31 @@
 //! For simpler use cases, consider using the [`crate::container`] module instead.
 
 pub mod cli;
+pub mod env_policy;
 pub mod error;
+pub mod host_dirs;
+pub(crate) mod info;
 pub mod instance;
 pub mod instance_utils;
+pub mod listen;
+pub mod logging;
+pub mod native_fallback;
+pub mod reload;
 pub mod shim;
+pub mod socket_policy;
 pub mod sync;
 
+pub use env_policy::EnvPolicy;
 pub use error::{Error, Result};
-pub use instance::{Instance, InstanceConfig};
+pub use host_dirs::HostDir;
+pub use instance::{EngineInfo, EngineMetrics, ExecConfig, Instance, InstanceConfig};
+pub use logging::LogFormat;
+pub use native_fallback::{NativeFallback, NativeFallbackPolicy};
 pub use shim::{Cli as ShimCli, Config};
+pub use socket_policy::SocketPolicy;
 
 pub(crate) mod containerd;
 pub(crate) mod oci;
-pub use oci::WasmLayer;
+pub use oci::{ImageInfo, ModuleBytes, WasmLayer};
 
 pub(crate) mod async_utils;
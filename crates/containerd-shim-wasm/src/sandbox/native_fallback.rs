@@ -0,0 +1,159 @@
+//! Policy on running the images that aren't wasm as native Linux containers.
+//!
+//! The policy of the node is set in the shim options, e.g., in the containerd config:
+//!
+//! ```toml
+//! [plugins."io.containerd.grpc.v1.cri".containerd.runtimes.wasm.options]
+//!   NativeFallback = "registry-allowlist"
+//!   NativeFallbackRegistries = ["registry.k8s.io", "ghcr.io/containerd"]
+//! ```
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+/// Annotation with the image of a container, set by the CRI plugin
+pub const IMAGE_NAME_ANNOTATION: &str = "io.kubernetes.cri.image-name";
+
+/// Registry of the images without one, e.g., `alpine:latest`
+const DEFAULT_REGISTRY: &str = "docker.io";
+
+/// Whether the images that aren't wasm run as native Linux containers
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum NativeFallback {
+    /// Any image can run as a native Linux container
+    #[default]
+    Allow,
+    /// Only wasm images can run
+    Deny,
+    /// Only the images from the registries of the allowlist can run as native Linux containers
+    RegistryAllowlist,
+}
+
+/// The native fallback of the node, with the allowlist of its registries
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct NativeFallbackPolicy {
+    pub mode: NativeFallback,
+    /// Registries, e.g., `ghcr.io`, or repository prefixes, e.g., `ghcr.io/containerd`
+    pub registries: Vec<String>,
+}
+
+impl NativeFallbackPolicy {
+    /// Whether `image` can run as a native Linux container
+    pub fn allows(&self, image: &str) -> bool {
+        match self.mode {
+            NativeFallback::Allow => true,
+            NativeFallback::Deny => false,
+            NativeFallback::RegistryAllowlist => {
+                let (registry, repository) = split_registry(image);
+                self.registries.iter().any(|allowed| {
+                    let allowed = allowed.trim_end_matches('/');
+                    match allowed.split_once('/') {
+                        None => allowed == registry,
+                        Some((allowed_registry, prefix)) => {
+                            allowed_registry == registry
+                                && repository
+                                    .strip_prefix(prefix)
+                                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+                        }
+                    }
+                })
+            }
+        }
+    }
+
+    /// Fails when `image` can't run as a native Linux container, with the error naming
+    /// the wasm `media_types` that the container was expected to have
+    pub fn check(&self, image: &str, media_types: &[&str]) -> anyhow::Result<()> {
+        if self.allows(image) {
+            return Ok(());
+        }
+        let image = if image.is_empty() { "<unknown>" } else { image };
+        let reason = match self.mode {
+            NativeFallback::RegistryAllowlist => {
+                "isn't from a registry allowed to run native Linux containers"
+            }
+            _ => "can't run as a native Linux container",
+        };
+        bail!(
+            "image {image} is not a wasm module, and {reason}: expected layers of media types {}",
+            media_types.join(", ")
+        )
+    }
+}
+
+/// The registry and the repository of an image reference,
+/// e.g., `("docker.io", "library/alpine:latest")` for `docker.io/library/alpine:latest`
+fn split_registry(image: &str) -> (&str, &str) {
+    match image.split_once('/') {
+        Some((registry, repository))
+            if registry.contains(['.', ':']) || registry == "localhost" =>
+        {
+            (registry, repository)
+        }
+        _ => (DEFAULT_REGISTRY, image),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_registry() {
+        assert_eq!(
+            split_registry("ghcr.io/containerd/runwasi:latest"),
+            ("ghcr.io", "containerd/runwasi:latest")
+        );
+        assert_eq!(
+            split_registry("localhost:5000/app"),
+            ("localhost:5000", "app")
+        );
+        assert_eq!(
+            split_registry("library/alpine"),
+            ("docker.io", "library/alpine")
+        );
+        assert_eq!(split_registry("alpine"), ("docker.io", "alpine"));
+    }
+
+    #[test]
+    fn test_allows() {
+        let image = "registry.k8s.io/pause:3.9";
+
+        let allow = NativeFallbackPolicy::default();
+        assert!(allow.allows(image));
+
+        let deny = NativeFallbackPolicy {
+            mode: NativeFallback::Deny,
+            ..Default::default()
+        };
+        assert!(!deny.allows(image));
+
+        let allowlist = NativeFallbackPolicy {
+            mode: NativeFallback::RegistryAllowlist,
+            registries: vec![
+                "registry.k8s.io".to_string(),
+                "ghcr.io/containerd".to_string(),
+            ],
+        };
+        assert!(allowlist.allows(image));
+        assert!(allowlist.allows("ghcr.io/containerd/runwasi:latest"));
+        assert!(!allowlist.allows("ghcr.io/containerdx/app"));
+        assert!(!allowlist.allows("docker.io/library/alpine"));
+        assert!(!allowlist.allows(""));
+    }
+
+    #[test]
+    fn test_check() {
+        let deny = NativeFallbackPolicy {
+            mode: NativeFallback::Deny,
+            ..Default::default()
+        };
+        let err = deny
+            .check("docker.io/library/alpine", &["application/wasm"])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("docker.io/library/alpine"));
+        assert!(err.contains("application/wasm"));
+    }
+}
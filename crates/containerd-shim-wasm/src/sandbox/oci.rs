@@ -1,105 +1,146 @@
 //! Generic helpers for working with OCI specs that can be consumed by any runtime.
 
-use std::collections::HashMap;
-use std::io::{ErrorKind, Write};
-#[cfg(unix)]
-use std::os::unix::process::CommandExt;
-use std::process;
-
-use anyhow::Context;
-use oci_spec::image::Descriptor;
-use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 
-use super::error::Result;
+use memmap2::Mmap;
+use oci_spec::image::{ANNOTATION_TITLE, Config as ImageConfig, Descriptor, Platform};
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WasmLayer {
     pub config: Descriptor,
+    /// The content of the layer, empty for a large layer kept in the file at `path`
     #[serde(with = "serde_bytes")]
     pub layer: Vec<u8>,
+    /// The file with the content of a large layer, so that it isn't kept in memory
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+impl WasmLayer {
+    /// The content of the layer, mapped from its file for a large layer.
+    /// Engines that can load a module from a file should prefer `path` for large layers.
+    pub fn bytes(&self) -> std::io::Result<ModuleBytes<'_>> {
+        match &self.path {
+            Some(path) => ModuleBytes::map(path),
+            None => Ok(ModuleBytes::Borrowed(&self.layer)),
+        }
+    }
+
+    /// The file with the content of the layer, for a large layer
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The name of the layer, from the `org.opencontainers.image.title` annotation of its descriptor
+    pub fn name(&self) -> Option<&str> {
+        self.config
+            .annotations()
+            .as_ref()?
+            .get(ANNOTATION_TITLE)
+            .map(String::as_str)
+    }
 }
 
-fn parse_env(envs: &[String]) -> HashMap<String, String> {
-    // make NAME=VALUE to HashMap<NAME, VALUE>.
-    envs.iter()
-        .filter_map(|e| {
-            let mut split = e.split('=');
-
-            split.next().map(|key| {
-                let value = split.collect::<Vec<&str>>().join("=");
-                (key.into(), value)
-            })
-        })
-        .collect()
+/// The content of a module, as a `&[u8]`.
+/// A module in a file is mapped read-only, so that the containers of the same module share
+/// the pages of the file instead of each reading it in its own memory.
+pub enum ModuleBytes<'a> {
+    Borrowed(&'a [u8]),
+    Owned(Vec<u8>),
+    Mapped(Mmap),
 }
 
-pub(crate) fn setup_prestart_hooks(hooks: &Option<oci_spec::runtime::Hooks>) -> Result<()> {
-    if let Some(hooks) = hooks {
-        let prestart_hooks = hooks.prestart().as_ref().unwrap();
-
-        for hook in prestart_hooks {
-            let mut hook_command = process::Command::new(hook.path());
-            // Based on OCI spec, the first argument of the args vector is the
-            // arg0, which can be different from the path.  For example, path
-            // may be "/usr/bin/true" and arg0 is set to "true". However, rust
-            // command differentiates arg0 from args, where rust command arg
-            // doesn't include arg0. So we have to make the split arg0 from the
-            // rest of args.
-            if let Some((arg0, args)) = hook.args().as_ref().and_then(|a| a.split_first()) {
-                log::debug!("run_hooks arg0: {:?}, args: {:?}", arg0, args);
-
-                #[cfg(unix)]
-                {
-                    hook_command.arg0(arg0).args(args);
-                }
-
-                #[cfg(windows)]
-                {
-                    if !&hook.path().ends_with(arg0) {
-                        return Err(crate::sandbox::Error::InvalidArgument("Running with arg0 as different name than executable is not supported on Windows due to rust std library process implementation.".to_string()));
-                    }
-
-                    hook_command.args(args);
-                }
-            } else {
-                #[cfg(unix)]
-                hook_command.arg0(hook.path());
-            };
-
-            let envs: HashMap<String, String> = if let Some(env) = hook.env() {
-                parse_env(env)
-            } else {
-                HashMap::new()
-            };
-            log::debug!("run_hooks envs: {:?}", envs);
-
-            let mut hook_process = hook_command
-                .env_clear()
-                .envs(envs)
-                .stdin(process::Stdio::piped())
-                .spawn()
-                .with_context(|| "Failed to execute hook")?;
-
-            if let Some(stdin) = &mut hook_process.stdin {
-                // We want to ignore BrokenPipe here. A BrokenPipe indicates
-                // either the hook is crashed/errored or it ran successfully.
-                // Either way, this is an indication that the hook command
-                // finished execution.  If the hook command was successful,
-                // which we will check later in this function, we should not
-                // fail this step here. We still want to check for all the other
-                // error, in the case that the hook command is waiting for us to
-                // write to stdin.
-                let state = format!("{{ \"pid\": {} }}", std::process::id());
-                if let Err(e) = stdin.write_all(state.as_bytes()) {
-                    if e.kind() != ErrorKind::BrokenPipe {
-                        // Not a broken pipe. The hook command may be waiting
-                        // for us.
-                        let _ = hook_process.kill();
-                    }
-                }
+impl ModuleBytes<'_> {
+    /// The content of the file at `path`, mapped, or read when it can't be mapped,
+    /// e.g., for an empty file or on a file system without `mmap`
+    pub fn map(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        // the shim writes the layers in the bundle, and nothing changes them once the module is loaded
+        match unsafe { Mmap::map(&file) } {
+            Ok(mmap) => Ok(Self::Mapped(mmap)),
+            Err(err) => {
+                log::debug!("reading {path:?}, it can't be mapped: {err}");
+                Ok(Self::Owned(std::fs::read(path)?))
             }
-            hook_process.wait()?;
         }
     }
-    Ok(())
+}
+
+impl Deref for ModuleBytes<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Borrowed(bytes) => bytes,
+            Self::Owned(bytes) => bytes,
+            Self::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ModuleBytes<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl std::fmt::Debug for ModuleBytes<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            Self::Borrowed(_) => "Borrowed",
+            Self::Owned(_) => "Owned",
+            Self::Mapped(_) => "Mapped",
+        };
+        f.debug_struct("ModuleBytes")
+            .field(kind, &self.len())
+            .finish()
+    }
+}
+
+/// The image of a container, as resolved from the store of containerd
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ImageInfo {
+    /// The platform of the manifest, from the config of the image, e.g., `wasip1`/`wasm`
+    pub platform: Platform,
+    /// The `config` of the config of the image, with its `Entrypoint`, `Cmd` and `Env`
+    pub config: Option<ImageConfig>,
+    /// The digest of the manifest, the one selected in the index of a multi-platform image
+    pub manifest_digest: Option<String>,
+}
+
+/// Media type of the config of a Wasm OCI artifact
+/// https://tag-runtime.cncf.io/wgs/wasm/deliverables/wasm-oci-artifact/
+pub(crate) const WASM_ARTIFACT_CONFIG_MEDIA_TYPE: &str = "application/vnd.wasm.config.v0+json";
+
+/// Media types of the layers of a Wasm OCI artifact, loaded whatever the engine supports
+pub(crate) const WASM_ARTIFACT_LAYER_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.wasm.content.layer.v1+wasm",
+    "application/wasm",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_module_bytes_of_a_file() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("module.wasm");
+        std::fs::write(&path, b"\0asm")?;
+
+        let bytes = ModuleBytes::map(&path)?;
+        assert!(matches!(bytes, ModuleBytes::Mapped(_)));
+        assert_eq!(&*bytes, b"\0asm");
+
+        // an empty file has nothing to map
+        std::fs::write(&path, b"")?;
+        assert!(ModuleBytes::map(&path)?.is_empty());
+
+        assert!(ModuleBytes::map(dir.path().join("missing.wasm")).is_err());
+        Ok(())
+    }
 }
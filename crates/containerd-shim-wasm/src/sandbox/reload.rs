@@ -0,0 +1,51 @@
+//! Hot reload of the module of a running container on `SIGHUP`.
+//!
+//! With the `io.runwasi.reload-on-sighup=true` annotation, a Kill request with `SIGHUP`
+//! doesn't reach the guest. The container process loads the module of the container again,
+//! e.g., new bytes pushed to a mounted volume, with [`crate::container::Engine::load`], stops
+//! the running instance with [`crate::container::Engine::interrupt`], and runs the entrypoint
+//! again, with the same stdio and listeners.
+//! When the new module fails to load, the running instance keeps running.
+
+use std::collections::HashMap;
+
+use anyhow::bail;
+
+/// Annotation of a container whose module is reloaded on `SIGHUP`, `true` or `false`
+pub const RELOAD_ANNOTATION: &str = "io.runwasi.reload-on-sighup";
+
+/// Whether the module of a container is reloaded on `SIGHUP`, from its
+/// `io.runwasi.reload-on-sighup` annotation
+pub fn reload_from_annotations(
+    annotations: Option<&HashMap<String, String>>,
+) -> anyhow::Result<bool> {
+    let Some(reload) = annotations.and_then(|a| a.get(RELOAD_ANNOTATION)) else {
+        return Ok(false);
+    };
+    match reload.trim() {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        reload => {
+            bail!(
+                "invalid {RELOAD_ANNOTATION} annotation: {reload:?}, expected \"true\" or \"false\""
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_from_annotations() -> anyhow::Result<()> {
+        let annotations =
+            |reload: &str| HashMap::from([(RELOAD_ANNOTATION.to_string(), reload.to_string())]);
+
+        assert!(reload_from_annotations(Some(&annotations("true")))?);
+        assert!(!reload_from_annotations(Some(&annotations("false")))?);
+        assert!(!reload_from_annotations(None)?);
+        assert!(reload_from_annotations(Some(&annotations("yes"))).is_err());
+        Ok(())
+    }
+}
@@ -16,6 +16,10 @@ use crate::sandbox::shim::events::{RemoteEventSender, ToTimestamp};
 use crate::sandbox::shim::local::Local;
 use crate::sandbox::sync::WaitableCell;
 
+/// File of the bundle with the pid of the shim, written by the start command
+#[cfg(target_os = "linux")]
+const SHIM_PID_FILE: &str = "shim.pid";
+
 /// Cli implements the containerd-shim cli interface using `Local<T>` as the task service.
 pub struct Cli<T: Instance + Sync + Send> {
     namespace: String,
@@ -63,15 +67,26 @@ where
         })?;
 
         let id = opts.id.clone();
-        let grouping = spec
-            .annotations()
-            .as_ref()
-            .and_then(|a| a.get("io.kubernetes.cri.sandbox-id"))
-            .unwrap_or(&id);
+        let grouping = shim_group(&spec, &id).to_string();
+
+        // the shim notifies the service manager that started this command, see `notify`
+        #[cfg(target_os = "linux")]
+        let start_pid = std::process::id().to_string();
+        #[cfg(target_os = "linux")]
+        let vars = vec![(crate::sys::notify::START_PID_ENV, start_pid.as_str())];
+        #[cfg(not(target_os = "linux"))]
+        let vars = vec![];
 
-        let (_child, address) = shim::spawn(opts, grouping, vec![])?;
+        #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+        let (pid, address) = shim::spawn(opts, &grouping, vars)?;
 
         write_address(&address)?;
+        // as the go shims do. The pid is 0 when the shim of the group is already running.
+        #[cfg(target_os = "linux")]
+        if pid != 0 {
+            std::fs::write(dir.join(SHIM_PID_FILE), pid.to_string())
+                .map_err(|err| ShimError::Other(format!("failed to write the shim pid: {err}")))?;
+        }
 
         Ok(address)
     }
@@ -79,6 +94,9 @@ where
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Info"))]
     fn wait(&mut self) {
         self.exit.wait().block_on();
+        super::metrics::stop();
+        #[cfg(target_os = "linux")]
+        crate::sys::notify::stopping();
     }
 
     #[cfg_attr(
@@ -88,7 +106,12 @@ where
     fn create_task_service(&self, publisher: RemotePublisher) -> Self::T {
         let events = RemoteEventSender::new(&self.namespace, publisher);
         let exit = self.exit.clone();
-        Local::<I>::new(events, exit, &self.namespace, &self.containerd_address)
+        let local = Local::<I>::new(events, exit, &self.namespace, &self.containerd_address);
+        // The listener of the shim socket was created by the start command, and already
+        // accepts connections. Their requests are served once this service is registered.
+        #[cfg(target_os = "linux")]
+        crate::sys::notify::ready();
+        local
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Info"))]
@@ -100,3 +123,126 @@ where
         })
     }
 }
+
+/// The group of the shim of a container, which serves all the containers of its group.
+/// The containers of a pod share the shim of their sandbox, while a container created
+/// with, e.g., ctr has a shim of its own.
+fn shim_group<'a>(spec: &'a Spec, id: &'a str) -> &'a str {
+    spec.annotations()
+        .as_ref()
+        .and_then(|a| a.get("io.kubernetes.cri.sandbox-id"))
+        .map_or(id, String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_shim_group() {
+        let mut spec = Spec::default();
+        assert_eq!(shim_group(&spec, "container"), "container");
+
+        spec.set_annotations(Some(HashMap::from([(
+            "io.kubernetes.cri.sandbox-id".to_string(),
+            "pod".to_string(),
+        )])));
+        assert_eq!(shim_group(&spec, "container"), "pod");
+        assert_eq!(shim_group(&spec, "sidecar"), "pod");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_ready_before_connect() -> anyhow::Result<()> {
+        use std::os::unix::net::UnixDatagram;
+        use std::sync::Arc;
+
+        use containerd_shim::api::{ConnectRequest, CreateTaskRequest};
+        use containerd_shim::protos::shim::events_ttrpc::{Events, create_events};
+        use containerd_shim::protos::shim::shim_ttrpc::{TaskClient, create_task};
+        use containerd_shim::protos::ttrpc::context::with_timeout;
+        use containerd_shim::protos::ttrpc::{Client, Server};
+        use shim::Shim as _;
+
+        use crate::sandbox::shim::local::tests::InstanceStub;
+
+        let dir = tempfile::tempdir()?;
+        let notify = UnixDatagram::bind(dir.path().join("notify.sock"))?;
+        notify.set_nonblocking(true)?;
+
+        let bundle = dir.path().join("bundle");
+        std::fs::create_dir_all(bundle.join("rootfs"))?;
+        Spec::default().save(bundle.join("config.json"))?;
+
+        // containerd, which rejects the events the shim publishes
+        struct Containerd;
+        impl Events for Containerd {}
+        let ttrpc = dir.path().join("containerd.sock.ttrpc");
+        let mut containerd = Server::new()
+            .bind(&format!("unix://{}", ttrpc.display()))?
+            .register_service(create_events(Arc::new(Containerd)));
+        containerd.start()?;
+        let publisher = RemotePublisher::new(ttrpc.to_string_lossy())?;
+
+        // as the start command does, the socket of the shim accepts connections before
+        // the task service is served
+        let address = format!("unix://{}", dir.path().join("shim.sock").display());
+        let server = Server::new().bind(&address)?;
+        let client = std::thread::spawn({
+            let address = address.clone();
+            let notify = notify.try_clone()?;
+            move || -> anyhow::Result<()> {
+                let client = TaskClient::new(Client::connect(&address)?);
+                let req = CreateTaskRequest {
+                    id: "test".to_string(),
+                    bundle: bundle.to_string_lossy().to_string(),
+                    ..Default::default()
+                };
+                client.create(with_timeout(0), &req)?;
+                let req = ConnectRequest {
+                    id: "test".to_string(),
+                    ..Default::default()
+                };
+                let res = client.connect(with_timeout(0), &req)?;
+                assert_eq!(res.shim_pid, std::process::id());
+
+                // the notification was sent once the first Connect succeeded
+                let mut buf = [0; 64];
+                let n = notify.recv(&mut buf)?;
+                assert_eq!(&buf[..n], b"READY=1\n");
+                Ok(())
+            }
+        });
+
+        let flags = Flags {
+            namespace: "test".to_string(),
+            id: "test".to_string(),
+            ..Default::default()
+        };
+        let shim = Cli::<InstanceStub>::new("test", &flags, &mut shim::Config::default());
+        assert!(
+            matches!(notify.recv(&mut [0; 64]), Err(err) if err.kind() == std::io::ErrorKind::WouldBlock)
+        );
+
+        // as for a shim started by systemd
+        let local = temp_env::with_vars(
+            [
+                ("NOTIFY_SOCKET", Some(dir.path().join("notify.sock"))),
+                (
+                    "SYSTEMD_EXEC_PID",
+                    Some(std::process::id().to_string().into()),
+                ),
+            ],
+            || shim.create_task_service(publisher),
+        );
+        let mut server = server.register_service(create_task(Arc::new(local)));
+        server.start()?;
+
+        let res = client.join().expect("the client thread panicked");
+        server.shutdown();
+        containerd.shutdown();
+        res
+    }
+}
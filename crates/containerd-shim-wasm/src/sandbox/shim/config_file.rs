@@ -0,0 +1,230 @@
+//! Configuration file of the shim, with its shim-wide settings.
+//!
+//! The file is the `ConfigPath` of the runtime options of containerd, or
+//! `/etc/runwasi/<shim-name>.toml`, e.g., `/etc/runwasi/containerd-shim-wasmtime-v1.toml`.
+//! It has the keys of the runtime options, and an `[engine]` table with the settings of the engine:
+//!
+//! ```toml
+//! ShutdownTimeout = 30
+//! LogFormat = "json"
+//!
+//! [engine]
+//! cache_dir = "/var/cache/runwasi"
+//! ```
+//!
+//! The environment variables of the shim override the file, and the `config_body` of the
+//! runtime options overrides both. Unknown keys are ignored with a warning, so that a file
+//! keeps working with older shims.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, bail};
+use toml::{Table, Value};
+
+use super::Config;
+use crate::container::COMPILE_CACHE_DIR_ENV;
+use crate::sandbox::logging::LOG_FORMAT_ENV;
+
+/// Directory of the default configuration files of the shims
+const CONFIG_DIR: &str = "/etc/runwasi";
+
+/// Overrides the `shutdown_timeout` of the configuration file, in seconds
+pub(super) const SHUTDOWN_TIMEOUT_ENV: &str = "RUNWASI_SHUTDOWN_TIMEOUT";
+
+/// The environment variables overriding the keys of the configuration file
+const ENV_KEYS: &[(&str, &str)] = &[
+    (SHUTDOWN_TIMEOUT_ENV, "shutdown_timeout"),
+    (LOG_FORMAT_ENV, "log_format"),
+    (COMPILE_CACHE_DIR_ENV, "compile_cache_dir"),
+    #[cfg(unix)]
+    (
+        crate::sys::container::pool::ZYGOTE_POOL_SIZE_ENV,
+        "zygote_pool_size",
+    ),
+];
+
+/// Key of the table with the settings of the engine
+const ENGINE_KEY: &str = "engine";
+
+/// The default configuration file of the running shim, `/etc/runwasi/<shim-name>.toml`
+pub(super) fn default_path() -> PathBuf {
+    let argv0 = std::env::args_os().next().unwrap_or_default();
+    let name = Path::new(&argv0).file_stem().unwrap_or_default();
+    Path::new(CONFIG_DIR).join(name).with_extension("toml")
+}
+
+/// The settings of the configuration file at `path`, overridden by the environment
+/// variables from `env`, and by the `body` of the runtime options
+pub(super) fn load(
+    path: &Path,
+    env: impl Fn(&str) -> Option<String>,
+    body: &str,
+) -> anyhow::Result<Config> {
+    let mut table = match std::fs::read_to_string(path) {
+        Ok(file) => parse(&file).with_context(|| format!("invalid {}", path.display()))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Table::new(),
+        Err(err) => bail!("failed to read {}: {err}", path.display()),
+    };
+    table.extend(env_table(env));
+    table.extend(parse(body)?);
+
+    if !matches!(table.get(ENGINE_KEY), None | Some(Value::Table(_))) {
+        bail!("the {ENGINE_KEY} settings must be a table");
+    }
+    let known = known_keys();
+    table.retain(|key, _| {
+        let known = known.iter().any(|known| known == key);
+        if !known {
+            log::warn!("ignoring unknown shim option {key:?}");
+        }
+        known
+    });
+
+    Ok(Value::Table(table).try_into()?)
+}
+
+/// The table of a TOML document, with the keys in snake case,
+/// as either `ShutdownTimeout` or `shutdown_timeout` can be used
+fn parse(toml: &str) -> anyhow::Result<Table> {
+    Ok(toml
+        .parse::<Table>()?
+        .into_iter()
+        .map(|(key, value)| (snake_case(&key), value))
+        .collect())
+}
+
+/// The keys overridden by the environment variables. A variable is a TOML value, e.g., `30`,
+/// or a string otherwise, e.g., `json`.
+fn env_table(env: impl Fn(&str) -> Option<String>) -> Table {
+    ENV_KEYS
+        .iter()
+        .filter_map(|(var, key)| {
+            let value = env(var)?;
+            let value = format!("value = {value}")
+                .parse::<Table>()
+                .ok()
+                .and_then(|mut table| table.remove("value"))
+                .unwrap_or(Value::String(value));
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
+
+/// The keys of `Config`, in snake case
+fn known_keys() -> Vec<String> {
+    let config = serde_json::to_value(Config::default()).unwrap_or_default();
+    let mut keys = config
+        .as_object()
+        .map(|fields| fields.keys().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    keys.push(ENGINE_KEY.to_string());
+    keys
+}
+
+fn snake_case(key: &str) -> String {
+    let mut snake = String::with_capacity(key.len());
+    for (i, c) in key.char_indices() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.push(c.to_ascii_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::sandbox::LogFormat;
+
+    #[derive(Deserialize, Default, Debug, PartialEq)]
+    #[serde(default)]
+    struct EngineSettings {
+        cache_dir: Option<PathBuf>,
+        parallel: bool,
+    }
+
+    fn load_with(file: &str, env: &[(&str, &str)], body: &str) -> anyhow::Result<Config> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("containerd-shim-test-v1.toml");
+        std::fs::write(&path, file)?;
+        let env: HashMap<_, _> = env.iter().cloned().collect();
+        load(&path, |var| env.get(var).map(|v| v.to_string()), body)
+    }
+
+    #[test]
+    fn test_precedence_of_the_settings() -> anyhow::Result<()> {
+        let file = "ShutdownTimeout = 10\nLogFormat = \"text\"\nzygote_pool_size = 2\n";
+
+        let config = load_with(file, &[], "")?;
+        assert_eq!(config.shutdown_timeout, Some(10));
+        assert_eq!(config.log_format, Some(LogFormat::Text));
+        assert_eq!(config.zygote_pool_size, Some(2));
+
+        // the environment overrides the file
+        let env = [(SHUTDOWN_TIMEOUT_ENV, "20"), (LOG_FORMAT_ENV, "json")];
+        let config = load_with(file, &env, "")?;
+        assert_eq!(config.shutdown_timeout, Some(20));
+        assert_eq!(config.log_format, Some(LogFormat::Json));
+        assert_eq!(config.zygote_pool_size, Some(2));
+
+        // and the runtime options override both
+        let config = load_with(file, &env, "ShutdownTimeout = 30\n")?;
+        assert_eq!(config.shutdown_timeout, Some(30));
+        assert_eq!(config.log_format, Some(LogFormat::Json));
+
+        assert_eq!(load_with("", &[], "")?, Config::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_engine_settings() -> anyhow::Result<()> {
+        let file = "[engine]\ncache_dir = \"/var/cache/runwasi\"\n";
+        let config = load_with(file, &[], "")?;
+        assert_eq!(
+            config.engine::<EngineSettings>()?,
+            EngineSettings {
+                cache_dir: Some("/var/cache/runwasi".into()),
+                parallel: false,
+            }
+        );
+
+        let config = load_with("", &[], "")?;
+        assert_eq!(
+            config.engine::<EngineSettings>()?,
+            EngineSettings::default()
+        );
+
+        assert!(load_with("engine = 1\n", &[], "").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_keys_are_ignored() -> anyhow::Result<()> {
+        let config = load_with("RemovedOption = true\nSystemdCgroup = true\n", &[], "")?;
+        assert!(config.systemd_cgroup);
+
+        assert!(load_with("not toml", &[], "").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_file() -> anyhow::Result<()> {
+        let config = load(
+            Path::new("/nonexistent/shim.toml"),
+            |_| None,
+            "SystemdCgroup = true",
+        )?;
+        assert!(config.systemd_cgroup);
+        assert!(default_path().starts_with(CONFIG_DIR));
+        Ok(())
+    }
+}
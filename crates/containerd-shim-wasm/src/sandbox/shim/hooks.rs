@@ -0,0 +1,344 @@
+//! The lifecycle hooks of the OCI runtime spec, run by the shim for every container.
+//!
+//! The `prestart`, `createRuntime` and `createContainer` hooks run once the instance is created,
+//! before its engine starts, and a failing hook fails the creation of the container.
+//! The `poststart` hooks run once the instance started, and the `poststop` hooks once it's
+//! deleted. A failure of those is only logged, as the spec asks.
+//! The `startContainer` hooks run in the container, and are left to libcontainer.
+//!
+//! Each hook reads the state of the container on stdin:
+//! https://github.com/opencontainers/runtime-spec/blob/main/runtime.md#state
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write as _};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, bail};
+use oci_spec::runtime::{Hook, Hooks, Spec};
+use serde::Serialize;
+
+/// How often a running hook is checked for its exit
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The state of the container, written to the stdin of its hooks
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct State<'a> {
+    oci_version: &'a str,
+    id: &'a str,
+    status: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<u32>,
+    bundle: &'a Path,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    annotations: &'a HashMap<String, String>,
+}
+
+/// The hooks of a container, with what they need to know of it
+#[derive(Default, Debug)]
+pub(super) struct LifecycleHooks {
+    hooks: Hooks,
+    oci_version: String,
+    id: String,
+    bundle: PathBuf,
+    annotations: HashMap<String, String>,
+}
+
+impl LifecycleHooks {
+    pub fn new(id: impl Into<String>, bundle: impl Into<PathBuf>, spec: &Spec) -> Self {
+        Self {
+            hooks: spec.hooks().clone().unwrap_or_default(),
+            oci_version: spec.version().clone(),
+            id: id.into(),
+            bundle: bundle.into(),
+            annotations: spec.annotations().clone().unwrap_or_default(),
+        }
+    }
+
+    /// Run the hooks of the creation of the container, the deprecated `prestart` hooks first
+    pub fn run_create(&self, pid: u32) -> anyhow::Result<()> {
+        let state = self.state("creating", Some(pid));
+        for (name, hooks) in [
+            ("prestart", self.hooks.prestart()),
+            ("createRuntime", self.hooks.create_runtime()),
+            ("createContainer", self.hooks.create_container()),
+        ] {
+            for hook in hooks.iter().flatten() {
+                run_hook(hook, &state)
+                    .with_context(|| format!("{name} hook {:?} failed", hook.path()))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn run_poststart(&self, pid: u32) {
+        let state = self.state("running", Some(pid));
+        self.run_logged("poststart", self.hooks.poststart(), &state);
+    }
+
+    pub fn run_poststop(&self, pid: Option<u32>) {
+        let state = self.state("stopped", pid);
+        self.run_logged("poststop", self.hooks.poststop(), &state);
+    }
+
+    fn state(&self, status: &'static str, pid: Option<u32>) -> Vec<u8> {
+        let state = State {
+            oci_version: &self.oci_version,
+            id: &self.id,
+            status,
+            pid,
+            bundle: &self.bundle,
+            annotations: &self.annotations,
+        };
+        serde_json::to_vec(&state).unwrap_or_default()
+    }
+
+    /// Run all the hooks, whatever the failures of the previous ones
+    fn run_logged(&self, name: &str, hooks: &Option<Vec<Hook>>, state: &[u8]) {
+        for hook in hooks.iter().flatten() {
+            if let Err(err) = run_hook(hook, state) {
+                log::warn!(
+                    "{name} hook {:?} of container {} failed: {err:#}",
+                    hook.path(),
+                    self.id
+                );
+            }
+        }
+    }
+}
+
+/// Run `hook` with the `state` of the container on its stdin, and wait for
+/// its exit, for at most its timeout
+fn run_hook(hook: &Hook, state: &[u8]) -> anyhow::Result<()> {
+    let mut hook_command = process::Command::new(hook.path());
+    // Based on OCI spec, the first argument of the args vector is the
+    // arg0, which can be different from the path.  For example, path
+    // may be "/usr/bin/true" and arg0 is set to "true". However, rust
+    // command differentiates arg0 from args, where rust command arg
+    // doesn't include arg0. So we have to make the split arg0 from the
+    // rest of args.
+    if let Some((arg0, args)) = hook.args().as_ref().and_then(|a| a.split_first()) {
+        log::debug!("run_hooks arg0: {:?}, args: {:?}", arg0, args);
+
+        #[cfg(unix)]
+        {
+            hook_command.arg0(arg0).args(args);
+        }
+
+        #[cfg(windows)]
+        {
+            if !&hook.path().ends_with(arg0) {
+                bail!(
+                    "Running with arg0 as different name than executable is not supported on Windows due to rust std library process implementation."
+                );
+            }
+
+            hook_command.args(args);
+        }
+    } else {
+        #[cfg(unix)]
+        hook_command.arg0(hook.path());
+    };
+
+    let envs = hook.env().as_deref().map(parse_env).unwrap_or_default();
+    log::debug!("run_hooks envs: {:?}", envs);
+
+    let mut hook_process = hook_command
+        .env_clear()
+        .envs(envs)
+        .stdin(process::Stdio::piped())
+        .spawn()
+        .with_context(|| "Failed to execute hook")?;
+
+    if let Some(mut stdin) = hook_process.stdin.take() {
+        // We want to ignore BrokenPipe here. A BrokenPipe indicates
+        // either the hook is crashed/errored or it ran successfully.
+        // Either way, this is an indication that the hook command
+        // finished execution.  If the hook command was successful,
+        // which we will check later in this function, we should not
+        // fail this step here. We still want to check for all the other
+        // error, in the case that the hook command is waiting for us to
+        // write to stdin.
+        match stdin.write_all(state) {
+            // Not a broken pipe. The hook command may be waiting
+            // for us.
+            Err(e) if e.kind() != ErrorKind::BrokenPipe => {
+                let _ = hook_process.kill();
+            }
+            _ => {}
+        }
+        // closing stdin, for the hooks reading the state until EOF
+    }
+
+    let timeout = hook
+        .timeout()
+        .to_owned()
+        .filter(|&secs| secs > 0)
+        .map(|secs| Duration::from_secs(secs as u64));
+    let status = match timeout {
+        None => hook_process.wait()?,
+        Some(timeout) => {
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Some(status) = hook_process.try_wait()? {
+                    break status;
+                }
+                if Instant::now() >= deadline {
+                    let _ = hook_process.kill();
+                    let _ = hook_process.wait();
+                    bail!("timed out after {timeout:?}");
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+    };
+    if !status.success() {
+        bail!("exited with {status}");
+    }
+    Ok(())
+}
+
+fn parse_env(envs: &[String]) -> HashMap<String, String> {
+    // make NAME=VALUE to HashMap<NAME, VALUE>.
+    envs.iter()
+        .filter_map(|e| {
+            let mut split = e.split('=');
+
+            split.next().map(|key| {
+                let value = split.collect::<Vec<&str>>().join("=");
+                (key.into(), value)
+            })
+        })
+        .collect()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use oci_spec::runtime::{HookBuilder, HooksBuilder, SpecBuilder};
+    use serde_json::Value;
+
+    use super::*;
+
+    /// A hook appending its name and the state it read to `log`
+    fn recording_hook(dir: &Path, name: &str, log: &Path, exit_code: i32) -> Hook {
+        let path = dir.join(name);
+        let script = format!(
+            "#!/bin/sh\necho \"{name} $(cat)\" >> {}\nexit {exit_code}\n",
+            log.display()
+        );
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        HookBuilder::default()
+            .path(path)
+            .env(vec!["PATH=/usr/bin:/bin".to_string()])
+            .build()
+            .unwrap()
+    }
+
+    /// The hooks that ran, with the state they read
+    fn recorded(log: &Path) -> Vec<(String, Value)> {
+        std::fs::read_to_string(log)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| {
+                let (name, state) = line.split_once(' ').unwrap();
+                (name.to_string(), serde_json::from_str(state).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lifecycle_hooks() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log = dir.path().join("hooks.log");
+        let hook = |name| recording_hook(dir.path(), name, &log, 0);
+        let spec = SpecBuilder::default()
+            .hooks(
+                HooksBuilder::default()
+                    .create_runtime(vec![hook("createRuntime")])
+                    .create_container(vec![hook("createContainer")])
+                    .poststart(vec![hook("poststart")])
+                    .poststop(vec![hook("poststop")])
+                    .build()?,
+            )
+            .annotations(HashMap::from([("app".to_string(), "hello".to_string())]))
+            .build()?;
+        let hooks = LifecycleHooks::new("test", "/run/bundle", &spec);
+
+        hooks.run_create(42)?;
+        hooks.run_poststart(42);
+        hooks.run_poststop(None);
+
+        let recorded = recorded(&log);
+        let names: Vec<_> = recorded.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["createRuntime", "createContainer", "poststart", "poststop"]
+        );
+        let (_, state) = &recorded[0];
+        assert_eq!(state["ociVersion"], spec.version().as_str());
+        assert_eq!(state["id"], "test");
+        assert_eq!(state["status"], "creating");
+        assert_eq!(state["pid"], 42);
+        assert_eq!(state["bundle"], "/run/bundle");
+        assert_eq!(state["annotations"]["app"], "hello");
+        assert_eq!(recorded[2].1["status"], "running");
+        assert_eq!(recorded[3].1["status"], "stopped");
+        assert!(recorded[3].1.get("pid").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_failing_hooks() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log = dir.path().join("hooks.log");
+        let spec = SpecBuilder::default()
+            .hooks(
+                HooksBuilder::default()
+                    .create_runtime(vec![recording_hook(dir.path(), "failing", &log, 1)])
+                    .create_container(vec![recording_hook(dir.path(), "skipped", &log, 0)])
+                    .poststop(vec![
+                        recording_hook(dir.path(), "failing", &log, 1),
+                        recording_hook(dir.path(), "poststop", &log, 0),
+                    ])
+                    .build()?,
+            )
+            .build()?;
+        let hooks = LifecycleHooks::new("test", "/run/bundle", &spec);
+
+        let err = hooks.run_create(42).unwrap_err();
+        assert!(err.to_string().contains("createRuntime hook"), "{err}");
+
+        // a failing poststop hook doesn't stop the next ones
+        hooks.run_poststop(Some(42));
+        let recorded = recorded(&log);
+        let names: Vec<_> = recorded.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["failing", "failing", "poststop"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hook_timeout() -> anyhow::Result<()> {
+        let hook = HookBuilder::default()
+            .path("/bin/sh")
+            .args(vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "sleep 10".to_string(),
+            ])
+            .timeout(1)
+            .build()?;
+
+        let start = Instant::now();
+        let err = run_hook(&hook, b"{}").unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+        assert!(start.elapsed() < Duration::from_secs(10));
+        Ok(())
+    }
+}
@@ -1,35 +1,124 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
 use chrono::{DateTime, Utc};
+use futures::FutureExt as _;
+use log::error;
+use oci_spec::runtime::LinuxResources;
 use tokio::sync::{OnceCell, RwLock};
 
+use crate::sandbox::shim::hooks::LifecycleHooks;
+use crate::sandbox::shim::restart::{RestartPolicy, Restarts};
 use crate::sandbox::shim::task_state::TaskState;
-use crate::sandbox::{Instance, InstanceConfig, Result};
+use crate::sandbox::sync::WaitableCell;
+use crate::sandbox::{Error, ExecConfig, Instance, InstanceConfig, Result};
 
 pub(super) struct InstanceData<T: Instance> {
-    pub instance: T,
-    pub config: InstanceConfig,
+    /// The running instance, replaced by a new one when it's restarted
+    instance: std::sync::RwLock<Arc<T>>,
+    id: String,
+    /// The config of the instance, with the stdio attached last
+    config: std::sync::RwLock<InstanceConfig>,
+    /// The lifecycle hooks of the spec, run by the shim
+    pub hooks: LifecycleHooks,
+    /// The restarts of the instance, with a restart policy
+    restarts: Option<Restarts>,
+    pid: Mutex<Option<u32>>,
+    state: RwLock<TaskState>,
+    execs: RwLock<HashMap<String, Arc<ExecData>>>,
+    /// Set once the `TaskExit` event of the started instance was published,
+    /// so that the `TaskDelete` event follows it
+    pub exit_published: WaitableCell<()>,
+}
+
+/// An additional process started in a running instance, see `Instance::exec`.
+pub(super) struct ExecData {
+    pub config: ExecConfig,
     pid: OnceCell<u32>,
+    exit: WaitableCell<(u32, DateTime<Utc>)>,
     state: RwLock<TaskState>,
 }
 
+impl ExecData {
+    pub fn pid(&self) -> Option<u32> {
+        self.pid.get().copied()
+    }
+
+    /// Waits for the exec process to exit.
+    /// Unlike `Instance::wait_exec`, this can be called any number of times.
+    pub async fn wait(&self) -> (u32, DateTime<Utc>) {
+        *self.exit.wait().await
+    }
+
+    /// The exit code of the exec process, if it already exited
+    pub fn try_wait(&self) -> Option<(u32, DateTime<Utc>)> {
+        self.exit.wait().now_or_never().copied()
+    }
+}
+
 impl<T: Instance> InstanceData<T> {
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     pub async fn new(
         id: impl AsRef<str> + std::fmt::Debug,
         config: InstanceConfig,
+        hooks: LifecycleHooks,
+        restart_policy: Option<RestartPolicy>,
     ) -> Result<Self> {
         let id = id.as_ref().to_string();
-        let instance = T::new(id, &config).await?;
+        let instance = T::new(id.clone(), &config).await?;
         Ok(Self {
-            instance,
-            config,
-            pid: OnceCell::default(),
+            instance: std::sync::RwLock::new(Arc::new(instance)),
+            id,
+            config: std::sync::RwLock::new(config),
+            hooks,
+            restarts: restart_policy.map(Restarts::new),
+            pid: Mutex::default(),
             state: RwLock::new(TaskState::Created),
+            execs: RwLock::default(),
+            exit_published: WaitableCell::new(),
         })
     }
 
+    /// The running instance. Hold it only for one call, as a restart replaces it.
+    pub fn instance(&self) -> Arc<T> {
+        self.instance.read().unwrap().clone()
+    }
+
+    pub fn config(&self) -> InstanceConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Attach the stdio FIFOs of a new client to the running instance, which a restart
+    /// of the instance keeps. An empty path keeps the stream as it is.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn attach_stdio(&self, stdin: &Path, stdout: &Path, stderr: &Path) -> Result<()> {
+        // not while the instance is restarted, which opens the stdio of the config
+        let _s = self.state.write().await;
+        self.instance().attach_stdio(stdin, stdout, stderr).await?;
+        let config = &mut *self.config.write().unwrap();
+        for (stream, path) in [
+            (&mut config.stdin, stdin),
+            (&mut config.stdout, stdout),
+            (&mut config.stderr, stderr),
+        ] {
+            if !path.as_os_str().is_empty() {
+                *stream = path.to_path_buf();
+            }
+        }
+        Ok(())
+    }
+
+    /// The pid of the running instance, which changes when it's restarted
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     pub fn pid(&self) -> Option<u32> {
-        self.pid.get().copied()
+        *self.pid.lock().unwrap()
+    }
+
+    /// The number of restarts of the instance by its restart policy, if it has one
+    pub fn restart_count(&self) -> Option<u32> {
+        self.restarts.as_ref().map(Restarts::count)
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
@@ -37,13 +126,13 @@ impl<T: Instance> InstanceData<T> {
         let mut s = self.state.write().await;
         s.start()?;
 
-        let res = self.instance.start().await;
+        let res = self.instance().start().await;
 
         // These state transitions are always `Ok(())` because
         // we hold the lock since `s.start()`
         let _ = match res {
             Ok(pid) => {
-                let _ = self.pid.set(pid);
+                *self.pid.lock().unwrap() = Some(pid);
                 s.started()
             }
             Err(_) => s.stop(),
@@ -55,17 +144,95 @@ impl<T: Instance> InstanceData<T> {
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     pub async fn kill(&self, signal: u32) -> Result<()> {
         let mut s = self.state.write().await;
+
+        if matches!(*s, TaskState::Paused) {
+            // A frozen process doesn't handle signals, resume it first
+            self.instance().resume().await?;
+            // Always `Ok(())` because we hold the lock
+            let _ = s.resume();
+        }
+
         s.kill()?;
 
-        self.instance.kill(signal).await
+        if let Some(restarts) = &self.restarts {
+            // an explicit kill is never followed by a restart
+            restarts.stop();
+            // the instance already exited, and is waiting for its restart
+            if self.instance().try_wait().is_some() {
+                return Ok(());
+            }
+        }
+
+        self.instance().kill(signal).await
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn update(&self, resources: &LinuxResources) -> Result<()> {
+        let mut s = self.state.write().await;
+        s.update()?;
+
+        self.instance().update(resources).await
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn pause(&self) -> Result<()> {
+        let mut s = self.state.write().await;
+
+        // Pausing a paused task is a no-op
+        if matches!(*s, TaskState::Paused) {
+            return Ok(());
+        }
+
+        s.pause()?;
+
+        let res = self.instance().pause().await;
+
+        if res.is_err() {
+            // Always `Ok(())` because we hold the lock since `s.pause()`
+            let _ = s.resume();
+        }
+
+        res
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn resume(&self) -> Result<()> {
+        let mut s = self.state.write().await;
+
+        // Resuming a running task is a no-op
+        if matches!(*s, TaskState::Started) {
+            return Ok(());
+        }
+
+        s.resume()?;
+
+        let res = self.instance().resume().await;
+
+        if res.is_err() {
+            // Always `Ok(())` because we hold the lock since `s.resume()`
+            let _ = s.pause();
+        }
+
+        res
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn is_paused(&self) -> bool {
+        matches!(*self.state.read().await, TaskState::Paused)
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     pub async fn delete(&self) -> Result<()> {
         let mut s = self.state.write().await;
+        // the instance exited, but the state might not know yet, e.g., when the exit
+        // is being published while the instance is deleted
+        if matches!(*s, TaskState::Started | TaskState::Paused) && self.try_wait().is_some() {
+            // Always `Ok(())` from these states
+            let _ = s.stop();
+        }
         s.delete()?;
 
-        let res = self.instance.delete().await;
+        let res = self.instance().delete().await;
 
         if res.is_err() {
             // Always `Ok(())` because we hold the lock since `s.delete()`
@@ -75,11 +242,221 @@ impl<T: Instance> InstanceData<T> {
         res
     }
 
+    /// Waits for the instance to exit. With a restart policy, only its last exit counts,
+    /// once `supervise` gave up restarting it.
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     pub async fn wait(&self) -> (u32, DateTime<Utc>) {
-        let res = self.instance.wait().await;
+        let res = match &self.restarts {
+            Some(restarts) => restarts.wait().await,
+            None => self.instance().wait().await,
+        };
         let mut s = self.state.write().await;
         *s = TaskState::Exited;
         res
     }
+
+    /// The exit code of the instance, if it already exited.
+    /// Unlike `wait`, this doesn't wait for the state of the instance, which might be locked.
+    pub fn try_wait(&self) -> Option<(u32, DateTime<Utc>)> {
+        match &self.restarts {
+            Some(restarts) => restarts.try_wait(),
+            None => self.instance().try_wait(),
+        }
+    }
+
+    /// Waits for the started instance to exit, restarting it as its restart policy asks,
+    /// and calling `restarted` with the pid of each restart.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, restarted), level = "Debug")
+    )]
+    pub async fn supervise(&self, mut restarted: impl FnMut(u32)) -> (u32, DateTime<Utc>) {
+        let Some(restarts) = &self.restarts else {
+            return self.wait().await;
+        };
+        let exit = loop {
+            let exit = self.instance().wait().await;
+            let Some(backoff) = restarts.backoff(exit.0) else {
+                break exit;
+            };
+            log::info!(
+                "container {} exited with {}, restarting it in {backoff:?}",
+                self.id,
+                exit.0
+            );
+            if !restarts.wait_backoff(backoff).await {
+                break exit;
+            }
+            match self.restart(restarts).await {
+                Ok(Some(pid)) => restarted(pid),
+                Ok(None) => break exit,
+                Err(err) => {
+                    error!("failed to restart container {}: {err}", self.id);
+                    break self.instance().try_wait().unwrap_or(exit);
+                }
+            }
+        };
+        restarts.exited(exit);
+        self.wait().await
+    }
+
+    /// Replace the exited instance by a new one, with the same config, and start it.
+    /// Returns the pid of the new instance, or `None` if it was killed in the meantime.
+    async fn restart(&self, restarts: &Restarts) -> Result<Option<u32>> {
+        // a kill or a delete waits for the restart, and then sees the new instance
+        let _s = self.state.write().await;
+        if restarts.is_stopped() {
+            return Ok(None);
+        }
+
+        self.instance().delete().await?;
+        let instance = Arc::new(T::new(self.id.clone(), &self.config()).await?);
+        // replaced before it starts, so that a failed start is cleaned up by the delete
+        *self.instance.write().unwrap() = instance.clone();
+        let pid = instance.start().await?;
+        *self.pid.lock().unwrap() = Some(pid);
+        restarts.restarted();
+        Ok(Some(pid))
+    }
+
+    /// A receiver notified of each restart of the instance, if it has a restart policy
+    pub fn subscribe_restarts(&self) -> Option<tokio::sync::watch::Receiver<u32>> {
+        self.restarts.as_ref().map(Restarts::subscribe)
+    }
+
+    /// Waits for the instance to exit, for up to `timeout`
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn wait_timeout(&self, timeout: Duration) -> Option<(u32, DateTime<Utc>)> {
+        tokio::time::timeout(timeout, self.wait()).await.ok()
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn get_exec(&self, exec_id: &str) -> Result<Arc<ExecData>> {
+        let exec = self.execs.read().await.get(exec_id).cloned();
+        exec.ok_or_else(|| Error::NotFound(exec_id.to_string()))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, config), level = "Debug")
+    )]
+    pub async fn add_exec(&self, exec_id: &str, config: ExecConfig) -> Result<()> {
+        // Hold the lock so that the instance can't exit while adding the exec
+        let s = self.state.read().await;
+        if !matches!(*s, TaskState::Started) {
+            return Err(Error::FailedPrecondition(format!(
+                "cannot exec in a task in the {:?} state",
+                *s
+            )));
+        }
+
+        let mut execs = self.execs.write().await;
+        if execs.contains_key(exec_id) {
+            return Err(Error::AlreadyExists(exec_id.to_string()));
+        }
+        execs.insert(
+            exec_id.to_string(),
+            Arc::new(ExecData {
+                config,
+                pid: OnceCell::default(),
+                exit: WaitableCell::new(),
+                state: RwLock::new(TaskState::Created),
+            }),
+        );
+        Ok(())
+    }
+
+    /// Starts the exec process `exec_id`.
+    /// Once started, `reap_exec` must be called to collect its exit status.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn start_exec(&self, exec_id: &str) -> Result<(Arc<ExecData>, u32)> {
+        let exec = self.get_exec(exec_id).await?;
+        let mut s = exec.state.write().await;
+        s.start()?;
+
+        let res = self.instance().exec(exec_id, &exec.config).await;
+
+        // These state transitions are always `Ok(())` because
+        // we hold the lock since `s.start()`
+        let _ = match res {
+            Ok(pid) => {
+                let _ = exec.pid.set(pid);
+                s.started()
+            }
+            Err(_) => s.stop(),
+        };
+
+        drop(s);
+        res.map(|pid| (exec, pid))
+    }
+
+    /// Waits for the started exec process `exec_id` to exit and records its exit status.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, exec), level = "Debug")
+    )]
+    pub async fn reap_exec(&self, exec_id: &str, exec: &ExecData) -> (u32, DateTime<Utc>) {
+        let res = self
+            .instance()
+            .wait_exec(exec_id)
+            .await
+            .unwrap_or_else(|err| {
+                error!("failed to wait for exec {exec_id}: {err}");
+                (137, Utc::now())
+            });
+        let mut s = exec.state.write().await;
+        *s = TaskState::Exited;
+        let _ = exec.exit.set(res);
+        res
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn kill_exec(&self, exec_id: &str, signal: u32) -> Result<()> {
+        let exec = self.get_exec(exec_id).await?;
+        let mut s = exec.state.write().await;
+        s.kill()?;
+
+        self.instance().kill_exec(exec_id, signal).await
+    }
+
+    /// Removes the exec process `exec_id`, which must not be running.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub async fn delete_exec(&self, exec_id: &str) -> Result<Arc<ExecData>> {
+        let exec = self.get_exec(exec_id).await?;
+        exec.state.write().await.delete()?;
+        self.execs.write().await.remove(exec_id);
+        Ok(exec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::shim::local::tests::InstanceStub;
+
+    #[tokio::test]
+    async fn test_exit_while_state_is_locked() -> Result<()> {
+        let i = InstanceData::<InstanceStub>::new(
+            "test",
+            InstanceConfig::default(),
+            LifecycleHooks::default(),
+            None,
+        )
+        .await?;
+        i.start().await?;
+        assert!(i.try_wait().is_none());
+        assert!(i.wait_timeout(Duration::from_millis(10)).await.is_none());
+
+        // the stub exits with 1 when killed
+        i.kill(9).await?;
+        {
+            // e.g., while the exit of the instance is being published
+            let _state = i.state.write().await;
+            assert_eq!(i.try_wait().map(|(code, _)| code), Some(1));
+        }
+
+        // the state isn't updated by `wait` yet, deleting still works
+        i.delete().await?;
+        Ok(())
+    }
 }
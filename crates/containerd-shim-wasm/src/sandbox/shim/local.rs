@@ -1,43 +1,67 @@
 use std::collections::HashMap;
 use std::fs::create_dir_all;
-use std::ops::Not;
-use std::path::Path;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::ensure;
 use containerd_shim::api::{
-    ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse, DeleteRequest, Empty,
-    KillRequest, ShutdownRequest, StartRequest, StartResponse, StateRequest, StateResponse,
-    StatsRequest, StatsResponse, WaitRequest, WaitResponse,
+    CloseIORequest, ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse,
+    DeleteRequest, Empty, ExecProcessRequest, KillRequest, PauseRequest, ResizePtyRequest,
+    ResumeRequest, ShutdownRequest, StartRequest, StartResponse, StateRequest, StateResponse,
+    StatsRequest, StatsResponse, UpdateTaskRequest, WaitRequest, WaitResponse,
 };
 use containerd_shim::error::Error as ShimError;
-use containerd_shim::protos::events::task::{TaskCreate, TaskDelete, TaskExit, TaskIO, TaskStart};
+use containerd_shim::protos::events::task::{
+    TaskCreate, TaskDelete, TaskExecAdded, TaskExecStarted, TaskExit, TaskIO, TaskOOM, TaskPaused,
+    TaskResumed, TaskStart,
+};
 use containerd_shim::protos::shim::shim_ttrpc::Task;
+use containerd_shim::protos::types::mount::Mount;
 use containerd_shim::protos::types::task::Status;
 use containerd_shim::util::IntoOption;
 use containerd_shim::{DeleteResponse, TtrpcContext, TtrpcResult};
 use futures::FutureExt as _;
 use log::debug;
-use oci_spec::runtime::Spec;
+use oci_spec::runtime::{LinuxResources, Process, Spec};
 use prost::Message;
 use protobuf::well_known_types::any::Any;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 #[cfg(feature = "opentelemetry")]
 use tracing_opentelemetry::OpenTelemetrySpanExt as _;
 
+use super::config_file;
+use super::hooks::LifecycleHooks;
 #[cfg(feature = "opentelemetry")]
 use super::otel::extract_context;
+use super::restart::RestartPolicy;
+use crate::container::{CompileCache, DEFAULT_COMPILE_CACHE_MAX_SIZE, DEFAULT_COMPILE_CACHE_ROOT};
 use crate::sandbox::async_utils::AmbientRuntime as _;
-use crate::sandbox::instance::{Instance, InstanceConfig};
+use crate::sandbox::instance::{ExecConfig, Instance, InstanceConfig, append_restarts_to};
+use crate::sandbox::logging::{self, LogContext};
 use crate::sandbox::shim::events::{EventSender, RemoteEventSender, ToTimestamp};
 use crate::sandbox::shim::instance_data::InstanceData;
+use crate::sandbox::shim::metrics::{self, MetricsAddress, ShimMetrics};
+use crate::sandbox::shim::sandbox_data::SandboxData;
 use crate::sandbox::sync::WaitableCell;
-use crate::sandbox::{Error, Result, oci};
+use crate::sandbox::{
+    EnvPolicy, Error, HostDir, LogFormat, NativeFallback, NativeFallbackPolicy, Result,
+    SocketPolicy,
+};
 use crate::sys::metrics::get_metrics;
 
+#[cfg(feature = "sandbox")]
+mod sandbox;
 #[cfg(test)]
-mod tests;
+pub(super) mod tests;
+
+/// Deadline of the Create and Start requests, in seconds, when the options don't set one
+const DEFAULT_OPERATION_TIMEOUT: u64 = 120;
 
 /// containerd runtime options
 #[derive(Message, Clone, PartialEq)]
@@ -52,45 +76,161 @@ struct Options {
 
 /// This is generated by decoding the `options` field of a `CreateTaskRequest` to get an `Options` struct,
 /// interpreting the `config_body` field as TOML,
-/// and deserializing it.
+/// and deserializing it on top of the configuration file of the shim and its environment variables.
 #[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default)]
 pub struct Config {
-    /// Enables systemd cgroup.
+    /// Enables systemd cgroup, for the containers whose spec has no cgroup path.
+    /// The others use the driver of their `cgroupsPath`.
     #[serde(alias = "SystemdCgroup")]
     pub systemd_cgroup: bool,
+    /// How long the shim waits for a new container after the last one was deleted, in seconds,
+    /// before it exits on a Shutdown request.  By default it exits right away.
+    #[serde(alias = "ShutdownTimeout")]
+    pub shutdown_timeout: Option<u64>,
+    /// Policy on the environment variables passed to the modules.
+    #[serde(alias = "EnvPolicy", default)]
+    pub env_policy: EnvPolicy,
+    /// Policy on the sockets of the guests, which the containers opt in to.
+    #[serde(alias = "SocketPolicy", default)]
+    pub socket_policy: SocketPolicy,
+    /// Memory kept out of the memory limit of the guests, in bytes, for the allocations of the
+    /// engine around them.  The default of the engine when not set.
+    #[serde(alias = "MemoryReserve")]
+    pub memory_reserve: Option<u64>,
+    /// Directory where the engines keep the modules they compiled, for the modules that aren't
+    /// precompiled in the content store, `/var/lib/runwasi/<engine>-cache` by default.
+    #[serde(alias = "CompileCacheDir")]
+    pub compile_cache_dir: Option<PathBuf>,
+    /// Size of the compile cache in bytes, past which the least recently used modules are
+    /// removed, 1GiB by default.  0 disables the cache.
+    #[serde(alias = "CompileCacheMaxSize")]
+    pub compile_cache_max_size: Option<u64>,
+    /// Format of the log records, overriding the `RUNWASI_LOG_FORMAT` environment variable.
+    #[serde(alias = "LogFormat")]
+    pub log_format: Option<LogFormat>,
+    /// Level of the log records, e.g., `debug`, overriding `RUST_LOG`.
+    #[serde(alias = "LogLevel")]
+    pub log_level: Option<String>,
+    /// Whether the images that aren't wasm run as native Linux containers.
+    #[serde(alias = "NativeFallback", default)]
+    pub native_fallback: NativeFallback,
+    /// Registries whose images run as native Linux containers, with `registry-allowlist`.
+    #[serde(alias = "NativeFallbackRegistries", default)]
+    pub native_fallback_registries: Vec<String>,
+    /// Number of idle zygotes forked ahead of the containers, to create them faster,
+    /// overriding the `RUNWASI_ZYGOTE_POOL_SIZE` environment variable.
+    #[serde(alias = "ZygotePoolSize")]
+    pub zygote_pool_size: Option<usize>,
+    /// Address serving the metrics of the shim in the Prometheus text format, either
+    /// `unix://<path>`, except on Windows, or a socket address on the loopback interface.
+    /// Disabled by default.
+    #[serde(alias = "MetricsAddress")]
+    pub metrics_address: Option<String>,
+    /// Directory of the crash reports of the containers, instead of their bundle,
+    /// which containerd removes with the container.
+    #[serde(alias = "CrashDir")]
+    pub crash_dir: Option<PathBuf>,
+    /// Directories of the node bind mounted in every container, after the mounts of its spec.
+    #[serde(alias = "HostDirs")]
+    pub host_dirs: Vec<HostDir>,
+    /// How long a Create request may take, in seconds, before the shim cleans up the
+    /// container and fails the request.  120 seconds by default.
+    #[serde(alias = "CreateTimeout")]
+    pub create_timeout: Option<u64>,
+    /// How long a Start request may take, in seconds, before the shim cleans up the
+    /// container and fails the request.  120 seconds by default.
+    #[serde(alias = "StartTimeout")]
+    pub start_timeout: Option<u64>,
+    /// Settings of the engine, from the `[engine]` table of the configuration file.
+    #[serde(alias = "Engine", skip_serializing_if = "toml::Table::is_empty")]
+    pub engine: toml::Table,
 }
 
 impl Config {
-    fn get_from_options(options: Option<&Any>) -> anyhow::Result<Self> {
-        let Some(opts) = options else {
-            return Ok(Default::default());
+    pub fn native_fallback_policy(&self) -> NativeFallbackPolicy {
+        NativeFallbackPolicy {
+            mode: self.native_fallback,
+            registries: self.native_fallback_registries.clone(),
+        }
+    }
+
+    /// The deadline of the Create requests
+    pub fn create_timeout(&self) -> Duration {
+        Duration::from_secs(self.create_timeout.unwrap_or(DEFAULT_OPERATION_TIMEOUT))
+    }
+
+    /// The deadline of the Start requests
+    pub fn start_timeout(&self) -> Duration {
+        Duration::from_secs(self.start_timeout.unwrap_or(DEFAULT_OPERATION_TIMEOUT))
+    }
+
+    /// The compile cache of `engine`, unless it's disabled
+    pub fn compile_cache(&self, engine: &str) -> Option<CompileCache> {
+        let max_size = self
+            .compile_cache_max_size
+            .unwrap_or(DEFAULT_COMPILE_CACHE_MAX_SIZE);
+        if max_size == 0 {
+            return None;
+        }
+        let dir = match &self.compile_cache_dir {
+            Some(dir) => dir.clone(),
+            None => Path::new(DEFAULT_COMPILE_CACHE_ROOT).join(format!("{engine}-cache")),
         };
+        Some(CompileCache::new(dir, max_size))
+    }
 
-        ensure!(
-            opts.type_url == "runtimeoptions.v1.Options",
-            "Invalid options type {}",
-            opts.type_url
-        );
+    /// The typed settings of the engine, from its `[engine]` table
+    pub fn engine<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        Ok(toml::Value::Table(self.engine.clone()).try_into()?)
+    }
 
-        let opts = Options::decode(opts.value.as_slice())?;
+    /// The config of the runtime `options` of a create, on top of the configuration file
+    fn get_from_options(options: Option<&Any>) -> Result<Self> {
+        Self::load(options)
+            .map_err(|err| Error::InvalidArgument(format!("invalid shim options: {err:#}")))
+    }
 
-        let config = toml::from_str(opts.config_body.as_str())
-            .map_err(|err| Error::InvalidArgument(format!("invalid shim options: {err}")))?;
+    fn load(options: Option<&Any>) -> anyhow::Result<Self> {
+        let opts = match options {
+            Some(opts) => {
+                ensure!(
+                    opts.type_url == "runtimeoptions.v1.Options",
+                    "Invalid options type {}",
+                    opts.type_url
+                );
+                Options::decode(opts.value.as_slice())?
+            }
+            None => Options::default(),
+        };
 
-        Ok(config)
+        let path = match opts.config_path.as_str() {
+            "" => config_file::default_path(),
+            path => PathBuf::from(path),
+        };
+        config_file::load(&path, |var| std::env::var(var).ok(), &opts.config_body)
     }
 }
 
 type LocalInstances<T> = RwLock<HashMap<String, Arc<InstanceData<T>>>>;
+type LocalSandboxes = RwLock<HashMap<String, Arc<SandboxData>>>;
 
 /// Local implements the Task service for a containerd shim.
 /// It defers all task operations to the `Instance` implementation.
 pub struct Local<T: Instance + Send + Sync, E: EventSender = RemoteEventSender> {
-    pub(super) instances: LocalInstances<T>,
+    pub(super) instances: Arc<LocalInstances<T>>,
+    /// The pod sandboxes created with the sandbox API
+    pub(super) sandboxes: LocalSandboxes,
     events: E,
     exit: WaitableCell<()>,
     namespace: String,
     containerd_address: String,
+    /// `shutdown_timeout` of the last created container
+    shutdown_timeout: Mutex<Option<Duration>>,
+    /// Number of created containers, to cancel a pending shutdown
+    created: Arc<AtomicU64>,
+    /// The cleanups of the creates that missed their deadline, which wait for their build
+    abandoned: Mutex<HashMap<String, JoinHandle<()>>>,
 }
 
 impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
@@ -105,15 +245,19 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         namespace: impl AsRef<str> + std::fmt::Debug,
         containerd_address: impl AsRef<str> + std::fmt::Debug,
     ) -> Self {
-        let instances = RwLock::default();
+        let instances = Arc::default();
         let namespace = namespace.as_ref().to_string();
         let containerd_address = containerd_address.as_ref().to_string();
         Self {
             instances,
+            sandboxes: RwLock::default(),
             events,
             exit,
             namespace,
             containerd_address,
+            shutdown_timeout: Mutex::default(),
+            created: Arc::default(),
+            abandoned: Mutex::default(),
         }
     }
 
@@ -130,7 +274,116 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     async fn is_empty(&self) -> bool {
-        self.instances.read().await.is_empty()
+        self.instances.read().await.is_empty() && self.sandboxes.read().await.is_empty()
+    }
+
+    /// Drop the entries of the container `id`
+    async fn remove_instance(&self, id: &str) {
+        self.instances.write().await.remove(id);
+        for sandbox in self.sandboxes.read().await.values() {
+            sandbox.remove_container(id);
+        }
+    }
+
+    /// Clean up the container `id` as a Delete would, after its Start missed its deadline,
+    /// so that containerd can create it again
+    async fn abandon(&self, id: &str, bundle: &Path) {
+        log::warn!("abandoning container {id}, its start did not finish in time");
+        let instance = self.instances.read().await.get(id).cloned();
+        if let Some(i) = instance {
+            discard(id, &i).await;
+        }
+        self.remove_instance(id).await;
+        unmount_rootfs(id, bundle);
+    }
+
+    /// Clean up what the `build` of the container `id` leaves once it ends, after its Create
+    /// missed its deadline, so that containerd can create it again
+    fn abandon_build(
+        &self,
+        id: &str,
+        bundle: &Path,
+        build: JoinHandle<Result<InstanceData<T>>>,
+        sandbox: Option<Arc<SandboxData>>,
+    ) {
+        log::warn!("abandoning container {id}, its create did not finish in time");
+        let cleanup = {
+            let id = id.to_string();
+            let bundle = bundle.to_path_buf();
+            async move {
+                if let Ok(Ok(instance)) = build.await {
+                    discard(&id, &instance).await;
+                }
+                if let Some(sandbox) = sandbox {
+                    sandbox.remove_container(&id);
+                }
+                unmount_rootfs(&id, &bundle);
+            }
+        }
+        .spawn();
+        self.abandoned
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), cleanup);
+    }
+
+    /// Wait until `deadline` for the cleanup of a former create of `id` that missed its
+    /// deadline, and whether there is none left
+    async fn wait_abandoned(&self, id: &str, deadline: Instant) -> bool {
+        let cleanup = self.abandoned.lock().unwrap().remove(id);
+        let Some(mut cleanup) = cleanup else {
+            return true;
+        };
+        if tokio::time::timeout_at(deadline, &mut cleanup)
+            .await
+            .is_ok()
+        {
+            return true;
+        }
+        self.abandoned
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), cleanup);
+        false
+    }
+
+    /// The sandbox created with the sandbox API that the container of `spec` belongs to
+    async fn sandbox_of(&self, spec: &Spec) -> Option<Arc<SandboxData>> {
+        let annotations = spec.annotations().as_ref()?;
+        let sandbox_id = annotations.get("io.kubernetes.cri.sandbox-id")?;
+        self.sandboxes.read().await.get(sandbox_id).cloned()
+    }
+
+    /// Grace period before exiting on Shutdown, from the shim options
+    fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout.lock().unwrap().unwrap_or_default()
+    }
+
+    /// Serve the metrics of the shim on `address`, with the latest metrics of the engines
+    async fn serve_metrics(&self, address: &MetricsAddress) {
+        let instances = self.instances.clone();
+        let render = move || {
+            let instances = instances.clone();
+            async move {
+                let instances: Vec<_> = instances
+                    .read()
+                    .await
+                    .iter()
+                    .map(|(id, i)| (id.clone(), i.clone()))
+                    .collect();
+                let mut engines = vec![];
+                for (id, i) in &instances {
+                    if let Some(engine) = i.instance().engine_metrics().await {
+                        engines.push((id.clone(), engine));
+                    }
+                }
+                ShimMetrics::global().render(instances.len(), &engines)
+            }
+        };
+        // the containers run without the metrics rather than fail
+        if let Err(err) = metrics::serve(address, render).await {
+            log::warn!("failed to serve the shim metrics on {address:?}: {err}");
+        }
     }
 }
 
@@ -138,52 +391,103 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
 impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     async fn task_create(&self, req: CreateTaskRequest) -> Result<CreateTaskResponse> {
-        let config = Config::get_from_options(req.options.as_ref())
-            .map_err(|err| Error::InvalidArgument(format!("invalid shim options: {err}")))?;
+        let config = Config::get_from_options(req.options.as_ref())?;
+        if let Some(format) = config.log_format {
+            logging::set_format(format);
+        }
+        if let Some(level) = &config.log_level {
+            let level = logging::parse_level(level)
+                .map_err(|err| Error::InvalidArgument(format!("invalid shim options: {err}")))?;
+            logging::set_options_level(level);
+        }
+        if let Some(address) = &config.metrics_address {
+            let address = address
+                .parse()
+                .map_err(|err| Error::InvalidArgument(format!("invalid shim options: {err}")))?;
+            self.serve_metrics(&address).await;
+        }
 
         if !req.checkpoint().is_empty() || !req.parent_checkpoint().is_empty() {
             return Err(ShimError::Unimplemented("checkpoint is not supported".to_string()).into());
         }
 
-        if req.terminal {
-            return Err(Error::InvalidArgument(
-                "terminal is not supported".to_string(),
-            ));
+        // a retry of containerd only starts once what a former create left is cleaned up
+        let deadline = Instant::now() + config.create_timeout();
+        if !self.wait_abandoned(req.id(), deadline).await {
+            return Err(Error::DeadlineExceeded(format!(
+                "a former create of container {} is still running",
+                req.id()
+            )));
         }
 
         if self.has_instance(&req.id).await {
+            return self.attach_instance(req).await;
+        }
+
+        self.create_instance(req, config, deadline).await
+    }
+
+    /// A create of a running container with its bundle and stdio opens its FIFOs again,
+    /// e.g., once containerd restarted and created them again, and any other is a conflict
+    async fn attach_instance(&self, req: CreateTaskRequest) -> Result<CreateTaskResponse> {
+        let i = self.get_instance(req.id()).await?;
+        let running = i.pid().is_some() && i.try_wait().is_none();
+        let config = i.config();
+        let same = config.bundle == Path::new(req.bundle())
+            && config.stdin == Path::new(req.stdin())
+            && config.stdout == Path::new(req.stdout())
+            && config.stderr == Path::new(req.stderr());
+        if !running || !same {
             return Err(Error::AlreadyExists(req.id));
         }
+        i.attach_stdio(
+            Path::new(req.stdin()),
+            Path::new(req.stdout()),
+            Path::new(req.stderr()),
+        )
+        .await?;
+        debug!("attached the stdio of {}", req.id());
+        Ok(CreateTaskResponse {
+            pid: std::process::id(),
+            ..Default::default()
+        })
+    }
 
+    /// The creation of the instance of `req`, once its options are applied
+    async fn create_instance(
+        &self,
+        req: CreateTaskRequest,
+        config: Config,
+        deadline: Instant,
+    ) -> Result<CreateTaskResponse> {
+        let shutdown_timeout = config.shutdown_timeout;
         let mut spec = Spec::load(Path::new(&req.bundle).join("config.json"))
-            .map_err(|err| Error::InvalidArgument(format!("could not load runtime spec: {err}")))?;
+            .map_err(|err| Error::InvalidSpec(format!("could not load runtime spec: {err}")))?;
+        logging::set_annotation_level(spec.annotations().as_ref());
+        let restart_policy = RestartPolicy::from_annotations(spec.annotations().as_ref())
+            .map_err(|err| Error::InvalidSpec(format!("{err:#}")))?;
 
-        spec.canonicalize_rootfs(req.bundle()).map_err(|err| {
-            ShimError::InvalidArgument(format!("could not canonicalize rootfs: {}", err))
-        })?;
+        let spec_terminal = spec
+            .process()
+            .as_ref()
+            .and_then(|process| process.terminal())
+            .unwrap_or_default();
+        if req.terminal != spec_terminal {
+            return Err(Error::InvalidArgument(format!(
+                "terminal is {}, while the runtime spec has terminal {spec_terminal}",
+                req.terminal
+            )));
+        }
+
+        spec.canonicalize_rootfs(req.bundle())
+            .map_err(|err| Error::InvalidSpec(format!("could not canonicalize rootfs: {}", err)))?;
 
         let rootfs = spec
             .root()
             .as_ref()
-            .ok_or_else(|| Error::InvalidArgument("rootfs is not set in runtime spec".to_string()))?
-            .path();
-
-        let _ = create_dir_all(rootfs);
-        let rootfs_mounts = req.rootfs().to_vec();
-        if !rootfs_mounts.is_empty() {
-            for m in rootfs_mounts {
-                let mount_type = m.type_().none_if(|&x| x.is_empty());
-                let source = m.source.as_str().none_if(|&x| x.is_empty());
-
-                #[cfg(unix)]
-                containerd_shim::mount::mount_rootfs(
-                    mount_type,
-                    source,
-                    &m.options.to_vec(),
-                    rootfs,
-                )?;
-            }
-        }
+            .ok_or_else(|| Error::InvalidSpec("rootfs is not set in runtime spec".to_string()))?
+            .path()
+            .to_path_buf();
 
         let cfg = InstanceConfig {
             namespace: self.namespace.clone(),
@@ -192,19 +496,71 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             stdout: req.stdout.as_str().into(),
             stderr: req.stderr.as_str().into(),
             stdin: req.stdin.as_str().into(),
+            terminal: req.terminal,
             config,
         };
 
+        // with the sandbox API, the containers of the pod are tasks routed to its sandbox
+        let sandbox = self.sandbox_of(&spec).await;
+        if let Some(sandbox) = &sandbox {
+            sandbox.add_container(req.id())?;
+        }
+
         // Check if this is a cri container
-        let instance = InstanceData::new(req.id(), cfg).await?;
+        let hooks = LifecycleHooks::new(req.id(), req.bundle(), &spec);
+
+        // a hung mount or engine must not leave a half-created container behind, that the
+        // retries of containerd would find, so the build goes on in its own task past the
+        // deadline and what it leaves is deleted once it ends
+        let build = build_instance(
+            req.id.clone(),
+            cfg,
+            rootfs,
+            req.rootfs().to_vec(),
+            hooks,
+            restart_policy,
+        );
+        #[cfg(feature = "tracing")]
+        let build = tracing::Instrument::in_current_span(build);
+        let mut build = build.spawn();
+        let res = match tokio::time::timeout_at(deadline, &mut build).await {
+            Ok(res) => res.unwrap_or_else(|err| {
+                Err(Error::Others(format!(
+                    "failed to create the container: {err}"
+                )))
+            }),
+            Err(_) => {
+                self.abandon_build(req.id(), Path::new(req.bundle()), build, sandbox);
+                return Err(Error::DeadlineExceeded(format!(
+                    "create of container {} did not finish in time",
+                    req.id()
+                )));
+            }
+        };
+        let instance = match res {
+            Ok(instance) => instance,
+            Err(err) => {
+                if let Some(sandbox) = &sandbox {
+                    sandbox.remove_container(req.id());
+                }
+                return Err(err);
+            }
+        };
 
         self.instances
             .write()
             .await
             .insert(req.id().to_string(), Arc::new(instance));
+        // counted once the instance is inserted, so that a pending shutdown
+        // either sees the instance or the count change
+        self.created.fetch_add(1, Ordering::SeqCst);
+        if let Some(timeout) = shutdown_timeout {
+            *self.shutdown_timeout.lock().unwrap() = Some(Duration::from_secs(timeout));
+        }
 
         self.events.send(TaskCreate {
             container_id: req.id,
+            pid: std::process::id(),
             bundle: req.bundle,
             rootfs: req.rootfs,
             io: Some(TaskIO {
@@ -219,24 +575,108 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
 
         debug!("create done");
 
-        // Per the spec, the prestart hook must be called as part of the create operation
-        debug!("call prehook before the start");
-        oci::setup_prestart_hooks(spec.hooks())?;
-
         Ok(CreateTaskResponse {
             pid: std::process::id(),
             ..Default::default()
         })
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_exec(&self, req: ExecProcessRequest) -> Result<Empty> {
+        if req.exec_id().is_empty() {
+            return Err(Error::InvalidArgument("exec id is not set".to_string()));
+        }
+
+        if req.terminal {
+            return Err(Error::InvalidArgument(
+                "terminal is not supported".to_string(),
+            ));
+        }
+
+        let spec = req
+            .spec
+            .as_ref()
+            .ok_or_else(|| Error::InvalidArgument("exec process spec is not set".to_string()))?;
+        let process: Process = serde_json::from_slice(&spec.value).map_err(|err| {
+            Error::InvalidSpec(format!("could not load exec process spec: {err}"))
+        })?;
+
+        let cfg = ExecConfig {
+            stdin: req.stdin.as_str().into(),
+            stdout: req.stdout.as_str().into(),
+            stderr: req.stderr.as_str().into(),
+            process,
+        };
+
+        let i = self.get_instance(req.id()).await?;
+        i.add_exec(req.exec_id(), cfg).await?;
+
+        self.events.send(TaskExecAdded {
+            container_id: req.id,
+            exec_id: req.exec_id,
+            ..Default::default()
+        });
+
+        Ok(Empty::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_start_exec(&self, req: StartRequest) -> Result<StartResponse> {
+        let i = self.get_instance(req.id()).await?;
+        let (exec, pid) = i.start_exec(req.exec_id()).await?;
+
+        self.events.send(TaskExecStarted {
+            container_id: req.id().into(),
+            exec_id: req.exec_id().into(),
+            pid,
+            ..Default::default()
+        });
+
+        let events = self.events.clone();
+
+        let container_id = req.id().to_string();
+        let id = req.exec_id().to_string();
+
+        async move {
+            let (exit_code, timestamp) = i.reap_exec(&id, &exec).await;
+            events.send(TaskExit {
+                container_id,
+                exit_status: exit_code,
+                exited_at: Some(timestamp.to_timestamp()).into(),
+                pid,
+                id,
+                ..Default::default()
+            });
+        }
+        .spawn();
+
+        debug!("exec started: {:?}", req);
+
+        Ok(StartResponse {
+            pid,
+            ..Default::default()
+        })
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     async fn task_start(&self, req: StartRequest) -> Result<StartResponse> {
-        if req.exec_id().is_empty().not() {
-            return Err(ShimError::Unimplemented("exec is not supported".to_string()).into());
+        if !req.exec_id().is_empty() {
+            return self.task_start_exec(req).await;
         }
 
         let i = self.get_instance(req.id()).await?;
-        let pid = i.start().await?;
+        let timeout = i.config().config.start_timeout();
+        let pid = match tokio::time::timeout(timeout, i.start()).await {
+            Ok(pid) => pid?,
+            Err(_) => {
+                self.abandon(req.id(), &i.config().bundle).await;
+                return Err(Error::DeadlineExceeded(format!(
+                    "start of container {} did not finish within {timeout:?}",
+                    req.id()
+                )));
+            }
+        };
+        i.hooks.run_poststart(pid);
 
         self.events.send(TaskStart {
             container_id: req.id().into(),
@@ -249,7 +689,50 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
         let id = req.id().to_string();
 
         async move {
-            let (exit_code, timestamp) = i.wait().await;
+            // make sure that delete doesn't wait for this event forever (even if there's a panic)
+            let _guard = i.exit_published.set_guard_with(|| ());
+
+            // report the OOM kills of the instance until it exits, e.g., for kubelet to show `OOMKilled`
+            let oom = async {
+                let mut restarts = i.subscribe_restarts();
+                loop {
+                    while i.instance().wait_oom().await.is_ok() {
+                        events.send(TaskOOM {
+                            container_id: id.clone(),
+                            ..Default::default()
+                        });
+                    }
+                    // the instance exited, and the next one is watched once it's restarted
+                    let Some(restarts) = &mut restarts else {
+                        break;
+                    };
+                    if restarts.changed().await.is_err() {
+                        break;
+                    }
+                }
+                std::future::pending().await
+            };
+            // each restart by the restart policy is published as a start of the task
+            let restarted = |pid| {
+                events.send(TaskStart {
+                    container_id: id.clone(),
+                    pid,
+                    ..Default::default()
+                })
+            };
+            let (exit_code, timestamp) = tokio::select! {
+                exit = i.supervise(restarted) => exit,
+                never = oom => never,
+            };
+            let pid = i.pid().unwrap_or(pid);
+            // the kill that made the instance exit, if it's noticed after the exit
+            if let Some(Ok(())) = i.instance().wait_oom().now_or_never() {
+                events.send(TaskOOM {
+                    container_id: id.clone(),
+                    ..Default::default()
+                });
+            }
+
             events.send(TaskExit {
                 container_id: id.clone(),
                 exit_status: exit_code,
@@ -271,31 +754,117 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     async fn task_kill(&self, req: KillRequest) -> Result<Empty> {
+        let i = self.get_instance(req.id()).await?;
+        if req.exec_id().is_empty() {
+            i.kill(req.signal()).await?;
+        } else {
+            i.kill_exec(req.exec_id(), req.signal()).await?;
+        }
+        Ok(Empty::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_close_io(&self, req: CloseIORequest) -> Result<Empty> {
+        if !req.exec_id().is_empty() {
+            return Err(Error::InvalidArgument(
+                "closing the io of an exec is not supported".to_string(),
+            ));
+        }
+
+        let i = self.get_instance(req.id()).await?;
+        if req.stdin {
+            i.instance().close_stdin().await?;
+        }
+
+        Ok(Empty::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_resize_pty(&self, req: ResizePtyRequest) -> Result<Empty> {
         if !req.exec_id().is_empty() {
-            return Err(Error::InvalidArgument("exec is not supported".to_string()));
+            return Err(Error::InvalidArgument(
+                "terminal is not supported for exec".to_string(),
+            ));
         }
+
+        self.get_instance(req.id())
+            .await?
+            .instance()
+            .resize_pty(req.width, req.height)
+            .await?;
+
+        Ok(Empty::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_update(&self, req: UpdateTaskRequest) -> Result<Empty> {
+        let resources = req
+            .resources
+            .as_ref()
+            .ok_or_else(|| Error::InvalidArgument("resources are not set".to_string()))?;
+        let resources: LinuxResources = serde_json::from_slice(&resources.value)
+            .map_err(|err| Error::InvalidArgument(format!("could not load resources: {err}")))?;
+
         self.get_instance(req.id())
             .await?
-            .kill(req.signal())
+            .update(&resources)
             .await?;
+
+        Ok(Empty::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_pause(&self, req: PauseRequest) -> Result<Empty> {
+        self.get_instance(req.id()).await?.pause().await?;
+
+        self.events.send(TaskPaused {
+            container_id: req.id,
+            ..Default::default()
+        });
+
+        Ok(Empty::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_resume(&self, req: ResumeRequest) -> Result<Empty> {
+        self.get_instance(req.id()).await?.resume().await?;
+
+        self.events.send(TaskResumed {
+            container_id: req.id,
+            ..Default::default()
+        });
+
         Ok(Empty::new())
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     async fn task_delete(&self, req: DeleteRequest) -> Result<DeleteResponse> {
+        let i = self.get_instance(req.id()).await?;
+
         if !req.exec_id().is_empty() {
-            return Err(Error::InvalidArgument("exec is not supported".to_string()));
+            let exec = i.delete_exec(req.exec_id()).await?;
+            let (exit_code, timestamp) = exec.try_wait().unzip();
+            return Ok(DeleteResponse {
+                pid: exec.pid().unwrap_or_default(),
+                exit_status: exit_code.unwrap_or_default(),
+                exited_at: timestamp.map(ToTimestamp::to_timestamp).into(),
+                ..Default::default()
+            });
         }
 
-        let i = self.get_instance(req.id()).await?;
-
         i.delete().await?;
 
+        // the instance exited, but its exit event might not be published yet
+        if i.pid().is_some() {
+            i.exit_published.wait().await;
+        }
+        i.hooks.run_poststop(i.pid());
+
         let pid = i.pid().unwrap_or_default();
-        let (exit_code, timestamp) = i.wait().now_or_never().unzip();
+        let (exit_code, timestamp) = i.try_wait().unzip();
         let timestamp = timestamp.map(ToTimestamp::to_timestamp);
 
-        self.instances.write().await.remove(req.id());
+        self.remove_instance(req.id()).await;
 
         self.events.send(TaskDelete {
             container_id: req.id().into(),
@@ -315,12 +884,12 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     async fn task_wait(&self, req: WaitRequest) -> Result<WaitResponse> {
-        if !req.exec_id().is_empty() {
-            return Err(Error::InvalidArgument("exec is not supported".to_string()));
-        }
-
         let i = self.get_instance(req.id()).await?;
-        let (exit_code, timestamp) = i.wait().await;
+        let (exit_code, timestamp) = if req.exec_id().is_empty() {
+            i.wait().await
+        } else {
+            i.get_exec(req.exec_id()).await?.wait().await
+        };
 
         debug!("wait finishes");
         Ok(WaitResponse {
@@ -332,28 +901,41 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
     async fn task_state(&self, req: StateRequest) -> Result<StateResponse> {
+        let i = self.get_instance(req.id()).await?;
+
         if !req.exec_id().is_empty() {
-            return Err(Error::InvalidArgument("exec is not supported".to_string()));
+            let exec = i.get_exec(req.exec_id()).await?;
+            let pid = exec.pid();
+            let (exit_code, timestamp) = exec.try_wait().unzip();
+            return Ok(StateResponse {
+                bundle: i.config().bundle.to_string_lossy().to_string(),
+                stdin: exec.config.stdin.to_string_lossy().to_string(),
+                stdout: exec.config.stdout.to_string_lossy().to_string(),
+                stderr: exec.config.stderr.to_string_lossy().to_string(),
+                pid: pid.unwrap_or_default(),
+                exit_status: exit_code.unwrap_or_default(),
+                exited_at: timestamp.map(ToTimestamp::to_timestamp).into(),
+                status: status(pid, exit_code).into(),
+                exec_id: req.exec_id,
+                ..Default::default()
+            });
         }
 
-        let i = self.get_instance(req.id()).await?;
         let pid = i.pid();
-        let (exit_code, timestamp) = i.wait().now_or_never().unzip();
+        let (exit_code, timestamp) = i.try_wait().unzip();
         let timestamp = timestamp.map(ToTimestamp::to_timestamp);
 
-        let status = if pid.is_none() {
-            Status::CREATED
-        } else if exit_code.is_none() {
-            Status::RUNNING
-        } else {
-            Status::STOPPED
+        let status = match status(pid, exit_code) {
+            Status::RUNNING if i.is_paused().await => Status::PAUSED,
+            status => status,
         };
 
+        let config = i.config();
         Ok(StateResponse {
-            bundle: i.config.bundle.to_string_lossy().to_string(),
-            stdin: i.config.stdin.to_string_lossy().to_string(),
-            stdout: i.config.stdout.to_string_lossy().to_string(),
-            stderr: i.config.stderr.to_string_lossy().to_string(),
+            bundle: config.bundle.to_string_lossy().to_string(),
+            stdin: config.stdin.to_string_lossy().to_string(),
+            stdout: config.stdout.to_string_lossy().to_string(),
+            stderr: config.stderr.to_string_lossy().to_string(),
             pid: pid.unwrap_or_default(),
             exit_status: exit_code.unwrap_or_default(),
             exited_at: timestamp.into(),
@@ -369,13 +951,129 @@ impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
             .pid()
             .ok_or_else(|| Error::InvalidArgument("task is not running".to_string()))?;
 
-        let metrics = get_metrics(pid)?;
+        let mut metrics = get_metrics(pid)?;
+        // without engine metrics, the payload only has the cgroup metrics
+        if let Some(engine) = i.instance().engine_metrics().await {
+            engine.append_to(&mut metrics.value);
+        }
+        if let Some(restarts) = i.restart_count() {
+            append_restarts_to(restarts, &mut metrics.value);
+        }
 
         Ok(StatsResponse {
             stats: Some(metrics).into(),
             ..Default::default()
         })
     }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn task_shutdown(&self, req: ShutdownRequest) {
+        // read before checking for instances, see `task_create`
+        let count = self.created.load(Ordering::SeqCst);
+        if !self.is_empty().await {
+            return;
+        }
+
+        let timeout = self.shutdown_timeout();
+        if req.now || timeout.is_zero() {
+            let _ = self.exit.set(());
+            return;
+        }
+
+        debug!("shutting down in {timeout:?}, unless a container is created");
+        let created = self.created.clone();
+        let exit = self.exit.clone();
+        async move {
+            tokio::time::sleep(timeout).await;
+            // cancelled if a container was created in the meantime
+            if created.load(Ordering::SeqCst) == count {
+                let _ = exit.set(());
+            }
+        }
+        .spawn();
+    }
+}
+
+/// Mount the `rootfs` of the container `id` and create its instance, up to its create hooks
+async fn build_instance<T: Instance + Send + Sync>(
+    id: String,
+    cfg: InstanceConfig,
+    rootfs: PathBuf,
+    rootfs_mounts: Vec<Mount>,
+    hooks: LifecycleHooks,
+    restart_policy: Option<RestartPolicy>,
+) -> Result<InstanceData<T>> {
+    let _ = create_dir_all(&rootfs);
+    if !rootfs_mounts.is_empty() {
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!("mount rootfs", mounts = rootfs_mounts.len());
+
+        // on a blocking thread, so that a hung mount doesn't hold a worker of the runtime
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            #[cfg(feature = "tracing")]
+            let _span = span.entered();
+
+            for m in rootfs_mounts {
+                let mount_type = m.type_().none_if(|&x| x.is_empty());
+                let source = m.source.as_str().none_if(|&x| x.is_empty());
+
+                #[cfg(unix)]
+                containerd_shim::mount::mount_rootfs(
+                    mount_type,
+                    source,
+                    &m.options.to_vec(),
+                    &rootfs,
+                )?;
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|err| Error::Internal(format!("failed to mount the rootfs: {err}")))??;
+    }
+
+    let instance = InstanceData::new(&id, cfg, hooks, restart_policy).await?;
+
+    // Per the spec, the createRuntime and createContainer hooks must be called as part of
+    // the create operation, and a failing hook fails it
+    debug!("call the create hooks before the start");
+    if let Err(err) = instance.hooks.run_create(std::process::id()) {
+        let _ = instance.delete().await;
+        return Err(Error::Others(format!("{err:#}")));
+    }
+    Ok(instance)
+}
+
+/// Kill and delete the instance of the abandoned container `id`
+async fn discard<T: Instance + Send + Sync>(id: &str, instance: &InstanceData<T>) {
+    let _ = instance.instance().kill(9).await;
+    if let Err(err) = instance.instance().delete().await {
+        log::warn!("failed to delete container {id}: {err}");
+    }
+}
+
+/// Unmount the rootfs mounted by the create of the container `id`, which containerd
+/// mounts at `<bundle>/rootfs`
+fn unmount_rootfs(id: &str, bundle: &Path) {
+    #[cfg(unix)]
+    {
+        use nix::mount::{MntFlags, umount2};
+        match umount2(&bundle.join("rootfs"), MntFlags::MNT_DETACH) {
+            Ok(()) | Err(nix::errno::Errno::EINVAL | nix::errno::Errno::ENOENT) => {}
+            Err(err) => log::warn!("failed to unmount the rootfs of container {id}: {err}"),
+        }
+    }
+    #[cfg(windows)]
+    let _ = (id, bundle);
+}
+
+fn status(pid: Option<u32>, exit_code: Option<u32>) -> Status {
+    if pid.is_none() {
+        Status::CREATED
+    } else if exit_code.is_none() {
+        Status::RUNNING
+    } else {
+        Status::STOPPED
+    }
 }
 
 impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
@@ -390,7 +1088,9 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         #[cfg(feature = "opentelemetry")]
         tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
 
-        Ok(self.task_create(req).block_on()?)
+        Ok(LogContext::new(req.id(), "")
+            .scope(metrics::observe("create", self.task_create(req)))
+            .block_on()?)
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
@@ -400,7 +1100,21 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         #[cfg(feature = "opentelemetry")]
         tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
 
-        Ok(self.task_start(req).block_on()?)
+        Ok(LogContext::new(req.id(), req.exec_id())
+            .scope(metrics::observe("start", self.task_start(req)))
+            .block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn exec(&self, _ctx: &TtrpcContext, req: ExecProcessRequest) -> TtrpcResult<Empty> {
+        debug!("exec: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(LogContext::new(req.id(), req.exec_id())
+            .scope(metrics::observe("exec", self.task_exec(req)))
+            .block_on()?)
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
@@ -410,7 +1124,69 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         #[cfg(feature = "opentelemetry")]
         tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
 
-        Ok(self.task_kill(req).block_on()?)
+        Ok(LogContext::new(req.id(), req.exec_id())
+            .scope(metrics::observe("kill", self.task_kill(req)))
+            .block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn close_io(&self, _ctx: &TtrpcContext, req: CloseIORequest) -> TtrpcResult<Empty> {
+        debug!("close_io: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(LogContext::new(req.id(), req.exec_id())
+            .scope(metrics::observe("close_io", self.task_close_io(req)))
+            .block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn resize_pty(&self, _ctx: &TtrpcContext, req: ResizePtyRequest) -> TtrpcResult<Empty> {
+        debug!("resize_pty: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(LogContext::new(req.id(), req.exec_id())
+            .scope(metrics::observe("resize_pty", self.task_resize_pty(req)))
+            .block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn update(&self, _ctx: &TtrpcContext, req: UpdateTaskRequest) -> TtrpcResult<Empty> {
+        debug!("update: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(LogContext::new(req.id(), "")
+            .scope(metrics::observe("update", self.task_update(req)))
+            .block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn pause(&self, _ctx: &TtrpcContext, req: PauseRequest) -> TtrpcResult<Empty> {
+        debug!("pause: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(LogContext::new(req.id(), "")
+            .scope(metrics::observe("pause", self.task_pause(req)))
+            .block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn resume(&self, _ctx: &TtrpcContext, req: ResumeRequest) -> TtrpcResult<Empty> {
+        debug!("resume: {:?}", req);
+
+        #[cfg(feature = "opentelemetry")]
+        tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
+
+        Ok(LogContext::new(req.id(), "")
+            .scope(metrics::observe("resume", self.task_resume(req)))
+            .block_on()?)
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
@@ -420,7 +1196,9 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         #[cfg(feature = "opentelemetry")]
         tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
 
-        Ok(self.task_delete(req).block_on()?)
+        Ok(LogContext::new(req.id(), req.exec_id())
+            .scope(metrics::observe("delete", self.task_delete(req)))
+            .block_on()?)
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
@@ -455,7 +1233,7 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         let res = async {
             tokio::select! {
                 _ = span_exporter => unreachable!(),
-                res = self.task_wait(req) => res,
+                res = LogContext::new(req.id(), req.exec_id()).scope(metrics::observe("wait", self.task_wait(req))) => res,
             }
         }
         .block_on()?;
@@ -487,19 +1265,21 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         #[cfg(feature = "opentelemetry")]
         tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
 
-        Ok(self.task_state(req).block_on()?)
+        Ok(LogContext::new(req.id(), req.exec_id())
+            .scope(metrics::observe("state", self.task_state(req)))
+            .block_on()?)
     }
 
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
-    fn shutdown(&self, _ctx: &TtrpcContext, _: ShutdownRequest) -> TtrpcResult<Empty> {
-        debug!("shutdown");
+    fn shutdown(&self, _ctx: &TtrpcContext, req: ShutdownRequest) -> TtrpcResult<Empty> {
+        debug!("shutdown: {:?}", req);
 
         #[cfg(feature = "opentelemetry")]
         tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
 
-        if self.is_empty().block_on() {
-            let _ = self.exit.set(());
-        }
+        LogContext::new(req.id(), "")
+            .scope(self.task_shutdown(req))
+            .block_on();
         Ok(Empty::new())
     }
 
@@ -510,6 +1290,8 @@ impl<T: Instance + Sync + Send, E: EventSender> Task for Local<T, E> {
         #[cfg(feature = "opentelemetry")]
         tracing::Span::current().set_parent(extract_context(&_ctx.metadata));
 
-        Ok(self.task_stats(req).block_on()?)
+        Ok(LogContext::new(req.id(), "")
+            .scope(metrics::observe("stats", self.task_stats(req)))
+            .block_on()?)
     }
 }
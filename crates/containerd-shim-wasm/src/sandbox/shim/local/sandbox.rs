@@ -0,0 +1,370 @@
+//! The sandbox API of containerd 2.x, with which the shim manages the pod sandbox itself,
+//! instead of running a pause container.
+//!
+//! The sandbox has no process of its own, it lives in the shim. The containers of the pod
+//! are tasks of the same shim, routed to their sandbox with the `io.kubernetes.cri.sandbox-id`
+//! annotation of their spec.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use containerd_shim::api::ShutdownRequest;
+use containerd_shim::{TtrpcContext, TtrpcResult};
+use containerd_shim_protos::sandbox::sandbox::{
+    CreateSandboxRequest, CreateSandboxResponse, PingRequest, PingResponse, PlatformRequest,
+    PlatformResponse, SandboxStatusRequest, SandboxStatusResponse, ShutdownSandboxRequest,
+    ShutdownSandboxResponse, StartSandboxRequest, StartSandboxResponse, StopSandboxRequest,
+    StopSandboxResponse, WaitSandboxRequest, WaitSandboxResponse,
+};
+use containerd_shim_protos::sandbox::sandbox_ttrpc::Sandbox;
+use containerd_shim_protos::types::platform::Platform;
+use log::debug;
+
+use super::Local;
+use crate::sandbox::async_utils::AmbientRuntime as _;
+use crate::sandbox::shim::events::{EventSender, ToTimestamp};
+use crate::sandbox::shim::sandbox_data::SandboxData;
+use crate::sandbox::{Error, Instance, Result};
+
+const SANDBOX_READY: &str = "SANDBOX_READY";
+const SANDBOX_NOTREADY: &str = "SANDBOX_NOTREADY";
+
+// These are the same functions as in Sandbox, but without the TtrcpContext, which is useful for testing
+impl<T: Instance + Send + Sync, E: EventSender> Local<T, E> {
+    async fn get_sandbox(&self, id: &str) -> Result<Arc<SandboxData>> {
+        let sandbox = self.sandboxes.read().await.get(id).cloned();
+        sandbox.ok_or_else(|| Error::NotFound(id.to_string()))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn sandbox_create(&self, req: CreateSandboxRequest) -> Result<CreateSandboxResponse> {
+        let mut sandboxes = self.sandboxes.write().await;
+        if sandboxes.contains_key(req.sandbox_id()) {
+            return Err(Error::AlreadyExists(req.sandbox_id));
+        }
+        let sandbox = SandboxData::new(req.bundle_path());
+        sandboxes.insert(req.sandbox_id, Arc::new(sandbox));
+        // counted like a container, to cancel a pending shutdown
+        self.created.fetch_add(1, Ordering::SeqCst);
+        Ok(CreateSandboxResponse::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn sandbox_start(&self, req: StartSandboxRequest) -> Result<StartSandboxResponse> {
+        let started_at = self.get_sandbox(req.sandbox_id()).await?.start()?;
+        Ok(StartSandboxResponse {
+            pid: std::process::id(),
+            created_at: Some(started_at.to_timestamp()).into(),
+            ..Default::default()
+        })
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn sandbox_platform(&self, req: PlatformRequest) -> Result<PlatformResponse> {
+        self.get_sandbox(req.sandbox_id()).await?;
+        let platform = Platform {
+            os: std::env::consts::OS.to_string(),
+            architecture: go_arch(std::env::consts::ARCH).to_string(),
+            ..Default::default()
+        };
+        Ok(PlatformResponse {
+            platform: Some(platform).into(),
+            ..Default::default()
+        })
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn sandbox_stop(&self, req: StopSandboxRequest) -> Result<StopSandboxResponse> {
+        let sandbox = self.get_sandbox(req.sandbox_id()).await?;
+        let timeout = match req.timeout_secs() {
+            0 => None,
+            secs => Some(Duration::from_secs(secs.into())),
+        };
+
+        // the containers are usually stopped by now, the remaining ones are killed
+        for id in sandbox.containers() {
+            let Ok(instance) = self.get_instance(&id).await else {
+                continue;
+            };
+            if instance.pid().is_none() {
+                continue;
+            }
+            if let Err(err) = instance.kill(9).await {
+                debug!("failed to kill container {id} of the sandbox: {err}");
+            }
+            match timeout {
+                Some(timeout) => {
+                    if instance.wait_timeout(timeout).await.is_none() {
+                        return Err(Error::Others(format!(
+                            "container {id} of the sandbox didn't exit within {timeout:?}"
+                        )));
+                    }
+                }
+                None => {
+                    instance.wait().await;
+                }
+            }
+        }
+
+        sandbox.stop();
+        Ok(StopSandboxResponse::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn sandbox_wait(&self, req: WaitSandboxRequest) -> Result<WaitSandboxResponse> {
+        let sandbox = self.get_sandbox(req.sandbox_id()).await?;
+        let (exit_status, exited_at) = sandbox.wait().await;
+        Ok(WaitSandboxResponse {
+            exit_status,
+            exited_at: Some(exited_at.to_timestamp()).into(),
+            ..Default::default()
+        })
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn sandbox_state(&self, req: SandboxStatusRequest) -> Result<SandboxStatusResponse> {
+        let sandbox = self.get_sandbox(req.sandbox_id()).await?;
+        let state = if sandbox.is_ready() {
+            SANDBOX_READY
+        } else {
+            SANDBOX_NOTREADY
+        };
+        let info = if req.verbose() {
+            HashMap::from([
+                ("bundle".to_string(), sandbox.bundle.display().to_string()),
+                ("containers".to_string(), sandbox.containers().join(",")),
+            ])
+        } else {
+            HashMap::new()
+        };
+        Ok(SandboxStatusResponse {
+            sandbox_id: req.sandbox_id,
+            pid: std::process::id(),
+            state: state.to_string(),
+            info,
+            created_at: Some(sandbox.created_at.to_timestamp()).into(),
+            exited_at: sandbox.exited_at().map(ToTimestamp::to_timestamp).into(),
+            ..Default::default()
+        })
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn sandbox_ping(&self, req: PingRequest) -> Result<PingResponse> {
+        self.get_sandbox(req.sandbox_id()).await?;
+        Ok(PingResponse::new())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn sandbox_shutdown(
+        &self,
+        req: ShutdownSandboxRequest,
+    ) -> Result<ShutdownSandboxResponse> {
+        let sandbox = self.get_sandbox(req.sandbox_id()).await?;
+        if !sandbox.containers().is_empty() {
+            return Err(Error::FailedPrecondition(format!(
+                "sandbox {} still has containers",
+                req.sandbox_id()
+            )));
+        }
+        sandbox.stop();
+        self.sandboxes.write().await.remove(req.sandbox_id());
+
+        // the shim exits once it has no sandboxes and no containers
+        self.task_shutdown(ShutdownRequest::default()).await;
+        Ok(ShutdownSandboxResponse::new())
+    }
+}
+
+impl<T: Instance + Sync + Send, E: EventSender> Sandbox for Local<T, E> {
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn create_sandbox(
+        &self,
+        _ctx: &TtrpcContext,
+        req: CreateSandboxRequest,
+    ) -> TtrpcResult<CreateSandboxResponse> {
+        debug!("create_sandbox: {:?}", req);
+        Ok(self.sandbox_create(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn start_sandbox(
+        &self,
+        _ctx: &TtrpcContext,
+        req: StartSandboxRequest,
+    ) -> TtrpcResult<StartSandboxResponse> {
+        debug!("start_sandbox: {:?}", req);
+        Ok(self.sandbox_start(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn platform(&self, _ctx: &TtrpcContext, req: PlatformRequest) -> TtrpcResult<PlatformResponse> {
+        debug!("platform: {:?}", req);
+        Ok(self.sandbox_platform(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn stop_sandbox(
+        &self,
+        _ctx: &TtrpcContext,
+        req: StopSandboxRequest,
+    ) -> TtrpcResult<StopSandboxResponse> {
+        debug!("stop_sandbox: {:?}", req);
+        Ok(self.sandbox_stop(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn wait_sandbox(
+        &self,
+        _ctx: &TtrpcContext,
+        req: WaitSandboxRequest,
+    ) -> TtrpcResult<WaitSandboxResponse> {
+        debug!("wait_sandbox: {:?}", req);
+        Ok(self.sandbox_wait(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn sandbox_status(
+        &self,
+        _ctx: &TtrpcContext,
+        req: SandboxStatusRequest,
+    ) -> TtrpcResult<SandboxStatusResponse> {
+        debug!("sandbox_status: {:?}", req);
+        Ok(self.sandbox_state(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn ping_sandbox(&self, _ctx: &TtrpcContext, req: PingRequest) -> TtrpcResult<PingResponse> {
+        debug!("ping_sandbox: {:?}", req);
+        Ok(self.sandbox_ping(req).block_on()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    fn shutdown_sandbox(
+        &self,
+        _ctx: &TtrpcContext,
+        req: ShutdownSandboxRequest,
+    ) -> TtrpcResult<ShutdownSandboxResponse> {
+        debug!("shutdown_sandbox: {:?}", req);
+        Ok(self.sandbox_shutdown(req).block_on()?)
+    }
+}
+
+/// The name of an architecture in an OCI platform, from `std::env::consts::ARCH`
+fn go_arch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "powerpc64" => "ppc64le",
+        "loongarch64" => "loong64",
+        arch => arch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::unbounded_channel as channel;
+
+    use super::*;
+    use crate::sandbox::shim::local::tests::InstanceStub;
+    use crate::sandbox::sync::WaitableCell;
+
+    #[tokio::test]
+    async fn test_sandbox_lifecycle() -> anyhow::Result<()> {
+        let (tx, _rx) = channel();
+        let local = Local::<InstanceStub, _>::new(
+            tx,
+            WaitableCell::new(),
+            "test_namespace",
+            "/test/address",
+        );
+        let id = "test-sandbox-lifecycle".to_string();
+
+        local
+            .sandbox_create(CreateSandboxRequest {
+                sandbox_id: id.clone(),
+                bundle_path: "/run/bundle".to_string(),
+                ..Default::default()
+            })
+            .await?;
+        let res = local
+            .sandbox_create(CreateSandboxRequest {
+                sandbox_id: id.clone(),
+                ..Default::default()
+            })
+            .await;
+        assert!(matches!(res, Err(Error::AlreadyExists(_))));
+
+        let status = local
+            .sandbox_state(SandboxStatusRequest {
+                sandbox_id: id.clone(),
+                ..Default::default()
+            })
+            .await?;
+        assert_eq!(status.state, SANDBOX_NOTREADY);
+
+        let started = local
+            .sandbox_start(StartSandboxRequest {
+                sandbox_id: id.clone(),
+                ..Default::default()
+            })
+            .await?;
+        assert_eq!(started.pid, std::process::id());
+
+        let status = local
+            .sandbox_state(SandboxStatusRequest {
+                sandbox_id: id.clone(),
+                verbose: true,
+                ..Default::default()
+            })
+            .await?;
+        assert_eq!(status.state, SANDBOX_READY);
+        assert_eq!(status.info["bundle"], "/run/bundle");
+
+        local
+            .sandbox_ping(PingRequest {
+                sandbox_id: id.clone(),
+                ..Default::default()
+            })
+            .await?;
+
+        local
+            .sandbox_stop(StopSandboxRequest {
+                sandbox_id: id.clone(),
+                ..Default::default()
+            })
+            .await?;
+        let waited = local
+            .sandbox_wait(WaitSandboxRequest {
+                sandbox_id: id.clone(),
+                ..Default::default()
+            })
+            .await?;
+        assert_eq!(waited.exit_status, 0);
+
+        local
+            .sandbox_shutdown(ShutdownSandboxRequest {
+                sandbox_id: id.clone(),
+                ..Default::default()
+            })
+            .await?;
+        let res = local
+            .sandbox_ping(PingRequest {
+                sandbox_id: id,
+                ..Default::default()
+            })
+            .await;
+        assert!(matches!(res, Err(Error::NotFound(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_go_arch() {
+        assert_eq!(go_arch("x86_64"), "amd64");
+        assert_eq!(go_arch("aarch64"), "arm64");
+        assert_eq!(go_arch("riscv64"), "riscv64");
+    }
+}
@@ -1,10 +1,12 @@
 use std::fs::{File, create_dir};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
 use anyhow::Context;
 use chrono::{DateTime, Utc};
 use containerd_shim::api::Status;
 use containerd_shim::event::Event;
+use futures::FutureExt as _;
 use protobuf::{MessageDyn, SpecialFields};
 use serde_json as json;
 use tempfile::tempdir;
@@ -12,7 +14,9 @@ use tokio::sync::mpsc::{UnboundedSender as Sender, unbounded_channel as channel}
 use tokio_async_drop::tokio_async_drop;
 
 use super::*;
+use crate::sandbox::instance::EngineMetrics;
 use crate::sandbox::shim::events::EventSender;
+use crate::sandbox::socket_policy::SocketMode;
 use crate::sandbox::sync::WaitableCell;
 
 /// This is used for the tests and is a no-op instance implementation.
@@ -20,27 +24,90 @@ pub struct InstanceStub {
     /// Since we are faking the container, we need to keep track of the "exit" code/time
     /// We'll just mark it as exited when kill is called.
     exit_code: WaitableCell<(u32, DateTime<Utc>)>,
+    /// Like a frozen process, a paused stub can't be paused again or killed.
+    paused: AtomicBool,
+    /// Like a wedged engine, the start of a stub hangs with a `slow-start` file in its bundle.
+    slow_start: bool,
+    /// Like a failing module, a stub exits with 2 once started, with a `crash` file in its bundle.
+    crash: bool,
+    /// A deleted stub leaves a `deleted` file in its bundle.
+    bundle: PathBuf,
 }
 
+/// The stubs with this file in their bundle hang while they are created, until it's removed
+const SLOW_CREATE: &str = "slow-create";
+
+/// Like a container that its executor rejects, the stubs with this file in their bundle
+/// fail to be created
+const INVALID: &str = "invalid";
+
 impl Instance for InstanceStub {
-    async fn new(_id: String, _cfg: &InstanceConfig) -> Result<Self, Error> {
+    async fn new(_id: String, cfg: &InstanceConfig) -> Result<Self, Error> {
+        while cfg.bundle.join(SLOW_CREATE).exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        if cfg.bundle.join(INVALID).exists() {
+            return Err(Error::InvalidSpec(
+                "invalid container configuration: the entrypoint isn't exported".to_string(),
+            ));
+        }
         Ok(InstanceStub {
             exit_code: WaitableCell::new(),
+            paused: AtomicBool::new(false),
+            slow_start: cfg.bundle.join("slow-start").exists(),
+            crash: cfg.bundle.join("crash").exists(),
+            bundle: cfg.bundle.clone(),
         })
     }
     async fn start(&self) -> Result<u32, Error> {
+        if self.slow_start {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }
+        if self.crash {
+            let _ = self.exit_code.set((2, Utc::now()));
+        }
         Ok(std::process::id())
     }
     async fn kill(&self, _signal: u32) -> Result<(), Error> {
+        if self.paused.load(Ordering::SeqCst) {
+            return Err(Error::FailedPrecondition("stub is paused".to_string()));
+        }
         let _ = self.exit_code.set((1, Utc::now()));
         Ok(())
     }
+    async fn pause(&self) -> Result<(), Error> {
+        if self.paused.swap(true, Ordering::SeqCst) {
+            return Err(Error::FailedPrecondition("stub is paused".to_string()));
+        }
+        Ok(())
+    }
+    async fn resume(&self) -> Result<(), Error> {
+        if !self.paused.swap(false, Ordering::SeqCst) {
+            return Err(Error::FailedPrecondition("stub is not paused".to_string()));
+        }
+        Ok(())
+    }
     async fn delete(&self) -> Result<(), Error> {
+        let _ = File::create(self.bundle.join("deleted"));
         Ok(())
     }
     async fn wait(&self) -> (u32, DateTime<Utc>) {
         *self.exit_code.wait().await
     }
+    async fn attach_stdio(
+        &self,
+        _stdin: &std::path::Path,
+        _stdout: &std::path::Path,
+        _stderr: &std::path::Path,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn engine_metrics(&self) -> Option<EngineMetrics> {
+        Some(EngineMetrics {
+            linear_memory_bytes: Some(65536),
+            ..Default::default()
+        })
+    }
 }
 
 struct LocalWithDestructor<T: Instance + Send + Sync, E: EventSender> {
@@ -130,197 +197,615 @@ async fn test_delete_after_create() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// A spec with a shell script hook at each step of the lifecycle, appending
+/// its name and status to `log`. The createRuntime hook exits with `exit_code`.
+#[cfg(unix)]
+fn spec_with_hooks(dir: &std::path::Path, log: &std::path::Path, exit_code: i32) -> Result<Spec> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use oci_spec::runtime::{HookBuilder, HooksBuilder};
+
+    let hook = |name: &str, exit_code: i32| -> Result<_> {
+        let path = dir.join(name);
+        let script = format!(
+            "#!/bin/sh\nstatus=$(sed 's/.*\"status\":\"\\([a-z]*\\)\".*/\\1/')\necho \"{name} $status\" >> {}\nexit {exit_code}\n",
+            log.display()
+        );
+        std::fs::write(&path, script)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        Ok(HookBuilder::default()
+            .path(path)
+            .env(vec!["PATH=/usr/bin:/bin".to_string()])
+            .build()?)
+    };
+    let hooks = HooksBuilder::default()
+        .create_runtime(vec![hook("createRuntime", exit_code)?])
+        .create_container(vec![hook("createContainer", 0)?])
+        .poststart(vec![hook("poststart", 0)?])
+        .poststop(vec![hook("poststop", 0)?])
+        .build()?;
+    let mut spec = Spec::default();
+    spec.set_hooks(Some(hooks));
+    Ok(spec)
+}
+
 // Use a multi threaded runtime because LocalWithDestructor needs
 // it to run its async drop.
+#[cfg(unix)]
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-async fn test_cri_task() -> Result<()> {
-    // Currently the relationship between the "base" container and the "instances" are pretty weak.
-    // When a cri sandbox is specified we just assume it's the sandbox container and treat it as such by not actually running the code (which is going to be wasm).
-    let (etx, _erx) = channel();
-    let exit_signal = WaitableCell::new();
+async fn test_lifecycle_hooks() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let hooks = tempdir()?;
+    let log = hooks.path().join("hooks.log");
+    let id = "test-lifecycle-hooks";
+    create_bundle(dir.path(), Some(spec_with_hooks(hooks.path(), &log, 0)?))?;
+
+    let (tx, _rx) = channel();
     let local = Arc::new(Local::<InstanceStub, _>::new(
-        etx,
-        exit_signal,
+        tx,
+        WaitableCell::new(),
         "test_namespace",
         "/test/address",
     ));
-
     let mut _wrapped = LocalWithDestructor::new(local.clone());
 
-    let temp = tempdir().unwrap();
-    let dir = temp.path();
-    let sandbox_id = "test-cri-task".to_string();
-    create_bundle(dir, Some(with_cri_sandbox(None, sandbox_id.clone())))?;
-
     local
         .task_create(CreateTaskRequest {
-            id: "testbase".to_string(),
-            bundle: dir.to_str().unwrap().to_string(),
+            id: id.to_string(),
+            bundle: dir.path().to_str().unwrap().to_string(),
             ..Default::default()
         })
         .await?;
+    let recorded = std::fs::read_to_string(&log)?;
+    assert_eq!(
+        recorded,
+        "createRuntime creating\ncreateContainer creating\n"
+    );
 
-    let state = local
-        .task_state(StateRequest {
-            id: "testbase".to_string(),
+    local
+        .task_start(StartRequest {
+            id: id.to_string(),
             ..Default::default()
         })
         .await?;
-    assert_eq!(state.status(), Status::CREATED);
-
-    // make sure that the instance exists
-    let _i = local.get_instance("testbase").await?;
-
     local
-        .task_start(StartRequest {
-            id: "testbase".to_string(),
+        .task_kill(KillRequest {
+            id: id.to_string(),
+            signal: 9,
             ..Default::default()
         })
         .await?;
-
-    let state = local
-        .task_state(StateRequest {
-            id: "testbase".to_string(),
+    local
+        .task_delete(DeleteRequest {
+            id: id.to_string(),
             ..Default::default()
         })
         .await?;
-    assert_eq!(state.status(), Status::RUNNING);
 
-    let ll = local.clone();
-    let (base_tx, mut base_rx) = channel();
-    tokio::spawn(async move {
-        let resp = ll
-            .task_wait(WaitRequest {
-                id: "testbase".to_string(),
-                ..Default::default()
-            })
-            .await;
-        base_tx.send(resp).unwrap();
-    });
-    base_rx.try_recv().unwrap_err();
+    let recorded = std::fs::read_to_string(&log)?;
+    assert_eq!(
+        recorded,
+        "createRuntime creating\ncreateContainer creating\npoststart running\npoststop stopped\n"
+    );
+    Ok(())
+}
 
-    let temp2 = tempdir().unwrap();
-    let dir2 = temp2.path();
-    create_bundle(dir2, Some(with_cri_sandbox(None, sandbox_id)))?;
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_failing_create_hook() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let hooks = tempdir()?;
+    let log = hooks.path().join("hooks.log");
+    let id = "test-failing-create-hook";
+    create_bundle(dir.path(), Some(spec_with_hooks(hooks.path(), &log, 1)?))?;
 
-    local
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let res = local
         .task_create(CreateTaskRequest {
-            id: "testinstance".to_string(),
-            bundle: dir2.to_str().unwrap().to_string(),
+            id: id.to_string(),
+            bundle: dir.path().to_str().unwrap().to_string(),
             ..Default::default()
         })
-        .await?;
+        .await;
+    match res.unwrap_err() {
+        Error::Others(err) => assert!(err.contains("createRuntime hook"), "{err}"),
+        e => return Err(e.into()),
+    }
+    assert!(!local.has_instance(id).await);
+    assert_eq!(std::fs::read_to_string(&log)?, "createRuntime creating\n");
+    Ok(())
+}
 
-    let state = local
-        .task_state(StateRequest {
-            id: "testinstance".to_string(),
-            ..Default::default()
+/// The options of a create with deadlines of 1 second
+fn with_deadlines(req: CreateTaskRequest) -> CreateTaskRequest {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: "CreateTimeout = 1\nStartTimeout = 1\n".to_string(),
+    };
+    CreateTaskRequest {
+        options: Some(Any {
+            type_url: options.type_url.clone(),
+            value: options.encode_to_vec(),
+            special_fields: SpecialFields::default(),
         })
-        .await?;
-    assert_eq!(state.status(), Status::CREATED);
+        .into(),
+        ..req
+    }
+}
 
-    // make sure that the instance exists
-    let _i = local.get_instance("testinstance").await?;
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_create_deadline() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let id = "test-create-deadline";
+    create_bundle(dir.path(), None)?;
+    File::create(dir.path().join(SLOW_CREATE))?;
 
-    local
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let req = with_deadlines(CreateTaskRequest {
+        id: id.to_string(),
+        bundle: dir.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    });
+    match local.task_create(req.clone()).await.unwrap_err() {
+        Error::DeadlineExceeded(_) => {}
+        e => return Err(e.into()),
+    }
+    assert!(!local.has_instance(id).await);
+
+    // a retry doesn't race with the create that is still going on
+    match local.task_create(req.clone()).await.unwrap_err() {
+        Error::DeadlineExceeded(_) => {}
+        e => return Err(e.into()),
+    }
+
+    // the retry of containerd succeeds once the engine is back, and what the former
+    // create built is deleted before
+    std::fs::remove_file(dir.path().join(SLOW_CREATE))?;
+    local.task_create(req).await?;
+    assert!(local.has_instance(id).await);
+    assert!(dir.path().join("deleted").exists());
+
+    Ok(())
+}
+
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_start_deadline() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let id = "test-start-deadline";
+    create_bundle(dir.path(), None)?;
+    File::create(dir.path().join("slow-start"))?;
+
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let req = with_deadlines(CreateTaskRequest {
+        id: id.to_string(),
+        bundle: dir.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    });
+    local.task_create(req.clone()).await?;
+    let res = local
         .task_start(StartRequest {
-            id: "testinstance".to_string(),
+            id: id.to_string(),
             ..Default::default()
         })
-        .await?;
+        .await;
+    match res.unwrap_err() {
+        Error::DeadlineExceeded(_) => {}
+        e => return Err(e.into()),
+    }
+    // the instance was cleaned up as by a delete
+    assert!(!local.has_instance(id).await);
 
-    let state = local
-        .task_state(StateRequest {
-            id: "testinstance".to_string(),
+    std::fs::remove_file(dir.path().join("slow-start"))?;
+    local.task_create(req).await?;
+    local
+        .task_start(StartRequest {
+            id: id.to_string(),
             ..Default::default()
         })
         .await?;
-    assert_eq!(state.status(), Status::RUNNING);
 
-    let stats = local
-        .task_stats(StatsRequest {
-            id: "testinstance".to_string(),
+    Ok(())
+}
+
+fn spec_with_restart_policy(policy: &str) -> Spec {
+    let mut spec = Spec::default();
+    spec.set_annotations(Some(HashMap::from([(
+        crate::sandbox::shim::restart::RESTART_POLICY_ANNOTATION.to_string(),
+        policy.to_string(),
+    )])));
+    spec
+}
+
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_restart_on_failure() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let id = "test-restart-on-failure";
+    create_bundle(dir.path(), Some(spec_with_restart_policy("on-failure:2")))?;
+    File::create(dir.path().join("crash"))?;
+
+    let (tx, mut rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    local
+        .task_create(CreateTaskRequest {
+            id: id.to_string(),
+            bundle: dir.path().to_str().unwrap().to_string(),
             ..Default::default()
         })
         .await?;
-    assert!(stats.has_stats());
-
-    let ll = local.clone();
-    let (instance_tx, mut instance_rx) = channel();
-    tokio::spawn(async move {
-        let resp = ll
-            .task_wait(WaitRequest {
-                id: "testinstance".to_string(),
-                ..Default::default()
-            })
-            .await;
-        instance_tx.send(resp).unwrap();
-    });
-    instance_rx.try_recv().unwrap_err();
-
     local
-        .task_kill(KillRequest {
-            id: "testinstance".to_string(),
-            signal: 9,
+        .task_start(StartRequest {
+            id: id.to_string(),
             ..Default::default()
         })
         .await?;
 
-    instance_rx
-        .recv()
-        .with_timeout(Duration::from_secs(50))
-        .await
-        .flatten()
-        .unwrap()?;
-
+    // the instance exited, and is running again after its backoff
     let state = local
         .task_state(StateRequest {
-            id: "testinstance".to_string(),
-            ..Default::default()
-        })
-        .await?;
-    assert_eq!(state.status(), Status::STOPPED);
-    local
-        .task_delete(DeleteRequest {
-            id: "testinstance".to_string(),
+            id: id.to_string(),
             ..Default::default()
         })
         .await?;
+    assert_eq!(state.status(), Status::RUNNING);
 
-    match local
-        .task_state(StateRequest {
-            id: "testinstance".to_string(),
+    let wait = local
+        .task_wait(WaitRequest {
+            id: id.to_string(),
             ..Default::default()
         })
+        .with_timeout(Duration::from_secs(5))
         .await
-        .unwrap_err()
-    {
-        Error::NotFound(_) => {}
-        e => return Err(e),
+        .context("the restart policy didn't give up")??;
+    assert_eq!(wait.exit_status, 2);
+
+    // the exit is published once the waiters are notified
+    let mut topics = vec![];
+    while topics.len() < 5 {
+        let (topic, _) = rx
+            .recv()
+            .with_timeout(Duration::from_secs(5))
+            .await
+            .flatten()
+            .context("missing events")?;
+        topics.push(topic);
     }
+    assert_eq!(
+        topics,
+        [
+            "/tasks/create",
+            "/tasks/start",
+            "/tasks/start",
+            "/tasks/start",
+            "/tasks/exit"
+        ]
+    );
 
-    base_rx.try_recv().unwrap_err();
-    let state = local
-        .task_state(StateRequest {
-            id: "testbase".to_string(),
+    let stats = local
+        .task_stats(StatsRequest {
+            id: id.to_string(),
             ..Default::default()
         })
         .await?;
-    assert_eq!(state.status(), Status::RUNNING);
+    assert_eq!(
+        crate::sandbox::instance::restarts_from_stats(&stats.stats.value),
+        Some(2)
+    );
 
     local
-        .task_kill(KillRequest {
-            id: "testbase".to_string(),
-            signal: 9,
+        .task_delete(DeleteRequest {
+            id: id.to_string(),
             ..Default::default()
         })
         .await?;
 
-    base_rx
-        .recv()
-        .with_timeout(Duration::from_secs(5))
-        .await
-        .flatten()
+    Ok(())
+}
+
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_no_restart_after_kill() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let id = "test-no-restart-after-kill";
+    create_bundle(dir.path(), Some(spec_with_restart_policy("on-failure")))?;
+
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    local
+        .task_create(CreateTaskRequest {
+            id: id.to_string(),
+            bundle: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+    local
+        .task_start(StartRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    // the stub exits with 1 when killed
+    local
+        .task_kill(KillRequest {
+            id: id.to_string(),
+            signal: 9,
+            ..Default::default()
+        })
+        .await?;
+    let wait = local
+        .task_wait(WaitRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .with_timeout(Duration::from_secs(5))
+        .await
+        .context("the killed instance was restarted")??;
+    assert_eq!(wait.exit_status, 1);
+
+    let state = local
+        .task_state(StateRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::STOPPED);
+
+    let invalid = tempdir()?;
+    create_bundle(invalid.path(), Some(spec_with_restart_policy("always")))?;
+    let res = local
+        .task_create(CreateTaskRequest {
+            id: "test-invalid-restart-policy".to_string(),
+            bundle: invalid.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await;
+    assert!(matches!(res, Err(Error::InvalidSpec(_))));
+
+    Ok(())
+}
+
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_cri_task() -> Result<()> {
+    // Currently the relationship between the "base" container and the "instances" are pretty weak.
+    // When a cri sandbox is specified we just assume it's the sandbox container and treat it as such by not actually running the code (which is going to be wasm).
+    let (etx, _erx) = channel();
+    let exit_signal = WaitableCell::new();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        etx,
+        exit_signal,
+        "test_namespace",
+        "/test/address",
+    ));
+
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    let sandbox_id = "test-cri-task".to_string();
+    create_bundle(dir, Some(with_cri_sandbox(None, sandbox_id.clone())))?;
+
+    local
+        .task_create(CreateTaskRequest {
+            id: "testbase".to_string(),
+            bundle: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let state = local
+        .task_state(StateRequest {
+            id: "testbase".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::CREATED);
+
+    // make sure that the instance exists
+    let _i = local.get_instance("testbase").await?;
+
+    local
+        .task_start(StartRequest {
+            id: "testbase".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let state = local
+        .task_state(StateRequest {
+            id: "testbase".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::RUNNING);
+
+    let ll = local.clone();
+    let (base_tx, mut base_rx) = channel();
+    tokio::spawn(async move {
+        let resp = ll
+            .task_wait(WaitRequest {
+                id: "testbase".to_string(),
+                ..Default::default()
+            })
+            .await;
+        base_tx.send(resp).unwrap();
+    });
+    base_rx.try_recv().unwrap_err();
+
+    let temp2 = tempdir().unwrap();
+    let dir2 = temp2.path();
+    create_bundle(dir2, Some(with_cri_sandbox(None, sandbox_id)))?;
+
+    local
+        .task_create(CreateTaskRequest {
+            id: "testinstance".to_string(),
+            bundle: dir2.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let state = local
+        .task_state(StateRequest {
+            id: "testinstance".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::CREATED);
+
+    // make sure that the instance exists
+    let _i = local.get_instance("testinstance").await?;
+
+    local
+        .task_start(StartRequest {
+            id: "testinstance".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let state = local
+        .task_state(StateRequest {
+            id: "testinstance".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::RUNNING);
+
+    let stats = local
+        .task_stats(StatsRequest {
+            id: "testinstance".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert!(stats.has_stats());
+    let engine = EngineMetrics::from_stats(&stats.stats.value).context("no engine metrics")?;
+    assert_eq!(engine.linear_memory_bytes, Some(65536));
+    assert_eq!(engine.fuel_consumed, None);
+
+    let ll = local.clone();
+    let (instance_tx, mut instance_rx) = channel();
+    tokio::spawn(async move {
+        let resp = ll
+            .task_wait(WaitRequest {
+                id: "testinstance".to_string(),
+                ..Default::default()
+            })
+            .await;
+        instance_tx.send(resp).unwrap();
+    });
+    instance_rx.try_recv().unwrap_err();
+
+    local
+        .task_kill(KillRequest {
+            id: "testinstance".to_string(),
+            signal: 9,
+            ..Default::default()
+        })
+        .await?;
+
+    instance_rx
+        .recv()
+        .with_timeout(Duration::from_secs(50))
+        .await
+        .flatten()
+        .unwrap()?;
+
+    let state = local
+        .task_state(StateRequest {
+            id: "testinstance".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::STOPPED);
+    local
+        .task_delete(DeleteRequest {
+            id: "testinstance".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    match local
+        .task_state(StateRequest {
+            id: "testinstance".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err()
+    {
+        Error::NotFound(_) => {}
+        e => return Err(e),
+    }
+
+    base_rx.try_recv().unwrap_err();
+    let state = local
+        .task_state(StateRequest {
+            id: "testbase".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::RUNNING);
+
+    local
+        .task_kill(KillRequest {
+            id: "testbase".to_string(),
+            signal: 9,
+            ..Default::default()
+        })
+        .await?;
+
+    base_rx
+        .recv()
+        .with_timeout(Duration::from_secs(5))
+        .await
+        .flatten()
         .unwrap()?;
     let state = local
         .task_state(StateRequest {
@@ -494,37 +979,1035 @@ async fn test_task_lifecycle() -> Result<()> {
     Ok(())
 }
 
-#[test]
-fn test_default_runtime_options() -> Result<()> {
-    let options: Option<&Any> = None;
-
-    let config = Config::get_from_options(options).unwrap();
-
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_terminal() -> Result<()> {
+    let (etx, _erx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        etx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, None)?;
+
+    // the runtime spec doesn't request a terminal
+    match local
+        .task_create(CreateTaskRequest {
+            id: "test".to_string(),
+            bundle: dir.to_str().unwrap().to_string(),
+            terminal: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap_err()
+    {
+        Error::InvalidArgument(_) => {}
+        e => return Err(e),
+    }
+
+    local
+        .task_create(CreateTaskRequest {
+            id: "test".to_string(),
+            bundle: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    // InstanceStub relies on the default implementation of resize_pty
+    match local
+        .task_resize_pty(ResizePtyRequest {
+            id: "test".to_string(),
+            width: 80,
+            height: 24,
+            ..Default::default()
+        })
+        .await
+        .unwrap_err()
+    {
+        Error::Unsupported(_) => {}
+        e => return Err(e),
+    }
+
+    Ok(())
+}
+
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_pause_resume() -> Result<()> {
+    let (etx, _erx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        etx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, None)?;
+
+    local
+        .task_create(CreateTaskRequest {
+            id: "test".to_string(),
+            bundle: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let pause = || PauseRequest {
+        id: "test".to_string(),
+        ..Default::default()
+    };
+    let resume = || ResumeRequest {
+        id: "test".to_string(),
+        ..Default::default()
+    };
+    let state = || {
+        let local = local.clone();
+        async move {
+            local
+                .task_state(StateRequest {
+                    id: "test".to_string(),
+                    ..Default::default()
+                })
+                .await
+                .map(|state| state.status())
+        }
+    };
+
+    // the task is not running yet
+    match local.task_pause(pause()).await.unwrap_err() {
+        Error::FailedPrecondition(_) => {}
+        e => return Err(e),
+    }
+
+    local
+        .task_start(StartRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let (tx, mut rx) = channel();
+    let ll = local.clone();
+    tokio::spawn(async move {
+        let resp = ll
+            .task_wait(WaitRequest {
+                id: "test".to_string(),
+                ..Default::default()
+            })
+            .await;
+        tx.send(resp).unwrap();
+    });
+
+    local.task_pause(pause()).await?;
+    assert_eq!(state().await?, Status::PAUSED);
+
+    // pausing again doesn't reach the stub
+    local.task_pause(pause()).await?;
+    assert_eq!(state().await?, Status::PAUSED);
+
+    local.task_resume(resume()).await?;
+    assert_eq!(state().await?, Status::RUNNING);
+    rx.try_recv().unwrap_err();
+
+    // kill resumes the task before signaling it
+    local.task_pause(pause()).await?;
+    local
+        .task_kill(KillRequest {
+            id: "test".to_string(),
+            signal: 9,
+            ..Default::default()
+        })
+        .await?;
+
+    rx.recv()
+        .with_timeout(Duration::from_secs(5))
+        .await
+        .flatten()
+        .unwrap()?;
+    assert_eq!(state().await?, Status::STOPPED);
+
+    match local.task_resume(resume()).await.unwrap_err() {
+        Error::FailedPrecondition(_) => {}
+        e => return Err(e),
+    }
+
+    Ok(())
+}
+
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_exec_unsupported() -> Result<()> {
+    let (etx, _erx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        etx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    create_bundle(dir, None)?;
+
+    local
+        .task_create(CreateTaskRequest {
+            id: "test".to_string(),
+            bundle: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let exec = || ExecProcessRequest {
+        id: "test".to_string(),
+        exec_id: "exec".to_string(),
+        spec: Some(Any {
+            type_url: "types.containerd.io/opencontainers/runtime-spec/1/Process".to_string(),
+            value: json::to_vec(&Process::default()).unwrap(),
+            ..Default::default()
+        })
+        .into(),
+        ..Default::default()
+    };
+
+    // the task is not running yet
+    match local.task_exec(exec()).await.unwrap_err() {
+        Error::FailedPrecondition(_) => {}
+        e => return Err(e),
+    }
+
+    local
+        .task_start(StartRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    local.task_exec(exec()).await?;
+
+    match local.task_exec(exec()).await.unwrap_err() {
+        Error::AlreadyExists(_) => {}
+        e => return Err(e),
+    }
+
+    // InstanceStub relies on the default implementation of exec
+    match local
+        .task_start(StartRequest {
+            id: "test".to_string(),
+            exec_id: "exec".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err()
+    {
+        Error::Unsupported(_) => {}
+        e => return Err(e),
+    }
+
+    local
+        .task_delete(DeleteRequest {
+            id: "test".to_string(),
+            exec_id: "exec".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    match local
+        .task_state(StateRequest {
+            id: "test".to_string(),
+            exec_id: "exec".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err()
+    {
+        Error::NotFound(_) => {}
+        e => return Err(e),
+    }
+
+    Ok(())
+}
+
+// Publishes to the containerd running on the host, like the tests of the containerd client.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_events_published_to_containerd() -> anyhow::Result<()> {
+    use containerd_client::services::v1::SubscribeRequest;
+    use containerd_client::services::v1::events_client::EventsClient;
+    use containerd_shim::publisher::RemotePublisher;
+
+    let namespace = format!("runwasi-test-events-{}", std::process::id());
+    let conn = containerd_client::connect("/run/containerd/containerd.sock").await?;
+
+    // containerd answers a subscription with its first event, so subscribe in the background
+    let (topics_tx, mut topics_rx) = channel();
+    let filters = vec![format!("namespace=={namespace}")];
+    tokio::spawn(async move {
+        let req = SubscribeRequest { filters };
+        let Ok(events) = EventsClient::new(conn).subscribe(req).await else {
+            return;
+        };
+        let mut events = events.into_inner();
+        while let Ok(Some(envelope)) = events.message().await {
+            let _ = topics_tx.send(envelope.topic);
+        }
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let publisher = RemotePublisher::new("/run/containerd/containerd.sock.ttrpc")?;
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        RemoteEventSender::new(&namespace, publisher),
+        WaitableCell::new(),
+        &namespace,
+        "/run/containerd/containerd.sock",
+    ));
+
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let temp = tempdir()?;
+    create_bundle(temp.path(), None)?;
+
+    local
+        .task_create(CreateTaskRequest {
+            id: "test".to_string(),
+            bundle: temp.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+    local
+        .task_start(StartRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    local
+        .task_kill(KillRequest {
+            id: "test".to_string(),
+            signal: 9,
+            ..Default::default()
+        })
+        .await?;
+    local
+        .task_wait(WaitRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    local
+        .task_delete(DeleteRequest {
+            id: "test".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    let mut topics = vec![];
+    while topics.len() < 4 {
+        let topic = tokio::time::timeout(Duration::from_secs(5), topics_rx.recv())
+            .await
+            .context("timed out waiting for the events")?
+            .context("the subscription ended")?;
+        topics.push(topic);
+    }
+    assert_eq!(
+        topics,
+        [
+            "/tasks/create",
+            "/tasks/start",
+            "/tasks/exit",
+            "/tasks/delete"
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_pod_containers() -> anyhow::Result<()> {
+    let (etx, _erx) = channel();
+    let exit = WaitableCell::new();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        etx,
+        exit.clone(),
+        "test_namespace",
+        "/test/address",
+    ));
+
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    // the containers of a pod are tasks of the shim of the pod
+    let temp = tempdir()?;
+    create_bundle(temp.path(), Some(with_cri_sandbox(None, "pod".to_string())))?;
+    let create = |id: &str| CreateTaskRequest {
+        id: id.to_string(),
+        bundle: temp.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+    let delete = |id: &str| DeleteRequest {
+        id: id.to_string(),
+        ..Default::default()
+    };
+    let shutdown = || ShutdownRequest {
+        now: true,
+        ..Default::default()
+    };
+
+    local.task_create(create("app")).await?;
+    local.task_create(create("sidecar")).await?;
+    local
+        .task_start(StartRequest {
+            id: "sidecar".to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    // deleting a container doesn't shut down the shim of its siblings
+    local.task_delete(delete("app")).await?;
+    local.task_shutdown(shutdown()).await;
+    assert!(exit.wait().now_or_never().is_none());
+
+    let state = local
+        .task_state(StateRequest {
+            id: "sidecar".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.status(), Status::RUNNING);
+
+    local
+        .task_kill(KillRequest {
+            id: "sidecar".to_string(),
+            signal: 9,
+            ..Default::default()
+        })
+        .await?;
+    local
+        .task_wait(WaitRequest {
+            id: "sidecar".to_string(),
+            ..Default::default()
+        })
+        .await?;
+    local.task_delete(delete("sidecar")).await?;
+    local.task_shutdown(shutdown()).await;
+    assert!(exit.wait().now_or_never().is_some());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_shutdown_grace_period() -> anyhow::Result<()> {
+    let (etx, _erx) = channel();
+    let exit = WaitableCell::new();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        etx,
+        exit.clone(),
+        "test_namespace",
+        "/test/address",
+    ));
+
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: "SystemdCgroup = false\nShutdownTimeout = 1\n".to_string(),
+    };
+    let options = Any {
+        type_url: options.type_url.clone(),
+        value: options.encode_to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let temp = tempdir()?;
+    create_bundle(temp.path(), None)?;
+    let create = |id: &str| CreateTaskRequest {
+        id: id.to_string(),
+        bundle: temp.path().to_str().unwrap().to_string(),
+        options: Some(options.clone()).into(),
+        ..Default::default()
+    };
+    let delete = |id: &str| DeleteRequest {
+        id: id.to_string(),
+        ..Default::default()
+    };
+
+    local.task_create(create("first")).await?;
+    local.task_delete(delete("first")).await?;
+    local.task_shutdown(ShutdownRequest::default()).await;
+    assert!(exit.wait().now_or_never().is_none());
+
+    // a container created during the grace period cancels the shutdown
+    local.task_create(create("second")).await?;
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(exit.wait().now_or_never().is_none());
+
+    // `now` skips the grace period
+    local.task_delete(delete("second")).await?;
+    local
+        .task_shutdown(ShutdownRequest {
+            now: true,
+            ..Default::default()
+        })
+        .await;
+    assert!(exit.wait().now_or_never().is_some());
+
+    Ok(())
+}
+
+#[test]
+fn test_default_runtime_options() -> Result<()> {
+    let options: Option<&Any> = None;
+
+    let config = Config::get_from_options(options).unwrap();
+
     assert_eq!(config.systemd_cgroup, false);
 
     Ok(())
 }
 
 #[test]
-fn test_custom_runtime_options() -> Result<()> {
+fn test_deadline_runtime_options() -> Result<()> {
+    let req = with_deadlines(CreateTaskRequest::default());
+    let config = Config::get_from_options(req.options.as_ref()).unwrap();
+    assert_eq!(config.create_timeout(), Duration::from_secs(1));
+    assert_eq!(config.start_timeout(), Duration::from_secs(1));
+
+    let config = Config::default();
+    assert_eq!(config.create_timeout(), Duration::from_secs(120));
+    assert_eq!(config.start_timeout(), Duration::from_secs(120));
+
+    Ok(())
+}
+
+#[test]
+fn test_custom_runtime_options() -> Result<()> {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: "SystemdCgroup = true\n".to_string(),
+    };
+    let req = CreateTaskRequest {
+        options: Some(Any {
+            type_url: options.type_url.clone(),
+            value: options.encode_to_vec(),
+            special_fields: SpecialFields::default(),
+        })
+        .into(),
+        ..Default::default()
+    };
+
+    let config = Config::get_from_options(req.options.as_ref()).unwrap();
+
+    assert_eq!(config.systemd_cgroup, true);
+
+    Ok(())
+}
+
+#[test]
+fn test_env_policy_runtime_options() -> Result<()> {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: "SystemdCgroup = true\n[EnvPolicy]\nDeny = [\"AWS_*\"]\nInject = { PLATFORM = \"wasm\" }\n".to_string(),
+    };
+    let options = Any {
+        type_url: options.type_url.clone(),
+        value: options.encode_to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let config = Config::get_from_options(Some(&options)).unwrap();
+
+    assert_eq!(config.env_policy.deny, ["AWS_*"]);
+    assert_eq!(config.env_policy.inject["PLATFORM"], "wasm");
+    assert!(config.env_policy.allow.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_socket_policy_runtime_options() -> Result<()> {
     let options = Options {
         type_url: "runtimeoptions.v1.Options".to_string(),
         config_path: "".to_string(),
-        config_body: "SystemdCgroup = true\n".to_string(),
+        config_body: "[SocketPolicy]\nMode = \"allowlist\"\nAllow = [\"10.96.0.0/12:80\"]\nIpNameLookup = true\n".to_string(),
     };
-    let req = CreateTaskRequest {
-        options: Some(Any {
-            type_url: options.type_url.clone(),
-            value: options.encode_to_vec(),
-            special_fields: SpecialFields::default(),
+    let options = Any {
+        type_url: options.type_url.clone(),
+        value: options.encode_to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let config = Config::get_from_options(Some(&options)).unwrap();
+
+    assert_eq!(config.socket_policy.mode, SocketMode::Allowlist);
+    assert_eq!(config.socket_policy.allow[0].to_string(), "10.96.0.0/12:80");
+    assert!(config.socket_policy.ip_name_lookup);
+
+    let config = Config::get_from_options(None).unwrap();
+    assert_eq!(config.socket_policy.mode, SocketMode::Deny);
+
+    Ok(())
+}
+
+#[test]
+fn test_memory_reserve_runtime_options() -> Result<()> {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: "MemoryReserve = 33554432\n".to_string(),
+    };
+    let options = Any {
+        type_url: options.type_url.clone(),
+        value: options.encode_to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let config = Config::get_from_options(Some(&options)).unwrap();
+    assert_eq!(config.memory_reserve, Some(32 * 1024 * 1024));
+
+    let config = Config::get_from_options(None).unwrap();
+    assert_eq!(config.memory_reserve, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_compile_cache_runtime_options() -> Result<()> {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: "CompileCacheDir = \"/var/cache/runwasi\"\nCompileCacheMaxSize = 1048576\n"
+            .to_string(),
+    };
+    let options = Any {
+        type_url: options.type_url.clone(),
+        value: options.encode_to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let config = Config::get_from_options(Some(&options)).unwrap();
+    assert_eq!(
+        config.compile_cache("wasmtime"),
+        Some(CompileCache::new("/var/cache/runwasi", 1024 * 1024))
+    );
+
+    let config = Config::get_from_options(None).unwrap();
+    assert_eq!(
+        config.compile_cache("wasmtime"),
+        Some(CompileCache::new(
+            "/var/lib/runwasi/wasmtime-cache",
+            1024 * 1024 * 1024
+        ))
+    );
+
+    // a cache of 0 bytes is disabled
+    let config = Config {
+        compile_cache_max_size: Some(0),
+        ..Default::default()
+    };
+    assert_eq!(config.compile_cache("wasmtime"), None);
+
+    Ok(())
+}
+
+#[test]
+fn test_native_fallback_runtime_options() -> Result<()> {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body:
+            "NativeFallback = \"registry-allowlist\"\nNativeFallbackRegistries = [\"ghcr.io\"]\n"
+                .to_string(),
+    };
+    let options = Any {
+        type_url: options.type_url.clone(),
+        value: options.encode_to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let config = Config::get_from_options(Some(&options)).unwrap();
+
+    let policy = config.native_fallback_policy();
+    assert_eq!(policy.mode, NativeFallback::RegistryAllowlist);
+    assert!(policy.allows("ghcr.io/containerd/busybox"));
+    assert!(!policy.allows("alpine"));
+    assert_eq!(Config::default().native_fallback, NativeFallback::Allow);
+
+    Ok(())
+}
+
+#[test]
+fn test_zygote_pool_runtime_options() -> Result<()> {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: "ZygotePoolSize = 8\n".to_string(),
+    };
+    let options = Any {
+        type_url: options.type_url.clone(),
+        value: options.encode_to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let config = Config::get_from_options(Some(&options)).unwrap();
+    assert_eq!(config.zygote_pool_size, Some(8));
+    assert_eq!(Config::default().zygote_pool_size, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_metrics_address_runtime_options() -> Result<()> {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: "MetricsAddress = \"127.0.0.1:9100\"\n".to_string(),
+    };
+    let options = Any {
+        type_url: options.type_url.clone(),
+        value: options.encode_to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let config = Config::get_from_options(Some(&options)).unwrap();
+    assert_eq!(config.metrics_address.as_deref(), Some("127.0.0.1:9100"));
+    assert_eq!(Config::default().metrics_address, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_log_format_runtime_options() -> Result<()> {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: "LogFormat = \"json\"\n".to_string(),
+    };
+    let options = Any {
+        type_url: options.type_url.clone(),
+        value: options.encode_to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let config = Config::get_from_options(Some(&options)).unwrap();
+    assert_eq!(config.log_format, Some(LogFormat::Json));
+
+    let config = Config::get_from_options(None).unwrap();
+    assert_eq!(config.log_format, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_log_level_runtime_options() -> Result<()> {
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: "LogLevel = \"debug\"\n".to_string(),
+    };
+    let options = Any {
+        type_url: options.type_url.clone(),
+        value: options.encode_to_vec(),
+        special_fields: SpecialFields::default(),
+    };
+
+    let config = Config::get_from_options(Some(&options)).unwrap();
+    assert_eq!(config.log_level.as_deref(), Some("debug"));
+
+    Ok(())
+}
+
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_invalid_log_level() -> anyhow::Result<()> {
+    let dir = tempdir().unwrap();
+    let annotations = HashMap::from([(
+        logging::LOG_LEVEL_ANNOTATION.to_string(),
+        "loud".to_string(),
+    )]);
+    let mut spec = Spec::default();
+    spec.set_annotations(Some(annotations));
+    create_bundle(dir.path(), Some(spec)).unwrap();
+
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    // an invalid level in the shim options fails the container
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: "LogLevel = \"loud\"\n".to_string(),
+    };
+    let res = local
+        .task_create(CreateTaskRequest {
+            id: "test-invalid-log-level-options".to_string(),
+            bundle: dir.path().to_str().unwrap().to_string(),
+            options: Some(Any {
+                type_url: options.type_url.clone(),
+                value: options.encode_to_vec(),
+                special_fields: SpecialFields::default(),
+            })
+            .into(),
+            ..Default::default()
         })
-        .into(),
+        .await;
+    assert!(matches!(res, Err(Error::InvalidArgument(_))));
+
+    // while an invalid annotation is ignored, and keeps the current level
+    let level = log::max_level();
+    local
+        .task_create(CreateTaskRequest {
+            id: "test-invalid-log-level-annotation".to_string(),
+            bundle: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(log::max_level(), level);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_invalid_options() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    create_bundle(dir.path(), None)?;
+
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let options = Options {
+        type_url: "runtimeoptions.v1.Options".to_string(),
+        config_path: "".to_string(),
+        config_body: "ShutdownTimeout = \"soon\"\n".to_string(),
+    };
+    let res = local
+        .task_create(CreateTaskRequest {
+            id: "test-invalid-options".to_string(),
+            bundle: dir.path().to_str().unwrap().to_string(),
+            options: Some(Any {
+                type_url: options.type_url.clone(),
+                value: options.encode_to_vec(),
+                special_fields: SpecialFields::default(),
+            })
+            .into(),
+            ..Default::default()
+        })
+        .await;
+    let Err(Error::InvalidArgument(msg)) = res else {
+        panic!("expected an invalid argument, got {res:?}");
+    };
+    // the error of the options is only described once
+    assert_eq!(msg.matches("invalid shim options").count(), 1, "{msg}");
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_error_codes() -> anyhow::Result<()> {
+    use containerd_shim::protos::ttrpc;
+
+    // the code of the status that containerd receives
+    fn code<T>(res: Result<T>) -> ttrpc::Code {
+        match ttrpc::Error::from(res.err().expect("an error")) {
+            ttrpc::Error::RpcStatus(status) => status.code(),
+            err => panic!("unexpected error {err:?}"),
+        }
+    }
+
+    let dir = tempdir()?;
+    let id = "test-error-codes";
+    create_bundle(dir.path(), None)?;
+
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let res = local
+        .task_delete(DeleteRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .await;
+    assert_eq!(code(res), ttrpc::Code::NOT_FOUND);
+
+    let create = CreateTaskRequest {
+        id: id.to_string(),
+        bundle: dir.path().to_str().unwrap().to_string(),
         ..Default::default()
     };
+    local.task_create(create.clone()).await?;
+    assert_eq!(
+        code(local.task_create(create).await),
+        ttrpc::Code::ALREADY_EXISTS
+    );
+
+    // a bundle without a spec
+    let empty = tempdir()?;
+    let res = local
+        .task_create(CreateTaskRequest {
+            id: "test-error-codes-invalid".to_string(),
+            bundle: empty.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .await;
+    assert_eq!(code(res), ttrpc::Code::INVALID_ARGUMENT);
 
-    let config = Config::get_from_options(req.options.as_ref()).unwrap();
+    local
+        .task_delete(DeleteRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .await?;
 
-    assert_eq!(config.systemd_cgroup, true);
+    Ok(())
+}
+
+#[test]
+fn test_error_codes_over_ttrpc() -> anyhow::Result<()> {
+    use containerd_shim::protos::shim::shim_ttrpc::{TaskClient, create_task};
+    use containerd_shim::protos::ttrpc::context::with_timeout;
+    use containerd_shim::protos::ttrpc::{self, Client, Server};
+
+    let dir = tempdir()?;
+    let bundle = dir.path().join("bundle");
+    create_dir(&bundle)?;
+    create_bundle(&bundle, None)?;
+    File::create(bundle.join(INVALID))?;
+
+    let (tx, _rx) = channel();
+    let local = Local::<InstanceStub, _>::new(tx, WaitableCell::new(), "test_namespace", "");
+    let address = format!("unix://{}", dir.path().join("shim.sock").display());
+    let mut server = Server::new()
+        .bind(&address)?
+        .register_service(create_task(Arc::new(local)));
+    server.start()?;
+
+    // containerd reads the code of the status of a container that its executor rejected
+    let client = TaskClient::new(Client::connect(&address)?);
+    let req = CreateTaskRequest {
+        id: "test-error-codes-over-ttrpc".to_string(),
+        bundle: bundle.to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    match client.create(with_timeout(0), &req) {
+        Err(ttrpc::Error::RpcStatus(status)) => {
+            assert_eq!(status.code(), ttrpc::Code::INVALID_ARGUMENT);
+            assert!(status.message().contains("the entrypoint isn't exported"));
+        }
+        res => panic!("unexpected result {res:?}"),
+    }
+
+    server.shutdown();
+    Ok(())
+}
+
+// Use a multi threaded runtime because LocalWithDestructor needs
+// it to run its async drop.
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_create_of_a_running_task_attaches_stdio() -> anyhow::Result<()> {
+    let dir = tempdir()?;
+    let id = "test-attach-stdio";
+    create_bundle(dir.path(), None)?;
+
+    let (tx, _rx) = channel();
+    let local = Arc::new(Local::<InstanceStub, _>::new(
+        tx,
+        WaitableCell::new(),
+        "test_namespace",
+        "/test/address",
+    ));
+    let mut _wrapped = LocalWithDestructor::new(local.clone());
+
+    let create = CreateTaskRequest {
+        id: id.to_string(),
+        bundle: dir.path().to_str().unwrap().to_string(),
+        stdout: "/run/containerd/fifo/1/stdout".to_string(),
+        ..Default::default()
+    };
+    local.task_create(create.clone()).await?;
+
+    // the task isn't running yet
+    let err = local.task_create(create.clone()).await.unwrap_err();
+    assert!(matches!(err, Error::AlreadyExists(_)), "{err}");
+
+    local
+        .task_start(StartRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    // containerd opens the FIFOs of the task again
+    local.task_create(create.clone()).await?;
+    let state = local
+        .task_state(StateRequest {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(state.stdout, "/run/containerd/fifo/1/stdout");
+    assert_eq!(state.status(), Status::RUNNING);
+
+    // other FIFOs are another container
+    let err = local
+        .task_create(CreateTaskRequest {
+            stdout: "/run/containerd/fifo/2/stdout".to_string(),
+            ..create.clone()
+        })
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::AlreadyExists(_)), "{err}");
+
+    // another bundle is another container
+    let other = tempdir()?;
+    create_bundle(other.path(), None)?;
+    let err = local
+        .task_create(CreateTaskRequest {
+            bundle: other.path().to_str().unwrap().to_string(),
+            ..create
+        })
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::AlreadyExists(_)), "{err}");
+
+    local
+        .task_kill(KillRequest {
+            id: id.to_string(),
+            signal: 9,
+            ..Default::default()
+        })
+        .await?;
 
     Ok(())
 }
@@ -0,0 +1,507 @@
+//! Metrics of the shim itself, in the Prometheus text format, on the `MetricsAddress`
+//! of the runtime options:
+//!
+//! ```toml
+//! [plugins."io.containerd.grpc.v1.cri".containerd.runtimes.wasm.options]
+//!   MetricsAddress = "unix:///run/runwasi/metrics.sock"
+//! ```
+//!
+//! The address is a unix socket, except on Windows, or a socket address on the loopback
+//! interface, e.g., `127.0.0.1:9100`. The endpoint is disabled without it, and it stops with the shim.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, bail};
+use containerd_shim::protos::ttrpc::Code;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::task::JoinHandle;
+
+use crate::sandbox::async_utils::AmbientRuntime as _;
+use crate::sandbox::{EngineMetrics, Error, Result};
+
+/// Upper bounds of the buckets of the latency histograms, in seconds
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A metric of the engines, by its name and how it's read from their metrics
+type EngineGauge = (&'static str, fn(&EngineMetrics) -> Option<f64>);
+
+/// The listener of the shim, stopped when the shim exits
+static LISTENER: Mutex<Option<MetricsListener>> = Mutex::new(None);
+
+/// Counters of the task API and of the precompilation cache
+#[derive(Default)]
+pub(crate) struct ShimMetrics {
+    /// Requests by method and status
+    rpcs: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    create: Histogram,
+    start: Histogram,
+    precompile_hits: AtomicU64,
+    precompile_misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_ns: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, le) in self.buckets.iter().zip(BUCKETS) {
+            if secs <= le {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ns
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, method: &str) {
+        for (bucket, le) in self.buckets.iter().zip(BUCKETS) {
+            let count = bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{method=\"{method}\",le=\"{le}\"}} {count}"
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_ns.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{method=\"{method}\",le=\"+Inf\"}} {count}"
+        );
+        let _ = writeln!(out, "{name}_sum{{method=\"{method}\"}} {sum}");
+        let _ = writeln!(out, "{name}_count{{method=\"{method}\"}} {count}");
+    }
+}
+
+impl ShimMetrics {
+    pub fn global() -> &'static Self {
+        static METRICS: LazyLock<ShimMetrics> = LazyLock::new(ShimMetrics::default);
+        &METRICS
+    }
+
+    /// Count a request of the task API, and its latency for Create and Start
+    pub fn record_rpc<T>(&self, method: &'static str, res: &Result<T>, duration: Duration) {
+        let status = match res {
+            Ok(_) => "ok",
+            Err(err) => status(err),
+        };
+        *self
+            .rpcs
+            .lock()
+            .unwrap()
+            .entry((method, status))
+            .or_default() += 1;
+        match method {
+            "create" => self.create.observe(duration),
+            "start" => self.start.observe(duration),
+            _ => {}
+        }
+    }
+
+    /// Count the layers loaded from the precompiled content, or precompiled by this shim
+    pub fn record_precompile(&self, hit: bool) {
+        let counter = if hit {
+            &self.precompile_hits
+        } else {
+            &self.precompile_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The metrics, with the number of `containers` and the latest metrics of their engines
+    pub fn render(&self, containers: usize, engines: &[(String, EngineMetrics)]) -> String {
+        let mut out = String::new();
+
+        let name = "runwasi_shim_rpc_total";
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for ((method, status), count) in self.rpcs.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{name}{{method=\"{method}\",status=\"{status}\"}} {count}"
+            );
+        }
+
+        let name = "runwasi_shim_rpc_duration_seconds";
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        self.create.render(&mut out, name, "create");
+        self.start.render(&mut out, name, "start");
+
+        let _ = writeln!(out, "# TYPE runwasi_shim_containers gauge");
+        let _ = writeln!(out, "runwasi_shim_containers {containers}");
+
+        let counters = [
+            ("runwasi_shim_precompile_hits_total", &self.precompile_hits),
+            (
+                "runwasi_shim_precompile_misses_total",
+                &self.precompile_misses,
+            ),
+        ];
+        for (name, value) in counters {
+            let value = value.load(Ordering::Relaxed);
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {value}");
+        }
+
+        let gauges: [EngineGauge; 4] = [
+            ("runwasi_engine_linear_memory_bytes", |m| {
+                m.linear_memory_bytes.map(|v| v as f64)
+            }),
+            ("runwasi_engine_table_elements", |m| {
+                m.table_elements.map(|v| v as f64)
+            }),
+            ("runwasi_engine_fuel_consumed", |m| {
+                m.fuel_consumed.map(|v| v as f64)
+            }),
+            ("runwasi_engine_compilation_seconds", |m| {
+                m.compilation_time_ns.map(|v| v as f64 / 1e9)
+            }),
+        ];
+        for (name, value) in gauges {
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            for (container, metrics) in engines {
+                if let Some(value) = value(metrics) {
+                    let _ = writeln!(out, "{name}{{container=\"{container}\"}} {value}");
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// Label of the status of a failed request, after its ttrpc code
+fn status(err: &Error) -> &'static str {
+    match err.code() {
+        Code::NOT_FOUND => "not_found",
+        Code::ALREADY_EXISTS => "already_exists",
+        Code::INVALID_ARGUMENT => "invalid_argument",
+        Code::FAILED_PRECONDITION => "failed_precondition",
+        Code::UNIMPLEMENTED => "unimplemented",
+        Code::DEADLINE_EXCEEDED => "deadline_exceeded",
+        Code::INTERNAL => "internal",
+        _ => "unknown",
+    }
+}
+
+/// Run the request `fut` of the task API `method`, and record it
+pub(crate) async fn observe<T>(
+    method: &'static str,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let start = Instant::now();
+    let res = fut.await;
+    ShimMetrics::global().record_rpc(method, &res, start.elapsed());
+    res
+}
+
+/// Where the metrics are served
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) enum MetricsAddress {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl FromStr for MetricsAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(address: &str) -> anyhow::Result<Self> {
+        if let Some(path) = address.strip_prefix("unix://") {
+            if path.is_empty() {
+                bail!("invalid metrics address {address:?}");
+            }
+            #[cfg(unix)]
+            return Ok(Self::Unix(path.into()));
+            #[cfg(not(unix))]
+            bail!(
+                "the metrics address {address:?} is a unix socket, which is only supported on unix"
+            );
+        }
+        let addr: SocketAddr = address
+            .parse()
+            .with_context(|| format!("invalid metrics address {address:?}"))?;
+        if !addr.ip().is_loopback() {
+            bail!("the metrics address {address:?} is not on the loopback interface");
+        }
+        Ok(Self::Tcp(addr))
+    }
+}
+
+/// HTTP listener that answers every request with the metrics, stopped when dropped
+pub(crate) struct MetricsListener {
+    task: JoinHandle<()>,
+    socket: Option<PathBuf>,
+}
+
+impl MetricsListener {
+    /// Serve the metrics of `render` on `address`
+    pub async fn bind<F, Fut>(address: &MetricsAddress, render: F) -> std::io::Result<Self>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = String> + Send,
+    {
+        let (task, socket) = match address {
+            #[cfg(unix)]
+            MetricsAddress::Unix(path) => {
+                let _ = std::fs::remove_file(path);
+                let listener = UnixListener::bind(path)?;
+                let task = async move {
+                    loop {
+                        if let Ok((stream, _)) = listener.accept().await {
+                            respond(stream, render().await).await;
+                        }
+                    }
+                }
+                .spawn();
+                (task, Some(path.clone()))
+            }
+            MetricsAddress::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                let task = async move {
+                    loop {
+                        if let Ok((stream, _)) = listener.accept().await {
+                            respond(stream, render().await).await;
+                        }
+                    }
+                }
+                .spawn();
+                (task, None)
+            }
+        };
+        log::info!("serving the shim metrics on {address:?}");
+        Ok(Self { task, socket })
+    }
+}
+
+impl Drop for MetricsListener {
+    fn drop(&mut self) {
+        self.task.abort();
+        if let Some(socket) = &self.socket {
+            let _ = std::fs::remove_file(socket);
+        }
+    }
+}
+
+/// Serve the metrics on `address`, unless the shim already serves them
+pub(crate) async fn serve<F, Fut>(address: &MetricsAddress, render: F) -> std::io::Result<()>
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = String> + Send,
+{
+    if LISTENER.lock().unwrap().is_some() {
+        return Ok(());
+    }
+    let listener = MetricsListener::bind(address, render).await?;
+    let mut current = LISTENER.lock().unwrap();
+    // another container of the shim started serving them meanwhile
+    if current.is_none() {
+        *current = Some(listener);
+    }
+    Ok(())
+}
+
+/// Stop serving the metrics, when the shim exits
+pub(crate) fn stop() {
+    LISTENER.lock().unwrap().take();
+}
+
+async fn respond(mut stream: impl AsyncRead + AsyncWrite + Unpin, body: String) {
+    // the request itself is irrelevant, read its head and discard it
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    let res = async {
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body.as_bytes()).await?;
+        stream.shutdown().await
+    };
+    if let Err(err) = res.await {
+        log::debug!("failed to serve the shim metrics: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// The samples of a scrape, by their name and labels
+    fn parse(text: &str) -> anyhow::Result<HashMap<String, f64>> {
+        let mut samples = HashMap::new();
+        for line in text.lines() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (series, value) = line.rsplit_once(' ').context("sample without a value")?;
+            samples.insert(series.to_string(), value.parse()?);
+        }
+        Ok(samples)
+    }
+
+    /// The response of the metrics to a scrape on `stream`
+    fn scrape(mut stream: impl Read + Write) -> anyhow::Result<String> {
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    #[test]
+    fn test_metrics_address() {
+        #[cfg(unix)]
+        assert_eq!(
+            "unix:///run/runwasi/metrics.sock"
+                .parse::<MetricsAddress>()
+                .unwrap(),
+            MetricsAddress::Unix("/run/runwasi/metrics.sock".into())
+        );
+        assert_eq!(
+            "127.0.0.1:9100".parse::<MetricsAddress>().unwrap(),
+            MetricsAddress::Tcp("127.0.0.1:9100".parse().unwrap())
+        );
+        assert!("0.0.0.0:9100".parse::<MetricsAddress>().is_err());
+        assert!("unix://".parse::<MetricsAddress>().is_err());
+        assert!("localhost".parse::<MetricsAddress>().is_err());
+        #[cfg(not(unix))]
+        assert!(
+            "unix:///run/runwasi/metrics.sock"
+                .parse::<MetricsAddress>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_scrape_metrics_tcp() -> anyhow::Result<()> {
+        let metrics = Arc::new(ShimMetrics::default());
+        metrics.record_rpc("create", &Ok(()), Duration::from_millis(2));
+
+        // a free port of the loopback interface
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let address = MetricsAddress::Tcp(addr);
+        let _listener = {
+            let metrics = metrics.clone();
+            MetricsListener::bind(&address, move || {
+                let metrics = metrics.clone();
+                async move { metrics.render(0, &[]) }
+            })
+            .block_on()?
+        };
+
+        let response = scrape(std::net::TcpStream::connect(addr)?)?;
+        let (head, body) = response.split_once("\r\n\r\n").context("no HTTP head")?;
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        let samples = parse(body)?;
+        assert_eq!(
+            samples[r#"runwasi_shim_rpc_total{method="create",status="ok"}"#],
+            1.0
+        );
+        assert_eq!(samples["runwasi_shim_containers"], 0.0);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scrape_metrics() -> anyhow::Result<()> {
+        use std::os::unix::net::UnixStream;
+
+        let metrics = Arc::new(ShimMetrics::default());
+        metrics.record_rpc("create", &Ok(()), Duration::from_millis(2));
+        metrics.record_rpc("start", &Ok(()), Duration::from_millis(20));
+        metrics.record_rpc::<()>(
+            "start",
+            &Err(Error::NotFound("container".to_string())),
+            Duration::ZERO,
+        );
+        metrics.record_precompile(true);
+
+        let dir = tempfile::tempdir()?;
+        let socket = dir.path().join("metrics.sock");
+        let address = MetricsAddress::Unix(socket.clone());
+        let listener = {
+            let metrics = metrics.clone();
+            MetricsListener::bind(&address, move || {
+                let metrics = metrics.clone();
+                async move {
+                    let engine = EngineMetrics {
+                        compilation_time_ns: Some(1_500_000_000),
+                        ..Default::default()
+                    };
+                    metrics.render(1, &[("app".to_string(), engine)])
+                }
+            })
+            .block_on()?
+        };
+
+        let response = scrape(UnixStream::connect(&socket)?)?;
+
+        let (head, body) = response.split_once("\r\n\r\n").context("no HTTP head")?;
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        let samples = parse(body)?;
+        assert_eq!(
+            samples[r#"runwasi_shim_rpc_total{method="create",status="ok"}"#],
+            1.0
+        );
+        assert_eq!(
+            samples[r#"runwasi_shim_rpc_total{method="start",status="not_found"}"#],
+            1.0
+        );
+        assert_eq!(
+            samples[r#"runwasi_shim_rpc_duration_seconds_bucket{method="create",le="0.005"}"#],
+            1.0
+        );
+        assert_eq!(
+            samples[r#"runwasi_shim_rpc_duration_seconds_bucket{method="start",le="0.01"}"#],
+            1.0
+        );
+        assert_eq!(
+            samples[r#"runwasi_shim_rpc_duration_seconds_count{method="start"}"#],
+            2.0
+        );
+        assert_eq!(samples["runwasi_shim_containers"], 1.0);
+        assert_eq!(samples["runwasi_shim_precompile_hits_total"], 1.0);
+        assert_eq!(samples["runwasi_shim_precompile_misses_total"], 0.0);
+        assert_eq!(
+            samples[r#"runwasi_engine_compilation_seconds{container="app"}"#],
+            1.5
+        );
+        assert!(!samples.contains_key(r#"runwasi_engine_fuel_consumed{container="app"}"#));
+
+        // the socket is removed with the listener
+        drop(listener);
+        assert!(!socket.exists());
+
+        Ok(())
+    }
+}
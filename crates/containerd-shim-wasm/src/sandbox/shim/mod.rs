@@ -3,12 +3,17 @@
 //! the container/sandbox.
 
 mod cli;
+mod config_file;
 mod events;
+mod hooks;
 mod instance_data;
 mod local;
 pub use local::Config;
+pub(crate) mod metrics;
 #[cfg(feature = "opentelemetry")]
 mod otel;
+mod restart;
+mod sandbox_data;
 mod task_state;
 
 pub use cli::Cli;
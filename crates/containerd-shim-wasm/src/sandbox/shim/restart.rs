@@ -0,0 +1,197 @@
+//! The restart policy of the containers run without a supervisor, e.g., with `ctr`.
+//!
+//! A container opts in with the `io.runwasi.restart-policy` annotation, either `on-failure`, to be
+//! restarted whenever it exits with a non-zero code, or `on-failure:<max-retries>`. The shim waits
+//! an exponential backoff before each restart, and runs the entrypoint again with the same bundle
+//! and stdio. The task stays running until the policy gives up, the instance exits with 0, or it's
+//! killed, and only then is its exit reported.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use chrono::{DateTime, Utc};
+use futures::FutureExt as _;
+use tokio::sync::watch;
+
+use crate::sandbox::sync::WaitableCell;
+
+/// Annotation with the restart policy of a container
+pub(super) const RESTART_POLICY_ANNOTATION: &str = "io.runwasi.restart-policy";
+
+/// Backoff before the first restart, doubled for each of the next ones
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Longest backoff between two restarts
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// When an exited instance is restarted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct RestartPolicy {
+    /// How many times the instance is restarted, or `None` to restart it forever
+    pub max_retries: Option<u32>,
+}
+
+impl RestartPolicy {
+    /// The restart policy of a container, from its `io.runwasi.restart-policy` annotation
+    pub fn from_annotations(
+        annotations: Option<&HashMap<String, String>>,
+    ) -> anyhow::Result<Option<Self>> {
+        let Some(policy) = annotations.and_then(|a| a.get(RESTART_POLICY_ANNOTATION)) else {
+            return Ok(None);
+        };
+        let (name, retries) = match policy.trim().split_once(':') {
+            Some((name, retries)) => (name, Some(retries)),
+            None => (policy.trim(), None),
+        };
+        match name {
+            "no" | "" if retries.is_none() => Ok(None),
+            "on-failure" => {
+                let max_retries = retries
+                    .map(|retries| retries.trim().parse::<u32>())
+                    .transpose()
+                    .with_context(|| {
+                        format!("invalid {RESTART_POLICY_ANNOTATION} annotation: {policy:?}")
+                    })?;
+                Ok(Some(Self { max_retries }))
+            }
+            _ => bail!(
+                "invalid {RESTART_POLICY_ANNOTATION} annotation: {policy:?}, expected \"no\", \"on-failure\" or \"on-failure:<max-retries>\""
+            ),
+        }
+    }
+}
+
+/// The restarts of an instance by its policy
+pub(super) struct Restarts {
+    policy: RestartPolicy,
+    /// Number of restarts so far, notified to the watchers of the instance
+    count: watch::Sender<u32>,
+    /// Set once the instance is killed, which stops the restarts
+    stopped: WaitableCell<()>,
+    /// Set once the policy gives up, with the last exit of the instance
+    exit: WaitableCell<(u32, DateTime<Utc>)>,
+}
+
+impl Restarts {
+    pub fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            count: watch::Sender::new(0),
+            stopped: WaitableCell::new(),
+            exit: WaitableCell::new(),
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        *self.count.borrow()
+    }
+
+    pub fn restarted(&self) {
+        self.count.send_modify(|count| *count += 1);
+    }
+
+    /// A receiver notified of each restart
+    pub fn subscribe(&self) -> watch::Receiver<u32> {
+        self.count.subscribe()
+    }
+
+    /// Stop the restarts, e.g., when the instance is killed
+    pub fn stop(&self) {
+        let _ = self.stopped.set(());
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.wait().now_or_never().is_some()
+    }
+
+    /// How long to wait before restarting an instance that exited with `exit_code`,
+    /// or `None` when the policy gives up
+    pub fn backoff(&self, exit_code: u32) -> Option<Duration> {
+        let count = self.count();
+        if exit_code == 0
+            || self.is_stopped()
+            || self.policy.max_retries.is_some_and(|max| count >= max)
+        {
+            return None;
+        }
+        Some(
+            INITIAL_BACKOFF
+                .saturating_mul(2u32.saturating_pow(count))
+                .min(MAX_BACKOFF),
+        )
+    }
+
+    /// Wait for `backoff`, or until the restarts are stopped. Returns whether to restart.
+    pub async fn wait_backoff(&self, backoff: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => !self.is_stopped(),
+            _ = self.stopped.wait() => false,
+        }
+    }
+
+    /// Record the final exit of the instance, once the policy gave up
+    pub fn exited(&self, exit: (u32, DateTime<Utc>)) {
+        let _ = self.exit.set(exit);
+    }
+
+    pub async fn wait(&self) -> (u32, DateTime<Utc>) {
+        *self.exit.wait().await
+    }
+
+    pub fn try_wait(&self) -> Option<(u32, DateTime<Utc>)> {
+        self.exit.wait().now_or_never().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(value: &str) -> anyhow::Result<Option<RestartPolicy>> {
+        let annotations =
+            HashMap::from([(RESTART_POLICY_ANNOTATION.to_string(), value.to_string())]);
+        RestartPolicy::from_annotations(Some(&annotations))
+    }
+
+    #[test]
+    fn test_restart_policy_from_annotations() -> anyhow::Result<()> {
+        assert_eq!(RestartPolicy::from_annotations(None)?, None);
+        assert_eq!(policy("no")?, None);
+        assert_eq!(
+            policy("on-failure")?,
+            Some(RestartPolicy { max_retries: None })
+        );
+        assert_eq!(
+            policy(" on-failure:3 ")?,
+            Some(RestartPolicy {
+                max_retries: Some(3)
+            })
+        );
+        assert!(policy("always").is_err());
+        assert!(policy("on-failure:many").is_err());
+        assert!(policy("no:3").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff() {
+        let restarts = Restarts::new(RestartPolicy {
+            max_retries: Some(2),
+        });
+        assert_eq!(restarts.backoff(0), None);
+        assert_eq!(restarts.backoff(1), Some(INITIAL_BACKOFF));
+        restarts.restarted();
+        assert_eq!(restarts.backoff(1), Some(INITIAL_BACKOFF * 2));
+        restarts.restarted();
+        assert_eq!(restarts.backoff(1), None);
+
+        let restarts = Restarts::new(RestartPolicy { max_retries: None });
+        for _ in 0..20 {
+            restarts.restarted();
+        }
+        assert_eq!(restarts.backoff(1), Some(MAX_BACKOFF));
+        restarts.stop();
+        assert_eq!(restarts.backoff(1), None);
+    }
+}
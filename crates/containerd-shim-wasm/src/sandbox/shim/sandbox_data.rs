@@ -0,0 +1,116 @@
+#![cfg_attr(not(feature = "sandbox"), allow(dead_code))] // sandboxes are only created with the sandbox API
+
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+use chrono::{DateTime, Utc};
+use futures::FutureExt as _;
+
+use crate::sandbox::sync::WaitableCell;
+use crate::sandbox::{Error, Result};
+
+/// A pod sandbox managed with the sandbox API of containerd, instead of a pause container.
+/// The containers of the pod are tasks of the same shim, which route them to their sandbox.
+pub(super) struct SandboxData {
+    pub bundle: PathBuf,
+    pub created_at: DateTime<Utc>,
+    started_at: OnceLock<DateTime<Utc>>,
+    exit: WaitableCell<(u32, DateTime<Utc>)>,
+    containers: Mutex<BTreeSet<String>>,
+}
+
+impl SandboxData {
+    pub fn new(bundle: impl Into<PathBuf>) -> Self {
+        Self {
+            bundle: bundle.into(),
+            created_at: Utc::now(),
+            started_at: OnceLock::new(),
+            exit: WaitableCell::new(),
+            containers: Mutex::default(),
+        }
+    }
+
+    /// Starts the sandbox, returning when it was started.
+    /// There is no process to start, the sandbox is the shim itself.
+    pub fn start(&self) -> Result<DateTime<Utc>> {
+        if self.exited_at().is_some() {
+            return Err(Error::FailedPrecondition(
+                "the sandbox is stopped".to_string(),
+            ));
+        }
+        Ok(*self.started_at.get_or_init(Utc::now))
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.started_at.get().is_some() && self.exited_at().is_none()
+    }
+
+    /// Stops the sandbox, once its containers are stopped
+    pub fn stop(&self) {
+        let _ = self.exit.set((0, Utc::now()));
+    }
+
+    /// Waits for the sandbox to stop
+    pub async fn wait(&self) -> (u32, DateTime<Utc>) {
+        *self.exit.wait().await
+    }
+
+    pub fn exited_at(&self) -> Option<DateTime<Utc>> {
+        self.exit
+            .wait()
+            .now_or_never()
+            .map(|(_, exited_at)| *exited_at)
+    }
+
+    /// Adds a container to the sandbox, which must not be stopped
+    pub fn add_container(&self, id: &str) -> Result<()> {
+        if self.exited_at().is_some() {
+            return Err(Error::FailedPrecondition(format!(
+                "can't create container {id} in a stopped sandbox"
+            )));
+        }
+        self.containers.lock().unwrap().insert(id.to_string());
+        Ok(())
+    }
+
+    pub fn remove_container(&self, id: &str) {
+        self.containers.lock().unwrap().remove(id);
+    }
+
+    pub fn containers(&self) -> Vec<String> {
+        self.containers.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sandbox_lifecycle() -> Result<()> {
+        let sandbox = SandboxData::new("/run/bundle");
+        assert!(!sandbox.is_ready());
+
+        let started_at = sandbox.start()?;
+        assert!(sandbox.is_ready());
+        // starting again is a no-op
+        assert_eq!(sandbox.start()?, started_at);
+
+        sandbox.add_container("app")?;
+        sandbox.add_container("sidecar")?;
+        sandbox.remove_container("sidecar");
+        assert_eq!(sandbox.containers(), ["app"]);
+
+        sandbox.stop();
+        assert!(!sandbox.is_ready());
+        assert!(sandbox.exited_at().is_some());
+        assert!(matches!(
+            sandbox.add_container("late"),
+            Err(Error::FailedPrecondition(_))
+        ));
+        assert!(matches!(sandbox.start(), Err(Error::FailedPrecondition(_))));
+
+        Ok(())
+    }
+}
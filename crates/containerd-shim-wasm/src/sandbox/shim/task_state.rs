@@ -6,6 +6,7 @@ pub(super) enum TaskState {
     Created,
     Starting,
     Started,
+    Paused,
     Exited,
     Deleting,
 }
@@ -29,6 +30,33 @@ impl TaskState {
         Ok(())
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    pub fn update(&mut self) -> Result<()> {
+        *self = match self {
+            Self::Created | Self::Started | Self::Paused => Ok(*self),
+            _ => state_transition_error(*self, "Updating"),
+        }?;
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    pub fn pause(&mut self) -> Result<()> {
+        *self = match self {
+            Self::Started => Ok(Self::Paused),
+            _ => state_transition_error(*self, Self::Paused),
+        }?;
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
+    pub fn resume(&mut self) -> Result<()> {
+        *self = match self {
+            Self::Paused => Ok(Self::Started),
+            _ => state_transition_error(*self, Self::Started),
+        }?;
+        Ok(())
+    }
+
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     pub fn delete(&mut self) -> Result<()> {
         *self = match self {
@@ -50,7 +78,7 @@ impl TaskState {
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
     pub fn stop(&mut self) -> Result<()> {
         *self = match self {
-            Self::Started | Self::Starting => Ok(Self::Exited),
+            Self::Started | Self::Starting | Self::Paused => Ok(Self::Exited),
             // This is for potential failure cases where we want delete to be able to be retried.
             Self::Deleting => Ok(Self::Exited),
             _ => state_transition_error(*self, Self::Exited),
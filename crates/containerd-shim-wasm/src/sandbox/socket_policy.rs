@@ -0,0 +1,352 @@
+//! Policy on the sockets of the guest, with `wasi:sockets`.
+//!
+//! The policy of the node is set in the shim options, e.g., in the containerd config:
+//!
+//! ```toml
+//! [plugins."io.containerd.grpc.v1.cri".containerd.runtimes.wasm.options.SocketPolicy]
+//!   Mode = "allowlist"
+//!   Allow = ["10.96.0.0/12:80", "[fd00::/8]:8000-8999"]
+//!   IpNameLookup = true
+//! ```
+//!
+//! It's the most a container can get: the sockets of a container are denied unless it opts in
+//! with the `io.runwasi.socket-policy` annotation, e.g., `{"mode": "allow", "ip_name_lookup": true}`,
+//! and it then gets the addresses that both policies allow.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+
+/// Annotation with the socket policy of a container, as JSON
+pub const SOCKET_POLICY_ANNOTATION: &str = "io.runwasi.socket-policy";
+
+/// Which addresses the guest can bind and connect to
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum SocketMode {
+    /// The guest can't create sockets
+    #[default]
+    Deny,
+    /// The guest can use any address
+    Allow,
+    /// The guest can only use the addresses of the allowlist
+    Allowlist,
+}
+
+/// The sockets that the guest can use
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct SocketPolicy {
+    #[serde(alias = "Mode")]
+    pub mode: SocketMode,
+    /// The addresses of the `allowlist` mode
+    #[serde(alias = "Allow")]
+    pub allow: Vec<AddrRule>,
+    /// Whether the guest can resolve names, with `wasi:sockets/ip-name-lookup`, whatever the mode
+    #[serde(alias = "IpNameLookup")]
+    pub ip_name_lookup: bool,
+}
+
+impl SocketPolicy {
+    /// The policy of a container, from its `io.runwasi.socket-policy` annotation
+    pub fn from_annotations(
+        annotations: Option<&HashMap<String, String>>,
+    ) -> anyhow::Result<Option<Self>> {
+        let Some(policy) = annotations.and_then(|a| a.get(SOCKET_POLICY_ANNOTATION)) else {
+            return Ok(None);
+        };
+        let policy = serde_json::from_str(policy)
+            .with_context(|| format!("invalid {SOCKET_POLICY_ANNOTATION} annotation"))?;
+        Ok(Some(policy))
+    }
+
+    /// The policy of a container with the policy of the node, and the one it opted in with.
+    /// A container without a policy can't create sockets.
+    pub fn for_container(&self, container: Option<&SocketPolicy>) -> SocketPolicy {
+        let Some(container) = container else {
+            return SocketPolicy::default();
+        };
+        let (mode, allow) = match (self.mode, container.mode) {
+            (SocketMode::Deny, _) | (_, SocketMode::Deny) => (SocketMode::Deny, vec![]),
+            (SocketMode::Allow, mode) => (mode, container.allow.clone()),
+            (SocketMode::Allowlist, SocketMode::Allow) => {
+                (SocketMode::Allowlist, self.allow.clone())
+            }
+            (SocketMode::Allowlist, SocketMode::Allowlist) => {
+                let allow = self
+                    .allow
+                    .iter()
+                    .flat_map(|node| container.allow.iter().filter_map(|c| node.intersect(c)))
+                    .collect();
+                (SocketMode::Allowlist, allow)
+            }
+        };
+        SocketPolicy {
+            mode,
+            allow,
+            ip_name_lookup: self.ip_name_lookup && container.ip_name_lookup,
+        }
+    }
+
+    /// Whether the guest can create sockets
+    pub fn is_enabled(&self) -> bool {
+        self.mode != SocketMode::Deny
+    }
+
+    /// Whether the guest can bind or connect to `addr`
+    pub fn allows(&self, addr: SocketAddr) -> bool {
+        match self.mode {
+            SocketMode::Deny => false,
+            SocketMode::Allow => true,
+            SocketMode::Allowlist => self.allow.iter().any(|rule| rule.matches(addr)),
+        }
+    }
+}
+
+/// A network, and a range of ports, e.g., `10.0.0.0/8`, `192.168.1.10:443`,
+/// or `[fd00::/8]:8000-8999`. All the ports are allowed without a port.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub struct AddrRule {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+    pub ports: RangeInclusive<u16>,
+}
+
+impl AddrRule {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    pub fn matches(&self, addr: SocketAddr) -> bool {
+        // e.g., `::ffff:10.0.0.1` for an IPv4 address on a dual-stack socket
+        self.contains(addr.ip().to_canonical()) && self.ports.contains(&addr.port())
+    }
+
+    /// The addresses of both rules, if any
+    fn intersect(&self, other: &AddrRule) -> Option<AddrRule> {
+        let (wide, narrow) = match self.prefix_len <= other.prefix_len {
+            true => (self, other),
+            false => (other, self),
+        };
+        let start = *self.ports.start().max(other.ports.start());
+        let end = *self.ports.end().min(other.ports.end());
+        if !wide.contains(narrow.addr) || start > end {
+            return None;
+        }
+        Some(AddrRule {
+            ports: start..=end,
+            ..narrow.clone()
+        })
+    }
+}
+
+impl FromStr for AddrRule {
+    type Err = anyhow::Error;
+
+    fn from_str(rule: &str) -> anyhow::Result<Self> {
+        let (net, ports) = match rule.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']') {
+                Some((net, "")) => (net, None),
+                Some((net, rest)) => match rest.strip_prefix(':') {
+                    Some(ports) => (net, Some(ports)),
+                    None => bail!("invalid address {rule:?}, expected [<ipv6>/<prefix>]:<ports>"),
+                },
+                None => bail!("invalid address {rule:?}, expected [<ipv6>/<prefix>]:<ports>"),
+            },
+            // the colons of an IPv6 network without brackets aren't a port
+            None => match rule.rsplit_once(':') {
+                Some((net, ports)) if !net.contains(':') => (net, Some(ports)),
+                _ => (rule, None),
+            },
+        };
+
+        let (addr, prefix_len) = match net.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (net, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid address {rule:?}"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .with_context(|| format!("invalid prefix length in {rule:?}"))?,
+            None => max_len,
+        };
+
+        let ports = match ports {
+            None => 0..=u16::MAX,
+            Some(ports) => {
+                let port = |port: &str| {
+                    port.parse::<u16>()
+                        .with_context(|| format!("invalid port in {rule:?}"))
+                };
+                let (start, end) = match ports.split_once('-') {
+                    Some((start, end)) => (port(start)?, port(end)?),
+                    None => (port(ports)?, port(ports)?),
+                };
+                if start > end {
+                    bail!("invalid range of ports in {rule:?}");
+                }
+                start..=end
+            }
+        };
+
+        Ok(AddrRule {
+            addr: addr.to_canonical(),
+            prefix_len,
+            ports,
+        })
+    }
+}
+
+impl TryFrom<String> for AddrRule {
+    type Error = anyhow::Error;
+
+    fn try_from(rule: String) -> anyhow::Result<Self> {
+        rule.trim().parse()
+    }
+}
+
+impl From<AddrRule> for String {
+    fn from(rule: AddrRule) -> Self {
+        rule.to_string()
+    }
+}
+
+impl fmt::Display for AddrRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let net = format!("{}/{}", self.addr, self.prefix_len);
+        let (start, end) = (*self.ports.start(), *self.ports.end());
+        match (self.addr, start, end) {
+            (_, 0, u16::MAX) => write!(f, "{net}"),
+            (IpAddr::V4(_), start, end) if start == end => write!(f, "{net}:{start}"),
+            (IpAddr::V4(_), start, end) => write!(f, "{net}:{start}-{end}"),
+            (IpAddr::V6(_), start, end) if start == end => write!(f, "[{net}]:{start}"),
+            (IpAddr::V6(_), start, end) => write!(f, "[{net}]:{start}-{end}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<AddrRule> {
+        rules.iter().map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_addr_rules() -> anyhow::Result<()> {
+        let rule: AddrRule = "10.0.0.0/8:443".parse()?;
+        assert!(rule.matches(addr("10.1.2.3:443")));
+        assert!(rule.matches(addr("[::ffff:10.1.2.3]:443")));
+        assert!(!rule.matches(addr("10.1.2.3:80")));
+        assert!(!rule.matches(addr("11.1.2.3:443")));
+
+        let rule: AddrRule = "[fd00::/8]:8000-8999".parse()?;
+        assert!(rule.matches(addr("[fd12::1]:8080")));
+        assert!(!rule.matches(addr("[fe80::1]:8080")));
+        assert_eq!(rule.to_string(), "[fd00::/8]:8000-8999");
+
+        let rule: AddrRule = "192.168.1.10".parse()?;
+        assert_eq!(rule.prefix_len, 32);
+        assert_eq!(rule.ports, 0..=u16::MAX);
+        assert_eq!("fd00::/8".parse::<AddrRule>()?.prefix_len, 8);
+        assert!("0.0.0.0/0".parse::<AddrRule>()?.matches(addr("1.1.1.1:53")));
+
+        for invalid in [
+            "10.0.0.0/33",
+            "10.0.0.0/8:http",
+            "10.0.0.0/8:90-80",
+            "[fd00::/8",
+            "example.com:80",
+        ] {
+            assert!(invalid.parse::<AddrRule>().is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_container_opts_in() -> anyhow::Result<()> {
+        let node = SocketPolicy {
+            mode: SocketMode::Allow,
+            ip_name_lookup: true,
+            ..Default::default()
+        };
+        assert!(!node.for_container(None).is_enabled());
+
+        let annotations = HashMap::from([(
+            SOCKET_POLICY_ANNOTATION.to_string(),
+            r#"{"mode": "allowlist", "allow": ["127.0.0.1:8080"]}"#.to_string(),
+        )]);
+        let container = SocketPolicy::from_annotations(Some(&annotations))?;
+        let policy = node.for_container(container.as_ref());
+        assert!(policy.allows(addr("127.0.0.1:8080")));
+        assert!(!policy.allows(addr("127.0.0.1:8081")));
+        assert!(!policy.ip_name_lookup);
+
+        let deny = SocketPolicy::default();
+        assert!(!deny.for_container(container.as_ref()).is_enabled());
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowlists_intersect() {
+        let node = SocketPolicy {
+            mode: SocketMode::Allowlist,
+            allow: rules(&["10.0.0.0/8", "192.168.0.0/16:443"]),
+            ..Default::default()
+        };
+        let container = SocketPolicy {
+            mode: SocketMode::Allowlist,
+            allow: rules(&["10.96.0.0/12:80-90", "192.168.1.0/24:80", "172.16.0.1"]),
+            ..Default::default()
+        };
+        let policy = node.for_container(Some(&container));
+        assert_eq!(policy.allow, rules(&["10.96.0.0/12:80-90"]));
+
+        let container = SocketPolicy {
+            mode: SocketMode::Allow,
+            ..Default::default()
+        };
+        assert_eq!(node.for_container(Some(&container)).allow, node.allow);
+    }
+
+    #[test]
+    fn test_invalid_annotation() {
+        let annotations = HashMap::from([(
+            SOCKET_POLICY_ANNOTATION.to_string(),
+            r#"{"mode": "allowlist", "allow": ["everything"]}"#.to_string(),
+        )]);
+        assert!(SocketPolicy::from_annotations(Some(&annotations)).is_err());
+        assert!(matches!(SocketPolicy::from_annotations(None), Ok(None)));
+    }
+}
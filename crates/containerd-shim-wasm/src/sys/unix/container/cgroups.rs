@@ -0,0 +1,201 @@
+//! The cgroup driver of a container, told from the `linux.cgroupsPath` of its spec.
+//!
+//! With the cgroupfs driver, the path is a directory of the cgroup hierarchy, e.g.,
+//! `/kubepods/burstable/pod<uid>/<id>`. With the systemd driver, e.g., on the nodes with
+//! `SystemdCgroup = true`, it's `<slice>:<prefix>:<name>`, e.g.,
+//! `kubepods-burstable-pod<uid>.slice:cri-containerd:<id>`, and the container runs in the
+//! transient scope `<prefix>-<name>.scope` of the slice.
+//!
+//! libcontainer creates the scope with the systemd D-Bus API, with the controllers of the container
+//! delegated to it, and sets the limits that systemd models as properties of the unit, writing the
+//! others in the files of the delegated cgroup. The updates of the limits go the same way, so that
+//! systemd doesn't reset them. The metrics and the OOM kills are read from the cgroup of the
+//! container process, wherever the driver placed it.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, bail};
+use containerd_shim::cgroup::update_resources;
+use libcgroups::common::{CgroupConfig, CgroupManager as _, ControllerOpt, create_cgroup_manager};
+use oci_spec::runtime::{LinuxResources, Spec};
+
+use crate::sandbox::Error as SandboxError;
+
+/// Where the cgroup of a container is, and who manages it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Cgroup {
+    /// A directory of the cgroup hierarchy, managed directly
+    Cgroupfs,
+    /// A scope of systemd, at its `<slice>:<prefix>:<name>` path, if the spec has one
+    Systemd(Option<String>),
+}
+
+impl Cgroup {
+    /// The cgroup of the container of `spec`. The `systemd_cgroup` shim option only decides
+    /// the driver of the containers without a cgroup path.
+    pub fn of(spec: Option<&Spec>, systemd_cgroup: bool) -> Result<Self> {
+        let path = spec
+            .and_then(|spec| spec.linux().as_ref())
+            .and_then(|linux| linux.cgroups_path().as_ref())
+            .map(|path| path.to_string_lossy().into_owned())
+            .filter(|path| !path.is_empty());
+        let Some(path) = path else {
+            return Ok(match systemd_cgroup {
+                true => Self::Systemd(None),
+                false => Self::Cgroupfs,
+            });
+        };
+        if !is_systemd_path(&path) {
+            if systemd_cgroup {
+                log::debug!("using the cgroupfs driver for the cgroup path {path:?}");
+            }
+            return Ok(Self::Cgroupfs);
+        }
+        let unit = systemd_unit(&path)?;
+        log::debug!("the cgroup of the container is the systemd unit {unit}");
+        Ok(Self::Systemd(Some(path)))
+    }
+
+    pub fn is_systemd(&self) -> bool {
+        matches!(self, Self::Systemd(_))
+    }
+
+    /// Apply new resource limits to the cgroup of the container `id`, whose init process is `pid`
+    pub fn update(
+        &self,
+        id: &str,
+        pid: u32,
+        resources: &LinuxResources,
+    ) -> Result<(), SandboxError> {
+        match self {
+            Self::Systemd(Some(path)) => Ok(update_scope(path, id, resources)?),
+            // the cgroup of the process, wherever the driver placed it
+            _ => Ok(update_resources(pid, resources)?),
+        }
+    }
+}
+
+/// Apply new resource limits to the scope at the systemd cgroup `path`, through systemd
+fn update_scope(path: &str, id: &str, resources: &LinuxResources) -> Result<()> {
+    let manager = create_cgroup_manager(CgroupConfig {
+        cgroup_path: PathBuf::from(path),
+        systemd_cgroup: true,
+        container_name: id.to_string(),
+    })
+    .with_context(|| format!("failed to open the systemd cgroup {path:?}"))?;
+    manager
+        .apply(&ControllerOpt {
+            resources,
+            disable_oom_killer: false,
+            oom_score_adj: None,
+            freezer_state: None,
+        })
+        .with_context(|| format!("failed to update the systemd cgroup {path:?}"))?;
+    Ok(())
+}
+
+/// Whether `path` has the `<slice>:<prefix>:<name>` form of the systemd driver,
+/// rather than being a directory of the cgroup hierarchy
+fn is_systemd_path(path: &str) -> bool {
+    !path.starts_with('/') && path.contains(':')
+}
+
+/// The scope of systemd of a `<slice>:<prefix>:<name>` path, e.g., `cri-containerd-<id>.scope`
+fn systemd_unit(path: &str) -> Result<String> {
+    let parts: Vec<_> = path.split(':').collect();
+    let [slice, prefix, name] = parts[..] else {
+        bail!("invalid systemd cgroup path {path:?}, expected <slice>:<prefix>:<name>");
+    };
+    // an empty slice is the default slice of systemd
+    if !slice.is_empty() && (!slice.ends_with(".slice") || slice.contains('/')) {
+        bail!("invalid systemd cgroup path {path:?}, {slice:?} is not a slice");
+    }
+    if name.is_empty() || name.contains('/') {
+        bail!("invalid systemd cgroup path {path:?}, the name must be a unit name");
+    }
+    // a name that is a unit already is used as is
+    if name.ends_with(".slice") || name.ends_with(".scope") {
+        return Ok(name.to_string());
+    }
+    Ok(match prefix {
+        "" => format!("{name}.scope"),
+        prefix => format!("{prefix}-{name}.scope"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::runtime::{LinuxBuilder, SpecBuilder};
+
+    use super::*;
+
+    fn spec(cgroups_path: &str) -> Spec {
+        SpecBuilder::default()
+            .linux(
+                LinuxBuilder::default()
+                    .cgroups_path(cgroups_path)
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_driver_from_the_cgroup_path() -> Result<()> {
+        let path = "kubepods-burstable-pod1234.slice:cri-containerd:abcd";
+        assert_eq!(
+            Cgroup::of(Some(&spec(path)), false)?,
+            Cgroup::Systemd(Some(path.to_string()))
+        );
+        assert_eq!(
+            Cgroup::of(Some(&spec("/kubepods/burstable/pod1234/abcd")), true)?,
+            Cgroup::Cgroupfs
+        );
+
+        // without a path, the shim options decide
+        assert_eq!(Cgroup::of(None, true)?, Cgroup::Systemd(None));
+        assert_eq!(Cgroup::of(Some(&spec("")), false)?, Cgroup::Cgroupfs);
+
+        assert!(Cgroup::of(Some(&spec("kubepods.slice:abcd")), false).is_err());
+        assert!(Cgroup::of(Some(&spec("kubepods:cri-containerd:abcd")), false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_dispatch() {
+        let resources = LinuxResources::default();
+        // a scope that doesn't exist, or a host without systemd, fails the update in systemd
+        let path = "runwasi-test.slice:runwasi-test:missing";
+        let err = Cgroup::Systemd(Some(path.to_string()))
+            .update("missing", u32::MAX, &resources)
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains(&format!("systemd cgroup {path:?}")),
+            "{err}"
+        );
+
+        // without the path of its scope, the cgroup of the process is updated, as with cgroupfs
+        for cgroup in [Cgroup::Systemd(None), Cgroup::Cgroupfs] {
+            let err = cgroup.update("missing", u32::MAX, &resources).unwrap_err();
+            assert!(!err.to_string().contains("systemd cgroup"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_systemd_unit() -> Result<()> {
+        assert_eq!(
+            systemd_unit("kubepods-burstable-pod1234.slice:cri-containerd:abcd")?,
+            "cri-containerd-abcd.scope"
+        );
+        assert_eq!(systemd_unit(":runwasi:abcd")?, "runwasi-abcd.scope");
+        assert_eq!(systemd_unit("system.slice::abcd")?, "abcd.scope");
+        assert_eq!(
+            systemd_unit("system.slice:runwasi:abcd.scope")?,
+            "abcd.scope"
+        );
+        assert!(systemd_unit("system.slice:runwasi:").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+//! Terminal of the containers created with `terminal: true`.
+//!
+//! libcontainer allocates the pty in the container, and sends its master to a console socket.
+//! The shim then copies between the master and the stdio FIFOs provided by containerd.
+
+use std::fs::{File, OpenOptions, remove_file};
+use std::io::{self, IoSliceMut, Write as _};
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+use nix::sys::socket::{ControlMessageOwned, MsgFlags, recvmsg};
+
+/// The default VEOF character of the line discipline, ^D
+const VEOF: u8 = 0x04;
+
+/// A socket waiting for libcontainer to send the pty master of a container.
+pub(super) struct ConsoleSocket {
+    path: PathBuf,
+    handle: JoinHandle<io::Result<OwnedFd>>,
+}
+
+impl ConsoleSocket {
+    /// Listen on a new console socket for the container `id`.
+    /// The socket is not in the bundle, as its path would often be too long for a unix socket.
+    pub fn listen(id: &str) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("runwasi-{id}.console"));
+        let _ = remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        let handle = thread::spawn(move || recv_master(listener));
+        Ok(Self { path, handle })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the pty master, once the container is built
+    pub fn master(self) -> io::Result<OwnedFd> {
+        let res = self
+            .handle
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("console socket thread panicked")));
+        let _ = remove_file(&self.path);
+        res
+    }
+
+    /// Stop waiting, when the container failed to build
+    pub fn cancel(self) {
+        let _ = UnixStream::connect(&self.path);
+        let _ = self.master();
+    }
+}
+
+fn recv_master(listener: UnixListener) -> io::Result<OwnedFd> {
+    let (stream, _) = listener.accept()?;
+    let mut buf = [0u8; 4096];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+    let msg = recvmsg::<()>(
+        stream.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::empty(),
+    )?;
+    let fd = msg.cmsgs()?.find_map(|cmsg| match cmsg {
+        ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+        _ => None,
+    });
+    match fd {
+        Some(fd) => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+        None => Err(io::Error::other(
+            "no pty master received on the console socket",
+        )),
+    }
+}
+
+/// The pty master of a container, connected to its stdio FIFOs.
+pub(super) struct Console {
+    master: File,
+    size: Mutex<Option<libc::winsize>>,
+}
+
+impl Console {
+    /// Copy from `stdin` to the master, and from the master to `stdout`.
+    /// When containerd closes `stdin`, the guest reads an EOF.
+    pub fn new(master: OwnedFd, stdin: &Path, stdout: &Path) -> io::Result<Self> {
+        let master = File::from(master);
+
+        if !stdin.as_os_str().is_empty() {
+            let stdin = stdin.to_path_buf();
+            let mut input = master.try_clone()?;
+            thread::spawn(move || {
+                // opening a FIFO read only blocks until the writer opens it
+                let Ok(mut stdin) = OpenOptions::new().read(true).open(stdin) else {
+                    return;
+                };
+                let _ = io::copy(&mut stdin, &mut input);
+                let _ = input.write_all(&[VEOF]);
+            });
+        }
+
+        if !stdout.as_os_str().is_empty() {
+            let stdout = stdout.to_path_buf();
+            let mut output = master.try_clone()?;
+            thread::spawn(move || {
+                let Ok(mut stdout) = OpenOptions::new().write(true).open(stdout) else {
+                    return;
+                };
+                // this fails with EIO once all the processes of the container exited
+                let _ = io::copy(&mut output, &mut stdout);
+            });
+        }
+
+        Ok(Self {
+            master,
+            size: Mutex::default(),
+        })
+    }
+
+    /// Resize the terminal, and keep the size to apply it again at start
+    pub fn resize(&self, width: u32, height: u32) -> io::Result<()> {
+        let size = libc::winsize {
+            ws_row: height as _,
+            ws_col: width as _,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        *self.size.lock().unwrap() = Some(size);
+        self.set_size(&size)
+    }
+
+    /// Apply the size of the last resize, if any
+    pub fn apply_size(&self) -> io::Result<()> {
+        match *self.size.lock().unwrap() {
+            Some(size) => self.set_size(&size),
+            None => Ok(()),
+        }
+    }
+
+    fn set_size(&self, size: &libc::winsize) -> io::Result<()> {
+        if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, size) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Console {
+    /// Hang up the processes still attached to the terminal, like closing a terminal would
+    fn drop(&mut self) {
+        let pgrp = unsafe { libc::tcgetpgrp(self.master.as_raw_fd()) };
+        if pgrp > 0 {
+            unsafe { libc::kill(-pgrp, libc::SIGHUP) };
+        }
+    }
+}
@@ -1,14 +1,18 @@
 use std::cell::RefCell;
+use std::fmt::Display;
 use std::io::Error as IoError;
 use std::mem::transmute;
 
 use anyhow::{Context, anyhow};
 use libcontainer::container::Container as YoukiContainer;
 use libcontainer::signal::Signal;
-use serde::Serialize;
+use oci_spec::runtime::LinuxResources;
 use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use zygote::{WireError, Zygote};
 
+use super::pool::ZygotePool;
+
 thread_local! {
     // The youki's Container will live in a static inside the zygote process.
     // Reserve some space for it here.
@@ -18,15 +22,48 @@ thread_local! {
 // The exposed container is just a wrapper around the zygore process
 pub struct Container(Zygote);
 
+/// A failed build of a container. Its kind crosses the zygote with its message.
+#[derive(Debug, Clone, Serialize, Deserialize, thiserror::Error)]
+#[error("{message}")]
+pub struct BuildError {
+    pub kind: BuildErrorKind,
+    message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BuildErrorKind {
+    /// The executor rejected the container, e.g., its module doesn't export its entrypoint
+    Invalid,
+    /// The container could not be built, e.g., its cgroup could not be created
+    Failed,
+}
+
+impl BuildError {
+    pub fn new(kind: BuildErrorKind, message: impl Display) -> Self {
+        let message = message.to_string();
+        Self { kind, message }
+    }
+}
+
+impl From<anyhow::Error> for BuildError {
+    /// The error of a build, of the kind of the `BuildError` it comes from, if any
+    fn from(err: anyhow::Error) -> Self {
+        let kind = err
+            .downcast_ref::<BuildError>()
+            .map_or(BuildErrorKind::Failed, |err| err.kind);
+        Self::new(kind, format!("{err:#}"))
+    }
+}
+
 // Constructor methods
 impl Container {
     pub fn build<Arg: Serialize + DeserializeOwned + 'static>(
         f: fn(Arg) -> anyhow::Result<YoukiContainer>,
         arg: Arg,
-    ) -> anyhow::Result<Self> {
-        let zygote = Zygote::global().spawn();
+    ) -> Result<Self, BuildError> {
+        let zygote = ZygotePool::global().take();
         let container = Container(zygote);
-        container.run_init(f, arg)?;
+        container.run_init(f, arg)??;
 
         Ok(container)
     }
@@ -54,6 +91,46 @@ impl Container {
     pub fn delete(&self) -> anyhow::Result<()> {
         self.run(|c, _| Ok(c.delete(true)?), ())
     }
+    pub fn pause(&self) -> anyhow::Result<()> {
+        self.run(|c, _| Ok(c.pause()?), ())
+    }
+    pub fn resume(&self) -> anyhow::Result<()> {
+        self.run(|c, _| Ok(c.resume()?), ())
+    }
+
+    /// Run `f` in the zygote, where the exec processes of the container are forked from,
+    /// after an update of the container's resources.
+    pub fn update(
+        &self,
+        f: fn(&LinuxResources) -> anyhow::Result<()>,
+        resources: &LinuxResources,
+    ) -> anyhow::Result<()> {
+        self.run_impl(
+            |_: &mut Option<YoukiContainer>,
+             (f, resources): (usize, LinuxResources)|
+             -> anyhow::Result<()> {
+                let f: fn(&LinuxResources) -> anyhow::Result<()> = unsafe { transmute(f) };
+                f(&resources)
+            },
+            (f as usize, resources.clone()),
+        )
+    }
+
+    /// Run `f` in the zygote, next to the container, to start an additional process in it.
+    /// `f` returns the PID of the started process.
+    pub fn exec<Arg: Serialize + DeserializeOwned + 'static>(
+        &self,
+        f: fn(Arg) -> anyhow::Result<i32>,
+        arg: Arg,
+    ) -> anyhow::Result<i32> {
+        self.run_impl(
+            |_: &mut Option<YoukiContainer>, (f, arg): (usize, Arg)| -> anyhow::Result<i32> {
+                let f: fn(Arg) -> anyhow::Result<i32> = unsafe { transmute(f) };
+                f(arg)
+            },
+            (f as usize, arg),
+        )
+    }
 }
 
 impl Container {
@@ -79,19 +156,25 @@ impl Container {
             .map_err(|e| anyhow!(e))
     }
 
+    /// Build the container with `f` in the zygote, which sends back the error of the build
+    /// with its kind
     fn run_init<Arg: Serialize + DeserializeOwned + 'static>(
         &self,
         f: fn(Arg) -> anyhow::Result<YoukiContainer>,
         arg: Arg,
-    ) -> anyhow::Result<()> {
+    ) -> Result<Result<(), BuildError>, BuildError> {
         self.run_impl(
-            |c: &mut Option<YoukiContainer>, (f, arg): (usize, Arg)| -> anyhow::Result<()> {
+            |c: &mut Option<YoukiContainer>,
+             (f, arg): (usize, Arg)|
+             -> anyhow::Result<Result<(), BuildError>> {
                 let f: fn(Arg) -> anyhow::Result<YoukiContainer> = unsafe { transmute(f) };
-                *c = Some(f(arg)?);
-                Ok(())
+                Ok(f(arg)
+                    .map(|container| *c = Some(container))
+                    .map_err(BuildError::from))
             },
             (f as usize, arg),
         )
+        .map_err(BuildError::from)
     }
 
     fn run<
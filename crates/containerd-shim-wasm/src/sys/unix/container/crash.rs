@@ -0,0 +1,205 @@
+//! Crash reports of the container processes.
+//!
+//! When `run_wasi` fails, e.g., on a trap of the module, or when the engine panics, the
+//! container process writes a JSON report next to the exit status: the error, the wasm
+//! backtrace when the engine provides one, the modules and the entrypoint, the resource usage
+//! of the process, and its last log records.
+//!
+//! The report is written to the bundle of the container by default, which containerd removes
+//! with the container, or to the `CrashDir` of the shim options.
+
+use std::backtrace::Backtrace;
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd as _;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::Utc;
+use nix::sys::resource::{UsageWho, getrusage};
+use nix::sys::time::{TimeVal, TimeValLike as _};
+use serde::Serialize;
+
+use crate::container::{Engine, EngineMetrics, RuntimeContext};
+use crate::sandbox::logging;
+use crate::sandbox::oci::WasmLayer;
+
+/// The report of a container process that crashed
+#[derive(Serialize, Clone, Default, Debug)]
+pub(crate) struct CrashReport {
+    pub container_id: String,
+    /// The exec id, or empty for the init process
+    pub exec_id: String,
+    pub timestamp: String,
+    /// `error` when `run_wasi` failed, `panic` when the engine panicked
+    pub kind: &'static str,
+    pub message: String,
+    /// The wasm frames of the error, from `Engine::wasm_backtrace`
+    pub wasm_backtrace: Option<String>,
+    /// The frames of the engine, on a panic
+    pub backtrace: Option<String>,
+    /// Digests of the wasm layers of the container
+    pub modules: Vec<String>,
+    pub entrypoint: Option<String>,
+    pub resources: Option<ResourceUsage>,
+    /// The latest metrics of the engine, if it reports them
+    pub engine: Option<EngineMetrics>,
+    /// The last log records of the container process
+    pub logs: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub(crate) struct ResourceUsage {
+    pub max_rss_bytes: u64,
+    pub user_cpu_seconds: f64,
+    pub system_cpu_seconds: f64,
+}
+
+impl ResourceUsage {
+    /// The resource usage of this process
+    fn current() -> Option<Self> {
+        let usage = getrusage(UsageWho::RUSAGE_SELF).ok()?;
+        let secs = |t: TimeVal| t.num_microseconds() as f64 / 1e6;
+        Some(Self {
+            // in KiB on Linux
+            max_rss_bytes: usage.max_rss() as u64 * 1024,
+            user_cpu_seconds: secs(usage.user_time()),
+            system_cpu_seconds: secs(usage.system_time()),
+        })
+    }
+}
+
+/// The name of the crash report of a container process, in the crash directory
+pub(crate) fn report_name(id: &str, exec_id: &str) -> String {
+    if exec_id.is_empty() {
+        format!("crash-{id}.json")
+    } else {
+        format!("crash-{id}-{exec_id}.json")
+    }
+}
+
+/// Writes the crash report of a container process.
+/// The crash directory is opened before the container process pivots to its rootfs,
+/// and the report is written through its file descriptor.
+#[derive(Clone)]
+pub(crate) struct CrashReporter {
+    dir: Arc<File>,
+    name: String,
+    /// The details of the process that don't change while it runs
+    base: CrashReport,
+}
+
+impl CrashReporter {
+    pub fn new(dir: impl AsRef<Path>, id: &str, exec_id: &str) -> std::io::Result<Self> {
+        let dir = File::open(dir)?;
+        let base = CrashReport {
+            container_id: id.to_string(),
+            exec_id: exec_id.to_string(),
+            ..Default::default()
+        };
+        Ok(Self {
+            dir: Arc::new(dir),
+            name: report_name(id, exec_id),
+            base,
+        })
+    }
+
+    /// Describe the modules and the entrypoint that the process runs
+    pub fn with_workload(mut self, ctx: &impl RuntimeContext, wasm_layers: &[WasmLayer]) -> Self {
+        self.base.modules = wasm_layers
+            .iter()
+            .map(|l| l.config.digest().to_string())
+            .collect();
+        self.base.entrypoint = ctx
+            .entrypoint()
+            .arg0
+            .map(|arg0| arg0.to_string_lossy().to_string());
+        self
+    }
+
+    /// A report of this process, at this time
+    pub fn report(&self, kind: &'static str, message: String) -> CrashReport {
+        CrashReport {
+            timestamp: Utc::now().to_rfc3339(),
+            kind,
+            message,
+            resources: ResourceUsage::current(),
+            logs: logging::recent_records(),
+            ..self.base.clone()
+        }
+    }
+
+    pub fn write(&self, report: &CrashReport) -> Result<()> {
+        let path =
+            PathBuf::from(format!("/proc/self/fd/{}", self.dir.as_raw_fd())).join(&self.name);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        serde_json::to_writer_pretty(file, report)?;
+        log::error!("wrote the crash report {}", self.name);
+        Ok(())
+    }
+
+    /// Write a report when the engine panics, before the previous panic hook runs
+    pub fn on_panic<E: Engine>(&self, engine: E) {
+        let reporter = self.clone();
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let payload = info.payload();
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Box<dyn Any>".to_string());
+            let message = match info.location() {
+                Some(location) => format!("{message}, at {location}"),
+                None => message,
+            };
+            let report = CrashReport {
+                backtrace: Some(Backtrace::force_capture().to_string()),
+                engine: engine.collect_metrics(),
+                ..reporter.report("panic", message)
+            };
+            if let Err(err) = reporter.write(&report) {
+                log::warn!("failed to write the crash report: {err}");
+            }
+            hook(info);
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_name() {
+        assert_eq!(report_name("app", ""), "crash-app.json");
+        assert_eq!(report_name("app", "exec-1"), "crash-app-exec-1.json");
+    }
+
+    #[test]
+    fn test_write_report() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let reporter = CrashReporter::new(dir.path(), "app", "exec-1")?;
+        log::error!("the module trapped");
+
+        let report = CrashReport {
+            wasm_backtrace: Some("0: 0x2a - app!main".to_string()),
+            ..reporter.report("error", "wasm trap: unreachable".to_string())
+        };
+        reporter.write(&report)?;
+
+        let path = dir.path().join("crash-app-exec-1.json");
+        let report: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        assert_eq!(report["container_id"], "app");
+        assert_eq!(report["exec_id"], "exec-1");
+        assert_eq!(report["kind"], "error");
+        assert_eq!(report["message"], "wasm trap: unreachable");
+        assert_eq!(report["wasm_backtrace"], "0: 0x2a - app!main");
+        assert!(report["resources"]["max_rss_bytes"].as_u64().unwrap() > 0);
+        Ok(())
+    }
+}
@@ -1,9 +1,14 @@
 use std::cell::OnceCell;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::Read;
+use std::io::{ErrorKind, Read, Write as _};
+use std::os::fd::AsRawFd as _;
 use std::os::unix::prelude::PermissionsExt;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 
 use anyhow::{Context, Result, bail};
 use libcontainer::workload::default::DefaultExecutor;
@@ -11,17 +16,35 @@ use libcontainer::workload::{
     Executor as LibcontainerExecutor, ExecutorError as LibcontainerExecutorError,
     ExecutorSetEnvsError, ExecutorValidationError,
 };
-use oci_spec::image::Platform;
+use nix::sys::signal::{SigHandler, SigSet, Signal};
 use oci_spec::runtime::Spec;
 
-use crate::container::{Engine, PathResolve, RuntimeContext, Source, WasiContext};
-use crate::sandbox::oci::WasmLayer;
+use super::crash::{CrashReport, CrashReporter};
+use super::lsm::{HostLsm, LABELS_EXIT_CODE, Labels};
+use super::sched::apply_cpu_affinity;
+use crate::container::{
+    CompileCache, Engine, ExecutionMode, Listener, PathResolve, RuntimeContext, SignalAction,
+    Source, WasiContext,
+};
+use crate::sandbox::listen::DEFAULT_SERVE_ADDR;
+use crate::sandbox::logging::LogContext;
+use crate::sandbox::oci::{self, ImageInfo, WasmLayer};
+use crate::sandbox::reload::reload_from_annotations;
+use crate::sandbox::{EnvPolicy, NativeFallbackPolicy, SocketPolicy};
+
+/// How often the metrics of the engine are collected
+const METRICS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Start of the validation errors of the executor
+const INVALID_CONTAINER: &str = "invalid container configuration";
 
 #[derive(Clone)]
 enum InnerExecutor {
     Wasm,
     Linux,
     CantHandle,
+    /// A linux container that the native fallback policy doesn't allow
+    Denied(String),
 }
 
 #[derive(Clone)]
@@ -29,8 +52,21 @@ pub(crate) struct Executor<E: Engine> {
     engine: E,
     inner: OnceCell<InnerExecutor>,
     wasm_layers: Vec<WasmLayer>,
-    platform: Platform,
+    image_info: ImageInfo,
     id: String,
+    exec_id: String,
+    metrics: Option<Arc<File>>,
+    rejections: Option<Arc<File>>,
+    env_policy: EnvPolicy,
+    socket_policy: SocketPolicy,
+    memory_reserve: Option<u64>,
+    compile_cache: Option<CompileCache>,
+    listeners: Vec<Listener>,
+    native_fallback: NativeFallbackPolicy,
+    image: String,
+    crash_reporter: Option<CrashReporter>,
+    _layer_files: Vec<Arc<File>>,
+    _compile_cache_dir: Option<Arc<File>>,
 }
 
 impl<E: Engine> LibcontainerExecutor for Executor<E> {
@@ -39,7 +75,22 @@ impl<E: Engine> LibcontainerExecutor for Executor<E> {
         // We can handle linux container. We delegate wasm container to the engine.
         match self.inner(spec) {
             InnerExecutor::CantHandle => Err(ExecutorValidationError::CantHandle(E::name())),
-            _ => Ok(()),
+            InnerExecutor::Denied(err) => Err(self.reject(&format!("{INVALID_CONTAINER}: {err}"))),
+            InnerExecutor::Wasm => {
+                let ctx = self.ctx(spec);
+                self.engine
+                    .validate_engine_config(&ctx.engine_config())
+                    .and_then(|_| EnvPolicy::from_annotations(spec.annotations().as_ref()))
+                    .and_then(|_| SocketPolicy::from_annotations(spec.annotations().as_ref()))
+                    .and_then(|_| ctx.entrypoint().check_export())
+                    .and_then(|_| Labels::from_spec(spec).check(&HostLsm))
+                    .map_err(|err| {
+                        let err = format!("{INVALID_CONTAINER}: {err}");
+                        log::error!("{err}");
+                        self.reject(&err)
+                    })
+            }
+            InnerExecutor::Linux => Ok(()),
         }
     }
 
@@ -49,17 +100,88 @@ impl<E: Engine> LibcontainerExecutor for Executor<E> {
         // Otherwise, run it as a wasm container
         match self.inner(spec) {
             InnerExecutor::CantHandle => Err(LibcontainerExecutorError::CantHandle(E::name())),
+            InnerExecutor::Denied(err) => Err(LibcontainerExecutorError::Other(err.clone())),
             InnerExecutor::Linux => {
                 log::info!("executing linux container");
                 DefaultExecutor {}.exec(spec)
             }
             InnerExecutor::Wasm => {
-                let ctx = self.ctx(spec);
-                log::info!("calling start function");
-                match self.engine.run_wasi(&ctx) {
+                LogContext::new(&self.id, &self.exec_id).set_for_process();
+                let spec = match self.apply_env_policy(spec) {
+                    Ok(spec) => spec,
+                    Err(err) => {
+                        log::info!("error applying the env policy: {err}");
+                        std::process::exit(137)
+                    }
+                };
+                let ctx = self.ctx(&spec);
+                // the mountpoints of the rootfs and the volumes get the mount label, and the threads
+                // of the engine inherit the labels of the process
+                let dirs = std::iter::once(PathBuf::from("/"))
+                    .chain(ctx.preopens().into_iter().map(|p| p.host_path))
+                    .collect::<Vec<_>>();
+                if let Err(err) = Labels::from_spec(&spec).apply(&HostLsm, &dirs) {
+                    log::error!("failed to apply the security labels of the container: {err:#}");
+                    std::process::exit(LABELS_EXIT_CODE)
+                }
+                // before the engine and the metrics start their threads, which inherit the affinity
+                if let Err(err) = apply_cpu_affinity(&spec) {
+                    log::warn!("failed to pin the engine to the cpuset of the container: {err:#}");
+                }
+                // and the blocked signals
+                let reload =
+                    reload_from_annotations(spec.annotations().as_ref()).unwrap_or_default();
+                let reloading = Arc::new(AtomicBool::new(false));
+                let reloads = match reload {
+                    true => self.dispatch_reloads(&spec, reloading.clone()),
+                    false => Ok(()),
+                };
+                if let Err(err) = reloads {
+                    log::warn!("failed to reload the module on SIGHUP: {err}");
+                }
+                if let Err(err) = dispatch_signals(self.engine.clone(), reload) {
+                    log::warn!("failed to dispatch the signals to the engine: {err}");
+                }
+                if let Some(metrics) = &self.metrics {
+                    report_metrics(self.engine.clone(), metrics.clone());
+                }
+                let crash_reporter = self
+                    .crash_reporter
+                    .clone()
+                    .map(|reporter| reporter.with_workload(&ctx, &self.wasm_layers));
+                if let Some(reporter) = &crash_reporter {
+                    reporter.on_panic(self.engine.clone());
+                }
+                let res = self.engine.execution_mode(&ctx).and_then(|mode| {
+                    // the socket is bound once, a reloaded module serves it as well
+                    let listener = match mode {
+                        ExecutionMode::Run => None,
+                        ExecutionMode::Serve => Some(serve_listener(&ctx)?),
+                    };
+                    loop {
+                        let res = match &listener {
+                            None => {
+                                log::info!("calling start function");
+                                self.engine.run_wasi(&ctx)
+                            }
+                            Some(listener) => {
+                                log::info!("serving on {}", listener.addr);
+                                self.engine.serve(&ctx, listener)
+                            }
+                        };
+                        if !reloading.swap(false, Ordering::SeqCst) {
+                            break res;
+                        }
+                        log::info!("running the reloaded module");
+                    }
+                });
+                match res {
                     Ok(code) => std::process::exit(code),
                     Err(err) => {
                         log::info!("error running start function: {err}");
+                        if let Some(reporter) = &crash_reporter {
+                            self.report_crash(reporter, &err);
+                        }
                         std::process::exit(137)
                     }
                 };
@@ -84,32 +206,214 @@ impl<E: Engine> LibcontainerExecutor for Executor<E> {
 }
 
 impl<E: Engine> Executor<E> {
-    pub fn new(engine: E, wasm_layers: Vec<WasmLayer>, platform: Platform, id: String) -> Self {
+    pub fn new(
+        engine: E,
+        mut wasm_layers: Vec<WasmLayer>,
+        image_info: ImageInfo,
+        id: String,
+    ) -> Self {
+        // The files of the large layers are in the bundle, which the container process
+        // can't see after the pivot root. Open them here, and use them through their fd.
+        let mut layer_files = vec![];
+        for layer in wasm_layers.iter_mut() {
+            let Some(path) = &layer.path else {
+                continue;
+            };
+            match File::open(path) {
+                Ok(file) => {
+                    layer.path = Some(format!("/proc/self/fd/{}", file.as_raw_fd()).into());
+                    layer_files.push(Arc::new(file));
+                }
+                Err(err) => log::warn!("failed to open layer {}: {err}", path.display()),
+            }
+        }
+
         Self {
             engine,
             inner: Default::default(),
             wasm_layers,
-            platform,
+            image_info,
             id,
+            exec_id: String::new(),
+            metrics: None,
+            rejections: None,
+            env_policy: EnvPolicy::default(),
+            socket_policy: SocketPolicy::default(),
+            memory_reserve: None,
+            compile_cache: None,
+            listeners: vec![],
+            native_fallback: NativeFallbackPolicy::default(),
+            image: String::new(),
+            crash_reporter: None,
+            _layer_files: layer_files,
+            _compile_cache_dir: None,
+        }
+    }
+
+    /// Send the metrics of the engine to the shim, as JSON lines written to `metrics`.
+    /// The file stays open in the container process, as libcontainer only marks
+    /// the file descriptors of the container as close-on-exec, and the engine doesn't exec.
+    pub fn with_metrics(mut self, metrics: File) -> Self {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Write the validation errors of the executor to `rejections`, from the container
+    /// process where libcontainer validates the container, and then only reports them as text.
+    pub fn with_rejections(mut self, rejections: File) -> Self {
+        self.rejections = Some(Arc::new(rejections));
+        self
+    }
+
+    /// Run an exec process of the container, rather than its init process
+    pub fn with_exec_id(mut self, exec_id: String) -> Self {
+        self.exec_id = exec_id;
+        self
+    }
+
+    /// Filter the environment of the modules with the policy of the node,
+    /// and the one of the container from its annotations
+    pub fn with_env_policy(mut self, policy: EnvPolicy) -> Self {
+        self.env_policy = policy;
+        self
+    }
+
+    /// Let the containers that opt in use the sockets allowed by the policy of the node
+    pub fn with_socket_policy(mut self, policy: SocketPolicy) -> Self {
+        self.socket_policy = policy;
+        self
+    }
+
+    /// Keep `reserve` bytes out of the memory limit of the guest, for the engine
+    pub fn with_memory_reserve(mut self, reserve: Option<u64>) -> Self {
+        self.memory_reserve = reserve;
+        self
+    }
+
+    /// Let the engine keep the modules it compiles in `cache`, if it caches them.
+    /// As the large layers, the directory of the cache is opened before the pivot root.
+    pub fn with_compile_cache(mut self, cache: Option<CompileCache>) -> Self {
+        let Some(cache) = cache.filter(|_| self.engine.can_cache_compiled()) else {
+            return self;
+        };
+        match cache.open() {
+            Ok((cache, dir)) => {
+                self.compile_cache = Some(cache);
+                self._compile_cache_dir = Some(Arc::new(dir));
+            }
+            Err(err) => log::warn!("failed to open the compile cache: {err:#}"),
+        }
+        self
+    }
+
+    /// Hand the sockets bound by the shim to the engine
+    pub fn with_listeners(mut self, listeners: Vec<Listener>) -> Self {
+        self.listeners = listeners;
+        self
+    }
+
+    /// Only run `image` as a native Linux container when the policy of the node allows it
+    pub fn with_native_fallback(mut self, policy: NativeFallbackPolicy, image: String) -> Self {
+        self.native_fallback = policy;
+        self.image = image;
+        self
+    }
+
+    /// Write a crash report when the engine fails or panics
+    pub fn with_crash_reporter(mut self, reporter: CrashReporter) -> Self {
+        self.crash_reporter = Some(reporter);
+        self
+    }
+
+    /// The spec with the environment allowed by the env policy
+    fn apply_env_policy(&self, spec: &Spec) -> Result<Spec> {
+        let container = EnvPolicy::from_annotations(spec.annotations().as_ref())?;
+        if self.env_policy.is_empty() && container.is_none() {
+            return Ok(spec.clone());
+        }
+        let mut spec = spec.clone();
+        if let Some(process) = spec.process_mut() {
+            let env = process.env().as_deref().unwrap_or_default();
+            let env = self.env_policy.apply(container.as_ref(), env);
+            process.set_env(Some(env));
+        }
+        Ok(spec)
+    }
+
+    /// Reload the module of the container on `SIGHUP`, for the `io.runwasi.reload-on-sighup`
+    /// annotation. The engine loads the new module, and then stops the running instance, which
+    /// the loop of `exec` runs again as `reloading` is set.
+    fn dispatch_reloads(&self, spec: &Spec, reloading: Arc<AtomicBool>) -> Result<()> {
+        let signals = SigSet::from(Signal::SIGHUP);
+        signals.thread_block()?;
+
+        let executor = self.clone();
+        let spec = spec.clone();
+        thread::spawn(move || {
+            let ctx = executor.ctx(&spec);
+            while signals.wait().is_ok() {
+                log::info!("reloading the module on SIGHUP");
+                match executor.engine.load(&ctx) {
+                    Ok(()) => {
+                        reloading.store(true, Ordering::SeqCst);
+                        executor.engine.interrupt();
+                    }
+                    Err(err) => log::error!(
+                        "failed to load the new module, the container keeps running the previous one: {err:#}"
+                    ),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn report_crash(&self, reporter: &CrashReporter, err: &anyhow::Error) {
+        let report = CrashReport {
+            wasm_backtrace: self.engine.wasm_backtrace(err),
+            engine: self.engine.collect_metrics(),
+            ..reporter.report("error", format!("{err:?}"))
+        };
+        if let Err(err) = reporter.write(&report) {
+            log::warn!("failed to write the crash report: {err}");
         }
     }
 
     fn ctx<'a>(&'a self, spec: &'a Spec) -> WasiContext<'a> {
         let wasm_layers = &self.wasm_layers;
-        let platform = &self.platform;
         WasiContext {
             spec,
             wasm_layers,
-            platform,
+            image: &self.image_info,
+            listeners: &self.listeners,
+            socket_policy: &self.socket_policy,
+            memory_reserve: self.memory_reserve,
+            compile_cache: self.compile_cache.as_ref(),
+            engine: E::name(),
             id: self.id.clone(),
         }
     }
 
+    /// The validation error `err`, also written for the zygote, which tells it from the
+    /// failures of the build by it
+    fn reject(&self, err: &str) -> ExecutorValidationError {
+        if let Some(rejections) = &self.rejections {
+            let _ = (&**rejections).write_all(err.as_bytes());
+        }
+        ExecutorValidationError::ArgValidationError(err.to_string())
+    }
+
     fn inner(&self, spec: &Spec) -> &InnerExecutor {
         self.inner.get_or_init(|| {
             let ctx = &self.ctx(spec);
             match is_linux_container(ctx) {
-                Ok(_) => InnerExecutor::Linux,
+                Ok(_) => match self.native_fallback.check(&self.image, &wasm_media_types::<E>()) {
+                    Ok(_) => InnerExecutor::Linux,
+                    Err(err) => {
+                        log::error!("{err}");
+                        InnerExecutor::Denied(err.to_string())
+                    }
+                },
                 Err(err) => {
                     log::debug!("error checking if linux container: {err}. Fallback to wasm container");
                     match self.engine.can_handle(ctx) {
@@ -126,6 +430,101 @@ impl<E: Engine> Executor<E> {
     }
 }
 
+/// The media types of the wasm layers of an image
+fn wasm_media_types<E: Engine>() -> Vec<&'static str> {
+    let mut media_types = E::supported_layers_types().to_vec();
+    for media_type in oci::WASM_ARTIFACT_LAYER_MEDIA_TYPES {
+        if !media_types.contains(media_type) {
+            media_types.push(media_type);
+        }
+    }
+    media_types
+}
+
+/// The socket served in the `http` mode, the first one bound by the shim, or a socket on the
+/// default address when the engine serves a module without the `io.runwasi.mode` annotation
+fn serve_listener(ctx: &impl RuntimeContext) -> Result<Listener> {
+    if let Some(listener) = ctx.listeners().first() {
+        return Ok(listener.clone());
+    }
+    let socket = std::net::TcpListener::bind(DEFAULT_SERVE_ADDR)
+        .with_context(|| format!("failed to listen on {DEFAULT_SERVE_ADDR}"))?;
+    Ok(Listener {
+        addr: socket.local_addr()?,
+        socket: Arc::new(socket),
+    })
+}
+
+fn report_metrics<E: Engine>(engine: E, metrics: Arc<File>) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(METRICS_INTERVAL);
+            let Some(m) = engine.collect_metrics() else {
+                break;
+            };
+            let Ok(mut line) = serde_json::to_vec(&m) else {
+                break;
+            };
+            line.push(b'\n');
+            // The FIFO is non-blocking, and a line is smaller than PIPE_BUF, so it is
+            // either written as a whole or dropped when the shim falls behind
+            match (&*metrics).write(&line) {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+    });
+}
+
+/// Call `Engine::handle_signal` for the `Engine::handled_signals` sent to the container process.
+/// The signals are blocked, and waited for in a thread, so that the engine handles them outside
+/// of a signal handler. With `reload`, `SIGHUP` reloads the module instead, see `dispatch_reloads`.
+fn dispatch_signals<E: Engine>(engine: E, reload: bool) -> Result<()> {
+    let mut signals = SigSet::empty();
+    for &signal in engine.handled_signals() {
+        match Signal::try_from(signal) {
+            Ok(Signal::SIGKILL | Signal::SIGSTOP) | Err(_) => {
+                log::warn!("the engine can't handle signal {signal}");
+            }
+            Ok(Signal::SIGHUP) if reload => {}
+            Ok(signal) => signals.add(signal),
+        }
+    }
+    if signals.iter().next().is_none() {
+        return Ok(());
+    }
+    signals.thread_block()?;
+
+    thread::spawn(move || {
+        loop {
+            let Ok(signal) = signals.wait() else {
+                break;
+            };
+            match engine.handle_signal(signal as i32) {
+                SignalAction::Handled => log::debug!("the engine handled {signal}"),
+                SignalAction::Terminate => {
+                    log::info!("terminating on {signal}");
+                    std::process::exit(128 + signal as i32);
+                }
+                SignalAction::Default => {
+                    // raise the signal again in this thread, with its default action
+                    let signal_set = SigSet::from(signal);
+                    unsafe {
+                        let _ = nix::sys::signal::signal(signal, SigHandler::SigDfl);
+                    }
+                    let _ = signal_set.thread_unblock();
+                    let _ = nix::sys::signal::raise(signal);
+                    // the default action ignores some signals, e.g., SIGWINCH
+                    let _ = signal_set.thread_block();
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
 fn is_linux_container(ctx: &impl RuntimeContext) -> Result<()> {
     if let Source::Oci(_) = ctx.entrypoint().source {
         bail!("the entry point contains wasm layers")
@@ -153,3 +552,103 @@ fn is_linux_container(ctx: &impl RuntimeContext) -> Result<()> {
         _ => bail!("not a valid script or elf file"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::image::{Descriptor, Digest, MediaType};
+    use oci_spec::runtime::{ProcessBuilder, SpecBuilder};
+
+    use super::*;
+    use crate::sandbox::NativeFallback;
+
+    #[derive(Clone, Default)]
+    struct EngineStub;
+
+    impl Engine for EngineStub {
+        fn name() -> &'static str {
+            "stub"
+        }
+        fn run_wasi(&self, _ctx: &impl RuntimeContext) -> Result<i32> {
+            Ok(0)
+        }
+    }
+
+    fn new_executor(
+        image: &str,
+        wasm: bool,
+        policy: NativeFallbackPolicy,
+    ) -> Result<Executor<EngineStub>> {
+        let layers = match wasm {
+            true => vec![WasmLayer {
+                config: Descriptor::new(
+                    MediaType::Other("application/wasm".to_string()),
+                    0,
+                    Digest::try_from(format!("sha256:{:064?}", 0))?,
+                ),
+                layer: vec![],
+                path: None,
+            }],
+            false => vec![],
+        };
+        Ok(
+            Executor::new(EngineStub, layers, ImageInfo::default(), "test".to_string())
+                .with_native_fallback(policy, image.to_string()),
+        )
+    }
+
+    #[test]
+    fn test_native_fallback() -> Result<()> {
+        // the entrypoint of a linux image is an ELF executable or a script
+        let spec = SpecBuilder::default()
+            .process(
+                ProcessBuilder::default()
+                    .args(vec!["/bin/sh".to_string()])
+                    .build()?,
+            )
+            .build()?;
+        let linux = "docker.io/library/alpine:latest";
+        let wasm = "ghcr.io/containerd/runwasi/wasi-demo-app:latest";
+
+        let policy = |mode| NativeFallbackPolicy {
+            mode,
+            registries: vec!["ghcr.io".to_string()],
+        };
+
+        for mode in [
+            NativeFallback::Allow,
+            NativeFallback::Deny,
+            NativeFallback::RegistryAllowlist,
+        ] {
+            // wasm images never fall back
+            let executor = new_executor(wasm, true, policy(mode))?;
+            assert!(matches!(executor.inner(&spec), InnerExecutor::Wasm));
+            assert!(executor.validate(&spec).is_ok());
+
+            let executor = new_executor(linux, false, policy(mode))?;
+            match mode {
+                NativeFallback::Allow => {
+                    assert!(matches!(executor.inner(&spec), InnerExecutor::Linux));
+                }
+                _ => {
+                    let Err(ExecutorValidationError::ArgValidationError(err)) =
+                        executor.validate(&spec)
+                    else {
+                        panic!("the {mode:?} native fallback allowed {linux}");
+                    };
+                    assert!(err.contains(linux));
+                    assert!(err.contains("application/vnd.wasm.content.layer.v1+wasm"));
+                }
+            }
+        }
+
+        // a linux image from an allowed registry
+        let executor = new_executor(
+            "ghcr.io/containerd/busybox",
+            false,
+            policy(NativeFallback::RegistryAllowlist),
+        )?;
+        assert!(matches!(executor.inner(&spec), InnerExecutor::Linux));
+
+        Ok(())
+    }
+}
@@ -1,81 +1,395 @@
-use std::marker::PhantomData;
-use std::path::Path;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Read as _;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
 
 use chrono::{DateTime, Utc};
+use containerd_shim::monitor::{Topic, monitor_subscribe};
+use libcontainer::container::Container as YoukiContainer;
 use libcontainer::container::builder::ContainerBuilder;
 use libcontainer::syscall::syscall::SyscallType;
+use nix::fcntl::OFlag;
+use nix::sys::signal::{Signal, kill};
 use nix::sys::wait::WaitStatus;
-use oci_spec::image::Platform;
+use nix::unistd::{Pid, pipe2};
+use oci_spec::runtime::{Hooks, LinuxResources, Spec};
 
-use super::container::Container;
-use crate::container::Engine;
+use super::cgroups::Cgroup;
+use super::console::{Console, ConsoleSocket};
+use super::container::{BuildError, BuildErrorKind, Container};
+use super::crash::{CrashReporter, report_name};
+use super::listeners;
+use super::sched::update_cpu_affinity;
+use crate::container::{
+    CompileCache, Engine, EngineConfig, ExecutionMode, is_reserved_signal, resume_signal,
+    suspend_signal,
+};
 use crate::sandbox::async_utils::AmbientRuntime as _;
+use crate::sandbox::host_dirs::mount_host_dirs;
 use crate::sandbox::instance_utils::determine_rootdir;
+use crate::sandbox::listen::{listen_addrs, mode_from_annotations};
+use crate::sandbox::logging::{self, LogContext};
+use crate::sandbox::native_fallback::IMAGE_NAME_ANNOTATION;
+use crate::sandbox::oci::{ImageInfo, WasmLayer};
+use crate::sandbox::reload::reload_from_annotations;
 use crate::sandbox::sync::WaitableCell;
 use crate::sandbox::{
-    Error as SandboxError, Instance as SandboxInstance, InstanceConfig, containerd,
+    EngineInfo, EngineMetrics, EnvPolicy, Error as SandboxError, ExecConfig,
+    Instance as SandboxInstance, InstanceConfig, NativeFallback, NativeFallbackPolicy,
+    SocketPolicy, containerd,
 };
 use crate::sys::container::executor::Executor;
+use crate::sys::container::pool::ZygotePool;
+use crate::sys::container::userns::UserNamespace;
+use crate::sys::metrics::EngineMetricsReader;
+use crate::sys::oom::OomWatcher;
 use crate::sys::pid_fd::PidFd;
-use crate::sys::stdio::open;
+use crate::sys::stdio::{InputRelay, OutputRelay, Stream, open_input, open_output};
 
 const DEFAULT_CONTAINER_ROOT_DIR: &str = "/run/containerd";
 
+type ExitCode = WaitableCell<(u32, DateTime<Utc>)>;
+
 pub struct Instance<E: Engine> {
-    exit_code: WaitableCell<(u32, DateTime<Utc>)>,
+    exit_code: ExitCode,
     container: Container,
     id: String,
-    _phantom: PhantomData<E>,
+    rootdir: PathBuf,
+    bundle: PathBuf,
+    modules: Vec<WasmLayer>,
+    image_info: ImageInfo,
+    env_policy: EnvPolicy,
+    socket_policy: SocketPolicy,
+    memory_reserve: Option<u64>,
+    compile_cache: Option<CompileCache>,
+    native_fallback: NativeFallbackPolicy,
+    image: String,
+    execs: Mutex<HashMap<String, (i32, ExitCode)>>,
+    console: Option<Console>,
+    stdin: Option<InputRelay>,
+    stdout: Option<OutputRelay>,
+    stderr: Option<OutputRelay>,
+    metrics: EngineMetricsReader,
+    oom: tokio::sync::Mutex<Option<OomWatcher>>,
+    /// Where the container processes write their crash reports
+    crash_dir: PathBuf,
+    /// The cgroup of the container, and its driver
+    cgroup: Cgroup,
+    engine: E,
 }
 
 impl<E: Engine + Default> SandboxInstance for Instance<E> {
     #[cfg_attr(feature = "tracing", tracing::instrument(level = "Info"))]
     async fn new(id: String, cfg: &InstanceConfig) -> Result<Self, SandboxError> {
         // check if container is OCI image with wasm layers and attempt to read the module
-        let (modules, platform) = containerd::Client::connect(&cfg.containerd_address, &cfg.namespace).await?
-            .load_modules(&id, &E::default())
+        // the engine configuration of the container decides if it can use the precompiled layers
+        let engine = shared_engine::<E>();
+        let mut spec = Spec::load(cfg.bundle.join("config.json")).ok();
+        // the directories of the node in the shim options, that the annotations can't change,
+        // every time the container is created, from the spec of the bundle that stays as it is
+        let mut spec_bundle = cfg.bundle.clone();
+        if let Some(spec) = &mut spec {
+            let mounted = mount_host_dirs(spec, &cfg.config.host_dirs)
+                .map_err(|err| SandboxError::InvalidArgument(err.to_string()))?;
+            let unhooked = remove_shim_hooks(spec);
+            if mounted || unhooked {
+                spec_bundle = save_container_spec(&cfg.bundle, spec)?;
+            }
+        }
+        let annotations = spec.as_ref().and_then(|spec| spec.annotations().as_ref());
+        let engine_config = EngineConfig::for_engine(E::name(), annotations);
+        let cgroup = Cgroup::of(spec.as_ref(), cfg.config.systemd_cgroup)
+            .map_err(|err| SandboxError::InvalidSpec(format!("{err:#}")))?;
+
+        let mode = mode_from_annotations(annotations)
+            .map_err(|err| SandboxError::InvalidArgument(err.to_string()))?;
+        if mode == Some(ExecutionMode::Serve) && !engine.can_serve() {
+            return Err(SandboxError::Unsupported(format!(
+                "the {} engine does not serve requests",
+                E::name()
+            )));
+        }
+        let reload = reload_from_annotations(annotations)
+            .map_err(|err| SandboxError::InvalidArgument(err.to_string()))?;
+        if reload && !engine.can_reload() {
+            return Err(SandboxError::Unsupported(format!(
+                "the {} engine does not reload modules",
+                E::name()
+            )));
+        }
+
+        // the shim binds the sockets of the container, and keeps them open across its restarts
+        let addrs = listen_addrs(annotations)
+            .map_err(|err| SandboxError::InvalidArgument(err.to_string()))?;
+        let listeners = match &spec {
+            Some(spec) => listeners::bind(spec, &addrs)?,
+            None => vec![],
+        };
+
+        // in a user namespace, the root of the container owns its rootfs
+        let userns = spec
+            .as_ref()
+            .map(UserNamespace::from_spec)
+            .transpose()
+            .map_err(|err| SandboxError::InvalidSpec(err.to_string()))?
+            .flatten();
+        if let (Some(userns), Some(root)) = (&userns, spec.as_ref().and_then(|s| s.root().as_ref()))
+        {
+            userns.remap_rootfs(&cfg.bundle.join(root.path()))?;
+        }
+
+        // large layers are kept in files in the bundle, instead of in the memory of the shim
+        let client = containerd::Client::connect(&cfg.containerd_address, &cfg.namespace)
+            .await?
+            .with_layers_dir(cfg.bundle.join("layers"))
+            .with_engine_config(engine_config);
+
+        // the image decides whether the container can run as a native Linux container
+        let native_fallback = cfg.config.native_fallback_policy();
+        let mut image = annotations
+            .and_then(|a| a.get(IMAGE_NAME_ANNOTATION))
+            .cloned()
+            .unwrap_or_default();
+        if image.is_empty() && native_fallback.mode != NativeFallback::Allow {
+            image = client.container_image(&id).await.unwrap_or_else(|err| {
+                log::warn!("failed to get the image of container {id}: {err}");
+                String::new()
+            });
+        }
+
+        let (modules, image_info) = client
+            .load_modules(&id, &engine)
             .await
             .unwrap_or_else(|e| {
                 log::warn!("Error obtaining wasm layers for container {id}.  Will attempt to use files inside container image. Error: {e}");
-                (vec![], Platform::default())
+                (vec![], ImageInfo::default())
             });
 
-        let container = Container::build(
-            |(id, cfg, modules, platform)| {
-                let rootdir = Path::new(DEFAULT_CONTAINER_ROOT_DIR).join(E::name());
-                let rootdir = determine_rootdir(&cfg.bundle, &cfg.namespace, rootdir)?;
-                let engine = E::default();
+        let rootdir = Path::new(DEFAULT_CONTAINER_ROOT_DIR).join(E::name());
+        let rootdir = determine_rootdir(&cfg.bundle, &cfg.namespace, rootdir)?;
 
-                let mut builder = ContainerBuilder::new(id.clone(), SyscallType::Linux)
-                    .with_executor(Executor::new(engine, modules, platform, id))
-                    .with_root_path(rootdir.clone())?;
+        // libcontainer sends the pty master to the console socket while building the container
+        let console = cfg
+            .terminal
+            .then(|| ConsoleSocket::listen(&id))
+            .transpose()?;
+        let console_socket = console.as_ref().map(|c| c.path().to_path_buf());
 
-                if let Ok(f) = open(&cfg.stdin) {
-                    builder = builder.with_stdin(f);
-                }
-                if let Ok(f) = open(&cfg.stdout) {
-                    builder = builder.with_stdout(f);
-                }
-                if let Ok(f) = open(&cfg.stderr) {
-                    builder = builder.with_stderr(f);
+        // Without a terminal, the FIFOs from containerd are relayed through FIFOs of the shim,
+        // so that the container's output survives a restart of their reader.
+        // The files and the null device are the stdio of the container as they are.
+        let relayed = |fifo: &Path| !cfg.terminal && Stream::of(fifo) == Stream::Fifo;
+        let stdin = relayed(&cfg.stdin)
+            .then(|| InputRelay::new(&cfg.stdin, cfg.bundle.join("stdin.relay")))
+            .transpose()?;
+        let mut stdout = relayed(&cfg.stdout)
+            .then(|| OutputRelay::new(&cfg.stdout, cfg.bundle.join("stdout.relay")))
+            .transpose()?;
+        let mut stderr = relayed(&cfg.stderr)
+            .then(|| OutputRelay::new(&cfg.stderr, cfg.bundle.join("stderr.relay")))
+            .transpose()?;
+
+        // the container process sends the metrics of the engine to this FIFO
+        let mut metrics = EngineMetricsReader::new(cfg.bundle.join("metrics.fifo"))?;
+
+        // a report left by a former container of the same id isn't about this one
+        let reports = crash_dir(cfg);
+        std::fs::create_dir_all(&reports)?;
+        let _ = std::fs::remove_file(reports.join(report_name(&id, "")));
+
+        let mut container_cfg = cfg.clone();
+        if let Some(relay) = &stdin {
+            container_cfg.stdin = relay.path().into();
+        }
+        if let Some(relay) = &stdout {
+            container_cfg.stdout = relay.path().into();
+        }
+        if let Some(relay) = &stderr {
+            container_cfg.stderr = relay.path().into();
+        }
+        // libcontainer picks its cgroup manager from the flag
+        container_cfg.config.systemd_cgroup = cgroup.is_systemd();
+
+        if let Some(size) = cfg.config.zygote_pool_size {
+            ZygotePool::global().resize(size);
+        }
+
+        // a former create of the id that missed its deadline may have left its state behind
+        remove_stale_container(&rootdir.join(&id));
+
+        let args = (
+            id.clone(),
+            container_cfg,
+            rootdir.clone(),
+            console_socket,
+            metrics.path().to_path_buf(),
+            modules.clone(),
+            image_info.clone(),
+            // the level of the shim when the container is created
+            log::max_level().to_string(),
+            (std::process::id(), listeners::export(&listeners)),
+            image.clone(),
+            spec_bundle,
+        );
+
+        // the build blocks on the zygote, on a blocking thread so that it doesn't hold
+        // the deadline of the create
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!("build container");
+        let container = tokio::task::spawn_blocking(move || {
+            // libcontainer sets up the rootfs, the namespaces and the cgroup in the zygote
+            #[cfg(feature = "tracing")]
+            let _span = span.entered();
+            Container::build(
+                |(
+                    id,
+                    cfg,
+                    rootdir,
+                    console_socket,
+                    metrics,
+                    modules,
+                    image_info,
+                    log_level,
+                    (shim_pid, listeners),
+                    image,
+                    spec_bundle,
+                )| {
+                    // this runs in the zygote of the container, where its processes are forked from
+                    if let Some(format) = cfg.config.log_format {
+                        logging::set_format(format);
+                    }
+                    if let Ok(level) = logging::parse_level(&log_level) {
+                        log::set_max_level(level);
+                    }
+                    LogContext::new(&id, "").set_for_process();
+
+                    let engine = E::default();
+                    let listeners = listeners::import(shim_pid, listeners)?;
+
+                    let mut executor = Executor::new(engine, modules, image_info, id.clone())
+                        .with_env_policy(cfg.config.env_policy.clone())
+                        .with_socket_policy(cfg.config.socket_policy.clone())
+                        .with_memory_reserve(cfg.config.memory_reserve)
+                        .with_compile_cache(cfg.config.compile_cache(E::name()))
+                        .with_native_fallback(cfg.config.native_fallback_policy(), image)
+                        .with_listeners(listeners);
+                    // non-blocking, so that the engine never waits for the shim
+                    match OpenOptions::new()
+                        .write(true)
+                        .custom_flags(libc::O_NONBLOCK)
+                        .open(&metrics)
+                    {
+                        Ok(f) => executor = executor.with_metrics(f),
+                        Err(err) => log::warn!("failed to open the engine metrics FIFO: {err}"),
+                    }
+                    match CrashReporter::new(crash_dir(&cfg), &id, "") {
+                        Ok(reporter) => executor = executor.with_crash_reporter(reporter),
+                        Err(err) => log::warn!("failed to open the crash directory: {err}"),
+                    }
+                    // the container process reports the validation errors of the executor here
+                    let (rejections, rejected) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+                    executor = executor.with_rejections(File::from(rejected));
+
+                    let mut builder = ContainerBuilder::new(id, SyscallType::Linux)
+                        .with_executor(executor)
+                        .with_root_path(rootdir)?;
+
+                    // with a terminal, the stdio of the container is the pty
+                    if console_socket.is_none() {
+                        // the relay is read only, so that the container reads an EOF
+                        // when the shim closes its input
+                        let stdin = match Stream::of(&cfg.stdin) {
+                            Stream::Fifo => File::open(&cfg.stdin)?,
+                            _ => open_input(&cfg.stdin)?,
+                        };
+                        builder = builder
+                            .with_stdin(stdin)
+                            .with_stdout(open_output(&cfg.stdout)?)
+                            .with_stderr(open_output(&cfg.stderr)?);
+                    }
+
+                    let container = builder
+                        .with_console_socket(console_socket)
+                        .as_init(&spec_bundle)
+                        .as_sibling(true)
+                        .with_systemd(cfg.config.systemd_cgroup)
+                        .build();
+
+                    // the container process ended once the build failed
+                    let mut rejection = String::new();
+                    let _ = File::from(rejections).read_to_string(&mut rejection);
+                    match container {
+                        Ok(container) => Ok(container),
+                        Err(_) if !rejection.is_empty() => {
+                            Err(BuildError::new(BuildErrorKind::Invalid, rejection).into())
+                        }
+                        Err(err) => Err(err.into()),
+                    }
+                },
+                args,
+            )
+        })
+        .await
+        .unwrap_or_else(|err| {
+            Err(BuildError::new(
+                BuildErrorKind::Failed,
+                format!("failed to build the container: {err}"),
+            ))
+        });
+
+        let container = match container {
+            Ok(container) => container,
+            Err(err) => {
+                if let Some(console) = console {
+                    console.cancel();
                 }
+                return Err(build_error(err));
+            }
+        };
 
-                let container = builder
-                    .as_init(&cfg.bundle)
-                    .as_sibling(true)
-                    .with_systemd(cfg.config.systemd_cgroup)
-                    .build()?;
+        let console = match console {
+            Some(socket) => Some(Console::new(socket.master()?, &cfg.stdin, &cfg.stdout)?),
+            None => None,
+        };
 
-                Ok(container)
-            },
-            (id.clone(), cfg.clone(), modules, platform),
-        )?;
+        if let Some(relay) = &stdin {
+            relay.attached();
+        }
+        if let Some(relay) = &mut stdout {
+            relay.attached();
+        }
+        if let Some(relay) = &mut stderr {
+            relay.attached();
+        }
+        metrics.attached();
 
         Ok(Self {
             id,
             exit_code: WaitableCell::new(),
             container,
-            _phantom: Default::default(),
+            rootdir,
+            bundle: cfg.bundle.clone(),
+            modules,
+            image_info,
+            env_policy: cfg.config.env_policy.clone(),
+            socket_policy: cfg.config.socket_policy.clone(),
+            memory_reserve: cfg.config.memory_reserve,
+            compile_cache: cfg.config.compile_cache(E::name()),
+            native_fallback,
+            image,
+            execs: Mutex::default(),
+            console,
+            stdin,
+            stdout,
+            stderr,
+            metrics,
+            oom: Default::default(),
+            crash_dir: reports,
+            cgroup,
+            engine,
         })
     }
 
@@ -95,6 +409,17 @@ impl<E: Engine + Default> SandboxInstance for Instance<E> {
         // miss the SIGCHLD event.
         let pidfd = PidFd::new(pid)?;
 
+        // the memory cgroup of the container exists once it's created
+        match OomWatcher::new(pid as u32) {
+            Ok(watcher) => *self.oom.lock().await = Some(watcher),
+            Err(err) => log::debug!("not watching the OOM kills of {}: {err}", self.id),
+        }
+
+        // apply the size of a resize before start
+        if let Some(console) = &self.console {
+            console.apply_size()?;
+        }
+
         self.container.start()?;
 
         let exit_code = self.exit_code.clone();
@@ -102,18 +427,7 @@ impl<E: Engine + Default> SandboxInstance for Instance<E> {
             // move the exit code guard into this task
             let _guard = guard;
 
-            let status = match pidfd.wait().await {
-                Ok(WaitStatus::Exited(_, status)) => status,
-                Ok(WaitStatus::Signaled(_, sig, _)) => 128 + sig as i32,
-                Ok(res) => {
-                    log::error!("waitpid unexpected result: {res:?}");
-                    137
-                }
-                Err(e) => {
-                    log::error!("waitpid failed: {e}");
-                    137
-                }
-            } as u32;
+            let status = exit_status(pidfd.wait().await);
             let _ = exit_code.set((status, Utc::now()));
         }
         .spawn();
@@ -125,15 +439,146 @@ impl<E: Engine + Default> SandboxInstance for Instance<E> {
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
     async fn kill(&self, signal: u32) -> Result<(), SandboxError> {
         log::info!("sending signal {signal} to instance: {}", self.id);
+        reject_reserved_signal(signal)?;
         self.container.kill(signal)?;
         Ok(())
     }
 
+    /// Close the input of the instance, which reads an EOF
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn close_stdin(&self) -> Result<(), SandboxError> {
+        if self.console.is_some() {
+            return Err(SandboxError::Unsupported(
+                "closing the input of a terminal is not supported".to_string(),
+            ));
+        }
+        if let Some(stdin) = &self.stdin {
+            log::info!("closing the stdin of instance: {}", self.id);
+            stdin.close();
+        }
+        Ok(())
+    }
+
+    /// Relay the stdio of the instance to the FIFOs of a new client. The container keeps
+    /// the relays of the shim, so only the relayed FIFOs can be replaced.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn attach_stdio(
+        &self,
+        stdin: &Path,
+        stdout: &Path,
+        stderr: &Path,
+    ) -> Result<(), SandboxError> {
+        if self.console.is_some() {
+            return Err(SandboxError::Unsupported(
+                "attaching the stdio of a terminal is not supported".to_string(),
+            ));
+        }
+        for (name, path, relayed) in [
+            ("stdin", stdin, self.stdin.is_some()),
+            ("stdout", stdout, self.stdout.is_some()),
+            ("stderr", stderr, self.stderr.is_some()),
+        ] {
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+            if !relayed || Stream::of(path) != Stream::Fifo {
+                return Err(SandboxError::InvalidArgument(format!(
+                    "can't attach {path:?} to the {name} of instance {}, only a FIFO replaces a FIFO",
+                    self.id
+                )));
+            }
+        }
+        log::info!("attaching new stdio to instance: {}", self.id);
+        if let Some(relay) = self
+            .stdin
+            .as_ref()
+            .filter(|_| !stdin.as_os_str().is_empty())
+        {
+            relay.attach_fifo(stdin)?;
+        }
+        if let Some(relay) = self
+            .stdout
+            .as_ref()
+            .filter(|_| !stdout.as_os_str().is_empty())
+        {
+            relay.attach_fifo(stdout);
+        }
+        if let Some(relay) = self
+            .stderr
+            .as_ref()
+            .filter(|_| !stderr.as_os_str().is_empty())
+        {
+            relay.attach_fifo(stderr);
+        }
+        Ok(())
+    }
+
+    /// Resize the terminal of the instance
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn resize_pty(&self, width: u32, height: u32) -> Result<(), SandboxError> {
+        let Some(console) = &self.console else {
+            return Err(SandboxError::InvalidArgument(format!(
+                "instance {} has no terminal",
+                self.id
+            )));
+        };
+        console.resize(width, height)?;
+        Ok(())
+    }
+
+    /// Apply new resource limits to the cgroup of the instance, then let the engine know
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn update(&self, resources: &LinuxResources) -> Result<(), SandboxError> {
+        log::info!("updating resources of instance: {}", self.id);
+        let pid = self.container.pid()?;
+        // errors from the kernel, e.g., EBUSY when lowering the memory limit
+        // below the current usage in cgroup v1, are returned as is
+        self.cgroup.update(&self.id, pid as u32, resources)?;
+        if let Err(err) = update_cpu_affinity(pid, resources) {
+            log::warn!(
+                "failed to pin instance {} to its new cpuset: {err:#}",
+                self.id
+            );
+        }
+        self.container
+            .update(
+                |resources| E::default().on_resources_updated(resources),
+                resources,
+            )
+            .map_err(SandboxError::EngineFailure)?;
+        Ok(())
+    }
+
+    /// Suspend the instance, with the cgroup freezer or with the engine
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn pause(&self) -> Result<(), SandboxError> {
+        log::info!("pausing instance: {}", self.id);
+        if self.engine.can_suspend() {
+            send_signal(self.container.pid()?, suspend_signal())?;
+        } else {
+            self.container.pause()?;
+        }
+        Ok(())
+    }
+
+    /// Resume the instance after a pause
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn resume(&self) -> Result<(), SandboxError> {
+        log::info!("resuming instance: {}", self.id);
+        if self.engine.can_suspend() {
+            send_signal(self.container.pid()?, resume_signal())?;
+        } else {
+            self.container.resume()?;
+        }
+        Ok(())
+    }
+
     /// Delete any reference to the instance
     /// This is called after the instance has exited.
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
     async fn delete(&self) -> Result<(), SandboxError> {
         log::info!("deleting instance: {}", self.id);
+        self.log_crash_report("");
         self.container.delete()?;
         Ok(())
     }
@@ -145,4 +590,445 @@ impl<E: Engine + Default> SandboxInstance for Instance<E> {
     async fn wait(&self) -> (u32, DateTime<Utc>) {
         *self.exit_code.wait().await
     }
+
+    /// Start an exec process as a tenant of the container.
+    /// The process runs the engine against the modules loaded for the container.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, cfg), level = "Info")
+    )]
+    async fn exec(&self, exec_id: &str, cfg: &ExecConfig) -> Result<u32, SandboxError> {
+        if !self.engine.can_exec() {
+            return Err(SandboxError::Unsupported(format!(
+                "the {} engine does not support exec",
+                E::name()
+            )));
+        }
+
+        log::info!("starting exec {exec_id} in instance: {}", self.id);
+
+        // libcontainer reads the process of a tenant from a file
+        let process = self.bundle.join(format!("exec-{exec_id}.json"));
+        std::fs::write(&process, serde_json::to_vec(&cfg.process)?)?;
+
+        // The process is started in `container.exec`, and it might exit and be reaped
+        // before we open its pidfd. Subscribe to the exits before starting it.
+        let subs = monitor_subscribe(Topic::Pid)?;
+
+        let res = self.container.exec(
+            |(
+                id,
+                exec_id,
+                rootdir,
+                process,
+                cfg,
+                modules,
+                image_info,
+                (env_policy, socket_policy, memory_reserve, compile_cache),
+                (native_fallback, image, crash_dir),
+            )| {
+                let engine = E::default();
+
+                // exec processes follow the env and socket policies, the memory reserve,
+                // the compile cache and the native fallback of the container
+                let mut executor = Executor::new(engine, modules, image_info, id.clone())
+                    .with_env_policy(env_policy)
+                    .with_socket_policy(socket_policy)
+                    .with_memory_reserve(memory_reserve)
+                    .with_compile_cache(compile_cache)
+                    .with_native_fallback(native_fallback, image);
+                match CrashReporter::new(crash_dir, &id, &exec_id) {
+                    Ok(reporter) => executor = executor.with_crash_reporter(reporter),
+                    Err(err) => log::warn!("failed to open the crash directory: {err}"),
+                }
+                let executor = executor.with_exec_id(exec_id);
+                let mut builder = ContainerBuilder::new(id.clone(), SyscallType::Linux)
+                    .with_executor(executor)
+                    .with_root_path(rootdir)?;
+
+                builder = builder
+                    .with_stdin(open_input(&cfg.stdin)?)
+                    .with_stdout(open_output(&cfg.stdout)?)
+                    .with_stderr(open_output(&cfg.stderr)?);
+
+                let pid = builder
+                    .as_tenant()
+                    .with_process(Some(process))
+                    .with_detach(true)
+                    .as_sibling(true)
+                    .build()?;
+
+                Ok(pid.as_raw())
+            },
+            (
+                self.id.clone(),
+                exec_id.to_string(),
+                self.rootdir.clone(),
+                process.clone(),
+                cfg.clone(),
+                self.modules.clone(),
+                self.image_info.clone(),
+                (
+                    self.env_policy.clone(),
+                    self.socket_policy.clone(),
+                    self.memory_reserve,
+                    self.compile_cache.clone(),
+                ),
+                (
+                    self.native_fallback.clone(),
+                    self.image.clone(),
+                    self.crash_dir.clone(),
+                ),
+            ),
+        );
+        let _ = std::fs::remove_file(&process);
+        let pid = res?;
+
+        let exit_code = ExitCode::new();
+        // make sure we have an exit code by the time we finish (even if there's a panic)
+        let guard = exit_code.clone().set_guard_with(|| (137, Utc::now()));
+
+        let pidfd = PidFd::with_subscription(pid, subs)?;
+
+        self.execs
+            .lock()
+            .unwrap()
+            .insert(exec_id.to_string(), (pid, exit_code.clone()));
+
+        async move {
+            // move the exit code guard into this task
+            let _guard = guard;
+
+            let status = exit_status(pidfd.wait().await);
+            let _ = exit_code.set((status, Utc::now()));
+        }
+        .spawn();
+
+        Ok(pid as _)
+    }
+
+    /// Send a signal to an exec process
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn kill_exec(&self, exec_id: &str, signal: u32) -> Result<(), SandboxError> {
+        log::info!(
+            "sending signal {signal} to exec {exec_id} in instance: {}",
+            self.id
+        );
+        let pid = self.exec_pid(exec_id)?.0;
+        reject_reserved_signal(signal)?;
+        let signal = Signal::try_from(signal as i32).map_err(|_| {
+            SandboxError::InvalidArgument(format!("invalid signal number {signal}"))
+        })?;
+        kill(Pid::from_raw(pid), signal)?;
+        Ok(())
+    }
+
+    /// Waits for an exec process to finish and returns its exit code
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Info"))]
+    async fn wait_exec(&self, exec_id: &str) -> Result<(u32, DateTime<Utc>), SandboxError> {
+        let exit_code = self.exec_pid(exec_id)?.1;
+        let res = *exit_code.wait().await;
+        self.execs.lock().unwrap().remove(exec_id);
+        self.log_crash_report(exec_id);
+        Ok(res)
+    }
+
+    /// Waits for an OOM kill in the memory cgroup of the instance
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn wait_oom(&self) -> Result<(), SandboxError> {
+        let mut oom = self.oom.lock().await;
+        let Some(watcher) = oom.as_mut() else {
+            return Err(SandboxError::Unsupported(format!(
+                "the OOM kills of {} are not watched",
+                self.id
+            )));
+        };
+        Ok(watcher.wait().await?)
+    }
+
+    /// Latest metrics sent by the engine running the instance
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    async fn engine_metrics(&self) -> Option<EngineMetrics> {
+        self.metrics.latest()
+    }
+
+    fn engine_info() -> EngineInfo {
+        let engine = shared_engine::<E>();
+        EngineInfo {
+            name: E::name().to_string(),
+            version: E::version().map(str::to_string),
+            features: E::features().iter().map(|f| f.to_string()).collect(),
+            precompile: engine.can_precompile().is_some(),
+            media_types: E::supported_layers_types()
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl<E: Engine> Instance<E> {
+    /// Log the crash report of a process of the container, if it crashed
+    fn log_crash_report(&self, exec_id: &str) {
+        let report = self.crash_dir.join(report_name(&self.id, exec_id));
+        if report.exists() {
+            let task = if exec_id.is_empty() {
+                &self.id
+            } else {
+                exec_id
+            };
+            log::error!(
+                "task {task} of container {} crashed, see its crash report {}",
+                self.id,
+                report.display()
+            );
+        }
+    }
+
+    fn exec_pid(&self, exec_id: &str) -> Result<(i32, ExitCode), SandboxError> {
+        self.execs
+            .lock()
+            .unwrap()
+            .get(exec_id)
+            .cloned()
+            .ok_or_else(|| SandboxError::NotFound(exec_id.to_string()))
+    }
+}
+
+/// The error of a failed build of the container. The executor of the engine validates the
+/// container while it's built, and its validation errors are errors of the spec rather than
+/// failures of the engine.
+fn build_error(err: BuildError) -> SandboxError {
+    match err.kind {
+        BuildErrorKind::Invalid => SandboxError::InvalidSpec(err.to_string()),
+        BuildErrorKind::Failed => SandboxError::EngineFailure(err.into()),
+    }
+}
+
+/// The signals of Pause and Resume are reserved for them, so that a Kill doesn't suspend
+/// the module of an engine that suspends it itself
+fn reject_reserved_signal(signal: u32) -> Result<(), SandboxError> {
+    if is_reserved_signal(signal as i32) {
+        return Err(SandboxError::InvalidArgument(format!(
+            "signal {signal} is reserved for the pause of the container"
+        )));
+    }
+    Ok(())
+}
+
+/// Send the realtime `signal` to the process `pid`, which the signals of nix can't name
+fn send_signal(pid: i32, signal: i32) -> nix::Result<()> {
+    nix::errno::Errno::result(unsafe { libc::kill(pid, signal) }).map(drop)
+}
+
+/// Delete the container of libcontainer at `root`, with its cgroup, left behind by a create
+/// that missed its deadline, which would fail the retries of the create
+fn remove_stale_container(root: &Path) {
+    if !root.exists() {
+        return;
+    }
+    log::warn!("deleting the stale container at {root:?}");
+    let deleted = YoukiContainer::load(root.to_path_buf()).and_then(|mut c| c.delete(true));
+    if let Err(err) = deleted {
+        log::warn!("failed to delete the stale container at {root:?}: {err}");
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
+
+/// Save `spec`, the spec given to libcontainer, in a directory of the `bundle`, and return it.
+/// The spec of the bundle stays as containerd wrote it, and the rootfs of `spec` the one of the
+/// bundle.
+fn save_container_spec(bundle: &Path, spec: &Spec) -> Result<PathBuf, SandboxError> {
+    let dir = bundle.join("libcontainer");
+    std::fs::create_dir_all(&dir)?;
+    let mut spec = spec.clone();
+    spec.canonicalize_rootfs(bundle)?;
+    spec.save(dir.join("config.json"))?;
+    Ok(dir)
+}
+
+/// Remove the hooks that the shim runs from the spec given to libcontainer, so that they
+/// run once. The startContainer hooks run in the container, and are left to libcontainer.
+/// Returns whether the spec changed.
+fn remove_shim_hooks(spec: &mut Spec) -> bool {
+    let Some(hooks) = spec.hooks().clone() else {
+        return false;
+    };
+    let mut container_hooks = Hooks::default();
+    container_hooks.set_start_container(hooks.start_container().clone());
+    if hooks == container_hooks {
+        return false;
+    }
+    spec.set_hooks(Some(container_hooks));
+    true
+}
+
+/// The directory of the crash reports of a container, its bundle unless the shim options set one
+fn crash_dir(cfg: &InstanceConfig) -> PathBuf {
+    cfg.config
+        .crash_dir
+        .clone()
+        .unwrap_or_else(|| cfg.bundle.clone())
+}
+
+/// The engine of the shim, shared by the containers it serves, e.g., the containers of a pod.
+/// The shim uses it to load and precompile their modules, while each container runs
+/// in its own process, with its own store.
+fn shared_engine<E: Engine + Default>() -> E {
+    static ENGINES: LazyLock<Mutex<HashMap<TypeId, Box<dyn Any + Send>>>> =
+        LazyLock::new(Mutex::default);
+    ENGINES
+        .lock()
+        .unwrap()
+        .entry(TypeId::of::<E>())
+        .or_insert_with(|| Box::new(E::default()))
+        .downcast_ref::<E>()
+        .expect("the engine of its type id")
+        .clone()
+}
+
+fn exit_status(res: std::io::Result<WaitStatus>) -> u32 {
+    (match res {
+        Ok(WaitStatus::Exited(_, status)) => status,
+        Ok(WaitStatus::Signaled(_, sig, _)) => 128 + sig as i32,
+        Ok(res) => {
+            log::error!("waitpid unexpected result: {res:?}");
+            137
+        }
+        Err(e) => {
+            log::error!("waitpid failed: {e}");
+            137
+        }
+    }) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use oci_spec::runtime::{HookBuilder, HooksBuilder, RootBuilder, SpecBuilder};
+
+    use super::*;
+    use crate::container::RuntimeContext;
+
+    static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Clone)]
+    struct EngineStub;
+
+    impl Default for EngineStub {
+        fn default() -> Self {
+            CREATED.fetch_add(1, Ordering::SeqCst);
+            Self
+        }
+    }
+
+    impl Engine for EngineStub {
+        fn name() -> &'static str {
+            "stub"
+        }
+
+        fn run_wasi(&self, _ctx: &impl RuntimeContext) -> anyhow::Result<i32> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_build_error() {
+        // the kind of the error of the build crosses the zygote
+        let err = Container::build(
+            |_| {
+                let err = BuildError::new(BuildErrorKind::Invalid, "the entrypoint isn't exported");
+                Err(anyhow::Error::from(err).context("validating the container"))
+            },
+            (),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.kind, BuildErrorKind::Invalid);
+        assert!(
+            matches!(build_error(err), SandboxError::InvalidSpec(msg) if msg == "validating the container: the entrypoint isn't exported")
+        );
+
+        // whatever the message
+        let err = Container::build(
+            |_| anyhow::bail!("invalid container configuration: failed to create the cgroup"),
+            (),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.kind, BuildErrorKind::Failed);
+        assert!(matches!(build_error(err), SandboxError::EngineFailure(_)));
+    }
+
+    #[test]
+    fn test_reserved_signals() {
+        let err = reject_reserved_signal(suspend_signal() as u32).unwrap_err();
+        assert!(matches!(err, SandboxError::InvalidArgument(_)), "{err}");
+        assert!(reject_reserved_signal(resume_signal() as u32).is_err());
+
+        // the signals that the modules handle reach them
+        assert!(reject_reserved_signal(libc::SIGUSR1 as u32).is_ok());
+        assert!(reject_reserved_signal(libc::SIGUSR2 as u32).is_ok());
+    }
+
+    #[test]
+    fn test_shared_engine() {
+        let _ = shared_engine::<EngineStub>();
+        let _ = shared_engine::<EngineStub>();
+        assert_eq!(CREATED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_remove_shim_hooks() -> anyhow::Result<()> {
+        let hook = |path: &str| HookBuilder::default().path(path).build().unwrap();
+        let mut spec = SpecBuilder::default()
+            .hooks(
+                HooksBuilder::default()
+                    .create_runtime(vec![hook("/usr/bin/create-runtime")])
+                    .start_container(vec![hook("/usr/bin/start-container")])
+                    .poststop(vec![hook("/usr/bin/poststop")])
+                    .build()?,
+            )
+            .build()?;
+
+        assert!(remove_shim_hooks(&mut spec));
+        let hooks = spec.hooks().clone().unwrap();
+        assert_eq!(hooks.create_runtime(), &None);
+        assert_eq!(hooks.poststop(), &None);
+        assert_eq!(
+            hooks.start_container(),
+            &Some(vec![hook("/usr/bin/start-container")])
+        );
+        assert!(!remove_shim_hooks(&mut spec));
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_container_spec() -> anyhow::Result<()> {
+        let bundle = tempfile::tempdir()?;
+        std::fs::create_dir(bundle.path().join("rootfs"))?;
+        let hook = HookBuilder::default().path("/usr/bin/poststop").build()?;
+        let spec = SpecBuilder::default()
+            .root(RootBuilder::default().path("rootfs").build()?)
+            .hooks(HooksBuilder::default().poststop(vec![hook]).build()?)
+            .build()?;
+        let config = bundle.path().join("config.json");
+        spec.save(&config)?;
+        let original = std::fs::read(&config)?;
+
+        // every create starts again from the spec of the bundle
+        for _ in 0..2 {
+            let mut spec = Spec::load(&config)?;
+            assert!(remove_shim_hooks(&mut spec));
+            let dir = save_container_spec(bundle.path(), &spec)?;
+
+            let saved = Spec::load(dir.join("config.json"))?;
+            assert_eq!(saved.hooks().clone().unwrap().poststop(), &None);
+            let rootfs = saved.root().as_ref().unwrap().path();
+            assert_eq!(rootfs, &bundle.path().canonicalize()?.join("rootfs"));
+        }
+        assert_eq!(std::fs::read(&config)?, original);
+        Ok(())
+    }
 }
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use anyhow::{Context, Result, bail};
+use nix::sched::{CloneFlags, setns};
+use oci_spec::runtime::{LinuxNamespaceType, Spec};
+
+use crate::container::Listener;
+
+/// A network namespace, `None` for the one of the shim, and an address in it
+type NetnsAddr = (Option<PathBuf>, SocketAddr);
+
+/// The sockets bound by the shim, by network namespace and address.
+/// They stay open for the life of the shim, so that a restarted container of a pod
+/// gets the same socket, rather than failing to bind it with `EADDRINUSE`.
+static LISTENERS: LazyLock<Mutex<HashMap<NetnsAddr, Arc<TcpListener>>>> =
+    LazyLock::new(Mutex::default);
+
+/// Bind the sockets of a container at `addrs`, in the network namespace of the container
+pub(super) fn bind(spec: &Spec, addrs: &[SocketAddr]) -> Result<Vec<Listener>> {
+    if addrs.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // without a network namespace, the container is in the network namespace of the shim
+    let netns = spec
+        .linux()
+        .as_ref()
+        .and_then(|linux| linux.namespaces().as_ref())
+        .and_then(|ns| ns.iter().find(|ns| ns.typ() == LinuxNamespaceType::Network));
+    let netns = match netns {
+        None => None,
+        Some(ns) => match ns.path() {
+            Some(path) => Some(path.clone()),
+            None => bail!("the container has a new network namespace, the shim can't listen in it"),
+        },
+    };
+
+    let mut listeners = LISTENERS.lock().unwrap();
+    addrs
+        .iter()
+        .map(|&addr| {
+            let key = (netns.clone(), addr);
+            let socket = match listeners.get(&key) {
+                Some(socket) => socket.clone(),
+                None => {
+                    let socket = Arc::new(bind_in(netns.clone(), addr)?);
+                    listeners.insert(key, socket.clone());
+                    socket
+                }
+            };
+            Ok(Listener { addr, socket })
+        })
+        .collect()
+}
+
+/// Bind `addr` from a thread in the network namespace `netns`, so that the shim stays in its own
+fn bind_in(netns: Option<PathBuf>, addr: SocketAddr) -> Result<TcpListener> {
+    let Some(netns) = netns else {
+        return TcpListener::bind(addr).with_context(|| format!("failed to listen on {addr}"));
+    };
+    std::thread::spawn(move || {
+        let ns = File::open(&netns)
+            .with_context(|| format!("failed to open network namespace {netns:?}"))?;
+        setns(ns, CloneFlags::CLONE_NEWNET)
+            .with_context(|| format!("failed to join network namespace {netns:?}"))?;
+        TcpListener::bind(addr).with_context(|| format!("failed to listen on {addr}"))
+    })
+    .join()
+    .unwrap_or_else(|_| bail!("failed to listen on {addr}"))
+}
+
+/// The addresses and file descriptors of the sockets, to import them with `import`
+pub(super) fn export(listeners: &[Listener]) -> Vec<(SocketAddr, RawFd)> {
+    listeners
+        .iter()
+        .map(|l| (l.addr, l.socket.as_raw_fd()))
+        .collect()
+}
+
+/// Duplicate the sockets exported by the shim `pid` in this process, e.g., in the zygote of a container
+pub(super) fn import(pid: u32, listeners: Vec<(SocketAddr, RawFd)>) -> Result<Vec<Listener>> {
+    if listeners.is_empty() {
+        return Ok(vec![]);
+    }
+    use libc::{SYS_pidfd_getfd, SYS_pidfd_open, syscall};
+    let pidfd = unsafe { syscall(SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if pidfd == -1 {
+        return Err(std::io::Error::last_os_error())
+            .context("failed to open the pidfd of the shim");
+    }
+    let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as RawFd) };
+
+    listeners
+        .into_iter()
+        .map(|(addr, fd)| {
+            let fd = unsafe { syscall(SYS_pidfd_getfd, pidfd.as_raw_fd(), fd, 0) };
+            if fd == -1 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("failed to get the socket of {addr} from the shim"));
+            }
+            let socket = unsafe { TcpListener::from_raw_fd(fd as RawFd) };
+            Ok(Listener {
+                addr,
+                socket: Arc::new(socket),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_reuses_sockets() -> Result<()> {
+        let spec = Spec::default();
+        // namespaces of the default spec, without the network one
+        let mut linux = spec.linux().clone().unwrap_or_default();
+        let namespaces = linux.namespaces().clone().unwrap_or_default();
+        linux.set_namespaces(Some(
+            namespaces
+                .into_iter()
+                .filter(|ns| ns.typ() != LinuxNamespaceType::Network)
+                .collect(),
+        ));
+        let mut spec = spec;
+        spec.set_linux(Some(linux));
+
+        let addr = "127.0.0.1:0".parse()?;
+        let first = bind(&spec, &[addr])?;
+        let second = bind(&spec, &[addr])?;
+        assert!(Arc::ptr_eq(&first[0].socket, &second[0].socket));
+
+        let imported = import(std::process::id(), export(&first))?;
+        assert_eq!(
+            imported[0].socket.local_addr()?,
+            first[0].socket.local_addr()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_network_namespace() {
+        // the default spec has a new network namespace
+        let spec = Spec::default();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        assert!(bind(&spec, &[addr]).is_err());
+    }
+}
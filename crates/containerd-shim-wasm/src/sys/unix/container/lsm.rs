@@ -0,0 +1,334 @@
+//! The SELinux and AppArmor labels of the OCI spec, for the wasm containers.
+//!
+//! libcontainer arms the labels of `process.selinuxLabel` and `process.apparmorProfile` for the
+//! next `execve` of the container process, which the engine never calls, as `setexeccon` does.
+//! The wasm containers change the labels of their running process instead, before the engine
+//! starts, and label the mountpoints of their rootfs and volumes with `linux.mountLabel`. The
+//! native Linux containers exec their entrypoint, and keep the labels of libcontainer.
+//!
+//! Changing the SELinux label of a running process is a dynamic transition, which the policy
+//! has to allow with the `setcurrent` and `dyntransition` permissions of the `process` class,
+//! from the domain of the shim to the label of the container. The policies of the distributions
+//! don't grant them to the container runtimes, and a denied transition leaves the container with
+//! the label of the shim, with a warning, rather than failing it. The hosts running wasm containers
+//! confined by an SELinux label need a policy module that grants them, e.g., for
+//! `container_runtime_t` to `container_t`.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use oci_spec::runtime::Spec;
+
+/// Extended attribute with the SELinux label of a file
+const SELINUX_XATTR: &str = "security.selinux";
+
+/// Exit code of a container process that failed to apply its labels, before the engine started,
+/// as for a command that can't be executed
+pub(super) const LABELS_EXIT_CODE: i32 = 126;
+
+/// The mandatory access control of the kernel, mocked in the tests
+pub(super) trait Lsm {
+    fn selinux_enabled(&self) -> bool;
+    fn apparmor_enabled(&self) -> bool;
+    /// Write the attribute `attr` of the module `lsm` for the calling thread, e.g., `current`
+    fn write_attr(&self, lsm: &str, attr: &str, value: &str) -> io::Result<()>;
+    /// Set the SELinux label of a file, without following symlinks
+    fn set_file_label(&self, path: &Path, label: &str) -> io::Result<()>;
+}
+
+/// The LSMs of the kernel, as seen from the container process
+pub(super) struct HostLsm;
+
+impl Lsm for HostLsm {
+    fn selinux_enabled(&self) -> bool {
+        // selinuxfs is only registered when SELinux is enabled, and it isn't mounted in the container
+        std::fs::read_to_string("/proc/filesystems")
+            .is_ok_and(|fs| fs.lines().any(|l| l.ends_with("\tselinuxfs")))
+    }
+
+    fn apparmor_enabled(&self) -> bool {
+        std::fs::read_to_string("/sys/module/apparmor/parameters/enabled")
+            .is_ok_and(|enabled| enabled.starts_with('Y'))
+    }
+
+    fn write_attr(&self, lsm: &str, attr: &str, value: &str) -> io::Result<()> {
+        // newer kernels have a directory with the attributes of each module
+        let attrs = Path::new("/proc/thread-self/attr");
+        let path = match attrs.join(lsm).is_dir() {
+            true => attrs.join(lsm).join(attr),
+            false => attrs.join(attr),
+        };
+        std::fs::write(path, value)
+    }
+
+    fn set_file_label(&self, path: &Path, label: &str) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let name = CString::new(SELINUX_XATTR)?;
+        let value = CString::new(label)?;
+        let value = value.as_bytes_with_nul();
+        let res = unsafe {
+            libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// The security labels of a container, from its spec
+#[derive(Default, Debug, PartialEq)]
+pub(super) struct Labels {
+    /// `process.selinuxLabel`
+    pub process_label: Option<String>,
+    /// `linux.mountLabel`
+    pub mount_label: Option<String>,
+    /// `process.apparmorProfile`
+    pub apparmor_profile: Option<String>,
+}
+
+impl Labels {
+    pub fn from_spec(spec: &Spec) -> Self {
+        let process = spec.process().as_ref();
+        let label = |label: &Option<String>| label.clone().filter(|l| !l.is_empty());
+        Self {
+            process_label: process.and_then(|p| label(p.selinux_label())),
+            mount_label: spec.linux().as_ref().and_then(|l| label(l.mount_label())),
+            apparmor_profile: process.and_then(|p| label(p.apparmor_profile())),
+        }
+    }
+
+    /// Check that the kernel has the LSMs of the labels. A container without labels runs
+    /// whatever the LSMs of the host.
+    pub fn check(&self, lsm: &impl Lsm) -> Result<()> {
+        let selinux = self.process_label.is_some() || self.mount_label.is_some();
+        if selinux && !lsm.selinux_enabled() {
+            bail!("the spec has SELinux labels, but SELinux is not enabled on the host");
+        }
+        if self.apparmor_profile.is_some() && !lsm.apparmor_enabled() {
+            bail!(
+                "the spec has the AppArmor profile {:?}, but AppArmor is not enabled on the host",
+                self.apparmor_profile.as_deref().unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+
+    /// Label the mountpoints of `dirs`, the rootfs and the volumes of the container, with the
+    /// mount label, and then change the labels of the calling thread, which the threads
+    /// of the engine inherit. The files under the mountpoints keep their labels, as relabeling
+    /// the content of the volumes is left to the container engine, e.g., to the `selinux_relabel`
+    /// of the CRI.
+    pub fn apply(&self, lsm: &impl Lsm, dirs: &[PathBuf]) -> Result<()> {
+        self.check(lsm)?;
+        if let Some(label) = &self.mount_label {
+            for dir in dirs {
+                label_mountpoint(lsm, dir, label)
+                    .with_context(|| format!("failed to label {dir:?} with {label:?}"))?;
+            }
+        }
+        if let Some(profile) = &self.apparmor_profile {
+            lsm.write_attr("apparmor", "current", &format!("changeprofile {profile}"))
+                .with_context(|| format!("failed to change to the AppArmor profile {profile:?}"))?;
+        }
+        // last, as the new label may not allow labeling files
+        if let Some(label) = &self.process_label {
+            match lsm.write_attr("selinux", "current", label) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                    log::warn!(
+                        "the policy denies the dynamic transition to the SELinux label {label:?}, the container keeps the label of the shim: {err}"
+                    );
+                }
+                Err(err) => {
+                    return Err(anyhow::Error::new(err)
+                        .context(format!("failed to set the SELinux label {label:?}")));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Label the mountpoint `dir`, unless its file system can't label files, e.g., a read-only
+/// rootfs, or a file system without extended attributes
+fn label_mountpoint(lsm: &impl Lsm, dir: &Path, label: &str) -> io::Result<()> {
+    match lsm.set_file_label(dir, label) {
+        Err(err) if matches!(err.raw_os_error(), Some(libc::EROFS | libc::EOPNOTSUPP)) => {
+            log::debug!("not labeling {dir:?}: {err}");
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use oci_spec::runtime::{LinuxBuilder, ProcessBuilder, SpecBuilder};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MockLsm {
+        selinux: bool,
+        apparmor: bool,
+        /// Like a policy without the dynamic transitions to the labels of the containers
+        deny_transitions: bool,
+        /// Like a label that the policy doesn't know
+        invalid_labels: bool,
+        attrs: RefCell<Vec<String>>,
+        labeled: RefCell<Vec<PathBuf>>,
+    }
+
+    impl Lsm for MockLsm {
+        fn selinux_enabled(&self) -> bool {
+            self.selinux
+        }
+
+        fn apparmor_enabled(&self) -> bool {
+            self.apparmor
+        }
+
+        fn write_attr(&self, lsm: &str, attr: &str, value: &str) -> io::Result<()> {
+            if self.deny_transitions && lsm == "selinux" {
+                return Err(io::Error::from_raw_os_error(libc::EACCES));
+            }
+            if self.invalid_labels && lsm == "selinux" {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            self.attrs
+                .borrow_mut()
+                .push(format!("{lsm}/{attr}={value}"));
+            Ok(())
+        }
+
+        fn set_file_label(&self, path: &Path, label: &str) -> io::Result<()> {
+            assert_eq!(label, "system_u:object_r:container_file_t:s0:c1,c2");
+            self.labeled.borrow_mut().push(path.to_path_buf());
+            Ok(())
+        }
+    }
+
+    fn labels() -> Labels {
+        Labels {
+            process_label: Some("system_u:system_r:container_t:s0:c1,c2".to_string()),
+            mount_label: Some("system_u:object_r:container_file_t:s0:c1,c2".to_string()),
+            apparmor_profile: None,
+        }
+    }
+
+    #[test]
+    fn test_labels_from_spec() -> Result<()> {
+        let spec = SpecBuilder::default()
+            .process(
+                ProcessBuilder::default()
+                    .selinux_label("system_u:system_r:container_t:s0:c1,c2")
+                    .apparmor_profile("")
+                    .build()?,
+            )
+            .linux(
+                LinuxBuilder::default()
+                    .mount_label("system_u:object_r:container_file_t:s0:c1,c2")
+                    .build()?,
+            )
+            .build()?;
+        assert_eq!(Labels::from_spec(&spec), labels());
+
+        let spec = SpecBuilder::default().build()?;
+        assert_eq!(Labels::from_spec(&spec), Labels::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_labels_need_their_lsm() {
+        let host = MockLsm::default();
+        assert!(Labels::default().check(&host).is_ok());
+
+        let err = labels().check(&host).unwrap_err();
+        assert!(err.to_string().contains("SELinux is not enabled"), "{err}");
+
+        let apparmor = Labels {
+            apparmor_profile: Some("cri-containerd.apparmor.d".to_string()),
+            ..Default::default()
+        };
+        let err = apparmor.apply(&host, &[]).unwrap_err();
+        assert!(err.to_string().contains("AppArmor is not enabled"), "{err}");
+        assert!(host.attrs.borrow().is_empty());
+
+        let host = MockLsm {
+            apparmor: true,
+            ..Default::default()
+        };
+        apparmor.apply(&host, &[]).unwrap();
+        assert_eq!(
+            *host.attrs.borrow(),
+            ["apparmor/current=changeprofile cri-containerd.apparmor.d"]
+        );
+    }
+
+    #[test]
+    fn test_apply_selinux_labels() -> Result<()> {
+        let rootfs = tempfile::tempdir()?;
+        std::fs::create_dir(rootfs.path().join("app"))?;
+        std::fs::write(rootfs.path().join("app/app.wasm"), "")?;
+        let volume = tempfile::tempdir()?;
+        std::fs::write(volume.path().join("data.txt"), "")?;
+
+        let host = MockLsm {
+            selinux: true,
+            ..Default::default()
+        };
+        let dirs = [rootfs.path().to_path_buf(), volume.path().to_path_buf()];
+        labels().apply(&host, &dirs)?;
+
+        // only the mountpoints, and not the files under them
+        assert_eq!(*host.labeled.borrow(), dirs);
+        assert_eq!(
+            *host.attrs.borrow(),
+            ["selinux/current=system_u:system_r:container_t:s0:c1,c2"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_selinux_transition_denied() -> Result<()> {
+        let rootfs = tempfile::tempdir()?;
+        let host = MockLsm {
+            selinux: true,
+            deny_transitions: true,
+            ..Default::default()
+        };
+
+        // the container starts with the label of the shim, and its mountpoints labeled
+        let dirs = [rootfs.path().to_path_buf()];
+        labels().apply(&host, &dirs)?;
+        assert_eq!(*host.labeled.borrow(), dirs);
+        assert!(host.attrs.borrow().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_selinux_label() {
+        let host = MockLsm {
+            selinux: true,
+            invalid_labels: true,
+            ..Default::default()
+        };
+        let err = labels().apply(&host, &[]).unwrap_err();
+        assert!(
+            err.to_string().contains("failed to set the SELinux label"),
+            "{err}"
+        );
+    }
+}
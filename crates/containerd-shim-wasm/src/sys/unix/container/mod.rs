@@ -1,5 +1,13 @@
 #[allow(clippy::module_inception)]
 mod container;
 
+mod cgroups;
+mod console;
+mod crash;
 mod executor;
 pub mod instance;
+mod listeners;
+mod lsm;
+pub(crate) mod pool;
+mod sched;
+mod userns;
@@ -0,0 +1,168 @@
+//! Pool of idle zygotes, forked ahead of the containers from the global zygote.
+//!
+//! Every container runs in its own zygote, where libcontainer builds it and its processes
+//! are forked from. Forking that zygote goes through the global zygote, one container at
+//! a time, which adds up when many containers are created in parallel.
+//! With a pool, a container takes a zygote that was already forked, and the pool is refilled
+//! in the background. Without a pool, or when it is empty, the zygote is forked as before.
+
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+
+use zygote::Zygote;
+
+/// Number of idle zygotes, overridden by the `ZygotePoolSize` option of the containers
+pub const ZYGOTE_POOL_SIZE_ENV: &str = "RUNWASI_ZYGOTE_POOL_SIZE";
+
+pub(crate) struct ZygotePool {
+    state: Mutex<PoolState>,
+}
+
+#[derive(Default)]
+struct PoolState {
+    idle: Vec<Zygote>,
+    size: usize,
+    /// Whether a thread is forking the missing zygotes
+    filling: bool,
+}
+
+impl ZygotePool {
+    fn new(size: usize) -> Self {
+        let state = PoolState {
+            size,
+            ..Default::default()
+        };
+        Self {
+            state: Mutex::new(state),
+        }
+    }
+
+    /// The pool of the shim, with `RUNWASI_ZYGOTE_POOL_SIZE` idle zygotes
+    pub fn global() -> &'static Self {
+        static POOL: LazyLock<ZygotePool> = LazyLock::new(|| ZygotePool::new(size_from_env()));
+        &POOL
+    }
+
+    /// Start forking the idle zygotes, e.g., when the shim starts
+    pub fn prefork(&'static self) {
+        self.fill();
+    }
+
+    /// Keep `size` idle zygotes, the extra ones exit
+    pub fn resize(&'static self, size: usize) {
+        let extra = {
+            let mut state = self.state.lock().unwrap();
+            if state.size == size {
+                return;
+            }
+            log::debug!("resizing the zygote pool to {size}");
+            state.size = size;
+            let keep = state.idle.len().min(size);
+            state.idle.split_off(keep)
+        };
+        drop(extra);
+        self.fill();
+    }
+
+    /// An idle zygote, or a new one when the pool is empty
+    pub fn take(&'static self) -> Zygote {
+        let zygote = self.state.lock().unwrap().idle.pop();
+        self.fill();
+        zygote.unwrap_or_else(|| Zygote::global().spawn())
+    }
+
+    #[cfg(test)]
+    fn idle(&self) -> usize {
+        self.state.lock().unwrap().idle.len()
+    }
+
+    /// Fork the missing idle zygotes in the background
+    fn fill(&'static self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.filling || state.idle.len() >= state.size {
+                return;
+            }
+            state.filling = true;
+        }
+
+        let spawned = thread::Builder::new()
+            .name("zygote-pool".to_string())
+            .spawn(move || {
+                loop {
+                    // the global zygote forks one zygote at a time, don't hold the lock meanwhile
+                    let zygote = Zygote::global().spawn();
+                    let mut state = self.state.lock().unwrap();
+                    if state.idle.len() < state.size {
+                        state.idle.push(zygote);
+                    }
+                    if state.idle.len() >= state.size {
+                        state.filling = false;
+                        break;
+                    }
+                }
+            });
+        if let Err(err) = spawned {
+            log::warn!("failed to refill the zygote pool: {err}");
+            self.state.lock().unwrap().filling = false;
+        }
+    }
+}
+
+fn size_from_env() -> usize {
+    let Ok(size) = std::env::var(ZYGOTE_POOL_SIZE_ENV) else {
+        return 0;
+    };
+    size.trim().parse().unwrap_or_else(|_| {
+        log::warn!("invalid {ZYGOTE_POOL_SIZE_ENV} {size:?}, the zygote pool is disabled");
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn wait_for_idle(pool: &ZygotePool, idle: usize) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pool.idle() != idle {
+            assert!(
+                Instant::now() < deadline,
+                "the pool has {} idle zygotes",
+                pool.idle()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_pool_is_refilled() {
+        let pool: &'static ZygotePool = Box::leak(Box::new(ZygotePool::new(2)));
+        pool.prefork();
+        wait_for_idle(pool, 2);
+
+        let zygote = pool.take();
+        assert_ne!(zygote.run(|_| std::process::id(), ()), std::process::id());
+        wait_for_idle(pool, 2);
+
+        // without idle zygotes, a new one is forked
+        pool.resize(0);
+        assert_eq!(pool.idle(), 0);
+        let zygote = pool.take();
+        assert_ne!(zygote.run(|_| std::process::id(), ()), std::process::id());
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn test_size_from_env() {
+        temp_env::with_var(ZYGOTE_POOL_SIZE_ENV, Some("4"), || {
+            assert_eq!(size_from_env(), 4)
+        });
+        temp_env::with_var(ZYGOTE_POOL_SIZE_ENV, Some("four"), || {
+            assert_eq!(size_from_env(), 0)
+        });
+        temp_env::with_var_unset(ZYGOTE_POOL_SIZE_ENV, || assert_eq!(size_from_env(), 0));
+    }
+}
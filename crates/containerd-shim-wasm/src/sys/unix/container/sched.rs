@@ -0,0 +1,96 @@
+use anyhow::{Context, Result, bail};
+use nix::sched::{CpuSet, sched_setaffinity};
+use nix::unistd::Pid;
+use oci_spec::runtime::{LinuxResources, Spec};
+
+/// Pin the process of the engine to the cpuset of the container, `linux.resources.cpu.cpus`.
+/// libcontainer already applies it to the cgroup of the container, and the rlimits, the oom
+/// score and the CPU shares and quota to its process. Setting the affinity as well, before the
+/// engine spawns its threads, keeps them off the other cores when the cpuset controller of the
+/// cgroup isn't available, e.g., when it isn't delegated.
+pub(super) fn apply_cpu_affinity(spec: &Spec) -> Result<()> {
+    let resources = spec.linux().as_ref().and_then(|l| l.resources().as_ref());
+    let Some(cpus) = resources.and_then(cpuset) else {
+        return Ok(());
+    };
+    sched_setaffinity(Pid::from_raw(0), &parse_cpus(cpus)?)
+        .context("failed to set the CPU affinity")?;
+    Ok(())
+}
+
+/// Pin the threads of the process `pid` to the new cpuset of the container, if it has one,
+/// as the threads pinned to the former cpuset would stay on its cores
+pub(super) fn update_cpu_affinity(pid: i32, resources: &LinuxResources) -> Result<()> {
+    let Some(cpus) = cpuset(resources) else {
+        return Ok(());
+    };
+    let cpus = parse_cpus(cpus)?;
+    for task in std::fs::read_dir(format!("/proc/{pid}/task"))? {
+        let Ok(tid) = task?.file_name().to_string_lossy().parse() else {
+            continue;
+        };
+        // the thread might have exited since
+        if let Err(err) = sched_setaffinity(Pid::from_raw(tid), &cpus) {
+            log::debug!("failed to set the CPU affinity of thread {tid}: {err}");
+        }
+    }
+    Ok(())
+}
+
+fn cpuset(resources: &LinuxResources) -> Option<&str> {
+    resources
+        .cpu()
+        .as_ref()
+        .and_then(|cpu| cpu.cpus().as_deref())
+        .filter(|cpus| !cpus.trim().is_empty())
+}
+
+/// The CPUs of a cpuset list, e.g., `0-3,8`
+fn parse_cpus(list: &str) -> Result<CpuSet> {
+    let mut cpus = CpuSet::new();
+    for range in list.trim().split(',') {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.trim(), end.trim()),
+            None => (range.trim(), range.trim()),
+        };
+        let start: usize = start
+            .parse()
+            .with_context(|| format!("invalid cpuset {list:?}"))?;
+        let end: usize = end
+            .parse()
+            .with_context(|| format!("invalid cpuset {list:?}"))?;
+        if start > end {
+            bail!("invalid cpuset {list:?}");
+        }
+        for cpu in start..=end {
+            cpus.set(cpu)
+                .with_context(|| format!("CPU {cpu} of cpuset {list:?} is out of range"))?;
+        }
+    }
+    Ok(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpus(set: &CpuSet) -> Vec<usize> {
+        (0..CpuSet::count())
+            .filter(|cpu| set.is_set(*cpu).unwrap_or(false))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_cpus() -> Result<()> {
+        assert_eq!(cpus(&parse_cpus("0")?), [0]);
+        assert_eq!(cpus(&parse_cpus("0-3,8")?), [0, 1, 2, 3, 8]);
+        assert_eq!(cpus(&parse_cpus(" 2,4-5\n")?), [2, 4, 5]);
+
+        assert!(parse_cpus("3-1").is_err());
+        assert!(parse_cpus("a").is_err());
+        assert!(parse_cpus("0,").is_err());
+        assert!(parse_cpus(&format!("{}", CpuSet::count())).is_err());
+
+        Ok(())
+    }
+}
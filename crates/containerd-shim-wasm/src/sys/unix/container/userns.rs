@@ -0,0 +1,201 @@
+use std::fs::{Permissions, read_dir, set_permissions, symlink_metadata};
+use std::os::unix::fs::{MetadataExt as _, PermissionsExt as _, lchown};
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use oci_spec::runtime::{LinuxIdMapping, LinuxNamespaceType, Spec};
+
+/// The new user namespace of a container, with the id mappings of its spec.
+/// libcontainer creates the namespace and writes the mappings, for the wasm containers
+/// as for the native ones, so that the engine runs as the mapped ids of the process user.
+pub(super) struct UserNamespace {
+    uid_mappings: Vec<LinuxIdMapping>,
+    gid_mappings: Vec<LinuxIdMapping>,
+}
+
+impl UserNamespace {
+    /// The user namespace of the container with `spec`, when it creates one
+    pub fn from_spec(spec: &Spec) -> Result<Option<Self>> {
+        let Some(linux) = spec.linux() else {
+            return Ok(None);
+        };
+        let userns = linux
+            .namespaces()
+            .as_ref()
+            .and_then(|ns| ns.iter().find(|ns| ns.typ() == LinuxNamespaceType::User));
+        // the mappings of an existing namespace are already written
+        if userns.is_none_or(|ns| ns.path().is_some()) {
+            return Ok(None);
+        }
+
+        let userns = Self {
+            uid_mappings: linux.uid_mappings().clone().unwrap_or_default(),
+            gid_mappings: linux.gid_mappings().clone().unwrap_or_default(),
+        };
+        if userns.uid_mappings.is_empty() || userns.gid_mappings.is_empty() {
+            bail!("the user namespace of the container has no uid or gid mappings");
+        }
+        if let Some(user) = spec.process().as_ref().map(|p| p.user()) {
+            if userns.host_uid(user.uid()).is_none() || userns.host_gid(user.gid()).is_none() {
+                bail!(
+                    "the user {}:{} of the container is not mapped in its user namespace",
+                    user.uid(),
+                    user.gid()
+                );
+            }
+        }
+        Ok(Some(userns))
+    }
+
+    /// The id on the host of `uid` in the namespace
+    pub fn host_uid(&self, uid: u32) -> Option<u32> {
+        host_id(&self.uid_mappings, uid)
+    }
+
+    /// The id on the host of `gid` in the namespace
+    pub fn host_gid(&self, gid: u32) -> Option<u32> {
+        host_id(&self.gid_mappings, gid)
+    }
+
+    /// Shift the ownership of the files of `rootfs` to the mapped ids, so that the root of the
+    /// namespace owns the files of the root of the host.
+    /// A rootfs that isn't owned by the root of the host is left as is, e.g., when the snapshotter
+    /// remapped it, or when it is an idmapped mount.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), level = "Debug"))]
+    pub fn remap_rootfs(&self, rootfs: &Path) -> Result<()> {
+        let metadata = symlink_metadata(rootfs)
+            .with_context(|| format!("failed to stat the rootfs {}", rootfs.display()))?;
+        if metadata.uid() != 0 || self.host_uid(0).is_none_or(|uid| uid == 0) {
+            return Ok(());
+        }
+        log::info!(
+            "shifting the ownership of {} to the user namespace",
+            rootfs.display()
+        );
+        self.remap(rootfs, metadata.dev())
+    }
+
+    fn remap(&self, path: &Path, dev: u64) -> Result<()> {
+        let metadata = symlink_metadata(path)?;
+        // the mounts in the rootfs keep their ownership
+        if metadata.dev() != dev {
+            return Ok(());
+        }
+
+        // the ids that aren't mapped are the overflow ids in the namespace
+        let uid = self.host_uid(metadata.uid());
+        let gid = self.host_gid(metadata.gid());
+        if uid.is_some() || gid.is_some() {
+            lchown(path, uid, gid)
+                .with_context(|| format!("failed to chown {}", path.display()))?;
+            // chown clears the setuid and setgid bits
+            if !metadata.file_type().is_symlink() && metadata.mode() & 0o6000 != 0 {
+                set_permissions(path, Permissions::from_mode(metadata.mode() & 0o7777))?;
+            }
+        }
+
+        if metadata.is_dir() {
+            for entry in read_dir(path)? {
+                self.remap(&entry?.path(), dev)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn host_id(mappings: &[LinuxIdMapping], id: u32) -> Option<u32> {
+    mappings.iter().find_map(|m| {
+        let offset = id.checked_sub(m.container_id())?;
+        (offset < m.size()).then(|| m.host_id() + offset)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir, write};
+    use std::os::unix::fs::symlink;
+
+    use oci_spec::runtime::{
+        LinuxBuilder, LinuxIdMappingBuilder, LinuxNamespaceBuilder, ProcessBuilder, SpecBuilder,
+        UserBuilder,
+    };
+
+    use super::*;
+
+    fn mapping(container_id: u32, host_id: u32, size: u32) -> Result<LinuxIdMapping> {
+        Ok(LinuxIdMappingBuilder::default()
+            .container_id(container_id)
+            .host_id(host_id)
+            .size(size)
+            .build()?)
+    }
+
+    fn spec(mappings: Vec<LinuxIdMapping>, uid: u32) -> Result<Spec> {
+        let userns = LinuxNamespaceBuilder::default()
+            .typ(LinuxNamespaceType::User)
+            .build()?;
+        let linux = LinuxBuilder::default()
+            .namespaces(vec![userns])
+            .uid_mappings(mappings.clone())
+            .gid_mappings(mappings)
+            .build()?;
+        let user = UserBuilder::default().uid(uid).gid(uid).build()?;
+        Ok(SpecBuilder::default()
+            .linux(linux)
+            .process(ProcessBuilder::default().user(user).build()?)
+            .build()?)
+    }
+
+    #[test]
+    fn test_host_ids() -> Result<()> {
+        let spec = spec(vec![mapping(0, 100000, 1000)?, mapping(1000, 5000, 1)?], 0)?;
+        let userns = UserNamespace::from_spec(&spec)?.context("no user namespace")?;
+
+        assert_eq!(userns.host_uid(0), Some(100000));
+        assert_eq!(userns.host_uid(999), Some(100999));
+        assert_eq!(userns.host_gid(1000), Some(5000));
+        assert_eq!(userns.host_uid(1001), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_user_namespace() -> Result<()> {
+        assert!(UserNamespace::from_spec(&spec(vec![], 0)?).is_err());
+        // the process user must be mapped
+        assert!(UserNamespace::from_spec(&spec(vec![mapping(0, 100000, 1000)?], 1000)?).is_err());
+        assert!(UserNamespace::from_spec(&SpecBuilder::default().build()?)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remap_rootfs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        create_dir(&rootfs)?;
+        create_dir(rootfs.join("etc"))?;
+        write(rootfs.join("etc").join("hostname"), "wasm")?;
+        symlink("/etc/hostname", rootfs.join("hostname"))?;
+        // ids that aren't mapped are kept
+        write(rootfs.join("unmapped"), "")?;
+        lchown(rootfs.join("unmapped"), Some(2000), Some(2000))?;
+
+        let spec = spec(vec![mapping(0, 100000, 1000)?], 0)?;
+        let userns = UserNamespace::from_spec(&spec)?.context("no user namespace")?;
+        userns.remap_rootfs(&rootfs)?;
+
+        for path in ["", "etc", "etc/hostname", "hostname"] {
+            let metadata = symlink_metadata(rootfs.join(path))?;
+            assert_eq!((metadata.uid(), metadata.gid()), (100000, 100000), "{path}");
+        }
+        assert_eq!(symlink_metadata(rootfs.join("unmapped"))?.uid(), 2000);
+
+        // a remapped rootfs is left as is
+        lchown(rootfs.join("etc"), Some(0), Some(0))?;
+        userns.remap_rootfs(&rootfs)?;
+        assert_eq!(symlink_metadata(rootfs.join("etc"))?.uid(), 0);
+
+        Ok(())
+    }
+}
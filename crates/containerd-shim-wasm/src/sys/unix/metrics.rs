@@ -1,12 +1,143 @@
+use std::fs::{File, OpenOptions, remove_file};
+use std::io::{BufRead as _, BufReader};
+use std::os::fd::AsRawFd as _;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
 use anyhow::Result;
 use containerd_shim::cgroup::collect_metrics;
 use containerd_shim::util::convert_to_any;
+use prost::Message as _;
 use protobuf::well_known_types::any::Any;
 
+use crate::sandbox::EngineMetrics;
+use crate::sys::stdio::{mkfifo, set_blocking};
+
+mod cgroup2;
+
+pub(crate) use cgroup2::cgroup_of;
+
 #[cfg_attr(feature = "tracing", tracing::instrument(level = "Debug"))]
 pub fn get_metrics(pid: u32) -> Result<Any> {
+    // the v1 metrics miss most of the stats of the unified hierarchy
+    if let Some(cgroup) = cgroup2::cgroup_of(pid) {
+        let mut metrics = Any::new();
+        metrics.type_url = cgroup2::TYPE_URL.to_string();
+        metrics.value = cgroup2::collect(&cgroup).encode_to_vec();
+        return Ok(metrics);
+    }
+
     let metrics = collect_metrics(pid)?;
 
     let metrics = convert_to_any(Box::new(metrics))?;
     Ok(metrics)
 }
+
+/// Metrics of the engine, sent by the container process through a FIFO of the shim,
+/// one JSON object per line.
+/// The shim keeps the latest, until the container process closes the FIFO.
+pub struct EngineMetricsReader {
+    path: PathBuf,
+    writer: Option<File>,
+    latest: Arc<Mutex<Option<EngineMetrics>>>,
+}
+
+impl EngineMetricsReader {
+    pub fn new(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        mkfifo(&path)?;
+        let reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)?;
+        // Hold a writer until the container opens the FIFO,
+        // otherwise the reader would see an EOF right away
+        let writer = OpenOptions::new().write(true).open(&path)?;
+        set_blocking(reader.as_raw_fd())?;
+
+        let latest = Arc::<Mutex<Option<EngineMetrics>>>::default();
+        thread::spawn({
+            let latest = latest.clone();
+            move || {
+                for line in BufReader::new(reader).lines() {
+                    let Ok(line) = line else {
+                        break;
+                    };
+                    match serde_json::from_str(&line) {
+                        Ok(metrics) => *latest.lock().unwrap() = Some(metrics),
+                        Err(err) => log::warn!("invalid engine metrics: {err}"),
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            path,
+            writer: Some(writer),
+            latest,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Call once the container opened the FIFO
+    pub fn attached(&mut self) {
+        self.writer = None;
+        let _ = remove_file(&self.path);
+    }
+
+    pub fn latest(&self) -> Option<EngineMetrics> {
+        self.latest.lock().unwrap().clone()
+    }
+}
+
+impl Drop for EngineMetricsReader {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write as _;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn reader_keeps_latest_metrics() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut reader = EngineMetricsReader::new(dir.path().join("metrics"))?;
+        let mut container = OpenOptions::new().write(true).open(reader.path())?;
+        reader.attached();
+        assert_eq!(reader.latest(), None);
+
+        for bytes in [1, 2] {
+            let metrics = EngineMetrics {
+                linear_memory_bytes: Some(bytes),
+                ..Default::default()
+            };
+            writeln!(container, "{}", serde_json::to_string(&metrics)?)?;
+        }
+
+        for _ in 0..50 {
+            if reader
+                .latest()
+                .is_some_and(|m| m.linear_memory_bytes == Some(2))
+            {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(10));
+        }
+        anyhow::bail!(
+            "the reader didn't get the latest metrics: {:?}",
+            reader.latest()
+        );
+    }
+}
@@ -0,0 +1,431 @@
+//! Metrics of a container in the unified cgroup hierarchy, as the `io.containerd.cgroups.v2.Metrics`
+//! that containerd expects from the shims on cgroup v2.
+//!
+//! The messages mirror
+//! <https://github.com/containerd/cgroups/blob/main/cgroup2/stats/metrics.proto>,
+//! without the hugetlb, rdma and PSI stats.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use prost::Message;
+
+pub const TYPE_URL: &str = "io.containerd.cgroups.v2.Metrics";
+
+/// Mount points of the unified hierarchy, on cgroup v2 hosts and on hybrid hosts
+const MOUNTS: [&str; 2] = ["/sys/fs/cgroup", "/sys/fs/cgroup/unified"];
+
+/// Value of an unlimited limit, like containerd does
+const MAX: u64 = u64::MAX;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metrics {
+    #[prost(message, optional, tag = "1")]
+    pub pids: Option<PidsStat>,
+    #[prost(message, optional, tag = "2")]
+    pub cpu: Option<CpuStat>,
+    #[prost(message, optional, tag = "4")]
+    pub memory: Option<MemoryStat>,
+    #[prost(message, optional, tag = "6")]
+    pub io: Option<IoStat>,
+    #[prost(message, optional, tag = "8")]
+    pub memory_events: Option<MemoryEvents>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PidsStat {
+    #[prost(uint64, tag = "1")]
+    pub current: u64,
+    #[prost(uint64, tag = "2")]
+    pub limit: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CpuStat {
+    #[prost(uint64, tag = "1")]
+    pub usage_usec: u64,
+    #[prost(uint64, tag = "2")]
+    pub user_usec: u64,
+    #[prost(uint64, tag = "3")]
+    pub system_usec: u64,
+    #[prost(uint64, tag = "4")]
+    pub nr_periods: u64,
+    #[prost(uint64, tag = "5")]
+    pub nr_throttled: u64,
+    #[prost(uint64, tag = "6")]
+    pub throttled_usec: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MemoryStat {
+    #[prost(uint64, tag = "1")]
+    pub anon: u64,
+    #[prost(uint64, tag = "2")]
+    pub file: u64,
+    #[prost(uint64, tag = "3")]
+    pub kernel_stack: u64,
+    #[prost(uint64, tag = "4")]
+    pub slab: u64,
+    #[prost(uint64, tag = "5")]
+    pub sock: u64,
+    #[prost(uint64, tag = "6")]
+    pub shmem: u64,
+    #[prost(uint64, tag = "7")]
+    pub file_mapped: u64,
+    #[prost(uint64, tag = "8")]
+    pub file_dirty: u64,
+    #[prost(uint64, tag = "9")]
+    pub file_writeback: u64,
+    #[prost(uint64, tag = "10")]
+    pub anon_thp: u64,
+    #[prost(uint64, tag = "11")]
+    pub inactive_anon: u64,
+    #[prost(uint64, tag = "12")]
+    pub active_anon: u64,
+    #[prost(uint64, tag = "13")]
+    pub inactive_file: u64,
+    #[prost(uint64, tag = "14")]
+    pub active_file: u64,
+    #[prost(uint64, tag = "15")]
+    pub unevictable: u64,
+    #[prost(uint64, tag = "16")]
+    pub slab_reclaimable: u64,
+    #[prost(uint64, tag = "17")]
+    pub slab_unreclaimable: u64,
+    #[prost(uint64, tag = "18")]
+    pub pgfault: u64,
+    #[prost(uint64, tag = "19")]
+    pub pgmajfault: u64,
+    #[prost(uint64, tag = "20")]
+    pub workingset_refault: u64,
+    #[prost(uint64, tag = "21")]
+    pub workingset_activate: u64,
+    #[prost(uint64, tag = "22")]
+    pub workingset_nodereclaim: u64,
+    #[prost(uint64, tag = "23")]
+    pub pgrefill: u64,
+    #[prost(uint64, tag = "24")]
+    pub pgscan: u64,
+    #[prost(uint64, tag = "25")]
+    pub pgsteal: u64,
+    #[prost(uint64, tag = "26")]
+    pub pgactivate: u64,
+    #[prost(uint64, tag = "27")]
+    pub pgdeactivate: u64,
+    #[prost(uint64, tag = "28")]
+    pub pglazyfree: u64,
+    #[prost(uint64, tag = "29")]
+    pub pglazyfreed: u64,
+    #[prost(uint64, tag = "30")]
+    pub thp_fault_alloc: u64,
+    #[prost(uint64, tag = "31")]
+    pub thp_collapse_alloc: u64,
+    #[prost(uint64, tag = "32")]
+    pub usage: u64,
+    #[prost(uint64, tag = "33")]
+    pub usage_limit: u64,
+    #[prost(uint64, tag = "34")]
+    pub swap_usage: u64,
+    #[prost(uint64, tag = "35")]
+    pub swap_limit: u64,
+    #[prost(uint64, tag = "36")]
+    pub max_usage: u64,
+    #[prost(uint64, tag = "37")]
+    pub swap_max_usage: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MemoryEvents {
+    #[prost(uint64, tag = "1")]
+    pub low: u64,
+    #[prost(uint64, tag = "2")]
+    pub high: u64,
+    #[prost(uint64, tag = "3")]
+    pub max: u64,
+    #[prost(uint64, tag = "4")]
+    pub oom: u64,
+    #[prost(uint64, tag = "5")]
+    pub oom_kill: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct IoStat {
+    #[prost(message, repeated, tag = "1")]
+    pub usage: Vec<IoEntry>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct IoEntry {
+    #[prost(uint64, tag = "1")]
+    pub major: u64,
+    #[prost(uint64, tag = "2")]
+    pub minor: u64,
+    #[prost(uint64, tag = "3")]
+    pub rbytes: u64,
+    #[prost(uint64, tag = "4")]
+    pub wbytes: u64,
+    #[prost(uint64, tag = "5")]
+    pub rios: u64,
+    #[prost(uint64, tag = "6")]
+    pub wios: u64,
+}
+
+/// Cgroup of the process `pid` in the unified hierarchy, if its controllers are there.
+///
+/// On hybrid hosts, the unified hierarchy usually has no controllers, and the container
+/// was placed in the v1 hierarchies, whose metrics are then reported instead.
+pub fn cgroup_of(pid: u32) -> Option<PathBuf> {
+    let cgroups = fs::read_to_string(format!("/proc/{pid}/cgroup")).ok()?;
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    let root = MOUNTS
+        .iter()
+        .find(|mount| Path::new(mount).join("cgroup.controllers").exists())?;
+    let dir = Path::new(root).join(path.trim_start_matches('/'));
+    let controllers = fs::read_to_string(dir.join("cgroup.controllers")).ok()?;
+    controllers
+        .split_whitespace()
+        .any(|c| matches!(c, "cpu" | "memory" | "io" | "pids"))
+        .then_some(dir)
+}
+
+/// Read the metrics of the cgroup `dir`
+pub fn collect(dir: &Path) -> Metrics {
+    metrics(|file| fs::read_to_string(dir.join(file)).ok())
+}
+
+/// Map the stat files of a cgroup to its metrics.
+/// The files that `read` can't read, e.g., of a controller that is not enabled, are left out.
+fn metrics(read: impl Fn(&str) -> Option<String>) -> Metrics {
+    let value = |file: &str| read(file).map(|s| parse_value(&s)).unwrap_or_default();
+
+    let pids = read("pids.current").map(|current| PidsStat {
+        current: parse_value(&current),
+        limit: value("pids.max"),
+    });
+
+    let cpu = read("cpu.stat").map(|stat| {
+        let stat = parse_keyed(&stat);
+        let get = |key: &str| stat.get(key).copied().unwrap_or_default();
+        CpuStat {
+            usage_usec: get("usage_usec"),
+            user_usec: get("user_usec"),
+            system_usec: get("system_usec"),
+            nr_periods: get("nr_periods"),
+            nr_throttled: get("nr_throttled"),
+            throttled_usec: get("throttled_usec"),
+        }
+    });
+
+    let memory = read("memory.stat").map(|stat| {
+        let stat = parse_keyed(&stat);
+        let get = |key: &str| stat.get(key).copied().unwrap_or_default();
+        MemoryStat {
+            anon: get("anon"),
+            file: get("file"),
+            kernel_stack: get("kernel_stack"),
+            slab: get("slab"),
+            sock: get("sock"),
+            shmem: get("shmem"),
+            file_mapped: get("file_mapped"),
+            file_dirty: get("file_dirty"),
+            file_writeback: get("file_writeback"),
+            anon_thp: get("anon_thp"),
+            inactive_anon: get("inactive_anon"),
+            active_anon: get("active_anon"),
+            inactive_file: get("inactive_file"),
+            active_file: get("active_file"),
+            unevictable: get("unevictable"),
+            slab_reclaimable: get("slab_reclaimable"),
+            slab_unreclaimable: get("slab_unreclaimable"),
+            pgfault: get("pgfault"),
+            pgmajfault: get("pgmajfault"),
+            workingset_refault: get("workingset_refault_file")
+                + get("workingset_refault_anon")
+                + get("workingset_refault"),
+            workingset_activate: get("workingset_activate_file")
+                + get("workingset_activate_anon")
+                + get("workingset_activate"),
+            workingset_nodereclaim: get("workingset_nodereclaim"),
+            pgrefill: get("pgrefill"),
+            pgscan: get("pgscan"),
+            pgsteal: get("pgsteal"),
+            pgactivate: get("pgactivate"),
+            pgdeactivate: get("pgdeactivate"),
+            pglazyfree: get("pglazyfree"),
+            pglazyfreed: get("pglazyfreed"),
+            thp_fault_alloc: get("thp_fault_alloc"),
+            thp_collapse_alloc: get("thp_collapse_alloc"),
+            usage: value("memory.current"),
+            usage_limit: value("memory.max"),
+            swap_usage: value("memory.swap.current"),
+            swap_limit: value("memory.swap.max"),
+            // only on kernels from 5.19
+            max_usage: value("memory.peak"),
+            swap_max_usage: value("memory.swap.peak"),
+        }
+    });
+
+    let memory_events = read("memory.events").map(|events| {
+        let events = parse_keyed(&events);
+        let get = |key: &str| events.get(key).copied().unwrap_or_default();
+        MemoryEvents {
+            low: get("low"),
+            high: get("high"),
+            max: get("max"),
+            oom: get("oom"),
+            oom_kill: get("oom_kill"),
+        }
+    });
+
+    let io = read("io.stat").map(|stat| IoStat {
+        usage: parse_io_stat(&stat),
+    });
+
+    Metrics {
+        pids,
+        cpu,
+        memory,
+        io,
+        memory_events,
+    }
+}
+
+/// Parse a file with a single value, e.g., `memory.max`, where `max` is no limit
+fn parse_value(content: &str) -> u64 {
+    match content.trim() {
+        "max" => MAX,
+        value => value.parse().unwrap_or_default(),
+    }
+}
+
+/// Parse a flat keyed file, e.g., `memory.stat`, with a `key value` pair per line
+fn parse_keyed(content: &str) -> HashMap<&str, u64> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            Some((key, value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Parse `io.stat`, with a line per device like `8:0 rbytes=1 wbytes=2 rios=3 wios=4 dbytes=0 dios=0`
+fn parse_io_stat(content: &str) -> Vec<IoEntry> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (major, minor) = fields.next()?.split_once(':')?;
+            let mut entry = IoEntry {
+                major: major.parse().ok()?,
+                minor: minor.parse().ok()?,
+                ..Default::default()
+            };
+            for (key, value) in fields.filter_map(|f| f.split_once('=')) {
+                let value = value.parse().unwrap_or_default();
+                match key {
+                    "rbytes" => entry.rbytes = value,
+                    "wbytes" => entry.wbytes = value,
+                    "rios" => entry.rios = value,
+                    "wios" => entry.wios = value,
+                    _ => {}
+                }
+            }
+            Some(entry)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Stat files copied from the cgroup of a container on a cgroup v2 host
+    fn fixture(file: &str) -> Option<String> {
+        let content = match file {
+            "cpu.stat" => include_str!("testdata/cpu.stat"),
+            "io.stat" => include_str!("testdata/io.stat"),
+            "memory.current" => include_str!("testdata/memory.current"),
+            "memory.events" => include_str!("testdata/memory.events"),
+            "memory.max" => include_str!("testdata/memory.max"),
+            "memory.peak" => include_str!("testdata/memory.peak"),
+            "memory.stat" => include_str!("testdata/memory.stat"),
+            "memory.swap.current" => include_str!("testdata/memory.swap.current"),
+            "memory.swap.max" => include_str!("testdata/memory.swap.max"),
+            "pids.current" => include_str!("testdata/pids.current"),
+            "pids.max" => include_str!("testdata/pids.max"),
+            _ => return None,
+        };
+        Some(content.to_string())
+    }
+
+    #[test]
+    fn maps_stat_files() {
+        let metrics = metrics(fixture);
+
+        let pids = metrics.pids.unwrap();
+        assert_eq!(pids.current, 3);
+        assert_eq!(pids.limit, 4915);
+
+        let cpu = metrics.cpu.unwrap();
+        assert_eq!(cpu.usage_usec, 1290447);
+        assert_eq!(cpu.user_usec, 1023479);
+        assert_eq!(cpu.system_usec, 266968);
+        assert_eq!(cpu.nr_periods, 120);
+        assert_eq!(cpu.nr_throttled, 7);
+        assert_eq!(cpu.throttled_usec, 94811);
+
+        let memory = metrics.memory.unwrap();
+        assert_eq!(memory.anon, 6512640);
+        assert_eq!(memory.file, 24576000);
+        assert_eq!(memory.kernel_stack, 49152);
+        assert_eq!(memory.slab, 1015344);
+        assert_eq!(memory.file_mapped, 17301504);
+        assert_eq!(memory.inactive_anon, 6479872);
+        assert_eq!(memory.active_file, 5283840);
+        assert_eq!(memory.pgfault, 5324);
+        assert_eq!(memory.pgmajfault, 141);
+        assert_eq!(memory.workingset_refault, 12);
+        assert_eq!(memory.usage, 32923648);
+        assert_eq!(memory.usage_limit, 134217728);
+        assert_eq!(memory.swap_usage, 0);
+        assert_eq!(memory.swap_limit, MAX);
+        assert_eq!(memory.max_usage, 35258368);
+        // the file is not there on kernels before 6.5
+        assert_eq!(memory.swap_max_usage, 0);
+
+        let events = metrics.memory_events.unwrap();
+        assert_eq!(events.max, 21);
+        assert_eq!(events.oom, 1);
+        assert_eq!(events.oom_kill, 1);
+
+        let io = metrics.io.unwrap();
+        assert_eq!(io.usage.len(), 2);
+        assert_eq!(
+            io.usage[0],
+            IoEntry {
+                major: 259,
+                minor: 0,
+                rbytes: 21745664,
+                wbytes: 4096,
+                rios: 527,
+                wios: 1,
+            }
+        );
+        assert_eq!(io.usage[1].major, 8);
+        assert_eq!(io.usage[1].wbytes, 1048576);
+    }
+
+    #[test]
+    fn leaves_out_missing_controllers() {
+        let metrics = metrics(|file| match file {
+            "memory.current" | "memory.max" => fixture(file),
+            _ => None,
+        });
+        assert_eq!(metrics.pids, None);
+        assert_eq!(metrics.cpu, None);
+        assert_eq!(metrics.memory, None);
+        assert_eq!(metrics.io, None);
+    }
+}
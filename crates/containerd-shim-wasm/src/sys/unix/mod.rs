@@ -2,4 +2,7 @@ pub mod container;
 pub mod metrics;
 pub mod stdio;
 
+#[cfg(target_os = "linux")]
+pub(crate) mod notify;
+mod oom;
 mod pid_fd;
@@ -0,0 +1,278 @@
+//! Notifications of the service manager, with the `sd_notify` protocol of systemd.
+//! The shim notifies when it is ready to serve its TTRPC API, and pings the watchdog.
+//! It only notifies the service manager that started it, and not, e.g., the one of the
+//! containerd that spawned it, whose `NOTIFY_SOCKET` the shim inherits and forgets.
+//!
+//! See <https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html>
+
+use std::os::linux::net::SocketAddrExt as _;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Once;
+use std::time::Duration;
+use std::{env, thread};
+
+use anyhow::{Context, Result, ensure};
+
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID_ENV: &str = "WATCHDOG_PID";
+const SYSTEMD_EXEC_PID_ENV: &str = "SYSTEMD_EXEC_PID";
+
+/// The pid of the start command, which it sets for the shim it spawns
+pub const START_PID_ENV: &str = "RUNWASI_SHIM_START_PID";
+
+/// Notify that the shim is ready, and start pinging the watchdog when it is enabled.
+/// Nothing is sent without a `NOTIFY_SOCKET`.
+pub fn ready() {
+    static READY: Once = Once::new();
+    READY.call_once(|| {
+        if let Err(err) = notify(&[("READY", "1")]) {
+            log::warn!("failed to notify the readiness of the shim: {err:#}");
+        }
+        if let Some(interval) = watchdog_interval() {
+            thread::spawn(move || ping_watchdog(interval));
+        }
+    });
+}
+
+/// Remove a `NOTIFY_SOCKET` that isn't the one of the shim, so that neither the shim nor
+/// its containers notify another service, e.g., the one of containerd.
+/// This must be called before the shim starts any thread, as it changes the environment.
+pub fn forget_foreign_socket() {
+    if env::var_os(NOTIFY_SOCKET_ENV).is_none() || own_notify_socket() {
+        return;
+    }
+    log::debug!("ignoring the {NOTIFY_SOCKET_ENV} of another process");
+    // SAFETY: the shim doesn't run other threads yet
+    unsafe {
+        env::remove_var(NOTIFY_SOCKET_ENV);
+    }
+}
+
+/// Notify that the shim is shutting down
+pub fn stopping() {
+    if let Err(err) = notify(&[("STOPPING", "1")]) {
+        log::warn!("failed to notify the shutdown of the shim: {err:#}");
+    }
+}
+
+/// Send the `fields` to the `NOTIFY_SOCKET`, returning whether they were sent
+pub fn notify(fields: &[(&str, &str)]) -> Result<bool> {
+    let Some(socket) = env::var_os(NOTIFY_SOCKET_ENV) else {
+        return Ok(false);
+    };
+    if !own_notify_socket() {
+        return Ok(false);
+    }
+    let socket = socket.to_string_lossy();
+    // an abstract socket starts with '@'
+    let addr = match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&*socket)?,
+    };
+    let message = message(fields)?;
+    UnixDatagram::unbound()?
+        .send_to_addr(message.as_bytes(), &addr)
+        .with_context(|| format!("failed to send {message:?} to {socket}"))?;
+    Ok(true)
+}
+
+/// The notification with the `KEY=VALUE` assignments of `fields`, one per line
+fn message(fields: &[(&str, &str)]) -> Result<String> {
+    let mut message = String::new();
+    for (key, value) in fields {
+        ensure!(
+            !key.is_empty() && !key.contains(['=', '\n']),
+            "invalid notification field {key:?}"
+        );
+        ensure!(
+            !value.contains('\n'),
+            "invalid value {value:?} of notification field {key}"
+        );
+        message.push_str(&format!("{key}={value}\n"));
+    }
+    Ok(message)
+}
+
+/// Whether the `NOTIFY_SOCKET` is the one of the service manager that started the shim, or
+/// the start command that spawned it. The service manager sets `SYSTEMD_EXEC_PID` to the
+/// process it started. Without it, e.g., before systemd 248, the socket can't be told from
+/// the one of the containerd that spawned the shim, and is taken to be foreign.
+fn own_notify_socket() -> bool {
+    let Some(exec_pid) = env::var(SYSTEMD_EXEC_PID_ENV)
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+    else {
+        return false;
+    };
+    exec_pid == std::process::id()
+        || env::var(START_PID_ENV).is_ok_and(|pid| pid.parse::<u32>().ok() == Some(exec_pid))
+}
+
+/// Half of the watchdog timeout, when the watchdog of the service manager watches this process
+fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var(WATCHDOG_USEC_ENV).ok()?.parse::<u64>().ok()?;
+    // the watchdog of another process, e.g., the containerd that spawned the shim
+    if let Ok(pid) = env::var(WATCHDOG_PID_ENV) {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec) / 2)
+}
+
+fn ping_watchdog(interval: Duration) {
+    loop {
+        if let Err(err) = notify(&[("WATCHDOG", "1")]) {
+            log::warn!("failed to ping the watchdog: {err:#}");
+        }
+        thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    #[test]
+    fn test_message() -> Result<()> {
+        assert_eq!(message(&[("READY", "1")])?, "READY=1\n");
+        assert_eq!(
+            message(&[("READY", "1"), ("STATUS", "serving 2 containers")])?,
+            "READY=1\nSTATUS=serving 2 containers\n"
+        );
+        assert_eq!(message(&[])?, "");
+
+        assert!(message(&[("READY=1", "1")]).is_err());
+        assert!(message(&[("", "1")]).is_err());
+        assert!(message(&[("STATUS", "line\nWATCHDOG=1")]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_notify() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("notify.sock");
+        let socket = UnixDatagram::bind(&path)?;
+
+        let pid = std::process::id().to_string();
+        let sent = temp_env::with_vars(
+            [
+                (NOTIFY_SOCKET_ENV, Some(path.as_os_str())),
+                (SYSTEMD_EXEC_PID_ENV, Some(OsStr::new(&pid))),
+            ],
+            || notify(&[("WATCHDOG", "1")]),
+        )?;
+        assert!(sent);
+
+        let mut buf = [0; 64];
+        let n = socket.recv(&mut buf)?;
+        assert_eq!(&buf[..n], b"WATCHDOG=1\n");
+
+        // without a socket, nothing is sent
+        let sent = temp_env::with_var_unset(NOTIFY_SOCKET_ENV, || notify(&[("READY", "1")]))?;
+        assert!(!sent);
+
+        // nor to the socket of another process
+        let sent = temp_env::with_vars(
+            [
+                (NOTIFY_SOCKET_ENV, Some(path.as_os_str())),
+                (SYSTEMD_EXEC_PID_ENV, None),
+            ],
+            || notify(&[("READY", "1")]),
+        )?;
+        assert!(!sent);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_own_notify_socket() {
+        let pid = std::process::id().to_string();
+        // the socket of an older systemd, or of a containerd run by it
+        temp_env::with_vars_unset([SYSTEMD_EXEC_PID_ENV, START_PID_ENV], || {
+            assert!(!own_notify_socket())
+        });
+        temp_env::with_vars(
+            [(SYSTEMD_EXEC_PID_ENV, None), (START_PID_ENV, Some("1"))],
+            || assert!(!own_notify_socket()),
+        );
+        temp_env::with_vars(
+            [
+                (SYSTEMD_EXEC_PID_ENV, Some(pid.as_str())),
+                (START_PID_ENV, None),
+            ],
+            || assert!(own_notify_socket()),
+        );
+        // the start command that spawned the shim was started by the service manager
+        temp_env::with_vars(
+            [
+                (SYSTEMD_EXEC_PID_ENV, Some("1")),
+                (START_PID_ENV, Some("1")),
+            ],
+            || assert!(own_notify_socket()),
+        );
+        // the socket of another service, e.g., of containerd
+        temp_env::with_vars(
+            [
+                (SYSTEMD_EXEC_PID_ENV, Some("1")),
+                (START_PID_ENV, Some("2")),
+            ],
+            || assert!(!own_notify_socket()),
+        );
+        temp_env::with_vars(
+            [(SYSTEMD_EXEC_PID_ENV, Some("1")), (START_PID_ENV, None)],
+            || assert!(!own_notify_socket()),
+        );
+    }
+
+    #[test]
+    fn test_forget_foreign_socket() {
+        let pid = std::process::id().to_string();
+        temp_env::with_vars(
+            [
+                (NOTIFY_SOCKET_ENV, Some("@notify")),
+                (SYSTEMD_EXEC_PID_ENV, Some(pid.as_str())),
+            ],
+            || {
+                forget_foreign_socket();
+                assert_eq!(env::var(NOTIFY_SOCKET_ENV).as_deref(), Ok("@notify"));
+            },
+        );
+        temp_env::with_vars(
+            [
+                (NOTIFY_SOCKET_ENV, Some("@notify")),
+                (SYSTEMD_EXEC_PID_ENV, None),
+            ],
+            || {
+                forget_foreign_socket();
+                assert!(env::var_os(NOTIFY_SOCKET_ENV).is_none());
+            },
+        );
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        let pid = std::process::id().to_string();
+        temp_env::with_vars(
+            [
+                (WATCHDOG_USEC_ENV, Some("2000000")),
+                (WATCHDOG_PID_ENV, Some(pid.as_str())),
+            ],
+            || assert_eq!(watchdog_interval(), Some(Duration::from_secs(1))),
+        );
+        temp_env::with_vars(
+            [
+                (WATCHDOG_USEC_ENV, Some("2000000")),
+                (WATCHDOG_PID_ENV, Some("1")),
+            ],
+            || assert_eq!(watchdog_interval(), None),
+        );
+        temp_env::with_vars_unset([WATCHDOG_USEC_ENV, WATCHDOG_PID_ENV], || {
+            assert_eq!(watchdog_interval(), None)
+        });
+    }
+}
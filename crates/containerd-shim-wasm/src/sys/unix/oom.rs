@@ -0,0 +1,191 @@
+//! Detection of the OOM kills in the memory cgroup of a container, so that the shim
+//! can report them with a `TaskOOM` event, e.g., for Kubernetes to show `OOMKilled`.
+
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd};
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use tokio::io::unix::AsyncFd;
+
+/// Mount point of the memory controller on cgroup v1 hosts
+const CGROUP1_MEMORY: &str = "/sys/fs/cgroup/memory";
+
+/// Waits for the OOM kills in a memory cgroup.
+/// The file descriptors of the watcher are closed when it's dropped.
+pub(super) struct OomWatcher {
+    /// The file with the `oom_kill` counter of the cgroup
+    counter: PathBuf,
+    /// Readable when the counter might have changed: an inotify of `memory.events`
+    /// on cgroup v2, or an eventfd registered for `memory.oom_control` on cgroup v1
+    ready: AsyncFd<OwnedFd>,
+    /// The `memory.oom_control` file that the eventfd is registered for, on cgroup v1
+    _control: Option<File>,
+    seen: u64,
+}
+
+impl OomWatcher {
+    /// Watch the memory cgroup of the process `pid`
+    pub(super) fn new(pid: u32) -> Result<Self> {
+        let (counter, ready, control) = if let Some(dir) = crate::sys::metrics::cgroup_of(pid) {
+            let events = dir.join("memory.events");
+            let ready = inotify(&events)?;
+            (events, ready, None)
+        } else {
+            let cgroups = fs::read_to_string(format!("/proc/{pid}/cgroup"))?;
+            let path = cgroups
+                .lines()
+                .find_map(|line| {
+                    let (_, rest) = line.split_once(':')?;
+                    let (controllers, path) = rest.split_once(':')?;
+                    controllers
+                        .split(',')
+                        .any(|c| c == "memory")
+                        .then_some(path)
+                })
+                .context("the process has no memory cgroup")?;
+            let dir = Path::new(CGROUP1_MEMORY).join(path.trim_start_matches('/'));
+            let control_path = dir.join("memory.oom_control");
+            let control = File::open(&control_path)?;
+            let ready = eventfd()?;
+            // register the eventfd to be notified of the OOMs of the cgroup
+            let registration = format!("{} {}", ready.as_raw_fd(), control.as_raw_fd());
+            fs::write(dir.join("cgroup.event_control"), registration)?;
+            (control_path, ready, Some(control))
+        };
+
+        let mut watcher = Self {
+            counter,
+            ready: AsyncFd::new(ready)?,
+            _control: control,
+            seen: 0,
+        };
+        watcher.seen = watcher.oom_kills();
+        Ok(watcher)
+    }
+
+    /// The number of processes of the cgroup killed by the OOM killer
+    fn oom_kills(&self) -> u64 {
+        fs::read_to_string(&self.counter)
+            .map(|content| oom_kills(&content))
+            .unwrap_or_default()
+    }
+
+    /// Waits for the next OOM kill in the cgroup.
+    /// A kill that happened since the last call returns right away.
+    pub(super) async fn wait(&mut self) -> Result<()> {
+        loop {
+            let kills = self.oom_kills();
+            if kills > self.seen {
+                self.seen = kills;
+                return Ok(());
+            }
+            let mut guard = self.ready.readable().await?;
+            // drain the notifications, which only mean that the counter might have changed
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = unsafe {
+                    libc::read(
+                        guard.get_inner().as_raw_fd(),
+                        buf.as_mut_ptr().cast(),
+                        buf.len(),
+                    )
+                };
+                if n > 0 {
+                    continue;
+                }
+                let err = std::io::Error::last_os_error();
+                if n == 0 || err.kind() == ErrorKind::WouldBlock {
+                    guard.clear_ready();
+                    break;
+                }
+                if err.kind() != ErrorKind::Interrupted {
+                    return Err(err.into());
+                }
+            }
+        }
+    }
+}
+
+/// The `oom_kill` counter of `memory.events` or `memory.oom_control`
+fn oom_kills(content: &str) -> u64 {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or_default()
+}
+
+fn inotify(path: &Path) -> Result<OwnedFd> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), path.as_ptr(), libc::IN_MODIFY) } == -1 {
+        bail!(
+            "failed to watch {path:?}: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(fd)
+}
+
+fn eventfd() -> Result<OwnedFd> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    if fd == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oom_kills() {
+        let events = include_str!("metrics/testdata/memory.events");
+        assert_eq!(oom_kills(events), 1);
+
+        let control = "oom_kill_disable 0\nunder_oom 0\noom_kill 3\n";
+        assert_eq!(oom_kills(control), 3);
+
+        assert_eq!(oom_kills(""), 0);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_oom_kill() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let events = dir.path().join("memory.events");
+        fs::write(&events, "oom 0\noom_kill 0\n")?;
+
+        let mut watcher = OomWatcher {
+            counter: events.clone(),
+            ready: AsyncFd::new(inotify(&events)?)?,
+            _control: None,
+            seen: 0,
+        };
+
+        let wait = tokio::spawn(async move {
+            watcher.wait().await?;
+            anyhow::Ok(watcher)
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!wait.is_finished());
+
+        fs::write(&events, "oom 1\noom_kill 1\n")?;
+        let mut watcher = tokio::time::timeout(std::time::Duration::from_secs(5), wait).await???;
+        assert_eq!(watcher.seen, 1);
+
+        // a kill since the last call returns right away
+        fs::write(&events, "oom 2\noom_kill 2\n")?;
+        watcher.wait().await?;
+        assert_eq!(watcher.seen, 2);
+        Ok(())
+    }
+}
@@ -9,26 +9,46 @@ use nix::unistd::Pid;
 use tokio::io::unix::AsyncFd;
 
 pub(super) struct PidFd {
-    fd: OwnedFd,
+    /// `None` if the process was already reaped when the pidfd was opened
+    fd: Option<OwnedFd>,
     pid: pid_t,
     subs: Subscription,
 }
 
 impl PidFd {
     pub(super) fn new(pid: impl Into<pid_t>) -> anyhow::Result<Self> {
-        use libc::{PIDFD_NONBLOCK, SYS_pidfd_open, syscall};
-        let pid = pid.into();
         let subs = monitor_subscribe(Topic::Pid)?;
+        Self::with_subscription(pid, subs)
+    }
+
+    /// Like `new`, for a process that might have exited and been reaped already.
+    /// `subs` must have been subscribed before the process was started, so that
+    /// its exit status can be read from there.
+    pub(super) fn with_subscription(
+        pid: impl Into<pid_t>,
+        subs: Subscription,
+    ) -> anyhow::Result<Self> {
+        use libc::{ESRCH, PIDFD_NONBLOCK, SYS_pidfd_open, syscall};
+        let pid = pid.into();
         let pidfd = unsafe { syscall(SYS_pidfd_open, pid, PIDFD_NONBLOCK) };
-        if pidfd == -1 {
-            return Err(std::io::Error::last_os_error().into());
-        }
-        let fd = unsafe { OwnedFd::from_raw_fd(pidfd as RawFd) };
+        let fd = if pidfd == -1 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(ESRCH) {
+                return Err(err.into());
+            }
+            None
+        } else {
+            Some(unsafe { OwnedFd::from_raw_fd(pidfd as RawFd) })
+        };
         Ok(Self { fd, pid, subs })
     }
 
     pub(super) async fn wait(self) -> std::io::Result<WaitStatus> {
-        let fd = AsyncFd::new(self.fd)?;
+        let Some(fd) = self.fd else {
+            let status = try_wait_pid(self.pid, self.subs).await?;
+            return Ok(WaitStatus::Exited(Pid::from_raw(self.pid), status));
+        };
+        let fd = AsyncFd::new(fd)?;
         loop {
             // Check with non-blocking waitid before awaiting on fd.
             // On some platforms, the readiness detecting mechanism relies on
@@ -1,7 +1,656 @@
-use std::fs::{File, OpenOptions};
-use std::io::Result;
-use std::path::Path;
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions, remove_file};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::fd::{AsRawFd as _, RawFd};
+use std::os::unix::ffi::OsStrExt as _;
+use std::os::unix::fs::{FileTypeExt as _, OpenOptionsExt as _};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Output buffered while the FIFO from containerd has no reader
+const MAX_PENDING: usize = 1024 * 1024;
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// How often the input relay checks if it was closed
+const CLOSE_POLL: Duration = Duration::from_secs(1);
+const DEV_NULL: &str = "/dev/null";
 
 pub fn open(path: impl AsRef<Path>) -> Result<File> {
     OpenOptions::new().read(true).write(true).open(path)
 }
+
+/// What a stdio path from containerd is connected to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    /// A FIFO, read by containerd or by a logging driver
+    Fifo,
+    /// A file, e.g., with the `file://` log URI of `ctr`, created if it doesn't exist
+    File,
+    /// The stream isn't used, and is the null device
+    Null,
+}
+
+impl Stream {
+    pub fn of(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        if path.as_os_str().is_empty() {
+            return Self::Null;
+        }
+        match path.metadata() {
+            Ok(metadata) if metadata.file_type().is_fifo() => Self::Fifo,
+            _ => Self::File,
+        }
+    }
+}
+
+/// Open the stdin `path` of a container, without a relay, e.g., for an exec.
+/// A FIFO is opened read-write, as the guest would read an EOF before containerd opens it
+/// otherwise, and a file is read to its end.
+pub fn open_input(path: impl AsRef<Path>) -> Result<File> {
+    let path = path.as_ref();
+    match Stream::of(path) {
+        Stream::Fifo => open(path),
+        Stream::File => File::open(path),
+        Stream::Null => File::open(DEV_NULL),
+    }
+}
+
+/// Open the stdout or stderr `path` of a container.
+/// The container writes to a file directly, appending to it, so that nothing is buffered
+/// by the shim and the output of a restarted container follows the former one.
+pub fn open_output(path: impl AsRef<Path>) -> Result<File> {
+    let path = path.as_ref();
+    match Stream::of(path) {
+        Stream::Fifo => open(path),
+        Stream::File => OpenOptions::new().append(true).create(true).open(path),
+        Stream::Null => OpenOptions::new().write(true).open(DEV_NULL),
+    }
+}
+
+/// Output of a container, relayed to a FIFO from containerd through a FIFO of the shim.
+///
+/// The shim always reads its FIFO, so that the guest doesn't block or lose its output when
+/// the reader of the containerd FIFO goes away, e.g., while containerd restarts.
+/// The output is then buffered, up to `MAX_PENDING` bytes, while the containerd FIFO
+/// is reopened with a backoff, or until a new FIFO is attached.
+pub struct OutputRelay {
+    path: PathBuf,
+    writer: Option<File>,
+    closed: Arc<AtomicBool>,
+    /// A FIFO replacing the containerd FIFO, taken by the relay
+    attached_fifo: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl OutputRelay {
+    /// Relay what the container writes to the FIFO `relay` to the FIFO `fifo`
+    pub fn new(fifo: impl AsRef<Path>, relay: impl AsRef<Path>) -> Result<Self> {
+        let path = relay.as_ref().to_path_buf();
+        let fifo = fifo.as_ref().to_path_buf();
+
+        mkfifo(&path)?;
+        let reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)?;
+        // Hold a writer until the container opens the relay,
+        // otherwise the reader would see an EOF right away
+        let writer = OpenOptions::new().write(true).open(&path)?;
+        set_blocking(reader.as_raw_fd())?;
+
+        let closed = Arc::<AtomicBool>::default();
+        let attached_fifo = Arc::<Mutex<Option<PathBuf>>>::default();
+        thread::spawn({
+            let closed = closed.clone();
+            let attached_fifo = attached_fifo.clone();
+            move || relay_output(reader, fifo, closed, attached_fifo)
+        });
+
+        Ok(Self {
+            path,
+            writer: Some(writer),
+            closed,
+            attached_fifo,
+        })
+    }
+
+    /// Relay the output to the FIFO `fifo` of a new client instead, e.g., after containerd
+    /// restarted. The relay switches to it before sending its next output, and the output
+    /// buffered without a reader goes to the new FIFO.
+    pub fn attach_fifo(&self, fifo: impl AsRef<Path>) {
+        *self.attached_fifo.lock().unwrap() = Some(fifo.as_ref().to_path_buf());
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Call once the container opened the relay, so that the relay
+    /// stops when the container closes its output
+    pub fn attached(&mut self) {
+        self.writer = None;
+        let _ = remove_file(&self.path);
+    }
+}
+
+impl Drop for OutputRelay {
+    fn drop(&mut self) {
+        // don't reopen the containerd FIFO anymore
+        self.closed.store(true, Ordering::Relaxed);
+        let _ = remove_file(&self.path);
+    }
+}
+
+fn relay_output(
+    mut reader: File,
+    mut fifo: PathBuf,
+    closed: Arc<AtomicBool>,
+    attached_fifo: Arc<Mutex<Option<PathBuf>>>,
+) {
+    let mut buf = vec![0; 64 * 1024];
+    let mut pending = Pending::default();
+    let mut output = open_fifo(&fifo);
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        // wait for some output, or for the next attempt to reopen the FIFO
+        let timeout = output.is_none().then_some(backoff);
+        match wait_readable(reader.as_raw_fd(), timeout) {
+            Ok(true) => match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => pending.push(&buf[..n]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(_) => break,
+            },
+            Ok(false) => {}
+            Err(_) => break,
+        }
+
+        if closed.load(Ordering::Relaxed) {
+            break;
+        }
+
+        if let Some(attached) = attached_fifo.lock().unwrap().take() {
+            log::info!(
+                "relaying the output to {} instead of {}",
+                attached.display(),
+                fifo.display()
+            );
+            fifo = attached;
+            output = None;
+        }
+
+        if output.is_none() {
+            output = open_fifo(&fifo);
+            match output {
+                Some(_) => {
+                    log::info!("reopened {}", fifo.display());
+                    backoff = MIN_BACKOFF;
+                }
+                None => backoff = (backoff * 2).min(MAX_BACKOFF),
+            }
+        }
+
+        if let Some(w) = &mut output {
+            if let Err(err) = pending.flush(w) {
+                log::warn!(
+                    "lost the reader of {}, buffering the output: {err}",
+                    fifo.display()
+                );
+                output = None;
+            }
+        }
+    }
+
+    // the container closed its output, send what's left if the FIFO is still open
+    if let Some(w) = &mut output {
+        let _ = pending.flush(w);
+    }
+}
+
+/// Output waiting for the containerd FIFO, without the oldest bytes past `MAX_PENDING`
+#[derive(Default)]
+struct Pending {
+    buf: VecDeque<u8>,
+    dropped: usize,
+}
+
+impl Pending {
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend(data);
+        if self.buf.len() > MAX_PENDING {
+            let excess = self.buf.len() - MAX_PENDING;
+            self.buf.drain(..excess);
+            self.dropped += excess;
+        }
+    }
+
+    fn flush(&mut self, w: &mut File) -> Result<()> {
+        if self.dropped > 0 {
+            log::warn!("dropped {} bytes of output without a reader", self.dropped);
+            self.dropped = 0;
+        }
+        while !self.buf.is_empty() {
+            let (data, _) = self.buf.as_slices();
+            match w.write(data) {
+                Ok(n) => {
+                    self.buf.drain(..n);
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Open a FIFO for writing, or `None` if it has no reader
+fn open_fifo(path: &Path) -> Option<File> {
+    // with O_NONBLOCK, this fails with ENXIO instead of blocking when there is no reader
+    let file = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .ok()?;
+    set_blocking(file.as_raw_fd()).ok()?;
+    Some(file)
+}
+
+/// Input of a container, relayed from a FIFO from containerd through a FIFO of the shim,
+/// so that the shim can close it while containerd keeps its FIFO open.
+///
+/// The shim is the only writer of its FIFO, and never holds a writer of the containerd FIFO,
+/// so the guest reads an EOF as soon as the client closes its input, e.g., at the end of what
+/// is piped to `ctr run`, or once the task is sent a `CloseIO`.
+pub struct InputRelay {
+    path: PathBuf,
+    writer: Arc<Mutex<Option<File>>>,
+    /// A FIFO replacing the containerd FIFO, taken by the relay
+    attached_fifo: Arc<Mutex<Option<File>>>,
+}
+
+impl InputRelay {
+    /// Relay the FIFO `fifo` to the reader of the FIFO `relay`
+    pub fn new(fifo: impl AsRef<Path>, relay: impl AsRef<Path>) -> Result<Self> {
+        let path = relay.as_ref().to_path_buf();
+
+        mkfifo(&path)?;
+        // Opening the relay read-write doesn't block. The container opens it read-only,
+        // and reads an EOF once this writer is closed. It's non-blocking, so that the relay
+        // never holds the writer while the guest doesn't read, and `close` takes it right away.
+        let writer = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)?;
+        let writer = Arc::new(Mutex::new(Some(writer)));
+        let mut input = open_input_fifo(fifo)?;
+        let attached_fifo = Arc::<Mutex<Option<File>>>::default();
+
+        thread::spawn({
+            let writer = writer.clone();
+            let attached_fifo = attached_fifo.clone();
+            move || {
+                let mut buf = vec![0; 64 * 1024];
+                loop {
+                    if writer.lock().unwrap().is_none() {
+                        break;
+                    }
+                    if let Some(attached) = attached_fifo.lock().unwrap().take() {
+                        input = attached;
+                    }
+                    match wait_readable(input.as_raw_fd(), Some(CLOSE_POLL)) {
+                        Ok(true) => {}
+                        Ok(false) => continue,
+                        Err(_) => break,
+                    }
+                    let n = match input.read(&mut buf) {
+                        // the client closed its input
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                        Err(_) => break,
+                    };
+                    if !relay_input(&writer, &buf[..n]) {
+                        break;
+                    }
+                }
+                *writer.lock().unwrap() = None;
+            }
+        });
+
+        Ok(Self {
+            path,
+            writer,
+            attached_fifo,
+        })
+    }
+
+    /// Relay the FIFO `fifo` of a new client instead, e.g., for `kubectl attach`.
+    /// The relay switches to it within `CLOSE_POLL`, and what is left in the former FIFO
+    /// is dropped. The input is still closed when the former client closes it first.
+    pub fn attach_fifo(&self, fifo: impl AsRef<Path>) -> Result<()> {
+        *self.attached_fifo.lock().unwrap() = Some(open_input_fifo(fifo)?);
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Call once the container opened the relay
+    pub fn attached(&self) {
+        let _ = remove_file(&self.path);
+    }
+
+    /// Close the input of the container, which reads an EOF once it read what was relayed
+    pub fn close(&self) {
+        *self.writer.lock().unwrap() = None;
+    }
+}
+
+/// Open a containerd FIFO of input, only for reading, without blocking until containerd opens it.
+/// `poll` doesn't report a hang up before its first writer, so the relay reads the EOF
+/// of the client, not the one of a FIFO without writers yet.
+fn open_input_fifo(fifo: impl AsRef<Path>) -> Result<File> {
+    let input = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(fifo)?;
+    set_blocking(input.as_raw_fd())?;
+    Ok(input)
+}
+
+/// Write `data` to the relay, waiting for the guest to read it without holding the writer.
+/// Returns `false` if the relay was closed, or failed.
+fn relay_input(writer: &Mutex<Option<File>>, mut data: &[u8]) -> bool {
+    while !data.is_empty() {
+        let mut writer = writer.lock().unwrap();
+        let Some(w) = writer.as_mut() else {
+            return false;
+        };
+        match w.write(data) {
+            Ok(n) => data = &data[n..],
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                let fd = w.as_raw_fd();
+                drop(writer);
+                if wait_for(fd, libc::POLLOUT, Some(CLOSE_POLL)).is_err() {
+                    return false;
+                }
+            }
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+impl Drop for InputRelay {
+    fn drop(&mut self) {
+        self.close();
+        let _ = remove_file(&self.path);
+    }
+}
+
+pub(crate) fn mkfifo(path: &Path) -> Result<()> {
+    let _ = remove_file(path);
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub(crate) fn set_blocking(fd: RawFd) -> Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Wait until `fd` is readable, or closed, for up to `timeout`
+fn wait_readable(fd: RawFd, timeout: Option<Duration>) -> Result<bool> {
+    wait_for(fd, libc::POLLIN, timeout)
+}
+
+/// Wait for the `events` of `fd`, or its hang up, for up to `timeout`
+fn wait_for(fd: RawFd, events: libc::c_short, timeout: Option<Duration>) -> Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    let timeout = timeout.map_or(-1, |t| t.as_millis() as libc::c_int);
+    match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+        -1 => match Error::last_os_error() {
+            err if err.kind() == ErrorKind::Interrupted => Ok(false),
+            err => Err(err),
+        },
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Write};
+
+    use super::{InputRelay, OutputRelay, Pending, Stream, mkfifo, open, open_input, open_output};
+
+    #[test]
+    fn pending_is_bounded() {
+        let mut pending = Pending::default();
+        pending.push(&vec![0; super::MAX_PENDING]);
+        pending.push(b"abc");
+        assert_eq!(pending.buf.len(), super::MAX_PENDING);
+        assert_eq!(pending.dropped, 3);
+        assert_eq!(pending.buf.back(), Some(&b'c'));
+    }
+
+    #[test]
+    fn stream_of_path() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let fifo = dir.path().join("fifo");
+        mkfifo(&fifo)?;
+        std::fs::write(dir.path().join("file"), "")?;
+
+        assert_eq!(Stream::of(&fifo), Stream::Fifo);
+        assert_eq!(Stream::of(dir.path().join("file")), Stream::File);
+        assert_eq!(Stream::of(dir.path().join("missing")), Stream::File);
+        assert_eq!(Stream::of(""), Stream::Null);
+        Ok(())
+    }
+
+    #[test]
+    fn input_from_each_stream() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("stdin");
+        std::fs::write(&file, "hello")?;
+
+        let mut input = String::new();
+        open_input(&file)?.read_to_string(&mut input)?;
+        assert_eq!(input, "hello");
+
+        // the null device is empty
+        let mut input = String::new();
+        open_input("")?.read_to_string(&mut input)?;
+        assert_eq!(input, "");
+
+        // the FIFO stays open for the writer that comes later
+        let fifo = dir.path().join("stdin.fifo");
+        mkfifo(&fifo)?;
+        let mut reader = open_input(&fifo)?;
+        OpenOptions::new()
+            .write(true)
+            .open(&fifo)?
+            .write_all(b"world")?;
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"world");
+
+        assert!(open_input(dir.path().join("missing")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn input_relay_ends_with_the_client() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let fifo = dir.path().join("stdin");
+        mkfifo(&fifo)?;
+
+        let relay = InputRelay::new(&fifo, dir.path().join("stdin.relay"))?;
+        let mut container = File::open(relay.path())?;
+        relay.attached();
+
+        // the client writes its input and closes it, e.g., `echo hello | ctr run -i`
+        OpenOptions::new()
+            .write(true)
+            .open(&fifo)?
+            .write_all(b"hello")?;
+        let mut input = String::new();
+        container.read_to_string(&mut input)?;
+        assert_eq!(input, "hello");
+        Ok(())
+    }
+
+    #[test]
+    fn input_relay_close() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let fifo = dir.path().join("stdin");
+        mkfifo(&fifo)?;
+
+        let relay = InputRelay::new(&fifo, dir.path().join("stdin.relay"))?;
+        let mut container = File::open(relay.path())?;
+        relay.attached();
+
+        // the client keeps its input open, as containerd does until CloseIO
+        let mut client = OpenOptions::new().write(true).open(&fifo)?;
+        client.write_all(b"hello")?;
+        let mut buf = [0; 5];
+        container.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"hello");
+
+        relay.close();
+        let mut rest = Vec::new();
+        container.read_to_end(&mut rest)?;
+        assert!(rest.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn input_relay_attach_fifo() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let fifo = dir.path().join("stdin");
+        mkfifo(&fifo)?;
+
+        let relay = InputRelay::new(&fifo, dir.path().join("stdin.relay"))?;
+        let mut container = File::open(relay.path())?;
+        relay.attached();
+        let mut client = OpenOptions::new().write(true).open(&fifo)?;
+        client.write_all(b"hello")?;
+        let mut buf = [0; 5];
+        container.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"hello");
+
+        // a new client attaches its FIFO, while the former one is still there
+        let attached = dir.path().join("stdin.attached");
+        mkfifo(&attached)?;
+        relay.attach_fifo(&attached)?;
+        OpenOptions::new()
+            .write(true)
+            .open(&attached)?
+            .write_all(b"world")?;
+        let mut input = String::new();
+        container.read_to_string(&mut input)?;
+        assert_eq!(input, "world");
+        drop(client);
+        Ok(())
+    }
+
+    #[test]
+    fn output_to_each_stream() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+
+        for name in ["stdout", "stderr"] {
+            // the file is created, and then appended to
+            let file = dir.path().join(name);
+            open_output(&file)?.write_all(b"hello\n")?;
+            open_output(&file)?.write_all(b"world\n")?;
+            assert_eq!(std::fs::read_to_string(&file)?, "hello\nworld\n");
+
+            open_output("")?.write_all(b"discarded")?;
+
+            let fifo = dir.path().join(format!("{name}.fifo"));
+            mkfifo(&fifo)?;
+            let mut writer = open_output(&fifo)?;
+            let mut reader = OpenOptions::new().read(true).open(&fifo)?;
+            writer.write_all(b"hello")?;
+            let mut buf = [0; 5];
+            reader.read_exact(&mut buf)?;
+            assert_eq!(&buf, b"hello");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn output_survives_reader_restart() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let fifo = dir.path().join("stdout");
+        mkfifo(&fifo)?;
+
+        let mut relay = OutputRelay::new(&fifo, dir.path().join("stdout.relay"))?;
+        let mut container = OpenOptions::new().write(true).open(relay.path())?;
+        relay.attached();
+
+        // written while containerd's FIFO has no reader
+        container.write_all(b"hello")?;
+
+        let mut reader = OpenOptions::new().read(true).open(&fifo)?;
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"hello");
+
+        // the reader goes away, and comes back
+        drop(reader);
+        container.write_all(b"world")?;
+
+        let mut reader = OpenOptions::new().read(true).open(&fifo)?;
+        reader.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"world");
+
+        Ok(())
+    }
+
+    #[test]
+    fn output_to_an_attached_fifo() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let fifo = dir.path().join("stdout");
+        mkfifo(&fifo)?;
+
+        let mut relay = OutputRelay::new(&fifo, dir.path().join("stdout.relay"))?;
+        let mut container = OpenOptions::new().write(true).open(relay.path())?;
+        relay.attached();
+
+        // the reader goes away with the output of the container
+        let reader = OpenOptions::new().read(true).open(&fifo)?;
+        drop(reader);
+        container.write_all(b"hello")?;
+
+        // the new client attaches another FIFO, and reads what was buffered
+        let attached = dir.path().join("stdout.attached");
+        mkfifo(&attached)?;
+        let mut reader = open(&attached)?;
+        relay.attach_fifo(&attached);
+        container.write_all(b"world")?;
+        let mut buf = [0; 10];
+        reader.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"helloworld");
+
+        Ok(())
+    }
+}
@@ -1 +1,2 @@
+mod oom;
 mod signals;
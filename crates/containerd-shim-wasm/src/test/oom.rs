@@ -0,0 +1,63 @@
+//! This module includes a test for the detection of the OOM kills of a container,
+//! which the shim reports with a `TaskOOM` event.
+//!
+//! The engine allocates memory outside of the guest, until the kernel kills the process,
+//! as an engine that doesn't enforce the memory limit of the container on its guest would.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use containerd_shim_wasm_test_modules::HELLO_WORLD;
+use oci_spec::runtime::{LinuxMemoryBuilder, LinuxResourcesBuilder};
+
+use crate::container::{Engine, Instance, RuntimeContext};
+use crate::sandbox::Instance as _;
+use crate::sandbox::async_utils::AmbientRuntime as _;
+use crate::testing::WasiTest;
+
+#[derive(Clone, Default)]
+pub struct GreedyEngine;
+
+impl Engine for GreedyEngine {
+    fn name() -> &'static str {
+        "greedy-engine"
+    }
+
+    fn run_wasi(&self, _ctx: &impl RuntimeContext) -> Result<i32> {
+        // fill each MiB, so that the process really uses it
+        let mut chunks = vec![];
+        loop {
+            chunks.push(vec![1u8; 1024 * 1024]);
+        }
+    }
+}
+
+type GreedyInstance = Instance<GreedyEngine>;
+
+#[test]
+fn test_oom_kill_is_detected() -> Result<()> {
+    // without swap, so that the container can't swap out its memory
+    let limit: i64 = 32 * 1024 * 1024;
+    let resources = LinuxResourcesBuilder::default()
+        .memory(
+            LinuxMemoryBuilder::default()
+                .limit(limit)
+                .swap(limit)
+                .build()?,
+        )
+        .build()?;
+
+    let container = WasiTest::<GreedyInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_resources(resources)
+        .build()?;
+    container.start()?;
+
+    async { tokio::time::timeout(Duration::from_secs(10), container.instance().wait_oom()).await }
+        .block_on()??;
+
+    let (exit_code, ..) = container.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 128 + libc::SIGKILL as u32);
+
+    Ok(())
+}
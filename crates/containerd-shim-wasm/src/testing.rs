@@ -5,22 +5,25 @@ use std::fs::{self, File, create_dir, read, read_to_string, write};
 use std::marker::PhantomData;
 use std::ops::Add;
 #[cfg(unix)]
-use std::os::unix::fs::symlink;
+use std::os::unix::fs::{PermissionsExt as _, symlink};
 #[cfg(windows)]
 use std::os::windows::fs::symlink_file as symlink;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use std::time::Duration;
 
 use anyhow::{Result, bail};
 pub use containerd_shim_wasm_test_modules as modules;
 use libc::{SIGINT, SIGTERM};
 use oci_spec::runtime::{
-    LinuxBuilder, LinuxNamespace, LinuxNamespaceType, ProcessBuilder, RootBuilder, SpecBuilder,
-    get_default_namespaces,
+    LinuxBuilder, LinuxIdMapping, LinuxIdMappingBuilder, LinuxNamespace, LinuxNamespaceBuilder,
+    LinuxNamespaceType, LinuxResources, Mount, MountBuilder, PosixRlimit, ProcessBuilder,
+    RootBuilder, SpecBuilder, get_default_mounts, get_default_namespaces,
 };
 
 use crate::sandbox::async_utils::AmbientRuntime as _;
-use crate::sandbox::{Instance, InstanceConfig};
+use crate::sandbox::shim::Config;
+use crate::sandbox::{EnvPolicy, HostDir, Instance, InstanceConfig, SocketPolicy};
 
 pub const TEST_NAMESPACE: &str = "runwasi-test";
 pub const SIGKILL: u32 = 9;
@@ -29,6 +32,19 @@ pub struct WasiTestBuilder<WasiInstance: Instance> {
     container_name: String,
     start_fn: String,
     namespaces: Vec<LinuxNamespace>,
+    id_mapping: Option<LinuxIdMapping>,
+    resources: Option<LinuxResources>,
+    oom_score_adj: Option<i32>,
+    rlimits: Vec<PosixRlimit>,
+    annotations: HashMap<String, String>,
+    mounts: Vec<Mount>,
+    readonly_rootfs: bool,
+    host_dirs: Vec<HostDir>,
+    env: Vec<String>,
+    env_policy: EnvPolicy,
+    socket_policy: SocketPolicy,
+    memory_reserve: Option<u64>,
+    compile_cache_dir: Option<PathBuf>,
     tempdir: tempfile::TempDir,
     _phantom: PhantomData<WasiInstance>,
 }
@@ -36,6 +52,7 @@ pub struct WasiTestBuilder<WasiInstance: Instance> {
 pub struct WasiTest<WasiInstance: Instance> {
     instance: WasiInstance,
     tempdir: tempfile::TempDir,
+    pid: OnceLock<u32>,
 }
 
 impl<WasiInstance: Instance> WasiTestBuilder<WasiInstance> {
@@ -67,6 +84,19 @@ impl<WasiInstance: Instance> WasiTestBuilder<WasiInstance> {
             container_name: "test".to_string(),
             start_fn: "".to_string(),
             namespaces: get_default_namespaces(),
+            id_mapping: None,
+            resources: None,
+            oom_score_adj: None,
+            rlimits: vec![],
+            annotations: HashMap::new(),
+            mounts: vec![],
+            readonly_rootfs: false,
+            host_dirs: vec![],
+            env: vec![],
+            env_policy: EnvPolicy::default(),
+            socket_policy: SocketPolicy::default(),
+            memory_reserve: None,
+            compile_cache_dir: None,
             _phantom: Default::default(),
         }
         .with_wasm([0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00])?
@@ -89,6 +119,120 @@ impl<WasiInstance: Instance> WasiTestBuilder<WasiInstance> {
         self
     }
 
+    /// Run the container in a new user namespace, where the ids from 0 to `size` are mapped
+    /// to the ids of the host from `host_id`
+    #[cfg(unix)]
+    pub fn with_user_namespace(mut self, host_id: u32, size: u32) -> Result<Self> {
+        self.namespaces.push(
+            LinuxNamespaceBuilder::default()
+                .typ(LinuxNamespaceType::User)
+                .build()?,
+        );
+        self.id_mapping = Some(
+            LinuxIdMappingBuilder::default()
+                .container_id(0u32)
+                .host_id(host_id)
+                .size(size)
+                .build()?,
+        );
+        // the root of the namespace mounts the rootfs from the bundle
+        fs::set_permissions(self.tempdir.path(), fs::Permissions::from_mode(0o711))?;
+        Ok(self)
+    }
+
+    /// Limit the resources of the container, as with `linux.resources` in the spec
+    pub fn with_resources(mut self, resources: LinuxResources) -> Self {
+        self.resources = Some(resources);
+        self
+    }
+
+    /// The `process.oomScoreAdj` of the spec
+    pub fn with_oom_score_adj(mut self, oom_score_adj: i32) -> Self {
+        self.oom_score_adj = Some(oom_score_adj);
+        self
+    }
+
+    /// Add a limit to the `process.rlimits` of the spec
+    pub fn with_rlimit(mut self, rlimit: PosixRlimit) -> Self {
+        self.rlimits.push(rlimit);
+        self
+    }
+
+    pub fn with_annotation(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.annotations.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn with_env(mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        self.env
+            .push(format!("{}={}", key.as_ref(), value.as_ref()));
+        self
+    }
+
+    /// The env policy of the node, as in the runtime options of the shim
+    pub fn with_env_policy(mut self, policy: EnvPolicy) -> Self {
+        self.env_policy = policy;
+        self
+    }
+
+    /// The socket policy of the node, as in the runtime options of the shim
+    pub fn with_socket_policy(mut self, policy: SocketPolicy) -> Self {
+        self.socket_policy = policy;
+        self
+    }
+
+    /// The memory kept out of the memory limit of the guest, as in the runtime options of the shim
+    pub fn with_memory_reserve(mut self, reserve: u64) -> Self {
+        self.memory_reserve = Some(reserve);
+        self
+    }
+
+    /// The directory of the compile cache, as in the runtime options of the shim, instead of
+    /// a new directory of the test, e.g., to start a second test with the modules compiled by a first one
+    pub fn with_compile_cache_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.compile_cache_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Mount the rootfs of the container read-only, as for `readOnlyRootFilesystem`
+    pub fn with_readonly_rootfs(mut self) -> Self {
+        self.readonly_rootfs = true;
+        self
+    }
+
+    /// Grant the host directory `host` at `guest` with the `host_dirs` of the shim options
+    pub fn with_host_dir(
+        mut self,
+        host: impl AsRef<Path>,
+        guest: impl AsRef<Path>,
+        readonly: bool,
+    ) -> Self {
+        self.host_dirs.push(HostDir {
+            host: host.as_ref().to_path_buf(),
+            guest: guest.as_ref().to_path_buf(),
+            readonly,
+        });
+        self
+    }
+
+    /// Bind mount the host directory `source` at `destination` in the container
+    pub fn with_mount(
+        mut self,
+        source: impl AsRef<Path>,
+        destination: impl AsRef<Path>,
+        read_only: bool,
+    ) -> Result<Self> {
+        let mode = if read_only { "ro" } else { "rw" };
+        let mount = MountBuilder::default()
+            .source(source.as_ref())
+            .destination(destination.as_ref())
+            .typ("bind")
+            .options(vec!["rbind".to_string(), mode.to_string()])
+            .build()?;
+        self.mounts.push(mount);
+        Ok(self)
+    }
+
     pub fn with_start_fn(mut self, start_fn: impl AsRef<str>) -> Self {
         start_fn.as_ref().clone_into(&mut self.start_fn);
         self
@@ -118,6 +262,16 @@ impl<WasiInstance: Instance> WasiTestBuilder<WasiInstance> {
         Ok(self)
     }
 
+    /// Make the stdin of the container a FIFO, as with `ctr run -i`, written with
+    /// [`WasiTest::open_stdin`]
+    #[cfg(unix)]
+    pub fn with_stdin_fifo(self) -> Result<Self> {
+        let path = self.tempdir.path().join("stdin");
+        let _ = fs::remove_file(&path);
+        crate::sys::stdio::mkfifo(&path)?;
+        Ok(self)
+    }
+
     pub fn with_stdout(self, stdout: impl AsRef<Path>) -> Result<Self> {
         let stdout = fs::canonicalize(stdout.as_ref())?;
 
@@ -157,6 +311,7 @@ impl<WasiInstance: Instance> WasiTestBuilder<WasiInstance> {
             let wasm_content = oci_helpers::ImageContent {
                 bytes,
                 media_type: oci_tar_builder::WASM_LAYER_MEDIA_TYPE.to_string(),
+                name: None,
             };
             oci_helpers::import_image(&image_name, &[&wasm_content])?;
 
@@ -188,18 +343,43 @@ impl<WasiInstance: Instance> WasiTestBuilder<WasiInstance> {
             s => "/hello.wasm#".to_string().add(s),
         };
 
+        let mut linux = LinuxBuilder::default().namespaces(self.namespaces);
+        if let Some(resources) = self.resources {
+            linux = linux.resources(resources);
+        }
+        if let Some(mapping) = self.id_mapping {
+            linux = linux
+                .uid_mappings(vec![mapping])
+                .gid_mappings(vec![mapping]);
+        }
+
+        let mut process = ProcessBuilder::default()
+            .cwd("/")
+            .args([entrypoint])
+            .env(self.env);
+        // without limits, the default ones of the spec
+        if !self.rlimits.is_empty() {
+            process = process.rlimits(self.rlimits);
+        }
+        if let Some(oom_score_adj) = self.oom_score_adj {
+            process = process.oom_score_adj(oom_score_adj);
+        }
+
         let spec = SpecBuilder::default()
-            .root(RootBuilder::default().path("rootfs").build()?)
-            .linux(
-                LinuxBuilder::default()
-                    .namespaces(self.namespaces)
+            .root(
+                RootBuilder::default()
+                    .path("rootfs")
+                    .readonly(self.readonly_rootfs)
                     .build()?,
             )
-            .process(
-                ProcessBuilder::default()
-                    .cwd("/")
-                    .args([entrypoint])
-                    .build()?,
+            .linux(linux.build()?)
+            .process(process.build()?)
+            .annotations(self.annotations)
+            .mounts(
+                get_default_mounts()
+                    .into_iter()
+                    .chain(self.mounts)
+                    .collect::<Vec<_>>(),
             )
             .build()?;
 
@@ -214,14 +394,34 @@ impl<WasiInstance: Instance> WasiTestBuilder<WasiInstance> {
             stdout: dir.join("stdout"),
             stderr: dir.join("stderr"),
             stdin: dir.join("stdin"),
+            config: Config {
+                env_policy: self.env_policy,
+                socket_policy: self.socket_policy,
+                memory_reserve: self.memory_reserve,
+                compile_cache_dir: Some(
+                    self.compile_cache_dir
+                        .unwrap_or_else(|| dir.join("compile-cache")),
+                ),
+                host_dirs: self.host_dirs,
+                ..Default::default()
+            },
             ..Default::default()
         };
 
         let instance = WasiInstance::new(self.container_name, &cfg).block_on()?;
-        Ok(WasiTest { instance, tempdir })
+        Ok(WasiTest {
+            instance,
+            tempdir,
+            pid: OnceLock::new(),
+        })
     }
 }
 
+/// Whether the cgroup v2 unified hierarchy is mounted
+pub fn is_cgroup_v2() -> bool {
+    Path::new("/sys/fs/cgroup/cgroup.controllers").exists()
+}
+
 impl<WasiInstance: Instance> WasiTest<WasiInstance> {
     pub fn builder() -> Result<WasiTestBuilder<WasiInstance>> {
         WasiTestBuilder::new()
@@ -235,16 +435,70 @@ impl<WasiInstance: Instance> WasiTest<WasiInstance> {
         log::info!("starting wasi test");
         let pid = self.instance.start().block_on()?;
         log::info!("wasi test pid {pid}");
+        let _ = self.pid.set(pid);
+
+        Ok(self)
+    }
+
+    /// The pid of the started instance
+    pub fn pid(&self) -> Option<u32> {
+        self.pid.get().copied()
+    }
 
+    pub fn update(&self, resources: &LinuxResources) -> Result<&Self> {
+        log::info!("updating wasi test resources");
+        self.instance.update(resources).block_on()?;
         Ok(self)
     }
 
+    /// Read `file` in the cgroup of the started instance.
+    /// With cgroup v1, the file is read from the hierarchy of its controller, e.g., `memory` for `memory.limit_in_bytes`.
+    pub fn read_cgroup(&self, file: &str) -> Result<String> {
+        let Some(pid) = self.pid.get() else {
+            bail!("wasi test is not started");
+        };
+        let (root, controller) = if is_cgroup_v2() {
+            (PathBuf::from("/sys/fs/cgroup"), "")
+        } else {
+            let controller = file.split('.').next().unwrap_or_default();
+            (Path::new("/sys/fs/cgroup").join(controller), controller)
+        };
+        let cgroups = read_to_string(format!("/proc/{pid}/cgroup"))?;
+        let path = cgroups.lines().find_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+            let matches = match controller {
+                "" => controllers.is_empty(),
+                controller => controllers.split(',').any(|c| c == controller),
+            };
+            matches.then(|| root.join(path.trim_start_matches('/')))
+        });
+        let Some(path) = path else {
+            bail!("no cgroup with {file} for pid {pid}");
+        };
+        Ok(read_to_string(path.join(file))?.trim().to_string())
+    }
+
     pub fn delete(&self) -> Result<&Self> {
         log::info!("deleting wasi test");
         self.instance.delete().block_on()?;
         Ok(self)
     }
 
+    /// Open the stdin FIFO of the container for writing, as its client would
+    #[cfg(unix)]
+    pub fn open_stdin(&self) -> Result<File> {
+        let path = self.tempdir.path().join("stdin");
+        Ok(fs::OpenOptions::new().write(true).open(path)?)
+    }
+
+    /// Close the stdin of the container, as `CloseIO` does
+    pub fn close_stdin(&self) -> Result<&Self> {
+        log::info!("closing the stdin of wasi test");
+        self.instance.close_stdin().block_on()?;
+        Ok(self)
+    }
+
     pub fn ctrl_c(&self) -> Result<&Self> {
         log::info!("sending SIGINT");
         self.instance.kill(SIGINT as u32).block_on()?;
@@ -263,6 +517,12 @@ impl<WasiInstance: Instance> WasiTest<WasiInstance> {
         Ok(self)
     }
 
+    pub fn signal(&self, signal: i32) -> Result<&Self> {
+        log::info!("sending signal {signal}");
+        self.instance.kill(signal as u32).block_on()?;
+        Ok(self)
+    }
+
     pub fn wait(&self, t: Duration) -> Result<(u32, String, String)> {
         log::info!("waiting wasi test");
         let (status, _) = match self.instance.wait().with_timeout(t).block_on() {
@@ -367,6 +627,8 @@ pub mod oci_helpers {
     pub struct ImageContent {
         pub bytes: Vec<u8>,
         pub media_type: String,
+        /// Title of the layer, e.g., the name of a module to link
+        pub name: Option<String>,
     }
 
     pub fn import_image(
@@ -381,7 +643,12 @@ pub mod oci_helpers {
         for (i, content) in wasm_content.iter().enumerate() {
             let path = tempdir.path().join(format!("{}.wasm", i));
             write(path.clone(), content.bytes.clone())?;
-            builder.add_layer_with_media_type(&path, content.media_type.clone());
+            match &content.name {
+                Some(name) => {
+                    builder.add_layer_with_title(&path, content.media_type.clone(), name.clone())
+                }
+                None => builder.add_layer_with_media_type(&path, content.media_type.clone()),
+            };
         }
 
         let config = spec::ConfigBuilder::default()
@@ -449,6 +716,23 @@ pub mod oci_helpers {
         Ok(())
     }
 
+    /// Remove the content that isn't referenced, like containerd's garbage collection would
+    pub fn prune_content() -> Result<()> {
+        let success = Command::new("ctr")
+            .arg("-n")
+            .arg(TEST_NAMESPACE)
+            .arg("content")
+            .arg("prune")
+            .arg("references")
+            .spawn()?
+            .wait()?
+            .success();
+        if !success {
+            bail!("failed to prune content");
+        }
+        Ok(())
+    }
+
     pub fn wait_for_content_removal(content_sha: &str) -> Result<(), anyhow::Error> {
         let start = Instant::now();
         let timeout = Duration::from_secs(60);
@@ -0,0 +1,7 @@
+// The version of wasmedge-sdk reported in the engine info of the shim, from the lockfile
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if let Some(version) = containerd_shim_wasm_build::locked_version("wasmedge-sdk") {
+        println!("cargo:rustc-env=WASMEDGE_SDK_VERSION={version}");
+    }
+}
@@ -18,6 +18,9 @@ use wasmedge_sdk::{Module, Store, Vm};
 
 pub type WasmEdgeInstance = Instance<WasmEdgeEngine>;
 
+/// Version of the wasmedge-sdk crate, from the lockfile, when it has one
+pub(crate) const WASMEDGE_SDK_VERSION: Option<&str> = option_env!("WASMEDGE_SDK_VERSION");
+
 #[derive(Clone)]
 pub struct WasmEdgeEngine {
     config: Config,
@@ -37,6 +40,20 @@ impl Engine for WasmEdgeEngine {
         "wasmedge"
     }
 
+    fn version() -> Option<&'static str> {
+        WASMEDGE_SDK_VERSION
+    }
+
+    fn features() -> &'static [&'static str] {
+        &[
+            "bulk-memory",
+            "multi-value",
+            "reference-types",
+            "simd",
+            "wasi-preview1",
+        ]
+    }
+
     fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32> {
         let args = ctx.args();
         let envs = ctx.envs();
@@ -79,15 +96,33 @@ impl Engine for WasmEdgeEngine {
             }
         }
 
+        // the preopens are `guest:host`, with a `:readonly` suffix for a read-only rootfs or mount
+        let root = if ctx.readonly_rootfs() {
+            "/:/:readonly"
+        } else {
+            "/:/"
+        };
+        let preopens = std::iter::once(root.to_string())
+            .chain(ctx.preopens().into_iter().map(|preopen| {
+                let suffix = if preopen.read_only { ":readonly" } else { "" };
+                format!(
+                    "{}:{}{suffix}",
+                    preopen.guest_path,
+                    preopen.host_path.display()
+                )
+            }))
+            .collect::<Vec<_>>();
         let mut wasi_module = WasiModule::create(
             Some(args.iter().map(String::as_str).collect()),
             Some(envs.iter().map(String::as_str).collect()),
-            Some(vec!["/:/"]),
+            Some(preopens.iter().map(String::as_str).collect()),
         )?;
         instances.insert(wasi_module.name().to_string(), wasi_module.as_mut());
 
-        let wasm_bytes = source.as_bytes()?;
-        let module = Module::from_bytes(Some(&self.config), &wasm_bytes)?;
+        let module = match source.layer_path() {
+            Some(path) => Module::from_file(Some(&self.config), path)?,
+            None => Module::from_bytes(Some(&self.config), &source.as_bytes()?)?,
+        };
         let mut vm = Vm::new(Store::new(Some(&self.config), instances).unwrap());
         let mod_name = name.unwrap_or_else(|| "main".to_string());
 
@@ -153,3 +153,49 @@ fn check_dynamic_linking() {
     let current_exe = std::env::current_exe().unwrap().canonicalize().unwrap();
     assert!(wasmedge_path != current_exe);
 }
+
+// wasmedge doesn't serve requests, the containers of the `http` mode can't be created
+#[test]
+#[serial]
+fn test_http_mode_is_unsupported() -> anyhow::Result<()> {
+    let res = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation("io.runwasi.mode", "http")
+        .build();
+
+    let Err(err) = res else {
+        panic!("the container of the http mode was created");
+    };
+    assert!(err.to_string().contains("does not serve requests"), "{err}");
+    Ok(())
+}
+
+// wasmedge doesn't reload modules, the containers reloading on SIGHUP can't be created
+#[test]
+#[serial]
+fn test_reload_is_unsupported() -> anyhow::Result<()> {
+    let res = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation("io.runwasi.reload-on-sighup", "true")
+        .build();
+
+    let Err(err) = res else {
+        panic!("the container reloading on SIGHUP was created");
+    };
+    assert!(err.to_string().contains("does not reload modules"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn test_engine_info() {
+    use containerd_shim_wasm::sandbox::Instance as _;
+
+    use crate::instance::WASMEDGE_SDK_VERSION;
+
+    let info = WasiInstance::engine_info();
+    assert_eq!(info.name, "wasmedge");
+    assert_eq!(info.version.as_deref(), WASMEDGE_SDK_VERSION);
+    assert!(info.features.iter().any(|f| f == "wasi-preview1"));
+    assert!(!info.precompile);
+    assert!(info.media_types.iter().any(|t| t == "application/wasm"));
+}
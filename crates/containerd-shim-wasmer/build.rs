@@ -0,0 +1,7 @@
+// The version of wasmer reported in the engine info of the shim, from the lockfile
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if let Some(version) = containerd_shim_wasm_build::locked_version("wasmer") {
+        println!("cargo:rustc-env=WASMER_VERSION={version}");
+    }
+}
@@ -7,6 +7,9 @@ use wasmer_wasix::{WasiEnv, WasiError};
 
 pub type WasmerInstance = Instance<WasmerEngine>;
 
+/// Version of the wasmer crate, from the lockfile, when it has one
+pub(crate) const WASMER_VERSION: Option<&str> = option_env!("WASMER_VERSION");
+
 #[derive(Clone, Default)]
 pub struct WasmerEngine {
     engine: wasmer::Cranelift,
@@ -17,6 +20,21 @@ impl Engine for WasmerEngine {
         "wasmer"
     }
 
+    fn version() -> Option<&'static str> {
+        WASMER_VERSION
+    }
+
+    fn features() -> &'static [&'static str] {
+        &[
+            "bulk-memory",
+            "multi-value",
+            "reference-types",
+            "simd",
+            "wasi-preview1",
+            "wasix",
+        ]
+    }
+
     fn run_wasi(&self, ctx: &impl RuntimeContext) -> Result<i32> {
         let args = ctx.args();
         let envs = ctx
@@ -49,12 +67,27 @@ impl Engine for WasmerEngine {
 
         containerd_shim_wasm::info!(ctx, "Creating `WasiEnv`...: args {args:?}, envs: {envs:?}");
         let fs = FileSystem::new(Handle::current(), "/")?;
-        let (instance, wasi_env) = WasiEnv::builder(mod_name)
+        let mut builder = WasiEnv::builder(mod_name)
             .args(&args[1..])
             .envs(envs)
             .fs(Box::new(fs))
-            .preopen_dir("/")?
-            .instantiate(module, &mut store)?;
+            .preopen_build(|p| {
+                p.directory("/")
+                    .read(true)
+                    .write(!ctx.readonly_rootfs())
+                    .create(!ctx.readonly_rootfs())
+            })?;
+        // the guest only gets read capabilities for a read-only rootfs or mount
+        for preopen in ctx.preopens() {
+            builder = builder.preopen_build(|p| {
+                p.directory(&preopen.host_path)
+                    .alias(&preopen.guest_path)
+                    .read(true)
+                    .write(!preopen.read_only)
+                    .create(!preopen.read_only)
+            })?;
+        }
+        let (instance, wasi_env) = builder.instantiate(module, &mut store)?;
 
         containerd_shim_wasm::info!(ctx, "Running {func:?}");
         let start = instance.exports.get_function(&func)?;
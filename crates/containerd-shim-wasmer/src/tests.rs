@@ -116,3 +116,17 @@ fn test_has_default_devices() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_engine_info() {
+    use containerd_shim_wasm::sandbox::Instance as _;
+
+    use crate::instance::WASMER_VERSION;
+
+    let info = WasiInstance::engine_info();
+    assert_eq!(info.name, "wasmer");
+    assert_eq!(info.version.as_deref(), WASMER_VERSION);
+    assert!(info.features.iter().any(|f| f == "wasi-preview1"));
+    assert!(!info.precompile);
+    assert!(info.media_types.iter().any(|t| t == "application/wasm"));
+}
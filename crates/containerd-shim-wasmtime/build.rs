@@ -0,0 +1,7 @@
+// The version of wasmtime reported in the engine info of the shim, from the lockfile
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if let Some(version) = containerd_shim_wasm_build::locked_version("wasmtime") {
+        println!("cargo:rustc-env=WASMTIME_VERSION={version}");
+    }
+}
@@ -7,10 +7,12 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 
-use anyhow::{Result, bail};
-use containerd_shim_wasm::container::RuntimeContext;
+use anyhow::{Context, Result, bail};
+use containerd_shim_wasm::container::{Listener, RuntimeContext};
+use containerd_shim_wasm::sandbox::SocketPolicy;
 use hyper::server::conn::http1;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
 use wasmtime::Store;
@@ -21,7 +23,8 @@ use wasmtime_wasi_http::body::HyperOutgoingBody;
 use wasmtime_wasi_http::io::TokioIo;
 use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
 
-use crate::instance::{WasiPreview2Ctx, envs_from_ctx};
+use crate::instance::{WasiPreview2Ctx, apply_socket_policy, envs_from_ctx};
+use crate::limits::Limits;
 
 const DEFAULT_ADDR: SocketAddr =
     SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)), 8080);
@@ -39,15 +42,21 @@ fn is_connection_error(e: &std::io::Error) -> bool {
     )
 }
 
+/// Whether the process is out of a resource that the connections being served give back
+fn is_resource_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    )
+}
+
 // [From axum](https://github.com/tokio-rs/axum/blob/280d16a61059f57230819a79b15aa12a263e8cca/axum/src/serve.rs#L425)
-async fn tcp_accept(listener: &TcpListener) -> Option<TcpStream> {
+// Fails when the listener itself is broken, which stops the server.
+async fn tcp_accept(listener: &TcpListener) -> std::io::Result<Option<TcpStream>> {
     match listener.accept().await {
-        Ok((stream, _addr)) => Some(stream),
-        Err(e) => {
-            if is_connection_error(&e) {
-                return None;
-            }
-
+        Ok((stream, _addr)) => Ok(Some(stream)),
+        Err(e) if is_connection_error(&e) => Ok(None),
+        Err(e) if is_resource_error(&e) => {
             // [From `hyper::Server` in 0.14](https://github.com/hyperium/hyper/blob/v0.14.27/src/server/tcp.rs#L186)
             //
             // > A possible scenario is that the process has hit the max open files
@@ -59,15 +68,39 @@ async fn tcp_accept(listener: &TcpListener) -> Option<TcpStream> {
             // > and then the listener will sleep for 1 second.
             log::error!("accept error: {e}");
             tokio::time::sleep(Duration::from_secs(1)).await;
-            None
+            Ok(None)
         }
+        Err(e) => Err(e),
     }
 }
 
+fn bind(addr: SocketAddr, backlog: u32) -> Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+    };
+
+    // Conditionally enable `SO_REUSEADDR` depending on the current
+    // platform. On Unix we want this to be able to rebind an address in
+    // the `TIME_WAIT` state which can happen then a server is killed with
+    // active TCP connections and then restarted. On Windows though if
+    // `SO_REUSEADDR` is specified then it enables multiple applications to
+    // bind the port at the same time which is not something we want. Hence
+    // this is conditionally set based on the platform (and deviates from
+    // Tokio's default from always-on).
+    socket.set_reuseaddr(!cfg!(windows))?;
+    socket.bind(addr)?;
+
+    Ok(socket.listen(backlog)?)
+}
+
 pub(crate) async fn serve_conn(
     ctx: &impl RuntimeContext,
     instance: ProxyPre<WasiPreview2Ctx>,
     cancel: CancellationToken,
+    limits: Limits,
+    listener: Option<&Listener>,
+    max_requests: Option<usize>,
 ) -> Result<()> {
     let mut env = envs_from_ctx(ctx).into_iter().collect::<HashMap<_, _>>();
 
@@ -81,40 +114,42 @@ pub(crate) async fn serve_conn(
         .and_then(|v| v.parse().ok())
         .unwrap_or(DEFAULT_BACKLOG);
 
-    let socket = match addr {
-        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
-        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+    // serve the socket of the `http` mode, or the one bound by the shim
+    // from the `io.runwasi.listen` annotation
+    let listener = match listener.or(ctx.listeners().first()) {
+        Some(listener) => {
+            let socket = listener.socket.try_clone()?;
+            socket.set_nonblocking(true)?;
+            TcpListener::from_std(socket)?
+        }
+        None => bind(addr, backlog)?,
     };
-
-    // Conditionally enable `SO_REUSEADDR` depending on the current
-    // platform. On Unix we want this to be able to rebind an address in
-    // the `TIME_WAIT` state which can happen then a server is killed with
-    // active TCP connections and then restarted. On Windows though if
-    // `SO_REUSEADDR` is specified then it enables multiple applications to
-    // bind the port at the same time which is not something we want. Hence
-    // this is conditionally set based on the platform (and deviates from
-    // Tokio's default from always-on).
-    socket.set_reuseaddr(!cfg!(windows))?;
-    socket.bind(addr)?;
-
-    let listener = socket.listen(backlog)?;
     let tracker = TaskTracker::new();
 
     containerd_shim_wasm::info!(ctx, "Serving HTTP on http://{}/", listener.local_addr()?);
 
     let env = env.into_iter().collect();
-    let handler = Arc::new(ProxyHandler::new(instance, env, tracker.clone()));
-
-    loop {
+    let handler = Arc::new(ProxyHandler::new(
+        instance,
+        env,
+        tracker.clone(),
+        limits,
+        max_requests,
+        ctx.socket_policy(),
+    ));
+
+    let res = loop {
         let stream = tokio::select! {
             conn = tcp_accept(&listener) => {
                 match conn {
-                    Some(conn) => conn,
-                    None => continue,
+                    Ok(Some(conn)) => conn,
+                    Ok(None) => continue,
+                    // the container exits with an error, once the in-flight requests are served
+                    Err(err) => break Err(err).context("the listener of the server failed"),
                 }
             }
             _ = cancel.cancelled() => {
-                break;
+                break Ok(());
             }
         };
 
@@ -133,12 +168,12 @@ pub(crate) async fn serve_conn(
                 log::error!("error: {e:?}");
             }
         });
-    }
+    };
 
     tracker.close();
     tracker.wait().await;
 
-    Ok(())
+    res
 }
 
 struct ProxyHandler {
@@ -146,6 +181,12 @@ struct ProxyHandler {
     next_id: AtomicU64,
     env: Vec<(String, String)>,
     tracker: TaskTracker,
+    // limits of the store of each request
+    limits: Limits,
+    /// Permits of the requests handled at once, when they are bounded
+    in_flight: Option<Arc<Semaphore>>,
+    /// The sockets of the guest, in the store of each request
+    socket_policy: SocketPolicy,
 }
 
 impl ProxyHandler {
@@ -153,12 +194,18 @@ impl ProxyHandler {
         instance_pre: ProxyPre<WasiPreview2Ctx>,
         env: Vec<(String, String)>,
         tracker: TaskTracker,
+        limits: Limits,
+        max_requests: Option<usize>,
+        socket_policy: SocketPolicy,
     ) -> Self {
         ProxyHandler {
             instance_pre,
             env,
             tracker,
+            limits: limits.for_requests(),
             next_id: AtomicU64::from(0),
+            in_flight: max_requests.map(|max| Arc::new(Semaphore::new(max))),
+            socket_policy,
         }
     }
 
@@ -168,14 +215,21 @@ impl ProxyHandler {
 
         builder.envs(&self.env);
         builder.env("REQUEST_ID", req_id.to_string());
+        apply_socket_policy(&mut builder, self.socket_policy.clone());
 
         let ctx = WasiPreview2Ctx {
             wasi_ctx: builder.build(),
             wasi_http: WasiHttpCtx::new(),
             resource_table: ResourceTable::default(),
+            limiter: self.limits.limiter(),
         };
 
-        Store::new(engine, ctx)
+        let mut store = Store::new(engine, ctx);
+        store.limiter(|ctx| &mut ctx.limiter);
+        self.limits
+            .apply(&mut store)
+            .expect("fuel is enabled in the engine config");
+        store
     }
 
     async fn handle_request(
@@ -184,6 +238,12 @@ impl ProxyHandler {
     ) -> Result<hyper::Response<HyperOutgoingBody>> {
         let (sender, receiver) = tokio::sync::oneshot::channel();
 
+        // held until the guest handled the request, which can be after its response
+        let permit = match &self.in_flight {
+            Some(in_flight) => Some(in_flight.clone().acquire_owned().await?),
+            None => None,
+        };
+
         let req_id = self.next_req_id();
 
         log::trace!(
@@ -199,6 +259,7 @@ impl ProxyHandler {
         let proxy = self.instance_pre.instantiate_async(&mut store).await?;
 
         let task = self.tracker.spawn(async move {
+            let _permit = permit;
             if let Err(e) = proxy
                 .wasi_http_incoming_handler()
                 .call_handle(store, req, out)
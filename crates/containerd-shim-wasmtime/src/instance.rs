@@ -1,12 +1,15 @@
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
-use std::sync::LazyLock;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
 
 use anyhow::{Context, Result, bail};
 use containerd_shim_wasm::container::{
-    Engine, Entrypoint, Instance, RuntimeContext, WasmBinaryType,
+    Engine, EngineConfig, EngineMetrics, Entrypoint, Instance, Listener, RuntimeContext,
+    WasmBinaryType, WasmModule,
 };
-use containerd_shim_wasm::sandbox::WasmLayer;
+use containerd_shim_wasm::sandbox::{SocketPolicy, WasmLayer};
+use tokio::sync::Notify;
 use tokio_util::sync::CancellationToken;
 use wasi_preview1::WasiP1Ctx;
 use wasi_preview2::bindings::Command;
@@ -19,6 +22,8 @@ use wasmtime_wasi_http::bindings::ProxyPre;
 use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
 
 use crate::http_proxy::serve_conn;
+use crate::limits::Limits;
+use crate::metrics::{self, MetricsLimiter};
 
 pub type WasmtimeInstance = Instance<WasmtimeEngine>;
 
@@ -56,6 +61,9 @@ impl<'a> ComponentTarget<'a> {
 #[derive(Clone, Default, Debug)]
 pub struct WasmtimeEngine;
 
+/// Notified when the container reloads its module, to stop the running instance
+static RELOAD: LazyLock<Notify> = LazyLock::new(Notify::new);
+
 static PRECOMPILER: LazyLock<wasmtime::Engine> = LazyLock::new(|| {
     let mut config = wasmtime::Config::new();
 
@@ -64,18 +72,60 @@ static PRECOMPILER: LazyLock<wasmtime::Engine> = LazyLock::new(|| {
     config.parallel_compilation(!cfg!(test));
     config.wasm_component_model(true); // enable component linking
     config.async_support(true); // must be on
+    config.epoch_interruption(true); // as in the engines running the modules
 
     wasmtime::Engine::new(&config).expect("failed to create wasmtime precompilation engine")
 });
 
+/// Version of the wasmtime crates, from the lockfile, when it has one
+pub(crate) const WASMTIME_VERSION: Option<&str> = option_env!("WASMTIME_VERSION");
+
+/// Fuel given to each store, with `runwasi.io/engine.fuel` or `io.runwasi.wasmtime.fuel`.
+/// The module traps when it runs out of fuel, and exits with `OUT_OF_FUEL_EXIT_CODE`.
+const FUEL_KEY: &str = "fuel";
+/// Maximum size of the wasm stack in bytes, with `runwasi.io/engine.max-wasm-stack`
+const MAX_WASM_STACK_KEY: &str = "max-wasm-stack";
+/// Maximum number of requests that a `wasi:http` component handles at once, with
+/// `runwasi.io/engine.max-concurrent-requests`. The next requests wait for one of them to finish.
+const MAX_CONCURRENT_REQUESTS_KEY: &str = "max-concurrent-requests";
+/// How long each store can run for, e.g., `io.runwasi.wasmtime.max-exec-time=30s`, per request for
+/// a server. The soft `RLIMIT_CPU` of the container is the deadline of a command without one.
+/// The guest traps past it, with the time it ran for in the error of the task.
+const MAX_EXEC_TIME_KEY: &str = "max-exec-time";
+
 #[derive(Clone)]
 pub struct WasmtimeEngineImpl {
     engine: wasmtime::Engine,
     cancel: CancellationToken,
+    limits: Limits,
+    /// The socket to serve, in the `http` mode
+    listener: Option<Listener>,
+    /// How many requests a `wasi:http` component handles at once, unbounded by default
+    max_requests: Option<usize>,
 }
 
 impl Default for WasmtimeEngineImpl {
     fn default() -> Self {
+        Self::new(&EngineConfig::default()).unwrap()
+    }
+}
+
+impl WasmtimeEngineImpl {
+    /// Create an engine with the engine configuration of a container
+    fn new(engine_config: &EngineConfig) -> Result<Self> {
+        let fuel = engine_config.parse::<u64>(FUEL_KEY)?;
+        let max_wasm_stack = engine_config.parse::<usize>(MAX_WASM_STACK_KEY)?;
+        let max_requests = engine_config.parse::<usize>(MAX_CONCURRENT_REQUESTS_KEY)?;
+        if max_requests == Some(0) {
+            bail!("{MAX_CONCURRENT_REQUESTS_KEY} must be at least 1");
+        }
+        let max_exec_time: Option<Duration> = engine_config
+            .parse::<humantime::Duration>(MAX_EXEC_TIME_KEY)?
+            .map(Into::into);
+        if max_exec_time == Some(Duration::ZERO) {
+            bail!("{MAX_EXEC_TIME_KEY} must be more than 0");
+        }
+
         let mut config = wasmtime::Config::new();
 
         // Disable Wasmtime parallel compilation for the tests
@@ -84,24 +134,57 @@ impl Default for WasmtimeEngineImpl {
         config.wasm_component_model(true); // enable component linking
         config.async_support(true); // must be on
 
+        config.consume_fuel(fuel.is_some());
+        // for the CPU quota and the deadline of the stores, always on so that the precompiled
+        // modules can be used
+        config.epoch_interruption(true);
+        if let Some(size) = max_wasm_stack {
+            config.max_wasm_stack(size);
+        }
+
         if use_pooling_allocator_by_default() {
             let cfg = wasmtime::PoolingAllocationConfig::default();
             config.allocation_strategy(wasmtime::InstanceAllocationStrategy::Pooling(cfg));
         }
 
-        Self {
-            engine: wasmtime::Engine::new(&config)
-                .context("failed to create wasmtime engine")
-                .unwrap(),
+        Ok(Self {
+            engine: wasmtime::Engine::new(&config).context("failed to create wasmtime engine")?,
             cancel: CancellationToken::new(),
-        }
+            limits: Limits {
+                fuel,
+                max_exec_time,
+                ..Default::default()
+            },
+            listener: None,
+            max_requests,
+        })
+    }
+
+    /// Enforce the resource limits of the container on the stores
+    fn with_resources(mut self, ctx: &impl RuntimeContext) -> Self {
+        self.limits = Limits::new(self.limits.fuel, self.limits.max_exec_time, ctx);
+        containerd_shim_wasm::debug!(ctx, "store limits: {:?}", self.limits);
+        self.limits.start_epoch(&self.engine);
+        self
+    }
+
+    /// Serve the `wasi:http/incoming-handler` of the component on `listener`
+    fn serving(mut self, listener: &Listener) -> Self {
+        self.listener = Some(listener.clone());
+        self
     }
 }
 
+pub struct WasiPreview1Ctx {
+    pub(crate) wasi_ctx: WasiP1Ctx,
+    pub(crate) limiter: MetricsLimiter,
+}
+
 pub struct WasiPreview2Ctx {
     pub(crate) wasi_ctx: wasi_preview2::WasiCtx,
     pub(crate) wasi_http: WasiHttpCtx,
     pub(crate) resource_table: ResourceTable,
+    pub(crate) limiter: MetricsLimiter,
 }
 
 impl WasiPreview2Ctx {
@@ -111,8 +194,14 @@ impl WasiPreview2Ctx {
             wasi_ctx: wasi_builder(ctx)?.build(),
             wasi_http: WasiHttpCtx::new(),
             resource_table: ResourceTable::default(),
+            limiter: MetricsLimiter::default(),
         })
     }
+
+    fn with_limiter(mut self, limiter: MetricsLimiter) -> Self {
+        self.limiter = limiter;
+        self
+    }
 }
 
 /// This impl is required to use wasmtime_wasi::preview2::WasiView trait.
@@ -150,17 +239,53 @@ impl Engine for WasmtimeEngine {
             name: _,
         } = ctx.entrypoint();
 
-        let wasm_bytes = &source.as_bytes()?;
-        WasmtimeEngineImpl::default()
-            .execute(ctx, wasm_bytes, func)
+        let modules = source.modules()?;
+        WasmtimeEngineImpl::new(&ctx.engine_config())?
+            .with_resources(ctx)
+            .execute(ctx, &modules, func)
+            .into_error_code()
+    }
+
+    fn can_serve(&self) -> bool {
+        true
+    }
+
+    fn serve(&self, ctx: &impl RuntimeContext, listener: &Listener) -> Result<i32> {
+        containerd_shim_wasm::info!(ctx, "setting up wasi to serve");
+        let Entrypoint { source, func, .. } = ctx.entrypoint();
+
+        let modules = source.modules()?;
+        WasmtimeEngineImpl::new(&ctx.engine_config())?
+            .with_resources(ctx)
+            .serving(listener)
+            .execute(ctx, &modules, func)
             .into_error_code()
     }
 
+    fn can_reload(&self) -> bool {
+        true
+    }
+
+    fn load(&self, ctx: &impl RuntimeContext) -> Result<()> {
+        let Entrypoint { source, .. } = ctx.entrypoint();
+
+        let engine = WasmtimeEngineImpl::new(&ctx.engine_config())?;
+        for module in source.modules()? {
+            engine.load_module(ctx, &module)?;
+        }
+        Ok(())
+    }
+
+    fn interrupt(&self) {
+        RELOAD.notify_one();
+    }
+
     fn precompile(&self, layers: &[WasmLayer]) -> Result<Vec<Option<Vec<u8>>>> {
         let mut compiled_layers = Vec::<Option<Vec<u8>>>::with_capacity(layers.len());
 
         for layer in layers {
-            if PRECOMPILER.detect_precompiled(&layer.layer).is_some() {
+            let bytes = layer.bytes()?;
+            if PRECOMPILER.detect_precompiled(&bytes).is_some() {
                 log::info!("Already precompiled");
                 compiled_layers.push(None);
                 continue;
@@ -168,11 +293,12 @@ impl Engine for WasmtimeEngine {
 
             use WasmBinaryType::*;
 
-            let compiled_layer = match WasmBinaryType::from_bytes(&layer.layer) {
-                Some(Module) => PRECOMPILER.precompile_module(&layer.layer)?,
-                Some(Component) => PRECOMPILER.precompile_component(&layer.layer)?,
+            let compiled_layer = match WasmBinaryType::from_bytes(&bytes) {
+                Some(Module) => PRECOMPILER.precompile_module(&bytes)?,
+                Some(Component) => PRECOMPILER.precompile_component(&bytes)?,
                 None => {
-                    log::warn!("Unknown WASM binary type");
+                    log::warn!("Unknown WASM binary type, not precompiling the layer");
+                    compiled_layers.push(None);
                     continue;
                 }
             };
@@ -183,6 +309,27 @@ impl Engine for WasmtimeEngine {
         Ok(compiled_layers)
     }
 
+    fn version() -> Option<&'static str> {
+        WASMTIME_VERSION
+    }
+
+    fn features() -> &'static [&'static str] {
+        &[
+            "bulk-memory",
+            "multi-value",
+            "reference-types",
+            "simd",
+            "relaxed-simd",
+            "tail-call",
+            "multi-memory",
+            "component-model",
+            "fuel",
+            "wasi-preview1",
+            "wasi-preview2",
+            "wasi-http",
+        ]
+    }
+
     fn can_precompile(&self) -> Option<String> {
         let mut hasher = DefaultHasher::new();
         PRECOMPILER
@@ -190,6 +337,35 @@ impl Engine for WasmtimeEngine {
             .hash(&mut hasher);
         Some(hasher.finish().to_string())
     }
+
+    fn collect_metrics(&self) -> Option<EngineMetrics> {
+        Some(metrics::collect())
+    }
+
+    fn wasm_backtrace(&self, err: &anyhow::Error) -> Option<String> {
+        // wasmtime adds the backtrace to the context of the traps
+        err.downcast_ref::<wasmtime::WasmBacktrace>()
+            .map(|backtrace| backtrace.to_string())
+    }
+
+    fn validate_engine_config(&self, config: &EngineConfig) -> Result<()> {
+        config.check_keys(&[
+            FUEL_KEY,
+            MAX_WASM_STACK_KEY,
+            MAX_CONCURRENT_REQUESTS_KEY,
+            MAX_EXEC_TIME_KEY,
+        ])?;
+        WasmtimeEngineImpl::new(config).map(|_| ())
+    }
+
+    fn precompile_compatible(&self, config: &EngineConfig) -> bool {
+        // the code compiled with fuel metering differs from the precompiled code
+        config.get(FUEL_KEY).is_none()
+    }
+
+    fn can_cache_compiled(&self) -> bool {
+        true
+    }
 }
 
 impl WasmtimeEngineImpl {
@@ -197,24 +373,39 @@ impl WasmtimeEngineImpl {
     ///
     /// This function adds wasi_preview1 to the linker and can be utilized
     /// to execute a wasm module that uses wasi_preview1.
+    /// The `libraries` are instantiated first, so that the module can import their exports
+    /// using their name as the module name.
     fn execute_module(
         &self,
         ctx: &impl RuntimeContext,
         module: Module,
+        libraries: Vec<(String, Module)>,
         func: &String,
     ) -> Result<i32> {
         containerd_shim_wasm::debug!(ctx, "execute module");
 
-        let ctx_p1 = wasi_builder(ctx)?.build_p1();
+        let ctx_p1 = WasiPreview1Ctx {
+            wasi_ctx: wasi_builder(ctx)?.build_p1(),
+            limiter: self.limits.limiter(),
+        };
         let mut store = Store::new(&self.engine, ctx_p1);
+        store.limiter(|ctx| &mut ctx.limiter);
+        self.limits.apply(&mut store)?;
         let mut module_linker = wasmtime::Linker::new(&self.engine);
 
         containerd_shim_wasm::debug!(ctx, "init linker");
-        wasi_preview1::add_to_linker_async(&mut module_linker, |wasi_ctx: &mut WasiP1Ctx| {
-            wasi_ctx
+        wasi_preview1::add_to_linker_async(&mut module_linker, |ctx: &mut WasiPreview1Ctx| {
+            &mut ctx.wasi_ctx
         })?;
 
         wasmtime_wasi::runtime::in_tokio(async move {
+            for (name, library) in &libraries {
+                containerd_shim_wasm::info!(ctx, "instantiating library module {name:?}");
+                module_linker
+                    .module_async(&mut store, name, library)
+                    .await?;
+            }
+
             containerd_shim_wasm::info!(ctx, "instantiating instance");
             let instance: wasmtime::Instance =
                 module_linker.instantiate_async(&mut store, &module).await?;
@@ -226,10 +417,10 @@ impl WasmtimeEngineImpl {
 
             containerd_shim_wasm::info!(ctx, "running start function {func:?}");
 
-            start_func
-                .call_async(&mut store, &[], &mut [])
+            let status = until_reload(start_func.call_async(&mut store, &[], &mut []))
                 .await
-                .into_error_code()
+                .into_error_code();
+            self.limits.exit_code(status)
         })
     }
 
@@ -245,6 +436,9 @@ impl WasmtimeEngineImpl {
             component.component_type().exports(&self.engine),
             func.as_str(),
         );
+        if self.listener.is_some() && !matches!(target, ComponentTarget::HttpProxy) {
+            bail!("the component doesn't export wasi:http/incoming-handler to serve requests");
+        }
 
         // This is a adapter logic that converts wasip1 `_start` function to wasip2 `run` function.
         let status = match target {
@@ -260,29 +454,50 @@ impl WasmtimeEngineImpl {
 
                 containerd_shim_wasm::info!(ctx, "starting HTTP server");
                 let cancel = self.cancel.clone();
-                serve_conn(ctx, instance, cancel).await
+                let mut serve = std::pin::pin!(serve_conn(
+                    ctx,
+                    instance,
+                    cancel.clone(),
+                    self.limits,
+                    self.listener.as_ref(),
+                    self.max_requests,
+                ));
+                tokio::select! {
+                    status = &mut serve => status,
+                    // on a reload, the server stops accepting connections, and finishes the in-flight requests
+                    _ = RELOAD.notified() => {
+                        containerd_shim_wasm::info!(ctx, "stopping the server to reload the module");
+                        cancel.cancel();
+                        serve.await
+                    }
+                }
             }
             ComponentTarget::Command => {
                 containerd_shim_wasm::info!(ctx, "Found command target");
-                let wasi_ctx = WasiPreview2Ctx::new(ctx)?;
+                let wasi_ctx = WasiPreview2Ctx::new(ctx)?.with_limiter(self.limits.limiter());
                 let (mut store, linker) = store_for_context(&self.engine, wasi_ctx)?;
+                self.limits.apply(&mut store)?;
 
                 let command = Command::instantiate_async(&mut store, &component, &linker).await?;
 
-                command
-                    .wasi_cli_run()
-                    .call_run(&mut store)
-                    .await?
-                    .map_err(|_| {
-                        anyhow::anyhow!(
-                            "failed to run component targeting `wasi:cli/command` world"
-                        )
-                    })
+                until_reload(async {
+                    command
+                        .wasi_cli_run()
+                        .call_run(&mut store)
+                        .await?
+                        .map_err(|_| {
+                            anyhow::anyhow!(
+                                "failed to run component targeting `wasi:cli/command` world"
+                            )
+                        })
+                })
+                .await
             }
             ComponentTarget::Core(func) => {
                 containerd_shim_wasm::info!(ctx, "Found Core target");
-                let wasi_ctx = WasiPreview2Ctx::new(ctx)?;
+                let wasi_ctx = WasiPreview2Ctx::new(ctx)?.with_limiter(self.limits.limiter());
                 let (mut store, linker) = store_for_context(&self.engine, wasi_ctx)?;
+                self.limits.apply(&mut store)?;
 
                 let pre = linker.instantiate_pre(&component)?;
                 let instance = pre.instantiate_async(&mut store).await?;
@@ -296,11 +511,11 @@ impl WasmtimeEngineImpl {
                     ctx,
                     "running exported function {func:?} {start_func:?}"
                 );
-                start_func.call_async(&mut store, &[], &mut []).await
+                until_reload(start_func.call_async(&mut store, &[], &mut [])).await
             }
         };
 
-        status.into_error_code()
+        self.limits.exit_code(status.into_error_code())
     }
 
     /// Execute a wasm component.
@@ -315,24 +530,35 @@ impl WasmtimeEngineImpl {
     ) -> Result<i32> {
         containerd_shim_wasm::debug!(ctx, "loading wasm component");
 
+        // a server shuts down gracefully on SIGTERM, e.g., when its pod is deleted
+        let serves = self.listener.is_some()
+            || matches!(
+                ComponentTarget::new(component.component_type().exports(&self.engine), &func),
+                ComponentTarget::HttpProxy
+            );
+
         wasmtime_wasi::runtime::in_tokio(async move {
             tokio::select! {
                 status = self.execute_component_async(ctx, component, func) => {
                     status
                 }
-                status = self.handle_signals() => {
+                status = self.handle_signals(serves) => {
                     status
                 }
             }
         })
     }
 
-    async fn handle_signals(&self) -> Result<i32> {
+    async fn handle_signals(&self, serves: bool) -> Result<i32> {
         match wait_for_signal().await? {
             libc::SIGINT => {
                 // Request graceful shutdown;
                 self.cancel.cancel();
             }
+            // the server stops accepting connections, finishes the in-flight requests and exits with 0
+            libc::SIGTERM if serves => {
+                self.cancel.cancel();
+            }
             sig => {
                 // On other signal, terminate the process without waiting for spawned tasks to finish.
                 return Ok(128 + sig);
@@ -343,36 +569,218 @@ impl WasmtimeEngineImpl {
         wait_for_signal().await
     }
 
-    fn execute(&self, ctx: &impl RuntimeContext, wasm_binary: &[u8], func: String) -> Result<i32> {
-        match WasmBinaryType::from_bytes(wasm_binary) {
-            Some(WasmBinaryType::Module) => {
-                containerd_shim_wasm::debug!(ctx, "loading wasm module");
-                let module = Module::from_binary(&self.engine, wasm_binary)?;
-                self.execute_module(ctx, module, &func)
+    /// Execute the first of the `modules`, linked with the other ones.
+    ///
+    /// Only modules can be linked, components have to be composed into
+    /// a single component ahead of time.
+    fn execute(
+        &self,
+        ctx: &impl RuntimeContext,
+        modules: &[WasmModule],
+        func: String,
+    ) -> Result<i32> {
+        let loaded = metrics::compile(|| {
+            modules
+                .iter()
+                .map(|module| self.load_module(ctx, module))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        let mut loaded = modules.iter().zip(loaded);
+        let (_, main) = loaded.next().context("no module to run")?;
+
+        // a module runs with wasi preview1 and a component with wasi preview2,
+        // whatever the variant selected in the index of a multi-platform image
+        match (&main, ctx.platform().os().to_string().as_str()) {
+            (Loaded::Module(_), "wasip2") => containerd_shim_wasm::warn!(
+                ctx,
+                "the image platform is wasip2, running its core module with wasi preview1"
+            ),
+            (Loaded::Component(_), "wasip1") => containerd_shim_wasm::warn!(
+                ctx,
+                "the image platform is wasip1, running its component with wasi preview2"
+            ),
+            _ => {}
+        }
+
+        match main {
+            Loaded::Module(_) if self.listener.is_some() => {
+                bail!(
+                    "a module can't serve requests, only a component exporting wasi:http/incoming-handler"
+                )
+            }
+            Loaded::Module(module) => {
+                let libraries = loaded
+                    .map(|(library, loaded)| {
+                        let name = library
+                            .name
+                            .clone()
+                            .context("library module has no name to link it with")?;
+                        match loaded {
+                            Loaded::Module(module) => Ok((name, module)),
+                            Loaded::Component(_) => {
+                                bail!("cannot link component {name:?} with a module")
+                            }
+                        }
+                    })
+                    .collect::<Result<_>>()?;
+                self.execute_module(ctx, module, libraries, &func)
             }
-            Some(WasmBinaryType::Component) => {
-                let component = Component::from_binary(&self.engine, wasm_binary)?;
+            Loaded::Component(component) => {
+                if modules.len() > 1 {
+                    bail!(
+                        "linking components is not supported, found {} components, compose them into a single component",
+                        modules.len()
+                    );
+                }
                 self.execute_component(ctx, component, func)
             }
+        }
+    }
+
+    /// Load a module of the source. A precompiled module in a file is mapped
+    /// from the file, instead of being read in memory.
+    fn load_module(&self, ctx: &impl RuntimeContext, module: &WasmModule) -> Result<Loaded> {
+        if let Some(path) = module.path {
+            match self.engine.detect_precompiled_file(path)? {
+                Some(Precompiled::Module) => {
+                    containerd_shim_wasm::info!(ctx, "using precompiled module file");
+                    let module = unsafe { Module::deserialize_file(&self.engine, path) }?;
+                    return Ok(Loaded::Module(module));
+                }
+                Some(Precompiled::Component) => {
+                    containerd_shim_wasm::info!(ctx, "using precompiled component file");
+                    let component = unsafe { Component::deserialize_file(&self.engine, path) }?;
+                    return Ok(Loaded::Component(component));
+                }
+                None => {}
+            }
+        }
+        self.load(ctx, &module.bytes()?)
+    }
+
+    /// Compile a module or a component, or load the code that a former container compiled for it
+    /// from the compile cache of the node. A file of the cache that this engine can't load, e.g.,
+    /// a truncated one, is compiled again.
+    fn compile(
+        &self,
+        ctx: &impl RuntimeContext,
+        wasm_binary: &[u8],
+        kind: WasmBinaryType,
+    ) -> Result<Loaded> {
+        let compile = || -> Result<Loaded> {
+            match kind {
+                WasmBinaryType::Module => {
+                    containerd_shim_wasm::debug!(ctx, "loading wasm module");
+                    Ok(Loaded::Module(Module::from_binary(
+                        &self.engine,
+                        wasm_binary,
+                    )?))
+                }
+                WasmBinaryType::Component => Ok(Loaded::Component(Component::from_binary(
+                    &self.engine,
+                    wasm_binary,
+                )?)),
+            }
+        };
+        let Some(cache) = ctx.compile_cache() else {
+            return compile();
+        };
+
+        let key = self.cache_key(wasm_binary);
+        if let Some(path) = cache.get(&key) {
+            let loaded = match kind {
+                WasmBinaryType::Module => {
+                    unsafe { Module::deserialize_file(&self.engine, &path) }.map(Loaded::Module)
+                }
+                WasmBinaryType::Component => {
+                    unsafe { Component::deserialize_file(&self.engine, &path) }
+                        .map(Loaded::Component)
+                }
+            };
+            match loaded {
+                Ok(loaded) => {
+                    containerd_shim_wasm::info!(ctx, "using {key} of the compile cache");
+                    return Ok(loaded);
+                }
+                Err(err) => {
+                    containerd_shim_wasm::warn!(
+                        ctx,
+                        "failed to load {key} of the compile cache, compiling it again: {err}"
+                    );
+                    cache.remove(&key);
+                }
+            }
+        }
+
+        let loaded = compile()?;
+        if let Err(err) = loaded
+            .serialize()
+            .and_then(|artifact| cache.put(&key, &artifact))
+        {
+            containerd_shim_wasm::warn!(ctx, "failed to store {key} in the compile cache: {err:#}");
+        }
+        Ok(loaded)
+    }
+
+    /// The key of the code compiled for `wasm_binary` in the compile cache: the SHA256 of the
+    /// binary, and the hash of the settings of the engine that the compiled code depends on,
+    /// e.g., fuel metering, so that a container with other settings compiles its own code
+    fn cache_key(&self, wasm_binary: &[u8]) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.engine
+            .precompile_compatibility_hash()
+            .hash(&mut hasher);
+        format!(
+            "{}-{:016x}.cwasm",
+            sha256::digest(wasm_binary),
+            hasher.finish()
+        )
+    }
+
+    fn load(&self, ctx: &impl RuntimeContext, wasm_binary: &[u8]) -> Result<Loaded> {
+        match WasmBinaryType::from_bytes(wasm_binary) {
+            Some(kind) => self.compile(ctx, wasm_binary, kind),
             None => match &self.engine.detect_precompiled(wasm_binary) {
                 Some(Precompiled::Module) => {
                     containerd_shim_wasm::info!(ctx, "using precompiled module");
                     let module = unsafe { Module::deserialize(&self.engine, wasm_binary) }?;
-                    self.execute_module(ctx, module, &func)
+                    Ok(Loaded::Module(module))
                 }
                 Some(Precompiled::Component) => {
                     containerd_shim_wasm::info!(ctx, "using precompiled component");
                     let component = unsafe { Component::deserialize(&self.engine, wasm_binary) }?;
-                    self.execute_component(ctx, component, func)
-                }
-                None => {
-                    bail!("invalid precompiled module")
+                    Ok(Loaded::Component(component))
                 }
+                // e.g., a component of a newer encoding than this wasmtime
+                None if wasm_binary.starts_with(b"\0asm") => bail!(
+                    "unsupported wasm binary, neither a core module nor a component that wasmtime {} can run",
+                    WASMTIME_VERSION.unwrap_or("of the shim")
+                ),
+                None => bail!(
+                    "not a wasm module or component, nor one precompiled by wasmtime {}",
+                    WASMTIME_VERSION.unwrap_or("of the shim")
+                ),
             },
         }
     }
 }
 
+/// A module or component loaded by the engine
+enum Loaded {
+    Module(Module),
+    Component(Component),
+}
+
+impl Loaded {
+    /// The compiled code, as the engine deserializes it
+    fn serialize(&self) -> Result<Vec<u8>> {
+        match self {
+            Self::Module(module) => module.serialize(),
+            Self::Component(component) => component.serialize(),
+        }
+    }
+}
+
 pub(crate) fn envs_from_ctx(ctx: &impl RuntimeContext) -> Vec<(String, String)> {
     ctx.envs()
         .iter()
@@ -383,11 +791,36 @@ pub(crate) fn envs_from_ctx(ctx: &impl RuntimeContext) -> Vec<(String, String)>
         .collect()
 }
 
-fn store_for_context<T: wasi_preview2::WasiView>(
+/// Let the guest use the sockets of its socket policy, with `wasi:sockets`.
+/// The addresses that it doesn't allow fail with `access-denied`.
+pub(crate) fn apply_socket_policy(
+    builder: &mut wasi_preview2::WasiCtxBuilder,
+    policy: SocketPolicy,
+) {
+    builder
+        .allow_tcp(policy.is_enabled())
+        .allow_udp(policy.is_enabled())
+        .allow_ip_name_lookup(policy.ip_name_lookup);
+    let policy = Arc::new(policy);
+    builder.socket_addr_check(move |addr, addr_use| {
+        // a UDP client binds an ephemeral port of any address before it sends datagrams
+        let ephemeral = matches!(addr_use, wasi_preview2::SocketAddrUse::UdpBind)
+            && addr.port() == 0
+            && addr.ip().is_unspecified();
+        let allowed = policy.allows(addr) || (policy.is_enabled() && ephemeral);
+        if !allowed {
+            log::debug!("the socket policy denies the {addr_use:?} of {addr}");
+        }
+        Box::pin(async move { allowed })
+    });
+}
+
+fn store_for_context(
     engine: &wasmtime::Engine,
-    ctx: T,
-) -> Result<(Store<T>, component::Linker<T>)> {
-    let store = Store::new(engine, ctx);
+    ctx: WasiPreview2Ctx,
+) -> Result<(Store<WasiPreview2Ctx>, component::Linker<WasiPreview2Ctx>)> {
+    let mut store = Store::new(engine, ctx);
+    store.limiter(|ctx| &mut ctx.limiter);
 
     log::debug!("init linker");
     let mut linker = component::Linker::new(engine);
@@ -404,6 +837,16 @@ fn wasi_builder(ctx: &impl RuntimeContext) -> Result<wasi_preview2::WasiCtxBuild
 
     let file_perms = wasi_preview2::FilePerms::all();
     let dir_perms = wasi_preview2::DirPerms::all();
+    // the guest only gets read capabilities for a read-only rootfs, or a read-only mount
+    let read_only = (
+        wasi_preview2::DirPerms::READ,
+        wasi_preview2::FilePerms::READ,
+    );
+    let (root_dir_perms, root_file_perms) = if ctx.readonly_rootfs() {
+        read_only
+    } else {
+        (dir_perms, file_perms)
+    };
     let envs = envs_from_ctx(ctx);
 
     let mut builder = wasi_preview2::WasiCtxBuilder::new();
@@ -411,16 +854,42 @@ fn wasi_builder(ctx: &impl RuntimeContext) -> Result<wasi_preview2::WasiCtxBuild
         .args(ctx.args())
         .envs(&envs)
         .inherit_stdio()
-        .inherit_network()
-        .allow_tcp(true)
-        .allow_udp(true)
-        .allow_ip_name_lookup(true)
-        .preopened_dir("/", "/", dir_perms, file_perms)?;
+        .preopened_dir("/", "/", root_dir_perms, root_file_perms)?;
+    apply_socket_policy(&mut builder, ctx.socket_policy());
+
+    // the mounts are also in the preopen of `/`, with their own capabilities,
+    // so that a read-write mount stays writable in a read-only rootfs
+    for preopen in ctx.preopens() {
+        let (dir_perms, file_perms) = if preopen.read_only {
+            read_only
+        } else {
+            (dir_perms, file_perms)
+        };
+        containerd_shim_wasm::debug!(ctx, "preopening {preopen:?}");
+        builder.preopened_dir(
+            &preopen.host_path,
+            &preopen.guest_path,
+            dir_perms,
+            file_perms,
+        )?;
+    }
 
     containerd_shim_wasm::debug!(ctx, "WASI context built successfully");
     Ok(builder)
 }
 
+/// Run the guest until the container reloads its module, see `Engine::interrupt`.
+/// A guest that doesn't wait, e.g., on I/O or on a clock, stops when it next does.
+async fn until_reload<T: Default>(run: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::select! {
+        res = run => res,
+        _ = RELOAD.notified() => {
+            log::info!("stopping the instance to reload the module");
+            Ok(T::default())
+        }
+    }
+}
+
 async fn wait_for_signal() -> Result<i32> {
     #[cfg(unix)]
     {
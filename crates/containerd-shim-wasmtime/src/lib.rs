@@ -1,5 +1,7 @@
 mod http_proxy;
 pub mod instance;
+mod limits;
+mod metrics;
 
 pub use instance::WasmtimeInstance;
 
@@ -0,0 +1,452 @@
+//! Limits of the stores of a container, from its engine configuration and from the
+//! resources in its OCI spec.
+//!
+//! The cgroup of the container already limits the whole process. Enforcing the limits on the
+//! stores makes the guest trap when it allocates too much memory, instead of the process being
+//! killed, and throttles a guest that would otherwise spin in the engine.
+//!
+//! The CPU quota and the deadline of the stores are counted in the epoch of the engine, which a
+//! background thread increments at each tick, when the container has either of them.
+
+use std::time::{Duration, Instant};
+use std::{fmt, thread};
+
+use anyhow::Result;
+use containerd_shim_wasm::container::{CpuQuota, RuntimeContext};
+use wasmtime::{Store, StoreLimitsBuilder, Trap, UpdateDeadline};
+
+use crate::metrics::MetricsLimiter;
+
+/// How often the epoch of the engine is incremented, when the container has a CPU quota
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+/// Deadline of the stores without a CPU quota, that the epoch never reaches
+const NO_DEADLINE: u64 = u64::MAX / 2;
+/// Memory kept out of the memory limit of the guest, for the allocations of the engine
+/// and of WASI while the guest runs, without the `MemoryReserve` shim option
+const DEFAULT_MEMORY_RESERVE: u64 = 8 * 1024 * 1024;
+/// Most instances, tables and memories of a store, far more than a module or a component has,
+/// so that a guest can't use the memory of the engine with them instead of its linear memory
+const MAX_INSTANCES: usize = 1000;
+const MAX_TABLES: usize = 1000;
+const MAX_MEMORIES: usize = 100;
+/// Exit code of a guest that ran out of fuel, as for the `SIGXCPU` of a process
+/// past its limit of CPU time
+pub(crate) const OUT_OF_FUEL_EXIT_CODE: i32 = 128 + 24;
+/// Exit code of a guest that grew a memory past the memory limit, as for the `SIGABRT`
+/// of a process whose allocation failed, rather than the `SIGKILL` of the OOM killer
+pub(crate) const OUT_OF_MEMORY_EXIT_CODE: i32 = 128 + 6;
+
+/// The error of a guest that grew a memory past the memory limit of its store
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct MemoryLimitExceeded {
+    /// The size the memory was growing to, in bytes
+    pub desired: usize,
+    /// The memory the guest can use, in bytes
+    pub limit: usize,
+}
+
+impl fmt::Display for MemoryLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "memory limit exceeded: the guest grew a memory to {} bytes, past its limit of {} bytes",
+            self.desired, self.limit
+        )
+    }
+}
+
+impl std::error::Error for MemoryLimitExceeded {}
+
+/// The error of a guest that ran past the deadline of its store
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct DeadlineExceeded {
+    /// How long the guest ran for, without the time it was throttled for its CPU quota
+    pub elapsed: Duration,
+    pub deadline: Duration,
+}
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "deadline exceeded: the guest ran for {:.2?}, past its limit of {:?}",
+            self.elapsed, self.deadline
+        )
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Limits {
+    /// Fuel given to each store, when fuel is enabled
+    pub fuel: Option<u64>,
+    /// Memory limit of the container, in bytes
+    pub memory: Option<u64>,
+    /// Memory kept out of the memory limit for the engine, in bytes, when not the default
+    pub memory_reserve: Option<u64>,
+    /// CPU time each store can run for in each period, when it is less than a CPU
+    pub cpu: Option<CpuQuota>,
+    /// How long each store can run for, with `max-exec-time`
+    pub max_exec_time: Option<Duration>,
+    /// CPU time of the container, from its `RLIMIT_CPU`, the deadline of the stores
+    /// without a `max-exec-time`
+    pub cpu_time: Option<Duration>,
+    /// Whether the stores yield to the other tasks of the runtime at each tick
+    pub yields: bool,
+}
+
+impl Limits {
+    /// The fuel and the deadline of the engine configuration, and the resources of the container in `ctx`
+    pub fn new(
+        fuel: Option<u64>,
+        max_exec_time: Option<Duration>,
+        ctx: &impl RuntimeContext,
+    ) -> Self {
+        Self {
+            fuel,
+            memory: ctx.memory_limit(),
+            memory_reserve: ctx.memory_reserve(),
+            // a guest runs on a single thread, a quota of a CPU or more never throttles it
+            cpu: ctx.cpu_quota().filter(|quota| quota.quota < quota.period),
+            max_exec_time,
+            cpu_time: ctx.cpu_time_limit(),
+            yields: false,
+        }
+    }
+
+    /// The limits of the store of each request of a server. The deadline is per request,
+    /// and the requests yield at each tick, so that a spinning request doesn't stall the others.
+    pub fn for_requests(self) -> Self {
+        Self {
+            // The requests are served concurrently on the tokio runtime, a request sleeping
+            // for its CPU quota would stall the others. The cgroup throttles them instead.
+            cpu: None,
+            // the CPU time of the whole process isn't the budget of a request
+            cpu_time: None,
+            yields: true,
+            ..self
+        }
+    }
+
+    /// How long each store can run for, if it has a deadline
+    pub fn deadline(&self) -> Option<Duration> {
+        self.max_exec_time.or(self.cpu_time)
+    }
+
+    /// Start incrementing the epoch of `engine`, which the CPU quota and the deadline of the
+    /// stores are counted in. Without either of them, the epoch never changes.
+    pub fn start_epoch(&self, engine: &wasmtime::Engine) {
+        if self.cpu.is_none() && self.deadline().is_none() {
+            return;
+        }
+        let engine = engine.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(EPOCH_TICK);
+                engine.increment_epoch();
+            }
+        });
+    }
+
+    /// The limiter of a new store, which traps when a memory grows past the memory limit.
+    /// The cgroup of the container counts the memory the process already uses, e.g., for the
+    /// engine, the compiled modules, and the other stores, so the guest only gets the rest.
+    pub fn limiter(&self) -> MetricsLimiter {
+        let limits = StoreLimitsBuilder::new()
+            .instances(MAX_INSTANCES)
+            .tables(MAX_TABLES)
+            .memories(MAX_MEMORIES);
+        let Some(memory) = self.memory else {
+            return MetricsLimiter::new(limits.build());
+        };
+        let reserve = self.memory_reserve.unwrap_or(DEFAULT_MEMORY_RESERVE);
+        let available = memory.saturating_sub(resident_memory().saturating_add(reserve));
+        let available = usize::try_from(available).unwrap_or(usize::MAX);
+        let limits = limits.memory_size(available).trap_on_grow_failure(true);
+        MetricsLimiter::new(limits.build()).with_memory_limit(available)
+    }
+
+    /// The exit code of a guest that trapped as it ran out of fuel or grew a memory past the
+    /// memory limit, the error of a guest that ran past its deadline, with the time it ran for
+    /// in its message, or the result of the guest otherwise
+    pub fn exit_code(&self, res: Result<i32>) -> Result<i32> {
+        let err = match res {
+            Ok(code) => return Ok(code),
+            Err(err) => err,
+        };
+        if let Some(fuel) = self
+            .fuel
+            .filter(|_| err.downcast_ref::<Trap>() == Some(&Trap::OutOfFuel))
+        {
+            log::error!("fuel exhausted after {fuel} instructions");
+            return Ok(OUT_OF_FUEL_EXIT_CODE);
+        }
+        if let Some(exceeded) = err.downcast_ref::<MemoryLimitExceeded>() {
+            log::error!("{exceeded}");
+            return Ok(OUT_OF_MEMORY_EXIT_CODE);
+        }
+        match err.downcast_ref::<DeadlineExceeded>() {
+            Some(&exceeded) => {
+                log::error!("{exceeded}");
+                // the wasm backtrace of the trap stays in the context of the error
+                Err(err.context(exceeded))
+            }
+            None => Err(err),
+        }
+    }
+
+    /// Give its fuel, its CPU quota and its deadline to a new store
+    pub fn apply<T>(&self, store: &mut Store<T>) -> Result<()> {
+        if let Some(fuel) = self.fuel {
+            store.set_fuel(fuel)?;
+        }
+
+        let quota = self.cpu;
+        let deadline = self.deadline();
+        if quota.is_none() && deadline.is_none() {
+            store.set_epoch_deadline(NO_DEADLINE);
+            return Ok(());
+        }
+
+        // The store runs for its quota, and then sleeps until the end of the period.
+        // The epoch counts the time the store runs for, including while it waits for I/O,
+        // so the guest never uses more than its quota of CPU time.
+        // The deadline counts the same time, without the periods the store slept for.
+        let budget =
+            quota.map(|quota| (quota.quota.as_nanos() / EPOCH_TICK.as_nanos()).max(1) as u64);
+        let yields = self.yields;
+        let start = Instant::now();
+        let mut throttled = Duration::ZERO;
+        let mut period_start = start;
+        let mut ticks = 0;
+        store.set_epoch_deadline(1);
+        store.epoch_deadline_callback(move |_| {
+            let elapsed = start.elapsed().saturating_sub(throttled);
+            if let Some(deadline) = deadline.filter(|&deadline| elapsed >= deadline) {
+                return Err(DeadlineExceeded { elapsed, deadline }.into());
+            }
+            if let (Some(quota), Some(budget)) = (quota, budget) {
+                ticks += 1;
+                if ticks >= budget {
+                    if let Some(rest) = quota.period.checked_sub(period_start.elapsed()) {
+                        thread::sleep(rest);
+                        throttled += rest;
+                    }
+                    ticks = 0;
+                    period_start = Instant::now();
+                }
+            }
+            Ok(match yields {
+                true => UpdateDeadline::Yield(1),
+                false => UpdateDeadline::Continue(1),
+            })
+        });
+        Ok(())
+    }
+}
+
+/// The resident memory of the process, in bytes, or 0 when it can't be read
+fn resident_memory() -> u64 {
+    let Ok(statm) = std::fs::read_to_string("/proc/self/statm") else {
+        return 0;
+    };
+    let pages = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pages| pages.parse::<u64>().ok())
+        .unwrap_or_default();
+    pages * page_size()
+}
+
+#[cfg(unix)]
+fn page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+#[cfg(not(unix))]
+fn page_size() -> u64 {
+    4096
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::{Config, Engine, Instance, Module};
+
+    use super::*;
+
+    // counts the iterations of a loop that never ends
+    const SPIN_LOOP: &str = r#"(module
+        (global $count (export "count") (mut i64) (i64.const 0))
+        (func (export "_start")
+            (loop $spin
+                (global.set $count (i64.add (global.get $count) (i64.const 1)))
+                (br $spin))))"#;
+
+    #[test]
+    fn test_out_of_fuel() -> Result<()> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::new(&engine, SPIN_LOOP)?;
+
+        let mut iterations = vec![];
+        for fuel in [10_000, 20_000] {
+            let limits = Limits {
+                fuel: Some(fuel),
+                ..Default::default()
+            };
+            let mut store = Store::new(&engine, ());
+            limits.apply(&mut store)?;
+            let instance = Instance::new(&mut store, &module, &[])?;
+            let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+
+            let err = start.call(&mut store, ()).unwrap_err();
+            assert_eq!(err.downcast_ref::<Trap>(), Some(&Trap::OutOfFuel));
+            assert_eq!(store.get_fuel()?, 0);
+            assert_eq!(limits.exit_code(Err(err))?, OUT_OF_FUEL_EXIT_CODE);
+
+            let count = instance.get_global(&mut store, "count").unwrap();
+            iterations.push(count.get(&mut store).unwrap_i64());
+        }
+
+        // the guest runs for as long as its budget lasts
+        assert!(iterations[0] > 0);
+        assert!(
+            (iterations[1] - 2 * iterations[0]).abs() <= 2,
+            "{iterations:?}"
+        );
+
+        // the other errors are left as they are
+        let limits = Limits {
+            fuel: Some(10_000),
+            ..Default::default()
+        };
+        assert!(
+            limits
+                .exit_code(Err(Trap::UnreachableCodeReached.into()))
+                .is_err()
+        );
+        assert_eq!(limits.exit_code(Ok(3))?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_limit_exceeded() -> Result<()> {
+        use wasmtime::ResourceLimiter as _;
+
+        // the reserve takes the whole limit, the guest can't grow its memory at all
+        let limits = Limits {
+            memory: Some(1 << 30),
+            memory_reserve: Some(1 << 30),
+            ..Default::default()
+        };
+        let mut limiter = limits.limiter();
+        let err = limiter.memory_growing(0, 65536, None).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MemoryLimitExceeded>(),
+            Some(&MemoryLimitExceeded {
+                desired: 65536,
+                limit: 0
+            })
+        );
+        assert_eq!(limits.exit_code(Err(err))?, OUT_OF_MEMORY_EXIT_CODE);
+
+        // without a memory limit, only the counts of the store are limited
+        let mut limiter = Limits::default().limiter();
+        assert!(limiter.memory_growing(0, 1 << 20, None)?);
+        assert_eq!(limiter.instances(), MAX_INSTANCES);
+        assert_eq!(limiter.memories(), MAX_MEMORIES);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deadline_exceeded() -> Result<()> {
+        let mut config = Config::new();
+        config.epoch_interruption(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::new(&engine, SPIN_LOOP)?;
+
+        let limits = Limits {
+            max_exec_time: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        limits.start_epoch(&engine);
+        let mut store = Store::new(&engine, ());
+        limits.apply(&mut store)?;
+        let instance = Instance::new(&mut store, &module, &[])?;
+        let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+
+        let err = start.call(&mut store, ()).unwrap_err();
+        let exceeded = *err
+            .downcast_ref::<DeadlineExceeded>()
+            .expect("the guest ran past its deadline");
+        assert_eq!(exceeded.deadline, Duration::from_millis(100));
+        assert!(
+            exceeded.elapsed >= exceeded.deadline && exceeded.elapsed < Duration::from_secs(1),
+            "{exceeded}"
+        );
+        let err = limits.exit_code(Err(err)).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("deadline exceeded: the guest ran for"),
+            "{err}"
+        );
+
+        // the CPU time of the container is the deadline of the stores without a max-exec-time,
+        // but not of each request of a server
+        let limits = Limits {
+            cpu_time: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        assert_eq!(limits.deadline(), Some(Duration::from_secs(30)));
+        assert_eq!(limits.for_requests().deadline(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deadline_of_requests() -> Result<()> {
+        let mut config = Config::new();
+        config.async_support(true);
+        config.epoch_interruption(true);
+        let engine = Engine::new(&config)?;
+        let spinning = Module::new(&engine, SPIN_LOOP)?;
+        let quick = Module::new(&engine, r#"(module (func (export "_start")))"#)?;
+
+        let deadline = Duration::from_millis(200);
+        let limits = Limits {
+            max_exec_time: Some(deadline),
+            ..Default::default()
+        }
+        .for_requests();
+        limits.start_epoch(&engine);
+
+        // each request runs in its own store, with a deadline of its own
+        let request = |module: Module| {
+            let engine = engine.clone();
+            async move {
+                let mut store = Store::new(&engine, ());
+                limits.apply(&mut store)?;
+                let instance = Instance::new_async(&mut store, &module, &[]).await?;
+                let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+                start.call_async(&mut store, ()).await?;
+                anyhow::Ok(Instant::now())
+            }
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let begin = Instant::now();
+        let (spinning, quick) =
+            runtime.block_on(async { tokio::join!(request(spinning), request(quick)) });
+
+        let err = spinning.unwrap_err();
+        let exceeded = err
+            .downcast_ref::<DeadlineExceeded>()
+            .expect("the request ran past its deadline");
+        assert!(exceeded.elapsed >= deadline, "{exceeded}");
+
+        // the spinning request yields to the other one, which finishes first
+        assert!(quick?.duration_since(begin) < deadline);
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! Metrics of the modules running in the container process, reported by
+//! `Engine::collect_metrics`.
+//!
+//! The container process runs a single module, so the metrics are kept in statics,
+//! updated by the stores of the module and read from the thread collecting the metrics.
+
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use containerd_shim_wasm::container::EngineMetrics;
+use wasmtime::{ResourceLimiter, StoreLimits};
+
+use crate::limits::MemoryLimitExceeded;
+
+static LINEAR_MEMORY_BYTES: AtomicU64 = AtomicU64::new(0);
+static TABLE_ELEMENTS: AtomicU64 = AtomicU64::new(0);
+static COMPILATION_TIME: OnceLock<Duration> = OnceLock::new();
+
+pub(crate) fn collect() -> EngineMetrics {
+    EngineMetrics {
+        linear_memory_bytes: Some(LINEAR_MEMORY_BYTES.load(Ordering::Relaxed)),
+        table_elements: Some(TABLE_ELEMENTS.load(Ordering::Relaxed)),
+        // the fuel consumed is not reported
+        fuel_consumed: None,
+        compilation_time_ns: COMPILATION_TIME.get().map(|t| t.as_nanos() as u64),
+    }
+}
+
+/// Compile or deserialize the module of the container, and record how long it took
+pub(crate) fn compile<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let start = Instant::now();
+    let res = f()?;
+    let _ = COMPILATION_TIME.set(start.elapsed());
+    Ok(res)
+}
+
+/// Limiter of a store that accounts the size of its memories and tables, after checking their
+/// `limits`. The size is removed from the metrics when the store is dropped, e.g., after a request
+/// of a `wasi:http` component.
+#[derive(Default)]
+pub(crate) struct MetricsLimiter {
+    memory_bytes: u64,
+    table_elements: u64,
+    limits: StoreLimits,
+    /// The memory size of the `limits`, for the errors of the memories growing past it
+    memory_limit: Option<usize>,
+}
+
+impl MetricsLimiter {
+    pub fn new(limits: StoreLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Report the traps of the memories growing past `limit`, the memory size of the `limits`,
+    /// as a `MemoryLimitExceeded`
+    pub fn with_memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+}
+
+impl ResourceLimiter for MetricsLimiter {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool> {
+        let allowed = match self.limits.memory_growing(current, desired, maximum) {
+            Ok(allowed) => allowed,
+            // past the memory limit, rather than past the maximum of the memory itself
+            Err(err) => match self.memory_limit.filter(|&limit| desired > limit) {
+                Some(limit) => return Err(err.context(MemoryLimitExceeded { desired, limit })),
+                None => return Err(err),
+            },
+        };
+        if !allowed {
+            return Ok(false);
+        }
+        let delta = desired.saturating_sub(current) as u64;
+        self.memory_bytes += delta;
+        LINEAR_MEMORY_BYTES.fetch_add(delta, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool> {
+        if !self.limits.table_growing(current, desired, maximum)? {
+            return Ok(false);
+        }
+        let delta = desired.saturating_sub(current) as u64;
+        self.table_elements += delta;
+        TABLE_ELEMENTS.fetch_add(delta, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances()
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.tables()
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.memories()
+    }
+}
+
+impl Drop for MetricsLimiter {
+    fn drop(&mut self) {
+        LINEAR_MEMORY_BYTES.fetch_sub(self.memory_bytes, Ordering::Relaxed);
+        TABLE_ELEMENTS.fetch_sub(self.table_elements, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use wasmtime::ResourceLimiter as _;
+
+    use super::*;
+
+    #[test]
+    fn limiter_accounts_live_stores() -> Result<()> {
+        let before = LINEAR_MEMORY_BYTES.load(Ordering::Relaxed);
+
+        let mut limiter = MetricsLimiter::default();
+        assert!(limiter.memory_growing(0, 65536, None)?);
+        assert!(limiter.memory_growing(65536, 131072, None)?);
+        assert_eq!(LINEAR_MEMORY_BYTES.load(Ordering::Relaxed), before + 131072);
+
+        drop(limiter);
+        assert_eq!(LINEAR_MEMORY_BYTES.load(Ordering::Relaxed), before);
+        Ok(())
+    }
+
+    #[test]
+    fn limiter_traps_past_the_limits() {
+        let limits = wasmtime::StoreLimitsBuilder::new()
+            .memory_size(131072)
+            .trap_on_grow_failure(true)
+            .build();
+        let mut limiter = MetricsLimiter::new(limits).with_memory_limit(131072);
+        let err = limiter.memory_growing(0, 196608, None).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MemoryLimitExceeded>(),
+            Some(&MemoryLimitExceeded {
+                desired: 196608,
+                limit: 131072
+            })
+        );
+        assert_eq!(limiter.memory_bytes, 0);
+
+        // past the maximum of the memory itself, rather than the limit of the container
+        let err = limiter.memory_growing(0, 131072, Some(65536)).unwrap_err();
+        assert!(err.downcast_ref::<MemoryLimitExceeded>().is_none());
+    }
+}
@@ -1,12 +1,22 @@
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::io::{Read as _, Write as _};
+use std::net::{SocketAddr, TcpListener};
+use std::time::{Duration, Instant};
 
 use WasmtimeTestInstance as WasiInstance;
 use containerd_shim_wasm::container::Instance;
+use containerd_shim_wasm::sandbox::env_policy::ENV_POLICY_ANNOTATION;
+use containerd_shim_wasm::sandbox::socket_policy::{SOCKET_POLICY_ANNOTATION, SocketMode};
+use containerd_shim_wasm::sandbox::{EnvPolicy, SocketPolicy};
 use containerd_shim_wasm::testing::modules::*;
-use containerd_shim_wasm::testing::{WasiTest, oci_helpers};
+use containerd_shim_wasm::testing::{WasiTest, is_cgroup_v2, oci_helpers};
+use oci_spec::runtime::{
+    LinuxCpuBuilder, LinuxMemoryBuilder, LinuxResourcesBuilder, PosixRlimitBuilder, PosixRlimitType,
+};
 use serial_test::serial;
 
 use crate::instance::WasmtimeEngine;
+use crate::limits::{OUT_OF_FUEL_EXIT_CODE, OUT_OF_MEMORY_EXIT_CODE};
 
 // use test configuration to avoid dead locks when running tests
 // https://github.com/containerd/runwasi/issues/357
@@ -171,6 +181,564 @@ fn test_exit_code() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn test_linked_modules_oci() -> anyhow::Result<()> {
+    let image_name = "localhost/linked:latest".to_string();
+    let layer = |module: &TestModule, name: &str| oci_helpers::ImageContent {
+        bytes: module.bytes.to_vec(),
+        media_type: "application/wasm".to_string(),
+        name: Some(format!("{name}.wasm")),
+    };
+    // the main module comes first, and imports from the library in the second layer
+    oci_helpers::import_image(
+        &image_name,
+        &[
+            &layer(&LINKED_MAIN, "linked_main"),
+            &layer(&LINKED_LIBRARY, "linked_library"),
+        ],
+    )?;
+
+    let (builder, _oci_cleanup) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(LINKED_MAIN)?
+        .as_oci_image(Some(image_name), None)?;
+
+    let (exit_code, _, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 42);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_fuel_from_annotation() -> anyhow::Result<()> {
+    // enough fuel to say hello
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation("runwasi.io/engine.fuel", "1000000")
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    // the module runs out of fuel
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation("runwasi.io/engine.fuel", "1")
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_ne!(exit_code, 0);
+    assert_eq!(stdout, "");
+
+    Ok(())
+}
+
+// Test that a module spinning forever stops when it runs out of the fuel of the annotation
+// of the engine, with its own exit code.
+#[test]
+#[serial]
+fn test_fuel_exhausted() -> anyhow::Result<()> {
+    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(SPIN_LOOP)?
+        .with_annotation("io.runwasi.wasmtime.fuel", "10000000")
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, OUT_OF_FUEL_EXIT_CODE as u32);
+
+    Ok(())
+}
+
+// Test that a module spinning forever fails once it ran for the max-exec-time of the annotation.
+#[test]
+#[serial]
+fn test_max_exec_time_exceeded() -> anyhow::Result<()> {
+    for invalid in ["soon", "0s"] {
+        let res = WasiTest::<WasiInstance>::builder()?
+            .with_wasm(SPIN_LOOP)?
+            .with_annotation("io.runwasi.wasmtime.max-exec-time", invalid)
+            .build();
+        assert!(res.is_err(), "{invalid}");
+    }
+
+    let start = Instant::now();
+    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(SPIN_LOOP)?
+        .with_annotation("io.runwasi.wasmtime.max-exec-time", "500ms")
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 137);
+    assert!(start.elapsed() >= Duration::from_millis(500));
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_fuel_from_annotation_oci_skips_precompiled() -> anyhow::Result<()> {
+    let (builder, _oci_cleanup1) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .as_oci_image(
+            Some("localhost/hello:latest".to_string()),
+            Some("c1".to_string()),
+        )?;
+
+    let (exit_code, _, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+
+    // the precompiled layer was compiled without fuel metering, the original one is loaded
+    let (builder, _oci_cleanup2) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation("runwasi.io/engine.fuel", "1000000")
+        .as_oci_image(
+            Some("localhost/hello:latest".to_string()),
+            Some("c2".to_string()),
+        )?;
+
+    let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_invalid_engine_config_fails_create() -> anyhow::Result<()> {
+    let res = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation("runwasi.io/engine.fuel", "lots")
+        .build();
+    assert!(res.is_err());
+
+    // unknown keys are ignored, unless in strict mode
+    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation("runwasi.io/engine.unknown", "1")
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+
+    let res = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_annotation("runwasi.io/engine.unknown", "1")
+        .with_annotation("runwasi.io/engine-strict", "true")
+        .build();
+    assert!(res.is_err());
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_mounts_are_preopened() -> anyhow::Result<()> {
+    let data = tempfile::tempdir()?;
+    std::fs::write(data.path().join("hello.txt"), "hello from the host\n")?;
+
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(READ_MOUNT)?
+        .with_mount(data.path(), "/mnt/data", false)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello from the host\nwritable\n");
+    assert!(data.path().join("new.txt").exists());
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_read_only_mounts_are_preopened_read_only() -> anyhow::Result<()> {
+    let data = tempfile::tempdir()?;
+    std::fs::write(data.path().join("hello.txt"), "hello from the host\n")?;
+
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(READ_MOUNT)?
+        .with_mount(data.path(), "/mnt/data", true)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello from the host\nread-only\n");
+    assert!(!data.path().join("new.txt").exists());
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_host_dirs_are_preopened() -> anyhow::Result<()> {
+    let models = tempfile::tempdir()?;
+    std::fs::write(models.path().join("hello.txt"), "hello from the node\n")?;
+
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(READ_MOUNT)?
+        .with_host_dir(models.path(), "/mnt/data", true)
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello from the node\nread-only\n");
+    assert!(!models.path().join("new.txt").exists());
+
+    // the mount of the spec is preferred over the host directory
+    let data = tempfile::tempdir()?;
+    std::fs::write(data.path().join("hello.txt"), "hello from the pod\n")?;
+
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(READ_MOUNT)?
+        .with_mount(data.path(), "/mnt/data", false)?
+        .with_host_dir(models.path(), "/mnt/data", true)
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello from the pod\nwritable\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_readonly_rootfs_is_preopened_read_only() -> anyhow::Result<()> {
+    let data = tempfile::tempdir()?;
+
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(WRITE_ROOT)?
+        .with_mount(data.path(), "/mnt/data", false)?
+        .with_readonly_rootfs()
+        .build()?;
+    let rootfs = test.root().join("rootfs");
+    let (exit_code, stdout, _) = test.start()?.wait(Duration::from_secs(10))?;
+
+    // the write fails without trapping, and the read-write mount stays writable
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "/new.txt: read-only\n/mnt/data/new.txt: writable\n");
+    assert!(!rootfs.join("new.txt").exists());
+
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(WRITE_ROOT)?
+        .with_mount(data.path(), "/mnt/data", false)?
+        .build()?;
+    let rootfs = test.root().join("rootfs");
+    let (exit_code, stdout, _) = test.start()?.wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "/new.txt: writable\n/mnt/data/new.txt: writable\n");
+    assert!(rootfs.join("new.txt").exists());
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_user_namespace_maps_the_ids_of_the_guest() -> anyhow::Result<()> {
+    use std::os::unix::fs::{MetadataExt as _, PermissionsExt as _};
+
+    let data = tempfile::tempdir()?;
+    std::fs::write(data.path().join("hello.txt"), "hello from the host\n")?;
+    // the root of the namespace isn't the root of the host
+    std::fs::set_permissions(data.path(), std::fs::Permissions::from_mode(0o777))?;
+
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(READ_MOUNT)?
+        .with_mount(data.path(), "/mnt/data", false)?
+        .with_user_namespace(100000, 65536)?
+        .build()?;
+
+    // the rootfs is owned by the root of the namespace
+    let module = std::fs::metadata(test.root().join("rootfs").join("hello.wasm"))?;
+    assert_eq!((module.uid(), module.gid()), (100000, 100000));
+
+    let (exit_code, stdout, _) = test.start()?.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello from the host\nwritable\n");
+
+    // the files written by the guest are owned by the mapped ids
+    let written = std::fs::metadata(data.path().join("new.txt"))?;
+    assert_eq!((written.uid(), written.gid()), (100000, 100000));
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_memory_limit_traps_the_guest() -> anyhow::Result<()> {
+    // without a limit, the guest gets all the memory it asks for
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(GROW_MEMORY)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "grew 128MiB\n");
+
+    // with a limit, the growth past the limit traps, before the kernel kills the process
+    let limit: i64 = 64 * 1024 * 1024;
+    let resources = LinuxResourcesBuilder::default()
+        .memory(LinuxMemoryBuilder::default().limit(limit).build()?)
+        .build()?;
+    let (exit_code, stdout, stderr) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(GROW_MEMORY)?
+        .with_resources(resources)
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, OUT_OF_MEMORY_EXIT_CODE as u32, "{stderr}");
+    assert_eq!(stdout, "", "{stderr}");
+
+    Ok(())
+}
+
+// Test that the memory reserve of the shim options leaves less of the memory limit to the guest.
+#[test]
+#[serial]
+fn test_memory_reserve() -> anyhow::Result<()> {
+    let limit: i64 = 256 * 1024 * 1024;
+    let resources = || {
+        LinuxResourcesBuilder::default()
+            .memory(LinuxMemoryBuilder::default().limit(limit).build()?)
+            .build()
+    };
+
+    // the default reserve leaves enough of the limit to grow 128MiB
+    let (exit_code, stdout, stderr) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(GROW_MEMORY)?
+        .with_resources(resources()?)
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0, "{stderr}");
+    assert_eq!(stdout, "grew 128MiB\n");
+
+    let (exit_code, stdout, stderr) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(GROW_MEMORY)?
+        .with_resources(resources()?)
+        .with_memory_reserve(192 * 1024 * 1024)
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, OUT_OF_MEMORY_EXIT_CODE as u32, "{stderr}");
+    assert_eq!(stdout, "", "{stderr}");
+
+    Ok(())
+}
+
+// Test that the modules of the bundle are compiled once, and loaded from the compile cache after.
+#[test]
+#[serial]
+fn test_compile_cache() -> anyhow::Result<()> {
+    let cache = tempfile::tempdir()?;
+    let run = || -> anyhow::Result<(u32, String, String)> {
+        WasiTest::<WasiInstance>::builder()?
+            .with_wasm(HELLO_WORLD)?
+            .with_compile_cache_dir(cache.path())
+            .build()?
+            .start()?
+            .wait(Duration::from_secs(10))
+    };
+    let artifacts = || -> anyhow::Result<Vec<_>> {
+        Ok(std::fs::read_dir(cache.path())?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?)
+    };
+
+    let (exit_code, stdout, stderr) = run()?;
+    assert_eq!(exit_code, 0, "{stderr}");
+    assert_eq!(stdout, "hello world\n");
+    let [artifact] = &artifacts()?[..] else {
+        panic!("expected a single artifact in the cache");
+    };
+    assert!(artifact.to_string_lossy().ends_with(".cwasm"));
+
+    // the second container loads the compiled module
+    let (exit_code, stdout, stderr) = run()?;
+    assert_eq!(exit_code, 0, "{stderr}");
+    assert_eq!(stdout, "hello world\n");
+    assert_eq!(artifacts()?, [artifact.clone()]);
+
+    // a corrupted artifact is compiled again
+    std::fs::write(artifact, b"not compiled")?;
+    let (exit_code, stdout, stderr) = run()?;
+    assert_eq!(exit_code, 0, "{stderr}");
+    assert_eq!(stdout, "hello world\n");
+    assert_ne!(std::fs::read(artifact)?, b"not compiled");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_cpu_quota_throttles_the_guest() -> anyhow::Result<()> {
+    // a tenth of a CPU is enough to say hello
+    let resources = LinuxResourcesBuilder::default()
+        .cpu(
+            LinuxCpuBuilder::default()
+                .quota(10_000)
+                .period(100_000u64)
+                .build()?,
+        )
+        .build()?;
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_resources(resources)
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "hello world\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_stdin_reads_eof() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(COUNT_STDIN)?
+        .with_stdin("hello world")?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "11\n");
+
+    // an empty stdin ends right away
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(COUNT_STDIN)?
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "0\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_stdin_fifo_reads_eof_of_the_client() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(COUNT_STDIN)?
+        .with_stdin_fifo()?
+        .build()?;
+    test.start()?;
+
+    // the client writes its input and closes it, e.g., `echo hello world | ctr run -i`
+    test.open_stdin()?.write_all(b"hello world")?;
+
+    let (exit_code, stdout, _) = test.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "11\n");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_close_stdin_fifo() -> anyhow::Result<()> {
+    let test = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(COUNT_STDIN)?
+        .with_stdin_fifo()?
+        .build()?;
+    test.start()?;
+
+    // the client keeps its input open, as containerd does until CloseIO
+    let client = test.open_stdin()?;
+    test.close_stdin()?;
+
+    let (exit_code, stdout, _) = test.wait(Duration::from_secs(10))?;
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "0\n");
+    drop(client);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_env_policy_of_the_node() -> anyhow::Result<()> {
+    let policy = EnvPolicy {
+        deny: vec!["*_SERVICE_HOST".to_string()],
+        inject: BTreeMap::from([("PLATFORM".to_string(), "wasm".to_string())]),
+        ..Default::default()
+    };
+
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(PRINT_ENV)?
+        .with_env("APP_NAME", "demo")
+        .with_env("DB_SERVICE_HOST", "10.0.0.1")
+        .with_env("PLATFORM", "linux")
+        .with_env_policy(policy)
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    let vars = stdout.lines().collect::<Vec<_>>();
+    assert!(vars.contains(&"APP_NAME=demo"), "{stdout}");
+    assert!(vars.contains(&"PLATFORM=wasm"), "{stdout}");
+    assert!(!vars.contains(&"PLATFORM=linux"), "{stdout}");
+    assert!(!stdout.contains("DB_SERVICE_HOST"), "{stdout}");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_env_policy_from_annotation() -> anyhow::Result<()> {
+    let (exit_code, stdout, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(PRINT_ENV)?
+        .with_env("APP_NAME", "demo")
+        .with_env("AWS_SECRET_ACCESS_KEY", "secret")
+        .with_annotation(ENV_POLICY_ANNOTATION, r#"{"deny": ["AWS_*"]}"#)
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert!(stdout.lines().any(|var| var == "APP_NAME=demo"), "{stdout}");
+    assert!(!stdout.contains("AWS_SECRET_ACCESS_KEY"), "{stdout}");
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_invalid_env_policy_fails_create() -> anyhow::Result<()> {
+    let res = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(PRINT_ENV)?
+        .with_annotation(ENV_POLICY_ANNOTATION, "deny everything")
+        .build();
+
+    assert!(res.is_err());
+
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn test_seccomp() -> anyhow::Result<()> {
@@ -243,6 +811,44 @@ fn test_wasip2_component() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn test_wasip2_component_oci_uses_precompiled() -> anyhow::Result<()> {
+    let (builder, _oci_cleanup1) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(COMPONENT_HELLO_WORLD)?
+        .as_oci_image(
+            Some("localhost/hello-component:latest".to_string()),
+            Some("c1".to_string()),
+        )?;
+
+    let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "Hello, world!\n");
+
+    let (label, _id) = oci_helpers::get_content_label()?;
+    assert!(
+        label.starts_with("runwasi.io/precompiled/wasmtime/"),
+        "was {}",
+        label
+    );
+
+    // the second run deserializes the precompiled component
+    let (builder, _oci_cleanup2) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(COMPONENT_HELLO_WORLD)?
+        .as_oci_image(
+            Some("localhost/hello-component:latest".to_string()),
+            Some("c2".to_string()),
+        )?;
+
+    let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(stdout, "Hello, world!\n");
+
+    Ok(())
+}
+
 // Test that the shim can execute a wasm component that is
 // compiled with wasi:http/proxy.
 //
@@ -302,10 +908,11 @@ fn test_wasip2_component_http_proxy_csharp() -> anyhow::Result<()> {
     Ok(())
 }
 
-// Test that the shim can terminate component targeting wasi:http/proxy by sending SIGTERM.
+// Test that a component targeting wasi:http/proxy shuts down gracefully on SIGTERM,
+// as when its pod is deleted.
 #[test]
 #[serial]
-fn test_wasip2_component_http_proxy_force_shutdown() -> anyhow::Result<()> {
+fn test_wasip2_component_http_proxy_graceful_shutdown() -> anyhow::Result<()> {
     let srv = WasiTest::<WasiInstance>::builder()?
         .with_wasm(HELLO_WASI_HTTP)?
         .with_host_network()
@@ -314,18 +921,318 @@ fn test_wasip2_component_http_proxy_force_shutdown() -> anyhow::Result<()> {
     let srv = srv.start()?;
     assert!(http_get().unwrap().status().is_success());
 
-    // Send SIGTERM
     let (exit_code, _, _) = srv.terminate()?.wait(Duration::from_secs(5))?;
-    // The exit code indicates that the process did not exit cleanly
-    assert_eq!(exit_code, 128 + libc::SIGTERM as u32);
+    assert_eq!(exit_code, 0);
 
     Ok(())
 }
 
+#[test]
+#[serial]
+fn test_wasip2_component_http_proxy_max_concurrent_requests() -> anyhow::Result<()> {
+    let res = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WASI_HTTP)?
+        .with_annotation("runwasi.io/engine.max-concurrent-requests", "0")
+        .build();
+    assert!(res.is_err());
+
+    let srv = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WASI_HTTP)?
+        .with_annotation("runwasi.io/engine.max-concurrent-requests", "1")
+        .with_host_network()
+        .build()?;
+
+    let srv = srv.start()?;
+    assert!(http_get().unwrap().status().is_success());
+
+    // the requests beyond the bound wait for their turn
+    let requests: Vec<_> = (0..4).map(|_| std::thread::spawn(http_get)).collect();
+    for request in requests {
+        let response = request.join().unwrap()?;
+        assert!(response.status().is_success());
+    }
+
+    let (exit_code, _, _) = srv.terminate()?.wait(Duration::from_secs(5))?;
+    assert_eq!(exit_code, 0);
+
+    Ok(())
+}
+
+// Test that the max-exec-time of a server is the deadline of each of its requests.
+#[test]
+#[serial]
+fn test_max_exec_time_of_requests() -> anyhow::Result<()> {
+    // the server runs for longer than the deadline, its requests don't
+    let srv = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WASI_HTTP)?
+        .with_annotation("io.runwasi.wasmtime.max-exec-time", "1s")
+        .with_host_network()
+        .build()?;
+
+    let srv = srv.start()?;
+    assert!(http_get().unwrap().status().is_success());
+    std::thread::sleep(Duration::from_secs(1));
+    assert!(http_get().unwrap().status().is_success());
+
+    let (exit_code, _, _) = srv.terminate()?.wait(Duration::from_secs(5))?;
+    assert_eq!(exit_code, 0);
+
+    // the requests spinning past the deadline fail, and the server keeps serving the next ones
+    let srv = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(SPIN_HTTP)?
+        .with_annotation("io.runwasi.wasmtime.max-exec-time", "500ms")
+        .with_host_network()
+        .build()?;
+
+    let srv = srv.start()?;
+    for _ in 0..2 {
+        let start = Instant::now();
+        let err = http_get_failure();
+        assert!(!err.is_connect(), "{err}");
+        assert!(start.elapsed() >= Duration::from_millis(500));
+    }
+
+    let (exit_code, _, _) = srv.terminate()?.wait(Duration::from_secs(5))?;
+    assert_eq!(exit_code, 0);
+
+    Ok(())
+}
+
+// Test that the `http` mode serves the component on the socket bound by the shim,
+// and shuts the server down gracefully on SIGTERM.
+#[test]
+#[serial]
+fn test_wasip2_component_http_mode() -> anyhow::Result<()> {
+    let srv = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WASI_HTTP)?
+        .with_host_network()
+        .with_annotation("io.runwasi.mode", "http")
+        .build()?;
+
+    let srv = srv.start()?;
+    assert!(http_get().unwrap().status().is_success());
+
+    let (exit_code, _, _) = srv.terminate()?.wait(Duration::from_secs(5))?;
+    assert_eq!(exit_code, 0);
+
+    Ok(())
+}
+
+// Test that SIGHUP reloads the component of a container with the `io.runwasi.reload-on-sighup`
+// annotation, and that the container keeps serving when the new module fails to load.
+#[test]
+#[serial]
+fn test_reload_on_sighup() -> anyhow::Result<()> {
+    let srv = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WASI_HTTP)?
+        .with_host_network()
+        .with_annotation("io.runwasi.mode", "http")
+        .with_annotation("io.runwasi.reload-on-sighup", "true")
+        .build()?;
+
+    let srv = srv.start()?;
+    assert!(http_get().unwrap().status().is_success());
+    let pid = srv.pid();
+
+    srv.signal(libc::SIGHUP)?;
+    assert!(http_get().unwrap().status().is_success());
+
+    // the previous component keeps serving
+    std::fs::write(
+        srv.root().join("rootfs").join("hello.wasm"),
+        b"\0asm invalid",
+    )?;
+    srv.signal(libc::SIGHUP)?;
+    assert!(http_get().unwrap().status().is_success());
+    assert_eq!(srv.pid(), pid);
+
+    let (exit_code, _, _) = srv.terminate()?.wait(Duration::from_secs(5))?;
+    assert_eq!(exit_code, 0);
+
+    Ok(())
+}
+
+// Test that a module can't be served in the `http` mode.
+#[test]
+#[serial]
+fn test_http_mode_without_handler() -> anyhow::Result<()> {
+    let (exit_code, _, _) = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WORLD)?
+        .with_host_network()
+        .with_annotation("io.runwasi.mode", "http")
+        .build()?
+        .start()?
+        .wait(Duration::from_secs(10))?;
+
+    assert_eq!(exit_code, 137);
+
+    Ok(())
+}
+
+// Test that Update applies the new memory limit to the cgroup of the container.
+#[test]
+#[serial]
+fn test_update_memory_limit() -> anyhow::Result<()> {
+    let srv = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WASI_HTTP)?
+        .with_host_network()
+        .build()?;
+
+    let srv = srv.start()?;
+    assert!(http_get().unwrap().status().is_success());
+
+    let limit: i64 = 256 * 1024 * 1024;
+    let resources = LinuxResourcesBuilder::default()
+        .memory(LinuxMemoryBuilder::default().limit(limit).build()?)
+        .build()?;
+    srv.update(&resources)?;
+
+    let file = if is_cgroup_v2() {
+        "memory.max"
+    } else {
+        "memory.limit_in_bytes"
+    };
+    assert_eq!(srv.read_cgroup(file)?, limit.to_string());
+
+    // the server keeps running with the new limit
+    assert!(http_get().unwrap().status().is_success());
+
+    srv.terminate()?.wait(Duration::from_secs(5))?;
+
+    Ok(())
+}
+
+// Test that the cpuset, the CPU shares, the rlimits and the oom score of the spec apply to the process of the engine.
+#[test]
+#[serial]
+fn test_cpuset_and_process_attributes() -> anyhow::Result<()> {
+    let resources = LinuxResourcesBuilder::default()
+        .cpu(
+            LinuxCpuBuilder::default()
+                .cpus("0")
+                .shares(512u64)
+                .build()?,
+        )
+        .build()?;
+    let nofile = PosixRlimitBuilder::default()
+        .typ(PosixRlimitType::RlimitNofile)
+        .soft(512u64)
+        .hard(512u64)
+        .build()?;
+    let srv = WasiTest::<WasiInstance>::builder()?
+        .with_wasm(HELLO_WASI_HTTP)?
+        .with_host_network()
+        .with_resources(resources)
+        .with_oom_score_adj(500)
+        .with_rlimit(nofile)
+        .build()?;
+
+    let srv = srv.start()?;
+    // the attributes are applied before the first request is served
+    assert!(http_get().unwrap().status().is_success());
+
+    assert_eq!(srv.read_cgroup("cpuset.cpus")?, "0");
+    let pid = srv.pid().expect("the instance is started");
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status"))?;
+    assert!(
+        status.lines().any(|l| l == "Cpus_allowed_list:\t0"),
+        "{status}"
+    );
+    let oom_score_adj = std::fs::read_to_string(format!("/proc/{pid}/oom_score_adj"))?;
+    assert_eq!(oom_score_adj.trim(), "500");
+    let limits = std::fs::read_to_string(format!("/proc/{pid}/limits"))?;
+    let open_files = limits
+        .lines()
+        .find(|l| l.starts_with("Max open files"))
+        .unwrap_or_default();
+    assert_eq!(
+        open_files.split_whitespace().collect::<Vec<_>>(),
+        ["Max", "open", "files", "512", "512", "files"]
+    );
+
+    srv.terminate()?.wait(Duration::from_secs(5))?;
+
+    Ok(())
+}
+
+// Test that a component only connects to the addresses of its socket policy, which the
+// container opts in to, within the policy of the node.
+#[test]
+#[serial]
+fn test_socket_policy() -> anyhow::Result<()> {
+    let addr = http_server()?;
+    let run = |node: SocketPolicy, container: Option<&str>| -> anyhow::Result<(u32, String)> {
+        let mut builder = WasiTest::<WasiInstance>::builder()?
+            .with_wasm(OUTBOUND_HTTP)?
+            .with_env("HTTP_ADDR", addr.to_string())
+            .with_socket_policy(node)
+            .with_host_network();
+        if let Some(policy) = container {
+            builder = builder.with_annotation(SOCKET_POLICY_ANNOTATION, policy);
+        }
+        let (exit_code, stdout, _) = builder.build()?.start()?.wait(Duration::from_secs(10))?;
+        Ok((exit_code, stdout))
+    };
+    let allow = || SocketPolicy {
+        mode: SocketMode::Allow,
+        ..Default::default()
+    };
+
+    let (exit_code, stdout) = run(
+        allow(),
+        Some(r#"{"mode": "allowlist", "allow": ["127.0.0.0/8"]}"#),
+    )?;
+    assert_eq!(stdout.trim(), "HTTP/1.0 200 OK");
+    assert_eq!(exit_code, 0);
+
+    for (node, container) in [
+        // the container didn't opt in
+        (allow(), None),
+        (
+            allow(),
+            Some(r#"{"mode": "allowlist", "allow": ["10.0.0.0/8"]}"#),
+        ),
+        // the node denies the sockets by default
+        (SocketPolicy::default(), Some(r#"{"mode": "allow"}"#)),
+    ] {
+        let (exit_code, stdout) = run(node, container)?;
+        assert_eq!(stdout.trim(), "PermissionDenied", "{container:?}");
+        assert_eq!(exit_code, 1);
+    }
+
+    Ok(())
+}
+
+/// Serve `200 OK` to the requests on a port of the loopback interface
+fn http_server() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(b"HTTP/1.0 200 OK\r\ncontent-length: 2\r\n\r\nok");
+        }
+    });
+    Ok(addr)
+}
+
 fn http_get() -> reqwest::Result<reqwest::blocking::Response> {
     http_get_with_backoff_secs(1)
 }
 
+// Helper method to make a `GET` request that the server fails, once it accepts the connections
+fn http_get_failure() -> reqwest::Error {
+    for _ in 0..100 {
+        match reqwest::blocking::get("http://127.0.0.1:8080") {
+            Err(err) if err.is_connect() => std::thread::sleep(Duration::from_millis(100)),
+            Err(err) => return err,
+            Ok(resp) => panic!("the server responded with {}", resp.status()),
+        }
+    }
+    panic!("the server never accepted the connection");
+}
+
 // Helper method to make a `GET` request
 fn http_get_with_backoff_secs(backoff: u64) -> reqwest::Result<reqwest::blocking::Response> {
     const MAX_ATTEMPTS: u32 = 10;
@@ -344,3 +1251,78 @@ fn http_get_with_backoff_secs(backoff: u64) -> reqwest::Result<reqwest::blocking
         }
     }
 }
+
+#[test]
+fn test_engine_info() {
+    use containerd_shim_wasm::sandbox::Instance as _;
+
+    use crate::instance::WASMTIME_VERSION;
+
+    let info = WasiInstance::engine_info();
+    assert_eq!(info.name, "wasmtime");
+    assert_eq!(info.version.as_deref(), WASMTIME_VERSION);
+    assert!(info.features.iter().any(|f| f == "wasi-preview1"));
+    assert!(info.precompile);
+    assert!(info.media_types.iter().any(|t| t == "application/wasm"));
+}
+
+#[test]
+fn test_wasm_backtrace_of_trap() -> anyhow::Result<()> {
+    use containerd_shim_wasm::container::Engine as _;
+
+    let wat = r#"(module (func $crash (export "_start") unreachable))"#;
+    let engine = wasmtime::Engine::default();
+    let module = wasmtime::Module::new(&engine, wat)?;
+    let mut store = wasmtime::Store::new(&engine, ());
+    let instance = wasmtime::Instance::new(&mut store, &module, &[])?;
+    let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+    let err = start.call(&mut store, ()).unwrap_err();
+
+    let backtrace = WasmtimeEngine
+        .wasm_backtrace(&err)
+        .expect("the wasm backtrace");
+    assert!(backtrace.contains("crash"), "{backtrace}");
+
+    let err = anyhow::anyhow!("not a trap");
+    assert_eq!(WasmtimeEngine.wasm_backtrace(&err), None);
+    Ok(())
+}
+
+#[test]
+fn test_precompile_modules_and_components() -> anyhow::Result<()> {
+    use containerd_shim_wasm::container::Engine as _;
+    use containerd_shim_wasm::sandbox::WasmLayer;
+    use oci_spec::image::{Descriptor, Digest, MediaType};
+    use wasmtime::Precompiled;
+
+    let layer = |bytes: &[u8]| -> anyhow::Result<WasmLayer> {
+        Ok(WasmLayer {
+            layer: bytes.to_vec(),
+            path: None,
+            config: Descriptor::new(
+                MediaType::Other("application/wasm".to_string()),
+                bytes.len() as u64,
+                Digest::try_from(format!("sha256:{}", "0".repeat(64)))?,
+            ),
+        })
+    };
+
+    let compiled = WasmtimeEngine.precompile(&[
+        layer(HELLO_WORLD.bytes)?,
+        layer(COMPONENT_HELLO_WORLD.bytes)?,
+    ])?;
+    let engine = wasmtime::Engine::default();
+    let kinds: Vec<_> = compiled
+        .iter()
+        .map(|compiled| engine.detect_precompiled(compiled.as_deref()?))
+        .collect();
+    assert_eq!(
+        kinds,
+        [Some(Precompiled::Module), Some(Precompiled::Component)]
+    );
+
+    // a precompiled layer isn't compiled again
+    let component = compiled[1].as_deref().unwrap();
+    assert_eq!(WasmtimeEngine.precompile(&[layer(component)?])?, [None]);
+    Ok(())
+}
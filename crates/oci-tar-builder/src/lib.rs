@@ -7,8 +7,8 @@ use anyhow::{Context, Error, Result};
 use indexmap::IndexMap;
 use log::{debug, warn};
 use oci_spec::image::{
-    DescriptorBuilder, Digest, ImageConfiguration, ImageIndexBuilder, ImageManifestBuilder,
-    MediaType, PlatformBuilder, SCHEMA_VERSION,
+    ANNOTATION_TITLE, DescriptorBuilder, Digest, ImageConfiguration, ImageIndexBuilder,
+    ImageManifestBuilder, MediaType, PlatformBuilder, SCHEMA_VERSION,
 };
 use oci_wasm::{WASM_ARCHITECTURE, WasmConfig};
 use serde::Serialize;
@@ -16,7 +16,7 @@ use sha256::{digest, try_digest};
 #[derive(Debug)]
 pub struct Builder<C: OciConfig> {
     configs: Vec<(C, String, MediaType)>,
-    layers: Vec<(PathBuf, String)>,
+    layers: Vec<(PathBuf, String, Option<String>)>,
 }
 
 pub trait OciConfig {
@@ -114,12 +114,25 @@ impl<C: OciConfig> Builder<C> {
     }
 
     pub fn add_layer(&mut self, layer: &PathBuf) -> &mut Self {
-        self.layers.push((layer.to_owned(), "".to_string()));
+        self.layers.push((layer.to_owned(), "".to_string(), None));
         self
     }
 
     pub fn add_layer_with_media_type(&mut self, layer: &PathBuf, media_type: String) -> &mut Self {
-        self.layers.push((layer.to_owned(), media_type));
+        self.layers.push((layer.to_owned(), media_type, None));
+        self
+    }
+
+    /// Add a layer with a `org.opencontainers.image.title` annotation,
+    /// e.g., the name of a module to link the other layers with.
+    pub fn add_layer_with_title(
+        &mut self,
+        layer: &PathBuf,
+        media_type: String,
+        title: String,
+    ) -> &mut Self {
+        self.layers
+            .push((layer.to_owned(), media_type, Some(title)));
         self
     }
 
@@ -147,13 +160,19 @@ impl<C: OciConfig> Builder<C> {
             if !layer.1.is_empty() {
                 media_type = MediaType::Other(layer.1.clone());
             }
-            let desc = DescriptorBuilder::default()
+            let mut desc = DescriptorBuilder::default()
                 // TODO: check file headers to determine mediatype? Could also just require it to be passed in on add_layer
                 .media_type(media_type)
                 .digest(Digest::try_from(format!("sha256:{dgst}"))?)
                 .size(meta.len())
                 .build()
                 .context("failed to build descriptor")?;
+            if let Some(title) = &layer.2 {
+                desc.set_annotations(Some(HashMap::from([(
+                    ANNOTATION_TITLE.to_string(),
+                    title.clone(),
+                )])));
+            }
             layer_digests.insert(format!("sha256:{dgst}"), desc);
 
             let mut th = tar::Header::new_gnu();
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{Context as _, Result, ensure};
+use serde::Deserialize;
+use tokio::time::Duration;
+
+use crate::term::outln;
+
+/// The figures of a run that are compared against a baseline.
+pub struct Baseline {
+    pub count: usize,
+    pub throughput: f64,
+    /// p99 of the create step plus p99 of the start step
+    pub startup_p99: Duration,
+}
+
+/// The subset of the JSON report needed to load a baseline.
+#[derive(Deserialize)]
+struct Report {
+    throughput: Option<f64>,
+    parameters: Parameters,
+    latencies: HashMap<String, Percentiles>,
+}
+
+#[derive(Deserialize)]
+struct Parameters {
+    count: usize,
+}
+
+#[derive(Deserialize)]
+struct Percentiles {
+    p99_ns: u64,
+}
+
+impl Baseline {
+    /// Load a baseline from a JSON report written by a previous run.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("failed to open baseline {}", path.display()))?;
+        let report: Report = serde_json::from_reader(file)
+            .with_context(|| format!("failed to parse baseline {}", path.display()))?;
+        let p99 = |step: &str| {
+            report
+                .latencies
+                .get(step)
+                .map(|p| Duration::from_nanos(p.p99_ns))
+                .with_context(|| format!("baseline has no {step} latencies"))
+        };
+        Ok(Self {
+            count: report.parameters.count,
+            throughput: report.throughput.context("baseline has no throughput")?,
+            startup_p99: p99("create")? + p99("start")?,
+        })
+    }
+
+    /// Small runs are noisy, so only runs with the same number of tasks are compared.
+    pub fn check_count(&self, count: usize) -> Result<()> {
+        ensure!(
+            self.count == count,
+            "the baseline ran {} tasks but this run has --count {count}, results are not comparable",
+            self.count,
+        );
+        Ok(())
+    }
+
+    /// Fail if `current` regressed by more than `max_regression` percent.
+    pub fn compare(&self, current: &Baseline, max_regression: f64, text: bool) -> Result<()> {
+        self.check_count(current.count)?;
+
+        let throughput = (self.throughput - current.throughput) / self.throughput * 100.0;
+        let base = self.startup_p99.as_secs_f64();
+        let startup = (current.startup_p99.as_secs_f64() - base) / base * 100.0;
+
+        if text {
+            outln!(
+                "\x1b[1m{:<22}  {:>14}  {:>14}  {:>11}\x1b[0m",
+                "",
+                "baseline",
+                "current",
+                "regression"
+            );
+            outln!(
+                "{:<22}  {:>14.2}  {:>14.2}  {:>10.1}%",
+                "throughput (tasks/s)",
+                self.throughput,
+                current.throughput,
+                throughput
+            );
+            outln!(
+                "{:<22}  {:>14}  {:>14}  {:>10.1}%",
+                "create+start p99",
+                format!("{:.2?}", self.startup_p99),
+                format!("{:.2?}", current.startup_p99),
+                startup
+            );
+        }
+
+        let mut regressions = vec![];
+        if throughput > max_regression {
+            regressions.push(format!("throughput regressed by {throughput:.1}%"));
+        }
+        if startup > max_regression {
+            regressions.push(format!("create+start p99 regressed by {startup:.1}%"));
+        }
+        if !regressions.is_empty() {
+            let regressions = regressions.join(", ");
+            return Err(
+                Regression(format!("{regressions} (max allowed {max_regression}%)")).into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Error of a run that regressed from its baseline by more than allowed.
+#[derive(Debug)]
+pub struct Regression(String);
+
+impl std::fmt::Display for Regression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Regression {}
@@ -5,7 +5,7 @@ use std::path::Path;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
-use anyhow::{Context as _, Result, bail};
+use anyhow::{Context as _, Result, bail, ensure};
 use containerd_client::services::v1::container::Runtime;
 use containerd_client::services::v1::containers_client::ContainersClient;
 use containerd_client::services::v1::content_client::ContentClient;
@@ -16,17 +16,20 @@ use containerd_client::services::v1::snapshots::snapshots_client::SnapshotsClien
 use containerd_client::services::v1::tasks_client::TasksClient;
 use containerd_client::services::v1::{
     Container as SpecContainer, CreateContainerRequest, CreateRequest, CreateTaskRequest,
-    DeleteContainerRequest, DeleteRequest, DeleteTaskRequest, GetImageRequest, KillRequest,
-    ReadContentRequest, StartRequest, WaitRequest,
+    DeleteContainerRequest, DeleteProcessRequest, DeleteRequest, DeleteTaskRequest,
+    ExecProcessRequest, GetImageRequest, GetRequest, KillRequest, MetricsRequest, PauseTaskRequest,
+    ReadContentRequest, ResumeTaskRequest, StartRequest, WaitRequest,
 };
 use containerd_client::types::Mount;
 use humantime::format_rfc3339;
 use oci_spec::image::{Arch, ImageConfiguration, ImageIndex, ImageManifest};
-use oci_spec::runtime::Spec;
+use oci_spec::runtime::{Process, Spec};
 use prost_types::Any;
 use tokio_async_drop::tokio_async_drop as async_drop;
-use tonic::Request;
 use tonic::transport::Channel;
+use tonic::{Code, Request};
+
+use crate::traits::{State, Unimplemented};
 
 struct ClientInner {
     channel: Channel,
@@ -210,6 +213,7 @@ impl ClientInner {
         &self,
         container_id: impl Into<String>,
         mounts: impl Into<Vec<Mount>>,
+        stdin: impl Into<String>,
         stdout: impl Into<String>,
         stderr: impl Into<String>,
     ) -> Result<()> {
@@ -218,6 +222,7 @@ impl ClientInner {
         let request = CreateTaskRequest {
             container_id: container_id.into(),
             rootfs: mounts.into(),
+            stdin: stdin.into(),
             stdout: stdout.into(),
             stderr: stderr.into(),
             ..Default::default()
@@ -257,12 +262,132 @@ impl ClientInner {
         Ok(status)
     }
 
-    async fn kill_task(&self, container_id: impl Into<String>) -> Result<()> {
+    async fn exec_process(
+        &self,
+        container_id: impl Into<String>,
+        exec_id: impl Into<String>,
+        process: Process,
+        stdout: impl Into<String>,
+        stderr: impl Into<String>,
+    ) -> Result<()> {
+        let mut client = TasksClient::new(self.channel.clone());
+
+        let spec = Any {
+            type_url: "types.containerd.io/opencontainers/runtime-spec/1/Process".to_string(),
+            value: serde_json::to_vec(&process)?,
+        };
+
+        let request = ExecProcessRequest {
+            container_id: container_id.into(),
+            exec_id: exec_id.into(),
+            stdout: stdout.into(),
+            stderr: stderr.into(),
+            spec: Some(spec),
+            ..Default::default()
+        };
+        let request = self.with_metadata(request);
+
+        client.exec(request).await?;
+
+        Ok(())
+    }
+
+    async fn start_process(
+        &self,
+        container_id: impl Into<String>,
+        exec_id: impl Into<String>,
+    ) -> Result<()> {
+        let mut client = TasksClient::new(self.channel.clone());
+
+        let request = StartRequest {
+            container_id: container_id.into(),
+            exec_id: exec_id.into(),
+        };
+        let request = self.with_metadata(request);
+
+        client.start(request).await?;
+
+        Ok(())
+    }
+
+    async fn wait_process(
+        &self,
+        container_id: impl Into<String>,
+        exec_id: impl Into<String>,
+    ) -> Result<u32> {
+        let mut client = TasksClient::new(self.channel.clone());
+
+        let request = WaitRequest {
+            container_id: container_id.into(),
+            exec_id: exec_id.into(),
+        };
+        let request = self.with_metadata(request);
+
+        let response = client.wait(request).await?;
+        let status = response.into_inner().exit_status;
+        Ok(status)
+    }
+
+    async fn delete_process(
+        &self,
+        container_id: impl Into<String>,
+        exec_id: impl Into<String>,
+    ) -> Result<()> {
+        let mut client = TasksClient::new(self.channel.clone());
+
+        let request = DeleteProcessRequest {
+            container_id: container_id.into(),
+            exec_id: exec_id.into(),
+        };
+        let request = self.with_metadata(request);
+
+        client.delete_process(request).await?;
+
+        Ok(())
+    }
+
+    async fn task_metrics(&self, container_id: impl Into<String>) -> Result<()> {
+        let mut client = TasksClient::new(self.channel.clone());
+
+        let container_id = container_id.into();
+        let request = MetricsRequest {
+            filters: vec![format!("id=={container_id}")],
+        };
+        let request = self.with_metadata(request);
+
+        let response = client.metrics(request).await?.into_inner();
+        let metric = response
+            .metrics
+            .into_iter()
+            .find(|m| m.id == container_id)
+            .context("metrics response has no entry for the task")?;
+        ensure!(metric.data.is_some(), "metrics response has no data");
+        Ok(())
+    }
+
+    async fn get_task(&self, container_id: impl Into<String>) -> Result<State> {
+        let mut client = TasksClient::new(self.channel.clone());
+
+        let request = GetRequest {
+            container_id: container_id.into(),
+            ..Default::default()
+        };
+        let request = self.with_metadata(request);
+
+        let response = client.get(request).await?.into_inner();
+        let process = response.process.context("task not found")?;
+        Ok(State {
+            status: process.status.into(),
+            exit_status: process.exit_status,
+        })
+    }
+
+    async fn kill_task(&self, container_id: impl Into<String>, signal: u32) -> Result<()> {
         let mut client = TasksClient::new(self.channel.clone());
 
         let request = KillRequest {
             container_id: container_id.into(),
-            signal: 9, // SIGKILL
+            signal,
             all: true,
             ..Default::default()
         };
@@ -273,6 +398,36 @@ impl ClientInner {
         Ok(())
     }
 
+    async fn pause_task(&self, container_id: impl Into<String>) -> Result<()> {
+        let mut client = TasksClient::new(self.channel.clone());
+
+        let request = PauseTaskRequest {
+            container_id: container_id.into(),
+        };
+        let request = self.with_metadata(request);
+
+        match client.pause(request).await {
+            Err(status) if status.code() == Code::Unimplemented => Err(Unimplemented.into()),
+            res => {
+                res?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn resume_task(&self, container_id: impl Into<String>) -> Result<()> {
+        let mut client = TasksClient::new(self.channel.clone());
+
+        let request = ResumeTaskRequest {
+            container_id: container_id.into(),
+        };
+        let request = self.with_metadata(request);
+
+        client.resume(request).await?;
+
+        Ok(())
+    }
+
     async fn delete_task(&self, container_id: impl Into<String>) -> Result<()> {
         let mut client = TasksClient::new(self.channel.clone());
 
@@ -307,10 +462,6 @@ impl Drop for ClientInner {
 }
 
 impl Client {
-    pub async fn default() -> Result<Self> {
-        Self::connect("/run/containerd/containerd.sock", "default").await
-    }
-
     pub async fn connect(socket: impl AsRef<Path>, namespace: impl Into<String>) -> Result<Self> {
         let inner = ClientInner::connect(socket, namespace)
             .await?
@@ -320,6 +471,10 @@ impl Client {
         Ok(Self(Arc::new(inner)))
     }
 
+    pub fn namespace(&self) -> &str {
+        &self.0.namespace
+    }
+
     pub async fn get_mounts(
         &self,
         id: impl Into<String>,
@@ -332,6 +487,12 @@ impl Client {
         Ok(mounts)
     }
 
+    /// Make sure that the image is available, loading its configuration.
+    pub async fn load_image(&self, image: impl Into<String>) -> Result<()> {
+        self.0.image_config(image.into()).await?;
+        Ok(())
+    }
+
     pub async fn entrypoint(&self, image: impl Into<String>) -> Result<Vec<String>> {
         let config = self.0.image_config(image.into()).await?;
         let Some(config) = config.config() else {
@@ -357,11 +518,12 @@ impl Client {
         &self,
         container_id: impl Into<String>,
         mounts: impl Into<Vec<Mount>>,
+        stdin: impl Into<String>,
         stdout: impl Into<String>,
         stderr: impl Into<String>,
     ) -> Result<()> {
         self.0
-            .create_task(container_id, mounts, stdout, stderr)
+            .create_task(container_id, mounts, stdin, stdout, stderr)
             .await
     }
 
@@ -373,8 +535,61 @@ impl Client {
         self.0.wait_task(container_id).await
     }
 
-    pub async fn kill_task(&self, container_id: impl Into<String>) -> Result<()> {
-        self.0.kill_task(container_id).await
+    pub async fn exec_process(
+        &self,
+        container_id: impl Into<String>,
+        exec_id: impl Into<String>,
+        process: Process,
+        stdout: impl Into<String>,
+        stderr: impl Into<String>,
+    ) -> Result<()> {
+        self.0
+            .exec_process(container_id, exec_id, process, stdout, stderr)
+            .await
+    }
+
+    pub async fn start_process(
+        &self,
+        container_id: impl Into<String>,
+        exec_id: impl Into<String>,
+    ) -> Result<()> {
+        self.0.start_process(container_id, exec_id).await
+    }
+
+    pub async fn wait_process(
+        &self,
+        container_id: impl Into<String>,
+        exec_id: impl Into<String>,
+    ) -> Result<u32> {
+        self.0.wait_process(container_id, exec_id).await
+    }
+
+    pub async fn delete_process(
+        &self,
+        container_id: impl Into<String>,
+        exec_id: impl Into<String>,
+    ) -> Result<()> {
+        self.0.delete_process(container_id, exec_id).await
+    }
+
+    pub async fn task_metrics(&self, container_id: impl Into<String>) -> Result<()> {
+        self.0.task_metrics(container_id).await
+    }
+
+    pub async fn get_task(&self, container_id: impl Into<String>) -> Result<State> {
+        self.0.get_task(container_id).await
+    }
+
+    pub async fn kill_task(&self, container_id: impl Into<String>, signal: u32) -> Result<()> {
+        self.0.kill_task(container_id, signal).await
+    }
+
+    pub async fn pause_task(&self, container_id: impl Into<String>) -> Result<()> {
+        self.0.pause_task(container_id).await
+    }
+
+    pub async fn resume_task(&self, container_id: impl Into<String>) -> Result<()> {
+        self.0.resume_task(container_id).await
     }
 
     pub async fn delete_task(&self, container_id: impl Into<String>) -> Result<()> {
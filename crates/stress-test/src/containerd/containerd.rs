@@ -3,6 +3,8 @@ use std::path::Path;
 use anyhow::Result;
 
 use super::{Client, Shim};
+use crate::stats::RpcLatencies;
+use crate::traits::Faults;
 
 pub struct Containerd {
     containerd: Client,
@@ -19,4 +21,12 @@ impl crate::traits::Containerd for Containerd {
     async fn start_shim(&self, shim: impl AsRef<Path> + Send) -> Result<Shim> {
         Shim::new(self.containerd.clone(), shim).await
     }
+
+    fn faults(&self) -> Faults {
+        Faults::default()
+    }
+
+    fn rpc_latencies(&self) -> Option<RpcLatencies> {
+        None
+    }
 }
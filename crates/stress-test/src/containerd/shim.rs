@@ -1,15 +1,20 @@
 use std::path::{Path, PathBuf};
 
 use anyhow::Result;
-use tokio::fs::{remove_file, symlink};
+use tokio::fs::remove_file;
+use tokio::time::Duration;
 use tokio_async_drop::tokio_async_drop;
 
 use super::{Client, Task};
 use crate::containerd;
+use crate::sys::symlink;
+use crate::traits::{Limits, Source};
+use crate::utils::make_shim_index;
 
 pub struct Shim {
     link: PathBuf,
     runtime: String,
+    sandbox_id: String,
     containerd: Client,
 }
 
@@ -19,15 +24,20 @@ impl Shim {
         binary: impl AsRef<Path>,
     ) -> Result<Self> {
         let pid = std::process::id();
-        let runtime = format!("io.containerd.runwasi{pid}.v1");
-        let link = format!("/usr/local/bin/containerd-shim-runwasi{pid}-v1");
+        // containerd starts one shim per sandbox, and each shim instance needs
+        // its own runtime link as the link is removed when the instance is dropped
+        let index = make_shim_index();
+        let runtime = format!("io.containerd.runwasi{pid}-{index}.v1");
+        let link = format!("/usr/local/bin/containerd-shim-runwasi{pid}-{index}-v1");
         let link = PathBuf::from(link);
-        symlink(binary.as_ref().canonicalize()?, &link).await?;
+        symlink(&binary.as_ref().canonicalize()?, &link)?;
+        let sandbox_id = format!("sandbox-{pid}-{index}");
 
         Ok(Self {
             containerd,
             link,
             runtime,
+            sandbox_id,
         })
     }
 }
@@ -45,9 +55,36 @@ impl crate::traits::Shim for Shim {
 
     async fn task<T: Into<String>>(
         &self,
-        image: impl Into<String>,
+        source: Source,
         args: impl IntoIterator<Item = T>,
+        env: &[String],
+        limits: Limits,
+        stdin: bool,
     ) -> Result<Task> {
-        Task::new(self.containerd.clone(), &self.runtime, image, args).await
+        Task::new(
+            self.containerd.clone(),
+            &self.runtime,
+            source,
+            args,
+            env,
+            limits,
+            stdin,
+            &self.sandbox_id,
+        )
+        .await
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        // containerd shuts the shim down by itself once its last task is deleted
+        Ok(())
+    }
+
+    fn startup(&self) -> Option<Duration> {
+        // containerd spawns the shim when creating the first task
+        None
+    }
+
+    async fn alive(&self) -> Option<bool> {
+        None
     }
 }
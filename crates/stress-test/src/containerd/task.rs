@@ -1,12 +1,13 @@
-use anyhow::{Result, ensure};
+use anyhow::{Context as _, Result};
 use containerd_client::types::Mount;
 use oci_spec::runtime::{ProcessBuilder, RootBuilder, Spec, SpecBuilder, UserBuilder};
 use tempfile::{TempDir, tempdir};
 use tokio_async_drop::tokio_async_drop;
 
 use super::Client;
-use crate::traits::Task as _;
-use crate::utils::{RunOnce, make_task_id};
+use crate::sys::StdinPipe;
+use crate::traits::{Exit, Limits, Source, State, Task as _};
+use crate::utils::{RunOnce, copy_bundle, make_exec_id, make_task_id};
 
 pub struct Task {
     containerd: Client,
@@ -15,45 +16,79 @@ pub struct Task {
     image: String,
     mounts: Vec<Mount>,
     spec: Spec,
+    stdin: Option<StdinPipe>,
     task_deleted: RunOnce,
     container_deleted: RunOnce,
     dir: TempDir,
 }
 
 impl Task {
+    #[allow(clippy::too_many_arguments)]
     pub(super) async fn new<T: Into<String>>(
         containerd: Client,
         runtime: impl Into<String>,
-        image: impl Into<String>,
+        source: Source,
         args: impl IntoIterator<Item = T>,
+        env: &[String],
+        limits: Limits,
+        stdin: bool,
+        sandbox_id: &str,
     ) -> Result<Self> {
-        let image = image.into();
         let runtime = runtime.into();
 
         let id = make_task_id();
-        let mounts = containerd.get_mounts(&id, &image).await?;
+        let dir = tempdir()?;
 
         let args: Vec<_> = args.into_iter().map(|arg| arg.into()).collect();
 
-        let process = ProcessBuilder::default()
-            .user(UserBuilder::default().build().unwrap())
-            .args(args)
-            .cwd("/")
-            .build()?;
+        let (image, mut spec, mounts) = match source {
+            Source::Image(image) => {
+                let mounts = containerd.get_mounts(&id, &image).await?;
 
-        let annotations = [(
-            "io.kubernetes.cri.sandbox-id".to_string(),
-            format!("sandbox-{}", std::process::id()),
-        )];
+                let process = ProcessBuilder::default()
+                    .user(UserBuilder::default().build().unwrap())
+                    .args(args)
+                    .env(env.to_vec())
+                    .cwd("/")
+                    .build()?;
 
-        let root = RootBuilder::default().path("rootfs").build()?;
+                let annotations = [(
+                    "io.kubernetes.cri.sandbox-id".to_string(),
+                    sandbox_id.to_string(),
+                )];
 
-        let spec = SpecBuilder::default()
-            .version("1.1.0")
-            .process(process)
-            .annotations(annotations)
-            .root(root)
-            .build()?;
+                let root = RootBuilder::default().path("rootfs").build()?;
+
+                let spec = SpecBuilder::default()
+                    .version("1.1.0")
+                    .process(process)
+                    .annotations(annotations)
+                    .root(root)
+                    .build()?;
+
+                (image, spec, mounts)
+            }
+            Source::Bundle(bundle) => {
+                let mut spec = copy_bundle(&bundle, dir.path(), args, env).await?;
+                spec.annotations_mut().get_or_insert_default().insert(
+                    "io.kubernetes.cri.sandbox-id".to_string(),
+                    sandbox_id.to_string(),
+                );
+
+                // bind mount the copy of the bundle's rootfs in place of a snapshot
+                let rootfs = dir.path().join("rootfs");
+                let mounts = vec![Mount {
+                    r#type: "bind".into(),
+                    source: rootfs.to_string_lossy().into_owned(),
+                    options: vec!["rbind".into(), "rw".into()],
+                    ..Default::default()
+                }];
+
+                (String::new(), spec, mounts)
+            }
+        };
+
+        limits.apply(&mut spec)?;
 
         Ok(Self {
             containerd,
@@ -62,14 +97,19 @@ impl Task {
             image,
             mounts,
             spec,
+            stdin: stdin.then(StdinPipe::default),
             task_deleted: RunOnce::new(),
             container_deleted: RunOnce::new(),
-            dir: tempdir()?,
+            dir,
         })
     }
 }
 
 impl crate::traits::Task for Task {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
     async fn create(&self) -> Result<()> {
         let stdout = self.dir.path().join("stdout");
         let stderr = self.dir.path().join("stderr");
@@ -77,6 +117,10 @@ impl crate::traits::Task for Task {
         let _ = std::fs::write(&stdout, "");
         let _ = std::fs::write(&stderr, "");
 
+        let stdin = match &self.stdin {
+            Some(pipe) => pipe.open(self.dir.path())?,
+            None => String::new(),
+        };
         let stdout = stdout.to_string_lossy().into_owned();
         let stderr = stderr.to_string_lossy().into_owned();
 
@@ -85,7 +129,7 @@ impl crate::traits::Task for Task {
             .await?;
 
         self.containerd
-            .create_task(&self.id, &self.mounts[..], stdout, stderr)
+            .create_task(&self.id, &self.mounts[..], stdin, stdout, stderr)
             .await?;
 
         Ok(())
@@ -95,22 +139,22 @@ impl crate::traits::Task for Task {
         self.containerd.start_task(&self.id).await
     }
 
-    async fn wait(&self) -> Result<()> {
+    async fn wait(&self) -> Result<Exit> {
         let status = self.containerd.wait_task(&self.id).await?;
         let stdout = std::fs::read_to_string(self.dir.path().join("stdout")).unwrap_or_default();
         let stderr = std::fs::read_to_string(self.dir.path().join("stderr")).unwrap_or_default();
-        ensure!(
-            status == 0,
-            "Exit status {status}, stdout: {stdout:?}, stderr: {stderr:?}"
-        );
-        Ok(())
+        Ok(Exit {
+            status,
+            stdout,
+            stderr,
+        })
     }
 
     async fn delete(&self) -> Result<()> {
         let res1 = self
             .task_deleted
             .try_run(async {
-                let _ = self.containerd.kill_task(&self.id).await;
+                let _ = self.containerd.kill_task(&self.id, 9).await; // SIGKILL
                 self.containerd.delete_task(&self.id).await?;
                 Ok(())
             })
@@ -124,6 +168,88 @@ impl crate::traits::Task for Task {
             .await;
         res1.and(res2)
     }
+
+    async fn reset(&self) {
+        self.task_deleted.reset().await;
+        self.container_deleted.reset().await;
+    }
+
+    async fn exec(&self, args: &[String]) -> Result<Exit> {
+        let exec_id = make_exec_id();
+        let stdout = self.dir.path().join(format!("{exec_id}-stdout"));
+        let stderr = self.dir.path().join(format!("{exec_id}-stderr"));
+
+        let _ = std::fs::write(&stdout, "");
+        let _ = std::fs::write(&stderr, "");
+
+        let process = ProcessBuilder::default()
+            .user(UserBuilder::default().build().unwrap())
+            .args(args.to_vec())
+            .cwd("/")
+            .build()?;
+
+        self.containerd
+            .exec_process(
+                &self.id,
+                &exec_id,
+                process,
+                stdout.to_string_lossy(),
+                stderr.to_string_lossy(),
+            )
+            .await?;
+        self.containerd.start_process(&self.id, &exec_id).await?;
+        let status = self.containerd.wait_process(&self.id, &exec_id).await?;
+        self.containerd.delete_process(&self.id, &exec_id).await?;
+
+        let stdout = std::fs::read_to_string(stdout).unwrap_or_default();
+        let stderr = std::fs::read_to_string(stderr).unwrap_or_default();
+        Ok(Exit {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+
+    async fn kill(&self, signal: u32) -> Result<()> {
+        self.containerd.kill_task(&self.id, signal).await
+    }
+
+    async fn stats(&self) -> Result<()> {
+        self.containerd.task_metrics(&self.id).await
+    }
+
+    async fn pause(&self) -> Result<()> {
+        self.containerd.pause_task(&self.id).await
+    }
+
+    async fn resume(&self) -> Result<()> {
+        self.containerd.resume_task(&self.id).await
+    }
+
+    async fn write_stdin(&self, data: &[u8]) -> Result<()> {
+        self.stdin
+            .as_ref()
+            .context("the task has no stdin")?
+            .write(data)
+            .await
+    }
+
+    async fn shim_pid(&self) -> Result<u32> {
+        // containerd keeps the pid of the shim in the task's state dir
+        let path = format!(
+            "/run/containerd/io.containerd.runtime.v2.task/{}/{}/shim.pid",
+            self.containerd.namespace(),
+            self.id
+        );
+        let pid = tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("failed to read {path}"))?;
+        Ok(pid.trim().parse()?)
+    }
+
+    async fn state(&self) -> Result<State> {
+        self.containerd.get_task(&self.id).await
+    }
 }
 
 impl Drop for Task {
@@ -0,0 +1,75 @@
+use std::fs::File;
+use std::io::{BufWriter, Write as _};
+use std::path::Path;
+
+use anyhow::Result;
+use clap::ValueEnum as _;
+
+use crate::Step;
+use crate::stats::TaskRecord;
+
+/// Writes one row per task as soon as the task completes, so that
+/// an interrupted run still leaves the partial data behind.
+pub struct CsvWriter(BufWriter<File>);
+
+impl CsvWriter {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write!(writer, "task,image")?;
+        for step in Step::value_variants() {
+            write!(writer, ",{}_us", step.name())?;
+        }
+        writeln!(writer, ",exit_status,concurrency,shim_rss_bytes,error")?;
+        writer.flush()?;
+        Ok(Self(writer))
+    }
+
+    pub fn write(&mut self, record: &TaskRecord, res: &Result<()>) -> Result<()> {
+        let writer = &mut self.0;
+        write!(writer, "{},{}", record.index, escape(record.image.clone()))?;
+        for step in Step::value_variants() {
+            match record.timings.get(*step) {
+                Some(duration) => write!(writer, ",{}", duration.as_micros())?,
+                None => write!(writer, ",")?,
+            }
+        }
+        match record.exit_status {
+            Some(status) => write!(writer, ",{status}")?,
+            None => write!(writer, ",")?,
+        }
+        match record.concurrency {
+            Some(concurrency) => write!(writer, ",{concurrency}")?,
+            None => write!(writer, ",")?,
+        }
+        match record.shim_rss_bytes {
+            Some(rss) => write!(writer, ",{rss}")?,
+            None => write!(writer, ",")?,
+        }
+        match res {
+            Ok(()) => writeln!(writer, ",")?,
+            Err(err) => writeln!(writer, ",{}", escape(format!("{err:#}")))?,
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn escape(field: String) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::escape;
+
+    #[test]
+    fn escape_fields() {
+        assert_eq!(escape("plain".into()), "plain");
+        assert_eq!(escape("a, b".into()), "\"a, b\"");
+        assert_eq!(escape("say \"hi\"".into()), "\"say \"\"hi\"\"\"");
+    }
+}
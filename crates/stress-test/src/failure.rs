@@ -0,0 +1,177 @@
+use std::fmt;
+
+use anyhow::Error;
+use serde::Serialize;
+
+use crate::baseline::Regression;
+use crate::leak::Leak;
+use crate::traits::UnexpectedExit;
+use crate::wave::{OomKilled, StepTimeout, UnexpectedState};
+
+/// Exit code of a run that couldn't be set up or broke down, as opposed to failing tasks
+const HARNESS_EXIT_CODE: u8 = 4;
+/// Exit code of a run whose tasks succeeded but regressed from the baseline
+const REGRESSION_EXIT_CODE: u8 = 1;
+/// Exit code of a run whose shims grew by more than --leak-threshold
+const LEAK_EXIT_CODE: u8 = 6;
+
+/// What made a task fail, from the most specific cause in its error chain.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Category {
+    /// A step exceeded --task-timeout, or the run timed out before the task finished
+    Timeout,
+    /// A call to the shim or to containerd failed, e.g. because the shim crashed
+    Rpc,
+    /// The task or one of its execs exited with an unexpected status
+    Exit,
+    /// The task was in an unexpected state
+    State,
+    /// Anything else, like a failure to prepare the task's bundle
+    Other,
+}
+
+impl Category {
+    pub fn of(err: &Error) -> Self {
+        if err.is::<StepTimeout>() {
+            Category::Timeout
+        } else if err.is::<UnexpectedExit>() || err.is::<OomKilled>() {
+            Category::Exit
+        } else if err.is::<UnexpectedState>() {
+            Category::State
+        } else if err
+            .chain()
+            .any(|cause| cause.is::<trapeze::Status>() || cause.is::<tonic::Status>())
+        {
+            Category::Rpc
+        } else {
+            Category::Other
+        }
+    }
+
+    /// Exit code of the process when this is the dominant cause of failure
+    fn exit_code(self) -> u8 {
+        match self {
+            Category::Exit | Category::State | Category::Other => 2,
+            Category::Timeout => 3,
+            Category::Rpc => 5,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Category::Timeout => "timed out",
+            Category::Rpc => "RPC errors",
+            Category::Exit => "unexpected exits",
+            Category::State => "unexpected states",
+            Category::Other => "other errors",
+        }
+    }
+}
+
+/// Number of failed tasks in each category.
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct Failures {
+    pub timeout: usize,
+    pub rpc: usize,
+    pub exit: usize,
+    pub state: usize,
+    pub other: usize,
+}
+
+impl Failures {
+    pub fn add(&mut self, category: Category) {
+        *self.get_mut(category) += 1;
+    }
+
+    /// The most frequent category, or `None` if no task failed.
+    /// Ties go to the category listed first in `Category`.
+    pub fn dominant(&self) -> Option<Category> {
+        self.iter()
+            .filter(|(_, count)| *count > 0)
+            .fold(None, |dominant, (category, count)| match dominant {
+                Some((_, max)) if max >= count => dominant,
+                _ => Some((category, count)),
+            })
+            .map(|(category, _)| category)
+    }
+
+    /// The categories with their counts, skipping the empty ones
+    pub fn summary(&self) -> String {
+        let counts: Vec<_> = self
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(category, count)| format!("{count} {}", category.name()))
+            .collect();
+        counts.join(", ")
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Category, usize)> {
+        [
+            (Category::Timeout, self.timeout),
+            (Category::Rpc, self.rpc),
+            (Category::Exit, self.exit),
+            (Category::State, self.state),
+            (Category::Other, self.other),
+        ]
+        .into_iter()
+    }
+
+    fn get_mut(&mut self, category: Category) -> &mut usize {
+        match category {
+            Category::Timeout => &mut self.timeout,
+            Category::Rpc => &mut self.rpc,
+            Category::Exit => &mut self.exit,
+            Category::State => &mut self.state,
+            Category::Other => &mut self.other,
+        }
+    }
+}
+
+/// Error of a run with failed tasks, carrying the category that sets the exit code.
+#[derive(Debug)]
+pub struct TasksFailed {
+    pub category: Category,
+    pub message: String,
+}
+
+impl fmt::Display for TasksFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for TasksFailed {}
+
+/// Exit code of the process for the error that ended the run
+pub fn exit_code(err: &Error) -> u8 {
+    if let Some(failed) = err.downcast_ref::<TasksFailed>() {
+        failed.category.exit_code()
+    } else if err.is::<Regression>() {
+        REGRESSION_EXIT_CODE
+    } else if err.is::<Leak>() {
+        LEAK_EXIT_CODE
+    } else {
+        HARNESS_EXIT_CODE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Category, Failures};
+
+    #[test]
+    fn dominant_category() {
+        let mut failures = Failures::default();
+        assert_eq!(failures.dominant(), None);
+
+        failures.add(Category::Exit);
+        failures.add(Category::Exit);
+        failures.add(Category::Rpc);
+        assert_eq!(failures.dominant(), Some(Category::Exit));
+
+        // ties go to the first category
+        failures.add(Category::Rpc);
+        assert_eq!(failures.dominant(), Some(Category::Rpc));
+        assert_eq!(failures.summary(), "2 RPC errors, 2 unexpected exits");
+    }
+}
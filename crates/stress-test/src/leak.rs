@@ -0,0 +1,115 @@
+//! Growth of the shims' memory across the waves of --leak-check.
+
+use std::fmt;
+
+use serde::Serialize;
+
+/// RSS of the shims once a wave settled.
+#[derive(Serialize)]
+pub struct WaveRss {
+    /// Number of the wave, starting at 1
+    pub wave: usize,
+    pub rss_bytes: u64,
+    /// Growth since the previous wave, or since before the first wave
+    pub delta_bytes: i64,
+}
+
+#[derive(Serialize)]
+pub struct LeakReport {
+    /// RSS of the shims before the first wave, after the warmup
+    pub initial_rss_bytes: u64,
+    pub waves: Vec<WaveRss>,
+    /// Slope of the linear fit of the RSS after each wave, divided by the tasks of a wave.
+    /// The RSS before the first wave is left out of the fit, as the first tasks
+    /// allocate the caches of the shim, which is not a leak.
+    pub growth_bytes_per_task: f64,
+}
+
+impl LeakReport {
+    pub fn new(initial_rss_bytes: u64, rss_bytes: &[u64], tasks_per_wave: usize) -> Self {
+        let waves = rss_bytes
+            .iter()
+            .scan(initial_rss_bytes, |previous, &rss_bytes| {
+                let delta_bytes = rss_bytes as i64 - *previous as i64;
+                *previous = rss_bytes;
+                Some((rss_bytes, delta_bytes))
+            })
+            .enumerate()
+            .map(|(wave, (rss_bytes, delta_bytes))| WaveRss {
+                wave: wave + 1,
+                rss_bytes,
+                delta_bytes,
+            })
+            .collect();
+        let rss: Vec<_> = rss_bytes.iter().map(|&rss| rss as f64).collect();
+        Self {
+            initial_rss_bytes,
+            waves,
+            growth_bytes_per_task: slope(&rss) / tasks_per_wave.max(1) as f64,
+        }
+    }
+
+    /// Fail if the shims grew by more than `threshold` bytes per task
+    pub fn check(&self, threshold: Option<u64>) -> Result<(), Leak> {
+        match threshold {
+            Some(threshold) if self.growth_bytes_per_task > threshold as f64 => Err(Leak {
+                growth_bytes_per_task: self.growth_bytes_per_task,
+                threshold,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Error of a run whose shims grew by more than --leak-threshold.
+#[derive(Debug)]
+pub struct Leak {
+    growth_bytes_per_task: f64,
+    threshold: u64,
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the shim grew by {:.0} bytes per task, above the threshold of {} bytes",
+            self.growth_bytes_per_task, self.threshold
+        )
+    }
+}
+
+impl std::error::Error for Leak {}
+
+/// Slope of the least squares fit of `ys`, sampled at 0, 1, 2...
+fn slope(ys: &[f64]) -> f64 {
+    let n = ys.len() as f64;
+    if ys.len() < 2 {
+        return 0.0;
+    }
+    let mean_x = (n - 1.0) / 2.0;
+    let mean_y = ys.iter().sum::<f64>() / n;
+    let (cov, var) = ys
+        .iter()
+        .enumerate()
+        .fold((0.0, 0.0), |(cov, var), (x, y)| {
+            let dx = x as f64 - mean_x;
+            (cov + dx * (y - mean_y), var + dx * dx)
+        });
+    cov / var
+}
+
+#[cfg(test)]
+mod test {
+    use super::LeakReport;
+
+    #[test]
+    fn growth_per_task() {
+        let report = LeakReport::new(1000, &[5000, 5100, 5200, 5300], 10);
+        assert_eq!(report.waves[0].delta_bytes, 4000);
+        assert_eq!(report.waves[3].delta_bytes, 100);
+        // the growth of the first wave is left out of the fit
+        assert_eq!(report.growth_bytes_per_task, 10.0);
+        assert!(report.check(Some(10)).is_ok());
+        assert!(report.check(Some(9)).is_err());
+    }
+}
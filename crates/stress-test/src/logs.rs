@@ -0,0 +1,52 @@
+use std::fs::{File, create_dir_all};
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::ValueEnum as _;
+use humantime::format_rfc3339_micros;
+
+use crate::Step;
+use crate::stats::TaskRecord;
+
+/// Directory holding the logs of a run: `shim.log` with the logs of the shim,
+/// and `tasks.log` with one line per task, with the time at which each of its
+/// steps started, to correlate the tasks with the shim logs.
+pub struct LogDir {
+    path: PathBuf,
+    tasks: File,
+}
+
+impl LogDir {
+    pub fn create(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        create_dir_all(&path)?;
+        let tasks = File::create(path.join("tasks.log"))?;
+        Ok(Self { path, tasks })
+    }
+
+    pub fn shim_log(path: impl AsRef<Path>) -> PathBuf {
+        path.as_ref().join("shim.log")
+    }
+
+    /// Where to look for the logs of the task with the given id
+    pub fn hint(&self, id: Option<&str>) -> String {
+        let id = id.unwrap_or("-");
+        format!("task {id}, logs in {}", self.path.display())
+    }
+
+    pub fn write(&mut self, record: &TaskRecord, res: &Result<()>) -> Result<()> {
+        let mut line = format!("{} {}", record.index, record.id.as_deref().unwrap_or("-"));
+        for step in Step::value_variants() {
+            if let Some(started) = record.timings.started(*step) {
+                line += &format!(" {}={}", step.name(), format_rfc3339_micros(started));
+            }
+        }
+        match res {
+            Ok(()) => line += " ok",
+            Err(err) => line += &format!(" error: {err:#}"),
+        }
+        writeln!(self.tasks, "{line}")?;
+        Ok(())
+    }
+}
@@ -1,28 +1,58 @@
+mod baseline;
 mod containerd;
+mod csv;
+mod failure;
+mod leak;
+mod logs;
+mod metrics;
 mod mocks;
+mod progress;
 mod protos;
+mod ramp;
+mod resources;
+mod stats;
+#[cfg_attr(unix, path = "sys/unix.rs")]
+#[cfg_attr(windows, path = "sys/windows.rs")]
+mod sys;
+mod term;
+mod trace;
 mod traits;
 mod utils;
+mod wave;
 
 use std::fs::File;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::pin::pin;
+use std::process::ExitCode;
 use std::sync::Arc;
-use std::time::Instant;
 
-use anyhow::{Result, bail};
+use anyhow::{Context as _, Result, bail, ensure};
+use baseline::Baseline;
 use clap::{Parser, ValueEnum};
-use futures::future::FusedFuture as _;
-use futures::stream::FuturesUnordered;
-use futures::{FutureExt as _, StreamExt as _};
+use csv::CsvWriter;
+use failure::{Category, Failures, TasksFailed};
+use futures::future::{join_all, try_join_all};
 use humantime::{format_duration, parse_duration};
-use nix::sys::prctl::set_child_subreaper;
+use leak::LeakReport;
+use logs::LogDir;
+use metrics::{Metrics, MetricsServer};
+use mocks::FaultInjection;
+use ramp::Ramp;
+use resources::{Sampler, Usage, rss_bytes};
 use serde::Serialize;
-use tokio::signal::ctrl_c;
-use tokio::sync::{Barrier, OnceCell, Semaphore};
-use tokio::time::Duration;
-use traits::{Containerd, Shim as _, Task as _};
-use utils::{reap_children, watchdog};
+use stats::{Percentiles, RpcStats, StepStats};
+use sys::{reap_children, track_children, wait_for_exit};
+use term::{errln, out, outln};
+use tokio::time::{Duration, Instant, sleep};
+use traits::{Containerd, Faults, Limits, Shim, Source, Status, Task};
+use utils::{
+    generate_stdin, parse_env, parse_fraction, parse_percent, parse_signal, random_seed, watchdog,
+};
+use wave::{
+    Image, Jitter, Kill, PauseResume, Retry, Wave, WaveResult, Workload, run_lifecycle, run_warmup,
+    run_wave,
+};
 
 #[derive(ValueEnum, Clone, Copy, PartialEq)]
 enum Step {
@@ -32,6 +62,23 @@ enum Step {
     Delete,
 }
 
+impl Step {
+    fn name(&self) -> &'static str {
+        match self {
+            Step::Create => "create",
+            Step::Start => "start",
+            Step::Wait => "wait",
+            Step::Delete => "delete",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
+
 #[derive(Serialize)]
 struct BenchmarkResult {
     name: String,
@@ -48,6 +95,38 @@ struct Cli {
     /// Use containerd to manage the shim
     containerd: bool,
 
+    #[arg(long, default_value = sys::CONTAINERD_ADDRESS)]
+    /// Address of the containerd socket to connect to
+    address: PathBuf,
+
+    #[arg(long, value_parser = parse_duration, conflicts_with = "containerd")]
+    /// Delay the responses of the mock containerd to the calls made by the shim
+    inject_latency: Option<Duration>,
+
+    #[arg(long, value_parser = parse_fraction, default_value("0"), conflicts_with = "containerd")]
+    /// Fraction of the calls made by the shim to the mock containerd that fail with a transient error
+    inject_error: f64,
+
+    #[arg(long, conflicts_with = "containerd")]
+    /// Record the latency of each call to the shim, and print it by method
+    rpc_latency: bool,
+
+    #[arg(long)]
+    /// Stop on the first task that fails, cancelling the other tasks
+    fail_fast: bool,
+
+    #[arg(long, default_value_t = 0)]
+    /// Retry a failed step up to this many times, with exponential backoff
+    retries: usize,
+
+    #[arg(long, requires = "retries")]
+    /// Only retry steps whose error contains this message, can be repeated [default: any error]
+    retry_on: Vec<String>,
+
+    #[arg(long, default_value = "default")]
+    /// Namespace to create the images' snapshots, containers and tasks in
+    namespace: String,
+
     #[arg(short, long)]
     /// Show the shim logs in stderr
     verbose: bool,
@@ -56,26 +135,201 @@ struct Cli {
     /// Number of tasks to create and start concurrently [0 = no limit]
     parallel: usize,
 
-    #[arg(short('n'), long, default_value("10"))]
+    #[arg(long, conflicts_with = "parallel")]
+    /// Gradually increase the number of parallel tasks, as <start>:<end>:<step>:<interval>
+    ramp: Option<Ramp>,
+
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["parallel", "ramp"])]
+    /// Run the test once for each of the given comma separated parallelism levels
+    sweep: Option<Vec<usize>>,
+
+    #[arg(short('n'), long, default_value("10"), conflicts_with = "duration")]
     /// Number of tasks to run
     count: usize,
 
+    #[arg(short, long, value_parser = parse_duration)]
+    /// Keep running new tasks until this duration elapses, instead of a fixed number of tasks
+    duration: Option<Duration>,
+
+    #[arg(long, default_value("0"))]
+    /// Number of task lifecycles to run before measuring, to warm up the shim
+    warmup: usize,
+
+    #[arg(long, default_value("0"))]
+    /// Number of concurrent execs to run in each task between start and wait
+    exec_count: usize,
+
+    #[arg(long)]
+    /// Arguments of the exec'd processes [default: same as the task]
+    exec_arg: Vec<String>,
+
+    #[arg(long)]
+    /// Write the content of this file to the stdin of each task once started
+    stdin: Option<PathBuf>,
+
+    #[arg(long, conflicts_with = "stdin")]
+    /// Write this many bytes of generated text to the stdin of each task once started
+    stdin_bytes: Option<usize>,
+
+    #[arg(long, value_parser = parse_env)]
+    /// Environment variable of the tasks, as `KEY=VALUE`, can be repeated
+    env: Vec<String>,
+
+    #[arg(long, value_parser = parse_duration)]
+    /// Signal each task after this delay instead of waiting for it to exit on its own
+    kill_after: Option<Duration>,
+
+    #[arg(long, value_parser = parse_signal, default_value = "SIGTERM", requires = "kill_after")]
+    /// Signal sent to the tasks with --kill-after
+    signal: u32,
+
+    #[arg(long, value_parser = parse_fraction, default_value("1"), requires = "kill_after")]
+    /// Fraction of the tasks that get signaled with --kill-after
+    kill_fraction: f64,
+
+    #[arg(long, requires = "kill_after")]
+    /// Expected exit status of signaled tasks [default: 128 + signal]
+    kill_exit_code: Option<u32>,
+
+    #[arg(long, value_parser = parse_duration)]
+    /// Poll the stats of each running task at this interval
+    stats_interval: Option<Duration>,
+
+    #[arg(long, default_value_t = 0)]
+    /// Number of pause/resume cycles to issue to each task while it's running
+    pause_resume: usize,
+
+    #[arg(long)]
+    /// Memory limit of each task, in bytes
+    memory_limit: Option<i64>,
+
+    #[arg(long, value_parser = parse_percent)]
+    /// CPU quota of each task, in percent of one CPU
+    cpu_quota: Option<f64>,
+
+    #[arg(long, value_parser = parse_duration)]
+    /// Sample the RSS and CPU time of the shim process tree at this interval
+    sample_resources: Option<Duration>,
+
+    #[arg(long, conflicts_with = "containerd")]
+    /// Run the shims with their compile cache in this directory, and measure the lifecycle of a
+    /// task with the files of the directory removed, then of a task with the module compiled by the first
+    compile_cache: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Shut the shim down after the run and measure how long its process takes to exit
+    measure_shutdown: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["containerd", "sample_resources", "measure_shutdown", "leak_check"]
+    )]
+    /// Don't create a pause task, so that only the tasks in flight keep the shim running,
+    /// and report if the shim exited before the end of the run
+    no_pause: bool,
+
+    #[arg(long, conflicts_with_all = ["sweep", "duration", "compare"])]
+    /// Run the workload this many times against the same shim, and report the growth
+    /// of its RSS once each wave settled
+    leak_check: Option<usize>,
+
+    #[arg(long, requires = "leak_check")]
+    /// Fail if the shim grows by more than this many bytes per task over the waves of --leak-check
+    leak_threshold: Option<u64>,
+
+    #[arg(long, value_parser = parse_duration, default_value = "2s", requires = "leak_check")]
+    /// Time for the shim to release the memory of a wave before its RSS is sampled
+    leak_settle: Duration,
+
+    #[arg(long)]
+    /// Spread the tasks over ceil(count / N) shim processes, one pause task each
+    tasks_per_shim: Option<NonZeroUsize>,
+
+    #[arg(long)]
+    /// Check the state of each task after create, start and wait
+    verify: bool,
+
+    #[arg(long, value_parser = parse_duration)]
+    /// Sleep each task for a random time up to this duration before create and before wait
+    jitter: Option<Duration>,
+
+    #[arg(long, requires = "jitter")]
+    /// Seed of the jitter, to replay the same scheduling as a previous run [default: random]
+    seed: Option<u64>,
+
+    #[arg(long, value_enum, default_value_t = Step::Delete)]
+    /// Stop the task lifecycle after this step, cleaning up outside of the measurement
+    until: Step,
+
+    #[arg(long, default_value = "1")]
+    /// Run the whole lifecycle of each task this many times with the same id, like a crash-looping container
+    cycles: NonZeroUsize,
+
     #[clap(short, long, value_parser = parse_duration, default_value = "2s")]
     /// Runtime timeout [0 = no timeout]
     timeout: Duration,
 
+    #[arg(long, value_parser = parse_duration)]
+    /// Fail a task when any of its create, start, wait or delete steps takes longer than this
+    task_timeout: Option<Duration>,
+
     #[clap(
         short,
         long,
         default_value = "ghcr.io/containerd/runwasi/wasi-demo-app:latest"
     )]
-    /// Image to use for the test
-    image: String,
+    /// Image to use for the test, can be repeated to assign images to tasks round-robin
+    image: Vec<String>,
+
+    #[arg(long, conflicts_with = "image")]
+    /// Use a local OCI bundle directory (with a config.json and a rootfs) instead of an image
+    bundle: Option<PathBuf>,
 
     #[arg(long)]
     /// Output the benchmark results to a JSON file
     json_output: Option<PathBuf>,
 
+    #[arg(long)]
+    /// Write the timings of each individual task to a CSV file
+    csv: Option<PathBuf>,
+
+    #[arg(long, conflicts_with_all = ["sweep", "baseline", "write_baseline"])]
+    /// Run the same workload against a second shim binary and compare the results
+    compare: Option<PathBuf>,
+
+    #[arg(long, conflicts_with = "sweep")]
+    /// Compare the results against the JSON report of a previous run, failing on regressions
+    baseline: Option<PathBuf>,
+
+    #[arg(long, default_value_t = 10.0, requires = "baseline")]
+    /// Maximum regression of the throughput and create+start p99 latency, in percent
+    max_regression: f64,
+
+    #[arg(long, conflicts_with = "sweep")]
+    /// Write the results as a baseline for future runs
+    write_baseline: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Write the shim logs and the timestamps of each task's steps to this directory
+    log_dir: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Serve live metrics of the run in the Prometheus text format on this address
+    metrics_addr: Option<SocketAddr>,
+
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    /// Format of the results printed to stdout
+    format: Format,
+
+    #[arg(long)]
+    /// Don't color the output, colors are only used on terminals anyway
+    no_color: bool,
+
+    #[arg(long)]
+    /// Export a span of each task's lifecycle to this OTLP gRPC endpoint, like http://localhost:4317.
+    /// The shims started without containerd export their spans in the same trace.
+    otlp_endpoint: Option<String>,
+
     /// Path to the shim binary
     shim: PathBuf,
 
@@ -84,30 +338,93 @@ struct Cli {
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    set_child_subreaper(true)?;
-    let res1 = main_impl().await;
-    let res2 = reap_children().await;
-    res1.and(res2)
+async fn main() -> ExitCode {
+    let res = async {
+        track_children()?;
+        let res1 = main_impl().await;
+        let res2 = reap_children().await;
+        res1.and(res2)
+    }
+    .await;
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            // as printed when returning the error from main
+            eprintln!("Error: {err:?}");
+            ExitCode::from(failure::exit_code(&err))
+        }
+    }
 }
 
 async fn main_impl() -> Result<()> {
     env_logger::try_init()?;
 
-    let mut cli = Cli::parse();
+    let cli = Cli::parse();
+    term::init(cli.no_color);
+
+    if cfg!(windows) {
+        // the containerd backend links the shim into /usr/local/bin, and
+        // the resources are sampled from /proc
+        ensure!(!cli.containerd, "--containerd is not supported on Windows");
+        ensure!(
+            cli.sample_resources.is_none(),
+            "--sample-resources is not supported on Windows"
+        );
+        ensure!(
+            cli.leak_check.is_none(),
+            "--leak-check is not supported on Windows"
+        );
+    }
+
+    // exports the spans left when dropped, at the end of the run
+    let _tracing = cli.otlp_endpoint.as_deref().map(trace::init).transpose()?;
 
-    let client = containerd::Client::default().await?;
+    let client = containerd::Client::connect(&cli.address, &cli.namespace).await?;
 
-    if cli.args.is_empty() {
-        cli.args = default_entrypoint(&client, &cli.image).await?;
+    // load all the images up front, so that a missing image fails
+    // the run before any task is created
+    let mut images = vec![];
+    if let Some(bundle) = &cli.bundle {
+        images.push(Image {
+            source: Source::Bundle(bundle.clone()),
+            args: cli.args.clone(),
+        });
+    } else {
+        for name in &cli.image {
+            client.load_image(name).await?;
+            let args = if cli.args.is_empty() {
+                default_entrypoint(&client, name).await?
+            } else {
+                cli.args.clone()
+            };
+            images.push(Image {
+                source: Source::Image(name.clone()),
+                args,
+            });
+        }
     }
 
     if cli.containerd {
         let containerd = containerd::Containerd::new(client).await?;
-        run_stress_test(cli, containerd).await
+        run_stress_test(cli, images, containerd).await
     } else {
-        let containerd = mocks::Containerd::new(client, cli.verbose).await?;
-        run_stress_test(cli, containerd).await
+        let faults = FaultInjection::new(cli.inject_latency, cli.inject_error);
+        // the shim logs to a file in the log dir, or to our stderr in verbose mode
+        let log = match &cli.log_dir {
+            Some(log_dir) => Some(LogDir::shim_log(log_dir)),
+            None => cli.verbose.then(|| PathBuf::from(sys::STDERR)),
+        };
+        let mut shim_env = match &cli.otlp_endpoint {
+            Some(endpoint) => trace::shim_env(endpoint),
+            None => vec![],
+        };
+        if let Some(dir) = &cli.compile_cache {
+            shim_env.push(("RUNWASI_COMPILE_CACHE_DIR", dir.display().to_string()));
+        }
+        let containerd =
+            mocks::Containerd::new(client, &cli.address, log, faults, cli.rpc_latency, shim_env)
+                .await?;
+        run_stress_test(cli, images, containerd).await
     }
 }
 
@@ -121,166 +438,1225 @@ fn get_runtime(path: &Path) -> Option<&str> {
     Some(runtime)
 }
 
-async fn run_stress_test(cli: Cli, c8d: impl Containerd) -> Result<()> {
+#[derive(Serialize)]
+struct Parameters {
+    images: Vec<String>,
+    count: usize,
+    parallel: usize,
+    warmup: usize,
+    until: &'static str,
+    cycles: usize,
+    duration_ns: Option<u64>,
+    ramp: Option<Ramp>,
+    timeout_ns: u64,
+    task_timeout_ns: Option<u64>,
+    /// Seed of the jitter, to replay the run
+    seed: Option<u64>,
+    /// A pause task kept each shim running, unless --no-pause
+    pause: bool,
+}
+
+#[derive(Serialize)]
+struct Report {
+    success: usize,
+    failed: usize,
+    incomplete: usize,
+    elapsed_ns: Option<u64>,
+    throughput: Option<f64>,
+    slowest_task_ns: Option<u64>,
+    /// Tasks that succeeded after retrying some of their steps
+    retried: usize,
+    /// Failed tasks that were killed for exceeding --memory-limit
+    oom_killed: usize,
+    /// Failed tasks counted by the category of their error
+    failures: Failures,
+    parameters: Parameters,
+    latencies: StepStats,
+    errors: Vec<String>,
+    exec: ExecReport,
+    stats: StatsReport,
+    resources: Option<Usage>,
+    /// Time for the shim to answer its first request once spawned
+    startup_ns: Option<u64>,
+    /// Time for the shim process to exit once shut down, with --measure-shutdown
+    shutdown_ns: Option<u64>,
+    /// Lifecycle of a task with a cold and with a warm compile cache, with --compile-cache
+    compile_cache: Option<ColdStart>,
+    /// Faults injected by the mock containerd during the run
+    faults: Faults,
+    /// RSS of the shims once settled before and after the wave, with --leak-check
+    settled_rss: Option<SettledRss>,
+    /// Whether a shim exited before the end of the wave, with --no-pause
+    shim_exited: Option<bool>,
+    /// Latency of the calls to the shim by method, with --rpc-latency
+    rpc: Option<RpcStats>,
+    images: Vec<ImageReport>,
+    /// Outcome of the tasks of each shim process, with --tasks-per-shim
+    shims: Vec<InstanceReport>,
+    /// Total time of the task lifecycle in each cycle, with --cycles
+    cycles: Vec<CycleReport>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+struct SettledRss {
+    before_bytes: u64,
+    after_bytes: u64,
+}
+
+#[derive(Serialize, Clone, Copy)]
+struct ColdStart {
+    cold_ns: u64,
+    warm_ns: u64,
+}
+
+#[derive(Serialize)]
+struct CycleReport {
+    /// Number of the cycle, starting at 1
+    cycle: usize,
+    #[serde(flatten)]
+    latency: Percentiles,
+}
+
+#[derive(Serialize)]
+struct ImageReport {
+    image: String,
+    success: usize,
+    failed: usize,
+}
+
+#[derive(Serialize)]
+struct InstanceReport {
+    shim: usize,
+    success: usize,
+    failed: usize,
+}
+
+#[derive(Serialize)]
+struct StatsReport {
+    calls: usize,
+    latency: Option<Percentiles>,
+}
+
+#[derive(Serialize)]
+struct ExecReport {
+    success: usize,
+    failed: usize,
+    errors: Vec<String>,
+}
+
+impl Report {
+    fn new(
+        result: WaveResult,
+        parameters: Parameters,
+        resources: Option<Usage>,
+        faults: Faults,
+    ) -> Self {
+        let slowest = result.timings.iter().map(|t| t.total()).max();
+        Self {
+            success: result.success,
+            failed: result.failed,
+            incomplete: result.incomplete(),
+            elapsed_ns: result.elapsed.map(|elapsed| elapsed.as_nanos() as u64),
+            throughput: result.throughput(),
+            slowest_task_ns: slowest.map(|slowest| slowest.as_nanos() as u64),
+            retried: result.retried,
+            oom_killed: result.oom_killed,
+            failures: result.failures,
+            latencies: StepStats::new(&result.timings),
+            errors: result.errors,
+            exec: ExecReport {
+                success: result.exec_success,
+                failed: result.exec_errors.len(),
+                errors: result.exec_errors,
+            },
+            stats: StatsReport {
+                calls: result.stats.len(),
+                latency: Percentiles::new(result.stats),
+            },
+            resources,
+            startup_ns: None,
+            shutdown_ns: None,
+            compile_cache: None,
+            faults,
+            settled_rss: None,
+            shim_exited: None,
+            rpc: None,
+            images: result
+                .per_image
+                .iter()
+                .zip(&parameters.images)
+                .map(|(outcomes, image)| ImageReport {
+                    image: image.clone(),
+                    success: outcomes.success,
+                    failed: outcomes.failed,
+                })
+                .collect(),
+            shims: result
+                .per_shim
+                .iter()
+                .enumerate()
+                .map(|(shim, outcomes)| InstanceReport {
+                    shim,
+                    success: outcomes.success,
+                    failed: outcomes.failed,
+                })
+                .collect(),
+            cycles: result
+                .cycles
+                .into_iter()
+                .enumerate()
+                .filter_map(|(cycle, totals)| {
+                    Some(CycleReport {
+                        cycle: cycle + 1,
+                        latency: Percentiles::new(totals)?,
+                    })
+                })
+                .collect(),
+            parameters,
+        }
+    }
+
+    fn succeeded(&self) -> bool {
+        self.failed == 0
+            && self.incomplete == 0
+            && self.exec.failed == 0
+            && self.shim_exited != Some(true)
+    }
+
+    /// The category of the failures that sets the exit code of the run
+    fn category(&self) -> Category {
+        match self.failures.dominant() {
+            Some(category) => category,
+            // tasks cut short by the global timeout
+            None if self.incomplete > 0 => Category::Timeout,
+            // the tasks finished, but the shim didn't survive them
+            None if self.shim_exited == Some(true) => Category::Rpc,
+            // only execs failed
+            None => Category::Exit,
+        }
+    }
+
+    fn failure(&self, message: impl Into<String>) -> anyhow::Error {
+        TasksFailed {
+            category: self.category(),
+            message: message.into(),
+        }
+        .into()
+    }
+
+    fn check(&self) -> Result<()> {
+        match self.succeeded() {
+            true => Ok(()),
+            false => Err(self.failure("Some tasks did not succeed")),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_ns.unwrap_or_default())
+    }
+
+    fn baseline(&self) -> Result<Baseline> {
+        let p99 = |step: Step| {
+            self.latencies
+                .get(step)
+                .map(|p| p.p99)
+                .with_context(|| format!("no {} latencies to compare", step.name()))
+        };
+        Ok(Baseline {
+            count: self.parameters.count,
+            throughput: self.throughput.unwrap_or_default(),
+            startup_p99: p99(Step::Create)? + p99(Step::Start)?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct SweepReport {
+    levels: Vec<Report>,
+}
+
+#[derive(Serialize)]
+struct LeakCheckReport<'a> {
+    waves: &'a [Report],
+    leak: &'a LeakReport,
+}
+
+#[derive(Serialize)]
+struct CompareReport<'a> {
+    shims: Vec<ShimReport<'a>>,
+}
+
+#[derive(Serialize)]
+struct ShimReport<'a> {
+    shim: PathBuf,
+    report: &'a Report,
+}
+
+/// The waves to run against each shim.
+struct Run<P> {
+    levels: Vec<usize>,
+    sweep: bool,
+    count: usize,
+    duration: Option<Duration>,
+    ramp: Option<Ramp>,
+    warmup: usize,
+    sample_resources: Option<Duration>,
+    measure_shutdown: bool,
+    /// Compile cache of the shims, emptied to measure a cold start, with --compile-cache
+    compile_cache: Option<PathBuf>,
+    tasks_per_shim: Option<NonZeroUsize>,
+    /// Time for the shims to settle before sampling their RSS around each wave, with --leak-check
+    leak_settle: Option<Duration>,
+    /// Create a pause task in each shim to keep it running between the tasks
+    pause: bool,
+    /// Parameters reported for the wave with the given parallelism
+    parameters: P,
+}
+
+impl<P: Fn(usize) -> Parameters> Run<P> {
+    /// Start the shims at `shim_path` and run one wave for each level, returning their reports.
+    async fn run<C: Containerd>(
+        &self,
+        c8d: &C,
+        shim_path: &Path,
+        workload: &Workload,
+        csv: &mut Option<CsvWriter>,
+        logs: &mut Option<LogDir>,
+    ) -> Result<Vec<Report>> {
+        let text = workload.text;
+
+        let instances = match self.tasks_per_shim {
+            Some(tasks) => self.count.div_ceil(tasks.get()).max(1),
+            None => 1,
+        };
+        let mut shims = vec![];
+        let mut pauses = vec![];
+        let mut startup = Duration::ZERO;
+        for _ in 0..instances {
+            let shim = c8d.start_shim(shim_path).await?;
+            let shim = Arc::new(shim);
+
+            if self.pause {
+                // create a "pause" container to keep the shim running
+                let image = &workload.images[0];
+                let pause = shim
+                    .task(
+                        image.source.clone(),
+                        &image.args,
+                        &workload.env,
+                        workload.limits,
+                        false,
+                    )
+                    .await?;
+                let start = Instant::now();
+                pause.create().await?;
+                // without a startup time of its own, the shim was spawned to create the pause task
+                startup = startup.max(shim.startup().unwrap_or_else(|| start.elapsed()));
+                pauses.push(pause);
+            } else {
+                startup = startup.max(shim.startup().unwrap_or_default());
+            }
+
+            shims.push(shim);
+        }
+        if text {
+            match instances {
+                1 => errln!("> Shim started in {startup:?}"),
+                n => errln!("> Started {n} shims, the slowest in {startup:?}"),
+            }
+        }
+
+        // before the warmup, which would warm the compile cache
+        let cold_start = match &self.compile_cache {
+            Some(dir) => {
+                let cold_start = measure_cold_start(&shims[0], workload, dir).await?;
+                if text {
+                    errln!(
+                        "> Cold start in {:?}, warm start in {:?}",
+                        Duration::from_nanos(cold_start.cold_ns),
+                        Duration::from_nanos(cold_start.warm_ns)
+                    );
+                }
+                Some(cold_start)
+            }
+            None => None,
+        };
+
+        let sample_pids =
+            self.sample_resources.is_some() || self.measure_shutdown || self.leak_settle.is_some();
+        let shim_pids = match sample_pids {
+            true => Some(try_join_all(pauses.iter().map(|pause| pause.shim_pid())).await?),
+            false => None,
+        };
+
+        if self.warmup > 0 {
+            if text {
+                errln!("> Warming up with {} iterations.", self.warmup);
+            }
+            let mut errors = vec![];
+            for shim in &shims {
+                errors.extend(run_warmup(shim, workload, self.warmup).await);
+            }
+            if text && !errors.is_empty() {
+                errln!("> \x1b[33m{} warmup iterations failed\x1b[0m", errors.len());
+            }
+        }
+
+        let leak_check = self.leak_settle.zip(shim_pids.as_deref());
+        let mut settled_rss = match leak_check {
+            Some((settle, pids)) => {
+                sleep(settle).await;
+                rss_bytes(pids).await?
+            }
+            None => 0,
+        };
+
+        let mut reports = vec![];
+        for (index, &parallel) in self.levels.iter().enumerate() {
+            if self.sweep {
+                for pause in &pauses {
+                    check_health(pause).await.map_err(|err| {
+                        err.context(format!("shim unhealthy before level {parallel}"))
+                    })?;
+                }
+                if text {
+                    outln!("\x1b[1mRunning with {parallel} parallel tasks\x1b[0m");
+                }
+            } else if leak_check.is_some() {
+                let wave = index + 1;
+                for pause in &pauses {
+                    check_health(pause)
+                        .await
+                        .map_err(|err| err.context(format!("shim unhealthy before wave {wave}")))?;
+                }
+                if text {
+                    outln!("\x1b[1mRunning wave {wave} of {}\x1b[0m", self.levels.len());
+                }
+            }
+
+            let sampler = shim_pids
+                .clone()
+                .zip(self.sample_resources)
+                .map(|(pids, interval)| Sampler::start(pids, interval));
+            let wave = Wave {
+                count: self.count,
+                parallel,
+                duration: self.duration,
+                ramp: self.ramp,
+                shim_rss: sampler.as_ref().map(|sampler| sampler.rss_bytes.clone()),
+            };
+            let faults_before = c8d.faults();
+            let rpc_latencies = c8d.rpc_latencies();
+            // leave out the calls of the pause tasks, warmup and previous levels
+            if let Some(latencies) = &rpc_latencies {
+                latencies.take();
+            }
+            let result = run_wave(&shims, workload, &wave, csv, logs).await?;
+            let faults_after = c8d.faults();
+            let faults = Faults {
+                delayed: faults_after.delayed - faults_before.delayed,
+                errors: faults_after.errors - faults_before.errors,
+            };
+            let resources = match sampler {
+                Some(sampler) => Some(sampler.finish().await?),
+                None => None,
+            };
+            let interrupted = result.interrupted;
+
+            let mut report = Report::new(result, (self.parameters)(parallel), resources, faults);
+            report.startup_ns = Some(startup.as_nanos() as u64);
+            report.compile_cache = cold_start;
+            report.rpc = rpc_latencies.map(|latencies| latencies.take());
+            if let Some((settle, pids)) = leak_check.filter(|_| !interrupted) {
+                // the memory of the wave can only be released once all its tasks are gone
+                ensure!(
+                    report.incomplete == 0,
+                    "{} tasks of wave {} are still in flight",
+                    report.incomplete,
+                    index + 1
+                );
+                sleep(settle).await;
+                let before_bytes = settled_rss;
+                settled_rss = rss_bytes(pids).await?;
+                report.settled_rss = Some(SettledRss {
+                    before_bytes,
+                    after_bytes: settled_rss,
+                });
+            }
+            if !self.pause {
+                // only the tasks of the wave kept the shims running, they must outlive them
+                let alive = join_all(shims.iter().map(|shim| shim.alive())).await;
+                report.shim_exited = Some(alive.contains(&Some(false)));
+            }
+            // the next levels would only run against a dead shim
+            let stop = interrupted || report.shim_exited == Some(true);
+            reports.push(report);
+
+            if stop {
+                break;
+            }
+        }
+
+        if let Some(pids) = shim_pids.filter(|_| self.measure_shutdown) {
+            let shutdowns = shims
+                .iter()
+                .zip(pauses)
+                .zip(pids)
+                .map(|((shim, pause), pid)| {
+                    measure_shutdown(&**shim, pause, pid, workload.timeout)
+                });
+            // the shims are shut down concurrently, the slowest one is reported
+            let shutdown = try_join_all(shutdowns)
+                .await?
+                .into_iter()
+                .max()
+                .unwrap_or_default();
+            if text {
+                errln!("> Shim shut down in {shutdown:?}");
+            }
+            if let Some(report) = reports.last_mut() {
+                report.shutdown_ns = Some(shutdown.as_nanos() as u64);
+            }
+        }
+
+        Ok(reports)
+    }
+}
+
+/// Remove the files of the compile cache in `dir`, then run a task with the cache cold, and
+/// one with the module that the first one compiled, returning how long their lifecycles took
+async fn measure_cold_start(
+    shim: &Arc<impl Shim>,
+    workload: &Workload,
+    dir: &Path,
+) -> Result<ColdStart> {
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_file() {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("failed to remove {}", path.display()))?;
+            }
+        }
+    }
+    let cold = run_lifecycle(shim, workload, 0)
+        .await
+        .context("task with a cold compile cache failed")?;
+    let warm = run_lifecycle(shim, workload, 0)
+        .await
+        .context("task with a warm compile cache failed")?;
+    Ok(ColdStart {
+        cold_ns: cold.as_nanos() as u64,
+        warm_ns: warm.as_nanos() as u64,
+    })
+}
+
+/// Delete the pause task and shut the shim down, returning how long it took
+/// for the shim process to exit.
+async fn measure_shutdown(
+    shim: &impl Shim,
+    pause: impl Task,
+    pid: u32,
+    timeout: Duration,
+) -> Result<Duration> {
+    let start = Instant::now();
+    pause.delete().await?;
+    drop(pause);
+    shim.shutdown().await?;
+    tokio::select! {
+        _ = wait_for_exit(pid) => Ok(start.elapsed()),
+        _ = watchdog(timeout) => {
+            // this is the leak --measure-shutdown is meant to catch, report it even in JSON mode
+            let timeout = format_duration(timeout);
+            log::error!("shim process {pid} is still running {timeout} after shutdown");
+            errln!("\x1b[1;31mOrphaned shim: process {pid} is still running {timeout} after shutdown\x1b[0m");
+            bail!("shim process {pid} didn't exit within {timeout} of shutdown");
+        }
+    }
+}
+
+async fn check_health(pause: &impl Task) -> Result<()> {
+    let state = pause.state().await?;
+    ensure!(
+        state.status == Status::Created,
+        "pause task is in unexpected state {:?}",
+        state.status
+    );
+    Ok(())
+}
+
+async fn run_stress_test(cli: Cli, images: Vec<Image>, c8d: impl Containerd) -> Result<()> {
     let Cli {
         containerd,
         shim: shim_path,
         parallel,
+        ramp,
+        sweep,
         count,
+        duration: run_duration,
+        warmup,
+        exec_count,
+        exec_arg,
+        stdin,
+        stdin_bytes,
+        env,
+        memory_limit,
+        cpu_quota,
+        kill_after,
+        signal,
+        kill_fraction,
+        kill_exit_code,
+        stats_interval,
+        pause_resume,
+        sample_resources,
+        measure_shutdown,
+        compile_cache,
+        no_pause,
+        leak_check,
+        leak_threshold,
+        leak_settle,
+        tasks_per_shim,
+        verify,
+        jitter,
+        seed,
+        retries,
+        retry_on,
+        fail_fast,
+        until,
         timeout,
-        image,
+        task_timeout,
+        cycles,
         json_output,
-        args,
+        csv,
+        baseline,
+        max_regression,
+        write_baseline,
+        compare,
+        log_dir,
+        metrics_addr,
+        format,
         ..
     } = cli;
 
-    println!("\x1b[1mUsing image {image:?} with arguments {args:?}\x1b[0m");
+    let levels = match leak_check {
+        // every wave runs the same workload against the same shims
+        Some(waves) => vec![parallel; waves],
+        None => sweep.clone().unwrap_or_else(|| vec![parallel]),
+    };
+    let cycles = cycles.get();
+    if cycles > 1 && until != Step::Delete {
+        bail!("--cycles requires the whole lifecycle, up to --until delete");
+    }
+    if let Some(waves) = leak_check {
+        ensure!(
+            waves >= 2,
+            "--leak-check needs at least 2 waves to fit a growth rate"
+        );
+        ensure!(
+            until == Step::Delete,
+            "--leak-check requires the whole lifecycle, up to --until delete"
+        );
+    }
+    if run_duration.is_some() && levels.contains(&0) && ramp.is_none() {
+        bail!("--duration requires a limit on the number of parallel tasks");
+    }
 
-    let shim = c8d.start_shim(shim_path.clone()).await?;
-    let shim = Arc::new(shim);
+    let text = format == Format::Text;
 
-    // create a "pause" container to keep the shim running
-    let pause = shim.task(&image, &args).await?;
-    pause.create().await?;
+    let baseline = baseline.map(Baseline::load).transpose()?;
+    if let Some(baseline) = &baseline {
+        baseline.check_count(count)?;
+    }
 
-    let permits = if parallel == 0 { count } else { parallel };
-    let semaphore = Arc::new(Semaphore::new(permits));
-    let barrier = Arc::new(Barrier::new(count + 1));
-    let start = Arc::new(OnceCell::new());
-    let mut tracker = FuturesUnordered::new();
-    let setup_start = Instant::now();
+    if text {
+        for Image { source, args } in &images {
+            match source {
+                Source::Image(image) => {
+                    outln!("\x1b[1mUsing image {image:?} with arguments {args:?}\x1b[0m")
+                }
+                Source::Bundle(bundle) => {
+                    outln!("\x1b[1mUsing bundle {bundle:?} with arguments {args:?}\x1b[0m")
+                }
+            }
+        }
+    }
 
-    for _ in 0..count {
-        let shim = shim.clone();
-        let image = image.clone();
-        let args = args.clone();
-        let semaphore = semaphore.clone();
-        let barrier = barrier.clone();
-        let start = start.clone();
-        tracker.push(async move {
-            // create the tasks bundles before starting measuring the benchmark
-            // this is not work done by the shim itself
-            let task = shim.task(image, args).await?;
+    let jitter = jitter.map(|max| Jitter {
+        max,
+        seed: seed.unwrap_or_else(random_seed),
+    });
+    if let Some(Jitter { max, seed }) = jitter.filter(|_| text) {
+        outln!("\x1b[1mUsing jitter up to {max:?} with seed {seed}\x1b[0m");
+    }
 
-            // wait for all tasks to be set up
-            barrier.wait().await;
+    let stdin = match (stdin, stdin_bytes) {
+        (Some(path), _) => Some(
+            std::fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?,
+        ),
+        (None, Some(len)) => Some(generate_stdin(len)),
+        (None, None) => None,
+    };
+
+    let mut csv = csv.map(CsvWriter::create).transpose()?;
+    let mut logs = log_dir.map(LogDir::create).transpose()?;
 
-            // Wait for a concurrentcy slot
-            let permit = semaphore.acquire_owned().await?;
-            let _ = start.set(Instant::now());
+    // the listener is stopped when dropped, after the summary is printed
+    let metrics = metrics_addr.map(|_| Arc::<Metrics>::default());
+    let _metrics_server = match (metrics_addr, &metrics) {
+        (Some(addr), Some(metrics)) => {
+            let server = MetricsServer::serve(addr, metrics.clone())
+                .await
+                .with_context(|| format!("failed to listen for metrics on {addr}"))?;
+            if text {
+                outln!("\x1b[1mServing metrics on http://{addr}/metrics\x1b[0m");
+            }
+            Some(server)
+        }
+        _ => None,
+    };
 
-            task.create().await?;
-            task.start().await?;
+    let image_names: Vec<_> = images
+        .iter()
+        .map(|image| image.source.to_string())
+        .collect();
 
-            // release the concurrency slot
-            drop(permit);
+    let workload = Workload {
+        images,
+        timeout,
+        task_timeout,
+        until,
+        cycles,
+        exec_count,
+        exec_args: exec_arg,
+        stdin,
+        env,
+        limits: Limits {
+            memory: memory_limit,
+            cpu_quota,
+        },
+        kill: kill_after.map(|after| Kill {
+            after,
+            signal,
+            fraction: kill_fraction,
+            exit_code: kill_exit_code,
+        }),
+        stats_interval,
+        pause_resume: PauseResume::new(pause_resume),
+        retry: Retry {
+            attempts: retries,
+            on: retry_on,
+        },
+        fail_fast,
+        verify,
+        jitter,
+        metrics,
+        text,
+    };
 
-            task.wait().await?;
-            task.delete().await?;
+    let run = Run {
+        levels,
+        sweep: sweep.is_some(),
+        count,
+        duration: run_duration,
+        ramp,
+        warmup,
+        sample_resources,
+        measure_shutdown,
+        compile_cache,
+        tasks_per_shim,
+        leak_settle: leak_check.map(|_| leak_settle),
+        pause: !no_pause,
+        parameters: |parallel| Parameters {
+            images: image_names.clone(),
+            count,
+            parallel,
+            warmup,
+            until: until.name(),
+            cycles,
+            duration_ns: run_duration.map(|d| d.as_nanos() as u64),
+            ramp,
+            timeout_ns: timeout.as_nanos() as u64,
+            task_timeout_ns: task_timeout.map(|timeout| timeout.as_nanos() as u64),
+            seed: jitter.map(|jitter| jitter.seed),
+            pause: !no_pause,
+        },
+    };
 
-            Ok(())
-        });
+    let reports = run
+        .run(&c8d, &shim_path, &workload, &mut csv, &mut logs)
+        .await?;
+
+    if let Some(compare) = compare {
+        if text {
+            outln!("\x1b[1mRunning the same workload with {compare:?}\x1b[0m");
+        }
+        let other = run
+            .run(&c8d, &compare, &workload, &mut csv, &mut logs)
+            .await?;
+        let (a, b) = (&reports[0], &other[0]);
+        if format == Format::Json {
+            let report = CompareReport {
+                shims: vec![
+                    ShimReport {
+                        shim: shim_path.clone(),
+                        report: a,
+                    },
+                    ShimReport {
+                        shim: compare.clone(),
+                        report: b,
+                    },
+                ],
+            };
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print_compare_table(a, b);
+        }
+        if let Some(json_output) = json_output {
+            let results: Vec<_> = [(a, &shim_path), (b, &compare)]
+                .into_iter()
+                .map(|(report, shim)| {
+                    let mut result = benchmark_result(report, shim, containerd);
+                    result.name = format!("{} - {}", result.name, shim.display());
+                    result
+                })
+                .collect();
+            serde_json::to_writer_pretty(&mut File::create(json_output)?, &results)?;
+        }
+        a.check()?;
+        return b.check();
     }
 
-    let setup_done = barrier.wait().fuse();
-    let mut setup_done = pin!(setup_done);
+    if leak_check.is_some() {
+        let initial = reports.first().and_then(|report| report.settled_rss);
+        let rss: Vec<_> = reports
+            .iter()
+            .filter_map(|report| report.settled_rss)
+            .map(|rss| rss.after_bytes)
+            .collect();
+        let leak = LeakReport::new(initial.map_or(0, |rss| rss.before_bytes), &rss, count);
+        if format == Format::Json {
+            let report = LeakCheckReport {
+                waves: &reports,
+                leak: &leak,
+            };
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print_leak_table(&reports, &leak, leak_threshold);
+        }
+        finish_sweep(&reports, &shim_path, containerd, json_output, |index, _| {
+            format!("wave {}", index + 1)
+        })?;
+        return Ok(leak.check(leak_threshold)?);
+    }
+
+    let level = |_, report: &Report| format!("parallel {}", report.parameters.parallel);
+    if format == Format::Json {
+        if sweep.is_some() {
+            let report = SweepReport { levels: reports };
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return finish_sweep(&report.levels, &shim_path, containerd, json_output, level);
+        }
+        println!("{}", serde_json::to_string_pretty(&reports[0])?);
+    }
 
-    eprintln!("> Setting up tasks.");
-    eprintln!("  Press Ctrl-C to terminate.\x1b[A");
+    if sweep.is_some() {
+        if text {
+            print_sweep_table(&reports);
+        }
+        return finish_sweep(&reports, &shim_path, containerd, json_output, level);
+    }
 
-    let mut incomplete = count;
-    let mut success = 0;
-    let mut failed = 0;
-    let mut clear_line = false;
+    let report = &reports[0];
+    let Report {
+        success,
+        failed,
+        incomplete,
+        ..
+    } = *report;
 
-    loop {
-        tokio::select! {
-            _ = &mut setup_done => {
-                let elapsed = setup_start.elapsed();
-                let elapsed = format_duration(elapsed);
-                eprint!("\x1b[2K");
-                eprintln!("> Setup took {elapsed}");
-                eprintln!("> Waiting for tasks to finish.");
-                eprintln!("  Press Ctrl-C to terminate.\x1b[A");
+    if !report.succeeded() {
+        if text {
+            outln!(
+                "\x1b[31m{success} tasks succeeded, {failed} tasks failed, {incomplete} tasks didn't finish\x1b[0m"
+            );
+            if report.exec.failed > 0 {
+                outln!(
+                    "\x1b[31m{} execs succeeded, {} execs failed\x1b[0m",
+                    report.exec.success,
+                    report.exec.failed
+                );
             }
-            _ = watchdog(timeout), if setup_done.is_terminated() => {
-                eprintln!("\x1b[2K");
-                eprintln!("\x1b[31mTimeout\x1b[0m");
-                break;
+            if failed > 0 {
+                outln!("\x1b[31m  failures: {}\x1b[0m", report.failures.summary());
             }
-            _ = ctrl_c() => {
-                eprintln!("\x1b[2K");
-                eprintln!("\x1b[31mCancelled\x1b[0m");
-                break;
+            if report.oom_killed > 0 {
+                outln!(
+                    "\x1b[31m  {} tasks were OOM killed\x1b[0m",
+                    report.oom_killed
+                );
             }
-            res = tracker.next() => {
-                eprint!("\x1b[2K");
-                if clear_line {
-                    eprint!("\x1b[A\x1b[2K");
-                }
-                let Some(res): Option<Result<()>> = res else {
-                    eprintln!();
-                    break;
-                };
-                match res {
-                    Ok(()) => {
-                        incomplete -= 1;
-                        success += 1;
-                        clear_line = true;
-                        eprintln!("> \x1b[32m{} .. [OK]\x1b[0m", count - tracker.len());
-                        eprintln!("  Press Ctrl-C to terminate.\x1b[A");
-                    }
-                    Err(err) => {
-                        incomplete -= 1;
-                        failed += 1;
-                        clear_line = false;
-                        eprintln!("> \x1b[31m{} .. {err}\x1b[0m", count - tracker.len());
-                        eprintln!("  Press Ctrl-C to terminate.\x1b[A");
-                    }
-                }
+            if report.shim_exited == Some(true) {
+                outln!("\x1b[31m  the shim exited before the end of the run\x1b[0m");
+            }
+            print_per_image(report, 31);
+            print_per_shim(report, 31);
+            if let Some(seed) = report.parameters.seed {
+                outln!("\x1b[31m  replay with --seed {seed}\x1b[0m");
+            }
+        }
+        if let Some(err) = report.errors.first().filter(|_| fail_fast) {
+            if text {
+                outln!("\x1b[1;31mFirst error: {err}\x1b[0m");
             }
+            return Err(report.failure(format!("Task failed: {err}")));
+        }
+        return report.check();
+    }
+
+    let throuput = report.throughput.unwrap_or_default();
+    let duration = format_duration(report.elapsed());
+
+    if text {
+        outln!("\x1b[32m{success} tasks succeeded\x1b[0m");
+        outln!("\x1b[32m  elapsed time: {duration}\x1b[0m");
+        outln!("\x1b[32m  throuput: {throuput} tasks/s\x1b[0m");
+        print_per_image(report, 32);
+        print_per_shim(report, 32);
+        if let Some(seed) = report.parameters.seed {
+            outln!("\x1b[32m  jitter seed: {seed}\x1b[0m");
+        }
+        if report.retried > 0 {
+            outln!("\x1b[33m  {} tasks needed retries\x1b[0m", report.retried);
+        }
+        if warmup > 0 {
+            outln!("\x1b[32m  warmup: {warmup} iterations\x1b[0m");
+        }
+        if exec_count > 0 {
+            outln!("\x1b[32m  execs: {} succeeded\x1b[0m", report.exec.success);
+        }
+        if let Some(latency) = report.stats.latency {
+            outln!(
+                "\x1b[32m  stats: {} calls, p99: {:?}\x1b[0m",
+                report.stats.calls,
+                latency.p99
+            );
+        }
+        if let Some(usage) = report.resources {
+            outln!(
+                "\x1b[32m  shim rss: {} KiB max, {} KiB final, cpu time: {:?}\x1b[0m",
+                usage.max_rss_bytes / 1024,
+                usage.final_rss_bytes / 1024,
+                usage.cpu_time
+            );
+        }
+        if report.faults.delayed > 0 || report.faults.errors > 0 {
+            outln!(
+                "\x1b[32m  injected faults: {} delayed calls, {} failed calls\x1b[0m",
+                report.faults.delayed,
+                report.faults.errors
+            );
         }
+        if let Some(startup) = report.startup_ns {
+            let startup = Duration::from_nanos(startup).as_secs_f64() * 1000.0;
+            outln!("\x1b[32m  shim startup: {startup:.1} ms\x1b[0m");
+        }
+        if let Some(ColdStart { cold_ns, warm_ns }) = report.compile_cache {
+            outln!(
+                "\x1b[32m  cold start: {:?}, warm start: {:?}\x1b[0m",
+                Duration::from_nanos(cold_ns),
+                Duration::from_nanos(warm_ns)
+            );
+        }
+        if let Some(shutdown) = report.shutdown_ns {
+            outln!(
+                "\x1b[32m  shim shutdown: {:?}\x1b[0m",
+                Duration::from_nanos(shutdown)
+            );
+        }
+        if report.shim_exited == Some(false) {
+            outln!("\x1b[32m  shim kept running without a pause task\x1b[0m");
+        }
+        if let Some(slowest) = report.slowest_task_ns {
+            outln!(
+                "\x1b[32m  slowest task: {:?}\x1b[0m",
+                Duration::from_nanos(slowest)
+            );
+        }
+        if let (Some(first), Some(last)) = (report.cycles.first(), report.cycles.last()) {
+            outln!(
+                "\x1b[32m  cycle {}  p50: {:?}, p99: {:?}; cycle {}  p50: {:?}, p99: {:?}\x1b[0m",
+                first.cycle,
+                first.latency.p50,
+                first.latency.p99,
+                last.cycle,
+                last.latency.p50,
+                last.latency.p99
+            );
+        }
+        for (step, p) in report.latencies.iter() {
+            outln!(
+                "\x1b[32m  {:<6}  p50: {:?}, p90: {:?}, p99: {:?}, max: {:?}\x1b[0m",
+                step.name(),
+                p.p50,
+                p.p90,
+                p.p99,
+                p.max
+            );
+        }
+        if let Some(rpc) = &report.rpc {
+            print_rpc_table(rpc);
+        }
+    }
+
+    if let Some(json_output) = json_output {
+        let results = vec![benchmark_result(report, &shim_path, containerd)];
+        serde_json::to_writer_pretty(&mut File::create(json_output)?, &results)?;
+    }
+
+    if let Some(write_baseline) = write_baseline {
+        serde_json::to_writer_pretty(&mut File::create(write_baseline)?, report)?;
+    }
+
+    if let Some(baseline) = baseline {
+        baseline.compare(&report.baseline()?, max_regression, text)?;
+    }
+    Ok(())
+}
+
+fn print_rpc_table(rpc: &RpcStats) {
+    outln!(
+        "\x1b[1m{:<10}  {:>8}  {:>12}  {:>12}  {:>12}  {:>12}\x1b[0m",
+        "method",
+        "calls",
+        "p50",
+        "p90",
+        "p99",
+        "max"
+    );
+    for (method, stats) in rpc.iter() {
+        let p = &stats.latency;
+        outln!(
+            "{:<10}  {:>8}  {:>12}  {:>12}  {:>12}  {:>12}",
+            method,
+            stats.calls,
+            format!("{:.2?}", p.p50),
+            format!("{:.2?}", p.p90),
+            format!("{:.2?}", p.p99),
+            format!("{:.2?}", p.max)
+        );
+    }
+}
+
+fn print_per_image(report: &Report, color: u8) {
+    if report.images.len() < 2 {
+        return;
+    }
+    for ImageReport {
+        image,
+        success,
+        failed,
+    } in &report.images
+    {
+        outln!("\x1b[{color}m  {image}: {success} succeeded, {failed} failed\x1b[0m");
+    }
+}
+
+fn print_per_shim(report: &Report, color: u8) {
+    if report.shims.len() < 2 {
+        return;
     }
+    for InstanceReport {
+        shim,
+        success,
+        failed,
+    } in &report.shims
+    {
+        outln!("\x1b[{color}m  shim {shim}: {success} succeeded, {failed} failed\x1b[0m");
+    }
+}
+
+fn print_compare_table(a: &Report, b: &Report) {
+    let delta = |a: f64, b: f64| {
+        if a == 0.0 {
+            "-".to_string()
+        } else {
+            format!("{:+.1}%", (b - a) / a * 100.0)
+        }
+    };
+    let row = |name: &str, a: String, b: String, delta: String| {
+        outln!("{name:<14}  {a:>14}  {b:>14}  {delta:>9}");
+    };
 
-    if success != count {
-        println!(
-            "\x1b[31m{success} tasks succeeded, {failed} tasks failed, {incomplete} tasks didn't finish\x1b[0m"
+    outln!(
+        "\x1b[1m{:<14}  {:>14}  {:>14}  {:>9}\x1b[0m",
+        "",
+        "A",
+        "B",
+        "delta"
+    );
+    let (ta, tb) = (
+        a.throughput.unwrap_or_default(),
+        b.throughput.unwrap_or_default(),
+    );
+    row(
+        "throughput/s",
+        format!("{ta:.2}"),
+        format!("{tb:.2}"),
+        delta(ta, tb),
+    );
+    row(
+        "failed",
+        a.failed.to_string(),
+        b.failed.to_string(),
+        delta(a.failed as f64, b.failed as f64),
+    );
+    if let (Some(ua), Some(ub)) = (a.resources, b.resources) {
+        row(
+            "max rss KiB",
+            (ua.max_rss_bytes / 1024).to_string(),
+            (ub.max_rss_bytes / 1024).to_string(),
+            delta(ua.max_rss_bytes as f64, ub.max_rss_bytes as f64),
         );
-        bail!("Some tasks did not succeed");
     }
+    for step in Step::value_variants() {
+        let (Some(pa), Some(pb)) = (a.latencies.get(*step), b.latencies.get(*step)) else {
+            continue;
+        };
+        for (name, da, db) in [("p50", pa.p50, pb.p50), ("p99", pa.p99, pb.p99)] {
+            row(
+                &format!("{} {name}", step.name()),
+                format!("{da:.2?}"),
+                format!("{db:.2?}"),
+                delta(da.as_secs_f64(), db.as_secs_f64()),
+            );
+        }
+    }
+}
 
-    let elapsed = start.get().unwrap().elapsed();
-    let throuput = count as f64 / elapsed.as_secs_f64();
-    let duration = format_duration(elapsed);
+fn print_sweep_table(reports: &[Report]) {
+    out!(
+        "\x1b[1m{:>8}  {:>7}  {:>6}  {:>14}",
+        "parallel",
+        "success",
+        "failed",
+        "throughput/s"
+    );
+    for step in Step::value_variants() {
+        out!("  {:>14}", format!("{} p99", step.name()));
+    }
+    outln!("\x1b[0m");
 
-    println!("\x1b[32m{success} tasks succeeded\x1b[0m");
-    println!("\x1b[32m  elapsed time: {duration}\x1b[0m");
-    println!("\x1b[32m  throuput: {throuput} tasks/s\x1b[0m");
+    for report in reports {
+        let color = if report.succeeded() { 32 } else { 31 };
+        out!(
+            "\x1b[{color}m{:>8}  {:>7}  {:>6}  {:>14.2}",
+            report.parameters.parallel,
+            report.success,
+            report.failed,
+            report.throughput.unwrap_or_default()
+        );
+        for step in Step::value_variants() {
+            let p99 = report
+                .latencies
+                .get(*step)
+                .map(|p| format!("{:.2?}", p.p99))
+                .unwrap_or_else(|| "-".into());
+            out!("  {p99:>14}");
+        }
+        outln!("\x1b[0m");
+    }
+}
 
-    let shim = get_runtime(&shim_path).unwrap_or("unknown");
-    let containerd_shim = if containerd { "containerd" } else { "mock" };
-    let image = match image.as_str() {
-        "ghcr.io/containerd/runwasi/wasi-demo-oci:latest" => "oci",
-        "ghcr.io/containerd/runwasi/wasi-demo-app:latest" => "app",
-        "ghcr.io/containerd/runwasi/wasi-demo-oci-artifact:latest" => "oci-artifact",
-        others => others,
+/// Write the benchmark results of each wave, named after the wave by `label`,
+/// and fail if any of them failed.
+fn print_leak_table(reports: &[Report], leak: &LeakReport, threshold: Option<u64>) {
+    outln!(
+        "\x1b[1mShim RSS before the first wave: {} KiB\x1b[0m",
+        leak.initial_rss_bytes / 1024
+    );
+    outln!(
+        "\x1b[1m{:>4}  {:>7}  {:>6}  {:>12}  {:>12}\x1b[0m",
+        "wave",
+        "success",
+        "failed",
+        "rss KiB",
+        "delta KiB"
+    );
+    for (report, wave) in reports.iter().zip(&leak.waves) {
+        let color = if report.succeeded() { 32 } else { 31 };
+        outln!(
+            "\x1b[{color}m{:>4}  {:>7}  {:>6}  {:>12}  {:>+12}\x1b[0m",
+            wave.wave,
+            report.success,
+            report.failed,
+            wave.rss_bytes / 1024,
+            wave.delta_bytes / 1024
+        );
+    }
+    let color = if leak.check(threshold).is_ok() {
+        32
+    } else {
+        31
     };
+    outln!(
+        "\x1b[{color}m  growth: {:.0} bytes per task\x1b[0m",
+        leak.growth_bytes_per_task
+    );
+}
 
+fn finish_sweep(
+    reports: &[Report],
+    shim_path: &Path,
+    containerd: bool,
+    json_output: Option<PathBuf>,
+    label: impl Fn(usize, &Report) -> String,
+) -> Result<()> {
     if let Some(json_output) = json_output {
-        let results = vec![BenchmarkResult {
-            name: format!(
-                "Stress Test Throughput with {containerd_shim} service - {shim} ({image})",
-            ),
-            unit: "tasks/s".to_string(),
-            value: throuput,
-            extra: Some(format!(
-                "Image: {}\nTasks: {}\nParallel: {}\nDuration: {}",
-                image, count, parallel, duration
-            )),
-        }];
+        let results: Vec<_> = reports
+            .iter()
+            .enumerate()
+            .map(|(index, report)| {
+                let mut result = benchmark_result(report, shim_path, containerd);
+                result.name = format!("{} - {}", result.name, label(index, report));
+                result
+            })
+            .collect();
         serde_json::to_writer_pretty(&mut File::create(json_output)?, &results)?;
     }
-    Ok(())
+
+    reports.iter().try_for_each(Report::check)
+}
+
+fn benchmark_result(report: &Report, shim_path: &Path, containerd: bool) -> BenchmarkResult {
+    let shim = get_runtime(shim_path).unwrap_or("unknown");
+    let containerd_shim = if containerd { "containerd" } else { "mock" };
+    let images: Vec<_> = report
+        .parameters
+        .images
+        .iter()
+        .map(|image| match image.as_str() {
+            "ghcr.io/containerd/runwasi/wasi-demo-oci:latest" => "oci",
+            "ghcr.io/containerd/runwasi/wasi-demo-app:latest" => "app",
+            "ghcr.io/containerd/runwasi/wasi-demo-oci-artifact:latest" => "oci-artifact",
+            others => others,
+        })
+        .collect();
+    let image = images.join(", ");
+    let count = report.parameters.count;
+    let parallel = report.parameters.parallel;
+    let duration = format_duration(report.elapsed());
+    let mut extra =
+        format!("Image: {image}\nTasks: {count}\nParallel: {parallel}\nDuration: {duration}");
+    if let Some(startup) = report.startup_ns {
+        extra += &format!("\nShim startup: {:?}", Duration::from_nanos(startup));
+    }
+
+    BenchmarkResult {
+        name: format!("Stress Test Throughput with {containerd_shim} service - {shim} ({image})"),
+        unit: "tasks/s".to_string(),
+        value: report.throughput.unwrap_or_default(),
+        extra: Some(extra),
+    }
 }
 
 async fn default_entrypoint(
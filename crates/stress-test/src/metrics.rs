@@ -0,0 +1,195 @@
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use clap::ValueEnum as _;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+use crate::Step;
+use crate::stats::Timings;
+
+/// Upper bounds of the buckets of the step latency histograms, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Live counters of a run, exposed in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    started: AtomicU64,
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    in_flight: AtomicU64,
+    steps: [Histogram; 4],
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_ns: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, le) in self.buckets.iter().zip(BUCKETS) {
+            if secs <= le {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ns
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn task_started(&self) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a completed task, `started` tells if `task_started` was called for it.
+    pub fn task_finished(&self, started: bool, timings: &Timings, success: bool) {
+        if started {
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        }
+        let counter = if success {
+            &self.succeeded
+        } else {
+            &self.failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        for step in Step::value_variants() {
+            if let Some(duration) = timings.get(*step) {
+                self.steps[*step as usize].observe(duration);
+            }
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("tasks_started_total", "counter", &self.started),
+            ("tasks_succeeded_total", "counter", &self.succeeded),
+            ("tasks_failed_total", "counter", &self.failed),
+            ("tasks_in_flight", "gauge", &self.in_flight),
+        ];
+        for (name, kind, value) in counters {
+            let value = value.load(Ordering::Relaxed);
+            let _ = writeln!(out, "# TYPE stress_test_{name} {kind}");
+            let _ = writeln!(out, "stress_test_{name} {value}");
+        }
+
+        let name = "stress_test_step_duration_seconds";
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for step in Step::value_variants() {
+            let histogram = &self.steps[*step as usize];
+            let step = step.name();
+            for (bucket, le) in histogram.buckets.iter().zip(BUCKETS) {
+                let count = bucket.load(Ordering::Relaxed);
+                let _ = writeln!(out, "{name}_bucket{{step=\"{step}\",le=\"{le}\"}} {count}");
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let sum = histogram.sum_ns.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(out, "{name}_bucket{{step=\"{step}\",le=\"+Inf\"}} {count}");
+            let _ = writeln!(out, "{name}_sum{{step=\"{step}\"}} {sum}");
+            let _ = writeln!(out, "{name}_count{{step=\"{step}\"}} {count}");
+        }
+        out
+    }
+}
+
+/// Minimal HTTP listener that answers every request with the current metrics.
+/// The listener stops when dropped.
+pub struct MetricsServer(JoinHandle<()>);
+
+impl MetricsServer {
+    pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let handle = tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    continue;
+                };
+                let metrics = metrics.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = respond(stream, &metrics).await {
+                        log::debug!("failed to serve metrics: {err}");
+                    }
+                });
+            }
+        });
+        Ok(Self(handle))
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    // the request itself is irrelevant, read its head and discard it
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let body = metrics.render();
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::time::Duration;
+
+    use super::Metrics;
+    use crate::Step;
+    use crate::stats::Timings;
+
+    #[tokio::test]
+    async fn render_metrics() {
+        let metrics = Metrics::default();
+        let mut timings = Timings::default();
+        timings
+            .time(Step::Create, async {
+                tokio::time::sleep(Duration::from_millis(2)).await;
+                Ok(())
+            })
+            .await
+            .unwrap();
+        metrics.task_started();
+        metrics.task_finished(true, &timings, true);
+
+        let text = metrics.render();
+        assert!(text.contains("stress_test_tasks_succeeded_total 1\n"));
+        assert!(text.contains("stress_test_tasks_in_flight 0\n"));
+        assert!(text.contains(
+            "stress_test_step_duration_seconds_bucket{step=\"create\",le=\"0.001\"} 0\n"
+        ));
+        assert!(text.contains(
+            "stress_test_step_duration_seconds_bucket{step=\"create\",le=\"0.005\"} 1\n"
+        ));
+        assert!(text.contains("stress_test_step_duration_seconds_count{step=\"start\"} 0\n"));
+    }
+}
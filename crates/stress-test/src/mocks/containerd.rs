@@ -1,44 +1,109 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use anyhow::Result;
 use tempfile::{TempDir, tempdir};
-use trapeze::{Server, ServerHandle, service};
+use tokio::time::{Duration, sleep};
+use trapeze::{Code, Server, ServerHandle, Status, service};
 
 use super::Shim;
-use crate::containerd;
 use crate::protos::containerd::services::events::ttrpc::v1::{Events, ForwardRequest};
+use crate::stats::RpcLatencies;
+use crate::traits::Faults;
+use crate::{containerd, sys};
 
-struct EventsService;
+/// Faults to inject in the responses to the calls made by the shim.
+#[derive(Default)]
+pub struct FaultInjection {
+    /// Delay before responding to each call
+    pub latency: Option<Duration>,
+    /// Fraction of the calls that fail with a transient error
+    pub error_rate: f64,
+    calls: AtomicUsize,
+    delayed: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+impl FaultInjection {
+    pub fn new(latency: Option<Duration>, error_rate: f64) -> Self {
+        Self {
+            latency,
+            error_rate,
+            ..Default::default()
+        }
+    }
+
+    async fn inject(&self) -> trapeze::Result<()> {
+        if let Some(latency) = self.latency {
+            self.delayed.fetch_add(1, Ordering::Relaxed);
+            sleep(latency).await;
+        }
+        // spread the failed calls evenly, so that runs are reproducible
+        let n = self.calls.fetch_add(1, Ordering::Relaxed) as f64;
+        if (n * self.error_rate).floor() != ((n + 1.0) * self.error_rate).floor() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            return Err(Status::new(Code::Unavailable, "injected fault"));
+        }
+        Ok(())
+    }
+
+    fn faults(&self) -> Faults {
+        Faults {
+            delayed: self.delayed.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct EventsService(Arc<FaultInjection>);
 
 impl Events for EventsService {
     async fn forward(&self, forward_request: ForwardRequest) -> trapeze::Result<()> {
         log::info!("forward_request: {forward_request:?}");
-        Ok(())
+        self.0.inject().await
     }
 }
 
 pub struct Containerd {
     dir: TempDir,
     _server: ServerHandle,
-    verbose: bool,
+    address: PathBuf,
+    log: Option<PathBuf>,
     containerd: containerd::Client,
+    faults: Arc<FaultInjection>,
+    latencies: Option<RpcLatencies>,
+    /// Environment variables set for the shims
+    shim_env: Vec<(&'static str, String)>,
 }
 
 impl Containerd {
-    pub async fn new(client: containerd::Client, verbose: bool) -> Result<Self> {
+    pub async fn new(
+        client: containerd::Client,
+        address: impl Into<PathBuf>,
+        log: Option<PathBuf>,
+        faults: FaultInjection,
+        rpc_latency: bool,
+        shim_env: Vec<(&'static str, String)>,
+    ) -> Result<Self> {
         let dir = tempdir()?;
-        let socket = dir.path().join("containerd.sock.ttrpc");
+        let faults = Arc::new(faults);
+        let events = EventsService(faults.clone());
 
         let _server = Server::new()
-            .register(service!(EventsService: Events))
-            .bind(format!("unix://{}", socket.display()))
+            .register(service!(events: Events))
+            .bind(sys::ttrpc_listen_address(dir.path()))
             .await?;
 
         Ok(Self {
             dir,
             _server,
-            verbose,
+            address: address.into(),
+            log,
             containerd: client,
+            faults,
+            latencies: rpc_latency.then(RpcLatencies::default),
+            shim_env,
         })
     }
 }
@@ -46,6 +111,23 @@ impl Containerd {
 impl crate::traits::Containerd for Containerd {
     type Shim = Shim;
     async fn start_shim(&self, shim: impl AsRef<Path> + Send) -> Result<Shim> {
-        Shim::new(self.containerd.clone(), &self.dir, self.verbose, shim).await
+        Shim::new(
+            self.containerd.clone(),
+            &self.dir,
+            &self.address,
+            self.log.as_deref(),
+            self.latencies.clone(),
+            &self.shim_env,
+            shim,
+        )
+        .await
+    }
+
+    fn faults(&self) -> Faults {
+        self.faults.faults()
+    }
+
+    fn rpc_latencies(&self) -> Option<RpcLatencies> {
+        self.latencies.clone()
     }
 }
@@ -3,6 +3,6 @@ mod shim;
 mod task;
 mod task_client;
 
-pub use containerd::Containerd;
+pub use containerd::{Containerd, FaultInjection};
 pub use shim::Shim;
 pub use task::Task;
@@ -5,61 +5,71 @@ use log::info;
 use oci_spec::runtime::SpecBuilder;
 use serde::Deserialize;
 use tempfile::{TempDir, tempdir_in};
-use tokio::fs::{canonicalize, symlink};
 use tokio::process::Command;
+use tokio::time::{Duration, Instant};
 use tokio_async_drop::tokio_async_drop;
 
 use super::Task;
-use crate::containerd;
 use crate::mocks::task_client::TaskClient;
 use crate::protos::containerd::task::v2::ShutdownRequest;
+use crate::stats::RpcLatencies;
+use crate::traits::{Limits, Source};
+use crate::utils::make_shim_index;
+use crate::{containerd, sys};
 
 pub struct Shim {
     dir: TempDir,
     client: TaskClient,
     containerd: containerd::Client,
+    sandbox_id: String,
+    startup: Duration,
 }
 
 impl Shim {
     pub(super) async fn new(
         containerd: containerd::Client,
         scratch: impl AsRef<Path>,
-        verbose: bool,
+        address: impl AsRef<Path>,
+        log: Option<&Path>,
+        latencies: Option<RpcLatencies>,
+        env: &[(&'static str, String)],
         binary: impl AsRef<Path>,
     ) -> Result<Self> {
         info!("Setting up shim");
 
         let scratch = scratch.as_ref();
 
-        let socket = scratch.join("containerd.sock.ttrpc");
+        let socket = sys::ttrpc_address(scratch);
         let dir = tempdir_in(scratch)?;
 
         let spec = SpecBuilder::default().build()?;
         spec.save(dir.path().join("config.json"))?;
 
-        if verbose {
-            let stderr = canonicalize("/proc/self/fd/2").await?;
-            symlink(stderr, dir.path().join("log")).await?;
-        } else {
-            symlink("/dev/null", dir.path().join("log")).await?;
-        }
+        sys::link_shim_log(dir.path(), log).await?;
 
         info!("Starting shim");
+        let start = Instant::now();
 
         let pid = std::process::id();
+        // each shim instance needs its own id, or `start` would return the address of the first one
+        let index = make_shim_index();
+        let id = format!("shim-benchmark-{pid}-{index}");
+        let sandbox_id = format!("sandbox-{pid}-{index}");
         let binary = binary.as_ref();
+        let address = address.as_ref().to_string_lossy();
         let start_shim = || {
             Command::new(binary)
                 .args([
                     "-namespace",
                     &format!("shim-benchmark-{pid}"),
                     "-id",
-                    &format!("shim-benchmark-{pid}"),
+                    &id,
                     "-address",
-                    "/run/containerd/containerd.sock",
+                    &address,
                     "start",
                 ])
                 .env("TTRPC_ADDRESS", &socket)
+                .envs(env.iter().cloned())
                 .current_dir(dir.path())
                 .output()
         };
@@ -94,12 +104,16 @@ impl Shim {
         }
 
         info!("Connecting to {address}");
-        let client = TaskClient::connect(address).await?;
+        // connecting probes the task service, so the shim has answered its first request
+        let client = TaskClient::connect(address, latencies).await?;
+        let startup = start.elapsed();
 
         Ok(Shim {
             dir,
             client,
             containerd,
+            sandbox_id,
+            startup,
         })
     }
 }
@@ -109,18 +123,43 @@ impl crate::traits::Shim for Shim {
 
     async fn task<T: Into<String>>(
         &self,
-        image: impl Into<String>,
+        source: Source,
         args: impl IntoIterator<Item = T>,
+        env: &[String],
+        limits: Limits,
+        stdin: bool,
     ) -> Result<Task> {
         Task::new(
             self.containerd.clone(),
             &self.dir,
-            image.into(),
+            source,
             args,
+            env,
+            limits,
+            stdin,
+            &self.sandbox_id,
             self.client.clone(),
         )
         .await
     }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.client
+            .shutdown(ShutdownRequest {
+                now: false,
+                ..Default::default()
+            })
+            .await?;
+        Ok(())
+    }
+
+    fn startup(&self) -> Option<Duration> {
+        Some(self.startup)
+    }
+
+    async fn alive(&self) -> Option<bool> {
+        Some(self.client.alive().await)
+    }
 }
 
 impl Drop for Shim {
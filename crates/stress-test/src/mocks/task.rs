@@ -1,67 +1,93 @@
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
-use anyhow::{Result, ensure};
-use nix::NixPath;
+use anyhow::{Context as _, Result, ensure};
 use oci_spec::runtime::{ProcessBuilder, RootBuilder, SpecBuilder, UserBuilder};
+use prost_types::Any;
 use tempfile::{TempDir, tempdir_in};
 use tokio::fs::{create_dir_all, write};
 use tokio_async_drop::tokio_async_drop;
+use trapeze::Code;
 
 use super::task_client::TaskClient;
 use crate::containerd;
 use crate::protos::containerd::task::v2::*;
 use crate::protos::containerd::types::Mount;
-use crate::traits::Task as _;
-use crate::utils::{RunOnce, make_task_id};
+use crate::sys::{StdinPipe, unmount_recursive};
+use crate::traits::{Exit, Limits, Source, State, Task as _, Unimplemented};
+use crate::utils::{RunOnce, copy_bundle, make_exec_id, make_task_id};
 
 pub struct Task {
     id: String,
     dir: TempDir,
     client: TaskClient,
     mounts: Vec<Mount>,
+    stdin: Option<StdinPipe>,
     deleted: RunOnce,
     unmounted: RunOnce,
 }
 
 impl Task {
+    #[allow(clippy::too_many_arguments)]
     pub(super) async fn new<T: Into<String>>(
         containerd: containerd::Client,
         scratch: impl AsRef<Path>,
-        image: String,
+        source: Source,
         args: impl IntoIterator<Item = T>,
+        env: &[String],
+        limits: Limits,
+        stdin: bool,
+        sandbox_id: &str,
         client: TaskClient,
     ) -> Result<Self> {
         let id = make_task_id();
-        let mounts = containerd.get_mounts(&id, &image).await?;
-        let mounts = map_mounts(mounts);
+        let dir = tempdir_in(scratch)?;
 
         let args: Vec<_> = args.into_iter().map(|arg| arg.into()).collect();
 
-        let process = ProcessBuilder::default()
-            .user(UserBuilder::default().build().unwrap())
-            .args(args)
-            .cwd("/")
-            .build()?;
+        let (mut spec, mounts) = match source {
+            Source::Image(image) => {
+                let mounts = containerd.get_mounts(&id, &image).await?;
 
-        let annotations = [(
-            "io.kubernetes.cri.sandbox-id".to_string(),
-            format!("sandbox-{}", std::process::id()),
-        )];
+                let process = ProcessBuilder::default()
+                    .user(UserBuilder::default().build().unwrap())
+                    .args(args)
+                    .env(env.to_vec())
+                    .cwd("/")
+                    .build()?;
 
-        let root = RootBuilder::default()
-            .path("rootfs")
-            .readonly(false)
-            .build()?;
+                let annotations = [(
+                    "io.kubernetes.cri.sandbox-id".to_string(),
+                    sandbox_id.to_string(),
+                )];
 
-        let spec = SpecBuilder::default()
-            .version("1.1.0")
-            .process(process)
-            .annotations(annotations)
-            .root(root)
-            .build()?;
+                let root = RootBuilder::default()
+                    .path("rootfs")
+                    .readonly(false)
+                    .build()?;
+
+                let spec = SpecBuilder::default()
+                    .version("1.1.0")
+                    .process(process)
+                    .annotations(annotations)
+                    .root(root)
+                    .build()?;
+
+                create_dir_all(dir.path().join("rootfs")).await?;
+                (spec, map_mounts(mounts))
+            }
+            Source::Bundle(bundle) => {
+                // the rootfs is used as is, there's nothing to mount
+                let mut spec = copy_bundle(&bundle, dir.path(), args, env).await?;
+                spec.annotations_mut().get_or_insert_default().insert(
+                    "io.kubernetes.cri.sandbox-id".to_string(),
+                    sandbox_id.to_string(),
+                );
+                (spec, vec![])
+            }
+        };
+
+        limits.apply(&mut spec)?;
 
-        let dir = tempdir_in(scratch)?;
-        create_dir_all(dir.path().join("rootfs")).await?;
         write(dir.path().join("options.json"), r#"{"root":"rootfs"}"#).await?;
         spec.save(dir.path().join("config.json"))?;
 
@@ -70,6 +96,7 @@ impl Task {
             dir,
             client,
             mounts,
+            stdin: stdin.then(StdinPipe::default),
             deleted: RunOnce::new(),
             unmounted: RunOnce::new(),
         })
@@ -77,6 +104,10 @@ impl Task {
 }
 
 impl crate::traits::Task for Task {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
     async fn create(&self) -> Result<()> {
         let stdout = self.dir.path().join("stdout");
         let stderr = self.dir.path().join("stderr");
@@ -84,10 +115,16 @@ impl crate::traits::Task for Task {
         let _ = std::fs::write(&stdout, "");
         let _ = std::fs::write(&stderr, "");
 
+        let stdin = match &self.stdin {
+            Some(pipe) => pipe.open(self.dir.path())?,
+            None => String::new(),
+        };
+
         self.client
             .create(CreateTaskRequest {
                 id: self.id.clone(),
                 bundle: self.dir.path().to_string_lossy().into_owned(),
+                stdin,
                 stdout: stdout.to_string_lossy().into_owned(),
                 stderr: stderr.to_string_lossy().into_owned(),
                 rootfs: self.mounts.clone(),
@@ -108,7 +145,7 @@ impl crate::traits::Task for Task {
         Ok(())
     }
 
-    async fn wait(&self) -> Result<()> {
+    async fn wait(&self) -> Result<Exit> {
         let status = self
             .client
             .wait(WaitRequest {
@@ -119,11 +156,11 @@ impl crate::traits::Task for Task {
             .exit_status;
         let stdout = std::fs::read_to_string(self.dir.path().join("stdout")).unwrap_or_default();
         let stderr = std::fs::read_to_string(self.dir.path().join("stderr")).unwrap_or_default();
-        ensure!(
-            status == 0,
-            "Exit status {status}, stdout: {stdout:?}, stderr: {stderr:?}"
-        );
-        Ok(())
+        Ok(Exit {
+            status,
+            stdout,
+            stderr,
+        })
     }
 
     async fn delete(&self) -> Result<()> {
@@ -148,38 +185,154 @@ impl crate::traits::Task for Task {
             .await;
         res1.and(res2)
     }
-}
 
-impl Drop for Task {
-    fn drop(&mut self) {
-        tokio_async_drop!({
-            let _ = self.delete().await;
+    async fn reset(&self) {
+        self.deleted.reset().await;
+        self.unmounted.reset().await;
+    }
+
+    async fn exec(&self, args: &[String]) -> Result<Exit> {
+        let exec_id = make_exec_id();
+        let stdout = self.dir.path().join(format!("{exec_id}-stdout"));
+        let stderr = self.dir.path().join(format!("{exec_id}-stderr"));
+
+        let _ = std::fs::write(&stdout, "");
+        let _ = std::fs::write(&stderr, "");
+
+        let process = ProcessBuilder::default()
+            .user(UserBuilder::default().build().unwrap())
+            .args(args.to_vec())
+            .cwd("/")
+            .build()?;
+
+        let spec = Any {
+            type_url: "types.containerd.io/opencontainers/runtime-spec/1/Process".to_string(),
+            value: serde_json::to_vec(&process)?,
+        };
+
+        self.client
+            .exec(ExecProcessRequest {
+                id: self.id.clone(),
+                exec_id: exec_id.clone(),
+                stdout: stdout.to_string_lossy().into_owned(),
+                stderr: stderr.to_string_lossy().into_owned(),
+                spec: Some(spec),
+                ..Default::default()
+            })
+            .await?;
+
+        self.client
+            .start(StartRequest {
+                id: self.id.clone(),
+                exec_id: exec_id.clone(),
+            })
+            .await?;
+
+        let status = self
+            .client
+            .wait(WaitRequest {
+                id: self.id.clone(),
+                exec_id: exec_id.clone(),
+            })
+            .await?
+            .exit_status;
+
+        self.client
+            .delete(DeleteRequest {
+                id: self.id.clone(),
+                exec_id,
+            })
+            .await?;
+
+        let stdout = std::fs::read_to_string(stdout).unwrap_or_default();
+        let stderr = std::fs::read_to_string(stderr).unwrap_or_default();
+        Ok(Exit {
+            status,
+            stdout,
+            stderr,
         })
     }
-}
 
-fn unmount_recursive(root: impl AsRef<Path>) -> Result<()> {
-    let root = root.as_ref().to_path_buf();
-    tokio::task::spawn_blocking(move || -> Result<()> {
-        let mut mounts = std::fs::read_to_string("/proc/mounts")?
-            .lines()
-            .filter_map(|m| m.split_whitespace().nth(1).map(|p| p.to_string()))
-            .filter_map(|m| {
-                let mount = PathBuf::from(m);
-                mount.starts_with(&root).then_some(mount)
+    async fn kill(&self, signal: u32) -> Result<()> {
+        self.client
+            .kill(KillRequest {
+                id: self.id.clone(),
+                signal,
+                ..Default::default()
             })
-            .collect::<Vec<_>>();
+            .await?;
+        Ok(())
+    }
 
-        mounts.sort_by_key(|p| p.len());
+    async fn stats(&self) -> Result<()> {
+        let response = self
+            .client
+            .stats(StatsRequest {
+                id: self.id.clone(),
+            })
+            .await?;
+        ensure!(response.stats.is_some(), "stats response has no metrics");
+        Ok(())
+    }
 
-        for mount in mounts.iter().rev() {
-            nix::mount::umount(mount)?;
+    async fn pause(&self) -> Result<()> {
+        let req = PauseRequest {
+            id: self.id.clone(),
+        };
+        match self.client.pause(req).await {
+            Err(status) if status.code() == Code::Unimplemented => Err(Unimplemented.into()),
+            res => Ok(res?),
         }
+    }
 
+    async fn resume(&self) -> Result<()> {
+        self.client
+            .resume(ResumeRequest {
+                id: self.id.clone(),
+            })
+            .await?;
         Ok(())
-    });
+    }
+
+    async fn write_stdin(&self, data: &[u8]) -> Result<()> {
+        self.stdin
+            .as_ref()
+            .context("the task has no stdin")?
+            .write(data)
+            .await
+    }
 
-    Ok(())
+    async fn shim_pid(&self) -> Result<u32> {
+        let response = self
+            .client
+            .connect_task(ConnectRequest {
+                id: self.id.clone(),
+            })
+            .await?;
+        Ok(response.shim_pid)
+    }
+
+    async fn state(&self) -> Result<State> {
+        let response = self
+            .client
+            .state(StateRequest {
+                id: self.id.clone(),
+                ..Default::default()
+            })
+            .await?;
+        Ok(State {
+            status: response.status.into(),
+            exit_status: response.exit_status,
+        })
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        tokio_async_drop!({
+            let _ = self.delete().await;
+        })
+    }
 }
 
 fn map_mount(m: containerd_client::types::Mount) -> Mount {
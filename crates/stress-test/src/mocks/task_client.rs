@@ -1,8 +1,10 @@
 use anyhow::{Result, bail};
+use tokio::time::Instant;
 use trapeze::{Client, Code};
 
 use crate::protos::containerd::task::v2::*;
 use crate::protos::containerd::task::v3::Task as TaskV3;
+use crate::stats::RpcLatencies;
 
 #[derive(Clone, Copy)]
 enum Version {
@@ -14,11 +16,13 @@ enum Version {
 pub struct TaskClient {
     client: Client,
     version: Version,
+    latencies: Option<RpcLatencies>,
 }
 
 macro_rules! multiplex {
     ($obj:ident.$method:ident ( $req:ident ) $($rest:tt)*) => {{
-        match $obj.version {
+        let start = Instant::now();
+        let res = match $obj.version {
             Version::V2 => {
                 trapeze::as_client!(&$obj.client: Task)
                     .$method($req)
@@ -29,12 +33,20 @@ macro_rules! multiplex {
                     .$method($req)
                     .await
             }
+        };
+        if let Some(latencies) = &$obj.latencies {
+            latencies.record(stringify!($method), start.elapsed());
         }
+        res
     }};
 }
 
 impl TaskClient {
-    pub async fn connect(address: impl AsRef<str>) -> Result<Self> {
+    /// Connect to the task service of a shim, recording the latency of each call into `latencies`
+    pub async fn connect(
+        address: impl AsRef<str>,
+        latencies: Option<RpcLatencies>,
+    ) -> Result<Self> {
         let client = Client::connect(address).await?;
 
         let version = 'v: {
@@ -55,7 +67,21 @@ impl TaskClient {
             bail!("unknown task service version")
         };
 
-        Ok(Self { version, client })
+        Ok(Self {
+            version,
+            client,
+            latencies,
+        })
+    }
+
+    /// Whether the shim still answers calls, probed with a request for a task that doesn't exist
+    pub async fn alive(&self) -> bool {
+        let req = DeleteRequest::default();
+        let res = match self.version {
+            Version::V2 => trapeze::as_client!(&self.client: Task).delete(req).await,
+            Version::V3 => trapeze::as_client!(&self.client: TaskV3).delete(req).await,
+        };
+        !matches!(res, Err(status) if status.code() == Code::Unavailable)
     }
 
     pub async fn shutdown(&self, req: ShutdownRequest) -> trapeze::Result<()> {
@@ -70,10 +96,38 @@ impl TaskClient {
         multiplex!(self.start(req))
     }
 
+    pub async fn stats(&self, req: StatsRequest) -> trapeze::Result<StatsResponse> {
+        multiplex!(self.stats(req))
+    }
+
+    pub async fn connect_task(&self, req: ConnectRequest) -> trapeze::Result<ConnectResponse> {
+        multiplex!(self.connect(req))
+    }
+
+    pub async fn pause(&self, req: PauseRequest) -> trapeze::Result<()> {
+        multiplex!(self.pause(req))
+    }
+
+    pub async fn resume(&self, req: ResumeRequest) -> trapeze::Result<()> {
+        multiplex!(self.resume(req))
+    }
+
+    pub async fn kill(&self, req: KillRequest) -> trapeze::Result<()> {
+        multiplex!(self.kill(req))
+    }
+
+    pub async fn exec(&self, req: ExecProcessRequest) -> trapeze::Result<()> {
+        multiplex!(self.exec(req))
+    }
+
     pub async fn wait(&self, req: WaitRequest) -> trapeze::Result<WaitResponse> {
         multiplex!(self.wait(req))
     }
 
+    pub async fn state(&self, req: StateRequest) -> trapeze::Result<StateResponse> {
+        multiplex!(self.state(req))
+    }
+
     pub async fn delete(&self, req: DeleteRequest) -> trapeze::Result<DeleteResponse> {
         multiplex!(self.delete(req))
     }
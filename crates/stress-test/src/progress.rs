@@ -0,0 +1,138 @@
+use std::fmt::Display;
+use std::time::Instant;
+
+use humantime::format_duration;
+use tokio::time::Duration;
+
+use crate::term::{self, errln};
+
+/// Minimum time between redraws of the progress line on a terminal
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+/// Maximum time between progress reports when not on a terminal
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Default)]
+pub struct Counts {
+    pub done: usize,
+    pub failed: usize,
+    pub in_flight: usize,
+}
+
+/// Progress of a wave on stderr.
+/// On a terminal this is a single line redrawn as tasks complete, otherwise
+/// a plain line is logged every few seconds and every 10% of the tasks.
+pub struct Progress {
+    enabled: bool,
+    tty: bool,
+    /// Number of tasks of the wave, or `None` in duration mode
+    count: Option<usize>,
+    duration: Option<Duration>,
+    start: Option<Instant>,
+    last: Instant,
+    reported: usize,
+    counts: Counts,
+}
+
+impl Progress {
+    pub fn new(enabled: bool, count: Option<usize>, duration: Option<Duration>) -> Self {
+        Self {
+            enabled,
+            tty: term::stderr_is_tty(),
+            count,
+            duration,
+            start: None,
+            last: Instant::now(),
+            reported: 0,
+            counts: Counts::default(),
+        }
+    }
+
+    /// Start reporting progress, once the tasks are set up
+    pub fn start(&mut self) {
+        self.start = Some(Instant::now());
+        self.last = Instant::now();
+        self.redraw();
+    }
+
+    /// Log a line above the progress line
+    pub fn message(&mut self, line: impl Display) {
+        if self.enabled {
+            errln!("> {line}");
+            self.redraw();
+        }
+    }
+
+    pub fn update(&mut self, counts: Counts) {
+        self.counts = counts;
+        self.tick();
+    }
+
+    /// Refresh the progress with the last counts, to keep the ETA moving
+    pub fn tick(&mut self) {
+        if !self.enabled || self.start.is_none() {
+            return;
+        }
+        if self.tty {
+            if self.last.elapsed() >= REDRAW_INTERVAL {
+                self.redraw();
+            }
+            return;
+        }
+        let decile = |done: usize| self.count.map(|count| done * 10 / count.max(1));
+        if self.last.elapsed() >= REPORT_INTERVAL
+            || decile(self.counts.done) > decile(self.reported)
+        {
+            self.reported = self.counts.done;
+            self.last = Instant::now();
+            errln!("> {}", self.status());
+        }
+    }
+
+    /// Clear the progress line
+    pub fn finish(&self) {
+        if self.enabled && self.tty {
+            eprint!("\r\x1b[2K");
+        }
+    }
+
+    fn redraw(&mut self) {
+        if !self.enabled || !self.tty {
+            return;
+        }
+        self.last = Instant::now();
+        match self.start {
+            Some(_) => eprint!("\r\x1b[2K> {}  (Ctrl-C to terminate)", self.status()),
+            None => eprint!("\r\x1b[2K  Press Ctrl-C to terminate."),
+        }
+    }
+
+    fn status(&self) -> String {
+        let Counts {
+            done,
+            failed,
+            in_flight,
+        } = self.counts;
+        let total = match self.count {
+            Some(count) => format!("{done}/{count}"),
+            None => done.to_string(),
+        };
+        let mut status = format!("{total} tasks done, {failed} failed, {in_flight} in flight");
+        if let Some(eta) = self.eta() {
+            let eta = Duration::from_secs(eta.as_secs());
+            status += &format!(", ETA {}", format_duration(eta));
+        }
+        status
+    }
+
+    fn eta(&self) -> Option<Duration> {
+        let elapsed = self.start?.elapsed();
+        match (self.count, self.duration) {
+            (_, Some(duration)) => Some(duration.saturating_sub(elapsed)),
+            (Some(count), None) if self.counts.done > 0 => {
+                let remaining = count.saturating_sub(self.counts.done) as u32;
+                Some(elapsed / self.counts.done as u32 * remaining)
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context as _, Error, Result, bail, ensure};
+use humantime::parse_duration;
+use serde::Serialize;
+
+use crate::stats::as_nanos;
+
+/// Schedule to increase the number of parallel tasks during the run,
+/// in the form `<start>:<end>:<step>:<interval>`.
+#[derive(Clone, Copy, Serialize)]
+pub struct Ramp {
+    pub start: usize,
+    pub end: usize,
+    pub step: usize,
+    #[serde(rename = "interval_ns", serialize_with = "as_nanos")]
+    pub interval: Duration,
+}
+
+impl Ramp {
+    /// The concurrency level that follows `level`, or `None` if the end of the ramp was reached.
+    pub fn next(&self, level: usize) -> Option<usize> {
+        (level < self.end).then(|| (level + self.step).min(self.end))
+    }
+}
+
+impl FromStr for Ramp {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<_> = s.split(':').collect();
+        let [start, end, step, interval] = parts[..] else {
+            bail!("expected <start>:<end>:<step>:<interval>, got {s:?}");
+        };
+        let ramp = Self {
+            start: start.parse().context("invalid ramp start")?,
+            end: end.parse().context("invalid ramp end")?,
+            step: step.parse().context("invalid ramp step")?,
+            interval: parse_duration(interval).context("invalid ramp interval")?,
+        };
+        ensure!(ramp.start > 0, "ramp start must be at least 1");
+        ensure!(
+            ramp.end >= ramp.start,
+            "ramp end must not be lower than start"
+        );
+        ensure!(ramp.step > 0, "ramp step must be at least 1");
+        ensure!(!ramp.interval.is_zero(), "ramp interval must not be zero");
+        Ok(ramp)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Ramp;
+
+    #[test]
+    fn parse_ramp() {
+        let ramp: Ramp = "1:10:4:5s".parse().unwrap();
+        assert_eq!(ramp.start, 1);
+        assert_eq!(ramp.end, 10);
+        assert_eq!(ramp.step, 4);
+        assert_eq!(ramp.interval, Duration::from_secs(5));
+
+        assert_eq!(ramp.next(1), Some(5));
+        assert_eq!(ramp.next(9), Some(10));
+        assert_eq!(ramp.next(10), None);
+
+        assert!("1:10:4".parse::<Ramp>().is_err());
+        assert!("0:10:4:5s".parse::<Ramp>().is_err());
+        assert!("10:1:4:5s".parse::<Ramp>().is_err());
+    }
+}
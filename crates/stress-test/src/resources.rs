@@ -0,0 +1,150 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context as _, Result};
+use serde::Serialize;
+use tokio::fs::{read_dir, read_to_string};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, interval};
+
+use crate::stats::as_nanos;
+use crate::sys::{clock_ticks, page_size};
+
+/// Resource usage of the shim processes and all of their descendants.
+#[derive(Serialize, Clone, Copy, Default)]
+pub struct Usage {
+    pub max_rss_bytes: u64,
+    pub final_rss_bytes: u64,
+    #[serde(rename = "cpu_time_ns", serialize_with = "as_nanos")]
+    pub cpu_time: Duration,
+}
+
+/// Periodically samples the resource usage of the shim process trees in the background.
+pub struct Sampler {
+    /// RSS of the last sample, to annotate the per-task records
+    pub rss_bytes: Arc<AtomicU64>,
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<Result<Usage>>,
+}
+
+impl Sampler {
+    pub fn start(pids: Vec<u32>, period: Duration) -> Self {
+        let rss_bytes = Arc::new(AtomicU64::new(0));
+        let (stop, mut stopped) = oneshot::channel();
+        let handle = tokio::spawn({
+            let rss_bytes = rss_bytes.clone();
+            async move {
+                let mut usage = Usage::default();
+                let mut ticks = interval(period);
+                loop {
+                    let done = tokio::select! {
+                        _ = ticks.tick() => false,
+                        _ = &mut stopped => true,
+                    };
+                    let sample = sample(&pids).await?;
+                    rss_bytes.store(sample.final_rss_bytes, Ordering::Relaxed);
+                    usage.max_rss_bytes = usage.max_rss_bytes.max(sample.final_rss_bytes);
+                    usage.final_rss_bytes = sample.final_rss_bytes;
+                    usage.cpu_time = usage.cpu_time.max(sample.cpu_time);
+                    if done {
+                        return Ok(usage);
+                    }
+                }
+            }
+        });
+        Self {
+            rss_bytes,
+            stop,
+            handle,
+        }
+    }
+
+    /// Take a last sample and return the usage recorded over the whole run.
+    pub async fn finish(self) -> Result<Usage> {
+        let _ = self.stop.send(());
+        self.handle.await?
+    }
+}
+
+/// Total RSS of the shim process trees right now
+pub async fn rss_bytes(pids: &[u32]) -> Result<u64> {
+    Ok(sample(pids).await?.final_rss_bytes)
+}
+
+async fn sample(pids: &[u32]) -> Result<Usage> {
+    let page_size = page_size()?;
+    let clock_ticks = clock_ticks()?;
+
+    let mut usage = Usage::default();
+    let mut pending = pids.to_vec();
+    while let Some(pid) = pending.pop() {
+        // processes may exit at any point, skip those that are gone
+        let Ok(statm) = read_to_string(format!("/proc/{pid}/statm")).await else {
+            continue;
+        };
+        let Ok(stat) = read_to_string(format!("/proc/{pid}/stat")).await else {
+            continue;
+        };
+        usage.final_rss_bytes += parse_statm_rss(&statm)? * page_size;
+        let ticks = parse_stat_cpu_ticks(&stat)?;
+        usage.cpu_time += Duration::from_secs_f64(ticks as f64 / clock_ticks as f64);
+        pending.extend(children(pid).await);
+    }
+    usage.max_rss_bytes = usage.final_rss_bytes;
+    Ok(usage)
+}
+
+async fn children(pid: u32) -> Vec<u32> {
+    let mut children = vec![];
+    let Ok(mut threads) = read_dir(format!("/proc/{pid}/task")).await else {
+        return children;
+    };
+    while let Ok(Some(thread)) = threads.next_entry().await {
+        let Ok(list) = read_to_string(thread.path().join("children")).await else {
+            continue;
+        };
+        children.extend(
+            list.split_whitespace()
+                .filter_map(|x| x.parse::<u32>().ok()),
+        );
+    }
+    children
+}
+
+/// Resident set size in pages, from the content of `/proc/<pid>/statm`.
+fn parse_statm_rss(statm: &str) -> Result<u64> {
+    let rss = statm
+        .split_whitespace()
+        .nth(1)
+        .context("statm is missing the resident field")?;
+    Ok(rss.parse()?)
+}
+
+/// CPU time in clock ticks used by the process and its reaped children,
+/// from the content of `/proc/<pid>/stat`.
+fn parse_stat_cpu_ticks(stat: &str) -> Result<u64> {
+    // the command name can contain spaces, the fields start after its closing parenthesis
+    let (_, fields) = stat.rsplit_once(')').context("malformed stat")?;
+    // utime, stime, cutime and cstime are fields 14 to 17, the first field after the name is 3
+    fields
+        .split_whitespace()
+        .skip(11)
+        .take(4)
+        .map(|f| -> Result<u64> { Ok(f.parse::<i64>()?.max(0) as u64) })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_stat_cpu_ticks, parse_statm_rss};
+
+    #[test]
+    fn parse_proc_files() {
+        assert_eq!(parse_statm_rss("2263 1517 913 10 0 263 0").unwrap(), 1517);
+
+        let stat = "42 (containerd shim) S 1 42 42 0 -1 4194560 1 0 0 0 7 3 2 1 20 0 1 0";
+        assert_eq!(parse_stat_cpu_ticks(stat).unwrap(), 13);
+        assert!(parse_stat_cpu_ticks("42 containerd").is_err());
+    }
+}
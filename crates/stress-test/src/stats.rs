@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use clap::ValueEnum as _;
+use serde::{Serialize, Serializer};
+
+use crate::Step;
+
+/// Duration of each lifecycle step of a single task.
+/// Each task owns its own `Timings`, which are only merged once the task
+/// completes, so that recording them doesn't contend between tasks.
+#[derive(Default, Clone)]
+pub struct Timings {
+    durations: [Option<Duration>; 4],
+    /// Wall clock time at which each step started, to correlate with the shim logs
+    started: [Option<SystemTime>; 4],
+}
+
+impl Timings {
+    pub async fn time<T>(&mut self, step: Step, fut: impl Future<Output = Result<T>>) -> Result<T> {
+        self.started[step as usize] = Some(SystemTime::now());
+        let start = Instant::now();
+        let res = fut.await;
+        self.durations[step as usize] = Some(start.elapsed());
+        res
+    }
+
+    pub fn get(&self, step: Step) -> Option<Duration> {
+        self.durations[step as usize]
+    }
+
+    pub fn started(&self, step: Step) -> Option<SystemTime> {
+        self.started[step as usize]
+    }
+
+    /// Total time spent in the recorded steps.
+    pub fn total(&self) -> Duration {
+        self.durations.iter().flatten().sum()
+    }
+}
+
+/// Everything recorded about a single task.
+pub struct TaskRecord {
+    pub index: usize,
+    /// Id of the task, once it has been set up
+    pub id: Option<String>,
+    pub image: String,
+    pub timings: Timings,
+    pub exit_status: Option<u32>,
+    /// Number of parallel tasks allowed when this task was admitted
+    pub concurrency: Option<usize>,
+    pub execs: usize,
+    pub exec_errors: Vec<String>,
+    /// Latency of each of the stats requests made while the task was running
+    pub stats: Vec<Duration>,
+    /// RSS of the shim when the task completed
+    pub shim_rss_bytes: Option<u64>,
+    /// Number of times a step of the task was retried
+    pub retries: usize,
+    /// Index of the current cycle, with --cycles
+    pub cycle: usize,
+    /// Timings of the cycles completed before the current one
+    pub cycles: Vec<Timings>,
+}
+
+impl TaskRecord {
+    pub fn new(index: usize, image: impl Into<String>) -> Self {
+        Self {
+            index,
+            id: None,
+            image: image.into(),
+            timings: Timings::default(),
+            exit_status: None,
+            concurrency: None,
+            execs: 0,
+            exec_errors: vec![],
+            stats: vec![],
+            shim_rss_bytes: None,
+            retries: 0,
+            cycle: 0,
+            cycles: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct Percentiles {
+    #[serde(rename = "p50_ns", serialize_with = "as_nanos")]
+    pub p50: Duration,
+    #[serde(rename = "p90_ns", serialize_with = "as_nanos")]
+    pub p90: Duration,
+    #[serde(rename = "p99_ns", serialize_with = "as_nanos")]
+    pub p99: Duration,
+    #[serde(rename = "max_ns", serialize_with = "as_nanos")]
+    pub max: Duration,
+}
+
+impl Percentiles {
+    /// Compute the percentiles of the given samples using the nearest-rank method.
+    /// Returns `None` if there are no samples.
+    pub fn new(mut samples: Vec<Duration>) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+        let rank = |p: f64| {
+            let n = (p / 100.0 * samples.len() as f64).ceil() as usize;
+            samples[n.clamp(1, samples.len()) - 1]
+        };
+        Some(Self {
+            p50: rank(50.0),
+            p90: rank(90.0),
+            p99: rank(99.0),
+            max: *samples.last().unwrap(),
+        })
+    }
+}
+
+/// Latencies of the calls made to the shims, by method, shared by all their clients.
+#[derive(Clone, Default)]
+pub struct RpcLatencies(Arc<Mutex<BTreeMap<&'static str, Vec<Duration>>>>);
+
+impl RpcLatencies {
+    pub fn record(&self, method: &'static str, latency: Duration) {
+        let mut samples = self.0.lock().unwrap();
+        samples.entry(method).or_default().push(latency);
+    }
+
+    /// The statistics of the calls recorded since the last call to `take`
+    pub fn take(&self) -> RpcStats {
+        let samples = std::mem::take(&mut *self.0.lock().unwrap());
+        let stats = samples
+            .into_iter()
+            .filter_map(|(method, samples)| {
+                let calls = samples.len();
+                let latency = Percentiles::new(samples)?;
+                Some((method, RpcMethodStats { calls, latency }))
+            })
+            .collect();
+        RpcStats(stats)
+    }
+}
+
+#[derive(Serialize)]
+pub struct RpcStats(BTreeMap<&'static str, RpcMethodStats>);
+
+impl RpcStats {
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &RpcMethodStats)> {
+        self.0.iter().map(|(method, stats)| (*method, stats))
+    }
+}
+
+#[derive(Serialize)]
+pub struct RpcMethodStats {
+    pub calls: usize,
+    #[serde(flatten)]
+    pub latency: Percentiles,
+}
+
+pub struct StepStats(Vec<(Step, Percentiles)>);
+
+impl StepStats {
+    pub fn new<'a>(timings: impl IntoIterator<Item = &'a Timings> + Clone) -> Self {
+        let stats = Step::value_variants()
+            .iter()
+            .filter_map(|step| {
+                let samples = timings.clone().into_iter().filter_map(|t| t.get(*step));
+                Some((*step, Percentiles::new(samples.collect())?))
+            })
+            .collect();
+        Self(stats)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Step, Percentiles)> {
+        self.0.iter()
+    }
+
+    pub fn get(&self, step: Step) -> Option<&Percentiles> {
+        self.0.iter().find(|(s, _)| *s == step).map(|(_, p)| p)
+    }
+}
+
+impl Serialize for StepStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(step, p)| (step.name(), p)))
+    }
+}
+
+pub fn as_nanos<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Percentiles;
+
+    #[test]
+    fn percentiles_nearest_rank() {
+        let samples = (1..=100).map(Duration::from_millis).collect();
+        let p = Percentiles::new(samples).unwrap();
+        assert_eq!(p.p50, Duration::from_millis(50));
+        assert_eq!(p.p90, Duration::from_millis(90));
+        assert_eq!(p.p99, Duration::from_millis(99));
+        assert_eq!(p.max, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn percentiles_empty() {
+        assert!(Percentiles::new(vec![]).is_none());
+    }
+}
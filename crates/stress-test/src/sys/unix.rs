@@ -0,0 +1,167 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr as _;
+
+use anyhow::{Context as _, Result};
+use nix::NixPath;
+use nix::sys::prctl::set_child_subreaper;
+use nix::sys::signal::Signal::SIGKILL;
+use nix::sys::signal::{Signal, kill};
+use nix::sys::stat::Mode;
+use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
+use nix::unistd::{Pid, SysconfVar, mkfifo, sysconf};
+use tokio::fs::{OpenOptions, canonicalize};
+use tokio::io::AsyncWriteExt as _;
+use tokio::net::unix::pipe;
+use tokio::time::{Duration, sleep};
+
+pub const CONTAINERD_ADDRESS: &str = "/run/containerd/containerd.sock";
+
+/// Path the shims log to in verbose mode
+pub const STDERR: &str = "/proc/self/fd/2";
+
+/// Become the subreaper of the shims, so that they remain our children once they daemonize
+pub fn track_children() -> Result<()> {
+    set_child_subreaper(true)?;
+    Ok(())
+}
+
+pub async fn reap_children() -> Result<()> {
+    let pid = std::process::id();
+    loop {
+        let list: Vec<u32> = tokio::fs::read_to_string(format!("/proc/{pid}/task/{pid}/children"))
+            .await?
+            .split_whitespace()
+            .filter_map(|x| x.parse().ok())
+            .collect();
+
+        if list.is_empty() {
+            return Ok(());
+        }
+
+        for pid in list {
+            let pid = Pid::from_raw(pid as _);
+            let _ = kill(pid, SIGKILL);
+            let _ = waitpid(pid, Some(WaitPidFlag::WNOHANG));
+        }
+    }
+}
+
+/// Wait for the process `pid` to exit, reaping it if it's a child of this process
+pub async fn wait_for_exit(pid: u32) {
+    let pid = Pid::from_raw(pid as _);
+    loop {
+        match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => {}
+            Ok(_) => return,
+            // not a child of this process, check if it still exists
+            Err(_) if kill(pid, None).is_err() => return,
+            Err(_) => {}
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Number of the signal named `name`, like `SIGTERM`
+pub fn signal_from_name(name: &str) -> Option<u32> {
+    Signal::from_str(name).ok().map(|signal| signal as u32)
+}
+
+/// Address the mock containerd serves its ttrpc services on, for the shims in `dir`
+pub fn ttrpc_address(dir: &Path) -> String {
+    dir.join("containerd.sock.ttrpc")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Address to bind the ttrpc server of the mock containerd to
+pub fn ttrpc_listen_address(dir: &Path) -> String {
+    format!("unix://{}", ttrpc_address(dir))
+}
+
+/// Size of a memory page, the unit of the RSS in `/proc/<pid>/statm`
+pub fn page_size() -> Result<u64> {
+    Ok(sysconf(SysconfVar::PAGE_SIZE)?.unwrap_or(4096) as u64)
+}
+
+/// Clock ticks per second, the unit of the CPU times in `/proc/<pid>/stat`
+pub fn clock_ticks() -> Result<u64> {
+    Ok(sysconf(SysconfVar::CLK_TCK)?.unwrap_or(100) as u64)
+}
+
+/// Link the log of a shim started in `dir` to `log`, or discard it
+pub async fn link_shim_log(dir: &Path, log: Option<&Path>) -> Result<()> {
+    if let Some(log) = log {
+        // make sure the log file exists so that it can be resolved
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log)
+            .await?;
+        let log = canonicalize(log).await?;
+        tokio::fs::symlink(log, dir.join("log")).await?;
+    } else {
+        tokio::fs::symlink("/dev/null", dir.join("log")).await?;
+    }
+    Ok(())
+}
+
+pub fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+pub fn unmount_recursive(root: impl AsRef<Path>) -> Result<()> {
+    let root = root.as_ref().to_path_buf();
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut mounts = std::fs::read_to_string("/proc/mounts")?
+            .lines()
+            .filter_map(|m| m.split_whitespace().nth(1).map(|p| p.to_string()))
+            .filter_map(|m| {
+                let mount = PathBuf::from(m);
+                mount.starts_with(&root).then_some(mount)
+            })
+            .collect::<Vec<_>>();
+
+        mounts.sort_by_key(|p| p.len());
+
+        for mount in mounts.iter().rev() {
+            nix::mount::umount(mount)?;
+        }
+
+        Ok(())
+    });
+
+    Ok(())
+}
+
+/// Write end of the stdin FIFO of a task.
+#[derive(Default)]
+pub struct StdinPipe(std::sync::Mutex<Option<pipe::Sender>>);
+
+impl StdinPipe {
+    /// Create the FIFO in the bundle `dir` if needed, open it for writing, and return its path.
+    /// The FIFO is opened read-write so that this doesn't block until the shim opens it.
+    pub fn open(&self, dir: &Path) -> Result<String> {
+        let path = dir.join("stdin");
+        if !path.exists() {
+            mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR)?;
+        }
+        let sender = pipe::OpenOptions::new()
+            .read_write(true)
+            .open_sender(&path)?;
+        *self.0.lock().unwrap() = Some(sender);
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    /// Write `data` and close the pipe, so that the task reads the end of its input.
+    /// This blocks until the task has read all but a pipe buffer of `data`.
+    pub async fn write(&self, data: &[u8]) -> Result<()> {
+        let mut sender = self
+            .0
+            .lock()
+            .unwrap()
+            .take()
+            .context("the task has no stdin")?;
+        sender.write_all(data).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use std::ffi::c_void;
+use std::io::Error;
+use std::mem::{size_of, zeroed};
+use std::path::Path;
+use std::ptr::{null, null_mut};
+use std::sync::OnceLock;
+
+use anyhow::{Context as _, Result, bail};
+use tokio::io::AsyncWriteExt as _;
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+use tokio::time::{Duration, sleep};
+use windows_sys::Win32::Foundation::{BOOL, CloseHandle, HANDLE, WAIT_OBJECT_0};
+use windows_sys::Win32::System::JobObjects::{
+    AssignProcessToJobObject, CreateJobObjectW, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+    JOBOBJECT_EXTENDED_LIMIT_INFORMATION, JobObjectBasicProcessIdList,
+    JobObjectExtendedLimitInformation, QueryInformationJobObject, SetInformationJobObject,
+};
+use windows_sys::Win32::System::Threading::{
+    GetCurrentProcess, INFINITE, OpenProcess, PROCESS_SYNCHRONIZE, PROCESS_TERMINATE,
+    TerminateProcess, WaitForSingleObject,
+};
+
+pub const CONTAINERD_ADDRESS: &str = r"\\.\pipe\containerd-containerd";
+
+/// Path the shims log to in verbose mode
+pub const STDERR: &str = "CONOUT$";
+
+/// Job object this process and all the shims it starts belong to
+static JOB: OnceLock<Handle> = OnceLock::new();
+
+/// Most processes listed in a query of the job's processes
+const MAX_PROCESSES: usize = 1024;
+
+struct Handle(HANDLE);
+
+// a handle can be used and closed from any thread
+unsafe impl Send for Handle {}
+unsafe impl Sync for Handle {}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+fn check(res: BOOL) -> Result<()> {
+    if res == 0 {
+        return Err(Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Put this process in a job object, so that the shims remain tracked once they daemonize.
+/// The shims are killed when the job is closed, even if this process crashes.
+pub fn track_children() -> Result<()> {
+    let job = unsafe { CreateJobObjectW(null(), null()) };
+    if job.is_null() {
+        return Err(Error::last_os_error().into());
+    }
+    let job = Handle(job);
+
+    let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { zeroed() };
+    info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+    check(unsafe {
+        SetInformationJobObject(
+            job.0,
+            JobObjectExtendedLimitInformation,
+            &info as *const _ as *const c_void,
+            size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+        )
+    })?;
+    check(unsafe { AssignProcessToJobObject(job.0, GetCurrentProcess()) })?;
+
+    let _ = JOB.set(job);
+    Ok(())
+}
+
+pub async fn reap_children() -> Result<()> {
+    let Some(job) = JOB.get() else {
+        return Ok(());
+    };
+
+    #[repr(C)]
+    struct ProcessIdList {
+        assigned: u32,
+        listed: u32,
+        ids: [usize; MAX_PROCESSES],
+    }
+
+    let pid = std::process::id() as usize;
+    loop {
+        let mut list: ProcessIdList = unsafe { zeroed() };
+        check(unsafe {
+            QueryInformationJobObject(
+                job.0,
+                JobObjectBasicProcessIdList,
+                &mut list as *mut _ as *mut c_void,
+                size_of::<ProcessIdList>() as u32,
+                null_mut(),
+            )
+        })?;
+
+        let children: Vec<_> = list.ids[..list.listed as usize]
+            .iter()
+            .copied()
+            .filter(|id| *id != pid)
+            .collect();
+
+        if children.is_empty() {
+            return Ok(());
+        }
+
+        for pid in children {
+            let Some(process) = open_process(pid as u32, PROCESS_TERMINATE | PROCESS_SYNCHRONIZE)
+            else {
+                continue;
+            };
+            unsafe {
+                TerminateProcess(process.0, 1);
+                WaitForSingleObject(process.0, INFINITE);
+            }
+        }
+    }
+}
+
+fn open_process(pid: u32, access: u32) -> Option<Handle> {
+    let process = unsafe { OpenProcess(access, 0, pid) };
+    (!process.is_null()).then_some(Handle(process))
+}
+
+/// Wait for the process `pid` to exit
+pub async fn wait_for_exit(pid: u32) {
+    // the process is gone if it can't be opened
+    let Some(process) = open_process(pid, PROCESS_SYNCHRONIZE) else {
+        return;
+    };
+    while unsafe { WaitForSingleObject(process.0, 0) } != WAIT_OBJECT_0 {
+        sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Number of the signal named `name`, like `SIGTERM`.
+/// Windows has no signals, the shims map these numbers to their own semantics.
+pub fn signal_from_name(name: &str) -> Option<u32> {
+    let signal = match name {
+        "SIGHUP" => 1,
+        "SIGINT" => 2,
+        "SIGQUIT" => 3,
+        "SIGKILL" => 9,
+        "SIGTERM" => 15,
+        _ => return None,
+    };
+    Some(signal)
+}
+
+/// Name of a named pipe unique to the directory `dir`
+fn pipe_name(dir: &Path, suffix: &str) -> String {
+    let name = dir.file_name().unwrap_or_default().to_string_lossy();
+    format!(
+        r"\\.\pipe\stress-test-{}-{name}-{suffix}",
+        std::process::id()
+    )
+}
+
+/// Address the mock containerd serves its ttrpc services on, for the shims in `dir`
+pub fn ttrpc_address(dir: &Path) -> String {
+    pipe_name(dir, "ttrpc")
+}
+
+/// Address to bind the ttrpc server of the mock containerd to
+pub fn ttrpc_listen_address(dir: &Path) -> String {
+    ttrpc_address(dir)
+}
+
+pub fn page_size() -> Result<u64> {
+    bail!("sampling resources is not supported on Windows")
+}
+
+pub fn clock_ticks() -> Result<u64> {
+    bail!("sampling resources is not supported on Windows")
+}
+
+/// The shims serve their log on a named pipe of their own on Windows, which isn't captured
+pub async fn link_shim_log(_dir: &Path, log: Option<&Path>) -> Result<()> {
+    if log.is_some() {
+        log::warn!("the shim logs aren't captured on Windows");
+    }
+    Ok(())
+}
+
+pub fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    // the target is relative to the directory of the link
+    let resolved = link.parent().unwrap_or(Path::new(".")).join(target);
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+/// The rootfs of the tasks aren't mounted by the harness on Windows
+pub fn unmount_recursive(_root: impl AsRef<Path>) -> Result<()> {
+    Ok(())
+}
+
+/// Server end of the stdin named pipe of a task.
+#[derive(Default)]
+pub struct StdinPipe(std::sync::Mutex<Option<NamedPipeServer>>);
+
+impl StdinPipe {
+    /// Create a named pipe for the bundle `dir`, and return its name.
+    /// Creating the pipe doesn't block, the shim connects to it when it creates the task.
+    pub fn open(&self, dir: &Path) -> Result<String> {
+        let name = pipe_name(dir, "stdin");
+        // close the pipe of a previous cycle, so that the new one can be created with the same name
+        self.0.lock().unwrap().take();
+        let server = ServerOptions::new().access_inbound(false).create(&name)?;
+        *self.0.lock().unwrap() = Some(server);
+        Ok(name)
+    }
+
+    /// Write `data` and close the pipe, so that the task reads the end of its input.
+    /// This blocks until the shim has connected and the task has read all but a pipe buffer of `data`.
+    pub async fn write(&self, data: &[u8]) -> Result<()> {
+        let mut server = self
+            .0
+            .lock()
+            .unwrap()
+            .take()
+            .context("the task has no stdin")?;
+        server.connect().await?;
+        server.write_all(data).await?;
+        Ok(())
+    }
+}
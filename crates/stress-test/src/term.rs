@@ -0,0 +1,101 @@
+use std::io::IsTerminal as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static STDOUT_COLOR: AtomicBool = AtomicBool::new(false);
+static STDERR_COLOR: AtomicBool = AtomicBool::new(false);
+static STDERR_TTY: AtomicBool = AtomicBool::new(false);
+
+/// Enable colors on the streams connected to a terminal, unless `no_color` is set.
+pub fn init(no_color: bool) {
+    let stdout_tty = std::io::stdout().is_terminal();
+    let stderr_tty = std::io::stderr().is_terminal();
+    STDOUT_COLOR.store(stdout_tty && !no_color, Ordering::Relaxed);
+    STDERR_COLOR.store(stderr_tty && !no_color, Ordering::Relaxed);
+    STDERR_TTY.store(stderr_tty, Ordering::Relaxed);
+}
+
+pub fn stderr_is_tty() -> bool {
+    STDERR_TTY.load(Ordering::Relaxed)
+}
+
+/// Print to stdout, with the escape sequences removed if colors are disabled
+macro_rules! out {
+    ($($arg:tt)*) => {
+        $crate::term::write_out(::std::format!($($arg)*), false)
+    };
+}
+
+/// Print a line to stdout, with the escape sequences removed if colors are disabled
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        $crate::term::write_out(::std::format!($($arg)*), true)
+    };
+}
+
+/// Print a line to stderr, with the escape sequences removed if colors are disabled.
+/// On a terminal this replaces the progress line, which is redrawn on its next update.
+macro_rules! errln {
+    ($($arg:tt)*) => {
+        $crate::term::write_err(::std::format!($($arg)*))
+    };
+}
+
+pub(crate) use errln;
+pub(crate) use out;
+pub(crate) use outln;
+
+pub fn write_out(text: String, newline: bool) {
+    let text = styled(text, STDOUT_COLOR.load(Ordering::Relaxed));
+    if newline {
+        println!("{text}");
+    } else {
+        print!("{text}");
+    }
+}
+
+pub fn write_err(line: String) {
+    let line = styled(line, STDERR_COLOR.load(Ordering::Relaxed));
+    if stderr_is_tty() {
+        eprint!("\r\x1b[2K");
+    }
+    eprintln!("{line}");
+}
+
+fn styled(text: String, color: bool) -> String {
+    if color { text } else { strip_ansi(&text) }
+}
+
+/// Remove the CSI escape sequences, like colors and cursor movements, from `text`
+fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        if chars.next() != Some('[') {
+            continue;
+        }
+        // parameters and intermediate bytes up to the final byte of the sequence
+        for c in chars.by_ref() {
+            if ('\x40'..='\x7e').contains(&c) {
+                break;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::strip_ansi;
+
+    #[test]
+    fn strip_escape_sequences() {
+        assert_eq!(strip_ansi("\x1b[32m  elapsed\x1b[0m"), "  elapsed");
+        assert_eq!(strip_ansi("\x1b[1;31mFirst error\x1b[0m"), "First error");
+        assert_eq!(strip_ansi("\x1b[A\x1b[2Kdone"), "done");
+        assert_eq!(strip_ansi("no escapes"), "no escapes");
+    }
+}
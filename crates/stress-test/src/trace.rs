@@ -0,0 +1,105 @@
+//! OpenTelemetry spans of the task lifecycles, exported with --otlp-endpoint.
+//! Without it nothing is initialized, and recording a span is a single check.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use opentelemetry::global::{self, BoxedTracer};
+use opentelemetry::trace::{Span as _, Status, TraceContextExt as _, Tracer as _};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig as _;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{Resource, runtime, trace as sdktrace};
+
+/// Context of the span covering the whole run, the parent of all the task spans
+static RUN: OnceLock<Context> = OnceLock::new();
+
+/// Exports the remaining spans when dropped.
+#[must_use]
+pub struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(run) = RUN.get() {
+            run.span().end();
+        }
+        global::shutdown_tracer_provider();
+    }
+}
+
+/// Install an exporter sending the spans to the OTLP gRPC `endpoint`, and start the span of the run.
+pub fn init(endpoint: &str) -> Result<Guard> {
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(endpoint);
+    let resource = Resource::new([KeyValue::new("service.name", "stress-test")]);
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(sdktrace::config().with_resource(resource))
+        .install_batch(runtime::Tokio)?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let span = tracer().start("run");
+    let _ = RUN.set(Context::current_with_span(span));
+    Ok(Guard)
+}
+
+/// Environment of the shims, so that they export their spans to the same
+/// endpoint as children of the run's span.
+/// The shims read the parent span from `TRACECONTEXT` when they start.
+pub fn shim_env(endpoint: &str) -> Vec<(&'static str, String)> {
+    let Some(run) = RUN.get() else {
+        return vec![];
+    };
+    let mut headers = HashMap::<String, String>::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(run, &mut headers));
+    vec![
+        ("OTEL_EXPORTER_OTLP_ENDPOINT", endpoint.to_string()),
+        ("OTEL_EXPORTER_OTLP_PROTOCOL", "grpc".to_string()),
+        ("TRACECONTEXT", serde_json::to_string(&headers).unwrap()),
+    ]
+}
+
+/// Start the span of a task, or `None` if tracing is disabled
+pub fn task(index: usize, id: &str) -> Option<Context> {
+    let run = RUN.get()?;
+    let tracer = tracer();
+    let span = tracer
+        .span_builder("task")
+        .with_attributes([
+            KeyValue::new("task.index", index as i64),
+            KeyValue::new("task.id", id.to_string()),
+        ])
+        .start_with_context(&tracer, run);
+    Some(run.with_span(span))
+}
+
+/// End the span of a task, marking it as failed if `res` is an error
+pub fn finish<T>(cx: &Context, res: &Result<T>) {
+    let span = cx.span();
+    if let Err(err) = res {
+        span.set_status(Status::error(format!("{err:#}")));
+    }
+    span.end();
+}
+
+/// Run `fut` in a span named `name`, a child of the span of the current task
+pub async fn step<T>(name: &'static str, fut: impl Future<Output = Result<T>>) -> Result<T> {
+    if RUN.get().is_none() {
+        return fut.await;
+    }
+    let mut span = tracer().start_with_context(name, &Context::current());
+    let res = fut.await;
+    if let Err(err) = &res {
+        span.set_status(Status::error(format!("{err:#}")));
+    }
+    span.end();
+    res
+}
+
+fn tracer() -> BoxedTracer {
+    global::tracer("stress-test")
+}
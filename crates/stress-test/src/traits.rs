@@ -1,11 +1,29 @@
-use std::path::Path;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
+use oci_spec::runtime::{LinuxCpuBuilder, LinuxMemoryBuilder, Spec};
+use serde::Serialize;
+use tokio::time::Duration;
+
+use crate::stats::RpcLatencies;
 
 #[trait_variant::make(Send)]
 pub trait Containerd {
     type Shim: Shim;
     async fn start_shim(&self, shim: impl AsRef<Path> + Send) -> Result<Self::Shim>;
+    /// Faults injected so far in the calls made by the shim
+    fn faults(&self) -> Faults;
+    /// Latencies of the calls made to the shims, if recorded
+    fn rpc_latencies(&self) -> Option<RpcLatencies>;
+}
+
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct Faults {
+    /// Calls whose response was delayed
+    pub delayed: usize,
+    /// Calls that failed with an injected error
+    pub errors: usize,
 }
 
 #[trait_variant::make(Send)]
@@ -13,15 +31,185 @@ pub trait Shim {
     type Task: Task;
     async fn task<T: Into<String>>(
         &self,
-        image: impl Into<String> + Send,
+        source: Source,
         args: impl IntoIterator<Item = T> + Send,
+        env: &[String],
+        limits: Limits,
+        stdin: bool,
     ) -> Result<Self::Task>;
+    /// Ask the shim to shut down gracefully, once all its tasks are deleted
+    async fn shutdown(&self) -> Result<()>;
+    /// Time from spawning the shim process to its first successful response,
+    /// or `None` if the shim is only spawned when the first task is created
+    fn startup(&self) -> Option<Duration>;
+    /// Whether the shim process still answers calls,
+    /// or `None` if containerd spawns the shim again as needed
+    async fn alive(&self) -> Option<bool>;
+}
+
+/// Resource limits written into the spec of every task.
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    /// Memory limit in bytes
+    pub memory: Option<i64>,
+    /// CPU quota in percent of a CPU
+    pub cpu_quota: Option<f64>,
+}
+
+impl Limits {
+    /// Set the limits in the `linux.resources` section of `spec`
+    pub fn apply(&self, spec: &mut Spec) -> Result<()> {
+        if self.memory.is_none() && self.cpu_quota.is_none() {
+            return Ok(());
+        }
+        // keep the other resources a bundle's spec may already set
+        let mut linux = spec.linux().clone().unwrap_or_default();
+        let mut resources = linux.resources().clone().unwrap_or_default();
+        if let Some(limit) = self.memory {
+            resources.set_memory(Some(LinuxMemoryBuilder::default().limit(limit).build()?));
+        }
+        if let Some(percent) = self.cpu_quota {
+            const PERIOD: u64 = 100_000;
+            let quota = (percent / 100.0 * PERIOD as f64) as i64;
+            let cpu = LinuxCpuBuilder::default()
+                .period(PERIOD)
+                .quota(quota)
+                .build()?;
+            resources.set_cpu(Some(cpu));
+        }
+        linux.set_resources(Some(resources));
+        spec.set_linux(Some(linux));
+        Ok(())
+    }
+}
+
+/// Where the spec and root filesystem of a task come from.
+#[derive(Clone)]
+pub enum Source {
+    /// An image available in containerd's content store
+    Image(String),
+    /// A prepared OCI bundle directory with a `config.json` and a `rootfs`
+    Bundle(PathBuf),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Image(image) => f.write_str(image),
+            Source::Bundle(bundle) => write!(f, "{}", bundle.display()),
+        }
+    }
 }
 
 #[trait_variant::make(Send)]
 pub trait Task {
+    fn id(&self) -> &str;
     async fn create(&self) -> Result<()>;
     async fn start(&self) -> Result<()>;
-    async fn wait(&self) -> Result<()>;
+    async fn wait(&self) -> Result<Exit>;
     async fn delete(&self) -> Result<()>;
+    /// Make a deleted task ready to be created again with the same id
+    async fn reset(&self);
+    async fn state(&self) -> Result<State>;
+    async fn exec(&self, args: &[String]) -> Result<Exit>;
+    async fn kill(&self, signal: u32) -> Result<()>;
+    async fn stats(&self) -> Result<()>;
+    async fn pause(&self) -> Result<()>;
+    async fn resume(&self) -> Result<()>;
+    /// Write `data` to the stdin of a task created with a stdin pipe, and close it
+    async fn write_stdin(&self, data: &[u8]) -> Result<()>;
+    /// PID of the shim process serving this task
+    async fn shim_pid(&self) -> Result<u32>;
+}
+
+/// Error returned by a `Task` when the shim doesn't implement the requested operation.
+#[derive(Debug)]
+pub struct Unimplemented;
+
+impl fmt::Display for Unimplemented {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("operation not implemented by the shim")
+    }
+}
+
+impl std::error::Error for Unimplemented {}
+
+pub struct Exit {
+    pub status: u32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Exit {
+    pub fn success(&self) -> Result<()> {
+        self.expect(0)
+    }
+
+    pub fn expect(&self, expected: u32) -> Result<()> {
+        if self.status != expected {
+            return Err(UnexpectedExit {
+                status: self.status,
+                expected,
+                stdout: self.stdout.clone(),
+                stderr: self.stderr.clone(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Error of a task or exec whose exit status isn't the expected one.
+#[derive(Debug)]
+pub struct UnexpectedExit {
+    status: u32,
+    expected: u32,
+    stdout: String,
+    stderr: String,
+}
+
+impl fmt::Display for UnexpectedExit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Self {
+            status,
+            expected,
+            stdout,
+            stderr,
+        } = self;
+        write!(
+            f,
+            "Exit status {status} (expected {expected}), stdout: {stdout:?}, stderr: {stderr:?}"
+        )
+    }
+}
+
+impl std::error::Error for UnexpectedExit {}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+    Unknown,
+    Created,
+    Running,
+    Stopped,
+    Paused,
+    Pausing,
+}
+
+impl From<i32> for Status {
+    fn from(status: i32) -> Self {
+        // values as defined in containerd's `containerd.v1.types.Status`
+        match status {
+            1 => Status::Created,
+            2 => Status::Running,
+            3 => Status::Stopped,
+            4 => Status::Paused,
+            5 => Status::Pausing,
+            _ => Status::Unknown,
+        }
+    }
+}
+
+pub struct State {
+    pub status: Status,
+    pub exit_status: u32,
 }
@@ -1,42 +1,89 @@
 use std::future::{Future, pending};
+use std::path::Path;
+use std::sync::LazyLock;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
-use nix::sys::signal::Signal::SIGKILL;
-use nix::sys::signal::kill;
-use nix::sys::wait::{WaitPidFlag, waitpid};
-use nix::unistd::Pid;
+use anyhow::{Context as _, Result, ensure};
+use oci_spec::runtime::{RootBuilder, Spec};
 use tokio::sync::Mutex;
 use tokio::time::sleep;
 
+use crate::sys;
+
 static COUNTER: AtomicUsize = AtomicUsize::new(0);
+static SHIMS: AtomicUsize = AtomicUsize::new(0);
+
+/// Random id of this run, so that containers left behind by a previous
+/// run that had the same pid don't collide with the ones of this run
+static RUN_ID: LazyLock<String> = LazyLock::new(|| {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{:08x}", now.as_nanos() as u32)
+});
+
+/// Seed for the pseudo-random choices of a run, when none is given
+pub fn random_seed() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_nanos() as u64 ^ ((std::process::id() as u64) << 32)
+}
 
 pub fn make_task_id() -> String {
     let pid = std::process::id();
+    let run_id = &*RUN_ID;
     let n = COUNTER.fetch_add(1, Ordering::SeqCst);
-    format!("shim-stress-test-{pid}-task-{n}")
+    format!("shim-stress-test-{pid}-{run_id}-task-{n}")
 }
 
-pub async fn reap_children() -> Result<()> {
-    let pid = std::process::id();
-    loop {
-        let list: Vec<u32> = tokio::fs::read_to_string(format!("/proc/{pid}/task/{pid}/children"))
-            .await?
-            .split_whitespace()
-            .filter_map(|x| x.parse().ok())
-            .collect();
-
-        if list.is_empty() {
-            return Ok(());
-        }
+/// Index of a new shim instance, to name the resources of each shim of this run apart
+pub fn make_shim_index() -> usize {
+    SHIMS.fetch_add(1, Ordering::SeqCst)
+}
 
-        for pid in list {
-            let pid = Pid::from_raw(pid as _);
-            let _ = kill(pid, SIGKILL);
-            let _ = waitpid(pid, Some(WaitPidFlag::WNOHANG));
-        }
+pub fn make_exec_id() -> String {
+    let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+    format!("exec-{n}")
+}
+
+/// Parse a fraction between 0 and 1
+pub fn parse_fraction(s: &str) -> Result<f64> {
+    let fraction: f64 = s.parse()?;
+    ensure!(
+        (0.0..=1.0).contains(&fraction),
+        "expected a value between 0 and 1"
+    );
+    Ok(fraction)
+}
+
+/// Parse a positive percentage, possibly above 100 for more than one CPU
+pub fn parse_percent(s: &str) -> Result<f64> {
+    let percent: f64 = s.parse()?;
+    ensure!(percent > 0.0, "expected a percentage above 0");
+    Ok(percent)
+}
+
+/// Parse an environment variable given as `KEY=VALUE`
+pub fn parse_env(s: &str) -> Result<String> {
+    let (key, _) = s.split_once('=').context("expected KEY=VALUE")?;
+    ensure!(!key.is_empty(), "expected a non-empty KEY in KEY=VALUE");
+    Ok(s.to_string())
+}
+
+/// Parse a signal given by number, or by name with or without the `SIG` prefix
+pub fn parse_signal(s: &str) -> Result<u32> {
+    if let Ok(signal) = s.parse() {
+        return Ok(signal);
     }
+    let name = s.to_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{name}")
+    };
+    sys::signal_from_name(&name).with_context(|| format!("unknown signal {s}"))
 }
 
 pub async fn watchdog(timeout: Duration) {
@@ -62,4 +109,70 @@ impl RunOnce {
         }
         Ok(())
     }
+
+    /// Allow the operation to run again
+    pub async fn reset(&self) {
+        *self.0.lock().await = false;
+    }
+}
+
+/// `len` bytes of printable text to feed to the tasks stdin
+pub fn generate_stdin(len: usize) -> Vec<u8> {
+    b"the quick brown fox jumps over the lazy dog\n"
+        .iter()
+        .copied()
+        .cycle()
+        .take(len)
+        .collect()
+}
+
+/// Copy the root filesystem of the OCI bundle at `src` into `dst`, and return
+/// the bundle's spec pointing at the copy, with its process args replaced by `args`
+/// unless `args` is empty, and the variables in `env` added to its environment.
+pub async fn copy_bundle(
+    src: &Path,
+    dst: &Path,
+    args: Vec<String>,
+    env: &[String],
+) -> Result<Spec> {
+    let mut spec = Spec::load(src.join("config.json"))
+        .with_context(|| format!("failed to load the spec of bundle {}", src.display()))?;
+
+    let (src, dst) = (src.join("rootfs"), dst.join("rootfs"));
+    tokio::task::spawn_blocking(move || copy_dir(&src, &dst)).await??;
+
+    let mut process = spec.process().clone().unwrap_or_default();
+    if !args.is_empty() {
+        process.set_args(Some(args));
+    }
+    if !env.is_empty() {
+        // the variables given on the command line override those of the bundle
+        let mut vars = process.env().clone().unwrap_or_default();
+        vars.retain(|var| {
+            let key = var.split('=').next();
+            !env.iter().any(|e| e.split('=').next() == key)
+        });
+        vars.extend_from_slice(env);
+        process.set_env(Some(vars));
+    }
+    spec.set_process(Some(process));
+    spec.set_root(Some(RootBuilder::default().path("rootfs").build()?));
+    Ok(spec)
+}
+
+fn copy_dir(src: &Path, dst: &Path) -> Result<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let (src, dst) = (entry.path(), dst.join(entry.file_name()));
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir(&src, &dst)?;
+        } else if file_type.is_symlink() {
+            sys::symlink(&std::fs::read_link(&src)?, &dst)?;
+        } else {
+            std::fs::copy(&src, &dst)?;
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,868 @@
+use std::future::{Future, pending};
+use std::pin::pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
+
+use anyhow::{Context as _, Error, Result};
+use futures::future::{FusedFuture as _, join_all};
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt as _, StreamExt as _};
+use humantime::format_duration;
+use tokio::signal::ctrl_c;
+use tokio::sync::{Barrier, OnceCell, Semaphore};
+use tokio::time::{Duration, interval, interval_at, sleep};
+
+use crate::csv::CsvWriter;
+use crate::failure::{Category, Failures};
+use crate::logs::LogDir;
+use crate::metrics::Metrics;
+use crate::progress::{Counts, Progress};
+use crate::ramp::Ramp;
+use crate::stats::{TaskRecord, Timings};
+use crate::term::errln;
+use crate::traits::{Limits, Shim, Source, State, Status, Task, Unimplemented};
+use crate::utils::watchdog;
+use crate::{Step, trace};
+
+/// What each task runs, shared by all the waves of a stress test.
+pub struct Workload {
+    /// Images assigned round-robin to the tasks
+    pub images: Vec<Image>,
+    pub timeout: Duration,
+    /// Time limit of each step of a task, failing only that task when exceeded
+    pub task_timeout: Option<Duration>,
+    /// Last step of the lifecycle to run and measure
+    pub until: Step,
+    /// Number of times each task runs its whole lifecycle with the same id
+    pub cycles: usize,
+    /// Number of concurrent execs to run in each task while it's running
+    pub exec_count: usize,
+    /// Arguments of the execs, empty to run the task's own arguments
+    pub exec_args: Vec<String>,
+    /// Data written to the stdin of each task once started
+    pub stdin: Option<Vec<u8>>,
+    /// Environment variables of the tasks, as `KEY=VALUE`
+    pub env: Vec<String>,
+    pub limits: Limits,
+    pub kill: Option<Kill>,
+    /// Interval between stats requests to each running task
+    pub stats_interval: Option<Duration>,
+    pub pause_resume: PauseResume,
+    pub retry: Retry,
+    /// Stop the wave on the first failed task
+    pub fail_fast: bool,
+    /// Check the state of each task between the steps of its lifecycle
+    pub verify: bool,
+    pub jitter: Option<Jitter>,
+    /// Live metrics updated as tasks start and complete
+    pub metrics: Option<Arc<Metrics>>,
+    pub text: bool,
+}
+
+/// Retry policy for steps failing with transient errors.
+pub struct Retry {
+    pub attempts: usize,
+    /// Only retry errors whose message contains one of these, or any error if empty
+    pub on: Vec<String>,
+}
+
+impl Retry {
+    fn matches(&self, err: &Error) -> bool {
+        let message = format!("{err:#}");
+        self.on.is_empty() || self.on.iter().any(|on| message.contains(on.as_str()))
+    }
+
+    /// Run and time `step`, retrying it with exponential backoff.
+    /// Outside of the concurrency slot of the task, each retry waits for a
+    /// slot of its own in `semaphore` so that retries don't exceed the parallelism.
+    /// An attempt running longer than `timeout` fails with `StepTimeout`, which is not retried.
+    async fn time<T, F: Future<Output = Result<T>>>(
+        &self,
+        step: Step,
+        timeout: Option<Duration>,
+        timings: &mut Timings,
+        retries: &mut usize,
+        semaphore: Option<&Semaphore>,
+        mut f: impl FnMut() -> F,
+    ) -> Result<T> {
+        let mut backoff = Duration::from_millis(100);
+        let mut res = timings
+            .time(step, trace::step(step.name(), limit(step, timeout, f())))
+            .await;
+        for _ in 0..self.attempts {
+            match &res {
+                Err(err) if !err.is::<StepTimeout>() && self.matches(err) => {
+                    log::debug!("retrying {} in {backoff:?}: {err:#}", step.name());
+                }
+                _ => break,
+            }
+            sleep(backoff).await;
+            backoff *= 2;
+            *retries += 1;
+            let _permit = match semaphore {
+                Some(semaphore) => Some(semaphore.acquire().await?),
+                None => None,
+            };
+            res = timings
+                .time(step, trace::step(step.name(), limit(step, timeout, f())))
+                .await;
+        }
+        res
+    }
+}
+
+/// Error of a step that didn't complete within the per-task timeout.
+#[derive(Debug)]
+pub struct StepTimeout {
+    step: &'static str,
+    after: Duration,
+}
+
+impl std::fmt::Display for StepTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let Self { step, after } = self;
+        write!(f, "timed out in {step} after {}", format_duration(*after))
+    }
+}
+
+impl std::error::Error for StepTimeout {}
+
+async fn limit<T>(
+    step: Step,
+    timeout: Option<Duration>,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(after) = timeout else {
+        return fut.await;
+    };
+    match tokio::time::timeout(after, fut).await {
+        Ok(res) => res,
+        Err(_) => Err(StepTimeout {
+            step: step.name(),
+            after,
+        }
+        .into()),
+    }
+}
+
+impl Workload {
+    fn image(&self, index: usize) -> &Image {
+        &self.images[index % self.images.len()]
+    }
+}
+
+/// An image or bundle, and the arguments of the tasks created from it.
+pub struct Image {
+    pub source: Source,
+    pub args: Vec<String>,
+}
+
+/// Pause/resume cycles issued to each running task.
+pub struct PauseResume {
+    cycles: usize,
+    /// Cleared once the shim reports that it doesn't implement pause
+    supported: AtomicBool,
+}
+
+impl PauseResume {
+    pub fn new(cycles: usize) -> Self {
+        Self {
+            cycles,
+            supported: AtomicBool::new(true),
+        }
+    }
+
+    async fn run(&self, task: &impl Task, text: bool) -> Result<()> {
+        for _ in 0..self.cycles {
+            if !self.supported.load(Ordering::Relaxed) {
+                break;
+            }
+            match task.pause().await {
+                Err(err) if err.is::<Unimplemented>() => {
+                    // only warn for the first task to find out
+                    if self.supported.swap(false, Ordering::Relaxed) {
+                        log::warn!("the shim doesn't implement pause, skipping --pause-resume");
+                        if text {
+                            errln!(
+                                "> \x1b[33mpause not implemented, skipping --pause-resume\x1b[0m"
+                            );
+                        }
+                    }
+                    break;
+                }
+                res => res?,
+            }
+            task.resume().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Error of a task found in a state it shouldn't be in, with --verify.
+#[derive(Debug)]
+pub struct UnexpectedState(String);
+
+impl std::fmt::Display for UnexpectedState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UnexpectedState {}
+
+/// Error of a task killed with SIGKILL while running under a memory limit.
+#[derive(Debug)]
+pub struct OomKilled;
+
+impl std::fmt::Display for OomKilled {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("exit status 137, the task was likely OOM killed")
+    }
+}
+
+impl std::error::Error for OomKilled {}
+
+/// Signal tasks after a delay instead of waiting for them to exit on their own.
+#[derive(Clone, Copy)]
+pub struct Kill {
+    pub after: Duration,
+    pub signal: u32,
+    /// Fraction of the tasks that get signaled
+    pub fraction: f64,
+    /// Expected exit status of the signaled tasks [default: 128 + signal]
+    pub exit_code: Option<u32>,
+}
+
+impl Kill {
+    /// Spread the signaled tasks evenly across the task indices
+    fn selects(&self, index: usize) -> bool {
+        let before = (index as f64 * self.fraction).floor();
+        let after = ((index + 1) as f64 * self.fraction).floor();
+        before != after
+    }
+}
+
+/// Seeded pseudo-random delays that shake up the interleaving of the tasks,
+/// while keeping runs with the same seed reproducible.
+#[derive(Clone, Copy)]
+pub struct Jitter {
+    pub max: Duration,
+    pub seed: u64,
+}
+
+impl Jitter {
+    /// Delay of the task `index` at the given point of its lifecycle,
+    /// only depending on the seed, the index and the point.
+    fn delay(&self, index: usize, point: u64) -> Duration {
+        let x = splitmix64(self.seed ^ splitmix64(((index as u64) << 8) | point));
+        self.max.mul_f64(x as f64 / u64::MAX as f64)
+    }
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// A single batch of tasks run against a shim.
+pub struct Wave {
+    pub count: usize,
+    pub parallel: usize,
+    pub duration: Option<Duration>,
+    pub ramp: Option<Ramp>,
+    /// Latest RSS of the shim, sampled in the background
+    pub shim_rss: Option<Arc<AtomicU64>>,
+}
+
+pub struct WaveResult {
+    pub count: usize,
+    pub success: usize,
+    pub failed: usize,
+    pub elapsed: Option<Duration>,
+    pub timings: Vec<Timings>,
+    pub errors: Vec<String>,
+    pub exec_success: usize,
+    pub exec_errors: Vec<String>,
+    pub stats: Vec<Duration>,
+    /// Outcome of the tasks of each image, in the order of the workload's images
+    pub per_image: Vec<Outcomes>,
+    /// Outcome of the tasks assigned to each shim process
+    pub per_shim: Vec<Outcomes>,
+    /// Tasks that succeeded after retrying some of their steps
+    pub retried: usize,
+    /// Failed tasks that were killed for exceeding the memory limit
+    pub oom_killed: usize,
+    /// Failed tasks counted by the category of their error
+    pub failures: Failures,
+    /// Total time of each completed cycle by cycle number, with more than one cycle
+    pub cycles: Vec<Vec<Duration>>,
+    /// The wave was stopped by a timeout or by the user before all tasks finished
+    pub interrupted: bool,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct Outcomes {
+    pub success: usize,
+    pub failed: usize,
+}
+
+impl WaveResult {
+    pub fn incomplete(&self) -> usize {
+        self.count - self.success - self.failed
+    }
+
+    pub fn throughput(&self) -> Option<f64> {
+        self.elapsed
+            .map(|elapsed| self.success as f64 / elapsed.as_secs_f64())
+    }
+}
+
+/// Run the complete lifecycle of a task of the `n`th image, outside of any wave,
+/// returning how long it took from its creation to its deletion
+pub async fn run_lifecycle<S: Shim>(
+    shim: &Arc<S>,
+    workload: &Workload,
+    n: usize,
+) -> Result<Duration> {
+    let image = workload.image(n);
+    let task = shim
+        .task(
+            image.source.clone(),
+            &image.args,
+            &workload.env,
+            workload.limits,
+            false,
+        )
+        .await?;
+    let start = Instant::now();
+    task.create().await?;
+    task.start().await?;
+    task.wait().await?.success()?;
+    task.delete().await?;
+    Ok(start.elapsed())
+}
+
+/// Run `iterations` complete task lifecycles one after the other, to pay the
+/// one-time costs of a cold shim before anything gets measured.
+/// Returns the errors of the failed iterations.
+pub async fn run_warmup<S: Shim>(
+    shim: &Arc<S>,
+    workload: &Workload,
+    iterations: usize,
+) -> Vec<anyhow::Error> {
+    let mut errors = vec![];
+    for n in 0..iterations {
+        if let Err(err) = run_lifecycle(shim, workload, n).await {
+            if workload.text {
+                errln!("> \x1b[33mwarmup {n} .. {err}\x1b[0m");
+            }
+            errors.push(err);
+        }
+    }
+    errors
+}
+
+/// Run a wave of tasks, assigned round-robin to `shims`.
+pub async fn run_wave<S: Shim>(
+    shims: &[Arc<S>],
+    workload: &Workload,
+    wave: &Wave,
+    csv: &mut Option<CsvWriter>,
+    logs: &mut Option<LogDir>,
+) -> Result<WaveResult> {
+    let Workload {
+        images,
+        timeout,
+        task_timeout,
+        until,
+        cycles,
+        exec_count,
+        exec_args,
+        stdin,
+        env,
+        limits,
+        kill,
+        stats_interval,
+        pause_resume,
+        retry,
+        fail_fast,
+        verify,
+        jitter,
+        metrics,
+        text,
+    } = workload;
+    let &Wave {
+        count,
+        parallel,
+        duration: run_duration,
+        ramp,
+        ref shim_rss,
+    } = wave;
+    let (timeout, task_timeout, until, cycles, exec_count) =
+        (*timeout, *task_timeout, *until, *cycles, *exec_count);
+    let (limits, kill) = (*limits, *kill);
+    let (stats_interval, fail_fast, verify, jitter, text) =
+        (*stats_interval, *fail_fast, *verify, *jitter, *text);
+
+    // In duration mode tasks are created on demand, so there's nothing to set up upfront
+    let setup_count = if run_duration.is_some() { 0 } else { count };
+    let permits = match ramp {
+        Some(ramp) => ramp.start,
+        None if parallel == 0 => count,
+        None => parallel,
+    };
+    let semaphore = Arc::new(Semaphore::new(permits));
+    let level = Arc::new(AtomicUsize::new(permits));
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let barrier = Arc::new(Barrier::new(setup_count + 1));
+    let start = Arc::new(OnceCell::new());
+    let mut tracker = FuturesUnordered::new();
+    let setup_start = Instant::now();
+
+    let run_task = |index: usize, barrier: Option<Arc<Barrier>>| {
+        let shim = shims[index % shims.len()].clone();
+        let image = workload.image(index);
+        let exec_args = if exec_args.is_empty() {
+            image.args.clone()
+        } else {
+            exec_args.clone()
+        };
+        let semaphore = semaphore.clone();
+        let level = level.clone();
+        let start = start.clone();
+        let metrics = metrics.clone();
+        let in_flight = in_flight.clone();
+        async move {
+            let mut record = TaskRecord::new(index, image.source.to_string());
+            // tasks stopped before `delete` are returned to be cleaned up later
+            let res: Result<Option<S::Task>> = async {
+                // create the tasks bundles before starting measuring the benchmark
+                // this is not work done by the shim itself
+                let task = shim
+                    .task(
+                        image.source.clone(),
+                        &image.args,
+                        env,
+                        limits,
+                        stdin.is_some(),
+                    )
+                    .await?;
+                record.id = Some(task.id().to_string());
+
+                // wait for all tasks to be set up
+                if let Some(barrier) = barrier {
+                    barrier.wait().await;
+                }
+
+                // jitter outside of the concurrency slot, so that it doesn't serialize the tasks
+                if let Some(jitter) = jitter {
+                    sleep(jitter.delay(index, 0)).await;
+                }
+
+                let span = trace::task(index, task.id());
+                let lifecycle = async {
+                    // Wait for a concurrentcy slot
+                    let mut permit = Some(semaphore.clone().acquire_owned().await?);
+                    let _ = start.set(Instant::now());
+                    record.concurrency = Some(level.load(Ordering::Relaxed));
+                    in_flight.fetch_add(1, Ordering::Relaxed);
+                    if let Some(metrics) = &metrics {
+                        metrics.task_started();
+                    }
+
+                    for cycle in 0..cycles {
+                        record.cycle = cycle;
+                        if cycle > 0 {
+                            // start over with the same id, as a restarted container does
+                            record.cycles.push(std::mem::take(&mut record.timings));
+                            task.reset().await;
+                            permit = Some(semaphore.clone().acquire_owned().await?);
+                        }
+
+                        let timings = &mut record.timings;
+                        let retries = &mut record.retries;
+                        retry
+                            .time(Step::Create, task_timeout, timings, retries, None, || {
+                                task.create()
+                            })
+                            .await?;
+                        if verify {
+                            verify_state(&task, Step::Create, &[Status::Created]).await?;
+                        }
+                        if until == Step::Create {
+                            return Ok(Some(task));
+                        }
+                        retry
+                            .time(Step::Start, task_timeout, timings, retries, None, || {
+                                task.start()
+                            })
+                            .await?;
+                        if verify {
+                            // a short lived task can legitimately exit before the state is queried
+                            verify_state(&task, Step::Start, &[Status::Running, Status::Stopped])
+                                .await?;
+                        }
+
+                        // release the concurrency slot
+                        drop(permit.take());
+
+                        if let Some(jitter) = jitter {
+                            sleep(jitter.delay(index, 1)).await;
+                        }
+
+                        if let Some(stdin) = stdin {
+                            task.write_stdin(stdin)
+                                .await
+                                .context("failed to write stdin")?;
+                        }
+
+                        if exec_count > 0 {
+                            let (task, exec_args) = (&task, &exec_args);
+                            let execs = (0..exec_count)
+                                .map(|_| async move { task.exec(exec_args).await?.success() });
+                            for res in join_all(execs).await {
+                                record.execs += 1;
+                                if let Err(err) = res {
+                                    record.exec_errors.push(format!("{err:#}"));
+                                }
+                            }
+                        }
+
+                        pause_resume.run(&task, text).await?;
+
+                        if until == Step::Start {
+                            return Ok(Some(task));
+                        }
+
+                        let kill = kill.filter(|kill| kill.selects(index));
+                        if let Some(kill) = kill {
+                            sleep(kill.after).await;
+                            task.kill(kill.signal).await?;
+                        }
+
+                        let semaphore = Some(&*semaphore);
+                        let wait = retry.time(
+                            Step::Wait,
+                            task_timeout,
+                            timings,
+                            retries,
+                            semaphore,
+                            || task.wait(),
+                        );
+                        let exit = match stats_interval {
+                            Some(interval) => tokio::select! {
+                                exit = wait => exit?,
+                                err = poll_stats(&task, interval, &mut record.stats) => return Err(err),
+                            },
+                            None => wait.await?,
+                        };
+                        record.exit_status = Some(exit.status);
+                        if verify {
+                            let state = verify_state(&task, Step::Wait, &[Status::Stopped]).await?;
+                            if state.exit_status != exit.status {
+                                return Err(UnexpectedState(format!(
+                                    "state after wait reports exit status {}, but wait returned {}",
+                                    state.exit_status, exit.status
+                                ))
+                                .into());
+                            }
+                        }
+                        match kill {
+                            Some(kill) => exit.expect(kill.exit_code.unwrap_or(128 + kill.signal))?,
+                            // a SIGKILL nobody asked for is the OOM killer enforcing the limit
+                            None if limits.memory.is_some() && exit.status == 128 + 9 => {
+                                return Err(OomKilled.into());
+                            }
+                            None => exit.success()?,
+                        }
+
+                        if until == Step::Wait {
+                            return Ok(Some(task));
+                        }
+
+                        retry
+                            .time(
+                                Step::Delete,
+                                task_timeout,
+                                timings,
+                                retries,
+                                semaphore,
+                                || task.delete(),
+                            )
+                            .await?;
+                    }
+
+                    Ok::<_, Error>(None)
+                };
+                match span {
+                    Some(cx) => {
+                        // the trait isn't imported, its `with_context` is also one of anyhow
+                        let traced =
+                            opentelemetry::trace::FutureExt::with_context(lifecycle, cx.clone());
+                        let res = traced.await;
+                        trace::finish(&cx, &res);
+                        res
+                    }
+                    None => lifecycle.await,
+                }
+            }
+            .await;
+            (record, res)
+        }
+    };
+
+    let mut admitted = 0;
+    if run_duration.is_some() {
+        // keep `permits` tasks in flight, replacing each one as it completes
+        while admitted < permits {
+            tracker.push(run_task(admitted, None));
+            admitted += 1;
+        }
+    } else {
+        while admitted < count {
+            tracker.push(run_task(admitted, Some(barrier.clone())));
+            admitted += 1;
+        }
+    }
+
+    let setup_done = barrier.wait().fuse();
+    let mut setup_done = pin!(setup_done);
+
+    let deadline = async {
+        match run_duration {
+            Some(run_duration) => sleep(run_duration).await,
+            None => pending().await,
+        }
+    };
+    let deadline = deadline.fuse();
+    let mut deadline = pin!(deadline);
+    let mut admitting = run_duration.is_some();
+
+    let mut ramp_ticks = ramp.map(|ramp| {
+        let first = tokio::time::Instant::now() + ramp.interval;
+        interval_at(first, ramp.interval)
+    });
+
+    let mut progress = Progress::new(text, run_duration.is_none().then_some(count), run_duration);
+    let mut progress_ticks = interval(Duration::from_secs(1));
+    progress.message("Setting up tasks.");
+
+    let mut success = 0;
+    let mut failed = 0;
+    let mut errors = vec![];
+    let mut timings = vec![];
+    let mut interrupted = false;
+    let mut cleanup = vec![];
+    let mut exec_success = 0;
+    let mut exec_errors = vec![];
+    let mut stats = vec![];
+    let mut per_image = vec![Outcomes::default(); images.len()];
+    let mut per_shim = vec![Outcomes::default(); shims.len()];
+    let mut retried = 0;
+    let mut oom_killed = 0;
+    let mut failures = Failures::default();
+    let mut cycle_totals = vec![vec![]; if cycles > 1 { cycles } else { 0 }];
+
+    loop {
+        tokio::select! {
+            _ = &mut setup_done => {
+                let elapsed = format_duration(setup_start.elapsed());
+                progress.message(format_args!("Setup took {elapsed}"));
+                progress.message("Waiting for tasks to finish.");
+                progress.start();
+            }
+            _ = &mut deadline => {
+                admitting = false;
+                progress.message(format_args!(
+                    "Duration elapsed, waiting for {} in-flight tasks.",
+                    tracker.len()
+                ));
+            }
+            _ = progress_ticks.tick(), if setup_done.is_terminated() => {
+                progress.tick();
+            }
+            _ = async { ramp_ticks.as_mut().unwrap().tick().await }, if ramp_ticks.is_some() && setup_done.is_terminated() => {
+                let ramp = ramp.unwrap();
+                let current = level.load(Ordering::Relaxed);
+                let Some(next) = ramp.next(current) else {
+                    ramp_ticks = None;
+                    continue;
+                };
+                semaphore.add_permits(next - current);
+                level.store(next, Ordering::Relaxed);
+                if admitting {
+                    // keep one in-flight task per permit
+                    for _ in current..next {
+                        tracker.push(run_task(admitted, None));
+                        admitted += 1;
+                    }
+                }
+                progress.message(format_args!("Concurrency level: {next}"));
+            }
+            _ = watchdog(timeout), if setup_done.is_terminated() => {
+                progress.message("\x1b[31mTimeout\x1b[0m");
+                interrupted = true;
+                break;
+            }
+            _ = ctrl_c() => {
+                progress.message("\x1b[31mCancelled\x1b[0m");
+                interrupted = true;
+                break;
+            }
+            res = tracker.next() => {
+                let Some((mut record, res)) = res else {
+                    break;
+                };
+                let res = res.map(|task| cleanup.extend(task));
+                if record.concurrency.is_some() {
+                    in_flight.fetch_sub(1, Ordering::Relaxed);
+                }
+                if let Some(metrics) = metrics {
+                    let started = record.concurrency.is_some();
+                    metrics.task_finished(started, &record.timings, res.is_ok());
+                }
+                record.shim_rss_bytes = shim_rss.as_ref().map(|rss| rss.load(Ordering::Relaxed));
+                if let Some(csv) = csv {
+                    csv.write(&record, &res)?;
+                }
+                if let Some(logs) = logs {
+                    logs.write(&record, &res)?;
+                }
+                exec_success += record.execs - record.exec_errors.len();
+                exec_errors.extend(record.exec_errors);
+                stats.extend(record.stats);
+                if cycles > 1 {
+                    // the last cycle only completed if the task succeeded
+                    let last = res.is_ok().then_some(&record.timings);
+                    for (cycle, t) in record.cycles.iter().chain(last).enumerate() {
+                        cycle_totals[cycle].push(t.total());
+                    }
+                }
+                timings.extend(record.cycles);
+                timings.push(record.timings);
+                let outcomes = &mut per_image[record.index % images.len()];
+                let shim_outcomes = &mut per_shim[record.index % shims.len()];
+                match res {
+                    Ok(()) => {
+                        success += 1;
+                        outcomes.success += 1;
+                        shim_outcomes.success += 1;
+                        if record.retries > 0 {
+                            retried += 1;
+                        }
+                    }
+                    Err(err) => {
+                        failed += 1;
+                        outcomes.failed += 1;
+                        shim_outcomes.failed += 1;
+                        if err.is::<OomKilled>() {
+                            oom_killed += 1;
+                        }
+                        failures.add(Category::of(&err));
+                        let hint = match logs.as_ref() {
+                            Some(logs) => format!(" [{}]", logs.hint(record.id.as_deref())),
+                            None => String::new(),
+                        };
+                        let cycle = match cycles {
+                            1 => String::new(),
+                            _ => format!("cycle {}/{cycles}: ", record.cycle + 1),
+                        };
+                        progress.message(format_args!("\x1b[31m{} .. {cycle}{err}{hint}\x1b[0m", success + failed));
+                        errors.push(format!("{cycle}{err:#}"));
+                        if fail_fast {
+                            // dropping the tracker below cancels the in-flight tasks,
+                            // which are deleted when dropped
+                            interrupted = true;
+                            break;
+                        }
+                    }
+                }
+                if admitting {
+                    tracker.push(run_task(admitted, None));
+                    admitted += 1;
+                }
+                progress.update(Counts {
+                    done: success + failed,
+                    failed,
+                    in_flight: in_flight.load(Ordering::Relaxed),
+                });
+            }
+        }
+    }
+    progress.finish();
+
+    // tasks that didn't run their whole lifecycle are cleaned up
+    // outside of the measurement window
+    let elapsed = start.get().map(|start| start.elapsed());
+    drop(tracker);
+    if !cleanup.is_empty() {
+        if text {
+            errln!("> Cleaning up {} tasks.", cleanup.len());
+        }
+        let results = join_all(cleanup.iter().map(|task| async move {
+            if until == Step::Start {
+                task.wait().await?;
+            }
+            task.delete().await
+        }))
+        .await;
+        for err in results.into_iter().filter_map(Result::err) {
+            log::warn!("failed to clean up task: {err:#}");
+            if text {
+                errln!("> \x1b[33mcleanup .. {err}\x1b[0m");
+            }
+        }
+    }
+
+    Ok(WaveResult {
+        count: admitted,
+        success,
+        failed,
+        elapsed,
+        timings,
+        errors,
+        exec_success,
+        exec_errors,
+        stats,
+        per_image,
+        per_shim,
+        retried,
+        oom_killed,
+        failures,
+        cycles: cycle_totals,
+        interrupted,
+    })
+}
+
+/// Query the state of `task` after `step`, failing unless its status is one of `expected`.
+async fn verify_state(task: &impl Task, step: Step, expected: &[Status]) -> Result<State> {
+    let state = task
+        .state()
+        .await
+        .with_context(|| format!("failed to query the state after {}", step.name()))?;
+    if !expected.contains(&state.status) {
+        return Err(UnexpectedState(format!(
+            "task is {:?} after {}, expected {expected:?}",
+            state.status,
+            step.name()
+        ))
+        .into());
+    }
+    Ok(state)
+}
+
+/// Request the stats of `task` every `interval`, recording the latency of each request.
+/// This only returns if a request fails.
+async fn poll_stats(task: &impl Task, interval: Duration, samples: &mut Vec<Duration>) -> Error {
+    loop {
+        sleep(interval).await;
+        let start = Instant::now();
+        if let Err(err) = task.stats().await {
+            return err.context("stats request failed");
+        }
+        samples.push(start.elapsed());
+    }
+}
@@ -1,4 +1,4 @@
-use std::fs::File;
+use std::fs::{File, read_to_string};
 use std::io::prelude::*;
 use std::thread::sleep;
 use std::time::Duration;
@@ -15,6 +15,17 @@ fn main() {
         "echo" => println!("{}", &args[2..].join(" ")),
         "sleep" => sleep(Duration::from_secs_f64(args[2].parse::<f64>().unwrap())),
         "exit" => process::exit(args[2].parse::<i32>().unwrap()),
+        "cat" => print!("{}", read_to_string(&args[2]).unwrap()),
+        "count-stdin" => {
+            let mut input = Vec::new();
+            std::io::stdin().read_to_end(&mut input).unwrap();
+            println!("{}", input.len());
+        }
+        "env" => {
+            for (key, value) in env::vars() {
+                println!("{key}={value}");
+            }
+        }
         "write" => {
             let mut file = File::create(&args[2]).unwrap();
             file.write_all(args[3..].join(" ").as_bytes()).unwrap();
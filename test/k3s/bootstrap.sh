@@ -6,6 +6,7 @@ cp -f /var/lib/rancher/k3s/agent/etc/containerd/config.toml /var/lib/rancher/k3s
 cat <<EOF >> /var/lib/rancher/k3s/agent/etc/containerd/config.toml.tmpl
 [plugins."io.containerd.cri.v1.runtime".containerd.runtimes.wasm]
   runtime_type = "$PWD/dist/bin/containerd-shim-$1-v1"
+  pod_annotations = ["runwasi.io/*", "io.runwasi.*"]
 [plugins."io.containerd.cri.v1.runtime".containerd.runtimes.wasm.options]
   SystemdCgroup = true
 EOF